@@ -0,0 +1,17 @@
+// `cargo bench` entry point. Criterion can't reach the handlers of a binary
+// crate, so this runs `netcore selfbench` on the freshly built binary.
+// Extra arguments pick handlers: `cargo bench -- echo scan`.
+use std::process::{Command, exit};
+
+fn main() {
+    let args: Vec<String> = std::env::args()
+        .skip(1)
+        .filter(|arg| arg != "--bench")
+        .collect();
+    let status = Command::new(env!("CARGO_BIN_EXE_netcore"))
+        .arg("selfbench")
+        .args(args)
+        .status()
+        .expect("failed to run netcore selfbench");
+    exit(status.code().unwrap_or(1));
+}
@@ -0,0 +1,676 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock, RwLock};
+
+use hyper::body::HttpBody;
+use hyper::{Body, Request};
+use tokio::time::{Duration, Instant, interval, timeout};
+
+use crate::audit;
+use crate::cli::{Args, Opt};
+use crate::history;
+use crate::json::Value;
+use crate::otel;
+use crate::say;
+use crate::stats;
+use crate::units::{self, SECS};
+
+const DEFAULT_REFRESH_SECS: u64 = 3600;
+const DEFAULT_BAN_WINDOW_SECS: u64 = 600;
+const DEFAULT_BAN_SECS: u64 = 3600;
+const MAX_TRACKED_OFFENDERS: usize = 4096;
+const MAX_FEED_BYTES: usize = 16 * 1024 * 1024;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--allow",
+        value: Some("<cidr>"),
+        help: "Only accept connections from this network (repeatable)",
+    },
+    Opt {
+        name: "--deny",
+        value: Some("<cidr>"),
+        help: "Refuse connections from this network (repeatable)",
+    },
+    Opt {
+        name: "--blocklist",
+        value: Some("<path|url>"),
+        help: "File or http:// feed of addresses and CIDRs to refuse (repeatable)",
+    },
+    Opt {
+        name: "--blocklist-refresh",
+        value: Some("<duration>"),
+        help: "How often to reload blocklists (default: 1h)",
+    },
+    Opt {
+        name: "--ban-after",
+        value: Some("<count>"),
+        help: "Ban addresses after this many offences (auth failures, protocol errors)",
+    },
+    Opt {
+        name: "--ban-window",
+        value: Some("<duration>"),
+        help: "Window in which offences are counted (default: 10m)",
+    },
+    Opt {
+        name: "--ban-time",
+        value: Some("<duration>"),
+        help: "How long a ban lasts (default: 1h)",
+    },
+    Opt {
+        name: "--ban-file",
+        value: Some("<path>"),
+        help: "Where bans are kept across restarts (default: next to the history file)",
+    },
+];
+
+#[derive(Clone, Copy)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    // First and last address of the network, as integers of the address
+    // family's width.
+    pub fn bounds(&self) -> (u128, u128) {
+        let (addr, bits) = match self.addr {
+            IpAddr::V4(net) => (u32::from(net) as u128, 32),
+            IpAddr::V6(net) => (u128::from(net), 128),
+        };
+        let host_bits = bits - self.prefix as u32;
+        let mask = u128::MAX.checked_shl(host_bits).unwrap_or(0);
+        let first = addr & mask;
+        (first, first | !mask & (u128::MAX >> (128 - bits)))
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Cidr, String> {
+        let invalid = || format!("invalid network '{}', expected ip or ip/prefix", value);
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| invalid())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|p| *p <= max)
+                .ok_or_else(invalid)?,
+            None => max,
+        };
+        Ok(Cidr { addr, prefix })
+    }
+}
+
+// Blocklist entries as sorted, merged ranges per address family, so a
+// lookup is a binary search however many entries the feeds have.
+#[derive(Default)]
+struct Blocked {
+    v4: Vec<(u128, u128)>,
+    v6: Vec<(u128, u128)>,
+}
+
+impl Blocked {
+    fn new(entries: impl IntoIterator<Item = Cidr>) -> Blocked {
+        let mut blocked = Blocked::default();
+        for cidr in entries {
+            // An IPv4-mapped network is the IPv4 network, which is what
+            // check() compares a mapped peer against.
+            let cidr = match cidr.addr.to_canonical() {
+                addr @ IpAddr::V4(_) if cidr.addr.is_ipv6() && cidr.prefix >= 96 => Cidr {
+                    addr,
+                    prefix: cidr.prefix - 96,
+                },
+                _ => cidr,
+            };
+            match cidr.addr {
+                IpAddr::V4(_) => blocked.v4.push(cidr.bounds()),
+                IpAddr::V6(_) => blocked.v6.push(cidr.bounds()),
+            }
+        }
+        for ranges in [&mut blocked.v4, &mut blocked.v6] {
+            ranges.sort_unstable();
+            let mut merged: Vec<(u128, u128)> = Vec::with_capacity(ranges.len());
+            for &(first, last) in ranges.iter() {
+                match merged.last_mut() {
+                    Some(previous) if first <= previous.1.saturating_add(1) => {
+                        previous.1 = previous.1.max(last);
+                    }
+                    _ => merged.push((first, last)),
+                }
+            }
+            *ranges = merged;
+        }
+        blocked
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (ranges, ip) = match ip.to_canonical() {
+            IpAddr::V4(ip) => (&self.v4, u32::from(ip) as u128),
+            IpAddr::V6(ip) => (&self.v6, u128::from(ip)),
+        };
+        let after = ranges.partition_point(|(first, _)| *first <= ip);
+        after > 0 && ranges[after - 1].1 >= ip
+    }
+}
+
+struct Acl {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    blocklists: Vec<String>,
+    blocked: RwLock<Blocked>,
+    bans: Option<Bans>,
+}
+
+struct Bans {
+    threshold: usize,
+    window: Duration,
+    ban_secs: u64,
+    path: PathBuf,
+    state: Mutex<BanState>,
+}
+
+#[derive(Default)]
+struct BanState {
+    offences: HashMap<IpAddr, Vec<Instant>>,
+    banned: HashMap<IpAddr, (u64, String)>,
+}
+
+impl Bans {
+    fn from_args(args: &Args) -> Result<Option<Bans>, String> {
+        let Some(threshold) = args.parsed::<usize>("--ban-after")? else {
+            return Ok(None);
+        };
+        if threshold == 0 {
+            return Err("--ban-after must be at least 1".to_string());
+        }
+        let duration = |name: &str, default: u64| {
+            Ok::<_, String>(
+                args.parsed_with(name, |v| units::duration(v, SECS).and_then(units::nonzero))?
+                    .unwrap_or(Duration::from_secs(default)),
+            )
+        };
+        let path = match args.value("--ban-file") {
+            Some(path) => PathBuf::from(path),
+            None => history::default_path().with_file_name("bans.tsv"),
+        };
+
+        let bans = Bans {
+            threshold,
+            window: duration("--ban-window", DEFAULT_BAN_WINDOW_SECS)?,
+            ban_secs: duration("--ban-time", DEFAULT_BAN_SECS)?.as_secs(),
+            state: Mutex::new(BanState {
+                offences: HashMap::new(),
+                banned: load_bans(&path),
+            }),
+            path,
+        };
+        let banned = bans.state.lock().unwrap().banned.len();
+        if banned > 0 {
+            say!("Restored {} ban(s) from {}", banned, bans.path.display());
+        }
+        Ok(Some(bans))
+    }
+
+    // Addresses are kept canonical, so a peer that shows up both IPv4-mapped
+    // and plain is one offender.
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let mut state = self.state.lock().unwrap();
+        match state.banned.get(&ip) {
+            Some((until, _)) if *until > history::now() => true,
+            Some(_) => {
+                state.banned.remove(&ip);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn offence(&self, ip: IpAddr, reason: &str) {
+        let ip = ip.to_canonical();
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        if state.offences.len() >= MAX_TRACKED_OFFENDERS {
+            let window = self.window;
+            state
+                .offences
+                .retain(|_, times| times.last().is_some_and(|t| now - *t < window));
+        }
+
+        let times = state.offences.entry(ip).or_default();
+        times.retain(|t| now - *t < self.window);
+        times.push(now);
+        if times.len() < self.threshold {
+            return;
+        }
+
+        state.offences.remove(&ip);
+        let until = history::now() + self.ban_secs;
+        state.banned.insert(ip, (until, reason.to_string()));
+        state.banned.retain(|_, (until, _)| *until > history::now());
+        say!(
+            "Banned {} for {} s after {} offences ({})",
+            ip,
+            self.ban_secs,
+            self.threshold,
+            reason
+        );
+        audit::record(
+            "ban",
+            vec![
+                ("ip", ip.to_string().into()),
+                ("seconds", Value::from(self.ban_secs)),
+                ("offences", Value::from(self.threshold as u64)),
+                ("reason", Value::from(reason)),
+            ],
+        );
+
+        let lines: String = state
+            .banned
+            .iter()
+            .map(|(ip, (until, reason))| format!("{}\t{}\t{}\n", ip, until, reason))
+            .collect();
+        drop(state);
+        if let Err(e) = save_bans(&self.path, &lines) {
+            eprintln!("Failed to save bans to {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn load_bans(path: &Path) -> HashMap<IpAddr, (u64, String)> {
+    let Ok(text) = std::fs::read_to_string(path) else {
+        return HashMap::new();
+    };
+    let now = history::now();
+    text.lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let ip = fields.next()?.parse().ok()?;
+            let until: u64 = fields.next()?.parse().ok()?;
+            let reason = fields.next().unwrap_or_default().to_string();
+            (until > now).then_some((ip, (until, reason)))
+        })
+        .collect()
+}
+
+fn save_bans(path: &Path, lines: &str) -> std::io::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, lines)
+}
+
+static ACL: OnceLock<Acl> = OnceLock::new();
+
+fn from_args(args: &Args) -> Result<(Acl, Duration), String> {
+    let parse = |name| {
+        args.values(name)
+            .map(str::parse)
+            .collect::<Result<Vec<Cidr>, _>>()
+    };
+    let acl = Acl {
+        allow: parse("--allow")?,
+        deny: parse("--deny")?,
+        blocklists: args.values("--blocklist").map(str::to_string).collect(),
+        blocked: RwLock::default(),
+        bans: Bans::from_args(args)?,
+    };
+    let refresh = args
+        .parsed_with("--blocklist-refresh", |v| {
+            units::duration(v, SECS).and_then(units::nonzero)
+        })?
+        .unwrap_or(Duration::from_secs(DEFAULT_REFRESH_SECS));
+    Ok((acl, refresh))
+}
+
+// For `config check`: the options, then each blocklist fetched as serve
+// would, as (subject, what was found) without installing any of it.
+pub async fn preflight(args: &Args) -> Vec<(String, Result<String, String>)> {
+    let acl = match from_args(args) {
+        Ok((acl, _)) => acl,
+        Err(e) => return vec![("access control".to_string(), Err(e))],
+    };
+    let mut results = vec![(
+        "access control".to_string(),
+        Ok(format!(
+            "{} allowed and {} denied networks",
+            acl.allow.len(),
+            acl.deny.len()
+        )),
+    )];
+    for source in &acl.blocklists {
+        let entries = fetch(source)
+            .await
+            .map(|text| format!("{} entries", parse_feed(&text).count()));
+        results.push((format!("blocklist {}", source), entries));
+    }
+    results
+}
+
+pub async fn init(args: &Args) -> Result<(), String> {
+    let (acl, refresh) = from_args(args)?;
+
+    if acl.allow.is_empty()
+        && acl.deny.is_empty()
+        && acl.blocklists.is_empty()
+        && acl.bans.is_none()
+    {
+        return Ok(());
+    }
+    for source in &acl.blocklists {
+        if !source.starts_with("http://") && !Path::new(source).exists() {
+            return Err(format!("blocklist {} does not exist", source));
+        }
+    }
+    if ACL.set(acl).is_err() {
+        return Ok(());
+    }
+
+    if let Some(acl) = ACL.get()
+        && !acl.blocklists.is_empty()
+    {
+        reload(acl).await;
+        tokio::spawn(async move {
+            let mut ticker = interval(refresh);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                reload(acl).await;
+            }
+        });
+    }
+    Ok(())
+}
+
+impl Acl {
+    // Deny beats allow, and the configured lists beat bans and blocklists,
+    // so the reason given is the one an operator can act on.
+    fn refusal(&self, ip: IpAddr) -> Option<&'static str> {
+        if self.deny.iter().any(|cidr| cidr.contains(ip))
+            || (!self.allow.is_empty() && !self.allow.iter().any(|cidr| cidr.contains(ip)))
+        {
+            Some("acl")
+        } else if self.bans.as_ref().is_some_and(|bans| bans.is_banned(ip)) {
+            Some("banned")
+        } else if self.blocked.read().unwrap().contains(ip) {
+            Some("blocklist")
+        } else {
+            None
+        }
+    }
+}
+
+// Returns why the peer is refused, if it is.
+pub fn check(addr: SocketAddr) -> Option<&'static str> {
+    let reason = ACL.get()?.refusal(addr.ip())?;
+
+    stats::blocked(reason);
+    otel::count(match reason {
+        "acl" => "netcore.blocked.acl",
+        "banned" => "netcore.blocked.banned",
+        _ => "netcore.blocked.blocklist",
+    });
+    say!("Refused connection from {} ({})", addr, reason);
+    audit::record(
+        "connection_refused",
+        vec![
+            ("peer", addr.to_string().into()),
+            ("reason", Value::from(reason)),
+        ],
+    );
+    Some(reason)
+}
+
+// Records abuse from a peer; enough of them within the window bans it.
+pub fn offence(addr: SocketAddr, reason: &str) {
+    if let Some(bans) = ACL.get().and_then(|acl| acl.bans.as_ref()) {
+        bans.offence(addr.ip(), reason);
+    }
+}
+
+async fn reload(acl: &Acl) {
+    let mut entries = Vec::new();
+    for source in &acl.blocklists {
+        match fetch(source).await {
+            Ok(text) => {
+                let before = entries.len();
+                entries.extend(parse_feed(&text));
+                say!(
+                    "Loaded {} blocklist entries from {}",
+                    entries.len() - before,
+                    source
+                );
+                audit::record(
+                    "reload",
+                    vec![
+                        ("what", Value::from("blocklist")),
+                        ("source", Value::from(source.as_str())),
+                        ("entries", Value::from((entries.len() - before) as u64)),
+                    ],
+                );
+            }
+            // Keep the previous entries rather than opening up on a failed fetch.
+            Err(e) => {
+                eprintln!("Failed to load blocklist {}: {}", source, e);
+                return;
+            }
+        }
+    }
+    *acl.blocked.write().unwrap() = Blocked::new(entries);
+}
+
+fn parse_feed(text: &str) -> impl Iterator<Item = Cidr> + '_ {
+    text.lines()
+        .map(|line| line.split(['#', ';']).next().unwrap_or_default())
+        .filter_map(|line| line.split_whitespace().next())
+        .filter_map(|entry| entry.parse().ok())
+}
+
+async fn fetch(source: &str) -> Result<String, String> {
+    if !source.starts_with("http://") {
+        return tokio::fs::read_to_string(source)
+            .await
+            .map_err(|e| e.to_string());
+    }
+
+    let request = Request::get(source)
+        .header("user-agent", concat!("netcore/", env!("CARGO_PKG_VERSION")))
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+    let response = timeout(
+        crate::timeouts::get().read,
+        crate::proxy::client().request(request),
+    )
+    .await
+    .map_err(|_| "timed out".to_string())?
+    .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("feed returned {}", response.status()));
+    }
+
+    let mut body = response.into_body();
+    let mut text = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        if text.len() + chunk.len() > MAX_FEED_BYTES {
+            return Err("feed too large".to_string());
+        }
+        text.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8_lossy(&text).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(allow: &[&str], deny: &[&str], blocked: &[&str]) -> Acl {
+        let parse = |list: &[&str]| list.iter().map(|v| v.parse().unwrap()).collect::<Vec<_>>();
+        Acl {
+            allow: parse(allow),
+            deny: parse(deny),
+            blocklists: Vec::new(),
+            blocked: RwLock::new(Blocked::new(parse(blocked))),
+            bans: None,
+        }
+    }
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn deny_beats_allow_beats_blocklist() {
+        let acl = acl(
+            &["10.0.0.0/8", "2001:db8::/32"],
+            &["10.1.0.0/16"],
+            &["10.2.0.0/16", "10.1.2.3"],
+        );
+        assert_eq!(acl.refusal(ip("10.0.0.1")), None);
+        assert_eq!(acl.refusal(ip("10.1.2.3")), Some("acl"));
+        assert_eq!(acl.refusal(ip("10.2.0.1")), Some("blocklist"));
+        assert_eq!(acl.refusal(ip("192.0.2.1")), Some("acl"));
+        assert_eq!(acl.refusal(ip("2001:db8::1")), None);
+        assert_eq!(acl.refusal(ip("2001:db9::1")), Some("acl"));
+
+        let open = self::acl(&[], &[], &[]);
+        assert_eq!(open.refusal(ip("192.0.2.1")), None);
+    }
+
+    #[test]
+    fn treats_ipv4_mapped_peers_as_ipv4() {
+        let acl = acl(&["192.0.2.0/24"], &["192.0.2.66"], &["192.0.2.128/25"]);
+        assert_eq!(acl.refusal(ip("::ffff:192.0.2.1")), None);
+        assert_eq!(acl.refusal(ip("::ffff:192.0.2.66")), Some("acl"));
+        assert_eq!(acl.refusal(ip("::ffff:192.0.2.200")), Some("blocklist"));
+        assert_eq!(acl.refusal(ip("::ffff:198.51.100.1")), Some("acl"));
+
+        let mapped = Blocked::new(["::ffff:198.51.100.0/120".parse().unwrap()]);
+        assert!(mapped.contains(ip("198.51.100.7")));
+        assert!(mapped.contains(ip("::ffff:198.51.100.7")));
+        assert!(!mapped.contains(ip("198.51.101.7")));
+    }
+
+    #[test]
+    fn merges_blocklist_ranges() {
+        let entries = [
+            "10.0.0.0/24",
+            "10.0.1.0/24",
+            "10.0.0.128/25",
+            "10.0.5.5",
+            "0.0.0.0/0",
+        ];
+        let blocked = Blocked::new(entries.iter().map(|v| v.parse().unwrap()));
+        assert_eq!(blocked.v4, [(0, u32::MAX as u128)]);
+
+        let entries = [
+            "10.0.0.0/24",
+            "10.0.1.0/24",
+            "10.0.0.128/25",
+            "10.0.5.5",
+            "2001:db8::/64",
+        ];
+        let blocked = Blocked::new(entries.iter().map(|v| v.parse().unwrap()));
+        assert_eq!(blocked.v4.len(), 2);
+        assert_eq!(blocked.v6.len(), 1);
+        for (addr, expected) in [
+            ("9.255.255.255", false),
+            ("10.0.0.0", true),
+            ("10.0.1.255", true),
+            ("10.0.2.0", false),
+            ("10.0.5.4", false),
+            ("10.0.5.5", true),
+            ("10.0.5.6", false),
+            ("2001:db8::ffff", true),
+            ("2001:db8:0:1::", false),
+            ("::a00:1", false),
+        ] {
+            assert_eq!(blocked.contains(ip(addr)), expected, "{}", addr);
+        }
+        assert!(!Blocked::default().contains(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn bans_after_repeated_offences() {
+        let path = std::env::temp_dir().join(format!("netcore-bans-{}.tsv", std::process::id()));
+        let bans = |path: &Path| Bans {
+            threshold: 3,
+            window: Duration::from_secs(600),
+            ban_secs: 3600,
+            path: path.to_path_buf(),
+            state: Mutex::new(BanState {
+                offences: HashMap::new(),
+                banned: load_bans(path),
+            }),
+        };
+        let mut acl = acl(&[], &["192.0.2.1"], &["192.0.2.0/24"]);
+        acl.bans = Some(bans(&path));
+        let offend = |addr: &str| acl.bans.as_ref().unwrap().offence(ip(addr), "auth");
+
+        offend("192.0.2.7");
+        offend("::ffff:192.0.2.7");
+        assert_eq!(acl.refusal(ip("192.0.2.7")), Some("blocklist"));
+        offend("192.0.2.7");
+        assert_eq!(acl.refusal(ip("192.0.2.7")), Some("banned"));
+        assert_eq!(acl.refusal(ip("::ffff:192.0.2.7")), Some("banned"));
+        assert_eq!(acl.refusal(ip("192.0.2.8")), Some("blocklist"));
+
+        // A denied address reports the deny rule, banned or not.
+        for _ in 0..3 {
+            offend("192.0.2.1");
+        }
+        assert_eq!(acl.refusal(ip("192.0.2.1")), Some("acl"));
+
+        // Bans outlive a restart, and expired ones are dropped on load.
+        let restored = bans(&path);
+        assert!(restored.is_banned(ip("192.0.2.7")));
+        let until = history::now() - 1;
+        std::fs::write(&path, format!("192.0.2.7\t{}\tauth\n", until)).unwrap();
+        assert!(!bans(&path).is_banned(ip("192.0.2.7")));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reloads_and_keeps_entries_on_failure() {
+        let path = std::env::temp_dir().join(format!("netcore-acl-{}.txt", std::process::id()));
+        std::fs::write(&path, "# feed\n203.0.113.0/24 ; test net\nnonsense\n").unwrap();
+        let mut acl = acl(&[], &[], &[]);
+        acl.blocklists = vec![path.display().to_string()];
+
+        reload(&acl).await;
+        assert_eq!(acl.refusal(ip("203.0.113.9")), Some("blocklist"));
+        assert_eq!(acl.refusal(ip("198.51.100.9")), None);
+
+        std::fs::write(&path, "198.51.100.0/24\n").unwrap();
+        reload(&acl).await;
+        assert_eq!(acl.refusal(ip("203.0.113.9")), None);
+        assert_eq!(acl.refusal(ip("198.51.100.9")), Some("blocklist"));
+
+        std::fs::remove_file(&path).unwrap();
+        reload(&acl).await;
+        assert_eq!(acl.refusal(ip("198.51.100.9")), Some("blocklist"));
+    }
+}
@@ -0,0 +1,87 @@
+// Answers ACME HTTP-01 challenges on the ports netcore already holds, so
+// an ACME client such as certbot, lego or acme.sh can get and renew a
+// Let's Encrypt certificate in webroot mode without netcore being stopped.
+// The client writes each token under --acme-webroot and the CA fetches it
+// from /.well-known/acme-challenge/ through the reverse proxy or the web
+// dashboard.
+//
+// netcore can't act as the ACME client itself or serve the certificate:
+// both need a TLS stack, which this build doesn't have. TLS-ALPN-01 is
+// left out for the same reason.
+
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Response, StatusCode};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use crate::cli::{Args, Opt};
+use crate::say;
+
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+pub const OPTS: &[Opt] = &[Opt {
+    name: "--acme-webroot",
+    value: Some("<dir>"),
+    help: "Answer ACME HTTP-01 challenges from the files an ACME client writes here in webroot mode",
+}];
+
+static WEBROOT: OnceLock<PathBuf> = OnceLock::new();
+
+pub fn init(args: &Args) -> Result<(), String> {
+    let Some(dir) = args.value("--acme-webroot") else {
+        return Ok(());
+    };
+    let dir = PathBuf::from(dir);
+    if !dir.is_dir() {
+        return Err(format!(
+            "--acme-webroot {} is not a directory",
+            dir.display()
+        ));
+    }
+    let _ = WEBROOT.set(dir);
+    Ok(())
+}
+
+// Tokens are base64url (RFC 8555 8.3), which also keeps them inside the
+// challenge directory.
+pub fn valid_token(token: &str) -> bool {
+    !token.is_empty()
+        && token
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+// The answer for a challenge request, or None if `path` isn't one or
+// --acme-webroot wasn't given, for the caller to handle as usual.
+pub fn respond(path: &str) -> Option<Response<Body>> {
+    let token = path.strip_prefix(CHALLENGE_PATH)?;
+    let webroot = WEBROOT.get()?;
+    let file = webroot.join(&CHALLENGE_PATH[1..]).join(token);
+    let (status, body) = match valid_token(token).then(|| fs::read(&file)) {
+        Some(Ok(body)) => {
+            say!("Answered ACME challenge {}", token);
+            (StatusCode::OK, body)
+        }
+        _ => (StatusCode::NOT_FOUND, b"not found\n".to_vec()),
+    };
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/octet-stream")
+        .body(Body::from(body))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_base64url_tokens_are_served() {
+        assert!(valid_token("LoqXcYV8q5ONbJQxbmR7SCTNo3tiAXDfowyjxAjEuX0"));
+        assert!(!valid_token("../../etc/passwd"));
+        assert!(!valid_token("a/b"));
+        assert!(!valid_token(""));
+        assert!(respond("/index.html").is_none());
+    }
+}
@@ -0,0 +1,250 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+
+// Completions per adjustment when the limit itself is smaller than this.
+const MIN_WINDOW: u32 = 16;
+// A window whose timeout rate sits this far above the best one seen counts
+// as congestion; a host that drops everything never trips it.
+const TIMEOUT_TOLERANCE: f64 = 0.1;
+// How fast the best rate seen may creep back up, per window.
+const BASELINE_DRIFT: f64 = 0.02;
+// Descriptors left for everything that isn't a probe.
+#[cfg(unix)]
+const RESERVED_FDS: usize = 32;
+
+pub enum Outcome {
+    // The other end answered, whatever it said.
+    Answered,
+    TimedOut,
+    // We ran out of sockets, buffers or ports locally.
+    Exhausted,
+}
+
+impl Outcome {
+    pub fn from_error(e: &io::Error) -> Outcome {
+        match e.raw_os_error() {
+            Some(
+                libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM | libc::EADDRNOTAVAIL,
+            ) => Outcome::Exhausted,
+            _ => Outcome::Answered,
+        }
+    }
+}
+
+// How many sockets can be open at once before hitting the open file limit.
+#[cfg(unix)]
+pub fn fd_budget() -> Option<usize> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    Some(
+        (limit.rlim_cur as usize)
+            .saturating_sub(RESERVED_FDS)
+            .max(1),
+    )
+}
+
+// Windows has no descriptor limit to read; this stays well inside its
+// default range of ephemeral ports.
+#[cfg(not(unix))]
+pub fn fd_budget() -> Option<usize> {
+    Some(1024)
+}
+
+struct State {
+    limit: f64,
+    in_flight: usize,
+    // Doubles the limit each window until the first sign of trouble.
+    slow_start: bool,
+    done: u32,
+    timed_out: u32,
+    baseline: Option<f64>,
+}
+
+// An AIMD limit on work in flight, like TCP's congestion window: it grows
+// while probes get answers and halves when timeouts climb above their usual
+// rate or the system runs out of descriptors.
+pub struct Limiter {
+    min: usize,
+    max: usize,
+    state: Mutex<State>,
+    released: Notify,
+}
+
+pub struct Permit {
+    limiter: Arc<Limiter>,
+}
+
+impl Limiter {
+    pub fn new(min: usize, max: usize) -> Arc<Limiter> {
+        let max = max.max(1);
+        let min = min.clamp(1, max);
+        Arc::new(Limiter {
+            min,
+            max,
+            state: Mutex::new(State {
+                limit: min.max(max / 8) as f64,
+                in_flight: 0,
+                slow_start: true,
+                done: 0,
+                timed_out: 0,
+                baseline: None,
+            }),
+            released: Notify::new(),
+        })
+    }
+
+    // Cancel safe: a permit is only taken in the poll that returns it.
+    pub async fn acquire(self: &Arc<Self>) -> Permit {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.in_flight < state.limit as usize {
+                    state.in_flight += 1;
+                    return Permit {
+                        limiter: self.clone(),
+                    };
+                }
+            }
+            released.await;
+        }
+    }
+
+    fn decrease(&self, state: &mut State) {
+        state.slow_start = false;
+        state.limit = (state.limit / 2.0).max(self.min as f64);
+    }
+
+    fn finish(&self, outcome: Outcome) {
+        let mut state = self.state.lock().unwrap();
+        match outcome {
+            Outcome::Answered if state.slow_start => state.limit += 1.0,
+            Outcome::Answered => state.limit += 1.0 / state.limit,
+            Outcome::TimedOut => state.timed_out += 1,
+            Outcome::Exhausted => {
+                self.decrease(&mut state);
+                state.done = 0;
+                state.timed_out = 0;
+            }
+        }
+        state.limit = state.limit.min(self.max as f64);
+        state.done += 1;
+        if state.done >= MIN_WINDOW.max(state.limit as u32) {
+            let rate = state.timed_out as f64 / state.done as f64;
+            let baseline = state
+                .baseline
+                .map_or(rate, |best| (best + BASELINE_DRIFT).min(rate));
+            if rate > baseline + TIMEOUT_TOLERANCE {
+                self.decrease(&mut state);
+            }
+            state.baseline = Some(baseline);
+            state.done = 0;
+            state.timed_out = 0;
+        }
+    }
+}
+
+impl Permit {
+    pub fn finish(self, outcome: Outcome) {
+        self.limiter.finish(outcome);
+    }
+}
+
+// Dropping a permit without reporting an outcome frees its slot and leaves
+// the limit alone.
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.limiter.state.lock().unwrap().in_flight -= 1;
+        self.limiter.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limit(limiter: &Limiter) -> f64 {
+        limiter.state.lock().unwrap().limit
+    }
+
+    fn window(limiter: &Limiter, answered: u32, timed_out: u32) {
+        for _ in 0..answered {
+            limiter.finish(Outcome::Answered);
+        }
+        for _ in 0..timed_out {
+            limiter.finish(Outcome::TimedOut);
+        }
+    }
+
+    #[test]
+    fn backs_off_above_the_usual_timeout_rate_and_recovers() {
+        let limiter = Limiter::new(4, 16);
+        assert_eq!(limit(&limiter), 4.0);
+
+        // Slow start grows by one per answer, up to the maximum, and the
+        // first window sets the baseline timeout rate.
+        window(&limiter, 16, 0);
+        assert_eq!(limit(&limiter), 16.0);
+
+        // A quarter timing out is well above a baseline of none.
+        window(&limiter, 12, 4);
+        assert_eq!(limit(&limiter), 8.0);
+        assert!(!limiter.state.lock().unwrap().slow_start);
+
+        // The baseline has drifted up to 0.04, and 2 of 16 (0.125) is
+        // within the tolerance of it: growth is additive again.
+        window(&limiter, 14, 2);
+        let grown = limit(&limiter);
+        assert!(grown > 9.0 && grown < 10.0, "{}", grown);
+
+        window(&limiter, 16, 0);
+        assert!(limit(&limiter) > grown + 1.0);
+    }
+
+    #[test]
+    fn halves_on_exhaustion_down_to_the_minimum() {
+        let limiter = Limiter::new(3, 64);
+        assert_eq!(limit(&limiter), 8.0);
+        limiter.finish(Outcome::Exhausted);
+        assert_eq!(limit(&limiter), 4.0);
+        limiter.finish(Outcome::Exhausted);
+        assert_eq!(limit(&limiter), 3.0);
+
+        // Out of slow start, an answer adds 1/limit.
+        limiter.finish(Outcome::Answered);
+        assert_eq!(limit(&limiter), 3.0 + 1.0 / 3.0);
+    }
+
+    #[test]
+    fn never_backs_off_from_a_host_that_drops_everything() {
+        let limiter = Limiter::new(1, 16);
+        window(&limiter, 0, 16);
+        window(&limiter, 0, 16);
+        assert_eq!(limit(&limiter), 2.0);
+    }
+
+    #[test]
+    fn sorts_local_exhaustion_from_answers() {
+        for errno in [
+            libc::EMFILE,
+            libc::ENFILE,
+            libc::ENOBUFS,
+            libc::EADDRNOTAVAIL,
+        ] {
+            let e = io::Error::from_raw_os_error(errno);
+            assert!(matches!(Outcome::from_error(&e), Outcome::Exhausted));
+        }
+        let refused = io::Error::from_raw_os_error(libc::ECONNREFUSED);
+        assert!(matches!(Outcome::from_error(&refused), Outcome::Answered));
+    }
+}
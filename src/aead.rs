@@ -0,0 +1,340 @@
+// ChaCha20-Poly1305 (RFC 8439) with a 96-bit nonce and 16-byte tag.
+//
+// This, x25519, ed25519 and sha2 are all the crypto netcore has, written
+// for the SSH server, paired sessions and the peer tunnel, and checked
+// against the RFCs' test vectors in their tests. They're kept to those few primitives:
+// there is no RSA, ECDSA or X.509 signing.
+
+pub const KEY_LEN: usize = 32;
+pub const NONCE_LEN: usize = 12;
+pub const TAG_LEN: usize = 16;
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]);
+    s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]);
+    s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn chacha20_block(key: &[u8; KEY_LEN], counter: u32, nonce: &[u8; NONCE_LEN]) -> [u8; 64] {
+    let mut initial = [0u32; 16];
+    initial[..4].copy_from_slice(&[0x61707865, 0x3320646e, 0x79622d32, 0x6b206574]);
+    for i in 0..8 {
+        initial[4 + i] = le32(&key[i * 4..]);
+    }
+    initial[12] = counter;
+    for i in 0..3 {
+        initial[13 + i] = le32(&nonce[i * 4..]);
+    }
+
+    let mut s = initial;
+    for _ in 0..10 {
+        quarter_round(&mut s, 0, 4, 8, 12);
+        quarter_round(&mut s, 1, 5, 9, 13);
+        quarter_round(&mut s, 2, 6, 10, 14);
+        quarter_round(&mut s, 3, 7, 11, 15);
+        quarter_round(&mut s, 0, 5, 10, 15);
+        quarter_round(&mut s, 1, 6, 11, 12);
+        quarter_round(&mut s, 2, 7, 8, 13);
+        quarter_round(&mut s, 3, 4, 9, 14);
+    }
+
+    let mut out = [0; 64];
+    for i in 0..16 {
+        out[i * 4..i * 4 + 4].copy_from_slice(&s[i].wrapping_add(initial[i]).to_le_bytes());
+    }
+    out
+}
+
+fn chacha20_xor(key: &[u8; KEY_LEN], counter: u32, nonce: &[u8; NONCE_LEN], data: &mut [u8]) {
+    for (i, chunk) in data.chunks_mut(64).enumerate() {
+        let stream = chacha20_block(key, counter.wrapping_add(i as u32), nonce);
+        for (byte, key) in chunk.iter_mut().zip(stream) {
+            *byte ^= key;
+        }
+    }
+}
+
+// Poly1305 in three limbs of 44, 44 and 42 bits, as in poly1305-donna.
+struct Poly1305 {
+    r: [u64; 3],
+    h: [u64; 3],
+    pad: [u64; 2],
+}
+
+const M44: u64 = (1 << 44) - 1;
+const M42: u64 = (1 << 42) - 1;
+
+fn le64(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+impl Poly1305 {
+    fn new(key: &[u8; 32]) -> Poly1305 {
+        let (t0, t1) = (le64(&key[0..]), le64(&key[8..]));
+        Poly1305 {
+            r: [
+                t0 & 0xffc0fffffff,
+                ((t0 >> 44) | (t1 << 20)) & 0xfffffc0ffff,
+                (t1 >> 24) & 0x00ffffffc0f,
+            ],
+            h: [0; 3],
+            pad: [le64(&key[16..]), le64(&key[24..])],
+        }
+    }
+
+    // Whole blocks carry a 2^128 bit; the final partial block is padded
+    // with a 1 byte instead.
+    fn block(&mut self, block: &[u8; 16], high_bit: u64) {
+        let (t0, t1) = (le64(&block[0..]), le64(&block[8..]));
+        let [r0, r1, r2] = self.r.map(u128::from);
+        let (s1, s2) = (r1 * 20, r2 * 20);
+
+        let h0 = u128::from(self.h[0] + (t0 & M44));
+        let h1 = u128::from(self.h[1] + (((t0 >> 44) | (t1 << 20)) & M44));
+        let h2 = u128::from(self.h[2] + (((t1 >> 24) & M42) | high_bit));
+
+        let d0 = h0 * r0 + h1 * s2 + h2 * s1;
+        let mut d1 = h0 * r1 + h1 * r0 + h2 * s2;
+        let mut d2 = h0 * r2 + h1 * r1 + h2 * r0;
+
+        d1 += d0 >> 44;
+        d2 += d1 >> 44;
+        let mut h0 = (d0 as u64) & M44;
+        let h1 = (d1 as u64) & M44;
+        let h2 = (d2 as u64) & M42;
+        h0 += ((d2 >> 42) as u64) * 5;
+        self.h = [h0 & M44, h1 + (h0 >> 44), h2];
+    }
+
+    // The whole message at once: only its last block may be partial.
+    fn update(&mut self, data: &[u8]) {
+        let mut chunks = data.chunks_exact(16);
+        for chunk in &mut chunks {
+            self.block(chunk.try_into().unwrap(), 1 << 40);
+        }
+        let rest = chunks.remainder();
+        if !rest.is_empty() {
+            let mut last = [0; 16];
+            last[..rest.len()].copy_from_slice(rest);
+            last[rest.len()] = 1;
+            self.block(&last, 0);
+        }
+    }
+
+    fn finish(self) -> [u8; TAG_LEN] {
+        let [mut h0, mut h1, mut h2] = self.h;
+        for _ in 0..2 {
+            h2 += h1 >> 44;
+            h1 &= M44;
+            h0 += (h2 >> 42) * 5;
+            h2 &= M42;
+            h1 += h0 >> 44;
+            h0 &= M44;
+        }
+
+        // h - p, kept only if it didn't go negative.
+        let mut g0 = h0 + 5;
+        let mut g1 = h1 + (g0 >> 44);
+        g0 &= M44;
+        let g2 = (h2 + (g1 >> 44)).wrapping_sub(1 << 42);
+        g1 &= M44;
+        let keep = (g2 >> 63).wrapping_sub(1);
+        h0 = (h0 & !keep) | (g0 & keep);
+        h1 = (h1 & !keep) | (g1 & keep);
+        h2 = (h2 & !keep) | (g2 & keep);
+
+        let (t0, t1) = (self.pad[0], self.pad[1]);
+        h0 += t0 & M44;
+        h1 += (((t0 >> 44) | (t1 << 20)) & M44) + (h0 >> 44);
+        h0 &= M44;
+        h2 += ((t1 >> 24) & M42) + (h1 >> 44);
+        h1 &= M44;
+        h2 &= M42;
+
+        let mut tag = [0; TAG_LEN];
+        tag[..8].copy_from_slice(&(h0 | (h1 << 44)).to_le_bytes());
+        tag[8..].copy_from_slice(&((h1 >> 20) | (h2 << 24)).to_le_bytes());
+        tag
+    }
+}
+
+fn tag(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], aad: &[u8], ciphertext: &[u8]) -> [u8; 16] {
+    let block = chacha20_block(key, 0, nonce);
+    let mut poly = Poly1305::new(block[..32].try_into().unwrap());
+    // Padded so that only the lengths block could be partial, and it isn't.
+    let padded = |data: &[u8]| {
+        let mut data = data.to_vec();
+        data.resize(data.len().div_ceil(16) * 16, 0);
+        data
+    };
+    let mut message = padded(aad);
+    message.extend_from_slice(&padded(ciphertext));
+    message.extend_from_slice(&(aad.len() as u64).to_le_bytes());
+    message.extend_from_slice(&(ciphertext.len() as u64).to_le_bytes());
+    poly.update(&message);
+    poly.finish()
+}
+
+// Encrypts `plaintext` and appends the tag.
+pub fn seal(key: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    let mut out = plaintext.to_vec();
+    chacha20_xor(key, 1, nonce, &mut out);
+    let tag = tag(key, nonce, aad, &out);
+    out.extend_from_slice(&tag);
+    out
+}
+
+// The plaintext, or None if the tag doesn't match.
+pub fn open(
+    key: &[u8; KEY_LEN],
+    nonce: &[u8; NONCE_LEN],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Option<Vec<u8>> {
+    let split = sealed.len().checked_sub(TAG_LEN)?;
+    let (ciphertext, received) = sealed.split_at(split);
+    let expected = tag(key, nonce, aad, ciphertext);
+    // Compare without stopping at the first difference.
+    let diff = expected
+        .iter()
+        .zip(received)
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    if diff != 0 {
+        return None;
+    }
+    let mut out = ciphertext.to_vec();
+    chacha20_xor(key, 1, nonce, &mut out);
+    Some(out)
+}
+
+// chacha20-poly1305@openssh.com, as SSH packets use it (OpenSSH's
+// PROTOCOL.chacha20poly1305): the second half of the key encrypts the
+// 4-byte packet length and the first half the rest, with the sequence
+// number as nonce, and the tag covers both.
+pub const SSH_KEY_LEN: usize = 64;
+
+fn ssh_nonce(seq: u32) -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[4..].copy_from_slice(&u64::from(seq).to_be_bytes());
+    nonce
+}
+
+fn ssh_tag(main: &[u8; KEY_LEN], nonce: &[u8; NONCE_LEN], ciphertext: &[u8]) -> [u8; TAG_LEN] {
+    let block = chacha20_block(main, 0, nonce);
+    let mut poly = Poly1305::new(block[..32].try_into().unwrap());
+    poly.update(ciphertext);
+    poly.finish()
+}
+
+fn ssh_keys(key: &[u8; SSH_KEY_LEN]) -> (&[u8; KEY_LEN], &[u8; KEY_LEN]) {
+    let (main, header) = key.split_at(KEY_LEN);
+    (main.try_into().unwrap(), header.try_into().unwrap())
+}
+
+// Encrypts a whole packet, length first, and appends the tag.
+pub fn ssh_seal(key: &[u8; SSH_KEY_LEN], seq: u32, packet: &[u8]) -> Vec<u8> {
+    let (main, header) = ssh_keys(key);
+    let nonce = ssh_nonce(seq);
+    let mut out = packet.to_vec();
+    chacha20_xor(header, 0, &nonce, &mut out[..4]);
+    chacha20_xor(main, 1, &nonce, &mut out[4..]);
+    let tag = ssh_tag(main, &nonce, &out);
+    out.extend_from_slice(&tag);
+    out
+}
+
+// The length of the packet that starts with `encrypted`, so the rest can
+// be read before it is authenticated.
+pub fn ssh_length(key: &[u8; SSH_KEY_LEN], seq: u32, encrypted: &[u8; 4]) -> u32 {
+    let mut length = *encrypted;
+    chacha20_xor(ssh_keys(key).1, 0, &ssh_nonce(seq), &mut length);
+    u32::from_be_bytes(length)
+}
+
+// The packet after its length, or None if the tag doesn't match.
+pub fn ssh_open(key: &[u8; SSH_KEY_LEN], seq: u32, sealed: &[u8]) -> Option<Vec<u8>> {
+    let (main, _) = ssh_keys(key);
+    let nonce = ssh_nonce(seq);
+    let split = sealed
+        .len()
+        .checked_sub(TAG_LEN)
+        .filter(|split| *split >= 4)?;
+    let (ciphertext, received) = sealed.split_at(split);
+    let expected = ssh_tag(main, &nonce, ciphertext);
+    let diff = expected
+        .iter()
+        .zip(received)
+        .fold(0, |diff, (a, b)| diff | (a ^ b));
+    if diff != 0 {
+        return None;
+    }
+    let mut out = ciphertext[4..].to_vec();
+    chacha20_xor(main, 1, &nonce, &mut out);
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex;
+
+    fn unhex(text: &str) -> Vec<u8> {
+        hex::decode(text).unwrap()
+    }
+
+    #[test]
+    fn chacha20_block_matches_rfc_8439() {
+        let key: [u8; 32] = std::array::from_fn(|i| i as u8);
+        let nonce: [u8; 12] = unhex("000000090000004a00000000")[..].try_into().unwrap();
+        assert_eq!(
+            hex::encode(&chacha20_block(&key, 1, &nonce)),
+            "10f1e7e4d13b5915500fdd1fa32071c4c7d1f4c733c068030422aa9ac3d46c4e\
+             d2826446079faa0914c2d705d98b02a2b5129cd1de164eb9cbd083e8a2503c4e"
+        );
+    }
+
+    #[test]
+    fn poly1305_matches_rfc_8439() {
+        let key = unhex("85d6be7857556d337f4452fe42d506a80103808afb0db2fd4abff6af4149f51b");
+        let mut poly = Poly1305::new(key[..].try_into().unwrap());
+        poly.update(b"Cryptographic Forum Research Group");
+        assert_eq!(
+            poly.finish().to_vec(),
+            unhex("a8061dc1305136c6c22b8baf0c0127a9")
+        );
+    }
+
+    #[test]
+    fn aead_matches_rfc_8439_and_rejects_tampering() {
+        let key: [u8; 32] = std::array::from_fn(|i| 0x80 + i as u8);
+        let nonce: [u8; 12] = unhex("070000004041424344454647")[..].try_into().unwrap();
+        let aad = unhex("50515253c0c1c2c3c4c5c6c7");
+        let plaintext = b"Ladies and Gentlemen of the class of '99: If I could offer you \
+only one tip for the future, sunscreen would be it.";
+
+        let sealed = seal(&key, &nonce, &aad, plaintext);
+        assert_eq!(
+            hex::encode(&sealed),
+            "d31a8d34648e60db7b86afbc53ef7ec2a4aded51296e08fea9e2b5a736ee62d6\
+             3dbea45e8ca9671282fafb69da92728b1a71de0a9e060b2905d6a5b67ecd3b36\
+             92ddbd7f2d778b8c9803aee328091b58fab324e4fad675945585808b4831d7bc\
+             3ff4def08e4b7a9de576d26586cec64b6116\
+             1ae10b594f09e26a7e902ecbd0600691"
+        );
+        assert_eq!(open(&key, &nonce, &aad, &sealed).unwrap(), plaintext);
+
+        let mut tampered = sealed.clone();
+        tampered[3] ^= 1;
+        assert_eq!(open(&key, &nonce, &aad, &tampered), None);
+        assert_eq!(open(&key, &nonce, b"other", &sealed), None);
+    }
+}
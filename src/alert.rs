@@ -0,0 +1,380 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::process::Command;
+use tokio::time::timeout;
+
+use crate::config::{Config, Section};
+use crate::history;
+use crate::json::Value;
+use crate::measure::Sample;
+use crate::say;
+use crate::syslog;
+use crate::webhook;
+
+const SMTP_PORT: u16 = 25;
+
+pub enum Condition {
+    PublicIpChanged,
+    RttAbove(f64),
+    CheckFailed,
+    CertExpiring,
+}
+
+pub enum Action {
+    Webhook(String),
+    Command(String),
+    Email(String),
+}
+
+pub struct Rule {
+    pub name: String,
+    pub condition: Condition,
+    pub subject: Option<String>,
+    pub actions: Vec<Action>,
+}
+
+pub struct Smtp {
+    pub server: String,
+    pub from: String,
+}
+
+#[derive(Default)]
+struct State {
+    public: HashMap<String, String>,
+    active: HashSet<(String, String)>,
+}
+
+pub struct Alerts {
+    rules: Vec<Rule>,
+    smtp: Option<Smtp>,
+    state: Mutex<State>,
+}
+
+fn rule(config: &Config, section: &Section) -> Result<Rule, String> {
+    let condition = match section.require(config, "when")? {
+        "public_ip_changed" => Condition::PublicIpChanged,
+        "check_failed" => Condition::CheckFailed,
+        "cert_expiring" => Condition::CertExpiring,
+        "rtt_above" => Condition::RttAbove(
+            section
+                .parsed(config, "threshold")?
+                .ok_or_else(|| config.error(section.line, "rtt_above needs a 'threshold' in ms"))?,
+        ),
+        other => {
+            return Err(config.error(
+                section.entry("when").map_or(section.line, |e| e.line),
+                &format!("unknown alert condition '{}'", other),
+            ));
+        }
+    };
+
+    let actions: Vec<Action> = section
+        .entries
+        .iter()
+        .filter_map(|e| match e.key.as_str() {
+            "webhook" => Some(Action::Webhook(e.value.clone())),
+            "command" => Some(Action::Command(e.value.clone())),
+            "email" => Some(Action::Email(e.value.clone())),
+            _ => None,
+        })
+        .collect();
+
+    if actions.is_empty() {
+        return Err(config.error(
+            section.line,
+            &format!("[alert {}] needs a webhook, command or email", section.name),
+        ));
+    }
+
+    Ok(Rule {
+        name: section.name.clone(),
+        condition,
+        subject: section.get("target").map(str::to_string),
+        actions,
+    })
+}
+
+impl Alerts {
+    pub fn from_config(config: &Config) -> Result<Alerts, String> {
+        let rules = config
+            .sections("alert")
+            .map(|section| rule(config, section))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let smtp = match config.sections("smtp").last() {
+            Some(section) => Some(Smtp {
+                server: section.require(config, "server")?.to_string(),
+                from: section.require(config, "from")?.to_string(),
+            }),
+            None => None,
+        };
+
+        let wants_email = rules
+            .iter()
+            .flat_map(|r| &r.actions)
+            .any(|a| matches!(a, Action::Email(_)));
+        if wants_email && smtp.is_none() {
+            return Err(format!(
+                "{}: email alerts need an [smtp] section",
+                config.path.display()
+            ));
+        }
+
+        Ok(Alerts {
+            rules,
+            smtp,
+            state: Mutex::default(),
+        })
+    }
+
+    fn triggered(&self, rule: &Rule, samples: &[Sample]) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        let mut messages = Vec::new();
+        let matches = |sample: &&Sample| {
+            rule.subject
+                .as_ref()
+                .is_none_or(|subject| *subject == sample.subject)
+        };
+
+        match rule.condition {
+            Condition::PublicIpChanged => {
+                for sample in samples
+                    .iter()
+                    .filter(|s| s.kind == "info" && s.subject.starts_with("public_") && s.ok)
+                    .filter(matches)
+                {
+                    let key = format!("{}/{}", rule.name, sample.subject);
+                    if let Some(previous) = state.public.insert(key, sample.value.clone())
+                        && previous != sample.value
+                    {
+                        messages.push(format!(
+                            "{} changed from {} to {}",
+                            sample.subject, previous, sample.value
+                        ));
+                    }
+                }
+            }
+            Condition::RttAbove(threshold) => {
+                let mut rtts: HashMap<&str, Vec<f64>> = HashMap::new();
+                for sample in samples.iter().filter(|s| s.kind == "ping").filter(matches) {
+                    let entry = rtts.entry(&sample.subject).or_default();
+                    if let Ok(rtt) = sample.value.parse() {
+                        entry.push(rtt);
+                    }
+                }
+
+                for (subject, values) in rtts {
+                    let avg = values.iter().sum::<f64>() / values.len().max(1) as f64;
+                    let breached = values.is_empty() || avg > threshold;
+                    let detail = if values.is_empty() {
+                        format!("no replies from {}", subject)
+                    } else {
+                        format!(
+                            "RTT to {} is {:.2} ms (threshold {} ms)",
+                            subject, avg, threshold
+                        )
+                    };
+                    if let Some(message) = transition(&mut state, rule, subject, breached, detail) {
+                        messages.push(message);
+                    }
+                }
+            }
+            Condition::CheckFailed => {
+                for sample in samples.iter().filter(|s| s.kind == "check").filter(matches) {
+                    let detail = format!("check {} failed: {}", sample.subject, sample.value);
+                    if let Some(message) =
+                        transition(&mut state, rule, &sample.subject, !sample.ok, detail)
+                    {
+                        messages.push(message);
+                    }
+                }
+            }
+            Condition::CertExpiring => {
+                for sample in samples.iter().filter(|s| s.kind == "cert").filter(matches) {
+                    let detail = match sample.value.parse::<f64>() {
+                        Ok(days) if days < 0.0 => {
+                            format!("certificate {} has expired", sample.subject)
+                        }
+                        Ok(days) => {
+                            format!("certificate {} expires in {:.1} days", sample.subject, days)
+                        }
+                        Err(_) => format!("certificate {}: {}", sample.subject, sample.value),
+                    };
+                    if let Some(message) =
+                        transition(&mut state, rule, &sample.subject, !sample.ok, detail)
+                    {
+                        messages.push(message);
+                    }
+                }
+            }
+        }
+
+        messages
+    }
+
+    pub async fn evaluate(&self, job: &str, samples: &[Sample]) {
+        for rule in &self.rules {
+            for message in self.triggered(rule, samples) {
+                say!("Alert '{}': {}", rule.name, message);
+                syslog::alert(&rule.name, &message);
+                for action in &rule.actions {
+                    if let Err(e) = self.fire(rule, job, &message, action).await {
+                        eprintln!("Alert '{}' action failed: {}", rule.name, e);
+                    }
+                }
+            }
+        }
+    }
+
+    async fn fire(
+        &self,
+        rule: &Rule,
+        job: &str,
+        message: &str,
+        action: &Action,
+    ) -> Result<(), String> {
+        match action {
+            Action::Webhook(url) => {
+                let payload = Value::object([
+                    ("alert", Value::from(rule.name.as_str())),
+                    ("job", Value::from(job)),
+                    ("message", Value::from(message)),
+                    ("time", Value::from(history::now())),
+                ]);
+                webhook::post(url, &payload).await
+            }
+            Action::Command(command) => run_command(command, &rule.name, message).await,
+            Action::Email(to) => match &self.smtp {
+                Some(smtp) => send_email(smtp, to, &rule.name, message).await,
+                None => Err("no [smtp] section configured".to_string()),
+            },
+        }
+    }
+}
+
+fn transition(
+    state: &mut State,
+    rule: &Rule,
+    subject: &str,
+    breached: bool,
+    detail: String,
+) -> Option<String> {
+    let key = (rule.name.clone(), subject.to_string());
+
+    if breached {
+        state.active.insert(key).then_some(detail)
+    } else {
+        if state.active.remove(&key) {
+            say!("Alert '{}' resolved for {}", rule.name, subject);
+            syslog::resolved(&rule.name, subject);
+        }
+        None
+    }
+}
+
+async fn run_command(command: &str, alert: &str, message: &str) -> Result<(), String> {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+
+    let status = cmd
+        .arg(command)
+        .env("NETCORE_ALERT", alert)
+        .env("NETCORE_ALERT_MESSAGE", message)
+        .status()
+        .await
+        .map_err(|e| e.to_string())?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("command exited with {}", status))
+    }
+}
+
+async fn smtp_reply<R: AsyncBufReadExt + Unpin>(
+    reader: &mut R,
+    expected: char,
+) -> Result<(), String> {
+    loop {
+        let mut line = String::new();
+        if reader
+            .read_line(&mut line)
+            .await
+            .map_err(|e| e.to_string())?
+            == 0
+        {
+            return Err("SMTP server closed the connection".to_string());
+        }
+        if !line.starts_with(expected) {
+            return Err(format!("unexpected SMTP reply: {}", line.trim_end()));
+        }
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+async fn smtp_session(smtp: &Smtp, to: &str, subject: &str, body: &str) -> Result<(), String> {
+    let server = if smtp.server.contains(':') {
+        smtp.server.clone()
+    } else {
+        format!("{}:{}", smtp.server, SMTP_PORT)
+    };
+
+    let stream = TcpStream::connect(&server)
+        .await
+        .map_err(|e| e.to_string())?;
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    smtp_reply(&mut reader, '2').await?;
+    let body = body.replace("\n.", "\n..");
+    let steps = [
+        (format!("EHLO {}\r\n", local_hostname()), '2'),
+        (format!("MAIL FROM:<{}>\r\n", smtp.from), '2'),
+        (format!("RCPT TO:<{}>\r\n", to), '2'),
+        ("DATA\r\n".to_string(), '3'),
+        (
+            format!(
+                "From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\n{}\r\n.\r\n",
+                smtp.from, to, subject, body
+            ),
+            '2',
+        ),
+    ];
+
+    for (command, expected) in steps {
+        writer
+            .write_all(command.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        smtp_reply(&mut reader, expected).await?;
+    }
+
+    let _ = writer.write_all(b"QUIT\r\n").await;
+    Ok(())
+}
+
+async fn send_email(smtp: &Smtp, to: &str, alert: &str, message: &str) -> Result<(), String> {
+    let subject = format!("[netcore] {}", alert);
+
+    timeout(
+        crate::timeouts::get().write * 5,
+        smtp_session(smtp, to, &subject, message),
+    )
+    .await
+    .map_err(|_| "SMTP session timed out".to_string())?
+}
+
+fn local_hostname() -> String {
+    std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string())
+}
@@ -0,0 +1,328 @@
+// The audit log (--audit-log): one JSON line per thing an operator may
+// later have to account for, appended and never rewritten. Connections
+// opened, closed and refused, admin commands over the control socket and
+// the web API, failed logins, bans, and blocklists and certificates
+// reloaded. It is separate from what netcore prints, which is for watching
+// rather than keeping. The file is rotated once it reaches --audit-max-size
+// or --audit-rotate-every, to <path>.1, <path>.2 and so on, optionally
+// gzipped, keeping --audit-keep of them.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::cli::{Args, Opt};
+use crate::events::{self, Event};
+use crate::gzip;
+use crate::history;
+use crate::json::Value;
+use crate::say;
+use crate::units::{self, SECS};
+
+const DEFAULT_MAX_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_KEEP: usize = 7;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--audit-log",
+        value: Some("<path>"),
+        help: "Append an audit record of connections, admin actions, reloads and bans to a file",
+    },
+    Opt {
+        name: "--audit-max-size",
+        value: Some("<size>"),
+        help: "Rotate the audit log when it reaches this size (default: 10M)",
+    },
+    Opt {
+        name: "--audit-rotate-every",
+        value: Some("<duration>"),
+        help: "Also rotate the audit log after this long, such as 1d",
+    },
+    Opt {
+        name: "--audit-keep",
+        value: Some("<n>"),
+        help: "Rotated audit logs to keep (default: 7)",
+    },
+    Opt {
+        name: "--audit-gzip",
+        value: None,
+        help: "Compress rotated audit logs",
+    },
+];
+
+struct Rotation {
+    max_size: u64,
+    every: Option<Duration>,
+    keep: usize,
+    gzip: bool,
+}
+
+struct Log {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    size: u64,
+    opened: SystemTime,
+}
+
+static LOG: OnceLock<Mutex<Log>> = OnceLock::new();
+
+// Readable by the owner only, as it names peers and what admins did.
+fn private() -> OpenOptions {
+    let mut options = OpenOptions::new();
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options
+}
+
+fn open(path: &Path) -> std::io::Result<(File, u64, SystemTime)> {
+    let file = private().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    // An existing log's age counts from when it was started, where the
+    // filesystem keeps that.
+    let opened = metadata.created().unwrap_or_else(|_| SystemTime::now());
+    Ok((file, metadata.len(), opened))
+}
+
+pub fn rotated(path: &Path, n: usize, gzip: bool) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    if gzip {
+        name.push(".gz");
+    }
+    PathBuf::from(name)
+}
+
+// Moves `path` to <path>.1, shifting older ones up and dropping any past
+// `keep`. The caller opens a new file at `path`.
+pub fn rotate(path: &Path, keep: usize, gzip: bool) -> std::io::Result<()> {
+    // Either name may be there, from before --audit-gzip was given or
+    // taken away.
+    for compressed in [false, true] {
+        let _ = fs::remove_file(rotated(path, keep, compressed));
+        for n in (1..keep).rev() {
+            let from = rotated(path, n, compressed);
+            if from.exists() {
+                fs::rename(&from, rotated(path, n + 1, compressed))?;
+            }
+        }
+    }
+    if keep == 0 {
+        fs::remove_file(path)
+    } else if gzip {
+        // Rotation is rare; compressing here keeps the numbering simple.
+        let data = fs::read(path)?;
+        private()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(rotated(path, 1, true))?
+            .write_all(&gzip::compress(&data))?;
+        fs::remove_file(path)
+    } else {
+        fs::rename(path, rotated(path, 1, false))
+    }
+}
+
+impl Log {
+    fn due(&self, adding: u64) -> bool {
+        let full = self.size > 0 && self.size + adding > self.rotation.max_size;
+        let old = self
+            .rotation
+            .every
+            .is_some_and(|every| self.opened.elapsed().is_ok_and(|elapsed| elapsed >= every));
+        full || old
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        rotate(&self.path, self.rotation.keep, self.rotation.gzip)?;
+        let (file, size, _) = open(&self.path)?;
+        self.file = file;
+        self.size = size;
+        self.opened = SystemTime::now();
+        Ok(())
+    }
+
+    fn append(&mut self, line: &str) -> std::io::Result<()> {
+        if self.due(line.len() as u64)
+            && let Err(e) = self.rotate()
+        {
+            eprintln!("Failed to rotate audit log {}: {}", self.path.display(), e);
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+fn timestamp() -> String {
+    let now = history::now();
+    format!("{}Z", history::format_time(now).replace(' ', "T"))
+}
+
+// Appends `kind` and its fields, if there is an audit log.
+pub fn record(kind: &str, fields: Vec<(&str, Value)>) {
+    let fields = fields
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect();
+    write(kind, fields);
+}
+
+fn write(kind: &str, fields: Vec<(String, Value)>) {
+    let Some(log) = LOG.get() else {
+        return;
+    };
+    let mut entry = vec![
+        ("time".to_string(), Value::from(timestamp())),
+        ("kind".to_string(), Value::from(kind)),
+    ];
+    entry.extend(fields);
+    let line = format!("{}\n", Value::Object(entry));
+    let mut log = log.lock().unwrap();
+    if let Err(e) = log.append(&line) {
+        eprintln!("Failed to write audit log {}: {}", log.path.display(), e);
+    }
+}
+
+// A command that changes something, from the control socket or the web
+// API; `error` says why it failed, if it did.
+pub fn admin(via: &str, from: Option<String>, command: &str, request: Value, error: Option<&str>) {
+    record(
+        "admin",
+        vec![
+            ("via", Value::from(via)),
+            ("from", Value::from(from)),
+            ("command", Value::from(command)),
+            ("request", request),
+            ("ok", Value::from(error.is_none())),
+            ("error", Value::from(error)),
+        ],
+    );
+}
+
+// Connections, with the fields the bus gives them.
+fn connection(event: &Event) -> Option<Vec<(String, Value)>> {
+    if !matches!(
+        event,
+        Event::ConnectionOpened { .. } | Event::ConnectionClosed { .. }
+    ) {
+        return None;
+    }
+    match event.to_json() {
+        Value::Object(fields) => Some(
+            fields
+                .into_iter()
+                .filter(|(name, _)| name != "event")
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+pub fn init(args: &Args) -> Result<(), String> {
+    let Some(path) = args.value("--audit-log") else {
+        return Ok(());
+    };
+    let path = PathBuf::from(path);
+    let rotation = Rotation {
+        max_size: args
+            .parsed_with("--audit-max-size", units::size)?
+            .unwrap_or(DEFAULT_MAX_SIZE),
+        every: args.parsed_with("--audit-rotate-every", |v| {
+            units::nonzero(units::duration(v, SECS)?)
+        })?,
+        keep: args.parsed("--audit-keep")?.unwrap_or(DEFAULT_KEEP),
+        gzip: args.flag("--audit-gzip"),
+    };
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    }
+    let (file, size, opened) = open(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    let log = Log {
+        path,
+        rotation,
+        file,
+        size,
+        opened,
+    };
+    say!("Writing the audit log to {}", log.path.display());
+    if LOG.set(Mutex::new(log)).is_err() {
+        return Ok(());
+    }
+    record(
+        "started",
+        vec![("pid", Value::from(std::process::id() as u64))],
+    );
+
+    let mut events = events::subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => {
+                    if let Some(fields) = connection(&event) {
+                        write(event.name(), fields);
+                    }
+                }
+                // Gaps are recorded, so the log says where it's incomplete.
+                Err(RecvError::Lagged(missed)) => {
+                    record("lagged", vec![("missed", Value::from(missed))]);
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotates_by_size_and_keeps_the_newest() {
+        let dir = std::env::temp_dir().join(format!("netcore-audit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.log");
+        let (file, size, opened) = open(&path).unwrap();
+        let mut log = Log {
+            path: path.clone(),
+            rotation: Rotation {
+                max_size: 10,
+                every: None,
+                keep: 2,
+                gzip: false,
+            },
+            file,
+            size,
+            opened,
+        };
+        for line in ["one one\n", "two two\n", "three\n", "four\n"] {
+            log.append(line).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "four\n");
+        assert_eq!(
+            fs::read_to_string(rotated(&path, 1, false)).unwrap(),
+            "three\n"
+        );
+        assert_eq!(
+            fs::read_to_string(rotated(&path, 2, false)).unwrap(),
+            "two two\n"
+        );
+        assert!(!rotated(&path, 3, false).exists());
+
+        log.rotation.gzip = true;
+        log.append("five five\n").unwrap();
+        let gzipped = fs::read(rotated(&path, 1, true)).unwrap();
+        assert_eq!(gzipped, gzip::compress(b"four\n"));
+        assert_eq!(
+            fs::read_to_string(rotated(&path, 2, false)).unwrap(),
+            "three\n"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use std::fmt;
+
+use crate::cli::{Args, Opt};
+use crate::secret;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--admin-token",
+        value: Some("<token>"),
+        help: "Token granting full access to admin endpoints, or file:, env: or exec: to look it up (repeatable)",
+    },
+    Opt {
+        name: "--read-token",
+        value: Some("<token>"),
+        help: "Token granting read-only access to admin endpoints, or file:, env: or exec: (repeatable)",
+    },
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Read,
+    Admin,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Read => write!(f, "read"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Auth {
+    tokens: Vec<(Role, String)>,
+}
+
+impl Auth {
+    pub fn from_args(args: &Args) -> Result<Auth, String> {
+        let mut tokens = Vec::new();
+        for (name, role) in [("--admin-token", Role::Admin), ("--read-token", Role::Read)] {
+            for token in secret::values(args, name)? {
+                if token.is_empty() {
+                    return Err(format!("{} must not be empty", name));
+                }
+                tokens.push((role, token));
+            }
+        }
+
+        Ok(Auth { tokens })
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn role(&self, offered: Option<&str>) -> Option<Role> {
+        // Without tokens a request from the operator can't be told apart
+        // from one a web page made their browser send, so it only reads.
+        if self.is_open() {
+            return Some(Role::Read);
+        }
+
+        let offered = offered?;
+        self.tokens
+            .iter()
+            .filter(|(_, token)| constant_time_eq(offered.as_bytes(), token.as_bytes()))
+            .map(|(role, _)| *role)
+            .max()
+    }
+}
+
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth(tokens: &[&str]) -> Auth {
+        let args = Args::parse(tokens.iter().map(|t| t.to_string()), &[OPTS]).unwrap();
+        Auth::from_args(&args).unwrap()
+    }
+
+    #[test]
+    fn maps_tokens_to_roles() {
+        let auth = auth(&[
+            "--admin-token",
+            "root",
+            "--read-token",
+            "viewer",
+            "--read-token",
+            "shared",
+            "--admin-token",
+            "shared",
+        ]);
+        assert!(!auth.is_open());
+        assert_eq!(auth.role(Some("root")), Some(Role::Admin));
+        assert_eq!(auth.role(Some("viewer")), Some(Role::Read));
+        // A token given for both roles gets the higher one.
+        assert_eq!(auth.role(Some("shared")), Some(Role::Admin));
+        assert_eq!(auth.role(Some("roo")), None);
+        assert_eq!(auth.role(Some("")), None);
+        assert!(Role::Read < Role::Admin);
+    }
+
+    #[test]
+    fn needs_a_token_once_any_is_set() {
+        assert_eq!(auth(&["--read-token", "viewer"]).role(None), None);
+
+        // With none configured, anyone may read but nobody is admin.
+        let open = auth(&[]);
+        assert!(open.is_open());
+        assert_eq!(open.role(None), Some(Role::Read));
+        assert_eq!(open.role(Some("anything")), Some(Role::Read));
+
+        let args = Args::parse(["--admin-token".to_string(), String::new()], &[OPTS]).unwrap();
+        assert!(Auth::from_args(&args).is_err());
+    }
+
+    #[test]
+    fn refuses_tokens_of_another_length() {
+        assert!(constant_time_eq(b"s3cret", b"s3cret"));
+        assert!(constant_time_eq(b"", b""));
+        assert!(!constant_time_eq(b"s3cret", b"s3creT"));
+        assert!(!constant_time_eq(b"s3cret", b"s3cret!"));
+        assert!(!constant_time_eq(b"s3cret!", b"s3cret"));
+        assert!(!constant_time_eq(b"", b"s"));
+    }
+}
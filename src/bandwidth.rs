@@ -0,0 +1,362 @@
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Duration, Instant, Sleep, sleep};
+
+use crate::cli::{Args, Opt};
+use crate::units;
+
+const BURST_SECS: f64 = 0.1;
+const MIN_BURST_BYTES: f64 = 64.0 * 1024.0;
+const QUANTUM: f64 = 1500.0;
+
+static NEXT_FLOW: AtomicU64 = AtomicU64::new(0);
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--max-bandwidth",
+        value: Some("<rate>"),
+        help: "Cap total throughput of the process, e.g. 50Mbps or 10M/s",
+    },
+    Opt {
+        name: "--listener-bandwidth",
+        value: Some("<rate>"),
+        help: "Cap total throughput of each listener",
+    },
+];
+
+struct Flow {
+    id: u64,
+    debt: f64,
+    deficit: f64,
+    waker: Option<Waker>,
+}
+
+struct BucketState {
+    tokens: f64,
+    updated: Instant,
+    queue: VecDeque<Flow>,
+}
+
+pub struct Bucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<BucketState>,
+}
+
+impl Bucket {
+    fn new(bytes_per_sec: u64) -> Bucket {
+        let rate = bytes_per_sec as f64;
+        let burst = (rate * BURST_SECS).max(MIN_BURST_BYTES);
+        Bucket {
+            rate,
+            burst,
+            state: Mutex::new(BucketState {
+                tokens: burst,
+                updated: Instant::now(),
+                queue: VecDeque::new(),
+            }),
+        }
+    }
+
+    // Bytes are charged after they moved. While nobody is queued and the
+    // bucket covers them they are paid at once; otherwise the flow joins the
+    // round-robin queue and must not move more until its debt is paid.
+    fn charge(&self, id: u64, bytes: usize) {
+        let bytes = bytes as f64;
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+
+        if state.queue.is_empty() && state.tokens >= bytes {
+            state.tokens -= bytes;
+            return;
+        }
+        match state.queue.iter_mut().find(|flow| flow.id == id) {
+            Some(flow) => flow.debt += bytes,
+            None => state.queue.push_back(Flow {
+                id,
+                debt: bytes,
+                deficit: 0.0,
+                waker: None,
+            }),
+        }
+    }
+
+    // Returns how long to wait before asking again if the flow still owes.
+    fn poll_paid(&self, id: u64, waker: &Waker) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        self.refill(&mut state);
+        schedule(&mut state);
+
+        let flow = state.queue.iter_mut().find(|flow| flow.id == id)?;
+        flow.waker = Some(waker.clone());
+        let wait = (QUANTUM - state.tokens).max(1.0) / self.rate;
+        Some(Duration::from_secs_f64(wait))
+    }
+
+    fn forget(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.queue.retain(|flow| flow.id != id);
+    }
+
+    fn refill(&self, state: &mut BucketState) {
+        let now = Instant::now();
+        let refill = (now - state.updated).as_secs_f64() * self.rate;
+        state.tokens = (state.tokens + refill).min(self.burst);
+        state.updated = now;
+    }
+}
+
+// Deficit round-robin: each queued flow may pay up to QUANTUM bytes of its
+// debt per turn, so a bulk transfer can't hold back small interactive writes.
+fn schedule(state: &mut BucketState) {
+    while state.tokens > 0.0
+        && let Some(flow) = state.queue.front_mut()
+    {
+        if flow.deficit <= 0.0 {
+            flow.deficit += QUANTUM;
+        }
+        let paid = flow.debt.min(flow.deficit).min(state.tokens);
+        flow.debt -= paid;
+        flow.deficit -= paid;
+        state.tokens -= paid;
+
+        if flow.debt <= 0.0 {
+            let flow = state.queue.pop_front().unwrap();
+            if let Some(waker) = flow.waker {
+                waker.wake();
+            }
+        } else if flow.deficit <= 0.0 {
+            state.queue.rotate_left(1);
+        } else {
+            break;
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Limits {
+    buckets: Vec<Arc<Bucket>>,
+}
+
+impl Limits {
+    pub fn wrap<S>(&self, inner: S) -> Limited<S> {
+        Limited {
+            inner,
+            buckets: self.buckets.clone(),
+            id: NEXT_FLOW.fetch_add(2, Ordering::Relaxed),
+            read_delay: None,
+            write_delay: None,
+        }
+    }
+}
+
+pub struct Bandwidth {
+    global: Option<Arc<Bucket>>,
+    listener: Option<u64>,
+}
+
+impl Bandwidth {
+    pub fn from_args(args: &Args) -> Result<Bandwidth, String> {
+        let rate = |name: &str| -> Result<Option<u64>, String> {
+            args.parsed_with(name, |v| {
+                units::rate(v).and_then(|rate| match rate {
+                    0 => Err("must be more than zero".to_string()),
+                    rate => Ok(rate),
+                })
+            })
+        };
+
+        Ok(Bandwidth {
+            global: rate("--max-bandwidth")?.map(|rate| Arc::new(Bucket::new(rate))),
+            listener: rate("--listener-bandwidth")?,
+        })
+    }
+
+    pub fn listener(&self) -> Limits {
+        let mut buckets: Vec<Arc<Bucket>> = self.global.iter().cloned().collect();
+        buckets.extend(self.listener.map(|rate| Arc::new(Bucket::new(rate))));
+        Limits { buckets }
+    }
+}
+
+pub struct Limited<S> {
+    inner: S,
+    buckets: Vec<Arc<Bucket>>,
+    // Reads are flow `id`, writes are flow `id + 1`.
+    id: u64,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    write_delay: Option<Pin<Box<Sleep>>>,
+}
+
+fn poll_paid(
+    buckets: &[Arc<Bucket>],
+    id: u64,
+    delay: &mut Option<Pin<Box<Sleep>>>,
+    cx: &mut Context<'_>,
+) -> Poll<()> {
+    loop {
+        let wait = buckets
+            .iter()
+            .filter_map(|bucket| bucket.poll_paid(id, cx.waker()))
+            .max();
+        let Some(wait) = wait else {
+            *delay = None;
+            return Poll::Ready(());
+        };
+
+        let sleep = delay.get_or_insert_with(|| Box::pin(sleep(wait)));
+        ready!(sleep.as_mut().poll(cx));
+        *delay = None;
+    }
+}
+
+fn charge(buckets: &[Arc<Bucket>], id: u64, bytes: usize) {
+    for bucket in buckets {
+        bucket.charge(id, bytes);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Limited<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        ready!(poll_paid(&this.buckets, this.id, &mut this.read_delay, cx));
+
+        let before = buf.filled().len();
+        let result = ready!(Pin::new(&mut this.inner).poll_read(cx, buf));
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            charge(&this.buckets, this.id, n);
+        }
+        Poll::Ready(result)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Limited<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        ready!(poll_paid(
+            &this.buckets,
+            this.id + 1,
+            &mut this.write_delay,
+            cx
+        ));
+
+        let result = ready!(Pin::new(&mut this.inner).poll_write(cx, buf));
+        if let Ok(n) = result
+            && n > 0
+        {
+            charge(&this.buckets, this.id + 1, n);
+        }
+        Poll::Ready(result)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl<S> Drop for Limited<S> {
+    fn drop(&mut self) {
+        for bucket in &self.buckets {
+            bucket.forget(self.id);
+            bucket.forget(self.id + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::task::Wake;
+
+    struct Wakes(AtomicUsize);
+
+    impl Wake for Wakes {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn flow(id: u64, debt: f64, waker: &Arc<Wakes>) -> Flow {
+        Flow {
+            id,
+            debt,
+            deficit: 0.0,
+            waker: Some(Waker::from(waker.clone())),
+        }
+    }
+
+    #[test]
+    fn pays_from_the_burst_then_queues() {
+        // At 1 byte/s the refill between calls is noise next to the burst.
+        let bucket = Bucket::new(1);
+        assert_eq!(bucket.burst, MIN_BURST_BYTES);
+        bucket.charge(1, 60_000);
+        assert!(bucket.state.lock().unwrap().queue.is_empty());
+
+        bucket.charge(1, 10_000);
+        bucket.charge(3, 100);
+        bucket.charge(1, 500);
+        let state = bucket.state.lock().unwrap();
+        let queued: Vec<(u64, f64)> = state.queue.iter().map(|f| (f.id, f.debt)).collect();
+        // Once someone waits, even a write the bucket could cover queues
+        // behind them.
+        assert_eq!(queued, [(1, 10_500.0), (3, 100.0)]);
+        assert!((state.tokens - (MIN_BURST_BYTES - 60_000.0)).abs() < 1.0);
+        drop(state);
+
+        bucket.forget(1);
+        let state = bucket.state.lock().unwrap();
+        assert_eq!(state.queue.len(), 1);
+        assert_eq!(state.queue[0].id, 3);
+    }
+
+    #[test]
+    fn shares_tokens_by_deficit_round_robin() {
+        let bulk = Arc::new(Wakes(AtomicUsize::new(0)));
+        let small = Arc::new(Wakes(AtomicUsize::new(0)));
+        let mut state = BucketState {
+            tokens: 3000.0,
+            updated: Instant::now(),
+            queue: VecDeque::from([flow(0, 10_000.0, &bulk), flow(2, 500.0, &small)]),
+        };
+        schedule(&mut state);
+
+        // The bulk flow pays one quantum, the small one all of its debt, and
+        // the bulk flow takes what is left into its next turn.
+        assert_eq!(state.tokens, 0.0);
+        assert_eq!(state.queue.len(), 1);
+        assert_eq!(state.queue[0].id, 0);
+        assert_eq!(state.queue[0].debt, 10_000.0 - QUANTUM - 1000.0);
+        assert_eq!(state.queue[0].deficit, QUANTUM - 1000.0);
+        assert_eq!(small.0.load(Ordering::Relaxed), 1);
+        assert_eq!(bulk.0.load(Ordering::Relaxed), 0);
+
+        // Paid off over later turns, it is woken once.
+        while !state.queue.is_empty() {
+            state.tokens += 2000.0;
+            schedule(&mut state);
+        }
+        assert_eq!(bulk.0.load(Ordering::Relaxed), 1);
+        assert!(state.tokens > 0.0);
+    }
+}
@@ -0,0 +1,245 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant, interval, timeout_at};
+
+use crate::cli::{self, Args, Command, Opt};
+use crate::exit;
+use crate::json::{self, Value};
+use crate::output;
+use crate::say;
+use crate::units::{self, SECS};
+
+const MAGIC: &str = "NETCORE-BEACON 1\n";
+const DEFAULT_PORT: u16 = 6880;
+const INTERVAL_SECS: u64 = 5;
+const EXPIRE_INTERVALS: u32 = 3;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--beacon",
+        value: None,
+        help: "Announce this instance on the LAN with UDP broadcasts",
+    },
+    Opt {
+        name: "--beacon-port",
+        value: Some("<port>"),
+        help: "UDP port for beacons (default: 6880)",
+    },
+];
+
+const LISTEN_OPTS: &[Opt] = &[Opt {
+    name: "--duration",
+    value: Some("<duration>"),
+    help: "Stop listening after this long",
+}];
+
+pub struct Beacon {
+    port: u16,
+}
+
+impl Beacon {
+    pub fn from_args(args: &Args) -> Result<Option<Beacon>, String> {
+        let port = args.parsed("--beacon-port")?.unwrap_or(DEFAULT_PORT);
+        Ok(args.flag("--beacon").then_some(Beacon { port }))
+    }
+}
+
+pub fn hostname() -> String {
+    let mut buf = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) } != 0 {
+        return "unknown".to_string();
+    }
+    buf.iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8 as char)
+        .collect()
+}
+
+pub async fn announce(beacon: Beacon, services: Vec<(&'static str, u16)>) {
+    let socket = match UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Failed to open beacon socket: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = socket.set_broadcast(true) {
+        eprintln!("Failed to enable broadcast for beacons: {}", e);
+        return;
+    }
+
+    let info = crate::get_host_info().await;
+    let payload = Value::object([
+        ("id", Value::from(format!("{:016x}", rand::random::<u64>()))),
+        ("host", Value::from(hostname())),
+        ("version", Value::from(env!("CARGO_PKG_VERSION"))),
+        (
+            "local_ipv4",
+            Value::from(info.local_ipv4.map(|ip| ip.to_string())),
+        ),
+        (
+            "local_ipv6",
+            Value::from(info.local_ipv6.map(|ip| ip.to_string())),
+        ),
+        (
+            "public_ipv4",
+            Value::from(info.public_ipv4.map(|ip| ip.to_string())),
+        ),
+        (
+            "public_ipv6",
+            Value::from(info.public_ipv6.map(|ip| ip.to_string())),
+        ),
+        (
+            "services",
+            Value::Array(
+                services
+                    .iter()
+                    .map(|(name, port)| {
+                        Value::object([
+                            ("name", Value::from(*name)),
+                            ("port", Value::from(*port as u64)),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ),
+    ]);
+    let message = format!("{}{}", MAGIC, payload);
+    let target = SocketAddrV4::new(Ipv4Addr::BROADCAST, beacon.port);
+
+    say!("Announcing on UDP broadcast port {}", beacon.port);
+    let mut ticker = interval(Duration::from_secs(INTERVAL_SECS));
+    loop {
+        ticker.tick().await;
+        if let Err(e) = socket.send_to(message.as_bytes(), target).await {
+            eprintln!("Failed to send beacon: {}", e);
+        }
+    }
+}
+
+fn listener(port: u16) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_broadcast(true)?;
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port).into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn describe(peer: &Value, from: SocketAddr) -> String {
+    let field = |key| peer.get(key).and_then(Value::as_str).unwrap_or("-");
+    let services: Vec<String> = peer
+        .get("services")
+        .map(Value::as_array)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|service| {
+            Some(format!(
+                "{}:{}",
+                service.get("name")?.as_str()?,
+                service.get("port")?.as_u64()?
+            ))
+        })
+        .collect();
+    format!(
+        "{} (netcore {}) from {}: ipv4 {} ipv6 {} public {} / {} services {}",
+        field("host"),
+        field("version"),
+        from.ip(),
+        field("local_ipv4"),
+        field("local_ipv6"),
+        field("public_ipv4"),
+        field("public_ipv6"),
+        if services.is_empty() {
+            "-".to_string()
+        } else {
+            services.join(", ")
+        }
+    )
+}
+
+fn emit_peer(event: &str, id: &str, summary: &str) {
+    output::emit(Value::object([
+        ("event", Value::from(event)),
+        ("id", Value::from(id)),
+        ("summary", Value::from(summary)),
+    ]));
+}
+
+pub const COMMAND: Command = Command {
+    name: "beacon",
+    usage: "netcore beacon",
+    about: "Listen for netcore servers announcing themselves on the LAN",
+    groups: &[OPTS, LISTEN_OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let port = cli::or_exit(args.parsed("--beacon-port")).unwrap_or(DEFAULT_PORT);
+    let duration = cli::or_exit(args.parsed_with("--duration", |v| units::duration(v, SECS)));
+
+    let socket = match listener(port) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Failed to listen for beacons on port {}: {}", port, e);
+            std::process::exit(exit::code(&e));
+        }
+    };
+    say!("Listening for beacons on UDP port {}", port);
+
+    let expire = Duration::from_secs(INTERVAL_SECS) * EXPIRE_INTERVALS;
+    let deadline = duration.map(|duration| Instant::now() + duration);
+    let mut peers: HashMap<String, (Instant, String)> = HashMap::new();
+    let mut sweep = interval(Duration::from_secs(INTERVAL_SECS));
+    let mut buf = [0; 4096];
+
+    loop {
+        let received = async {
+            match deadline {
+                Some(deadline) => timeout_at(deadline, socket.recv_from(&mut buf)).await.ok(),
+                None => Some(socket.recv_from(&mut buf).await),
+            }
+        };
+        tokio::select! {
+            result = received => match result {
+                Some(Ok((n, from))) => {
+                    let text = String::from_utf8_lossy(&buf[..n]);
+                    let Some(peer) = text.strip_prefix(MAGIC).and_then(|body| json::parse(body).ok()) else {
+                        continue;
+                    };
+                    let Some(id) = peer.get("id").and_then(Value::as_str).map(str::to_string) else {
+                        continue;
+                    };
+                    let summary = describe(&peer, from);
+                    let (event, verb) = match peers.insert(id.clone(), (Instant::now(), summary.clone())) {
+                        None => ("found", "Found"),
+                        Some((_, previous)) if previous != summary => ("updated", "Updated"),
+                        Some(_) => continue,
+                    };
+                    say!("{} {}", verb, summary);
+                    emit_peer(event, &id, &summary);
+                }
+                Some(Err(e)) => eprintln!("Beacon receive error: {}", e),
+                None => break,
+            },
+            _ = sweep.tick() => peers.retain(|id, (seen, summary)| {
+                let alive = seen.elapsed() < expire;
+                if !alive {
+                    say!("Lost {}", summary);
+                    emit_peer("lost", id, summary);
+                }
+                alive
+            }),
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    say!();
+    say!("{} instance(s) seen", peers.len());
+    for (_, summary) in peers.values() {
+        say!("  {}", summary);
+    }
+}
@@ -0,0 +1,300 @@
+// BLAKE3 with the default 32-byte output, following the reference
+// implementation: 1 KiB chunks hashed into a binary tree of chaining values.
+// Portable and unoptimised; it checks tunnel streams, not disks.
+
+const IV: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+const PERMUTATION: [usize; 16] = [2, 6, 3, 10, 7, 0, 4, 13, 1, 11, 12, 5, 9, 14, 15, 8];
+
+const CHUNK_LEN: usize = 1024;
+const BLOCK_LEN: usize = 64;
+
+const CHUNK_START: u32 = 1 << 0;
+const CHUNK_END: u32 = 1 << 1;
+const PARENT: u32 = 1 << 2;
+const ROOT: u32 = 1 << 3;
+
+fn g(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize, x: u32, y: u32) {
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(x);
+    state[d] = (state[d] ^ state[a]).rotate_right(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(12);
+    state[a] = state[a].wrapping_add(state[b]).wrapping_add(y);
+    state[d] = (state[d] ^ state[a]).rotate_right(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_right(7);
+}
+
+fn round(state: &mut [u32; 16], m: &[u32; 16]) {
+    g(state, 0, 4, 8, 12, m[0], m[1]);
+    g(state, 1, 5, 9, 13, m[2], m[3]);
+    g(state, 2, 6, 10, 14, m[4], m[5]);
+    g(state, 3, 7, 11, 15, m[6], m[7]);
+    g(state, 0, 5, 10, 15, m[8], m[9]);
+    g(state, 1, 6, 11, 12, m[10], m[11]);
+    g(state, 2, 7, 8, 13, m[12], m[13]);
+    g(state, 3, 4, 9, 14, m[14], m[15]);
+}
+
+fn compress(cv: &[u32; 8], block: &[u32; 16], counter: u64, len: u32, flags: u32) -> [u32; 16] {
+    let mut state = [
+        cv[0],
+        cv[1],
+        cv[2],
+        cv[3],
+        cv[4],
+        cv[5],
+        cv[6],
+        cv[7],
+        IV[0],
+        IV[1],
+        IV[2],
+        IV[3],
+        counter as u32,
+        (counter >> 32) as u32,
+        len,
+        flags,
+    ];
+    let mut m = *block;
+    for i in 0..7 {
+        round(&mut state, &m);
+        if i < 6 {
+            m = PERMUTATION.map(|p| m[p]);
+        }
+    }
+    for i in 0..8 {
+        state[i] ^= state[i + 8];
+        state[i + 8] ^= cv[i];
+    }
+    state
+}
+
+fn words(bytes: &[u8; BLOCK_LEN]) -> [u32; 16] {
+    std::array::from_fn(|i| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap()))
+}
+
+fn first_8(words: [u32; 16]) -> [u32; 8] {
+    std::array::from_fn(|i| words[i])
+}
+
+// The last compression of a node, kept back until it's known whether the
+// node is the root.
+struct Output {
+    cv: [u32; 8],
+    block: [u32; 16],
+    counter: u64,
+    len: u32,
+    flags: u32,
+}
+
+impl Output {
+    fn chaining_value(&self) -> [u32; 8] {
+        first_8(compress(
+            &self.cv,
+            &self.block,
+            self.counter,
+            self.len,
+            self.flags,
+        ))
+    }
+
+    fn root(&self) -> [u8; 32] {
+        let words = compress(&self.cv, &self.block, 0, self.len, self.flags | ROOT);
+        let mut out = [0; 32];
+        for (i, word) in words[..8].iter().enumerate() {
+            out[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+}
+
+fn parent(left: [u32; 8], right: [u32; 8]) -> Output {
+    let mut block = [0; 16];
+    block[..8].copy_from_slice(&left);
+    block[8..].copy_from_slice(&right);
+    Output {
+        cv: IV,
+        block,
+        counter: 0,
+        len: BLOCK_LEN as u32,
+        flags: PARENT,
+    }
+}
+
+#[derive(Clone)]
+struct Chunk {
+    cv: [u32; 8],
+    counter: u64,
+    block: [u8; BLOCK_LEN],
+    block_len: usize,
+    blocks_compressed: usize,
+}
+
+impl Chunk {
+    fn new(counter: u64) -> Chunk {
+        Chunk {
+            cv: IV,
+            counter,
+            block: [0; BLOCK_LEN],
+            block_len: 0,
+            blocks_compressed: 0,
+        }
+    }
+
+    fn len(&self) -> usize {
+        BLOCK_LEN * self.blocks_compressed + self.block_len
+    }
+
+    fn start_flag(&self) -> u32 {
+        if self.blocks_compressed == 0 {
+            CHUNK_START
+        } else {
+            0
+        }
+    }
+
+    fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            // A full block is only compressed once more input shows it isn't
+            // the chunk's last.
+            if self.block_len == BLOCK_LEN {
+                let flags = self.start_flag();
+                let words = words(&self.block);
+                self.cv = first_8(compress(
+                    &self.cv,
+                    &words,
+                    self.counter,
+                    BLOCK_LEN as u32,
+                    flags,
+                ));
+                self.blocks_compressed += 1;
+                self.block = [0; BLOCK_LEN];
+                self.block_len = 0;
+            }
+            let take = (BLOCK_LEN - self.block_len).min(input.len());
+            self.block[self.block_len..self.block_len + take].copy_from_slice(&input[..take]);
+            self.block_len += take;
+            input = &input[take..];
+        }
+    }
+
+    fn output(&self) -> Output {
+        Output {
+            cv: self.cv,
+            block: words(&self.block),
+            counter: self.counter,
+            len: self.block_len as u32,
+            flags: self.start_flag() | CHUNK_END,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Hasher {
+    chunk: Chunk,
+    // Chaining values of complete subtrees, largest first.
+    stack: Vec<[u32; 8]>,
+}
+
+impl Default for Hasher {
+    fn default() -> Hasher {
+        Hasher::new()
+    }
+}
+
+impl Hasher {
+    pub fn new() -> Hasher {
+        Hasher {
+            chunk: Chunk::new(0),
+            stack: Vec::new(),
+        }
+    }
+
+    // Merges completed subtrees: one merge per trailing zero bit in the
+    // number of chunks so far.
+    fn push_chunk(&mut self, mut cv: [u32; 8], mut chunks: u64) {
+        while chunks & 1 == 0 {
+            cv = parent(self.stack.pop().unwrap(), cv).chaining_value();
+            chunks >>= 1;
+        }
+        self.stack.push(cv);
+    }
+
+    pub fn update(&mut self, mut input: &[u8]) {
+        while !input.is_empty() {
+            if self.chunk.len() == CHUNK_LEN {
+                let cv = self.chunk.output().chaining_value();
+                let chunks = self.chunk.counter + 1;
+                self.push_chunk(cv, chunks);
+                self.chunk = Chunk::new(chunks);
+            }
+            let take = (CHUNK_LEN - self.chunk.len()).min(input.len());
+            self.chunk.update(&input[..take]);
+            input = &input[take..];
+        }
+    }
+
+    pub fn finalize(&self) -> [u8; 32] {
+        let mut output = self.chunk.output();
+        for cv in self.stack.iter().rev() {
+            output = parent(*cv, output.chaining_value());
+        }
+        output.root()
+    }
+}
+
+pub fn hash(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Hasher::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn matches_published_digests() {
+        assert_eq!(
+            hex::encode(&hash(b"")),
+            "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262"
+        );
+        assert_eq!(
+            hex::encode(&hash(b"abc")),
+            "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85"
+        );
+        // From the official test vectors, whose inputs count 0..251 over and
+        // over: one chunk and a bit, and two whole chunks.
+        let input = |len: usize| (0..len).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        assert_eq!(
+            hex::encode(&hash(&input(1025))),
+            "d00278ae47eb27b34faecf67b4fe263f82d5412916c1ffd97c8cb7fb814b8444"
+        );
+        assert_eq!(
+            hex::encode(&hash(&input(2048))),
+            "e776b6028c7cd22a4d0ba182a8bf62205d2ef576467e838ed6f2529b85fba24a"
+        );
+    }
+
+    #[test]
+    fn incremental_updates_match_one_shot() {
+        let mut rng = StdRng::seed_from_u64(163);
+        let data: Vec<u8> = (0..9 * CHUNK_LEN + 17).map(|_| rng.r#gen()).collect();
+        let expected = hash(&data);
+        for _ in 0..20 {
+            let mut hasher = Hasher::new();
+            let mut rest = &data[..];
+            while !rest.is_empty() {
+                let n = rng.gen_range(1..=rest.len().min(3000));
+                hasher.update(&rest[..n]);
+                rest = &rest[n..];
+            }
+            assert_eq!(hasher.finalize(), expected);
+        }
+        assert_ne!(hash(&data[1..]), expected);
+    }
+}
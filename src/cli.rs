@@ -0,0 +1,188 @@
+use crate::exit;
+use crate::output;
+use crate::proxy;
+use crate::schema;
+
+pub struct Opt {
+    pub name: &'static str,
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+}
+
+// A subcommand's usage line and every option it takes, which both its own
+// parsing and the generated help and shell completions read.
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub about: &'static str,
+    pub groups: &'static [&'static [Opt]],
+}
+
+impl Command {
+    // Its own options after the ones every command takes.
+    pub fn options(&self) -> impl Iterator<Item = &'static Opt> + use<> {
+        [HELP, output::OPTS, proxy::OPTS]
+            .into_iter()
+            .chain(self.groups.iter().copied())
+            .flat_map(|opts| opts.iter())
+    }
+}
+
+pub struct Args {
+    positional: Vec<String>,
+    values: Vec<(&'static str, String)>,
+    flags: Vec<&'static str>,
+}
+
+impl Args {
+    pub fn parse<I>(tokens: I, groups: &[&[Opt]]) -> Result<Args, String>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = Args {
+            positional: Vec::new(),
+            values: Vec::new(),
+            flags: Vec::new(),
+        };
+        let mut tokens = tokens.into_iter();
+
+        while let Some(token) = tokens.next() {
+            if !token.starts_with("--") {
+                args.positional.push(token);
+                continue;
+            }
+
+            let (name, inline) = match token.split_once('=') {
+                Some((name, value)) => (name.to_string(), Some(value.to_string())),
+                None => (token, None),
+            };
+
+            let opt = groups
+                .iter()
+                .flat_map(|opts| opts.iter())
+                .find(|opt| opt.name == name)
+                .ok_or_else(|| format!("unknown option '{}'", name))?;
+
+            match (opt.value, inline) {
+                (Some(_), Some(value)) => args.values.push((opt.name, value)),
+                (Some(placeholder), None) => {
+                    let value = tokens
+                        .next()
+                        .ok_or_else(|| format!("option '{}' expects {}", name, placeholder))?;
+                    args.values.push((opt.name, value));
+                }
+                (None, Some(_)) => return Err(format!("option '{}' takes no value", name)),
+                (None, None) => args.flags.push(opt.name),
+            }
+        }
+
+        Ok(args)
+    }
+
+    pub fn value(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .rev()
+            .find(|(opt, _)| *opt == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn values<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.values
+            .iter()
+            .filter(move |(opt, _)| *opt == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.contains(&name)
+    }
+
+    pub fn positional(&self) -> &[String] {
+        &self.positional
+    }
+
+    pub fn parsed<T: std::str::FromStr>(&self, name: &str) -> Result<Option<T>, String> {
+        match self.value(name) {
+            Some(value) => value
+                .parse()
+                .map(Some)
+                .map_err(|_| format!("invalid value '{}' for '{}'", value, name)),
+            None => Ok(None),
+        }
+    }
+
+    // Like parsed, for values that need more than FromStr, such as the
+    // durations and sizes in units.
+    pub fn parsed_with<T>(
+        &self,
+        name: &str,
+        parse: impl FnOnce(&str) -> Result<T, String>,
+    ) -> Result<Option<T>, String> {
+        self.value(name)
+            .map(|value| {
+                parse(value).map_err(|e| format!("invalid value '{}' for '{}': {}", value, name, e))
+            })
+            .transpose()
+    }
+}
+
+pub fn print_usage(command: &str, groups: &[&[Opt]]) {
+    println!("Usage: {} [options]", command);
+    println!();
+    println!("Options:");
+
+    for opt in groups.iter().flat_map(|opts| opts.iter()) {
+        let left = match opt.value {
+            Some(placeholder) => format!("{} {}", opt.name, placeholder),
+            None => opt.name.to_string(),
+        };
+        println!("  {:<28} {}", left, opt.help);
+    }
+}
+
+pub const HELP: &[Opt] = &[Opt {
+    name: "--help",
+    value: None,
+    help: "Print this help",
+}];
+
+pub fn parse_or_exit(command: &str, tokens: Vec<String>, groups: &[&[Opt]]) -> Args {
+    let mut all = vec![HELP];
+    all.extend_from_slice(groups);
+
+    let args = match Args::parse(tokens, &all) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(exit::USAGE);
+        }
+    };
+
+    if args.flag("--help") {
+        print_usage(command, &all);
+        std::process::exit(0);
+    }
+
+    args
+}
+
+pub fn parse_command(command: &Command, tokens: Vec<String>) -> Args {
+    let mut groups = vec![output::OPTS, proxy::OPTS];
+    groups.extend_from_slice(command.groups);
+    let args = parse_or_exit(command.usage, tokens, &groups);
+    if args.flag("--schema") {
+        println!("{}", schema::for_command(command.name));
+        std::process::exit(exit::SUCCESS);
+    }
+    or_exit(output::init(command.name, &args));
+    proxy::init(&args);
+    args
+}
+
+pub fn or_exit<T>(result: Result<T, String>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(exit::USAGE);
+    })
+}
@@ -0,0 +1,359 @@
+// Shell completion scripts and the --help-json command tree, both generated
+// from the same Command table the binary parses with, so they can't fall
+// behind the options that actually exist.
+
+use crate::cli::{self, Command, Opt};
+use crate::exit;
+use crate::json::Value;
+
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish", "powershell"];
+
+pub const COMMAND: Command = Command {
+    name: "completions",
+    usage: "netcore completions <bash|zsh|fish|powershell>",
+    about: "Print a shell completion script",
+    groups: &[],
+};
+
+pub fn help_json(commands: &[&Command]) -> Value {
+    let commands = commands.iter().map(|command| {
+        let options = command.options().map(|opt| {
+            Value::object([
+                ("name", Value::from(opt.name)),
+                ("value", opt.value.map_or(Value::Null, Value::from)),
+                ("help", Value::from(opt.help)),
+            ])
+        });
+        Value::object([
+            ("name", Value::from(command.name)),
+            ("usage", Value::from(command.usage)),
+            ("about", Value::from(command.about)),
+            ("options", Value::Array(options.collect())),
+        ])
+    });
+    let exit_codes = exit::ALL.iter().map(|&(code, name)| {
+        Value::object([
+            ("code", Value::from(code as f64)),
+            ("name", Value::from(name)),
+        ])
+    });
+    Value::object([
+        ("name", Value::from("netcore")),
+        ("commands", Value::Array(commands.collect())),
+        ("exit_codes", Value::Array(exit_codes.collect())),
+    ])
+}
+
+// Quotes text for a single-quoted string in a POSIX shell.
+fn sh_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', r"'\''"))
+}
+
+// Commands are typed as their name, except the default which needs none.
+fn subcommands<'a>(commands: &'a [&'a Command]) -> impl Iterator<Item = &'a &'a Command> {
+    commands.iter().skip(1)
+}
+
+fn bash(commands: &[&Command]) -> String {
+    let names: Vec<&str> = subcommands(commands).map(|c| c.name).collect();
+    let mut cases = String::new();
+    for command in commands {
+        let all: Vec<&str> = command.options().map(|opt| opt.name).collect();
+        let valued: Vec<&str> = command
+            .options()
+            .filter(|opt| opt.value.is_some())
+            .map(|opt| opt.name)
+            .collect();
+        cases.push_str(&format!(
+            "        {})\n            opts=\"{}\"\n            valued=\" {} \"\n            ;;\n",
+            command.name,
+            all.join(" "),
+            valued.join(" ")
+        ));
+    }
+    format!(
+        r#"# bash completion for netcore
+_netcore() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    local command={default}
+    if [ "$COMP_CWORD" -gt 1 ] && [[ "${{COMP_WORDS[1]}}" != -* ]]; then
+        command="${{COMP_WORDS[1]}}"
+    elif [ "$COMP_CWORD" -eq 1 ] && [[ "$cur" != -* ]]; then
+        COMPREPLY=($(compgen -W "{names}" -- "$cur"))
+        return
+    fi
+    local opts valued
+    case "$command" in
+{cases}        *)
+            return
+            ;;
+    esac
+    if [[ "$valued" == *" $prev "* ]]; then
+        COMPREPLY=($(compgen -f -- "$cur"))
+    else
+        COMPREPLY=($(compgen -W "$opts" -- "$cur"))
+    fi
+}}
+complete -o default -F _netcore netcore
+"#,
+        default = commands[0].name,
+        names = names.join(" "),
+        cases = cases,
+    )
+}
+
+fn zsh_spec(opt: &Opt) -> String {
+    let help = opt
+        .help
+        .replace('\\', "\\\\")
+        .replace('[', "\\[")
+        .replace(']', "\\]")
+        .replace(':', "\\:");
+    let spec = match opt.value {
+        Some(value) => format!(
+            "*{}[{}]:{}:_files",
+            opt.name,
+            help,
+            value.replace(':', "\\:")
+        ),
+        None => format!("*{}[{}]", opt.name, help),
+    };
+    sh_quote(&spec)
+}
+
+fn zsh(commands: &[&Command]) -> String {
+    let described: Vec<String> = subcommands(commands)
+        .map(|c| {
+            format!(
+                "        {}",
+                sh_quote(&format!("{}:{}", c.name, c.about.replace(':', "\\:")))
+            )
+        })
+        .collect();
+    let mut cases = String::new();
+    for command in commands {
+        let specs: Vec<String> = command.options().map(zsh_spec).collect();
+        cases.push_str(&format!(
+            "        {})\n            _arguments -s \\\n                {} \\\n                '*:argument:_files'\n            ;;\n",
+            command.name,
+            specs.join(" \\\n                ")
+        ));
+    }
+    format!(
+        r#"#compdef netcore
+_netcore() {{
+    local -a commands
+    commands=(
+{described}
+    )
+    if (( CURRENT == 2 )) && [[ $words[2] != -* ]]; then
+        _describe command commands
+        return
+    fi
+    local command={default}
+    if [[ $words[2] != -* ]]; then
+        command=$words[2]
+        shift words
+        (( CURRENT-- ))
+    fi
+    case $command in
+{cases}    esac
+}}
+_netcore "$@"
+"#,
+        described = described.join("\n"),
+        default = commands[0].name,
+        cases = cases,
+    )
+}
+
+fn fish_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn fish(commands: &[&Command]) -> String {
+    let names: Vec<&str> = subcommands(commands).map(|c| c.name).collect();
+    let mut out = String::from("# fish completion for netcore\n");
+    for command in subcommands(commands) {
+        out.push_str(&format!(
+            "complete -c netcore -n __fish_use_subcommand -a {} -d {}\n",
+            command.name,
+            fish_quote(command.about)
+        ));
+    }
+    for (i, command) in commands.iter().enumerate() {
+        // The default command's options apply until a subcommand is given.
+        let condition = match i {
+            0 => format!("'not __fish_seen_subcommand_from {}'", names.join(" ")),
+            _ => format!("'__fish_seen_subcommand_from {}'", command.name),
+        };
+        for opt in command.options() {
+            let takes_value = if opt.value.is_some() { " -r -F" } else { "" };
+            out.push_str(&format!(
+                "complete -c netcore -n {} -l {}{} -d {}\n",
+                condition,
+                opt.name.trim_start_matches("--"),
+                takes_value,
+                fish_quote(opt.help)
+            ));
+        }
+    }
+    out
+}
+
+fn ps_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "''"))
+}
+
+fn powershell(commands: &[&Command]) -> String {
+    let names: Vec<String> = subcommands(commands)
+        .map(|c| format!("        @({}, {})", ps_quote(c.name), ps_quote(c.about)))
+        .collect();
+    let mut tables = String::new();
+    for command in commands {
+        let opts: Vec<String> = command
+            .options()
+            .map(|opt| format!("@({}, {})", ps_quote(opt.name), ps_quote(opt.help)))
+            .collect();
+        tables.push_str(&format!(
+            "        {} = @({})\n",
+            ps_quote(command.name),
+            opts.join(", ")
+        ));
+    }
+    format!(
+        r#"# PowerShell completion for netcore
+Register-ArgumentCompleter -Native -CommandName netcore -ScriptBlock {{
+    param($wordToComplete, $commandAst, $cursorPosition)
+    $subcommands = @(
+{names}
+    )
+    $options = @{{
+{tables}    }}
+    $words = @($commandAst.CommandElements | Select-Object -Skip 1 | ForEach-Object {{ $_.ToString() }})
+    if ($wordToComplete -ne '') {{
+        $words = @($words | Select-Object -SkipLast 1)
+    }}
+    if ($words.Count -eq 0 -and -not $wordToComplete.StartsWith('-')) {{
+        $candidates = $subcommands
+    }} elseif ($words.Count -gt 0 -and -not $words[0].StartsWith('-')) {{
+        $candidates = $options[$words[0]]
+    }} else {{
+        $candidates = $options[{default}]
+    }}
+    $candidates | Where-Object {{ $_[0] -like "$wordToComplete*" }} | ForEach-Object {{
+        [System.Management.Automation.CompletionResult]::new($_[0], $_[0], 'ParameterValue', $_[1])
+    }}
+}}
+"#,
+        names = names.join("\n"),
+        tables = tables,
+        default = ps_quote(commands[0].name),
+    )
+}
+
+// `commands` starts with the default command, the one run without a name.
+pub fn script(shell: &str, commands: &[&Command]) -> Result<String, String> {
+    match shell {
+        "bash" => Ok(bash(commands)),
+        "zsh" => Ok(zsh(commands)),
+        "fish" => Ok(fish(commands)),
+        "powershell" | "pwsh" => Ok(powershell(commands)),
+        other => Err(format!(
+            "unknown shell '{}', expected {}",
+            other,
+            SHELLS.join(", ")
+        )),
+    }
+}
+
+pub fn command(tokens: Vec<String>, commands: &[&Command]) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let [shell] = args.positional() else {
+        eprintln!("usage: {}", COMMAND.usage);
+        std::process::exit(exit::USAGE);
+    };
+    print!("{}", cli::or_exit(script(shell, commands)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const OPTS: &[Opt] = &[
+        Opt {
+            name: "--port",
+            value: Some("<n>"),
+            help: "Port: the one [to] use",
+        },
+        Opt {
+            name: "--quiet",
+            value: None,
+            help: "Don't print",
+        },
+    ];
+    const SERVE: Command = Command {
+        name: "serve",
+        usage: "netcore",
+        about: "Run the server",
+        groups: &[OPTS],
+    };
+    const PING: Command = Command {
+        name: "ping",
+        usage: "netcore ping <host>",
+        about: "Ping: a host",
+        groups: &[OPTS],
+    };
+
+    #[test]
+    fn every_shell_gets_every_command_and_option() {
+        let commands = [&SERVE, &PING];
+        for shell in SHELLS {
+            let script = script(shell, &commands).unwrap();
+            for needle in ["ping", "port", "quiet"] {
+                assert!(script.contains(needle), "{} lacks {}", shell, needle);
+            }
+        }
+        assert!(
+            script("zsh", &commands)
+                .unwrap()
+                .contains(r"Port\: the one \[to\] use")
+        );
+        assert!(
+            script("fish", &commands)
+                .unwrap()
+                .contains(r"'Don\'t print'")
+        );
+        assert!(
+            script("powershell", &commands)
+                .unwrap()
+                .contains("'Don''t print'")
+        );
+        assert!(
+            script("bash", &commands)
+                .unwrap()
+                .contains("valued=\" --port \"")
+        );
+        assert!(script("tcsh", &commands).is_err());
+
+        let json = help_json(&commands);
+        let ping = &json.get("commands").unwrap().as_array()[1];
+        assert_eq!(ping.get("name").and_then(Value::as_str), Some("ping"));
+        let options = ping.get("options").unwrap().as_array();
+        assert_eq!(
+            options[0].get("name").and_then(Value::as_str),
+            Some("--help")
+        );
+        assert_eq!(
+            options[1].get("name").and_then(Value::as_str),
+            Some("--quiet")
+        );
+        assert_eq!(
+            options[4].get("name").and_then(Value::as_str),
+            Some("--no-proxy")
+        );
+        assert_eq!(options[5].get("value").and_then(Value::as_str), Some("<n>"));
+        assert_eq!(options[6].get("value"), Some(&Value::Null));
+    }
+}
@@ -0,0 +1,371 @@
+// The config file (--config), and NETCORE_* environment variables over it.
+// Each setting is taken from the first of these that gives it:
+//
+//   1. the command line option, where there is one
+//   2. NETCORE_<SECTION>_<KEY>, or NETCORE_<SECTION>_<NAME>_<KEY> for a named
+//      section such as [job backup]
+//   3. the config file
+//   4. the default
+//
+// The first two come out of the code reading each section, which looks at
+// its options before the section. Variables replace every value the file
+// gives a key, and add sections the file lacks, except named ones, whose
+// name can't be told apart from the key. Other NETCORE_ variables, such as
+// the ones `netcore info --format env` writes, are left alone.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::cli::Args;
+
+pub const ENV_PREFIX: &str = "NETCORE_";
+
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+    pub line: usize,
+}
+
+pub struct Section {
+    pub kind: String,
+    pub name: String,
+    pub line: usize,
+    pub entries: Vec<Entry>,
+}
+
+pub struct Config {
+    pub path: PathBuf,
+    pub sections: Vec<Section>,
+    // Lines of the file; entries from variables are numbered after them, and
+    // `env` holds their names in that order.
+    lines: usize,
+    pub env: Vec<String>,
+}
+
+// How a section is named in variables: upper case, with anything but
+// letters and digits as '_'.
+fn env_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+impl Config {
+    // --config, if given, and the environment over it; None if neither sets
+    // anything. `kinds` are the sections a variable may add.
+    pub fn from_args(args: &Args, kinds: &[&str]) -> Result<Option<Config>, String> {
+        let mut config = match args.value("--config") {
+            Some(path) => Config::load(Path::new(path))?,
+            None => Config::parse(Path::new(""), "")?,
+        };
+        let mut vars: Vec<(String, String)> = env::vars().collect();
+        vars.sort();
+        config.apply_env(kinds, vars);
+        let given = args.value("--config").is_some() || !config.env.is_empty();
+        Ok(given.then_some(config))
+    }
+
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        Config::parse(path, &text)
+    }
+
+    pub fn parse(path: &Path, text: &str) -> Result<Config, String> {
+        let mut config = Config {
+            path: path.to_path_buf(),
+            sections: Vec::new(),
+            lines: text.lines().count(),
+            env: Vec::new(),
+        };
+
+        for (index, raw) in text.lines().enumerate() {
+            let line = index + 1;
+            let trimmed = raw.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') || trimmed.starts_with(';') {
+                continue;
+            }
+
+            if let Some(header) = trimmed.strip_prefix('[') {
+                let header = header
+                    .strip_suffix(']')
+                    .ok_or_else(|| config.error(line, "unterminated section header"))?;
+                let mut parts = header.split_whitespace();
+                let kind = parts
+                    .next()
+                    .ok_or_else(|| config.error(line, "empty section header"))?;
+                config.sections.push(Section {
+                    kind: kind.to_string(),
+                    name: parts.collect::<Vec<_>>().join(" "),
+                    line,
+                    entries: Vec::new(),
+                });
+                continue;
+            }
+
+            let (key, value) = trimmed
+                .split_once('=')
+                .ok_or_else(|| config.error(line, "expected key = value"))?;
+            let entry = Entry {
+                key: key.trim().to_string(),
+                value: unquote(value.trim()).to_string(),
+                line,
+            };
+
+            match config.sections.last_mut() {
+                Some(section) => section.entries.push(entry),
+                None => return Err(config.error(line, "key outside of a section")),
+            }
+        }
+
+        Ok(config)
+    }
+
+    // Sets what NETCORE_ variables among `vars` give. The section a variable
+    // belongs to is the one whose name it starts with, the longest such
+    // name, so NETCORE_JOB_BACKUP_SCHEDULE goes to [job backup] when there
+    // is one; the rest of it is the key.
+    pub fn apply_env(&mut self, kinds: &[&str], vars: impl IntoIterator<Item = (String, String)>) {
+        for (var, value) in vars {
+            let Some(rest) = var.strip_prefix(ENV_PREFIX) else {
+                continue;
+            };
+            let found = self
+                .sections
+                .iter()
+                .enumerate()
+                .filter_map(|(index, section)| {
+                    let mut prefix = env_name(&section.kind);
+                    if !section.name.is_empty() {
+                        prefix = format!("{}_{}", prefix, env_name(&section.name));
+                    }
+                    let key = rest.strip_prefix(&prefix)?.strip_prefix('_')?;
+                    (!key.is_empty()).then_some((prefix.len(), index, key))
+                })
+                .max_by_key(|(len, _, _)| *len)
+                .map(|(_, index, key)| (index, key.to_ascii_lowercase()));
+            let (index, key) = match found {
+                Some(found) => found,
+                None => {
+                    let Some((kind, key)) = kinds.iter().find_map(|kind| {
+                        let key = rest.strip_prefix(&env_name(kind))?.strip_prefix('_')?;
+                        (!key.is_empty()).then(|| (kind, key.to_ascii_lowercase()))
+                    }) else {
+                        continue;
+                    };
+                    self.sections.push(Section {
+                        kind: kind.to_string(),
+                        name: String::new(),
+                        line: self.lines + self.env.len() + 1,
+                        entries: Vec::new(),
+                    });
+                    (self.sections.len() - 1, key)
+                }
+            };
+            self.env.push(var);
+            let line = self.lines + self.env.len();
+            let section = &mut self.sections[index];
+            section.entries.retain(|entry| entry.key != key);
+            section.entries.push(Entry { key, value, line });
+        }
+    }
+
+    // Where `line` came from: the file, or the variable set after it.
+    pub fn error(&self, line: usize, message: &str) -> String {
+        match line.checked_sub(self.lines + 1) {
+            Some(var) if var < self.env.len() => format!("{}: {}", self.env[var], message),
+            _ => format!("{}:{}: {}", self.path.display(), line, message),
+        }
+    }
+
+    pub fn sections<'a>(&'a self, kind: &'a str) -> impl Iterator<Item = &'a Section> + 'a {
+        self.sections.iter().filter(move |s| s.kind == kind)
+    }
+
+    // A section nothing reads is most likely a typo, so it's an error rather
+    // than silently ignored.
+    pub fn check_kinds(&self, known: &[&str]) -> Result<(), String> {
+        match self
+            .sections
+            .iter()
+            .find(|section| !known.contains(&section.kind.as_str()))
+        {
+            Some(section) => Err(self.error(
+                section.line,
+                &format!(
+                    "unknown section [{}], expected one of {}",
+                    section.kind,
+                    known.join(", ")
+                ),
+            )),
+            None => Ok(()),
+        }
+    }
+
+    // The settings of one part of netcore, such as [mux]: at most one such
+    // section, holding only the keys it knows.
+    pub fn section(&self, kind: &str, keys: &[&str]) -> Result<Option<&Section>, String> {
+        let mut found = self.sections.iter().filter(|section| section.kind == kind);
+        let Some(section) = found.next() else {
+            return Ok(None);
+        };
+        if let Some(again) = found.next() {
+            return Err(self.error(
+                again.line,
+                &format!("[{}] was already given on line {}", kind, section.line),
+            ));
+        }
+        if let Some(entry) = section
+            .entries
+            .iter()
+            .find(|entry| !keys.contains(&entry.key.as_str()))
+        {
+            return Err(self.error(
+                entry.line,
+                &format!(
+                    "unknown key '{}' in [{}], expected {}",
+                    entry.key,
+                    kind,
+                    keys.join(", ")
+                ),
+            ));
+        }
+        Ok(Some(section))
+    }
+}
+
+impl Section {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.entry(key).map(|e| e.value.as_str())
+    }
+
+    pub fn entry(&self, key: &str) -> Option<&Entry> {
+        self.entries.iter().rev().find(|e| e.key == key)
+    }
+
+    pub fn require(&self, config: &Config, key: &str) -> Result<&str, String> {
+        self.get(key).ok_or_else(|| {
+            config.error(
+                self.line,
+                &format!("[{} {}] is missing '{}'", self.kind, self.name, key),
+            )
+        })
+    }
+
+    pub fn parsed<T: std::str::FromStr>(
+        &self,
+        config: &Config,
+        key: &str,
+    ) -> Result<Option<T>, String> {
+        match self.entry(key) {
+            Some(entry) => entry.value.parse().map(Some).map_err(|_| {
+                config.error(
+                    entry.line,
+                    &format!("invalid value '{}' for '{}'", entry.value, key),
+                )
+            }),
+            None => Ok(None),
+        }
+    }
+
+    pub fn parsed_with<T>(
+        &self,
+        config: &Config,
+        key: &str,
+        parse: impl FnOnce(&str) -> Result<T, String>,
+    ) -> Result<Option<T>, String> {
+        self.entry(key)
+            .map(|entry| entry.parsed_with(config, parse))
+            .transpose()
+    }
+
+    // Every value of a key that may be given more than once, in order.
+    pub fn parsed_all_with<T>(
+        &self,
+        config: &Config,
+        key: &str,
+        parse: impl Fn(&str) -> Result<T, String>,
+    ) -> Result<Vec<T>, String> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.key == key)
+            .map(|entry| entry.parsed_with(config, &parse))
+            .collect()
+    }
+}
+
+impl Entry {
+    fn parsed_with<T>(
+        &self,
+        config: &Config,
+        parse: impl FnOnce(&str) -> Result<T, String>,
+    ) -> Result<T, String> {
+        parse(&self.value).map_err(|e| {
+            config.error(
+                self.line,
+                &format!("invalid value '{}' for '{}': {}", self.value, self.key, e),
+            )
+        })
+    }
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|v| v.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variables_override_the_file_and_name_themselves_in_errors() {
+        let text = "[echo]\nbuffer = 4K\n\n[job nightly-backup]\nschedule = daily\n";
+        let mut config = Config::parse(Path::new("netcore.conf"), text).unwrap();
+        let vars = [
+            ("NETCORE_ECHO_BUFFER", "lots"),
+            ("NETCORE_JOB_NIGHTLY_BACKUP_SCHEDULE", "hourly"),
+            ("NETCORE_MUX_SNIFF_TIMEOUT", "500"),
+            ("NETCORE_ALERT_MESSAGE", "disk full"),
+            ("NETCORE_PUBLIC_IPV4", "203.0.113.7"),
+            ("HOME", "/root"),
+        ];
+        config.apply_env(
+            &["echo", "mux"],
+            vars.map(|(var, value)| (var.to_string(), value.to_string())),
+        );
+
+        let echo = config.section("echo", &["buffer"]).unwrap().unwrap();
+        assert_eq!(echo.entries.len(), 1);
+        let error = echo
+            .parsed::<u8>(&config, "buffer")
+            .map(|_| ())
+            .unwrap_err();
+        assert_eq!(
+            error,
+            "NETCORE_ECHO_BUFFER: invalid value 'lots' for 'buffer'"
+        );
+        assert_eq!(echo.line, 1);
+
+        let job = config.sections("job").next().unwrap();
+        assert_eq!(job.get("schedule"), Some("hourly"));
+        let mux = config.section("mux", &["sniff_timeout"]).unwrap().unwrap();
+        assert_eq!(mux.get("sniff_timeout"), Some("500"));
+        assert_eq!(
+            config.error(mux.line, "bad"),
+            "NETCORE_MUX_SNIFF_TIMEOUT: bad"
+        );
+        assert_eq!(config.sections.len(), 3);
+        assert_eq!(config.env.len(), 3);
+        assert_eq!(config.error(5, "bad"), "netcore.conf:5: bad");
+    }
+}
@@ -0,0 +1,190 @@
+// Whether netcore runs in a container, and how the container is networked.
+// On a bridge network the local address is the container's own, private to
+// the host, and the gateway is the bridge on the host; with host
+// networking the addresses are the host's. Without saying which, `info`
+// run in a container reads as the host's addresses when it isn't.
+//
+// Runtimes leave marker files, name themselves in the container variable,
+// or show up in the cgroup and mount paths; none of these is set for
+// certain, so each is tried in turn.
+
+use std::fs;
+use std::net::IpAddr;
+
+use crate::gateway;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Network {
+    // The host's own interfaces, as with --network host.
+    Host,
+    // A private address behind a bridge or overlay on the host.
+    Bridge,
+    // Nothing but loopback, as with --network none.
+    Isolated,
+}
+
+impl Network {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Network::Host => "host",
+            Network::Bridge => "bridge",
+            Network::Isolated => "none",
+        }
+    }
+}
+
+pub struct Container {
+    pub runtime: &'static str,
+    pub network: Network,
+    // The bridge's address on a bridge network: the host, as seen from
+    // inside.
+    pub gateway: Option<IpAddr>,
+}
+
+// The runtime named by marker files, the container variable, cgroups or
+// mounts, most specific first.
+fn runtime(
+    markers: &[&str],
+    variable: Option<&str>,
+    cgroup: &str,
+    mountinfo: &str,
+) -> Option<&'static str> {
+    // Only the mounts a runtime sets up for the container itself: on a host
+    // running containers, theirs are all listed too.
+    let own_mounts = mountinfo.lines().filter(|line| {
+        let mount_point = line.split_whitespace().nth(4);
+        matches!(
+            mount_point,
+            Some("/" | "/etc/hostname" | "/etc/resolv.conf")
+        )
+    });
+    let paths = own_mounts.fold(cgroup.to_string(), |paths, line| paths + "\n" + line);
+    if paths.contains("kubepods") || paths.contains("/kubelet/pods/") {
+        return Some("kubernetes");
+    }
+    if markers.contains(&"/run/.containerenv") || paths.contains("libpod") {
+        return Some("podman");
+    }
+    if markers.contains(&"/.dockerenv") || paths.contains("/docker/") || paths.contains("docker-") {
+        return Some("docker");
+    }
+    match variable {
+        Some("podman") => return Some("podman"),
+        Some("lxc" | "lxc-libvirt") => return Some("lxc"),
+        Some("systemd-nspawn") => return Some("systemd-nspawn"),
+        Some("docker") => return Some("docker"),
+        Some(_) => return Some("container"),
+        None => {}
+    }
+    if paths.contains("containerd") {
+        return Some("containerd");
+    }
+    if paths.contains("/lxc/") || paths.contains("lxc.payload") {
+        return Some("lxc");
+    }
+    None
+}
+
+// Host networking shows the host's bridges and container veths; a
+// container's own namespace has only its end of one.
+fn network(interfaces: &[String]) -> Network {
+    let host_side = |name: &str| {
+        name == "docker0"
+            || name == "cni0"
+            || name == "podman0"
+            || name.starts_with("br-")
+            || name.starts_with("veth")
+    };
+    if interfaces.iter().any(|name| host_side(name)) {
+        Network::Host
+    } else if interfaces.iter().all(|name| name == "lo") {
+        Network::Isolated
+    } else {
+        Network::Bridge
+    }
+}
+
+fn interfaces() -> Vec<String> {
+    fs::read_dir("/sys/class/net")
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+pub fn detect() -> Option<Container> {
+    let markers: Vec<&str> = ["/.dockerenv", "/run/.containerenv"]
+        .into_iter()
+        .filter(|path| fs::metadata(path).is_ok())
+        .collect();
+    let variable = std::env::var("container").ok();
+    let read = |path| fs::read_to_string(path).unwrap_or_default();
+    let runtime = runtime(
+        &markers,
+        variable.as_deref(),
+        &read("/proc/1/cgroup"),
+        &read("/proc/self/mountinfo"),
+    )?;
+
+    let network = network(&interfaces());
+    let gateway = match network {
+        Network::Bridge => gateway::default_ipv4()
+            .or_else(gateway::default_ipv6)
+            .map(|gateway| gateway.addr),
+        _ => None,
+    };
+    Some(Container {
+        runtime,
+        network,
+        gateway,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_runtimes_and_network_modes() {
+        assert_eq!(runtime(&[], None, "0::/\n", ""), None);
+        assert_eq!(
+            runtime(&["/.dockerenv"], None, "0::/\n", ""),
+            Some("docker")
+        );
+        assert_eq!(
+            runtime(&[], None, "12:pids:/kubepods/besteffort/pod1/abc\n", ""),
+            Some("kubernetes")
+        );
+        assert_eq!(
+            runtime(
+                &[],
+                None,
+                "0::/\n",
+                "612 590 8:1 /var/lib/docker/containers/abc/hostname /etc/hostname rw - ext4 /dev/sda1 rw\n"
+            ),
+            Some("docker")
+        );
+        assert_eq!(runtime(&[], Some("podman"), "0::/\n", ""), Some("podman"));
+        // A host with containers running sees their mounts, but not as its
+        // own.
+        assert_eq!(
+            runtime(
+                &[],
+                None,
+                "0::/\n",
+                "700 25 0:50 / /var/lib/docker/overlay2/x/merged rw - overlay overlay rw\n"
+            ),
+            None
+        );
+
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert_eq!(network(&names(&["lo", "eth0"])), Network::Bridge);
+        assert_eq!(
+            network(&names(&["lo", "eth0", "docker0", "veth12ab"])),
+            Network::Host
+        );
+        assert_eq!(network(&names(&["lo"])), Network::Isolated);
+    }
+}
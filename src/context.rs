@@ -0,0 +1,99 @@
+use std::net::SocketAddr;
+use std::time::Instant;
+use tokio::net::TcpStream;
+
+use crate::mux;
+use crate::stats::Tracker;
+
+// What a ClientHello offered, parsed once when the connection is sniffed.
+#[derive(Clone, Debug, Default)]
+pub struct TlsInfo {
+    pub sni: Option<String>,
+    pub alpn: Vec<String>,
+    pub ja3: Option<String>,
+}
+
+impl TlsInfo {
+    pub fn from_client_hello(prefix: &[u8]) -> Option<TlsInfo> {
+        Some(TlsInfo {
+            alpn: mux::client_hello_alpn(prefix)?,
+            sni: mux::client_hello_sni(prefix),
+            ja3: mux::client_hello_ja3(prefix),
+        })
+    }
+}
+
+// Who a verified client certificate says the client is.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ClientIdentity {
+    pub subject: String,
+    pub issuer: String,
+}
+
+// Everything known about an accepted connection, handed from the accept
+// loop through routing to the handler that finally serves it.
+#[derive(Clone, Debug)]
+pub struct ConnContext {
+    pub peer: SocketAddr,
+    pub local: Option<SocketAddr>,
+    pub started: Instant,
+    pub tls: Option<TlsInfo>,
+    pub client: Option<ClientIdentity>,
+    // Whatever earlier layers decided, e.g. the sniffed protocol; recorded
+    // on the connection's stats and trace span.
+    pub labels: Vec<(&'static str, String)>,
+}
+
+impl ConnContext {
+    pub fn new(peer: SocketAddr) -> ConnContext {
+        ConnContext {
+            peer,
+            local: None,
+            started: Instant::now(),
+            tls: None,
+            client: None,
+            labels: Vec::new(),
+        }
+    }
+
+    pub fn accepted(socket: &TcpStream, peer: SocketAddr) -> ConnContext {
+        ConnContext {
+            local: socket.local_addr().ok(),
+            ..ConnContext::new(peer)
+        }
+    }
+
+    pub fn label(&mut self, key: &'static str, value: impl ToString) {
+        self.labels.push((key, value.to_string()));
+    }
+
+    // For handlers that only learn who the client is after accepting, such
+    // as the reverse proxy, which checks certificates with each request.
+    pub fn identify(&mut self, client: ClientIdentity, tracker: &mut Tracker) {
+        tracker.attr("netcore.tls.client", client.subject.as_str());
+        tracker.attr("netcore.tls.client.issuer", client.issuer.as_str());
+        self.client = Some(client);
+    }
+
+    pub fn record(&self, tracker: &mut Tracker) {
+        if let Some(local) = self.local {
+            tracker.local(local);
+            tracker.attr("netcore.local", local.to_string());
+        }
+        for (key, value) in &self.labels {
+            tracker.attr(key, value.as_str());
+        }
+        if let Some(tls) = &self.tls {
+            if let Some(sni) = &tls.sni {
+                tracker.attr("netcore.tls.sni", sni.as_str());
+            }
+            if !tls.alpn.is_empty() {
+                tracker.attr("netcore.tls.alpn", tls.alpn.join(","));
+            }
+        }
+        if let Some(client) = &self.client {
+            tracker.attr("netcore.tls.client", client.subject.as_str());
+            tracker.attr("netcore.tls.client.issuer", client.issuer.as_str());
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use std::env;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+
+use tokio::time::Duration;
+
+use crate::audit;
+use crate::auth::Role;
+use crate::cli::{self, Args, Command, Opt};
+use crate::exit;
+use crate::flows;
+use crate::json::{self, Value};
+use crate::measure::{self, Sample};
+use crate::outbound::OutboundConfig;
+use crate::output;
+use crate::say;
+use crate::scan::{self, PortResult};
+use crate::stats;
+
+const MAX_PING_COUNT: u64 = 20;
+const PING_INTERVAL: Duration = Duration::from_millis(200);
+
+pub const OPTS: &[Opt] = &[Opt {
+    name: "--control-socket",
+    value: Some("<path>"),
+    help: "Control socket path (default: $XDG_RUNTIME_DIR/netcore/netcore.sock)",
+}];
+
+pub const SERVE_OPTS: &[Opt] = &[Opt {
+    name: "--no-control",
+    value: None,
+    help: "Do not listen on the control socket",
+}];
+
+#[cfg(unix)]
+fn uid() -> u32 {
+    unsafe { libc::getuid() }
+}
+
+// The socket lives in a directory only its user can enter: netcore/ in the
+// runtime directory, or netcore-<uid>/ in the temporary directory where
+// there is none. Anyone can create names in /tmp, so that directory is
+// checked as well as made.
+pub fn default_path() -> PathBuf {
+    let dir = match env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) => PathBuf::from(dir).join("netcore"),
+        #[cfg(unix)]
+        None => env::temp_dir().join(format!("netcore-{}", uid())),
+        #[cfg(not(unix))]
+        None => {
+            let user = env::var("USER").unwrap_or_else(|_| "default".to_string());
+            env::temp_dir().join(format!("netcore-{}", user))
+        }
+    };
+    dir.join("netcore.sock")
+}
+
+// `dir`, made if `create` and missing, as long as it's a directory (not a
+// link to one) owned by this user and closed to everyone else.
+#[cfg(unix)]
+fn private_dir(dir: &Path, create: bool) -> Result<(), String> {
+    use std::fs::{self, DirBuilder};
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    if create
+        && let Err(e) = DirBuilder::new().mode(0o700).create(dir)
+        && e.kind() != std::io::ErrorKind::AlreadyExists
+    {
+        return Err(format!("cannot create {}: {}", dir.display(), e));
+    }
+    let metadata =
+        fs::symlink_metadata(dir).map_err(|e| format!("cannot use {}: {}", dir.display(), e))?;
+    if !metadata.is_dir() || metadata.uid() != uid() || metadata.mode() & 0o077 != 0 {
+        return Err(format!(
+            "{} is not a directory private to this user, so its control socket isn't used",
+            dir.display()
+        ));
+    }
+    Ok(())
+}
+
+// Makes way for a new socket at `path`: nothing is there, or a socket of
+// this user's that nobody answers on, which is removed. Anything else,
+// including a link, is left alone.
+#[cfg(unix)]
+fn clear_stale(path: &Path, answering: bool) -> Result<(), String> {
+    use std::fs;
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("cannot use {}: {}", path.display(), e)),
+    };
+    if !metadata.file_type().is_socket() || metadata.uid() != uid() {
+        return Err(format!(
+            "{} exists and is not a socket of this user's, so it is left alone",
+            path.display()
+        ));
+    }
+    if answering {
+        return Err(format!(
+            "Control socket {} is in use by another instance",
+            path.display()
+        ));
+    }
+    fs::remove_file(path).map_err(|e| format!("cannot remove {}: {}", path.display(), e))
+}
+
+pub fn path_from_args(args: &Args) -> PathBuf {
+    args.value("--control-socket")
+        .map(PathBuf::from)
+        .unwrap_or_else(default_path)
+}
+
+pub fn required_role(command: &str) -> Role {
+    match command {
+        "kill" | "check" | "ping" | "scan" => Role::Admin,
+        _ => Role::Read,
+    }
+}
+
+pub async fn dispatch(request: &Value, outbound: &OutboundConfig) -> Value {
+    let command = request.get("command").and_then(Value::as_str);
+    let result = match command {
+        Some("status") => Ok(Value::object([
+            ("ok", Value::from(true)),
+            ("version", Value::from(env!("CARGO_PKG_VERSION"))),
+        ])),
+        Some("stats") => Ok(stats::snapshot()),
+        Some("connections") => Ok(stats::snapshot()
+            .get("connections")
+            .cloned()
+            .unwrap_or(Value::Array(Vec::new()))),
+        Some("flows") => Ok(flows::flows_json(limit(request))),
+        Some("talkers") => Ok(flows::talkers_json(limit(request))),
+        Some("kill") => kill(request),
+        Some("info") => {
+            let info = crate::get_host_info().await;
+            Ok(samples(&measure::host_info_samples(&info)))
+        }
+        Some("check") => Ok(samples(&measure::check(outbound).await)),
+        Some("ping") => ping(request, outbound).await,
+        Some("scan") => scan(request, outbound).await,
+        Some(other) => Err(format!("unknown command '{}'", other)),
+        None => Err("missing 'command'".to_string()),
+    };
+
+    if let Some(command) = command.filter(|command| required_role(command) == Role::Admin) {
+        let error = result.as_ref().err().map(String::as_str);
+        audit::admin("control", None, command, request.clone(), error);
+    }
+    result.unwrap_or_else(|e| Value::object([("error", Value::from(e))]))
+}
+
+fn samples(samples: &[Sample]) -> Value {
+    Value::Array(samples.iter().map(Sample::to_json).collect())
+}
+
+fn limit(request: &Value) -> usize {
+    request
+        .get("limit")
+        .and_then(Value::as_u64)
+        .map_or(flows::DEFAULT_LIMIT, |limit| limit as usize)
+}
+
+fn kill(request: &Value) -> Result<Value, String> {
+    let id = request
+        .get("id")
+        .and_then(Value::as_u64)
+        .ok_or("kill requires a numeric 'id'")?;
+
+    if stats::kill(id) {
+        Ok(Value::object([("killed", Value::from(id))]))
+    } else {
+        Err(format!("no active connection with id {}", id))
+    }
+}
+
+async fn ping(request: &Value, outbound: &OutboundConfig) -> Result<Value, String> {
+    let target = request
+        .get("target")
+        .and_then(Value::as_str)
+        .ok_or("ping requires a 'target'")?;
+    let count = request
+        .get("count")
+        .and_then(Value::as_u64)
+        .unwrap_or(4)
+        .min(MAX_PING_COUNT) as u32;
+
+    let addrs = outbound
+        .resolve(target)
+        .await
+        .map_err(|e| format!("failed to resolve {}: {}", target, e))?;
+    let results = measure::ping(outbound, addrs[0], count, PING_INTERVAL, |_, _| {}).await;
+
+    Ok(samples(&measure::ping_samples(target, &results)))
+}
+
+async fn scan(request: &Value, outbound: &OutboundConfig) -> Result<Value, String> {
+    let target = request
+        .get("target")
+        .and_then(Value::as_str)
+        .ok_or("scan requires a 'target'")?;
+    let addr = match target.parse::<IpAddr>() {
+        Ok(addr) => addr,
+        Err(_) => outbound
+            .resolve(&format!("{}:0", target))
+            .await
+            .map_err(|e| format!("failed to resolve {}: {}", target, e))?[0]
+            .ip(),
+    };
+    let ports = request.get("ports").and_then(Value::as_str);
+    let results = scan::host_ports(outbound, addr, ports).await?;
+
+    Ok(Value::Array(
+        results.iter().map(PortResult::to_json).collect(),
+    ))
+}
+
+#[cfg(unix)]
+pub async fn serve(path: PathBuf, outbound: OutboundConfig) {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::{UnixListener, UnixStream};
+
+    if path == default_path()
+        && let Some(dir) = path.parent()
+        && let Err(e) = private_dir(dir, true)
+    {
+        eprintln!("{}", e);
+        return;
+    }
+    let answering = UnixStream::connect(&path).await.is_ok();
+    if let Err(e) = clear_stale(&path, answering) {
+        eprintln!("{}", e);
+        return;
+    }
+
+    // Created 0600 rather than changed to it after, so there's no moment
+    // another user could connect.
+    let umask = unsafe { libc::umask(0o177) };
+    let listener = UnixListener::bind(&path);
+    unsafe { libc::umask(umask) };
+    let listener = match listener {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to bind control socket {}: {}", path.display(), e);
+            return;
+        }
+    };
+    say!("Control socket listening on {}", path.display());
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                eprintln!("Control socket accept error: {}", e);
+                continue;
+            }
+        };
+
+        let outbound = outbound.clone();
+        tokio::spawn(async move {
+            let (reader, mut writer) = stream.into_split();
+            let mut lines = BufReader::new(reader).lines();
+
+            while let Ok(Some(line)) = lines.next_line().await {
+                let response = match json::parse(&line) {
+                    Ok(request) => dispatch(&request, &outbound).await,
+                    Err(e) => Value::object([("error", Value::from(e))]),
+                };
+                if writer
+                    .write_all(format!("{}\n", response).as_bytes())
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn serve(path: PathBuf, _outbound: OutboundConfig) {
+    eprintln!(
+        "Control socket {} is not supported on this platform",
+        path.display()
+    );
+}
+
+#[cfg(unix)]
+pub async fn request(path: &Path, request: &Value) -> Result<Value, exit::Error> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::UnixStream;
+
+    // Someone else's directory at the default path could hold a socket
+    // that collects whatever is sent to it.
+    if path == default_path()
+        && let Some(dir) = path.parent()
+        && dir.exists()
+    {
+        private_dir(dir, false)?;
+    }
+    let stream = UnixStream::connect(path)
+        .await
+        .map_err(|e| exit::Error::io(format!("cannot connect to {}", path.display()), e))?;
+    let (reader, mut writer) = stream.into_split();
+
+    writer
+        .write_all(format!("{}\n", request).as_bytes())
+        .await?;
+
+    let mut line = String::new();
+    BufReader::new(reader).read_line(&mut line).await?;
+
+    let response = json::parse(&line)?;
+    match response.get("error").and_then(Value::as_str) {
+        Some(error) => Err(error.into()),
+        None => Ok(response),
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn request(path: &Path, _request: &Value) -> Result<Value, exit::Error> {
+    Err(exit::Error::from(format!(
+        "control socket {} is not supported on this platform",
+        path.display()
+    )))
+}
+
+pub const COMMAND: Command = Command {
+    name: "ctl",
+    usage: "netcore ctl <status|stats|connections|flows|talkers|kill <id>|info|check|ping <host:port>|scan <host>>",
+    about: "Query or control a running server",
+    groups: &[OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+
+    let positional = args.positional();
+    let Some(name) = positional.first() else {
+        eprintln!("ctl requires a command");
+        std::process::exit(exit::USAGE);
+    };
+
+    let mut fields = vec![("command", Value::from(name.as_str()))];
+    match (name.as_str(), positional.get(1)) {
+        ("kill", Some(id)) => fields.push((
+            "id",
+            Value::from(cli::or_exit(
+                id.parse::<u64>()
+                    .map_err(|_| format!("invalid connection id '{}'", id)),
+            )),
+        )),
+        ("ping" | "scan", Some(target)) => fields.push(("target", Value::from(target.as_str()))),
+        ("kill" | "ping" | "scan", None) => {
+            eprintln!("ctl {} requires an argument", name);
+            std::process::exit(exit::USAGE);
+        }
+        _ => {}
+    }
+
+    match request(&path_from_args(&args), &Value::object(fields)).await {
+        Ok(response) => {
+            say!("{}", response);
+            output::emit(Value::object([("response", response)]));
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(exit::code(&e));
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn only_private_directories_and_stale_own_sockets_are_used() {
+        let base = env::temp_dir().join(format!("netcore-control-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(&base).unwrap();
+
+        let dir = base.join("private");
+        private_dir(&dir, true).unwrap();
+        assert_eq!(
+            fs::metadata(&dir).unwrap().permissions().mode() & 0o777,
+            0o700
+        );
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(private_dir(&dir, true).is_err());
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+        let link = base.join("link");
+        std::os::unix::fs::symlink(&dir, &link).unwrap();
+        assert!(private_dir(&link, true).is_err());
+
+        let path = dir.join("netcore.sock");
+        assert_eq!(clear_stale(&path, false), Ok(()));
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(clear_stale(&path, true).is_err());
+        assert!(path.exists());
+        assert_eq!(clear_stale(&path, false), Ok(()));
+        assert!(!path.exists());
+
+        // Never a file or a link, whatever they point at.
+        let file = dir.join("file");
+        fs::write(&file, "keep").unwrap();
+        assert!(clear_stale(&file, false).is_err());
+        let link = dir.join("link.sock");
+        std::os::unix::fs::symlink(&file, &link).unwrap();
+        assert!(clear_stale(&link, false).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep");
+        fs::remove_dir_all(&base).unwrap();
+    }
+}
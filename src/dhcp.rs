@@ -0,0 +1,311 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant, timeout_at};
+
+use crate::cli::{self, Command, Opt};
+use crate::exit;
+use crate::json::Value;
+use crate::output;
+use crate::say;
+use crate::units::{self, SECS};
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const LISTEN_SECS: u64 = 5;
+
+const OPTION_SUBNET_MASK: u8 = 1;
+const OPTION_ROUTER: u8 = 3;
+const OPTION_DNS: u8 = 6;
+const OPTION_DOMAIN: u8 = 15;
+const OPTION_LEASE_TIME: u8 = 51;
+const OPTION_MESSAGE_TYPE: u8 = 53;
+const OPTION_SERVER_ID: u8 = 54;
+const OPTION_PARAMETERS: u8 = 55;
+const OPTION_END: u8 = 255;
+
+const DHCP_DISCOVER: u8 = 1;
+const DHCP_OFFER: u8 = 2;
+
+const PROBE_OPTS: &[Opt] = &[
+    Opt {
+        name: "--interface",
+        value: Some("<name>"),
+        help: "Send the discover on this interface",
+    },
+    Opt {
+        name: "--timeout",
+        value: Some("<duration>"),
+        help: "How long to collect offers (default: 5s)",
+    },
+];
+
+pub struct Offer {
+    from: SocketAddr,
+    address: Ipv4Addr,
+    options: Vec<(u8, Vec<u8>)>,
+}
+
+impl Offer {
+    fn option(&self, code: u8) -> Option<&[u8]> {
+        self.options
+            .iter()
+            .find(|(c, _)| *c == code)
+            .map(|(_, v)| v.as_slice())
+    }
+
+    fn addresses(&self, code: u8) -> Vec<Ipv4Addr> {
+        self.option(code)
+            .unwrap_or_default()
+            .chunks_exact(4)
+            .map(|b| Ipv4Addr::new(b[0], b[1], b[2], b[3]))
+            .collect()
+    }
+
+    fn server_id(&self) -> Option<Ipv4Addr> {
+        self.addresses(OPTION_SERVER_ID).first().copied()
+    }
+
+    fn to_json(&self) -> Value {
+        let addresses = |code| {
+            let addresses: Vec<String> = self
+                .addresses(code)
+                .iter()
+                .map(Ipv4Addr::to_string)
+                .collect();
+            Value::from(addresses)
+        };
+        Value::object([
+            ("from", Value::from(self.from.ip().to_string())),
+            (
+                "server_id",
+                Value::from(self.server_id().map(|id| id.to_string())),
+            ),
+            ("address", Value::from(self.address.to_string())),
+            ("subnet_mask", addresses(OPTION_SUBNET_MASK)),
+            ("router", addresses(OPTION_ROUTER)),
+            ("dns", addresses(OPTION_DNS)),
+        ])
+    }
+}
+
+fn discover(xid: u32, mac: [u8; 6]) -> Vec<u8> {
+    let mut packet = vec![0; 236];
+    packet[0] = 1;
+    packet[1] = 1;
+    packet[2] = 6;
+    packet[4..8].copy_from_slice(&xid.to_be_bytes());
+    packet[10] = 0x80;
+    packet[28..34].copy_from_slice(&mac);
+
+    packet.extend_from_slice(&MAGIC_COOKIE);
+    packet.extend_from_slice(&[OPTION_MESSAGE_TYPE, 1, DHCP_DISCOVER]);
+    packet.extend_from_slice(&[
+        OPTION_PARAMETERS,
+        5,
+        OPTION_SUBNET_MASK,
+        OPTION_ROUTER,
+        OPTION_DNS,
+        OPTION_DOMAIN,
+        OPTION_LEASE_TIME,
+    ]);
+    packet.push(OPTION_END);
+    packet.resize(300, 0);
+    packet
+}
+
+pub fn parse_offer(packet: &[u8], xid: u32, from: SocketAddr) -> Option<Offer> {
+    if packet.len() < 240 || packet[0] != 2 || packet[4..8] != xid.to_be_bytes() {
+        return None;
+    }
+    if packet[236..240] != MAGIC_COOKIE {
+        return None;
+    }
+
+    let mut options = Vec::new();
+    let mut rest = &packet[240..];
+    while let Some((&code, tail)) = rest.split_first() {
+        match code {
+            0 => rest = tail,
+            OPTION_END => break,
+            _ => {
+                let (&len, tail) = tail.split_first()?;
+                let value = tail.get(..len as usize)?;
+                options.push((code, value.to_vec()));
+                rest = &tail[len as usize..];
+            }
+        }
+    }
+
+    let offer = Offer {
+        from,
+        address: Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]),
+        options,
+    };
+    (offer.option(OPTION_MESSAGE_TYPE) == Some(&[DHCP_OFFER])).then_some(offer)
+}
+
+fn bind(interface: Option<&str>) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    socket.set_broadcast(true)?;
+    if let Some(interface) = interface {
+        bind_device(&socket, interface)?;
+    }
+    socket.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, CLIENT_PORT).into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &Socket, interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("cannot bind to interface {} on this platform", interface),
+    ))
+}
+
+fn join(addresses: &[Ipv4Addr]) -> String {
+    addresses
+        .iter()
+        .map(Ipv4Addr::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn print_offer(offer: &Offer) {
+    match offer.server_id() {
+        Some(id) => say!("Offer from {} (server id {})", offer.from.ip(), id),
+        None => say!("Offer from {} (no server id)", offer.from.ip()),
+    }
+    say!("  offered address  {}", offer.address);
+
+    for (code, label) in [
+        (OPTION_SUBNET_MASK, "subnet mask"),
+        (OPTION_ROUTER, "router"),
+        (OPTION_DNS, "dns"),
+    ] {
+        let addresses = offer.addresses(code);
+        if !addresses.is_empty() {
+            say!("  {:<16} {}", label, join(&addresses));
+        }
+    }
+    if let Some(domain) = offer.option(OPTION_DOMAIN) {
+        say!("  {:<16} {}", "domain", String::from_utf8_lossy(domain));
+    }
+    if let Some(&[a, b, c, d]) = offer.option(OPTION_LEASE_TIME) {
+        say!("  {:<16} {} s", "lease", u32::from_be_bytes([a, b, c, d]));
+    }
+
+    let other: Vec<String> = offer
+        .options
+        .iter()
+        .map(|(code, _)| *code)
+        .filter(|code| {
+            ![
+                OPTION_SUBNET_MASK,
+                OPTION_ROUTER,
+                OPTION_DNS,
+                OPTION_DOMAIN,
+                OPTION_LEASE_TIME,
+                OPTION_MESSAGE_TYPE,
+                OPTION_SERVER_ID,
+            ]
+            .contains(code)
+        })
+        .map(|code| code.to_string())
+        .collect();
+    if !other.is_empty() {
+        say!("  {:<16} {}", "other options", other.join(", "));
+    }
+}
+
+pub const PROBE_COMMAND: Command = Command {
+    name: "dhcp-probe",
+    usage: "netcore dhcp-probe",
+    about: "Broadcast a DHCP discover and list the servers that offer",
+    groups: &[PROBE_OPTS],
+};
+
+pub async fn probe_command(tokens: Vec<String>) {
+    let args = cli::parse_command(&PROBE_COMMAND, tokens);
+    let listen = cli::or_exit(args.parsed_with("--timeout", |v| units::duration(v, SECS)))
+        .unwrap_or(Duration::from_secs(LISTEN_SECS));
+
+    let socket = match bind(args.value("--interface")) {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!(
+                "Cannot bind DHCP client port {}: {} (needs root or CAP_NET_BIND_SERVICE, and no running DHCP client on the port)",
+                CLIENT_PORT, e
+            );
+            std::process::exit(exit::code(&e));
+        }
+    };
+
+    let xid = rand::random::<u32>();
+    let mut mac: [u8; 6] = rand::random();
+    mac[0] = (mac[0] & 0xfe) | 0x02;
+
+    let target = SocketAddrV4::new(Ipv4Addr::BROADCAST, SERVER_PORT);
+    if let Err(e) = socket.send_to(&discover(xid, mac), target).await {
+        eprintln!("Failed to broadcast DHCPDISCOVER: {}", e);
+        std::process::exit(exit::code(&e));
+    }
+    say!(
+        "Sent DHCPDISCOVER (xid {:08x}), collecting offers for {} s",
+        xid,
+        listen.as_secs()
+    );
+
+    let deadline = Instant::now() + listen;
+    let mut offers: Vec<Offer> = Vec::new();
+    let mut buf = [0; 1500];
+    while let Ok(Ok((n, from))) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        if let Some(offer) = parse_offer(&buf[..n], xid, from)
+            && !offers
+                .iter()
+                .any(|o| o.server_id() == offer.server_id() && o.from == offer.from)
+        {
+            offers.push(offer);
+        }
+    }
+
+    say!();
+    for offer in &offers {
+        print_offer(offer);
+        output::emit(offer.to_json());
+    }
+
+    match offers.len() {
+        0 => {
+            say!("FAIL  no DHCP server answered");
+            output::verdict("FAIL", "servers", "no DHCP server answered");
+            std::process::exit(exit::FAILURE);
+        }
+        1 => {
+            say!("PASS  one DHCP server answered");
+            output::verdict("PASS", "servers", "one DHCP server answered");
+        }
+        n => {
+            let detail = format!("{} DHCP servers answered; all but one may be rogue", n);
+            say!("WARN  {}", detail);
+            output::verdict("WARN", "servers", &detail);
+            for (code, label) in [(OPTION_ROUTER, "router"), (OPTION_DNS, "DNS")] {
+                let first = offers[0].addresses(code);
+                if offers.iter().any(|o| o.addresses(code) != first) {
+                    let detail = format!("servers disagree on the {} option", label);
+                    say!("WARN  {}", detail);
+                    output::verdict("WARN", label, &detail);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,418 @@
+use hyper::{Body, Method, Request};
+use socket2::SockAddr;
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Mutex, OnceLock};
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant, timeout};
+
+use crate::cli::{self, Command, Opt};
+use crate::exit;
+use crate::json::Value;
+use crate::measure::millis;
+use crate::output;
+use crate::proxy;
+use crate::say;
+use crate::timeouts;
+use crate::units::{self, MILLIS};
+
+const DNS_PORT: u16 = 53;
+const PUBLIC_RESOLVERS: &[&str] = &["1.1.1.1", "8.8.8.8", "9.9.9.9"];
+const SAMPLE_QUERIES: &[&str] = &[
+    "example.com",
+    "google.com",
+    "wikipedia.org",
+    "github.com",
+    "cloudflare.com",
+    "amazon.com",
+];
+const RCODE_NXDOMAIN: u8 = 3;
+
+static PTR_CACHE: OnceLock<Mutex<HashMap<IpAddr, Option<String>>>> = OnceLock::new();
+
+const BENCH_OPTS: &[Opt] = &[
+    Opt {
+        name: "--resolver",
+        value: Some("<ip[:port]|url>"),
+        help: "Resolver to benchmark instead of the defaults (repeatable)",
+    },
+    Opt {
+        name: "--query",
+        value: Some("<name>"),
+        help: "Name to resolve instead of the sample set (repeatable)",
+    },
+    Opt {
+        name: "--rounds",
+        value: Some("<n>"),
+        help: "How many times to resolve each name (default: 3)",
+    },
+    Opt {
+        name: "--timeout",
+        value: Some("<duration>"),
+        help: "Per-query timeout (default: --dns-timeout)",
+    },
+];
+
+enum Resolver {
+    Udp { label: String, addr: SocketAddr },
+    Doh { url: String },
+}
+
+impl fmt::Display for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Resolver::Udp { label, .. } => write!(f, "{}", label),
+            Resolver::Doh { url } => write!(f, "{}", url),
+        }
+    }
+}
+
+impl Resolver {
+    fn parse(value: &str) -> Result<Resolver, String> {
+        if value.starts_with("http://") || value.starts_with("https://") {
+            return Ok(Resolver::Doh {
+                url: value.to_string(),
+            });
+        }
+
+        let addr = value
+            .parse::<SocketAddr>()
+            .or_else(|_| {
+                value
+                    .parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, DNS_PORT))
+            })
+            .map_err(|_| format!("invalid resolver '{}', expected ip[:port] or a URL", value))?;
+        Ok(Resolver::Udp {
+            label: value.to_string(),
+            addr,
+        })
+    }
+
+    async fn query(&self, packet: &[u8], wait: Duration) -> Result<Vec<u8>, String> {
+        let exchange = async {
+            match self {
+                Resolver::Udp { addr, .. } => query_udp(*addr, packet).await,
+                Resolver::Doh { url } => query_doh(url, packet).await,
+            }
+        };
+        timeout(wait, exchange)
+            .await
+            .map_err(|_| "timed out".to_string())?
+    }
+}
+
+// The name servers a resolv.conf style file lists.
+pub fn nameservers(path: &str) -> Vec<IpAddr> {
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("nameserver"))
+        .filter_map(|ip| ip.trim().parse::<IpAddr>().ok())
+        .collect()
+}
+
+fn system_resolvers() -> Vec<Resolver> {
+    nameservers("/etc/resolv.conf")
+        .into_iter()
+        .map(|ip| Resolver::Udp {
+            label: format!("system ({})", ip),
+            addr: SocketAddr::new(ip, DNS_PORT),
+        })
+        .collect()
+}
+
+// Goes through the system resolver, so /etc/hosts and local resolvers that
+// answer for LAN names work too. Misses are cached; timeouts are not.
+pub async fn reverse(ip: IpAddr, wait: Duration) -> Option<String> {
+    let cache = PTR_CACHE.get_or_init(Default::default);
+    if let Some(name) = cache.lock().unwrap().get(&ip) {
+        return name.clone();
+    }
+    let lookup = tokio::task::spawn_blocking(move || lookup_ptr(ip));
+    let name = timeout(wait, lookup).await.ok()?.ok()?;
+    cache.lock().unwrap().insert(ip, name.clone());
+    name
+}
+
+fn lookup_ptr(ip: IpAddr) -> Option<String> {
+    let addr = SockAddr::from(SocketAddr::new(ip, 0));
+    let mut host = [0 as libc::c_char; 1025];
+    let result = unsafe {
+        libc::getnameinfo(
+            addr.as_ptr(),
+            addr.len(),
+            host.as_mut_ptr(),
+            host.len() as libc::socklen_t,
+            std::ptr::null_mut(),
+            0,
+            libc::NI_NAMEREQD,
+        )
+    };
+    if result != 0 {
+        return None;
+    }
+    let name = unsafe { CStr::from_ptr(host.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+pub fn encode_query(id: u16, name: &str) -> Result<Vec<u8>, String> {
+    let mut packet = Vec::with_capacity(32 + name.len());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&[0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(format!("invalid query name '{}'", name));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.extend_from_slice(&[0, 0, 1, 0, 1]);
+    Ok(packet)
+}
+
+pub fn check_response(response: &[u8], id: u16) -> Result<(), String> {
+    if response.len() < 12 || response[..2] != id.to_be_bytes() || response[2] & 0x80 == 0 {
+        return Err("malformed response".to_string());
+    }
+    match response[3] & 0x0f {
+        0 | RCODE_NXDOMAIN => Ok(()),
+        2 => Err("SERVFAIL".to_string()),
+        5 => Err("REFUSED".to_string()),
+        rcode => Err(format!("rcode {}", rcode)),
+    }
+}
+
+async fn query_udp(addr: SocketAddr, packet: &[u8]) -> Result<Vec<u8>, String> {
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await.map_err(|e| e.to_string())?;
+    socket.connect(addr).await.map_err(|e| e.to_string())?;
+    socket.send(packet).await.map_err(|e| e.to_string())?;
+
+    let mut buf = vec![0; 4096];
+    loop {
+        let n = socket.recv(&mut buf).await.map_err(|e| e.to_string())?;
+        if buf[..n].starts_with(&packet[..2]) {
+            buf.truncate(n);
+            return Ok(buf);
+        }
+    }
+}
+
+async fn query_doh(url: &str, packet: &[u8]) -> Result<Vec<u8>, String> {
+    if !url.starts_with("http://") {
+        return Err("only http:// DoH endpoints are available".to_string());
+    }
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header("content-type", "application/dns-message")
+        .header("accept", "application/dns-message")
+        .header("user-agent", concat!("netcore/", env!("CARGO_PKG_VERSION")))
+        .body(Body::from(packet.to_vec()))
+        .map_err(|e| e.to_string())?;
+
+    let response = proxy::client()
+        .request(request)
+        .await
+        .map_err(|e| e.to_string())?;
+    if !response.status().is_success() {
+        return Err(format!("HTTP {}", response.status()));
+    }
+    hyper::body::to_bytes(response.into_body())
+        .await
+        .map(|body| body.to_vec())
+        .map_err(|e| e.to_string())
+}
+
+struct Summary {
+    resolver: Resolver,
+    rtts: Vec<f64>,
+    failures: usize,
+    last_error: Option<String>,
+}
+
+impl Summary {
+    fn total(&self) -> usize {
+        self.rtts.len() + self.failures
+    }
+
+    fn failure_rate(&self) -> f64 {
+        self.failures as f64 / self.total().max(1) as f64
+    }
+
+    fn to_json(&self) -> Value {
+        let max = self.rtts.iter().copied().reduce(f64::max);
+        let avg =
+            (!self.rtts.is_empty()).then(|| self.rtts.iter().sum::<f64>() / self.rtts.len() as f64);
+        Value::object([
+            ("resolver", Value::from(self.resolver.to_string())),
+            ("queries", Value::from(self.total() as u64)),
+            ("failures", Value::from(self.failures as u64)),
+            ("median_ms", Value::from(self.median())),
+            ("avg_ms", Value::from(avg)),
+            ("max_ms", Value::from(max)),
+            ("last_error", Value::from(self.last_error.clone())),
+        ])
+    }
+
+    fn median(&self) -> Option<f64> {
+        let mut sorted = self.rtts.clone();
+        sorted.sort_by(f64::total_cmp);
+        sorted.get(sorted.len() / 2).copied()
+    }
+}
+
+async fn bench(resolver: Resolver, queries: &[String], rounds: u32, wait: Duration) -> Summary {
+    let mut summary = Summary {
+        resolver,
+        rtts: Vec::new(),
+        failures: 0,
+        last_error: None,
+    };
+
+    for _ in 0..rounds {
+        for name in queries {
+            let id: u16 = rand::random();
+            let result = match encode_query(id, name) {
+                Ok(packet) => {
+                    let start = Instant::now();
+                    summary
+                        .resolver
+                        .query(&packet, wait)
+                        .await
+                        .and_then(|response| check_response(&response, id))
+                        .map(|_| millis(start.elapsed()))
+                }
+                Err(e) => Err(e),
+            };
+            match result {
+                Ok(rtt) => summary.rtts.push(rtt),
+                Err(e) => {
+                    summary.failures += 1;
+                    summary.last_error = Some(format!("{}: {}", name, e));
+                }
+            }
+        }
+    }
+
+    summary
+}
+
+pub const BENCH_COMMAND: Command = Command {
+    name: "dns-bench",
+    usage: "netcore dns-bench",
+    about: "Compare how quickly resolvers answer",
+    groups: &[BENCH_OPTS, timeouts::OPTS],
+};
+
+pub async fn bench_command(tokens: Vec<String>) {
+    let args = cli::parse_command(&BENCH_COMMAND, tokens);
+    cli::or_exit(timeouts::init(&args, None));
+    let rounds: u32 = cli::or_exit(args.parsed("--rounds")).unwrap_or(3).max(1);
+    let wait = cli::or_exit(args.parsed_with("--timeout", |v| units::duration(v, MILLIS)))
+        .unwrap_or(timeouts::get().dns);
+
+    let mut resolvers: Vec<Resolver> = cli::or_exit(
+        args.values("--resolver")
+            .map(Resolver::parse)
+            .collect::<Result<_, _>>(),
+    );
+    if resolvers.is_empty() {
+        resolvers = system_resolvers();
+        resolvers.extend(
+            PUBLIC_RESOLVERS
+                .iter()
+                .filter_map(|ip| Resolver::parse(ip).ok()),
+        );
+    }
+
+    let mut queries: Vec<String> = args.values("--query").map(str::to_string).collect();
+    if queries.is_empty() {
+        queries = SAMPLE_QUERIES.iter().map(|q| q.to_string()).collect();
+    }
+
+    say!(
+        "Resolving {} name(s) x {} round(s) against {} resolver(s)",
+        queries.len(),
+        rounds,
+        resolvers.len()
+    );
+    say!();
+    say!(
+        "{:<28} {:>8} {:>10} {:>10} {:>10}",
+        "RESOLVER",
+        "FAILED",
+        "MEDIAN",
+        "AVG",
+        "MAX"
+    );
+
+    let mut summaries = Vec::new();
+    for resolver in resolvers {
+        let summary = bench(resolver, &queries, rounds, wait).await;
+        let failed = format!("{}/{}", summary.failures, summary.total());
+        match summary.median() {
+            Some(median) => {
+                let avg = summary.rtts.iter().sum::<f64>() / summary.rtts.len() as f64;
+                let max = summary.rtts.iter().copied().fold(0.0, f64::max);
+                say!(
+                    "{:<28} {:>8} {:>7.2} ms {:>7.2} ms {:>7.2} ms",
+                    summary.resolver.to_string(),
+                    failed,
+                    median,
+                    avg,
+                    max
+                );
+            }
+            None => say!(
+                "{:<28} {:>8} {:>10} {:>10} {:>10}",
+                summary.resolver.to_string(),
+                failed,
+                "-",
+                "-",
+                "-"
+            ),
+        }
+        if let Some(error) = &summary.last_error {
+            say!("  last error: {}", error);
+        }
+        output::emit(summary.to_json());
+        summaries.push(summary);
+    }
+
+    say!();
+    let best = summaries
+        .iter()
+        .filter(|s| s.median().is_some())
+        .min_by(|a, b| {
+            a.failure_rate().total_cmp(&b.failure_rate()).then(
+                a.median()
+                    .unwrap_or(f64::MAX)
+                    .total_cmp(&b.median().unwrap_or(f64::MAX)),
+            )
+        });
+    match best {
+        Some(best) => {
+            say!(
+                "Recommended: {} (median {:.2} ms, {:.0}% failed)",
+                best.resolver,
+                best.median().unwrap_or_default(),
+                best.failure_rate() * 100.0
+            );
+            output::verdict("PASS", &best.resolver.to_string(), "recommended");
+        }
+        None => {
+            say!("No resolver answered");
+            output::verdict("FAIL", "resolvers", "no resolver answered");
+            std::process::exit(exit::FAILURE);
+        }
+    }
+}
@@ -0,0 +1,90 @@
+// KEY=value lines for `netcore info --format env` and --write, in the
+// subset of quoting that POSIX shells, docker-compose and the dotenv
+// libraries all read the same way: bare when the value is plain, double
+// quoted with \ escapes otherwise.
+
+use std::fs;
+use std::path::Path;
+
+// Keys netcore owns in a file it updates; the rest are left alone.
+const PREFIX: &str = "NETCORE_";
+
+pub fn line(key: &str, value: &str) -> String {
+    let plain = value
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || ".:-_/,@+".contains(c));
+    if plain {
+        return format!("{}={}", key, value);
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | '$' | '`') {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    format!("{}=\"{}\"", key, quoted)
+}
+
+pub fn render(vars: &[(String, String)]) -> String {
+    vars.iter()
+        .map(|(key, value)| line(key, value) + "\n")
+        .collect()
+}
+
+// `existing` with every NETCORE_ line replaced by `vars`, so addresses
+// that have gone away don't linger.
+fn merge(existing: &str, vars: &[(String, String)]) -> String {
+    let mut out: String = existing
+        .lines()
+        .filter(|line| {
+            !line
+                .trim_start()
+                .trim_start_matches("export ")
+                .starts_with(PREFIX)
+        })
+        .map(|line| format!("{}\n", line))
+        .collect();
+    out.push_str(&render(vars));
+    out
+}
+
+// Updates `path` in place, creating it if needed. The new file is renamed
+// over the old one, so a compose file reading it never sees half of it.
+pub fn write(path: &Path, vars: &[(String, String)]) -> Result<(), String> {
+    let existing = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(format!("{}: {}", path.display(), e)),
+    };
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, merge(&existing, vars))
+        .and_then(|_| fs::rename(&temp, path))
+        .map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quotes_when_needed_and_keeps_other_keys() {
+        assert_eq!(
+            line("NETCORE_PUBLIC_IPV6", "2001:db8::1"),
+            "NETCORE_PUBLIC_IPV6=2001:db8::1"
+        );
+        assert_eq!(
+            line("NETCORE_PUBLIC_IPV4_GEO", "DE, AS3320 \"Telekom\" $x"),
+            r#"NETCORE_PUBLIC_IPV4_GEO="DE, AS3320 \"Telekom\" \$x""#
+        );
+        assert_eq!(line("NETCORE_LOCAL_IPV6", ""), "NETCORE_LOCAL_IPV6=");
+
+        let vars = vec![("NETCORE_PUBLIC_IPV4".to_string(), "203.0.113.7".to_string())];
+        let existing = "# app\nPORT=8080\nNETCORE_PUBLIC_IPV4=198.51.100.1\nexport NETCORE_PUBLIC_IPV4_GEO=US\n";
+        assert_eq!(
+            merge(existing, &vars),
+            "# app\nPORT=8080\nNETCORE_PUBLIC_IPV4=203.0.113.7\n"
+        );
+    }
+}
@@ -0,0 +1,221 @@
+// End-to-end encryption for paired sessions, so the relay in the middle only
+// ever sees ciphertext. Each side sends an ephemeral X25519 public key, and
+// the keys for the two directions are derived from the shared secret, both
+// public keys and the pairing code. Data then travels in ChaCha20-Poly1305
+// records: a 2-byte length followed by the sealed bytes, with a counter as
+// the nonce.
+//
+// The relay also knows the pairing code, so a relay that swaps in its own
+// keys isn't caught by the code alone; the fingerprint printed on both sides
+// is what rules that out when the users compare it.
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::time::timeout;
+
+use crate::aead::{self, KEY_LEN, NONCE_LEN, TAG_LEN};
+use crate::blake3::Hasher;
+use crate::otel;
+use crate::timeouts;
+use crate::x25519;
+
+const MAGIC: &[u8; 4] = b"NCE1";
+const MAX_RECORD: usize = 16 * 1024;
+const BUFFER: usize = 64 * 1024;
+const NOT_ENCRYPTING: &str =
+    "peer did not send an encryption key; both sides need --no-encrypt to go without";
+
+struct Keys {
+    send: [u8; KEY_LEN],
+    receive: [u8; KEY_LEN],
+    fingerprint: String,
+}
+
+fn derive(
+    label: &str,
+    shared: &[u8; 32],
+    offer: &[u8; 32],
+    join: &[u8; 32],
+    code: &str,
+) -> [u8; 32] {
+    let mut hasher = Hasher::new();
+    hasher.update(label.as_bytes());
+    hasher.update(shared);
+    hasher.update(offer);
+    hasher.update(join);
+    hasher.update(code.as_bytes());
+    hasher.finalize()
+}
+
+fn keys(
+    secret: &[u8; 32],
+    ours: &[u8; 32],
+    theirs: &[u8; 32],
+    offerer: bool,
+    code: &str,
+) -> Result<Keys, String> {
+    let shared = x25519::x25519(secret, theirs);
+    // A low-order public key forces the shared secret to zero.
+    if shared == [0; 32] {
+        return Err("peer sent an invalid public key".to_string());
+    }
+    let (offer, join) = if offerer {
+        (ours, theirs)
+    } else {
+        (theirs, ours)
+    };
+    let to_join = derive("netcore e2e offer to join", &shared, offer, join, code);
+    let to_offer = derive("netcore e2e join to offer", &shared, offer, join, code);
+    let digest = derive("netcore e2e fingerprint", &shared, offer, join, code);
+    let number = u32::from_le_bytes(digest[..4].try_into().unwrap()) % 100_000_000;
+    let (send, receive) = if offerer {
+        (to_join, to_offer)
+    } else {
+        (to_offer, to_join)
+    };
+    Ok(Keys {
+        send,
+        receive,
+        fingerprint: format!("{:04}-{:04}", number / 10_000, number % 10_000),
+    })
+}
+
+fn nonce(counter: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+async fn seal_records<R, W>(mut plain: R, mut io: W, key: [u8; KEY_LEN])
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0; MAX_RECORD];
+    let mut counter = 0u64;
+    loop {
+        let n = match plain.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let len = ((n + TAG_LEN) as u16).to_be_bytes();
+        let sealed = aead::seal(&key, &nonce(counter), &len, &buffer[..n]);
+        counter += 1;
+        let mut record = len.to_vec();
+        record.extend_from_slice(&sealed);
+        if io.write_all(&record).await.is_err() {
+            return;
+        }
+    }
+    let _ = io.shutdown().await;
+}
+
+// Stops at the first record that fails to open, which ends the session: past
+// that point nothing from the peer can be trusted.
+async fn open_records<R, W>(mut io: R, mut plain: W, key: [u8; KEY_LEN])
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut counter = 0u64;
+    loop {
+        let mut len = [0; 2];
+        if io.read_exact(&mut len).await.is_err() {
+            break;
+        }
+        let mut sealed = vec![0; u16::from_be_bytes(len) as usize];
+        if io.read_exact(&mut sealed).await.is_err() {
+            break;
+        }
+        let Some(data) = aead::open(&key, &nonce(counter), &len, &sealed) else {
+            otel::count("netcore.e2e.rejected");
+            break;
+        };
+        counter += 1;
+        if plain.write_all(&data).await.is_err() {
+            break;
+        }
+    }
+    let _ = plain.shutdown().await;
+}
+
+// Runs the key exchange over `io` and returns a stream whose data is
+// encrypted on the way through it, with the fingerprint to show the user.
+// `offerer` is the side that created the pairing code.
+pub async fn session<S>(
+    mut io: S,
+    offerer: bool,
+    code: &str,
+) -> Result<(DuplexStream, String), String>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let secret: [u8; 32] = rand::random();
+    let ours = x25519::public_key(&secret);
+    let mut hello = MAGIC.to_vec();
+    hello.extend_from_slice(&ours);
+    io.write_all(&hello).await.map_err(|e| e.to_string())?;
+
+    let mut reply = [0; 36];
+    timeout(timeouts::get().handshake, io.read_exact(&mut reply))
+        .await
+        .map_err(|_| "timed out waiting for the peer's encryption key".to_string())?
+        .map_err(|_| NOT_ENCRYPTING.to_string())?;
+    if &reply[..4] != MAGIC {
+        return Err(NOT_ENCRYPTING.to_string());
+    }
+    let theirs: [u8; 32] = reply[4..].try_into().unwrap();
+    let keys = keys(&secret, &ours, &theirs, offerer, code)?;
+
+    let (local, remote) = tokio::io::duplex(BUFFER);
+    let (remote_read, remote_write) = tokio::io::split(remote);
+    let (io_read, io_write) = tokio::io::split(io);
+    tokio::spawn(seal_records(remote_read, io_write, keys.send));
+    tokio::spawn(open_records(io_read, remote_write, keys.receive));
+    Ok((local, keys.fingerprint))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn carries_data_both_ways_and_agrees_on_the_fingerprint() {
+        let (a, b) = tokio::io::duplex(1024);
+        let (offer, join) = tokio::join!(session(a, true, "ABC234"), session(b, false, "ABC234"));
+        let ((mut offer, offer_print), (mut join, join_print)) = (offer.unwrap(), join.unwrap());
+        assert_eq!(offer_print, join_print);
+
+        let data: Vec<u8> = (0..100_000).map(|i| i as u8).collect();
+        let sent = data.clone();
+        let writer = tokio::spawn(async move {
+            offer.write_all(&sent).await.unwrap();
+            offer.shutdown().await.unwrap();
+            offer
+        });
+        let mut received = Vec::new();
+        join.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, data);
+
+        join.write_all(b"thanks").await.unwrap();
+        join.shutdown().await.unwrap();
+        let mut offer = writer.await.unwrap();
+        let mut reply = Vec::new();
+        offer.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"thanks");
+    }
+
+    #[test]
+    fn keys_depend_on_the_code_and_direction() {
+        let (a, b): ([u8; 32], [u8; 32]) = (rand::random(), rand::random());
+        let (pa, pb) = (x25519::public_key(&a), x25519::public_key(&b));
+        let offer = keys(&a, &pa, &pb, true, "ABC234").unwrap();
+        let join = keys(&b, &pb, &pa, false, "ABC234").unwrap();
+        assert_eq!(offer.send, join.receive);
+        assert_eq!(offer.receive, join.send);
+        assert_ne!(offer.send, offer.receive);
+        let other = keys(&b, &pb, &pa, false, "ABC235").unwrap();
+        assert_ne!(other.receive, offer.send);
+        assert_ne!(other.fingerprint, offer.fingerprint);
+        assert!(keys(&a, &pa, &[0; 32], true, "ABC234").is_err());
+    }
+}
@@ -0,0 +1,418 @@
+// Ed25519 signatures (RFC 8032) for the SSH server's host key and client
+// keys: points in extended coordinates over the x25519 field, scalars
+// reduced mod L by shift-and-subtract. Signing multiplies with a fixed
+// sequence of doublings and additions, swapping rather than branching on
+// the secret bits, and the reduction selects by mask for the same reason.
+
+use std::ops::{Add, Mul, Sub};
+use std::sync::LazyLock;
+
+use crate::sha2::sha512;
+use crate::x25519::{Fe, ONE, ZERO, cswap};
+
+// The group order, 2^252 + 27742317777372353535851937790883648493, in
+// little-endian 64-bit limbs.
+const L: [u64; 4] = [
+    0x5812631a5cf5d3ed,
+    0x14def9dea2f79cd6,
+    0,
+    0x1000000000000000,
+];
+
+struct Curve {
+    d2: Fe,
+    d: Fe,
+    sqrt_m1: Fe,
+    base: Point,
+}
+
+static CURVE: LazyLock<Curve> = LazyLock::new(|| {
+    // d = -121665 / 121666
+    let d = ZERO.sub(small(121_665)).mul(small(121_666).invert());
+    // sqrt(-1) = 2^((p - 1) / 4); (p - 1) / 4 = 2^253 - 5.
+    let sqrt_m1 = pow(small(2), 252, |bit| bit != 2);
+    let mut curve = Curve {
+        d2: d.add(d),
+        d,
+        sqrt_m1,
+        base: IDENTITY,
+    };
+    let mut base = [0x66; 32];
+    base[0] = 0x58;
+    curve.base = Point::decode_with(&curve, &base).unwrap();
+    curve
+});
+
+fn small(n: u64) -> Fe {
+    let mut bytes = [0; 32];
+    bytes[..8].copy_from_slice(&n.to_le_bytes());
+    Fe::from_bytes(&bytes)
+}
+
+// x raised to the exponent whose bits from `top` down are set where
+// `set` says. Only for public exponents.
+fn pow(x: Fe, top: usize, set: impl Fn(usize) -> bool) -> Fe {
+    let mut result = ONE;
+    for bit in (0..=top).rev() {
+        result = result.square();
+        if set(bit) {
+            result = result.mul(x);
+        }
+    }
+    result
+}
+
+fn equal(a: Fe, b: Fe) -> bool {
+    a.to_bytes() == b.to_bytes()
+}
+
+fn is_negative(a: Fe) -> bool {
+    a.to_bytes()[0] & 1 == 1
+}
+
+#[derive(Clone, Copy)]
+struct Point {
+    x: Fe,
+    y: Fe,
+    z: Fe,
+    t: Fe,
+}
+
+const IDENTITY: Point = Point {
+    x: ZERO,
+    y: ONE,
+    z: ONE,
+    t: ZERO,
+};
+
+impl Point {
+    fn decode(bytes: &[u8; 32]) -> Option<Point> {
+        Point::decode_with(&CURVE, bytes)
+    }
+
+    // RFC 8032 5.1.3.
+    fn decode_with(curve: &Curve, bytes: &[u8; 32]) -> Option<Point> {
+        let sign = bytes[31] >> 7 == 1;
+        let mut y_bytes = *bytes;
+        y_bytes[31] &= 0x7f;
+        let y = Fe::from_bytes(&y_bytes);
+        if y.to_bytes() != y_bytes {
+            return None;
+        }
+
+        let yy = y.square();
+        let u = yy.sub(ONE);
+        let v = curve.d.mul(yy).add(ONE);
+        let v3 = v.square().mul(v);
+        let v7 = v3.square().mul(v);
+        // (p - 5) / 8 = 2^252 - 3.
+        let mut x = u.mul(v3).mul(pow(u.mul(v7), 251, |bit| bit != 1));
+        let vxx = v.mul(x.square());
+        if !equal(vxx, u) {
+            if !equal(vxx, ZERO.sub(u)) {
+                return None;
+            }
+            x = x.mul(curve.sqrt_m1);
+        }
+        if equal(x, ZERO) && sign {
+            return None;
+        }
+        if is_negative(x) != sign {
+            x = ZERO.sub(x);
+        }
+        Some(Point {
+            x,
+            y,
+            z: ONE,
+            t: x.mul(y),
+        })
+    }
+
+    fn encode(&self) -> [u8; 32] {
+        let zinv = self.z.invert();
+        let x = self.x.mul(zinv);
+        let mut bytes = self.y.mul(zinv).to_bytes();
+        bytes[31] |= (is_negative(x) as u8) << 7;
+        bytes
+    }
+
+    // RFC 8032 5.1.4; complete, so it also doubles.
+    fn add(&self, other: &Point) -> Point {
+        let a = self.y.sub(self.x).mul(other.y.sub(other.x));
+        let b = self.y.add(self.x).mul(other.y.add(other.x));
+        let c = self.t.mul(CURVE.d2).mul(other.t);
+        let d = self.z.add(self.z).mul(other.z);
+        let (e, f, g, h) = (b.sub(a), d.sub(c), d.add(c), b.add(a));
+        Point {
+            x: e.mul(f),
+            y: g.mul(h),
+            z: f.mul(g),
+            t: e.mul(h),
+        }
+    }
+
+    fn mul(&self, scalar: &[u8; 32]) -> Point {
+        let mut q = IDENTITY;
+        for bit in (0..256).rev() {
+            q = q.add(&q);
+            let mut sum = q.add(self);
+            let swap = u64::from((scalar[bit / 8] >> (bit % 8)) & 1);
+            cswap(swap, &mut q.x, &mut sum.x);
+            cswap(swap, &mut q.y, &mut sum.y);
+            cswap(swap, &mut q.z, &mut sum.z);
+            cswap(swap, &mut q.t, &mut sum.t);
+        }
+        q
+    }
+}
+
+fn limbs<const N: usize>(bytes: &[u8]) -> [u64; N] {
+    std::array::from_fn(|i| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap()))
+}
+
+fn scalar_bytes(limbs: [u64; 4]) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    for (chunk, limb) in bytes.chunks_exact_mut(8).zip(limbs) {
+        chunk.copy_from_slice(&limb.to_le_bytes());
+    }
+    bytes
+}
+
+// Subtracts L from r when r >= L, picking the result with a mask rather
+// than a branch, so that reducing secret scalars takes the same time
+// whatever their value. Returns whether L was subtracted.
+fn subtract_l(r: &mut [u64; 4]) -> bool {
+    let mut diff = [0u64; 4];
+    let mut borrow = 0;
+    for i in 0..4 {
+        let (d, b1) = r[i].overflowing_sub(L[i]);
+        let (d, b2) = d.overflowing_sub(borrow);
+        diff[i] = d;
+        borrow = u64::from(b1 | b2);
+    }
+    // All ones when nothing was borrowed, that is when r >= L.
+    let mask = borrow.wrapping_sub(1);
+    for i in 0..4 {
+        r[i] = (diff[i] & mask) | (r[i] & !mask);
+    }
+    mask & 1 == 1
+}
+
+// A number of up to 512 bits mod L, a bit at a time.
+fn reduce(wide: &[u64; 8]) -> [u64; 4] {
+    let mut r = [0u64; 4];
+    for bit in (0..512).rev() {
+        for i in (1..4).rev() {
+            r[i] = (r[i] << 1) | (r[i - 1] >> 63);
+        }
+        r[0] = (r[0] << 1) | ((wide[bit / 64] >> (bit % 64)) & 1);
+        subtract_l(&mut r);
+    }
+    r
+}
+
+fn reduce_hash(hash: &[u8; 64]) -> [u8; 32] {
+    scalar_bytes(reduce(&limbs(hash)))
+}
+
+// (a * b + c) mod L.
+fn mul_add(a: &[u8; 32], b: &[u8; 32], c: &[u8; 32]) -> [u8; 32] {
+    let (a, b, c) = (limbs::<4>(a), limbs::<4>(b), limbs::<4>(c));
+    let mut wide = [0u64; 8];
+    for i in 0..4 {
+        let mut carry = 0u128;
+        for j in 0..4 {
+            let t = u128::from(wide[i + j]) + u128::from(a[i]) * u128::from(b[j]) + carry;
+            wide[i + j] = t as u64;
+            carry = t >> 64;
+        }
+        wide[i + 4] = carry as u64;
+    }
+    let mut carry = 0u128;
+    for (i, limb) in wide.iter_mut().enumerate() {
+        let t = u128::from(*limb) + u128::from(c.get(i).copied().unwrap_or(0)) + carry;
+        *limb = t as u64;
+        carry = t >> 64;
+    }
+    scalar_bytes(reduce(&wide))
+}
+
+fn hash_to_scalar(parts: &[&[u8]]) -> [u8; 32] {
+    reduce_hash(&sha512(&parts.concat()))
+}
+
+pub struct Keypair {
+    scalar: [u8; 32],
+    prefix: [u8; 32],
+    pub public: [u8; 32],
+}
+
+impl Keypair {
+    pub fn from_seed(seed: &[u8; 32]) -> Keypair {
+        let hash = sha512(seed);
+        let mut scalar: [u8; 32] = hash[..32].try_into().unwrap();
+        scalar[0] &= 248;
+        scalar[31] &= 127;
+        scalar[31] |= 64;
+        Keypair {
+            scalar,
+            prefix: hash[32..].try_into().unwrap(),
+            public: CURVE.base.mul(&scalar).encode(),
+        }
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; 64] {
+        let r = hash_to_scalar(&[&self.prefix, message]);
+        let big_r = CURVE.base.mul(&r).encode();
+        let k = hash_to_scalar(&[&big_r, &self.public, message]);
+        let s = mul_add(&k, &self.scalar, &r);
+        let mut signature = [0; 64];
+        signature[..32].copy_from_slice(&big_r);
+        signature[32..].copy_from_slice(&s);
+        signature
+    }
+
+    // The same secret as an X25519 key, as libsodium's
+    // crypto_sign_ed25519_sk_to_curve25519 makes it, so an identity can
+    // agree on keys as well as sign.
+    pub fn x25519_secret(&self) -> [u8; 32] {
+        self.scalar
+    }
+}
+
+// The X25519 public key to go with x25519_secret: the Montgomery u of the
+// point, (1 + y) / (1 - y).
+pub fn to_x25519(public: &[u8; 32]) -> Option<[u8; 32]> {
+    Point::decode(public)?;
+    let mut y = *public;
+    y[31] &= 0x7f;
+    let y = Fe::from_bytes(&y);
+    Some(ONE.add(y).mul(ONE.sub(y).invert()).to_bytes())
+}
+
+pub fn verify(public: &[u8; 32], message: &[u8], signature: &[u8; 64]) -> bool {
+    let (Some(a), Some(r)) = (
+        Point::decode(public),
+        Point::decode(signature[..32].try_into().unwrap()),
+    ) else {
+        return false;
+    };
+    let s: [u8; 32] = signature[32..].try_into().unwrap();
+    if subtract_l(&mut limbs(&s)) {
+        return false;
+    }
+    let k = hash_to_scalar(&[&signature[..32], public, message]);
+    CURVE.base.mul(&s).encode() == r.add(&a.mul(&k)).encode()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex;
+
+    fn bytes<const N: usize>(text: &str) -> [u8; N] {
+        hex::decode(text).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn matches_rfc_8032_vectors() {
+        let cases = [
+            (
+                "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+                "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+                &b""[..],
+                "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+            ),
+            (
+                "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+                "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+                &[0x72][..],
+                "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+            ),
+            (
+                "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+                "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+                &[0xaf, 0x82][..],
+                "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+            ),
+        ];
+        for (seed, public, message, signature) in cases {
+            let keypair = Keypair::from_seed(&bytes(seed));
+            assert_eq!(keypair.public, bytes::<32>(public));
+            let signature = bytes::<64>(signature);
+            assert_eq!(keypair.sign(message), signature);
+            assert!(verify(&keypair.public, message, &signature));
+            assert!(!verify(&keypair.public, b"tampered", &signature));
+        }
+    }
+
+    #[test]
+    fn converts_to_x25519_keys_that_agree() {
+        let alice = Keypair::from_seed(&[1; 32]);
+        let bob = Keypair::from_seed(&[2; 32]);
+        for keypair in [&alice, &bob] {
+            assert_eq!(
+                to_x25519(&keypair.public),
+                Some(crate::x25519::public_key(&keypair.x25519_secret()))
+            );
+        }
+        assert_eq!(
+            crate::x25519::x25519(&alice.x25519_secret(), &to_x25519(&bob.public).unwrap()),
+            crate::x25519::x25519(&bob.x25519_secret(), &to_x25519(&alice.public).unwrap())
+        );
+        // Not a point on the curve.
+        let mut bad = [0; 32];
+        bad[0] = 2;
+        assert_eq!(to_x25519(&bad), None);
+    }
+
+    #[test]
+    fn reduces_around_the_group_order() {
+        let wide = |limbs: [u64; 4]| std::array::from_fn(|i| limbs.get(i).copied().unwrap_or(0));
+        let below = [L[0] - 1, L[1], L[2], L[3]];
+        assert_eq!(reduce(&wide(below)), below);
+        assert_eq!(reduce(&wide(L)), [0; 4]);
+        assert_eq!(reduce(&wide([L[0] + 1, L[1], L[2], L[3]])), [1, 0, 0, 0]);
+        assert_eq!(
+            reduce(&[u64::MAX; 8]),
+            [
+                0xa40611e3449c0f00,
+                0xd00e1ba768859347,
+                0xceec73d217f5be65,
+                0x0399411b7c309a3d,
+            ]
+        );
+
+        let mut scalar = L;
+        assert!(subtract_l(&mut scalar));
+        assert_eq!(scalar, [0; 4]);
+        assert!(!subtract_l(&mut scalar));
+    }
+
+    // Adding the group order to S gives a second signature that checks
+    // out arithmetically; it must be refused as malleable.
+    #[test]
+    fn refuses_non_canonical_and_altered_signatures() {
+        let keypair = Keypair::from_seed(&[1; 32]);
+        let signature = keypair.sign(b"message");
+        assert!(verify(&keypair.public, b"message", &signature));
+
+        let l = bytes::<32>("edd3f55c1a631258d69cf7a2def9de1400000000000000000000000000000010");
+        let mut malleated = signature;
+        let mut carry = 0;
+        for (s, l) in malleated[32..].iter_mut().zip(l) {
+            let sum = u16::from(*s) + u16::from(l) + carry;
+            *s = sum as u8;
+            carry = sum >> 8;
+        }
+        assert!(!verify(&keypair.public, b"message", &malleated));
+
+        for byte in [0, 40] {
+            let mut altered = signature;
+            altered[byte] ^= 1;
+            assert!(!verify(&keypair.public, b"message", &altered));
+        }
+        let other = Keypair::from_seed(&[2; 32]);
+        assert!(!verify(&other.public, b"message", &signature));
+    }
+}
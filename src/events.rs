@@ -0,0 +1,119 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::LazyLock;
+use tokio::sync::broadcast;
+
+use crate::json::Value;
+
+// Subscribers that fall further behind than this miss the oldest events
+// and are told how many.
+const CAPACITY: usize = 256;
+
+#[derive(Clone, Debug)]
+pub enum Event {
+    ListenerStarted {
+        addr: SocketAddr,
+    },
+    // The listener on `addr` keeps accepting after this.
+    AcceptFailed {
+        addr: SocketAddr,
+        error: String,
+    },
+    ConnectionOpened {
+        id: u64,
+        handler: &'static str,
+        peer: SocketAddr,
+    },
+    ConnectionClosed {
+        id: u64,
+        handler: &'static str,
+        peer: SocketAddr,
+        duration_ms: u64,
+        bytes_in: u64,
+        bytes_out: u64,
+        error: Option<String>,
+    },
+    // The first public address found for a family has no `old`.
+    PublicIpChanged {
+        old: Option<IpAddr>,
+        new: IpAddr,
+    },
+    // A tunnel stream's data didn't match the BLAKE3 digest the peer sent
+    // for it.
+    IntegrityFailed {
+        stream: u32,
+        expected: String,
+        actual: String,
+    },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::ListenerStarted { .. } => "listener_started",
+            Event::AcceptFailed { .. } => "accept_failed",
+            Event::ConnectionOpened { .. } => "connection_opened",
+            Event::ConnectionClosed { .. } => "connection_closed",
+            Event::PublicIpChanged { .. } => "public_ip_changed",
+            Event::IntegrityFailed { .. } => "integrity_failed",
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut fields = vec![("event", Value::from(self.name()))];
+        match self {
+            Event::ListenerStarted { addr } => fields.push(("addr", addr.to_string().into())),
+            Event::AcceptFailed { addr, error } => fields.extend([
+                ("addr", addr.to_string().into()),
+                ("error", Value::from(error.as_str())),
+            ]),
+            Event::ConnectionOpened { id, handler, peer } => fields.extend([
+                ("id", Value::from(*id)),
+                ("handler", Value::from(*handler)),
+                ("peer", peer.to_string().into()),
+            ]),
+            Event::ConnectionClosed {
+                id,
+                handler,
+                peer,
+                duration_ms,
+                bytes_in,
+                bytes_out,
+                error,
+            } => fields.extend([
+                ("id", Value::from(*id)),
+                ("handler", Value::from(*handler)),
+                ("peer", peer.to_string().into()),
+                ("duration_ms", Value::from(*duration_ms)),
+                ("bytes_in", Value::from(*bytes_in)),
+                ("bytes_out", Value::from(*bytes_out)),
+                ("error", Value::from(error.clone())),
+            ]),
+            Event::PublicIpChanged { old, new } => fields.extend([
+                ("old", Value::from(old.map(|ip| ip.to_string()))),
+                ("new", Value::from(new.to_string())),
+            ]),
+            Event::IntegrityFailed {
+                stream,
+                expected,
+                actual,
+            } => fields.extend([
+                ("stream", Value::from(*stream as u64)),
+                ("expected", Value::from(expected.as_str())),
+                ("actual", Value::from(actual.as_str())),
+            ]),
+        }
+        Value::object(fields)
+    }
+}
+
+static BUS: LazyLock<broadcast::Sender<Event>> = LazyLock::new(|| broadcast::channel(CAPACITY).0);
+
+// Every event emitted after this call, in order.
+pub fn subscribe() -> broadcast::Receiver<Event> {
+    BUS.subscribe()
+}
+
+pub fn emit(event: Event) {
+    // Nobody listening isn't an error.
+    let _ = BUS.send(event);
+}
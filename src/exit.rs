@@ -0,0 +1,262 @@
+// Exit codes, the same for every command so scripts can branch on them:
+//
+//   0    success: everything asked for was done and looked fine
+//   1    failure: the command ran but the answer is no (a probe failed, a
+//        scan --diff found changes), or an error none of the codes below fit
+//   2    partial: results for some targets or checks but not all of them
+//   3    no connectivity: no address or route, or no target reachable at all
+//   4    permission: needs privileges, like raw sockets or a low port
+//   64   usage: invalid arguments or configuration
+//   130  interrupted
+//
+// Codes only ever get added to this list; a script written against it keeps
+// working.
+
+use std::fmt::{self, Display};
+use std::io;
+
+pub const SUCCESS: i32 = 0;
+pub const FAILURE: i32 = 1;
+pub const PARTIAL: i32 = 2;
+pub const NO_CONNECTIVITY: i32 = 3;
+pub const PERMISSION: i32 = 4;
+pub const USAGE: i32 = 64;
+pub const INTERRUPTED: i32 = 130;
+
+// Listed in --help-json for tools that wrap the CLI.
+pub const ALL: &[(i32, &str)] = &[
+    (SUCCESS, "success"),
+    (FAILURE, "failure"),
+    (PARTIAL, "partial"),
+    (NO_CONNECTIVITY, "no_connectivity"),
+    (PERMISSION, "permission"),
+    (USAGE, "usage"),
+    (INTERRUPTED, "interrupted"),
+];
+
+// Why a command gave up, decided from the error itself where it happened
+// rather than from its text, which differs between systems and locales.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failure {
+    Permission,
+    NoConnectivity,
+    Other,
+}
+
+impl Failure {
+    // The errno values that mean missing privileges or no network; the
+    // rest, like a refused connection or a missing file, are plain failures.
+    pub fn of(error: &io::Error) -> Failure {
+        match error.raw_os_error() {
+            Some(libc::EACCES | libc::EPERM) => Failure::Permission,
+            Some(libc::ENETUNREACH | libc::ENETDOWN | libc::EHOSTUNREACH | libc::EADDRNOTAVAIL) => {
+                Failure::NoConnectivity
+            }
+            Some(_) => Failure::Other,
+            // Resolver errors carry no errno: std reports a name that
+            // can't be looked up at the moment as Uncategorized, which
+            // can't be matched on, so this is the one place text decides.
+            None if error.kind() == io::ErrorKind::PermissionDenied => Failure::Permission,
+            None if error
+                .to_string()
+                .contains("Temporary failure in name resolution") =>
+            {
+                Failure::NoConnectivity
+            }
+            None => Failure::Other,
+        }
+    }
+
+    pub fn code(self) -> i32 {
+        match self {
+            Failure::Permission => PERMISSION,
+            Failure::NoConnectivity => NO_CONNECTIVITY,
+            Failure::Other => FAILURE,
+        }
+    }
+}
+
+// An error message that keeps the Failure of the I/O error behind it, for
+// work that otherwise reports errors as text.
+#[derive(Debug)]
+pub struct Error {
+    pub failure: Failure,
+    pub message: String,
+}
+
+impl Error {
+    pub fn io(context: impl Display, error: io::Error) -> Error {
+        Error {
+            failure: Failure::of(&error),
+            message: format!("{}: {}", context, error),
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Error {
+        Error {
+            failure: Failure::of(&error),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl From<String> for Error {
+    fn from(message: String) -> Error {
+        Error {
+            failure: Failure::Other,
+            message,
+        }
+    }
+}
+
+impl From<&str> for Error {
+    fn from(message: &str) -> Error {
+        message.to_string().into()
+    }
+}
+
+impl From<Error> for String {
+    fn from(error: Error) -> String {
+        error.message
+    }
+}
+
+// Errors a command can give up on. Plain messages are failures; errors
+// from the system say which kind.
+pub trait Classify {
+    fn failure(&self) -> Failure;
+}
+
+impl Classify for io::Error {
+    fn failure(&self) -> Failure {
+        Failure::of(self)
+    }
+}
+
+impl Classify for Error {
+    fn failure(&self) -> Failure {
+        self.failure
+    }
+}
+
+impl Classify for String {
+    fn failure(&self) -> Failure {
+        Failure::Other
+    }
+}
+
+impl Classify for str {
+    fn failure(&self) -> Failure {
+        Failure::Other
+    }
+}
+
+// A client error is classified by the I/O error that caused it, if any.
+impl Classify for hyper::Error {
+    fn failure(&self) -> Failure {
+        let mut source = std::error::Error::source(self);
+        while let Some(error) = source {
+            if let Some(error) = error.downcast_ref::<io::Error>() {
+                return Failure::of(error);
+            }
+            source = error.source();
+        }
+        Failure::Other
+    }
+}
+
+impl<T: Classify + ?Sized> Classify for &T {
+    fn failure(&self) -> Failure {
+        (**self).failure()
+    }
+}
+
+// The code for a command that gave up on `error`.
+pub fn code(error: impl Classify) -> i32 {
+    error.failure().code()
+}
+
+// For commands that answer for several targets: all of them, some, or none.
+pub fn from_counts(succeeded: usize, total: usize) -> i32 {
+    match succeeded {
+        _ if succeeded == total => SUCCESS,
+        0 => NO_CONNECTIVITY,
+        _ => PARTIAL,
+    }
+}
+
+// Like cli::or_exit, for errors from doing the work rather than from the
+// command line.
+pub fn or_exit<T, E: Display + Classify>(result: Result<T, E>) -> T {
+    result.unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(code(&e));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn sorts_errors_by_errno() {
+        let code_of = |errno| code(io::Error::from_raw_os_error(errno));
+        assert_eq!(code_of(libc::EACCES), PERMISSION);
+        assert_eq!(code_of(libc::EPERM), PERMISSION);
+        assert_eq!(code_of(libc::ENETUNREACH), NO_CONNECTIVITY);
+        assert_eq!(code_of(libc::ENETDOWN), NO_CONNECTIVITY);
+        assert_eq!(code_of(libc::EHOSTUNREACH), NO_CONNECTIVITY);
+        assert_eq!(code_of(libc::EADDRNOTAVAIL), NO_CONNECTIVITY);
+        assert_eq!(code_of(libc::ECONNREFUSED), FAILURE);
+        assert_eq!(code_of(libc::ENOENT), FAILURE);
+        assert_eq!(
+            code(io::Error::from(io::ErrorKind::PermissionDenied)),
+            PERMISSION
+        );
+        assert_eq!(code(io::Error::other("permission denied")), FAILURE);
+    }
+
+    #[test]
+    fn keeps_the_failure_behind_a_message() {
+        let denied = Error::io(
+            "Failed to listen on port 80",
+            io::Error::from_raw_os_error(libc::EACCES),
+        );
+        assert_eq!(code(&denied), PERMISSION);
+        assert!(
+            denied
+                .to_string()
+                .starts_with("Failed to listen on port 80: ")
+        );
+        let unreachable: Error = io::Error::from_raw_os_error(libc::ENETUNREACH).into();
+        assert_eq!(code(&unreachable), NO_CONNECTIVITY);
+
+        // Text alone never picks a code, whatever it says.
+        assert_eq!(code("connect failed: Network is unreachable"), FAILURE);
+        assert_eq!(code(Error::from("Permission denied".to_string())), FAILURE);
+        assert_eq!(
+            String::from(denied),
+            format!(
+                "Failed to listen on port 80: {}",
+                io::Error::from_raw_os_error(libc::EACCES)
+            )
+        );
+    }
+
+    #[test]
+    fn counts_targets() {
+        assert_eq!(from_counts(3, 3), SUCCESS);
+        assert_eq!(from_counts(1, 3), PARTIAL);
+        assert_eq!(from_counts(0, 3), NO_CONNECTIVITY);
+        assert_eq!(from_counts(0, 0), SUCCESS);
+    }
+}
@@ -0,0 +1,286 @@
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::cli::{Args, Opt};
+use crate::context::TlsInfo;
+use crate::hex;
+use crate::say;
+use crate::stats::Tracker;
+
+#[cfg(target_os = "linux")]
+const MAX_SYN_BYTES: usize = 120;
+
+pub const OPTS: &[Opt] = &[Opt {
+    name: "--fingerprint",
+    value: None,
+    help: "Record TCP and TLS fingerprints of accepted connections",
+}];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn init(args: &Args) {
+    ENABLED.store(args.flag("--fingerprint"), Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+// The kernel keeps the SYN of each connection accepted on this listener
+// until it is read back with TCP_SAVED_SYN.
+#[cfg(target_os = "linux")]
+pub fn prepare(listener: &TcpListener) {
+    if !enabled() {
+        return;
+    }
+
+    let on: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            listener.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_SAVE_SYN,
+            &on as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&on) as libc::socklen_t,
+        )
+    };
+    if result != 0 {
+        eprintln!(
+            "Failed to enable SYN capture for fingerprints: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+// Other kernels don't save SYNs, so only TLS fingerprints are recorded.
+#[cfg(not(target_os = "linux"))]
+pub fn prepare(_listener: &TcpListener) {}
+
+// Also describes SYN-ACKs, which answer a SYN carrying every common option
+// in the order and form the responding stack prefers.
+pub struct Syn {
+    pub ttl: u8,
+    pub window: u16,
+    pub mss: Option<u16>,
+    pub options: Vec<&'static str>,
+}
+
+impl Syn {
+    pub fn initial_ttl(&self) -> u8 {
+        match self.ttl {
+            0..=32 => 32,
+            33..=64 => 64,
+            65..=128 => 128,
+            _ => 255,
+        }
+    }
+
+    pub fn os_guess(&self) -> &'static str {
+        let options = self.options.join(",");
+        match self.initial_ttl() {
+            64 if options.starts_with("mss,sok,ts,nop,ws") => "Linux",
+            64 if options.starts_with("mss,nop,ws,nop,nop,ts,sok") => "macOS/BSD",
+            64 => "Unix-like",
+            128 => "Windows",
+            255 => "network device or Solaris",
+            _ => "embedded or legacy",
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn saved_syn(socket: &TcpStream) -> Option<Vec<u8>> {
+    let mut buf = vec![0u8; MAX_SYN_BYTES];
+    let mut len = buf.len() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_SAVED_SYN,
+            buf.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 || len == 0 {
+        return None;
+    }
+    buf.truncate(len as usize);
+    Some(buf)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn saved_syn(_socket: &TcpStream) -> Option<Vec<u8>> {
+    None
+}
+
+pub fn parse_syn(packet: &[u8]) -> Option<Syn> {
+    let (ttl, tcp) = match packet.first()? >> 4 {
+        4 => {
+            let header = (packet[0] & 0x0f) as usize * 4;
+            (*packet.get(8)?, packet.get(header..)?)
+        }
+        6 => (*packet.get(7)?, packet.get(40..)?),
+        _ => return None,
+    };
+
+    let window = u16::from_be_bytes([*tcp.get(14)?, *tcp.get(15)?]);
+    let header = (tcp.get(12)? >> 4) as usize * 4;
+    let mut rest = tcp.get(20..header)?;
+
+    let mut mss = None;
+    let mut options = Vec::new();
+    while let Some(&kind) = rest.first() {
+        let len = match kind {
+            0 | 1 => 1,
+            _ => (*rest.get(1)? as usize).max(2),
+        };
+        options.push(match kind {
+            0 => "eol",
+            1 => "nop",
+            2 => {
+                mss = rest.get(2..4).map(|v| u16::from_be_bytes([v[0], v[1]]));
+                "mss"
+            }
+            3 => "ws",
+            4 => "sok",
+            8 => "ts",
+            _ => "?",
+        });
+        if kind == 0 {
+            break;
+        }
+        rest = rest.get(len..)?;
+    }
+
+    Some(Syn {
+        ttl,
+        window,
+        mss,
+        options,
+    })
+}
+
+pub fn record(tracker: &mut Tracker, socket: &TcpStream, tls: Option<&TlsInfo>) {
+    if !enabled() {
+        return;
+    }
+
+    let mut fields = Vec::new();
+    if let Some(syn) = saved_syn(socket).as_deref().and_then(parse_syn) {
+        fields.push(format!("ttl={}/{}", syn.ttl, syn.initial_ttl()));
+        fields.push(format!("window={}", syn.window));
+        fields.push(format!("os={}", syn.os_guess()));
+        tracker.attr("netcore.tcp.ttl", syn.ttl as u64);
+        tracker.attr("netcore.tcp.window", syn.window as u64);
+        if let Some(mss) = syn.mss {
+            tracker.attr("netcore.tcp.mss", mss as u64);
+        }
+        tracker.attr("netcore.tcp.options", syn.options.join(","));
+        tracker.attr("netcore.os_guess", syn.os_guess());
+    }
+    if let Some(ja3) = tls.and_then(|tls| tls.ja3.as_deref()) {
+        let hash = hex::encode(&md5(ja3.as_bytes()));
+        fields.push(format!("ja3={}", hash));
+        tracker.attr("netcore.tls.ja3", hash);
+        tracker.attr("netcore.tls.ja3_full", ja3);
+    }
+
+    if !fields.is_empty() {
+        say!("Fingerprint of {}: {}", tracker.peer(), fields.join(" "));
+    }
+}
+
+// JA3 hashes are MD5 by definition.
+fn md5(input: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let table: Vec<u32> = (0..64)
+        .map(|i| ((i as f64 + 1.0).sin().abs() * 4294967296.0) as u32)
+        .collect();
+
+    let mut message = input.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&((input.len() as u64) * 8).to_le_bytes());
+
+    let mut state: [u32; 4] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476];
+    for block in message.chunks(64) {
+        let words: Vec<u32> = block
+            .chunks(4)
+            .map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]))
+            .collect();
+        let [mut a, mut b, mut c, mut d] = state;
+
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(table[i])
+                .wrapping_add(words[g])
+                .rotate_left(SHIFTS[(i / 16) * 4 + i % 4]);
+            (a, d, c) = (d, c, b);
+            b = b.wrapping_add(rotated);
+        }
+
+        for (word, add) in state.iter_mut().zip([a, b, c, d]) {
+            *word = word.wrapping_add(add);
+        }
+    }
+
+    let mut digest = [0u8; 16];
+    for (chunk, word) in digest.chunks_mut(4).zip(state) {
+        chunk.copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn md5_matches_rfc_1321() {
+        // The test suite from RFC 1321, appendix A.5.
+        let suite = [
+            ("", "d41d8cd98f00b204e9800998ecf8427e"),
+            ("a", "0cc175b9c0f1b6a831c399e269772661"),
+            ("abc", "900150983cd24fb0d6963f7d28e17f72"),
+            ("message digest", "f96b697d7cb7938d525a2f31aaf161d0"),
+            (
+                "abcdefghijklmnopqrstuvwxyz",
+                "c3fcd3d76192e4007dfb496cca67e13b",
+            ),
+            (
+                "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789",
+                "d174ab98d277d9f5a5611c2c9f419d9f",
+            ),
+            (
+                "12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "57edf4a22be3c955ac49da2e2107b67a",
+            ),
+        ];
+        for (input, digest) in suite {
+            assert_eq!(hex::encode(&md5(input.as_bytes())), digest, "{:?}", input);
+        }
+
+        // Lengths either side of where the padding needs another block.
+        for (length, digest) in [
+            (55, "ef1772b6dff9a122358552954ad0df65"),
+            (56, "3b0c8ac703f828b04c6c197006d17218"),
+            (63, "b06521f39153d618550606be297466d5"),
+            (64, "014842d480b571495a4a0363793f7367"),
+            (65, "c743a45e0d2e6a95cb859adae0248435"),
+        ] {
+            assert_eq!(hex::encode(&md5(&vec![b'a'; length])), digest, "{}", length);
+        }
+    }
+}
@@ -0,0 +1,508 @@
+// Why connections to a port that is listening might not arrive: reads the
+// local firewall (nftables on Linux, Windows Firewall through netsh) and
+// works out whether it lets new TCP connections to the port in, naming the
+// rule or policy that doesn't and the command that would open it. A relay
+// can check the port from outside first, so the firewall only gets blamed
+// once something actually failed to connect.
+
+use std::net::SocketAddr;
+use std::process::Command as Process;
+
+use crate::cli::{self, Command, Opt};
+use crate::exit;
+use crate::json::Value;
+use crate::outbound::{self, OutboundConfig};
+use crate::output;
+use crate::reachable;
+use crate::relay;
+use crate::say;
+use crate::timeouts;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--port",
+        value: Some("<n>"),
+        help: "TCP port to check",
+    },
+    Opt {
+        name: "--relay",
+        value: Some("<host:port>"),
+        help: "Have this netcore relay connect back to check the port from outside",
+    },
+    Opt {
+        name: "--print-fix",
+        value: None,
+        help: "Print the command that would open the port",
+    },
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Open(String),
+    Blocked { by: String, fix: String },
+    Unknown(String),
+}
+
+// One place a connection has to get through, like an nftables input chain.
+#[derive(Debug, PartialEq)]
+pub struct Finding {
+    pub scope: String,
+    pub verdict: Verdict,
+}
+
+// Whether `port` is one of `spec`: `22`, `80,443`, `{ 80, 443 }`,
+// `1000-2000` or `Any`.
+fn port_in_list(spec: &str, port: u16) -> bool {
+    spec.trim_matches(|c| c == '{' || c == '}')
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|item| !item.is_empty())
+        .any(|item| match item.split_once('-') {
+            _ if item.eq_ignore_ascii_case("any") => true,
+            Some((low, high)) => match (low.parse::<u16>(), high.parse::<u16>()) {
+                (Ok(low), Ok(high)) => (low..=high).contains(&port),
+                _ => false,
+            },
+            None => item.parse() == Ok(port),
+        })
+}
+
+struct Chain {
+    family: String,
+    table: String,
+    name: String,
+    input: bool,
+    policy: String,
+    rules: Vec<String>,
+}
+
+impl Chain {
+    fn scope(&self) -> String {
+        format!("nft {} {} {}", self.family, self.table, self.name)
+    }
+}
+
+// The chains of `nft list ruleset` output, with their rules one per line.
+fn parse_nft(ruleset: &str) -> Vec<Chain> {
+    let mut chains = Vec::new();
+    let mut table = None;
+    let mut chain: Option<Chain> = None;
+    let mut depth = 0usize;
+    for line in ruleset.lines().map(str::trim) {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match (depth, words.as_slice(), &table, &mut chain) {
+            (0, ["table", family, name, "{"], _, _) => {
+                table = Some((family.to_string(), name.to_string()));
+            }
+            (1, ["chain", name, "{"], Some((family, table)), _) => {
+                chain = Some(Chain {
+                    family: family.clone(),
+                    table: table.clone(),
+                    name: name.to_string(),
+                    input: false,
+                    policy: "accept".to_string(),
+                    rules: Vec::new(),
+                });
+            }
+            (2, ["type", ..], _, Some(chain)) => {
+                chain.input = line.contains("hook input");
+                if let Some((_, policy)) = line.split_once("policy ") {
+                    chain.policy = policy.trim_end_matches(';').trim().to_string();
+                }
+            }
+            (2, [_, ..], _, Some(chain)) if line != "}" => chain.rules.push(line.to_string()),
+            _ => {}
+        }
+        depth += line.matches('{').count();
+        depth = depth.saturating_sub(line.matches('}').count());
+        if depth < 2
+            && let Some(chain) = chain.take()
+        {
+            chains.push(chain);
+        }
+    }
+    chains
+}
+
+// Whether `rule` says anything about a new TCP connection to `port` from
+// an arbitrary address. Rules for replies, loopback, other protocols or
+// particular sources don't.
+fn applies(rule: &str, port: u16) -> bool {
+    let words: Vec<&str> = rule.split_whitespace().collect();
+    let has = |word: &str| words.contains(&word);
+    let loopback = words
+        .windows(2)
+        .any(|pair| matches!(pair[0], "iif" | "iifname") && pair[1].trim_matches('"') == "lo");
+    let other_protocol =
+        has("udp") || has("icmp") || has("icmpv6") || (has("l4proto") && !rule.contains("tcp"));
+    if (has("ct") && !rule.contains("new")) || loopback || other_protocol || has("saddr") {
+        return false;
+    }
+    let Some(at) = words.iter().position(|word| *word == "dport") else {
+        return true;
+    };
+    let (negated, rest) = match words.get(at + 1) {
+        Some(&"!=") => (true, &words[at + 2..]),
+        _ => (false, &words[at + 1..]),
+    };
+    let spec = match rest.first() {
+        Some(&"{") => rest
+            .iter()
+            .take_while(|word| **word != "}")
+            .copied()
+            .collect(),
+        Some(word) => word.to_string(),
+        None => return false,
+    };
+    port_in_list(&spec, port) != negated
+}
+
+enum Outcome<'a> {
+    Accept,
+    Block(&'a str),
+}
+
+// The first rule in `chain`, or a chain it jumps to, that decides `port`.
+fn evaluate<'a>(
+    chains: &'a [Chain],
+    chain: &'a Chain,
+    port: u16,
+    depth: usize,
+) -> Option<Outcome<'a>> {
+    for rule in chain.rules.iter().filter(|rule| applies(rule, port)) {
+        let words: Vec<&str> = rule.split_whitespace().collect();
+        let has = |word: &str| words.contains(&word);
+        if let Some(at) = words
+            .iter()
+            .position(|word| matches!(*word, "jump" | "goto"))
+        {
+            let target = chains.iter().find(|target| {
+                target.family == chain.family
+                    && target.table == chain.table
+                    && Some(&target.name.as_str()) == words.get(at + 1)
+            });
+            if let Some(outcome) = target.and_then(|target| {
+                (depth < 16)
+                    .then(|| evaluate(chains, target, port, depth + 1))
+                    .flatten()
+            }) {
+                return Some(outcome);
+            }
+        } else if has("accept") {
+            return Some(Outcome::Accept);
+        } else if has("drop") || has("reject") {
+            return Some(Outcome::Block(rule));
+        } else if has("return") {
+            return None;
+        }
+    }
+    None
+}
+
+// Every input chain sees each packet, so each has to let the port in.
+pub fn nft_findings(ruleset: &str, port: u16) -> Vec<Finding> {
+    let chains = parse_nft(ruleset);
+    let mut findings: Vec<Finding> = chains
+        .iter()
+        .filter(|chain| chain.input && matches!(chain.family.as_str(), "inet" | "ip" | "ip6"))
+        .map(|chain| {
+            let fix = format!(
+                "nft insert rule {} {} {} tcp dport {} accept",
+                chain.family, chain.table, chain.name, port
+            );
+            let verdict = match evaluate(&chains, chain, port, 0) {
+                Some(Outcome::Accept) => Verdict::Open("a rule accepts the port".to_string()),
+                Some(Outcome::Block(rule)) => Verdict::Blocked {
+                    by: format!("rule '{}'", rule),
+                    fix,
+                },
+                None if chain.policy == "accept" => {
+                    Verdict::Open("no rule matches and the policy is accept".to_string())
+                }
+                None => Verdict::Blocked {
+                    by: format!("policy {} with no rule accepting the port", chain.policy),
+                    fix,
+                },
+            };
+            Finding {
+                scope: chain.scope(),
+                verdict,
+            }
+        })
+        .collect();
+    if findings.is_empty() {
+        findings.push(Finding {
+            scope: "nft".to_string(),
+            verdict: Verdict::Open(
+                "no input chains, so nothing filters inbound traffic".to_string(),
+            ),
+        });
+    }
+    findings
+}
+
+fn netsh_field<'a>(block: &'a str, name: &str) -> &'a str {
+    block
+        .lines()
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            (key.trim() == name).then(|| value.trim())
+        })
+        .unwrap_or("")
+}
+
+// `profile` is `netsh advfirewall show currentprofile` output and `rules`
+// is `netsh advfirewall firewall show rule name=all dir=in`. Block rules
+// beat allow rules, which beat the profile's default policy.
+pub fn netsh_findings(profile: &str, rules: &str, port: u16) -> Vec<Finding> {
+    let name = profile
+        .lines()
+        .find_map(|line| line.trim().strip_suffix(" Profile Settings:"))
+        .unwrap_or("current");
+    let setting = |key: &str| {
+        profile
+            .lines()
+            .find_map(|line| line.trim().strip_prefix(key))
+            .map(str::trim)
+            .unwrap_or("")
+    };
+    let scope = format!("Windows Firewall ({} profile)", name);
+    let allow = format!(
+        "netsh advfirewall firewall add rule name=\"netcore {}\" dir=in action=allow protocol=TCP localport={}",
+        port, port
+    );
+    if setting("State").eq_ignore_ascii_case("off") {
+        let verdict = Verdict::Open("the firewall is off".to_string());
+        return vec![Finding { scope, verdict }];
+    }
+
+    let matching: Vec<&str> = rules
+        .split("Rule Name:")
+        .skip(1)
+        .filter(|block| {
+            let profiles = netsh_field(block, "Profiles");
+            netsh_field(block, "Enabled") == "Yes"
+                && matches!(netsh_field(block, "Protocol"), "TCP" | "Any")
+                && port_in_list(netsh_field(block, "LocalPort"), port)
+                && (profiles.contains(name) || profiles == "Any")
+        })
+        .collect();
+    let rule_name = |block: &str| block.lines().next().unwrap_or("").trim().to_string();
+    let policy = setting("Firewall Policy");
+    let verdict = if let Some(block) = matching
+        .iter()
+        .find(|block| netsh_field(block, "Action") == "Block")
+    {
+        Verdict::Blocked {
+            by: format!("block rule '{}'", rule_name(block)),
+            fix: format!(
+                "netsh advfirewall firewall set rule name=\"{}\" new enable=no",
+                rule_name(block)
+            ),
+        }
+    } else if policy.starts_with("BlockInboundAlways") {
+        Verdict::Blocked {
+            by: "policy BlockInboundAlways, which ignores allow rules".to_string(),
+            fix: "netsh advfirewall set currentprofile firewallpolicy blockinbound,allowoutbound"
+                .to_string(),
+        }
+    } else if let Some(block) = matching.first() {
+        Verdict::Open(format!("allowed by rule '{}'", rule_name(block)))
+    } else if policy.starts_with("BlockInbound") {
+        Verdict::Blocked {
+            by: "policy BlockInbound with no rule allowing the port".to_string(),
+            fix: allow,
+        }
+    } else {
+        Verdict::Open(format!("policy {}", policy))
+    };
+    vec![Finding { scope, verdict }]
+}
+
+fn run(program: &str, args: &[&str]) -> Result<String, String> {
+    let output = Process::new(program)
+        .args(args)
+        .output()
+        .map_err(|e| format!("cannot run {}: {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// What the local firewall does with new connections to `port`.
+pub fn inspect(port: u16) -> Vec<Finding> {
+    let (scope, result) = if cfg!(windows) {
+        let findings =
+            run("netsh", &["advfirewall", "show", "currentprofile"]).and_then(|profile| {
+                let rules = run(
+                    "netsh",
+                    &[
+                        "advfirewall",
+                        "firewall",
+                        "show",
+                        "rule",
+                        "name=all",
+                        "dir=in",
+                    ],
+                )?;
+                Ok(netsh_findings(&profile, &rules, port))
+            });
+        ("Windows Firewall", findings)
+    } else if cfg!(target_os = "linux") {
+        let findings =
+            run("nft", &["-n", "list", "ruleset"]).map(|ruleset| nft_findings(&ruleset, port));
+        ("nft", findings)
+    } else {
+        let reason = "only nftables and Windows Firewall can be inspected".to_string();
+        ("firewall", Err(reason))
+    };
+    result.unwrap_or_else(|reason| {
+        let verdict = Verdict::Unknown(reason);
+        vec![Finding {
+            scope: scope.to_string(),
+            verdict,
+        }]
+    })
+}
+
+pub const COMMAND: Command = Command {
+    name: "firewall",
+    usage: "netcore firewall --port <n> [--relay <host:port>]",
+    about: "Check whether the local firewall lets connections to a port in",
+    groups: &[OPTS, outbound::OPTS, timeouts::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let port = match cli::or_exit(args.parsed::<u16>("--port")) {
+        Some(port) => port,
+        None => {
+            eprintln!("usage: {}", COMMAND.usage);
+            std::process::exit(exit::USAGE);
+        }
+    };
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+
+    exit::or_exit(reachable::listen(port).await);
+
+    if let Some(relay) = args.value("--relay") {
+        let probe = exit::or_exit(relay::probe(&outbound, relay, port).await);
+        let target = SocketAddr::new(probe.observed, port);
+        let (status, detail) = match &probe.result {
+            Ok(()) => ("PASS", format!("{} reached {}", relay, target)),
+            Err(reason) => (
+                "FAIL",
+                format!("{} couldn't reach {}: {}", relay, target, reason),
+            ),
+        };
+        say!("{}  {}", status, detail);
+        output::verdict(status, "inbound", &detail);
+        if probe.result.is_ok() {
+            return;
+        }
+    }
+
+    let findings = tokio::task::spawn_blocking(move || inspect(port))
+        .await
+        .unwrap_or_default();
+    let mut blocked = false;
+    for finding in &findings {
+        let (status, detail, fix) = match &finding.verdict {
+            Verdict::Open(why) => ("PASS", format!("lets port {} in: {}", port, why), None),
+            Verdict::Blocked { by, fix } => {
+                blocked = true;
+                ("FAIL", format!("blocks port {}: {}", port, by), Some(fix))
+            }
+            Verdict::Unknown(why) => ("WARN", format!("cannot tell: {}", why), None),
+        };
+        say!("{}  {} {}", status, finding.scope, detail);
+        output::emit(Value::object([
+            ("status", Value::from(status.to_ascii_lowercase())),
+            ("subject", Value::from("firewall")),
+            ("scope", Value::from(finding.scope.as_str())),
+            ("detail", Value::from(detail)),
+            ("fix", Value::from(fix.cloned())),
+        ]));
+        // Asked for explicitly, so printed even with --quiet.
+        if let Some(fix) =
+            fix.filter(|_| args.flag("--print-fix") && output::mode() != output::Mode::Porcelain)
+        {
+            println!("    {}", fix);
+        }
+    }
+    if blocked || args.value("--relay").is_some() {
+        std::process::exit(exit::FAILURE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_rule_or_policy_that_blocks_a_port() {
+        let ruleset = r#"table inet filter {
+	set trusted {
+		type ipv4_addr
+		elements = { 10.0.0.1 }
+	}
+	chain input {
+		type filter hook input priority filter; policy drop;
+		ct state established,related accept
+		iif "lo" accept
+		tcp dport { 22, 8000-8100 } accept
+		ip saddr @trusted accept
+		jump extra
+	}
+	chain extra {
+		tcp dport 9000 reject with tcp reset
+		tcp dport 9001 accept
+	}
+}
+"#;
+        let verdict = |port| nft_findings(ruleset, port).remove(0).verdict;
+        assert_eq!(nft_findings(ruleset, 22)[0].scope, "nft inet filter input");
+        assert!(matches!(verdict(8080), Verdict::Open(_)));
+        assert!(matches!(verdict(9001), Verdict::Open(_)));
+        assert!(
+            matches!(verdict(9000), Verdict::Blocked { by, .. } if by.contains("reject with tcp reset"))
+        );
+        assert_eq!(
+            verdict(7000),
+            Verdict::Blocked {
+                by: "policy drop with no rule accepting the port".to_string(),
+                fix: "nft insert rule inet filter input tcp dport 7000 accept".to_string(),
+            }
+        );
+
+        let profile = "Public Profile Settings:\n\
+            ----------------------------------------------------------------------\n\
+            State                                 ON\n\
+            Firewall Policy                       BlockInbound,AllowOutbound\n";
+        let rules = "\nRule Name:                            Web\n\
+            ----------------------------------------------------------------------\n\
+            Enabled:                              Yes\n\
+            Profiles:                             Private,Public\n\
+            Protocol:                             TCP\n\
+            LocalPort:                            80,443\n\
+            Action:                               Allow\n\
+            \nRule Name:                            No telnet\n\
+            Enabled:                              Yes\n\
+            Profiles:                             Any\n\
+            Protocol:                             Any\n\
+            LocalPort:                            23\n\
+            Action:                               Block\n";
+        let verdict = |port| netsh_findings(profile, rules, port).remove(0).verdict;
+        assert!(matches!(verdict(443), Verdict::Open(by) if by.contains("Web")));
+        assert!(matches!(verdict(23), Verdict::Blocked { by, .. } if by.contains("No telnet")));
+        assert!(
+            matches!(verdict(8080), Verdict::Blocked { fix, .. } if fix.contains("localport=8080"))
+        );
+    }
+}
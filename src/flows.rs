@@ -0,0 +1,277 @@
+// Flow accounting: bytes and packets per 5-tuple, and the top talkers they
+// add up to. The server keeps a table of the connections it handled,
+// closed ones here and live ones read from stats, for `ctl flows`, `ctl
+// talkers` and `netcore top`; `sniff --flows` builds its own from the
+// packets it sees. A flow is both directions of one conversation, under
+// the address that started it.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
+
+use crate::geoip;
+use crate::ipfix;
+use crate::json::Value;
+use crate::stats;
+
+// Closed flows kept before the least recently active are forgotten.
+const CAPACITY: usize = 4096;
+pub const DEFAULT_LIMIT: usize = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub protocol: &'static str,
+    pub src: SocketAddr,
+    // Unknown for connections a handler took without a local address.
+    pub dst: Option<SocketAddr>,
+}
+
+impl Key {
+    fn reversed(&self) -> Option<Key> {
+        Some(Key {
+            protocol: self.protocol,
+            src: self.dst?,
+            dst: Some(self.src),
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Flow {
+    pub bytes: u64,
+    // Only a capture sees packets; the proxy path sees bytes.
+    pub packets: Option<u64>,
+    pub first: Instant,
+    pub last: Instant,
+}
+
+impl Flow {
+    fn to_json(self, key: &Key, now: Instant, active: bool) -> Value {
+        Value::object([
+            ("protocol", Value::from(key.protocol)),
+            ("src", Value::from(key.src.to_string())),
+            ("dst", Value::from(key.dst.map(|dst| dst.to_string()))),
+            ("bytes", Value::from(self.bytes)),
+            ("packets", Value::from(self.packets)),
+            (
+                "duration_ms",
+                Value::from((self.last - self.first).as_millis() as u64),
+            ),
+            ("idle_ms", Value::from((now - self.last).as_millis() as u64)),
+            ("active", Value::from(active)),
+        ])
+    }
+}
+
+pub struct Talker {
+    pub addr: IpAddr,
+    pub bytes: u64,
+    pub packets: Option<u64>,
+    pub flows: u64,
+}
+
+impl Talker {
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("addr", Value::from(self.addr.to_string())),
+            (
+                "geo",
+                Value::from(geoip::lookup(self.addr).map(|geo| geo.to_string())),
+            ),
+            ("bytes", Value::from(self.bytes)),
+            ("packets", Value::from(self.packets)),
+            ("flows", Value::from(self.flows)),
+        ])
+    }
+}
+
+pub struct Table {
+    flows: HashMap<Key, Flow>,
+    capacity: usize,
+}
+
+impl Table {
+    pub fn new(capacity: usize) -> Table {
+        Table {
+            flows: HashMap::new(),
+            capacity,
+        }
+    }
+
+    // Counts toward the conversation `key` belongs to, whichever way it
+    // goes.
+    pub fn add(
+        &mut self,
+        key: Key,
+        bytes: u64,
+        packets: Option<u64>,
+        first: Instant,
+        now: Instant,
+    ) {
+        let key = match key.reversed() {
+            Some(reversed) if self.flows.contains_key(&reversed) => reversed,
+            _ => key,
+        };
+        if !self.flows.contains_key(&key) && self.flows.len() >= self.capacity {
+            let idle = self
+                .flows
+                .iter()
+                .min_by_key(|(_, flow)| flow.last)
+                .map(|(key, _)| *key);
+            if let Some(idle) = idle {
+                self.flows.remove(&idle);
+            }
+        }
+        let flow = self.flows.entry(key).or_insert(Flow {
+            bytes: 0,
+            packets: None,
+            first,
+            last: now,
+        });
+        flow.bytes += bytes;
+        if let Some(packets) = packets {
+            flow.packets = Some(flow.packets.unwrap_or(0) + packets);
+        }
+        flow.last = flow.last.max(now);
+    }
+
+    pub fn len(&self) -> usize {
+        self.flows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.flows.is_empty()
+    }
+
+    // The biggest first.
+    pub fn top(&self, limit: usize) -> Vec<(Key, Flow)> {
+        top(self.flows.iter().map(|(key, flow)| (*key, *flow)), limit)
+    }
+
+    pub fn talkers(&self, limit: usize) -> Vec<Talker> {
+        talkers(self.flows.iter().map(|(key, flow)| (*key, *flow)), limit)
+    }
+}
+
+fn top(flows: impl Iterator<Item = (Key, Flow)>, limit: usize) -> Vec<(Key, Flow)> {
+    let mut flows: Vec<(Key, Flow)> = flows.collect();
+    flows.sort_by(|(a, x), (b, y)| y.bytes.cmp(&x.bytes).then(a.src.cmp(&b.src)));
+    flows.truncate(limit);
+    flows
+}
+
+// Flows summed by the address that started them, the biggest first.
+fn talkers(flows: impl Iterator<Item = (Key, Flow)>, limit: usize) -> Vec<Talker> {
+    let mut by_addr: HashMap<IpAddr, Talker> = HashMap::new();
+    for (key, flow) in flows {
+        let addr = key.src.ip().to_canonical();
+        let talker = by_addr.entry(addr).or_insert(Talker {
+            addr,
+            bytes: 0,
+            packets: None,
+            flows: 0,
+        });
+        talker.bytes += flow.bytes;
+        talker.flows += 1;
+        if let Some(packets) = flow.packets {
+            talker.packets = Some(talker.packets.unwrap_or(0) + packets);
+        }
+    }
+    let mut talkers: Vec<Talker> = by_addr.into_values().collect();
+    talkers.sort_by(|a, b| b.bytes.cmp(&a.bytes).then(a.addr.cmp(&b.addr)));
+    talkers.truncate(limit);
+    talkers
+}
+
+static SERVED: LazyLock<Mutex<Table>> = LazyLock::new(|| Mutex::new(Table::new(CAPACITY)));
+
+// A connection the server handled, as it closes.
+pub fn record(key: Key, bytes: u64, started: Instant) {
+    ipfix::finished(&key, bytes, started);
+    let now = Instant::now();
+    SERVED.lock().unwrap().add(key, bytes, None, started, now);
+}
+
+// Closed flows and live connections together, each marked active or not.
+fn served() -> Vec<(Key, Flow, bool)> {
+    let mut flows: Vec<(Key, Flow, bool)> = SERVED
+        .lock()
+        .unwrap()
+        .flows
+        .iter()
+        .map(|(key, flow)| (*key, *flow, false))
+        .collect();
+    let now = Instant::now();
+    for (key, bytes, started) in stats::live_flows() {
+        let flow = Flow {
+            bytes,
+            packets: None,
+            first: started,
+            last: now,
+        };
+        flows.push((key, flow, true));
+    }
+    flows
+}
+
+pub fn flows_json(limit: usize) -> Value {
+    let flows = served();
+    let active: Vec<Key> = flows
+        .iter()
+        .filter(|(_, _, active)| *active)
+        .map(|(key, _, _)| *key)
+        .collect();
+    let now = Instant::now();
+    let top = top(flows.into_iter().map(|(key, flow, _)| (key, flow)), limit);
+    Value::Array(
+        top.iter()
+            .map(|(key, flow)| flow.to_json(key, now, active.contains(key)))
+            .collect(),
+    )
+}
+
+pub fn talkers_json(limit: usize) -> Value {
+    let flows = served().into_iter().map(|(key, flow, _)| (key, flow));
+    Value::Array(talkers(flows, limit).iter().map(Talker::to_json).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merges_directions_and_ranks_talkers() {
+        let addr = |text: &str| text.parse::<SocketAddr>().unwrap();
+        let key = |src, dst| Key {
+            protocol: "tcp",
+            src: addr(src),
+            dst: Some(addr(dst)),
+        };
+        let now = Instant::now();
+        let mut table = Table::new(3);
+        table.add(key("10.0.0.2:5000", "10.0.0.1:80"), 100, Some(1), now, now);
+        table.add(key("10.0.0.1:80", "10.0.0.2:5000"), 900, Some(2), now, now);
+        table.add(key("10.0.0.3:6000", "10.0.0.1:80"), 50, Some(1), now, now);
+        table.add(key("10.0.0.2:5001", "10.0.0.1:80"), 20, Some(1), now, now);
+        assert_eq!(table.len(), 3);
+
+        let top = table.top(1);
+        assert_eq!(top[0].0.src, addr("10.0.0.2:5000"));
+        assert_eq!((top[0].1.bytes, top[0].1.packets), (1000, Some(3)));
+
+        let talkers = table.talkers(10);
+        assert_eq!(talkers[0].addr, "10.0.0.2".parse::<IpAddr>().unwrap());
+        assert_eq!((talkers[0].bytes, talkers[0].flows), (1020, 2));
+        assert_eq!(talkers[1].bytes, 50);
+
+        // Full: the flow idle longest makes room.
+        let later = now + std::time::Duration::from_secs(1);
+        table.add(key("10.0.0.2:5000", "10.0.0.1:80"), 1, None, now, later);
+        table.add(key("10.0.0.3:6000", "10.0.0.1:80"), 1, None, now, later);
+        table.add(key("10.0.0.4:7000", "10.0.0.1:80"), 1, None, later, later);
+        assert_eq!(table.len(), 3);
+        let top = table.top(10);
+        assert!(top.iter().all(|(key, _)| key.src.port() != 5001));
+    }
+}
@@ -0,0 +1,286 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::net::{Ipv6Addr, SocketAddr};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::aead;
+use crate::cli::{self, Command, Opt};
+use crate::dhcp;
+use crate::dns;
+use crate::exit;
+use crate::fingerprint;
+use crate::identity;
+use crate::ipv6;
+use crate::json;
+use crate::lz4;
+use crate::mux;
+use crate::ntp;
+use crate::output;
+use crate::relay;
+use crate::say;
+use crate::sshd;
+#[cfg(feature = "syn-scan")]
+use crate::synscan;
+use crate::tls;
+use crate::tunnel;
+use crate::udpscan;
+
+const DEFAULT_RUNS: u64 = 100_000;
+const DEFAULT_CORPUS: &str = "fuzz/corpus";
+const MAX_INPUT: usize = 64 * 1024;
+const INTERESTING: &[u8] = &[0x00, 0x01, 0x7f, 0x80, 0xfe, 0xff];
+
+const OPTS: &[Opt] = &[
+    Opt {
+        name: "--runs",
+        value: Some("<n>"),
+        help: "Inputs to try per target (default: 100000)",
+    },
+    Opt {
+        name: "--corpus",
+        value: Some("<dir>"),
+        help: "Seed inputs, one directory per target (default: fuzz/corpus)",
+    },
+    Opt {
+        name: "--seed",
+        value: Some("<n>"),
+        help: "Random seed, to replay a run (default: random)",
+    },
+];
+
+type Target = fn(&[u8]);
+
+// Runs an async parser on a runtime of its own, on a thread of its own as
+// the command already runs on one, so that a panic in it reaches
+// catch_unwind.
+fn block_on<F>(future: F) -> F::Output
+where
+    F: Future + Send,
+    F::Output: Send,
+{
+    std::thread::scope(|scope| {
+        scope
+            .spawn(|| {
+                tokio::runtime::Builder::new_current_thread()
+                    .enable_time()
+                    .build()
+                    .expect("failed to start a runtime")
+                    .block_on(future)
+            })
+            .join()
+            .unwrap_or_else(|payload| panic::resume_unwind(payload))
+    })
+}
+
+// The parsers of what peers and servers send, each under the name of its
+// corpus directory, and each must return normally on any input. Replies
+// read inline by one-off probes (mail, VoIP, the proxy handshakes) aren't
+// split out into parsers, so they aren't here.
+pub const TARGETS: &[(&str, Target)] = &[
+    ("dns", |data| {
+        let id = data
+            .get(..2)
+            .map_or(0, |id| u16::from_be_bytes([id[0], id[1]]));
+        let _ = dns::check_response(data, id);
+    }),
+    ("dhcp-offer", |data| {
+        let xid = data
+            .get(4..8)
+            .map_or(0, |xid| u32::from_be_bytes(xid.try_into().unwrap()));
+        let from = SocketAddr::from(([192, 0, 2, 1], 67));
+        let _ = dhcp::parse_offer(data, xid, from);
+    }),
+    ("ipv6-ra", |data| {
+        let _ = ipv6::parse_advertisement(data, Ipv6Addr::LOCALHOST, "lo".to_string());
+    }),
+    ("tls-client-hello", |data| {
+        let _ = mux::client_hello_sni(data);
+        let _ = mux::client_hello_alpn(data);
+        let _ = mux::client_hello_ja3(data);
+    }),
+    ("tls-server-hello", |data| {
+        let _ = tls::parse_server_hello(data);
+    }),
+    ("x509", |data| {
+        let _ = tls::parse_certificate(data);
+    }),
+    ("tcp-syn", |data| {
+        let _ = fingerprint::parse_syn(data);
+        #[cfg(feature = "syn-scan")]
+        let _ = synscan::parse_reply(data, 40000);
+    }),
+    ("udp-reply", |data| {
+        for &port in udpscan::COMMON_PORTS {
+            let _ = udpscan::describe(port, data);
+        }
+    }),
+    ("json", |data| {
+        let _ = json::parse(&String::from_utf8_lossy(data));
+    }),
+    ("lz4", |data| {
+        let _ = lz4::decompress(data, 16 * 1024);
+    }),
+    ("tunnel", |data| {
+        block_on(tunnel::read_frames_from(data));
+    }),
+    ("relay", |data| {
+        let mut reader = data;
+        let line = block_on(relay::read_line_within(&mut reader, Duration::from_secs(1)));
+        if let Some(line) = line {
+            let _ = relay::Request::parse(&line);
+        }
+    }),
+    ("ssh", |data| {
+        let _ = sshd::negotiate(data);
+        let _ = identity::from_blob(data);
+        let Some(length) = data.get(..4) else {
+            return;
+        };
+        if let Ok(len) = sshd::checked_length(u32::from_be_bytes(length.try_into().unwrap())) {
+            let _ = sshd::unpad(&data[4..data.len().min(4 + len)]);
+        }
+        let key = [7; aead::SSH_KEY_LEN];
+        let _ = aead::ssh_length(&key, 3, length.try_into().unwrap());
+        let _ = aead::ssh_open(&key, 3, data);
+    }),
+    ("ntp", |data| {
+        let _ = ntp::parse_reply(data, true, 3_900_000_000.0, 3_900_000_000.1);
+        let _ = ntp::parse_reply(data, false, 0.0, 0.0);
+    }),
+];
+
+fn seeds(corpus: &Path, target: &str) -> Vec<Vec<u8>> {
+    let Ok(entries) = std::fs::read_dir(corpus.join(target)) else {
+        return Vec::new();
+    };
+    let mut paths: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).collect();
+    paths.sort();
+    paths
+        .iter()
+        .filter_map(|path| std::fs::read(path).ok())
+        .collect()
+}
+
+// A few random edits of the kinds that trip length and offset handling:
+// flipped bits, boundary bytes, truncation, and bytes added or removed.
+fn mutate(rng: &mut StdRng, seeds: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = if seeds.is_empty() {
+        Vec::new()
+    } else {
+        seeds[rng.gen_range(0..seeds.len())].clone()
+    };
+    for _ in 0..rng.gen_range(1..=4) {
+        let at = rng.gen_range(0..=data.len());
+        match rng.gen_range(0..7) {
+            0 if at < data.len() => data[at] ^= 1 << rng.gen_range(0..8),
+            1 if at < data.len() => data[at] = INTERESTING[rng.gen_range(0..INTERESTING.len())],
+            2 if at < data.len() => data[at] = rng.r#gen(),
+            3 => data.truncate(at),
+            4 => {
+                let len = rng.gen_range(1..=16);
+                let bytes: Vec<u8> = (0..len).map(|_| rng.r#gen()).collect();
+                data.splice(at..at, bytes);
+            }
+            5 => {
+                let end = rng.gen_range(at..=data.len());
+                data.drain(at..end);
+            }
+            _ if !seeds.is_empty() => {
+                let other = &seeds[rng.gen_range(0..seeds.len())];
+                let from = rng.gen_range(0..=other.len());
+                data.truncate(at);
+                data.extend_from_slice(&other[from..]);
+            }
+            _ => {}
+        }
+    }
+    data.truncate(MAX_INPUT);
+    data
+}
+
+// Runs `target` over its seeds and `runs` mutations of them, returning the
+// first input that panics.
+pub fn run(target: Target, seeds: &[Vec<u8>], runs: u64, rng: &mut StdRng) -> Option<Vec<u8>> {
+    seeds
+        .iter()
+        .cloned()
+        .chain((0..runs).map(|_| mutate(rng, seeds)))
+        .find(|input| panic::catch_unwind(AssertUnwindSafe(|| target(input))).is_err())
+}
+
+pub const COMMAND: Command = Command {
+    name: "fuzz",
+    usage: "netcore fuzz [<target>...]",
+    about: "Run the built-in fuzz targets against their seed corpora",
+    groups: &[OPTS],
+};
+
+pub fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let runs = cli::or_exit(args.parsed("--runs")).unwrap_or(DEFAULT_RUNS);
+    let corpus = PathBuf::from(args.value("--corpus").unwrap_or(DEFAULT_CORPUS));
+    let seed = cli::or_exit(args.parsed("--seed")).unwrap_or_else(rand::random);
+
+    let names = args.positional();
+    for name in names {
+        if !TARGETS.iter().any(|(target, _)| target == name) {
+            let known: Vec<&str> = TARGETS.iter().map(|(target, _)| *target).collect();
+            eprintln!(
+                "unknown fuzz target '{}', expected one of {}",
+                name,
+                known.join(", ")
+            );
+            std::process::exit(exit::USAGE);
+        }
+    }
+
+    say!("Fuzzing with seed {}", seed);
+    let mut crashed = false;
+    for (name, target) in TARGETS {
+        if !names.is_empty() && !names.iter().any(|n| n == name) {
+            continue;
+        }
+        let seeds = seeds(&corpus, name);
+        let mut rng = StdRng::seed_from_u64(seed);
+        match run(*target, &seeds, runs, &mut rng) {
+            None => {
+                let detail = format!("{} seeds, {} runs", seeds.len(), runs);
+                say!("PASS  {:<18} {}", name, detail);
+                output::verdict("PASS", name, &detail);
+            }
+            Some(input) => {
+                let path = format!("crash-{}.bin", name);
+                let detail = match std::fs::write(&path, &input) {
+                    Ok(()) => format!("panicking input saved to {}", path),
+                    Err(e) => format!("failed to save input: {}", e),
+                };
+                say!("FAIL  {:<18} {}", name, detail);
+                output::verdict("FAIL", name, &detail);
+                crashed = true;
+            }
+        }
+    }
+    if crashed {
+        std::process::exit(exit::FAILURE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parsers_survive_mutated_seeds() {
+        let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join(DEFAULT_CORPUS);
+        for (name, target) in TARGETS {
+            let seeds = seeds(&corpus, name);
+            assert!(!seeds.is_empty(), "no corpus seeds for {}", name);
+            let mut rng = StdRng::seed_from_u64(153);
+            if let Some(input) = run(*target, &seeds, 5_000, &mut rng) {
+                panic!("{} panicked on {:02x?}", name, input);
+            }
+        }
+    }
+}
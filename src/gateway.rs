@@ -0,0 +1,247 @@
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::fs;
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+use crate::ipv6;
+
+const ICMP_ECHO_REPLY: u8 = 0;
+const ICMP_ECHO_REQUEST: u8 = 8;
+const ICMPV6_ECHO_REQUEST: u8 = 128;
+const ICMPV6_ECHO_REPLY: u8 = 129;
+const ECHO_PAYLOAD: &[u8] = b"netcore gateway check";
+
+pub struct Gateway {
+    pub addr: IpAddr,
+    pub interface: String,
+}
+
+pub struct Report {
+    pub gateway: Gateway,
+    pub neighbour: Result<String, String>,
+    pub ping: Result<Duration, String>,
+}
+
+impl Report {
+    pub fn ok(&self) -> bool {
+        self.neighbour.is_ok() && self.ping.is_ok()
+    }
+
+    pub fn describe(&self) -> String {
+        let link = match &self.neighbour {
+            Ok(entry) => format!("link {}", entry),
+            Err(e) => e.clone(),
+        };
+        let ping = match &self.ping {
+            Ok(rtt) => format!("ping {:.2} ms", crate::measure::millis(*rtt)),
+            Err(e) => format!("ping {}", e),
+        };
+        format!(
+            "{} on {}, {}, {}",
+            self.gateway.addr, self.gateway.interface, link, ping
+        )
+    }
+}
+
+// Default routes as gateway and interface, in table order. Point to point
+// links like VPNs route without a gateway.
+fn default_routes_ipv4() -> Vec<(Option<IpAddr>, String)> {
+    let table = fs::read_to_string("/proc/net/route").unwrap_or_default();
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 8 || fields[1] != "00000000" || fields[7] != "00000000" {
+                return None;
+            }
+            let gateway = u32::from_str_radix(fields[2], 16).ok()?;
+            let addr = (gateway != 0).then(|| IpAddr::V4(Ipv4Addr::from(gateway.to_le_bytes())));
+            Some((addr, fields[0].to_string()))
+        })
+        .collect()
+}
+
+fn default_routes_ipv6() -> Vec<(Option<IpAddr>, String)> {
+    let table = fs::read_to_string("/proc/net/ipv6_route").unwrap_or_default();
+    table
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 10
+                || fields[1] != "00"
+                || u128::from_str_radix(fields[0], 16) != Ok(0)
+                || fields[9] == "lo"
+            {
+                return None;
+            }
+            let gateway = u128::from_str_radix(fields[4], 16).ok()?;
+            let addr = (gateway != 0).then(|| IpAddr::V6(Ipv6Addr::from(gateway)));
+            Some((addr, fields[9].to_string()))
+        })
+        .collect()
+}
+
+fn first_gateway(routes: Vec<(Option<IpAddr>, String)>) -> Option<Gateway> {
+    routes.into_iter().find_map(|(addr, interface)| {
+        Some(Gateway {
+            addr: addr?,
+            interface,
+        })
+    })
+}
+
+pub fn default_ipv4() -> Option<Gateway> {
+    first_gateway(default_routes_ipv4())
+}
+
+pub fn default_ipv6() -> Option<Gateway> {
+    first_gateway(default_routes_ipv6())
+}
+
+// Every interface with a default route of either family: the paths a
+// multi-homed host can choose between.
+pub fn default_interfaces() -> Vec<String> {
+    let mut interfaces: Vec<String> = Vec::new();
+    for (_, interface) in default_routes_ipv4()
+        .into_iter()
+        .chain(default_routes_ipv6())
+    {
+        if !interfaces.contains(&interface) {
+            interfaces.push(interface);
+        }
+    }
+    interfaces
+}
+
+// The Internet checksum (RFC 1071), also used for the SYN scanner's TCP
+// segments.
+pub(crate) fn checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = data
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]) as u32)
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn icmp_socket(domain: Domain, protocol: Protocol) -> io::Result<Socket> {
+    match Socket::new(domain, Type::DGRAM, Some(protocol)) {
+        Ok(socket) => Ok(socket),
+        Err(_) => Socket::new(domain, Type::RAW, Some(protocol)),
+    }
+}
+
+fn echo(gateway: &Gateway, wait: Duration) -> io::Result<Duration> {
+    let seq: u16 = rand::random();
+    let (socket, target, request, reply) = match gateway.addr {
+        IpAddr::V4(ip) => (
+            icmp_socket(Domain::IPV4, Protocol::ICMPV4)?,
+            SocketAddr::new(IpAddr::V4(ip), 0),
+            ICMP_ECHO_REQUEST,
+            ICMP_ECHO_REPLY,
+        ),
+        IpAddr::V6(ip) => {
+            let scope = if ip.is_unicast_link_local() {
+                ipv6::interface_index(&gateway.interface)
+            } else {
+                0
+            };
+            (
+                icmp_socket(Domain::IPV6, Protocol::ICMPV6)?,
+                SocketAddr::V6(SocketAddrV6::new(ip, 0, 0, scope)),
+                ICMPV6_ECHO_REQUEST,
+                ICMPV6_ECHO_REPLY,
+            )
+        }
+    };
+
+    let mut packet = vec![request, 0, 0, 0];
+    packet.extend_from_slice(&(std::process::id() as u16).to_be_bytes());
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(ECHO_PAYLOAD);
+    if gateway.addr.is_ipv4() {
+        let sum = checksum(&packet);
+        packet[2..4].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    let start = Instant::now();
+    let deadline = start + wait;
+    socket.send_to(&packet, &SockAddr::from(target))?;
+
+    let mut buf = [MaybeUninit::<u8>::uninit(); 1500];
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+            }
+            Err(e) => return Err(e),
+        };
+        if from.as_socket().map(|from| from.ip()) != Some(gateway.addr) {
+            continue;
+        }
+
+        let data: Vec<u8> = buf[..n]
+            .iter()
+            .map(|b| unsafe { b.assume_init() })
+            .collect();
+        // Raw IPv4 sockets hand back the IP header as well.
+        let icmp = match data.first() {
+            Some(first) if gateway.addr.is_ipv4() && first >> 4 == 4 => {
+                &data[((first & 0x0f) as usize * 4).min(data.len())..]
+            }
+            _ => &data[..],
+        };
+        if icmp.len() >= 8 && icmp[0] == reply && icmp[6..8] == seq.to_be_bytes() {
+            return Ok(start.elapsed());
+        }
+    }
+}
+
+fn neighbour(gateway: &Gateway) -> Result<String, String> {
+    let output = Command::new("ip")
+        .args(["neigh", "show", "to"])
+        .arg(gateway.addr.to_string())
+        .args(["dev", &gateway.interface])
+        .output()
+        .map_err(|e| format!("cannot read neighbour table: {}", e))?;
+    let text = String::from_utf8_lossy(&output.stdout);
+    let fields: Vec<&str> = text.split_whitespace().collect();
+
+    let state = fields.last().copied().unwrap_or("");
+    let lladdr = fields
+        .iter()
+        .position(|f| *f == "lladdr")
+        .and_then(|i| fields.get(i + 1));
+
+    match (lladdr, state) {
+        (Some(lladdr), "REACHABLE" | "STALE" | "DELAY" | "PROBE" | "PERMANENT" | "NOARP") => {
+            Ok(format!("{} {}", lladdr, state.to_lowercase()))
+        }
+        (_, "") => Err("no neighbour entry".to_string()),
+        (_, state) => Err(format!("neighbour {}", state.to_lowercase())),
+    }
+}
+
+pub fn probe(gateway: Gateway, wait: Duration) -> Report {
+    // The echo request forces ARP/NDP resolution, so ping before reading the table.
+    let ping = echo(&gateway, wait).map_err(|e| e.to_string());
+    let neighbour = neighbour(&gateway);
+    Report {
+        gateway,
+        neighbour,
+        ping,
+    }
+}
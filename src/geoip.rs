@@ -0,0 +1,450 @@
+use std::fmt;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::OnceLock;
+
+use crate::cli::{Args, Opt};
+use crate::json::Value;
+
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+const MAX_DEPTH: usize = 32;
+
+pub const OPTS: &[Opt] = &[Opt {
+    name: "--geoip",
+    value: Some("<path>"),
+    help: "MaxMind/GeoLite2 .mmdb database for country and ASN lookups (repeatable)",
+}];
+
+static DATABASES: OnceLock<Vec<Database>> = OnceLock::new();
+
+pub fn init(args: &Args) -> Result<(), String> {
+    let databases = args
+        .values("--geoip")
+        .map(|path| Database::open(Path::new(path)))
+        .collect::<Result<Vec<_>, _>>()?;
+    if !databases.is_empty() {
+        let _ = DATABASES.set(databases);
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct Geo {
+    pub country: Option<String>,
+    pub asn: Option<u64>,
+    pub org: Option<String>,
+}
+
+impl fmt::Display for Geo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut parts = Vec::new();
+        parts.extend(self.country.clone());
+        parts.extend(self.asn.map(|asn| format!("AS{}", asn)));
+        parts.extend(self.org.clone());
+        write!(f, "{}", parts.join(" "))
+    }
+}
+
+pub fn lookup(ip: IpAddr) -> Option<Geo> {
+    let mut geo = Geo::default();
+    for record in DATABASES.get()?.iter().filter_map(|db| db.lookup(ip)) {
+        let country = record
+            .get("country")
+            .or_else(|| record.get("registered_country"))
+            .and_then(|country| country.get("iso_code"))
+            .and_then(Value::as_str);
+        geo.country = geo.country.or(country.map(str::to_string));
+        geo.asn = geo.asn.or(record
+            .get("autonomous_system_number")
+            .and_then(Value::as_u64));
+        geo.org = geo.org.or(record
+            .get("autonomous_system_organization")
+            .and_then(Value::as_str)
+            .map(str::to_string));
+    }
+
+    (geo.country.is_some() || geo.asn.is_some()).then_some(geo)
+}
+
+struct Database {
+    data: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    ipv4_start: usize,
+}
+
+impl Database {
+    fn open(path: &Path) -> Result<Database, String> {
+        let data = std::fs::read(path)
+            .map_err(|e| format!("failed to read GeoIP database {}: {}", path.display(), e))?;
+        let invalid = |what: &str| format!("invalid GeoIP database {}: {}", path.display(), what);
+
+        let start = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or_else(|| invalid("no metadata"))?
+            + METADATA_MARKER.len();
+        let (metadata, _) = Decoder {
+            data: &data[start..],
+        }
+        .decode(0, 0)
+        .ok_or_else(|| invalid("unreadable metadata"))?;
+
+        let field = |name: &str| {
+            metadata
+                .get(name)
+                .and_then(Value::as_u64)
+                .ok_or_else(|| invalid(&format!("missing {}", name)))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(invalid("unsupported record size"));
+        }
+        if node_count * record_size / 4 + 16 > start {
+            return Err(invalid("search tree out of bounds"));
+        }
+
+        let mut db = Database {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            ipv4_start: 0,
+        };
+        // IPv4 addresses live under ::/96 in IPv6 databases.
+        if ip_version == 6 {
+            for _ in 0..96 {
+                if db.ipv4_start >= node_count {
+                    break;
+                }
+                db.ipv4_start = db.record(db.ipv4_start, 0);
+            }
+        }
+        Ok(db)
+    }
+
+    fn record(&self, node: usize, bit: u8) -> usize {
+        let base = node * self.record_size / 4;
+        let b = |i: usize| self.data[base + i] as usize;
+        match (self.record_size, bit) {
+            (24, 0) => b(0) << 16 | b(1) << 8 | b(2),
+            (24, _) => b(3) << 16 | b(4) << 8 | b(5),
+            (28, 0) => (b(3) & 0xf0) << 20 | b(0) << 16 | b(1) << 8 | b(2),
+            (28, _) => (b(3) & 0x0f) << 24 | b(4) << 16 | b(5) << 8 | b(6),
+            (_, 0) => b(0) << 24 | b(1) << 16 | b(2) << 8 | b(3),
+            (_, _) => b(4) << 24 | b(5) << 16 | b(6) << 8 | b(7),
+        }
+    }
+
+    fn lookup(&self, ip: IpAddr) -> Option<Value> {
+        let (bits, mut node) = match ip {
+            IpAddr::V4(ip) if self.ip_version == 6 => (ip.octets().to_vec(), self.ipv4_start),
+            IpAddr::V4(ip) => (ip.octets().to_vec(), 0),
+            IpAddr::V6(ip) if self.ip_version == 6 => (ip.octets().to_vec(), 0),
+            IpAddr::V6(_) => return None,
+        };
+
+        for i in 0..bits.len() * 8 {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, (bits[i / 8] >> (7 - i % 8)) & 1);
+        }
+        if node <= self.node_count {
+            return None;
+        }
+
+        let section = self.node_count * self.record_size / 4 + 16;
+        let offset = node - self.node_count - 16;
+        Decoder {
+            data: self.data.get(section..)?,
+        }
+        .decode(offset, 0)
+        .map(|(value, _)| value)
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+}
+
+impl Decoder<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Option<&[u8]> {
+        self.data.get(offset..offset.checked_add(len)?)
+    }
+
+    fn uint(&self, offset: usize, len: usize) -> Option<u64> {
+        let bytes = self.bytes(offset, len)?;
+        Some(bytes.iter().fold(0, |n, b| n << 8 | *b as u64))
+    }
+
+    // Returns the value and the offset just past it.
+    fn decode(&self, offset: usize, depth: usize) -> Option<(Value, usize)> {
+        if depth > MAX_DEPTH {
+            return None;
+        }
+        let control = *self.data.get(offset)?;
+        let mut offset = offset + 1;
+        let mut kind = control >> 5;
+
+        if kind == 1 {
+            let size = ((control >> 3) & 0x3) as usize;
+            let low = (control & 0x7) as u64;
+            let pointer = match size {
+                0 => low << 8 | self.uint(offset, 1)?,
+                1 => (low << 16 | self.uint(offset, 2)?) + 2048,
+                2 => (low << 24 | self.uint(offset, 3)?) + 526_336,
+                _ => self.uint(offset, 4)?,
+            };
+            let (value, _) = self.decode(pointer as usize, depth + 1)?;
+            return Some((value, offset + size + 1));
+        }
+
+        if kind == 0 {
+            kind = 7 + *self.data.get(offset)?;
+            offset += 1;
+        }
+
+        let mut size = (control & 0x1f) as usize;
+        if size >= 29 {
+            let extra = size - 28;
+            let value = self.uint(offset, extra)? as usize;
+            size = match extra {
+                1 => 29 + value,
+                2 => 285 + value,
+                _ => 65_821 + value,
+            };
+            offset += extra;
+        }
+
+        match kind {
+            2 => {
+                let text = String::from_utf8_lossy(self.bytes(offset, size)?).into_owned();
+                Some((Value::from(text), offset + size))
+            }
+            3 => {
+                let bytes: [u8; 8] = self.bytes(offset, 8)?.try_into().ok()?;
+                Some((Value::from(f64::from_be_bytes(bytes)), offset + 8))
+            }
+            15 => {
+                let bytes: [u8; 4] = self.bytes(offset, 4)?.try_into().ok()?;
+                Some((Value::from(f32::from_be_bytes(bytes) as f64), offset + 4))
+            }
+            5 | 6 | 9 | 10 => {
+                let value = self.bytes(offset, size)?;
+                let value = value.iter().fold(0u128, |n, b| n << 8 | *b as u128);
+                let value = u64::try_from(value)
+                    .map_or_else(|_| Value::from(value.to_string()), Value::from);
+                Some((value, offset + size))
+            }
+            8 => {
+                let value = self.uint(offset, size)? as u32 as i32;
+                Some((Value::from(value as f64), offset + size))
+            }
+            7 => {
+                let mut fields = Vec::with_capacity(size);
+                for _ in 0..size {
+                    let (key, next) = self.decode(offset, depth + 1)?;
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    fields.push((key.as_str()?.to_string(), value));
+                    offset = next;
+                }
+                Some((Value::object(fields), offset))
+            }
+            11 => {
+                let mut items = Vec::with_capacity(size);
+                for _ in 0..size {
+                    let (value, next) = self.decode(offset, depth + 1)?;
+                    items.push(value);
+                    offset = next;
+                }
+                Some((Value::Array(items), offset))
+            }
+            14 => Some((Value::from(size != 0), offset)),
+            4 => Some((Value::Null, offset + size)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(data: &[u8]) -> Option<(Value, usize)> {
+        Decoder { data }.decode(0, 0)
+    }
+
+    fn string(text: &str) -> Vec<u8> {
+        let control = match text.len() {
+            len @ 0..29 => vec![0x40 | len as u8],
+            len => vec![0x40 | 29, (len - 29) as u8],
+        };
+        [&control[..], text.as_bytes()].concat()
+    }
+
+    fn uint(kind: u8, value: u64, len: usize) -> Vec<u8> {
+        [
+            &[kind << 5 | len as u8][..],
+            &value.to_be_bytes()[8 - len..],
+        ]
+        .concat()
+    }
+
+    #[test]
+    fn decodes_the_spec_types() {
+        // Encodings as laid out in the MaxMind DB format spec's data
+        // section: type in the top 3 bits, size in the low 5.
+        assert_eq!(decode(b"\x43abc").unwrap().0.as_str(), Some("abc"));
+        assert_eq!(decode(b"\xa2\x01\x00").unwrap().0.as_u64(), Some(256));
+        assert_eq!(
+            decode(b"\xc3\x01\x00\x00").unwrap().0.as_u64(),
+            Some(65_536)
+        );
+        assert_eq!(decode(b"\xa0").unwrap(), (Value::from(0u64), 1));
+        // uint64 and uint128 are extended types 9 and 10.
+        let big = decode(b"\x08\x02\x01\x00\x00\x00\x00\x00\x00\x00").unwrap();
+        assert_eq!(big.0.as_u64(), Some(1 << 56));
+        let huge = decode(&[&[0x09, 0x03, 0x01][..], &[0; 8]].concat()).unwrap();
+        assert_eq!(huge.0.as_str(), Some("18446744073709551616"));
+        // int32, negative.
+        let negative = decode(b"\x04\x01\xff\xff\xff\xfe").unwrap();
+        assert_eq!(negative.0, Value::from(-2.0));
+        // double and float.
+        let double = [&[0x68][..], &1.5f64.to_be_bytes()].concat();
+        assert_eq!(decode(&double).unwrap(), (Value::from(1.5), 9));
+        let float = [&[0x04, 0x08][..], &0.25f32.to_be_bytes()].concat();
+        assert_eq!(decode(&float).unwrap(), (Value::from(0.25), 6));
+        // Booleans carry their value in the size.
+        assert_eq!(decode(b"\x01\x07").unwrap(), (Value::from(true), 2));
+        assert_eq!(decode(b"\x00\x07").unwrap(), (Value::from(false), 2));
+        // An array of two strings, extended type 11.
+        let (array, end) = decode(b"\x02\x04\x42en\x42de").unwrap();
+        assert_eq!(array, Value::Array(vec!["en".into(), "de".into()]));
+        assert_eq!(end, 8);
+
+        // Sizes past 28 take extra bytes: 29 + n, 285 + n, 65821 + n.
+        let long = "x".repeat(300);
+        let mut data = vec![0x5e, 0x00, 15];
+        data.extend_from_slice(long.as_bytes());
+        assert_eq!(decode(&data).unwrap(), (Value::from(long.as_str()), 303));
+        let mut data = vec![0x5d, 1];
+        data.extend_from_slice(&long.as_bytes()[..30]);
+        assert_eq!(decode(&data).unwrap().0.as_str(), Some(&long[..30]));
+    }
+
+    #[test]
+    fn follows_pointers_and_refuses_loops() {
+        // A map whose value points back at a string earlier in the data.
+        let mut data = string("NL");
+        data.extend([0xe1]);
+        data.extend(string("iso_code"));
+        data.extend([0x20, 0x00]);
+        let (map, end) = Decoder { data: &data }.decode(3, 0).unwrap();
+        assert_eq!(map.get("iso_code").and_then(Value::as_str), Some("NL"));
+        assert_eq!(end, data.len());
+
+        // The larger pointer sizes add 2048 and 526336.
+        let mut far = vec![0x28, 0x00, 0x00];
+        far.resize(2048, 0);
+        far.extend(string("far"));
+        assert_eq!(decode(&far).unwrap(), (Value::from("far"), 3));
+
+        assert_eq!(decode(b"\x20\x00"), None);
+        assert_eq!(decode(b"\x44ab"), None);
+        assert_eq!(decode(b""), None);
+    }
+
+    fn database(record_size: usize, ip_version: u64, left: usize, right: usize) -> Vec<u8> {
+        let mut data = match record_size {
+            24 => [&left.to_be_bytes()[5..], &right.to_be_bytes()[5..]].concat(),
+            28 => {
+                let (left, right) = (left as u32, right as u32);
+                let mut node = left.to_be_bytes()[1..].to_vec();
+                node.push(((left >> 24) as u8) << 4 | (right >> 24) as u8 & 0x0f);
+                node.extend(&right.to_be_bytes()[1..]);
+                node
+            }
+            _ => [(left as u32).to_be_bytes(), (right as u32).to_be_bytes()].concat(),
+        };
+        data.extend([0; 16]);
+        data.extend([0xe3]);
+        data.extend(string("country"));
+        data.extend([0xe1]);
+        data.extend(string("iso_code"));
+        data.extend(string("NL"));
+        data.extend(string("autonomous_system_number"));
+        data.extend(uint(6, 1136, 2));
+        data.extend(string("autonomous_system_organization"));
+        data.extend(string("KPN"));
+        data.extend(METADATA_MARKER);
+        data.extend([0xe3]);
+        data.extend(string("node_count"));
+        data.extend(uint(6, 1, 1));
+        data.extend(string("record_size"));
+        data.extend(uint(5, record_size as u64, 1));
+        data.extend(string("ip_version"));
+        data.extend(uint(5, ip_version, 1));
+        data
+    }
+
+    #[test]
+    fn looks_up_networks_in_the_search_tree() {
+        let dir = std::env::temp_dir().join(format!("netcore-geoip-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("test.mmdb");
+        let ip = |value: &str| value.parse::<IpAddr>().unwrap();
+        // One node; the data record follows the 16-byte separator, so it
+        // is numbered node_count + 16.
+        let (empty, found) = (1, 17);
+
+        for record_size in [24, 28, 32] {
+            // IPv4: 0.0.0.0/1 has a record, 128.0.0.0/1 doesn't.
+            std::fs::write(&path, database(record_size, 4, found, empty)).unwrap();
+            let db = Database::open(&path).unwrap();
+            let record = db.lookup(ip("10.0.0.1")).unwrap();
+            let country = record.get("country").and_then(|c| c.get("iso_code"));
+            assert_eq!(country.and_then(Value::as_str), Some("NL"));
+            assert_eq!(
+                record
+                    .get("autonomous_system_number")
+                    .and_then(Value::as_u64),
+                Some(1136)
+            );
+            assert!(db.lookup(ip("192.0.2.1")).is_none());
+            assert!(db.lookup(ip("2001:db8::1")).is_none());
+
+            // IPv6: node 0 points to itself for a 0 bit, so ::/96 and the
+            // IPv4 addresses under it walk the same tree.
+            std::fs::write(&path, database(record_size, 6, 0, found)).unwrap();
+            let db = Database::open(&path).unwrap();
+            assert!(db.lookup(ip("8000::1")).is_some());
+            assert!(db.lookup(ip("10.0.0.1")).is_some());
+            assert!(db.lookup(ip("0.0.0.0")).is_none());
+        }
+
+        std::fs::write(&path, b"not a database").unwrap();
+        let error = Database::open(&path).err().unwrap();
+        assert!(error.contains("no metadata"), "{}", error);
+        let mut corrupt = database(24, 4, found, empty);
+        corrupt[0..6].copy_from_slice(&[0xff; 6]);
+        std::fs::write(&path, &corrupt).unwrap();
+        assert!(
+            Database::open(&path)
+                .unwrap()
+                .lookup(ip("10.0.0.1"))
+                .is_none()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let geo = Geo {
+            country: Some("NL".to_string()),
+            asn: Some(1136),
+            org: Some("KPN".to_string()),
+        };
+        assert_eq!(geo.to_string(), "NL AS1136 KPN");
+    }
+}
@@ -0,0 +1,254 @@
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{Duration, Instant, Sleep, sleep};
+
+use crate::acl;
+use crate::cli::{Args, Opt};
+use crate::units::{self, SECS};
+
+const DEFAULT_HEADER_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_MIN_RATE: u64 = 1024;
+const STALL_GRACE: Duration = Duration::from_secs(10);
+const STALL_CHECK: Duration = Duration::from_secs(1);
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--header-timeout",
+        value: Some("<duration>"),
+        help: "Close HTTP connections that don't send request headers in time (default: 10s)",
+    },
+    Opt {
+        name: "--min-rate",
+        value: Some("<rate>"),
+        help: "Close connections that read responses slower than this (default: 1K/s)",
+    },
+];
+
+#[derive(Clone, Copy)]
+pub struct Guard {
+    pub header_timeout: Duration,
+    min_rate: u64,
+}
+
+impl Guard {
+    pub fn from_args(args: &Args) -> Result<Guard, String> {
+        let header_timeout = args
+            .parsed_with("--header-timeout", |v| units::duration(v, SECS))?
+            .unwrap_or(Duration::from_secs(DEFAULT_HEADER_TIMEOUT_SECS));
+        if header_timeout.is_zero() {
+            return Err("--header-timeout must be longer than zero".to_string());
+        }
+
+        let min_rate = args
+            .parsed_with("--min-rate", units::rate)?
+            .unwrap_or(DEFAULT_MIN_RATE);
+
+        Ok(Guard {
+            header_timeout,
+            min_rate,
+        })
+    }
+
+    pub fn wrap<S>(&self, inner: S, peer: SocketAddr) -> Guarded<S> {
+        Guarded {
+            inner,
+            peer,
+            min_rate: self.min_rate as f64,
+            stalled: Duration::ZERO,
+            moved: 0,
+            stall: None,
+        }
+    }
+}
+
+struct Stall {
+    since: Instant,
+    check: Pin<Box<Sleep>>,
+}
+
+// Only time spent blocked on a full send buffer counts against the peer, so
+// idle keep-alive connections and fast readers are never measured.
+pub struct Guarded<S> {
+    inner: S,
+    peer: SocketAddr,
+    min_rate: f64,
+    stalled: Duration,
+    moved: u64,
+    stall: Option<Stall>,
+}
+
+fn too_slow(moved: u64, min_rate: f64, stalled: Duration) -> bool {
+    stalled >= STALL_GRACE && (moved as f64) < min_rate * stalled.as_secs_f64()
+}
+
+fn trip(peer: SocketAddr) -> io::Error {
+    acl::offence(peer, "slow");
+    io::Error::new(io::ErrorKind::TimedOut, "peer reads below the minimum rate")
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Guarded<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Guarded<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        // A peer that drains a little before each check comes due is
+        // caught here, on the next write, from the stalls added up so far.
+        if too_slow(this.moved, this.min_rate, this.stalled) {
+            return Poll::Ready(Err(trip(this.peer)));
+        }
+        match Pin::new(&mut this.inner).poll_write(cx, buf) {
+            Poll::Ready(Ok(n)) => {
+                if let Some(stall) = this.stall.take() {
+                    this.stalled += stall.since.elapsed();
+                    this.moved += n as u64;
+                }
+                return Poll::Ready(Ok(n));
+            }
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => {}
+        }
+
+        let stall = this.stall.get_or_insert_with(|| Stall {
+            since: Instant::now(),
+            check: Box::pin(sleep(STALL_CHECK)),
+        });
+        loop {
+            ready!(stall.check.as_mut().poll(cx));
+
+            let stalled = this.stalled + stall.since.elapsed();
+            if too_slow(this.moved, this.min_rate, stalled) {
+                return Poll::Ready(Err(trip(this.peer)));
+            }
+            stall.check.as_mut().reset(Instant::now() + STALL_CHECK);
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    // Takes `chunk` bytes per write, then blocks for `delay` before taking
+    // more, like a peer that drains its receive window at a fixed pace.
+    struct Pacer {
+        chunk: usize,
+        delay: Duration,
+        ready_at: Instant,
+    }
+
+    impl AsyncWrite for Pacer {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            // Guarded polls again on its own stall checks, so no waker is kept.
+            if Instant::now() < self.ready_at {
+                return Poll::Pending;
+            }
+            self.ready_at = Instant::now() + self.delay;
+            Poll::Ready(Ok(buf.len().min(self.chunk)))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn guarded(chunk: usize, delay: Duration) -> Guarded<Pacer> {
+        let guard = Guard {
+            header_timeout: Duration::from_secs(DEFAULT_HEADER_TIMEOUT_SECS),
+            min_rate: DEFAULT_MIN_RATE,
+        };
+        let pacer = Pacer {
+            chunk,
+            delay,
+            ready_at: Instant::now(),
+        };
+        guard.wrap(pacer, "192.0.2.1:4000".parse().unwrap())
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn trips_on_slow_readers_after_the_grace_period() {
+        let cases = [
+            // (bytes per write, pause between writes, trips)
+            (4096, Duration::from_secs(1), false),
+            (1024, Duration::from_secs(1), false),
+            (512, Duration::from_secs(1), true),
+            // Drains before every stall check, but still far too slowly.
+            (100, Duration::from_millis(900), true),
+            (64 * 1024, Duration::from_secs(30), true),
+            (1, Duration::ZERO, false),
+        ];
+        for (chunk, delay, trips) in cases {
+            let mut stream = guarded(chunk, delay);
+            let started = Instant::now();
+            let result = stream.write_all(&[0; 128 * 1024]).await;
+            assert_eq!(result.is_err(), trips, "{} bytes every {:?}", chunk, delay);
+            if let Err(e) = result {
+                assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+                assert!(started.elapsed() >= STALL_GRACE);
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn counts_only_time_spent_blocked() {
+        let mut stream = guarded(2048, Duration::from_secs(1));
+        for _ in 0..5 {
+            stream.write_all(&[0; 4096]).await.unwrap();
+            // Idle time between responses, as on a keep-alive connection,
+            // never counts against the peer.
+            sleep(Duration::from_secs(60)).await;
+        }
+        assert!(stream.stall.is_none());
+        assert!(stream.stalled < STALL_GRACE);
+        assert_eq!(stream.moved, 5 * 2048);
+    }
+
+    #[test]
+    fn reads_its_limits() {
+        let parse = |tokens: &[&str]| {
+            let args = Args::parse(tokens.iter().map(|t| t.to_string()), &[OPTS]).unwrap();
+            Guard::from_args(&args)
+        };
+        let guard = parse(&[]).unwrap();
+        assert_eq!(guard.header_timeout, Duration::from_secs(10));
+        assert_eq!(guard.min_rate, 1024);
+
+        let guard = parse(&["--header-timeout", "500ms", "--min-rate", "4K"]).unwrap();
+        assert_eq!(guard.header_timeout, Duration::from_millis(500));
+        assert_eq!(guard.min_rate, 4096);
+
+        assert!(parse(&["--header-timeout", "0"]).is_err());
+    }
+}
@@ -0,0 +1,253 @@
+// gzip (RFC 1952) around a single deflate block (RFC 1951) with the fixed
+// Huffman codes and greedy LZ77 matching over a 32 KiB window. It compresses
+// rotated logs well enough, as they repeat a lot, without the dynamic code
+// tables a general-purpose compressor would build.
+
+const WINDOW: usize = 32 * 1024;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+const HASH_BITS: u32 = 15;
+// Candidates tried per position before settling for the best so far.
+const MAX_CHAIN: usize = 32;
+const END_OF_BLOCK: u16 = 256;
+
+// (base, extra bits) for length codes 257..=285.
+const LENGTHS: [(u16, u8); 29] = [
+    (3, 0),
+    (4, 0),
+    (5, 0),
+    (6, 0),
+    (7, 0),
+    (8, 0),
+    (9, 0),
+    (10, 0),
+    (11, 1),
+    (13, 1),
+    (15, 1),
+    (17, 1),
+    (19, 2),
+    (23, 2),
+    (27, 2),
+    (31, 2),
+    (35, 3),
+    (43, 3),
+    (51, 3),
+    (59, 3),
+    (67, 4),
+    (83, 4),
+    (99, 4),
+    (115, 4),
+    (131, 5),
+    (163, 5),
+    (195, 5),
+    (227, 5),
+    (258, 0),
+];
+
+// (base, extra bits) for distance codes 0..=29.
+const DISTANCES: [(u16, u8); 30] = [
+    (1, 0),
+    (2, 0),
+    (3, 0),
+    (4, 0),
+    (5, 1),
+    (7, 1),
+    (9, 2),
+    (13, 2),
+    (17, 3),
+    (25, 3),
+    (33, 4),
+    (49, 4),
+    (65, 5),
+    (97, 5),
+    (129, 6),
+    (193, 6),
+    (257, 7),
+    (385, 7),
+    (513, 8),
+    (769, 8),
+    (1025, 9),
+    (1537, 9),
+    (2049, 10),
+    (3073, 10),
+    (4097, 11),
+    (6145, 11),
+    (8193, 12),
+    (12289, 12),
+    (16385, 13),
+    (24577, 13),
+];
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+struct Bits {
+    out: Vec<u8>,
+    buffer: u64,
+    count: u32,
+}
+
+impl Bits {
+    // Least significant bit first, as deflate packs everything but codes.
+    fn put(&mut self, value: u32, bits: u32) {
+        self.buffer |= (value as u64) << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.out.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes go most significant bit first.
+    fn code(&mut self, code: u32, bits: u32) {
+        self.put(code.reverse_bits() >> (32 - bits), bits);
+    }
+
+    fn symbol(&mut self, symbol: u16) {
+        match symbol {
+            0..=143 => self.code(0x30 + symbol as u32, 8),
+            144..=255 => self.code(0x190 + (symbol as u32 - 144), 9),
+            256..=279 => self.code(symbol as u32 - 256, 7),
+            _ => self.code(0xc0 + (symbol as u32 - 280), 8),
+        }
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.out.push(self.buffer as u8);
+        }
+        self.out
+    }
+}
+
+fn hash(data: &[u8]) -> usize {
+    let value = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+    (value.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+}
+
+fn code_of(table: &[(u16, u8)], value: u16) -> usize {
+    table
+        .iter()
+        .rposition(|(base, _)| *base <= value)
+        .unwrap_or(0)
+}
+
+pub fn deflate(data: &[u8]) -> Vec<u8> {
+    let mut bits = Bits {
+        out: Vec::with_capacity(data.len() / 2),
+        buffer: 0,
+        count: 0,
+    };
+    // BFINAL, then BTYPE 01: fixed Huffman codes.
+    bits.put(1, 1);
+    bits.put(1, 2);
+
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut previous = vec![usize::MAX; WINDOW];
+    let insert = |head: &mut Vec<usize>, previous: &mut Vec<usize>, at: usize| {
+        if at + MIN_MATCH <= data.len() {
+            let h = hash(&data[at..]);
+            previous[at % WINDOW] = head[h];
+            head[h] = at;
+        }
+    };
+
+    let mut at = 0;
+    while at < data.len() {
+        let mut best = (0, 0);
+        if at + MIN_MATCH <= data.len() {
+            let mut candidate = head[hash(&data[at..])];
+            let limit = (data.len() - at).min(MAX_MATCH);
+            for _ in 0..MAX_CHAIN {
+                if candidate == usize::MAX || at - candidate > WINDOW {
+                    break;
+                }
+                let len = data[candidate..]
+                    .iter()
+                    .zip(&data[at..at + limit])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best.0 {
+                    best = (len, at - candidate);
+                    if len == limit {
+                        break;
+                    }
+                }
+                let next = previous[candidate % WINDOW];
+                if next == usize::MAX || next >= candidate {
+                    break;
+                }
+                candidate = next;
+            }
+        }
+
+        let (len, distance) = best;
+        if len >= MIN_MATCH {
+            let code = code_of(&LENGTHS, len as u16);
+            let (base, extra) = LENGTHS[code];
+            bits.symbol(257 + code as u16);
+            bits.put((len as u16 - base) as u32, extra as u32);
+            let code = code_of(&DISTANCES, distance as u16);
+            let (base, extra) = DISTANCES[code];
+            bits.code(code as u32, 5);
+            bits.put((distance as u16 - base) as u32, extra as u32);
+            for skipped in at..at + len {
+                insert(&mut head, &mut previous, skipped);
+            }
+            at += len;
+        } else {
+            bits.symbol(data[at] as u16);
+            insert(&mut head, &mut previous, at);
+            at += 1;
+        }
+    }
+    bits.symbol(END_OF_BLOCK);
+    bits.finish()
+}
+
+// A gzip member holding `data`, with no name and no timestamp.
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    // Magic, deflate, no flags, no mtime, no extra flags, Unix.
+    let mut out = vec![0x1f, 0x8b, 8, 0, 0, 0, 0, 0, 0, 3];
+    out.extend(deflate(data));
+    out.extend(crc32(data).to_le_bytes());
+    out.extend((data.len() as u32).to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compresses_repetitive_text_into_a_gzip_member() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b""), 0);
+        // Just the block header and end of block.
+        assert_eq!(deflate(b""), [0x03, 0x00]);
+        // "a" as a literal, then a match of 4 at distance 1.
+        assert_eq!(deflate(b"aaaaa"), [0x4b, 0x04, 0x01, 0x00]);
+
+        let line = b"{\"kind\":\"connection_closed\",\"peer\":\"192.0.2.7:40312\"}\n";
+        let data = line.repeat(200);
+        let gzip = compress(&data);
+        assert!(gzip.len() < data.len() / 20);
+        assert_eq!(&gzip[..3], &[0x1f, 0x8b, 8]);
+        let trailer = &gzip[gzip.len() - 8..];
+        assert_eq!(trailer[..4], crc32(&data).to_le_bytes());
+        assert_eq!(trailer[4..], (data.len() as u32).to_le_bytes());
+    }
+}
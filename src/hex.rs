@@ -0,0 +1,34 @@
+// Lower-case hex, as digests, trace ids and fingerprints are printed.
+
+pub fn encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// The bytes `text` spells, in either case; None unless it's all hex digit
+// pairs.
+pub fn decode(text: &str) -> Option<Vec<u8>> {
+    // from_str_radix would take a sign as well.
+    if !text.len().is_multiple_of(2) || !text.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_and_rejects_odd_or_foreign_digits() {
+        assert_eq!(encode(&[0x00, 0xab, 0x7f]), "00ab7f");
+        assert_eq!(decode("00AB7f"), Some(vec![0x00, 0xab, 0x7f]));
+        assert_eq!(decode(""), Some(vec![]));
+        assert_eq!(decode("abc"), None);
+        assert_eq!(decode("zz"), None);
+        assert_eq!(decode("+1"), None);
+        assert_eq!(decode("é1"), None);
+    }
+}
@@ -0,0 +1,490 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::audit;
+use crate::cli::{self, Args, Command, Opt};
+use crate::exit;
+use crate::json::Value;
+use crate::output;
+use crate::say;
+
+pub const NONE: &str = "-";
+const SECS_PER_DAY: u64 = 86_400;
+// The file is rotated at this size, the way the audit log is, to <path>.1
+// up to <path>.8. A check job every minute writes about 4 MiB a week, so
+// queries reach back two months; past the oldest kept file they say so.
+const MAX_SIZE: u64 = 4 * 1024 * 1024;
+const KEEP: usize = 8;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--record",
+        value: None,
+        help: "Append results to the measurement history",
+    },
+    Opt {
+        name: "--history-file",
+        value: Some("<path>"),
+        help: "History file location (implies --record)",
+    },
+];
+
+const QUERY_OPTS: &[Opt] = &[
+    Opt {
+        name: "--history-file",
+        value: Some("<path>"),
+        help: "History file location",
+    },
+    Opt {
+        name: "--since",
+        value: Some("<days>"),
+        help: "Only consider the last N days",
+    },
+    Opt {
+        name: "--kind",
+        value: Some("<kind>"),
+        help: "Only list records of this kind (info, ping, ...)",
+    },
+];
+
+pub struct Record {
+    pub time: u64,
+    pub kind: String,
+    pub subject: String,
+    pub value: String,
+}
+
+#[derive(Clone)]
+pub struct History {
+    path: PathBuf,
+}
+
+impl History {
+    pub fn new(path: PathBuf) -> History {
+        History { path }
+    }
+
+    pub fn from_args(args: &Args) -> Option<History> {
+        match args.value("--history-file") {
+            Some(path) => Some(History::new(PathBuf::from(path))),
+            None => args.flag("--record").then(|| History::new(default_path())),
+        }
+    }
+
+    pub fn append(&self, kind: &str, subject: &str, value: &str) -> io::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let line = format!(
+            "{}\t{}\t{}\t{}\n",
+            now(),
+            clean(kind),
+            clean(subject),
+            clean(value)
+        );
+        if fs::metadata(&self.path).is_ok_and(|m| m.len() + line.len() as u64 > MAX_SIZE) {
+            audit::rotate(&self.path, KEEP, false)?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(line.as_bytes())
+    }
+
+    // Oldest first, from every kept file.
+    pub fn records(&self) -> io::Result<Vec<Record>> {
+        let mut text = Vec::new();
+        for n in (1..=KEEP).rev() {
+            text.extend(tail(&audit::rotated(&self.path, n, false), MAX_SIZE)?);
+        }
+        text.extend(tail(&self.path, MAX_SIZE)?);
+
+        let mut records = Vec::new();
+        for line in String::from_utf8_lossy(&text).lines() {
+            let mut fields = line.splitn(4, '\t');
+            let (Some(time), Some(kind), Some(subject), Some(value)) =
+                (fields.next(), fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let Ok(time) = time.parse() else {
+                continue;
+            };
+
+            records.push(Record {
+                time,
+                kind: kind.to_string(),
+                subject: subject.to_string(),
+                value: value.to_string(),
+            });
+        }
+
+        Ok(records)
+    }
+
+    // When the oldest kept file is there, earlier records may have been
+    // rotated away, so a query reaching back further than these is cut.
+    pub fn kept_since(&self, records: &[Record]) -> Option<u64> {
+        audit::rotated(&self.path, KEEP, false)
+            .exists()
+            .then(|| records.first().map_or(0, |r| r.time))
+    }
+}
+
+// Up to the last `limit` bytes of `path`, from the start of a line.
+fn tail(path: &Path, limit: u64) -> io::Result<Vec<u8>> {
+    let mut file = match fs::File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let len = file.metadata()?.len();
+    // One byte more than asked for, so a cut that lands on a line's start
+    // doesn't lose that line.
+    let start = len.saturating_sub(limit.saturating_add(1));
+    file.seek(SeekFrom::Start(start))?;
+    let mut data = Vec::new();
+    file.take(limit.saturating_add(1)).read_to_end(&mut data)?;
+    if start > 0 || data.len() as u64 > limit {
+        let line = data
+            .iter()
+            .position(|&b| b == b'\n')
+            .map_or(data.len(), |i| i + 1);
+        data.drain(..line);
+    }
+    Ok(data)
+}
+
+fn clean(field: &str) -> String {
+    field.replace(['\t', '\n', '\r'], " ")
+}
+
+pub fn default_path() -> PathBuf {
+    let base = match (env::var_os("XDG_DATA_HOME"), env::var_os("HOME")) {
+        (Some(data), _) => PathBuf::from(data),
+        (None, Some(home)) => PathBuf::from(home).join(".local").join("share"),
+        (None, None) => return PathBuf::from("netcore-history.tsv"),
+    };
+
+    base.join("netcore").join("history.tsv")
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day)
+}
+
+// The inverse of civil_from_days.
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+pub fn format_date(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / SECS_PER_DAY) as i64);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+pub fn format_time(secs: u64) -> String {
+    let rem = secs % SECS_PER_DAY;
+    format!(
+        "{} {:02}:{:02}:{:02}",
+        format_date(secs),
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    if values.len().is_multiple_of(2) {
+        Some((values[mid - 1] + values[mid]) / 2.0)
+    } else {
+        Some(values[mid])
+    }
+}
+
+fn public_ip_changes(records: &[Record]) {
+    let mut last: HashMap<&str, &str> = HashMap::new();
+    let mut changes = 0;
+
+    for record in records
+        .iter()
+        .filter(|r| r.kind == "info" && r.subject.starts_with("public_"))
+    {
+        match last.insert(&record.subject, &record.value) {
+            Some(previous) if previous != record.value => {
+                say!(
+                    "{}  {}  {} -> {}",
+                    format_time(record.time),
+                    record.subject,
+                    previous,
+                    record.value
+                );
+                changes += 1;
+            }
+            Some(_) => {}
+            None => say!(
+                "{}  {}  {} (first seen)",
+                format_time(record.time),
+                record.subject,
+                record.value
+            ),
+        }
+    }
+
+    say!("{} public IP change(s) recorded", changes);
+}
+
+fn rtt_per_day(records: &[Record]) {
+    let mut days: BTreeMap<(u64, &str), (Vec<f64>, usize)> = BTreeMap::new();
+
+    for record in records.iter().filter(|r| r.kind == "ping") {
+        let entry = days
+            .entry((record.time / SECS_PER_DAY, &record.subject))
+            .or_default();
+        match record.value.parse() {
+            Ok(rtt) => entry.0.push(rtt),
+            Err(_) => entry.1 += 1,
+        }
+    }
+
+    if days.is_empty() {
+        say!("No RTT measurements recorded");
+        return;
+    }
+
+    for ((day, subject), (mut samples, lost)) in days {
+        let date = format_date(day * SECS_PER_DAY);
+        match median(&mut samples) {
+            Some(rtt) => say!(
+                "{}  {}  median {:.2} ms ({} samples, {} lost)",
+                date,
+                subject,
+                rtt,
+                samples.len(),
+                lost
+            ),
+            None => say!("{}  {}  no replies ({} lost)", date, subject, lost),
+        }
+    }
+}
+
+fn list(records: &[Record], kind: Option<&str>) {
+    for record in records
+        .iter()
+        .filter(|r| kind.is_none_or(|kind| r.kind == kind))
+    {
+        say!(
+            "{}  {}  {}  {}",
+            format_time(record.time),
+            record.kind,
+            record.subject,
+            record.value
+        );
+        output::emit(Value::object([
+            ("time", Value::from(record.time)),
+            ("kind", Value::from(record.kind.as_str())),
+            ("subject", Value::from(record.subject.as_str())),
+            ("value", Value::from(record.value.as_str())),
+        ]));
+    }
+}
+
+pub const COMMAND: Command = Command {
+    name: "history",
+    usage: "netcore history <public-ip|rtt|list>",
+    about: "Show recorded measurements",
+    groups: &[QUERY_OPTS],
+};
+
+pub fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+
+    let history = History::new(
+        args.value("--history-file")
+            .map(PathBuf::from)
+            .unwrap_or_else(default_path),
+    );
+    let since = cli::or_exit(args.parsed::<u64>("--since"))
+        .map(|days| now().saturating_sub(days.saturating_mul(SECS_PER_DAY)));
+
+    let mut records = match history.records() {
+        Ok(records) => records,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", history.path.display(), e);
+            std::process::exit(exit::code(&e));
+        }
+    };
+    if let Some(kept) = history.kept_since(&records)
+        && since.is_none_or(|since| since < kept)
+    {
+        eprintln!(
+            "Records before {} have been rotated out of {}",
+            format_time(kept),
+            history.path.display()
+        );
+    }
+    records.retain(|r| since.is_none_or(|since| r.time >= since));
+
+    match args.positional().first().map(String::as_str) {
+        Some("public-ip") => public_ip_changes(&records),
+        Some("rtt") => rtt_per_day(&records),
+        Some("list") | None => list(&records, args.value("--kind")),
+        Some(other) => {
+            eprintln!("unknown history query '{}'", other);
+            std::process::exit(exit::USAGE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_days_to_dates() {
+        for (days, date) in [
+            (0, (1970, 1, 1)),
+            (-1, (1969, 12, 31)),
+            (11_016, (2000, 2, 29)),
+            (11_017, (2000, 3, 1)),
+            (-25_508, (1900, 3, 1)),
+            (47_540, (2100, 2, 28)),
+            (20_088, (2024, 12, 31)),
+            (-719_162, (1, 1, 1)),
+            (2_932_896, (9999, 12, 31)),
+        ] {
+            assert_eq!(civil_from_days(days), date, "{}", days);
+            assert_eq!(days_from_civil(date.0, date.1, date.2), days);
+        }
+        for days in -800_000..800_000 {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+        assert_eq!(format_time(1_700_000_000), "2023-11-14 22:13:20");
+        assert_eq!(format_date(0), "1970-01-01");
+    }
+
+    #[test]
+    fn rotates_and_reads_the_tail() {
+        assert_eq!(
+            tail(Path::new("/nonexistent/netcore-history.tsv"), 10).unwrap(),
+            b""
+        );
+
+        let dir = env::temp_dir().join(format!("netcore-history-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.tsv");
+        fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        assert_eq!(tail(&path, 100).unwrap(), b"one\ntwo\nthree\n");
+        assert_eq!(tail(&path, 14).unwrap(), b"one\ntwo\nthree\n");
+        assert_eq!(tail(&path, 13).unwrap(), b"two\nthree\n");
+        assert_eq!(tail(&path, 10).unwrap(), b"two\nthree\n");
+        assert_eq!(tail(&path, 9).unwrap(), b"three\n");
+        assert_eq!(tail(&path, 3).unwrap(), b"");
+
+        // A full file moves to .1 and queries see both, newest last.
+        let old = "1\tping\thost\t1.0\n".repeat(MAX_SIZE as usize / 16);
+        fs::write(&path, &old).unwrap();
+        let history = History::new(path.clone());
+        history.append("ping", "host", "2.0").unwrap();
+        assert_eq!(
+            fs::read_to_string(audit::rotated(&path, 1, false)).unwrap(),
+            old
+        );
+        let records = history.records().unwrap();
+        let newest = records.last().unwrap();
+        assert_eq!(
+            (newest.kind.as_str(), newest.value.as_str()),
+            ("ping", "2.0")
+        );
+        assert_eq!(records[0].value, "1.0");
+        assert_eq!(records.len(), old.len() / 16 + 1);
+
+        history.append("ping", "host", "3.0").unwrap();
+        let values: Vec<String> = history
+            .records()
+            .unwrap()
+            .into_iter()
+            .map(|r| r.value)
+            .collect();
+        assert_eq!(values[values.len() - 2..], ["2.0", "3.0"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keeps_rotated_files_and_says_when_it_dropped_some() {
+        let dir = env::temp_dir().join(format!("netcore-history-keep-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history.tsv");
+        let history = History::new(path.clone());
+        let line = |time: usize| format!("{}\tping\thost\t1.0\n", time);
+
+        for n in 1..KEEP {
+            fs::write(audit::rotated(&path, n, false), line(100 - n)).unwrap();
+        }
+        fs::write(&path, line(200)).unwrap();
+        let records = history.records().unwrap();
+        let times: Vec<u64> = records.iter().map(|r| r.time).collect();
+        assert_eq!(times, [93, 94, 95, 96, 97, 98, 99, 200]);
+        assert_eq!(history.kept_since(&records), None);
+
+        // Filling the last slot means the next rotation drops the oldest.
+        fs::write(audit::rotated(&path, KEEP, false), line(100 - KEEP)).unwrap();
+        let records = history.records().unwrap();
+        assert_eq!(records.len(), KEEP + 1);
+        assert_eq!(history.kept_since(&records), Some(92));
+
+        fs::write(&path, line(200).repeat(MAX_SIZE as usize / line(200).len())).unwrap();
+        history.append("ping", "host", "2.0").unwrap();
+        let records = history.records().unwrap();
+        assert_eq!(records[0].time, 93);
+        assert_eq!(history.kept_since(&records), Some(93));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn takes_medians() {
+        assert_eq!(median(&mut []), None);
+        assert_eq!(median(&mut [4.0]), Some(4.0));
+        assert_eq!(median(&mut [9.0, 1.0, 5.0]), Some(5.0));
+        assert_eq!(median(&mut [9.0, 1.0, 5.0, 2.0]), Some(3.5));
+        assert_eq!(median(&mut [-0.5, 0.5]), Some(0.0));
+    }
+}
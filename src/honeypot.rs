@@ -0,0 +1,299 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{Duration, Instant, timeout_at};
+
+use crate::acl;
+use crate::cli::{self, Args, Command, Opt};
+use crate::exit;
+use crate::history;
+use crate::json::Value;
+use crate::say;
+use crate::stats;
+use crate::units;
+
+const DEFAULT_SSH_PORT: u16 = 2222;
+const DEFAULT_HTTP_PORT: u16 = 8080;
+const DEFAULT_MAX_PAYLOAD: usize = 4096;
+const SESSION_SECS: u64 = 30;
+const SSH_BANNER: &str = "SSH-2.0-OpenSSH_8.9p1 Ubuntu-3ubuntu0.6\r\n";
+const HTTP_SERVER: &str = "Apache/2.4.52 (Ubuntu)";
+
+const OPTS: &[Opt] = &[
+    Opt {
+        name: "--bind",
+        value: Some("<ip>"),
+        help: "Address to listen on (default: 0.0.0.0)",
+    },
+    Opt {
+        name: "--ssh-port",
+        value: Some("<port>"),
+        help: "Fake SSH port, 0 to disable (default: 2222)",
+    },
+    Opt {
+        name: "--http-port",
+        value: Some("<port>"),
+        help: "Fake HTTP admin port, 0 to disable (default: 8080)",
+    },
+    Opt {
+        name: "--log",
+        value: Some("<path>"),
+        help: "JSONL interaction log (default: honeypot.jsonl next to the history file)",
+    },
+    Opt {
+        name: "--max-payload",
+        value: Some("<size>"),
+        help: "Most input kept per interaction (default: 4K)",
+    },
+];
+
+const LOGIN_PAGE: &str = "<!DOCTYPE html>\n<html><head><title>Administration</title></head>\n<body><h2>Router Administration</h2>\n<form method=\"post\">\n<p>Username <input name=\"username\"></p>\n<p>Password <input name=\"password\" type=\"password\"></p>\n<p><input type=\"submit\" value=\"Log in\"></p>\n</form></body></html>\n";
+
+struct Log {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl Log {
+    fn write(&self, event: Value) {
+        let _guard = self.lock.lock().unwrap();
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| writeln!(file, "{}", event));
+        if let Err(e) = result {
+            eprintln!("Failed to write {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+struct Input {
+    bytes: Vec<u8>,
+    truncated: bool,
+}
+
+impl Input {
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.bytes).into_owned()
+    }
+}
+
+// Reads until `done` says the request is complete, the peer goes quiet or
+// closes, or the payload limit is hit; whatever arrived is kept.
+async fn read_input(socket: &mut TcpStream, limit: usize, done: impl Fn(&[u8]) -> bool) -> Input {
+    let deadline = Instant::now() + Duration::from_secs(SESSION_SECS);
+    let mut bytes = Vec::new();
+    let mut chunk = [0; 1024];
+
+    while bytes.len() < limit && !done(&bytes) {
+        match timeout_at(deadline, socket.read(&mut chunk)).await {
+            Ok(Ok(n)) if n > 0 => bytes.extend_from_slice(&chunk[..n]),
+            _ => break,
+        }
+    }
+    let truncated = bytes.len() > limit;
+    bytes.truncate(limit);
+    Input { bytes, truncated }
+}
+
+fn event(service: &str, peer: SocketAddr, input: &Input, fields: Vec<(&str, Value)>) -> Value {
+    let mut event = vec![
+        ("time", Value::from(history::now())),
+        ("service", Value::from(service)),
+        ("peer", Value::from(peer.ip().to_string())),
+        ("peer_port", Value::from(peer.port() as u64)),
+        ("bytes", Value::from(input.bytes.len() as u64)),
+        ("truncated", Value::from(input.truncated)),
+    ];
+    event.extend(fields);
+    event.push(("input", Value::from(input.text())));
+    Value::object(event)
+}
+
+async fn ssh_session(mut socket: TcpStream, peer: SocketAddr, log: Arc<Log>, limit: usize) {
+    let tracker = stats::track("honeypot-ssh", peer);
+    if socket.write_all(SSH_BANNER.as_bytes()).await.is_err() {
+        return;
+    }
+    tracker.sent(SSH_BANNER.len() as u64);
+
+    let input = read_input(&mut socket, limit, |_| false).await;
+    tracker.received(input.bytes.len() as u64);
+
+    let text = input.text();
+    let client = text
+        .lines()
+        .find(|line| line.starts_with("SSH-"))
+        .map(|line| line.trim_end().to_string());
+    say!(
+        "Honeypot ssh from {}: {} bytes, client {}",
+        peer,
+        input.bytes.len(),
+        client.as_deref().unwrap_or("-").escape_debug()
+    );
+    log.write(event(
+        "ssh",
+        peer,
+        &input,
+        vec![("client", Value::from(client))],
+    ));
+}
+
+fn request_complete(bytes: &[u8]) -> bool {
+    let Some(end) = bytes.windows(4).position(|w| w == b"\r\n\r\n") else {
+        return false;
+    };
+    let head = String::from_utf8_lossy(&bytes[..end]);
+    let length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse::<usize>().ok())
+        .unwrap_or(0);
+    bytes.len() >= end + 4 + length
+}
+
+async fn http_session(mut socket: TcpStream, peer: SocketAddr, log: Arc<Log>, limit: usize) {
+    let tracker = stats::track("honeypot-http", peer);
+    let input = read_input(&mut socket, limit, request_complete).await;
+    tracker.received(input.bytes.len() as u64);
+
+    let text = input.text();
+    let mut request_line = text.lines().next().unwrap_or_default().split_whitespace();
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    let user_agent = text
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("user-agent"))
+        .map(|(_, value)| value.trim().to_string());
+
+    let (status, body) = match method.as_str() {
+        "GET" | "HEAD" => ("200 OK", LOGIN_PAGE.to_string()),
+        "POST" => (
+            "401 Unauthorized",
+            LOGIN_PAGE.replace("<form", "<p>Invalid username or password</p>\n<form"),
+        ),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nServer: {}\r\nContent-Type: text/html\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        HTTP_SERVER,
+        body.len(),
+        if method == "HEAD" { "" } else { &body }
+    );
+    if socket.write_all(response.as_bytes()).await.is_ok() {
+        tracker.sent(response.len() as u64);
+    }
+
+    say!(
+        "Honeypot http from {}: {} {}",
+        peer,
+        method.escape_debug(),
+        path.escape_debug()
+    );
+    log.write(event(
+        "http",
+        peer,
+        &input,
+        vec![
+            ("method", Value::from(method)),
+            ("path", Value::from(path)),
+            ("user_agent", Value::from(user_agent)),
+        ],
+    ));
+}
+
+async fn listen(listener: TcpListener, service: &'static str, log: Arc<Log>, limit: usize) {
+    loop {
+        let (socket, peer) = match listener.accept().await {
+            Ok((_, peer)) if acl::check(peer).is_some() => continue,
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("Honeypot {} accept error: {}", service, e);
+                continue;
+            }
+        };
+
+        let log = log.clone();
+        if service == "ssh" {
+            tokio::spawn(ssh_session(socket, peer, log, limit));
+        } else {
+            tokio::spawn(http_session(socket, peer, log, limit));
+        }
+    }
+}
+
+async fn bind(ip: IpAddr, port: u16, service: &str) -> Result<Option<TcpListener>, exit::Error> {
+    if port == 0 {
+        return Ok(None);
+    }
+    let addr = SocketAddr::new(ip, port);
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| exit::Error::io(format!("failed to listen on {} for {}", addr, service), e))?;
+    say!("Honeypot {} listening on {}", service, addr);
+    Ok(Some(listener))
+}
+
+fn log_path(args: &Args) -> PathBuf {
+    match args.value("--log") {
+        Some(path) => PathBuf::from(path),
+        None => history::default_path().with_file_name("honeypot.jsonl"),
+    }
+}
+
+pub const COMMAND: Command = Command {
+    name: "honeypot",
+    usage: "netcore honeypot",
+    about: "Run fake SSH and HTTP services and log who connects",
+    groups: &[OPTS, acl::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let ip: IpAddr = cli::or_exit(args.parsed("--bind")).unwrap_or(IpAddr::from([0, 0, 0, 0]));
+    let ssh_port = cli::or_exit(args.parsed("--ssh-port")).unwrap_or(DEFAULT_SSH_PORT);
+    let http_port = cli::or_exit(args.parsed("--http-port")).unwrap_or(DEFAULT_HTTP_PORT);
+    let limit = cli::or_exit(args.parsed_with("--max-payload", units::size))
+        .map_or(DEFAULT_MAX_PAYLOAD, |limit| limit as usize);
+    cli::or_exit(acl::init(&args).await);
+
+    let path = log_path(&args);
+    if let Some(dir) = path.parent()
+        && let Err(e) = fs::create_dir_all(dir)
+    {
+        eprintln!("Failed to create {}: {}", dir.display(), e);
+        std::process::exit(exit::code(&e));
+    }
+    let log = Arc::new(Log {
+        path,
+        lock: Mutex::new(()),
+    });
+
+    let ssh = exit::or_exit(bind(ip, ssh_port, "ssh").await);
+    let http = exit::or_exit(bind(ip, http_port, "http").await);
+    if ssh.is_none() && http.is_none() {
+        eprintln!("honeypot needs at least one of --ssh-port or --http-port");
+        std::process::exit(exit::USAGE);
+    }
+    say!("Logging interactions to {}", log.path.display());
+
+    let ssh = async {
+        if let Some(listener) = ssh {
+            listen(listener, "ssh", log.clone(), limit).await;
+        }
+    };
+    let http = async {
+        if let Some(listener) = http {
+            listen(listener, "http", log.clone(), limit).await;
+        }
+    };
+    tokio::join!(ssh, http);
+}
@@ -0,0 +1,109 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use tokio::time::Duration;
+
+use crate::HostInfo;
+use crate::cli::{Args, Opt};
+use crate::history::{self, NONE};
+use crate::units::{self, SECS};
+
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--cache-ttl",
+        value: Some("<duration>"),
+        help: "How long cached public addresses are reused (default: 5m)",
+    },
+    Opt {
+        name: "--refresh",
+        value: None,
+        help: "Look up public addresses again and update the cache",
+    },
+    Opt {
+        name: "--no-cache",
+        value: None,
+        help: "Neither read nor write the host info cache",
+    },
+];
+
+// The last public addresses found, so scripts and shell prompts that run
+// `netcore info` often don't query the lookup services every time.
+pub struct Cache {
+    path: PathBuf,
+    ttl: Duration,
+    refresh: bool,
+}
+
+impl Cache {
+    pub fn from_args(args: &Args) -> Result<Option<Cache>, String> {
+        let ttl = args
+            .parsed_with("--cache-ttl", |v| units::duration(v, SECS))?
+            .unwrap_or(DEFAULT_TTL);
+        if args.flag("--no-cache") {
+            return Ok(None);
+        }
+        Ok(default_path().map(|path| Cache {
+            path,
+            ttl,
+            refresh: args.flag("--refresh"),
+        }))
+    }
+
+    // The cached info, unless it's older than the TTL or --refresh was
+    // given. Callers still have to check the local addresses match.
+    pub fn load(&self) -> Option<HostInfo> {
+        if self.refresh {
+            return None;
+        }
+        let text = fs::read_to_string(&self.path).ok()?;
+        let mut info = HostInfo::default();
+        let mut time = None;
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once('\t') else {
+                continue;
+            };
+            let addr = (value != NONE).then_some(value);
+            match key {
+                "time" => time = value.parse::<u64>().ok(),
+                "local_ipv4" => info.local_ipv4 = addr.and_then(|a| a.parse().ok()),
+                "public_ipv4" => info.public_ipv4 = addr.and_then(|a| a.parse().ok()),
+                "local_ipv6" => info.local_ipv6 = addr.and_then(|a| a.parse().ok()),
+                "public_ipv6" => info.public_ipv6 = addr.and_then(|a| a.parse().ok()),
+                _ => {}
+            }
+        }
+        let age = history::now().checked_sub(time?)?;
+        (age < self.ttl.as_secs()).then_some(info)
+    }
+
+    pub fn store(&self, info: &HostInfo) -> io::Result<()> {
+        let field = |addr: Option<String>| addr.unwrap_or_else(|| NONE.to_string());
+        let text = format!(
+            "time\t{}\nlocal_ipv4\t{}\npublic_ipv4\t{}\nlocal_ipv6\t{}\npublic_ipv6\t{}\n",
+            history::now(),
+            field(info.local_ipv4.map(|ip| ip.to_string())),
+            field(info.public_ipv4.map(|ip| ip.to_string())),
+            field(info.local_ipv6.map(|ip| ip.to_string())),
+            field(info.public_ipv6.map(|ip| ip.to_string())),
+        );
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Other invocations may be reading it right now.
+        let partial = self.path.with_extension("tmp");
+        fs::write(&partial, text)?;
+        fs::rename(&partial, &self.path)
+    }
+}
+
+fn default_path() -> Option<PathBuf> {
+    let base = match (env::var_os("XDG_CACHE_HOME"), env::var_os("HOME")) {
+        (Some(cache), _) => PathBuf::from(cache),
+        (None, Some(home)) => PathBuf::from(home).join(".cache"),
+        (None, None) => return None,
+    };
+    Some(base.join("netcore").join("host-info"))
+}
@@ -0,0 +1,449 @@
+// Flow export to a NetFlow v9 or IPFIX collector (--flow-collector), so a
+// relay shows up in the monitoring that already watches the routers. Each
+// connection the server handles is one flow: live ones are reported every
+// interval with the bytes moved since the last report, and a closed one
+// once more with whatever it moved since. The templates go out with every
+// message, as collectors on UDP may have missed or forgotten them.
+
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, MissedTickBehavior, interval};
+
+use crate::cli::{Args, Opt};
+use crate::flows::Key;
+use crate::say;
+use crate::stats;
+use crate::units::{self, SECS};
+
+const EXPORT_INTERVAL_SECS: u64 = 10;
+// Under a 1500 byte MTU with room for IPv6 and UDP headers.
+const MAX_MESSAGE: usize = 1400;
+const MAX_PENDING: usize = 4096;
+const TEMPLATE_V4: u16 = 256;
+const TEMPLATE_V6: u16 = 257;
+
+// Information elements, numbered the same in both formats.
+const OCTETS: u16 = 1;
+const PROTOCOL: u16 = 4;
+const SRC_PORT: u16 = 7;
+const SRC_V4: u16 = 8;
+const DST_PORT: u16 = 11;
+const DST_V4: u16 = 12;
+const LAST_SWITCHED: u16 = 21;
+const FIRST_SWITCHED: u16 = 22;
+const SRC_V6: u16 = 27;
+const DST_V6: u16 = 28;
+const START_MILLIS: u16 = 152;
+const END_MILLIS: u16 = 153;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--flow-collector",
+        value: Some("<host:port>"),
+        help: "Export connection flows to a NetFlow/IPFIX collector over UDP",
+    },
+    Opt {
+        name: "--flow-format",
+        value: Some("<ipfix|netflow9>"),
+        help: "Flow export format (default: ipfix)",
+    },
+    Opt {
+        name: "--flow-interval",
+        value: Some("<duration>"),
+        help: "How often to report live flows (default: 10s)",
+    },
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Ipfix,
+    Netflow9,
+}
+
+// One report of a flow: what it moved since the last one.
+#[derive(Clone, Copy, Debug)]
+pub struct Record {
+    pub src: SocketAddr,
+    pub dst: SocketAddr,
+    pub protocol: u8,
+    pub octets: u64,
+    // Unix milliseconds.
+    pub start: u64,
+    pub end: u64,
+}
+
+impl Record {
+    fn new(key: &Key, octets: u64, started: Instant, now: Instant) -> Record {
+        let src = SocketAddr::new(key.src.ip().to_canonical(), key.src.port());
+        let dst = match key.dst {
+            Some(dst) if dst.ip().to_canonical().is_ipv4() == src.is_ipv4() => {
+                SocketAddr::new(dst.ip().to_canonical(), dst.port())
+            }
+            _ if src.is_ipv4() => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            _ => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        };
+        let end = unix_millis();
+        Record {
+            src,
+            dst,
+            protocol: match key.protocol {
+                "udp" => 17,
+                _ => 6,
+            },
+            octets,
+            start: end.saturating_sub((now - started).as_millis() as u64),
+            end,
+        }
+    }
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+// (element, length) of each field, in the order records carry them.
+fn fields(format: Format, v6: bool) -> Vec<(u16, u16)> {
+    let (src, dst, width) = match v6 {
+        true => (SRC_V6, DST_V6, 16),
+        false => (SRC_V4, DST_V4, 4),
+    };
+    let mut fields = vec![
+        (src, width),
+        (dst, width),
+        (SRC_PORT, 2),
+        (DST_PORT, 2),
+        (PROTOCOL, 1),
+        (OCTETS, 8),
+    ];
+    match format {
+        Format::Ipfix => fields.extend([(START_MILLIS, 8), (END_MILLIS, 8)]),
+        Format::Netflow9 => fields.extend([(FIRST_SWITCHED, 4), (LAST_SWITCHED, 4)]),
+    }
+    fields
+}
+
+fn put16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn put32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_be_bytes());
+}
+
+fn set(id: u16, body: &[u8]) -> Vec<u8> {
+    let mut set = Vec::with_capacity(body.len() + 4);
+    put16(&mut set, id);
+    put16(&mut set, (body.len() + 4) as u16);
+    set.extend_from_slice(body);
+    set
+}
+
+// The template set describing both record layouts.
+fn templates(format: Format) -> Vec<u8> {
+    let mut body = Vec::new();
+    for (id, v6) in [(TEMPLATE_V4, false), (TEMPLATE_V6, true)] {
+        let fields = fields(format, v6);
+        put16(&mut body, id);
+        put16(&mut body, fields.len() as u16);
+        for (element, length) in fields {
+            put16(&mut body, element);
+            put16(&mut body, length);
+        }
+    }
+    let id = match format {
+        Format::Ipfix => 2,
+        Format::Netflow9 => 0,
+    };
+    set(id, &body)
+}
+
+fn encode(format: Format, record: &Record, uptime: impl Fn(u64) -> u32, out: &mut Vec<u8>) {
+    match (record.src.ip(), record.dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => {
+            out.extend_from_slice(&src.octets());
+            out.extend_from_slice(&dst.octets());
+        }
+        (src, dst) => {
+            let v6 = |ip: IpAddr| match ip {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            out.extend_from_slice(&v6(src).octets());
+            out.extend_from_slice(&v6(dst).octets());
+        }
+    }
+    put16(out, record.src.port());
+    put16(out, record.dst.port());
+    out.push(record.protocol);
+    out.extend_from_slice(&record.octets.to_be_bytes());
+    match format {
+        Format::Ipfix => {
+            out.extend_from_slice(&record.start.to_be_bytes());
+            out.extend_from_slice(&record.end.to_be_bytes());
+        }
+        Format::Netflow9 => {
+            put32(out, uptime(record.start));
+            put32(out, uptime(record.end));
+        }
+    }
+}
+
+// Messages carrying `records`, each with the templates first and no
+// bigger than MAX_MESSAGE. `sequence` counts what the format says it
+// counts: data records for IPFIX, messages for NetFlow v9.
+pub fn messages(
+    format: Format,
+    records: &[Record],
+    sequence: &mut u32,
+    booted: u64,
+    now: u64,
+) -> Vec<Vec<u8>> {
+    let templates = templates(format);
+    let uptime = |millis: u64| millis.saturating_sub(booted) as u32;
+    let record_size = |v6: bool| {
+        fields(format, v6)
+            .iter()
+            .map(|(_, length)| *length as usize)
+            .sum::<usize>()
+    };
+    let room = MAX_MESSAGE - 20 - templates.len() - 4;
+    let mut chunks: Vec<Vec<&Record>> = vec![Vec::new()];
+    let mut used = 0;
+    for record in records {
+        // Budgeting a set header per record only ever overestimates.
+        let size = record_size(record.src.is_ipv6()) + 4;
+        if used + size > room && !chunks.last().unwrap().is_empty() {
+            chunks.push(Vec::new());
+            used = 0;
+        }
+        chunks.last_mut().unwrap().push(record);
+        used += size;
+    }
+
+    let mut messages = Vec::new();
+    for chunk in chunks.into_iter().filter(|chunk| !chunk.is_empty()) {
+        let mut body = templates.clone();
+        for (template, v6) in [(TEMPLATE_V4, false), (TEMPLATE_V6, true)] {
+            let mut data = Vec::new();
+            let matching = chunk.iter().filter(|record| record.src.is_ipv6() == v6);
+            for record in matching {
+                encode(format, record, uptime, &mut data);
+            }
+            if !data.is_empty() {
+                body.extend(set(template, &data));
+            }
+        }
+
+        let mut message = Vec::with_capacity(body.len() + 20);
+        match format {
+            Format::Ipfix => {
+                put16(&mut message, 10);
+                put16(&mut message, (body.len() + 16) as u16);
+                put32(&mut message, (now / 1000) as u32);
+                put32(&mut message, *sequence);
+                // Observation domain.
+                put32(&mut message, 0);
+                *sequence = sequence.wrapping_add(chunk.len() as u32);
+            }
+            Format::Netflow9 => {
+                put16(&mut message, 9);
+                // Records in the message, templates included.
+                put16(&mut message, (chunk.len() + 2) as u16);
+                put32(&mut message, uptime(now));
+                put32(&mut message, (now / 1000) as u32);
+                put32(&mut message, *sequence);
+                // Source ID.
+                put32(&mut message, 0);
+                *sequence = sequence.wrapping_add(1);
+            }
+        }
+        message.extend(body);
+        messages.push(message);
+    }
+    messages
+}
+
+struct Exporter {
+    format: Format,
+    collector: SocketAddr,
+    socket: UdpSocket,
+    booted: u64,
+    // Closed flows waiting for the next export.
+    pending: Mutex<Vec<Record>>,
+    // Octets already reported for each live flow.
+    reported: Mutex<HashMap<Key, u64>>,
+    sequence: Mutex<u32>,
+}
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+// A flow that has closed: reported with the next export.
+pub fn finished(key: &Key, octets: u64, started: Instant) {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    let reported = exporter.reported.lock().unwrap().remove(key).unwrap_or(0);
+    let record = Record::new(
+        key,
+        octets.saturating_sub(reported),
+        started,
+        Instant::now(),
+    );
+    let mut pending = exporter.pending.lock().unwrap();
+    if pending.len() < MAX_PENDING {
+        pending.push(record);
+    }
+}
+
+impl Exporter {
+    async fn export(&self) {
+        let now = Instant::now();
+        let mut records = std::mem::take(&mut *self.pending.lock().unwrap());
+        {
+            let mut reported = self.reported.lock().unwrap();
+            for (key, octets, started) in stats::live_flows() {
+                let before = reported.insert(key, octets).unwrap_or(0);
+                if octets > before {
+                    records.push(Record::new(&key, octets - before, started, now));
+                }
+            }
+        }
+        if records.is_empty() {
+            return;
+        }
+
+        let messages = {
+            let mut sequence = self.sequence.lock().unwrap();
+            messages(
+                self.format,
+                &records,
+                &mut sequence,
+                self.booted,
+                unix_millis(),
+            )
+        };
+        for message in messages {
+            if let Err(e) = self.socket.send_to(&message, self.collector).await {
+                eprintln!("Failed to export flows to {}: {}", self.collector, e);
+                return;
+            }
+        }
+    }
+}
+
+pub async fn init(args: &Args) -> Result<(), String> {
+    let Some(collector) = args.value("--flow-collector") else {
+        return Ok(());
+    };
+    let format = match args.value("--flow-format").unwrap_or("ipfix") {
+        "ipfix" => Format::Ipfix,
+        "netflow9" | "v9" => Format::Netflow9,
+        other => {
+            return Err(format!(
+                "unknown --flow-format '{}', expected ipfix or netflow9",
+                other
+            ));
+        }
+    };
+    let period = args
+        .parsed_with("--flow-interval", |v| units::duration(v, SECS))?
+        .unwrap_or(Duration::from_secs(EXPORT_INTERVAL_SECS));
+
+    let address = tokio::net::lookup_host(collector)
+        .await
+        .map_err(|e| format!("{}: {}", collector, e))?
+        .next()
+        .ok_or_else(|| format!("{}: no addresses", collector))?;
+    let bind: SocketAddr = match address {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind)
+        .await
+        .map_err(|e| format!("Failed to open a socket for flow export: {}", e))?;
+    let exporter = Exporter {
+        format,
+        collector: address,
+        socket,
+        booted: unix_millis(),
+        pending: Mutex::default(),
+        reported: Mutex::default(),
+        sequence: Mutex::new(0),
+    };
+    if EXPORTER.set(exporter).is_err() {
+        return Ok(());
+    }
+
+    say!(
+        "Exporting flows to {} as {}",
+        address,
+        match format {
+            Format::Ipfix => "IPFIX",
+            Format::Netflow9 => "NetFlow v9",
+        }
+    );
+    tokio::spawn(async move {
+        let mut ticker = interval(period.max(Duration::from_secs(1)));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Some(exporter) = EXPORTER.get() {
+                exporter.export().await;
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_hold_whole_sets_and_count_records() {
+        let record = |src: &str, dst: &str| Record {
+            src: src.parse().unwrap(),
+            dst: dst.parse().unwrap(),
+            protocol: 6,
+            octets: 1500,
+            start: 1_700_000_000_000,
+            end: 1_700_000_002_000,
+        };
+        let mut records = vec![record("[2001:db8::1]:5000", "[2001:db8::2]:443")];
+        records.extend((0..60).map(|_| record("10.0.0.2:5000", "10.0.0.1:6881")));
+
+        let mut sequence = 0;
+        let sent = messages(Format::Ipfix, &records, &mut sequence, 0, 1_700_000_003_000);
+        assert!(sent.len() > 1);
+        assert_eq!(sequence, 61);
+        for message in &sent {
+            assert!(message.len() <= MAX_MESSAGE);
+            assert_eq!(be16(message, 0), 10);
+            assert_eq!(be16(message, 2) as usize, message.len());
+            // The sets fill the message exactly.
+            let mut at = 16;
+            while at < message.len() {
+                at += be16(message, at + 2) as usize;
+            }
+            assert_eq!(at, message.len());
+        }
+        // The first message starts with the template set, then IPv4 data.
+        assert_eq!(be16(&sent[0], 16), 2);
+
+        let mut sequence = 7;
+        let v9 = messages(Format::Netflow9, &records[1..3], &mut sequence, 0, 5000);
+        assert_eq!((be16(&v9[0], 0), be16(&v9[0], 2)), (9, 4));
+        assert_eq!(sequence, 8);
+    }
+
+    fn be16(data: &[u8], at: usize) -> u16 {
+        u16::from_be_bytes([data[at], data[at + 1]])
+    }
+}
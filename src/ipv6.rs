@@ -0,0 +1,523 @@
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::ffi::CString;
+use std::io;
+use std::mem::MaybeUninit;
+use std::net::{IpAddr, Ipv6Addr, SocketAddrV6};
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+
+use crate::cli::{self, Command, Opt};
+use crate::exit;
+use crate::json::Value;
+use crate::output;
+use crate::say;
+use crate::units::{self, SECS};
+
+const ICMPV6_ROUTER_SOLICITATION: u8 = 133;
+const ICMPV6_ROUTER_ADVERTISEMENT: u8 = 134;
+const ALL_ROUTERS: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 2);
+const LISTEN_SECS: u64 = 5;
+
+const DIAG_OPTS: &[Opt] = &[
+    Opt {
+        name: "--interface",
+        value: Some("<name>"),
+        help: "Only solicit and listen on this interface",
+    },
+    Opt {
+        name: "--timeout",
+        value: Some("<duration>"),
+        help: "How long to listen for advertisements (default: 5s)",
+    },
+];
+
+struct Prefix {
+    network: Ipv6Addr,
+    len: u8,
+    on_link: bool,
+    autonomous: bool,
+    valid: u32,
+    preferred: u32,
+}
+
+impl Prefix {
+    fn contains(&self, addr: Ipv6Addr) -> bool {
+        let mask = u128::MAX
+            .checked_shl(128 - self.len.min(128) as u32)
+            .unwrap_or(0);
+        u128::from(addr) & mask == u128::from(self.network) & mask
+    }
+}
+
+pub struct Advertisement {
+    router: Ipv6Addr,
+    interface: String,
+    hop_limit: u8,
+    managed: bool,
+    other: bool,
+    lifetime: u16,
+    prefixes: Vec<Prefix>,
+    rdnss: Vec<(Ipv6Addr, u32)>,
+    mtu: Option<u32>,
+}
+
+pub fn parse_advertisement(
+    packet: &[u8],
+    router: Ipv6Addr,
+    interface: String,
+) -> Option<Advertisement> {
+    if packet.len() < 16 || packet[0] != ICMPV6_ROUTER_ADVERTISEMENT || packet[1] != 0 {
+        return None;
+    }
+
+    let mut ra = Advertisement {
+        router,
+        interface,
+        hop_limit: packet[4],
+        managed: packet[5] & 0x80 != 0,
+        other: packet[5] & 0x40 != 0,
+        lifetime: u16::from_be_bytes([packet[6], packet[7]]),
+        prefixes: Vec::new(),
+        rdnss: Vec::new(),
+        mtu: None,
+    };
+
+    let mut options = &packet[16..];
+    while options.len() >= 8 {
+        let len = options[1] as usize * 8;
+        if len == 0 || len > options.len() {
+            break;
+        }
+        let (option, rest) = options.split_at(len);
+        options = rest;
+
+        match option[0] {
+            3 if len == 32 => {
+                let be32 = |i: usize| {
+                    u32::from_be_bytes([option[i], option[i + 1], option[i + 2], option[i + 3]])
+                };
+                let network: [u8; 16] = option[16..32].try_into().ok()?;
+                ra.prefixes.push(Prefix {
+                    network: Ipv6Addr::from(network),
+                    len: option[2],
+                    on_link: option[3] & 0x80 != 0,
+                    autonomous: option[3] & 0x40 != 0,
+                    valid: be32(4),
+                    preferred: be32(8),
+                });
+            }
+            5 if len == 8 => {
+                ra.mtu = Some(u32::from_be_bytes([
+                    option[4], option[5], option[6], option[7],
+                ]));
+            }
+            25 if len >= 24 => {
+                let lifetime = u32::from_be_bytes([option[4], option[5], option[6], option[7]]);
+                for chunk in option[8..].chunks_exact(16) {
+                    let addr: [u8; 16] = chunk.try_into().ok()?;
+                    ra.rdnss.push((Ipv6Addr::from(addr), lifetime));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(ra)
+}
+
+pub fn interface_index(name: &str) -> u32 {
+    CString::new(name)
+        .map(|name| unsafe { libc::if_nametoindex(name.as_ptr()) })
+        .unwrap_or(0)
+}
+
+fn interface_name(index: u32) -> String {
+    let mut buf = [0 as libc::c_char; libc::IF_NAMESIZE];
+    let name = unsafe { libc::if_indextoname(index, buf.as_mut_ptr()) };
+    if name.is_null() {
+        return index.to_string();
+    }
+    buf.iter()
+        .take_while(|c| **c != 0)
+        .map(|c| *c as u8 as char)
+        .collect()
+}
+
+fn ipv6_interfaces(only: Option<&str>) -> Vec<(String, Ipv6Addr)> {
+    local_ip_address::list_afinet_netifas()
+        .unwrap_or_default()
+        .into_iter()
+        .filter_map(|(name, ip)| match ip {
+            IpAddr::V6(ip) if !ip.is_loopback() => Some((name, ip)),
+            _ => None,
+        })
+        .filter(|(name, _)| only.is_none_or(|only| only == name))
+        .collect()
+}
+
+pub fn is_global_unicast(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xe000 == 0x2000
+}
+
+// How this machine's IPv6 gets to the internet. Teredo and 6to4 relay
+// through third parties and are often slow or half broken, and an MTU
+// below what Ethernet or PPPoE would give points at some other tunnel,
+// like a tunnel broker's 6in4. All of those are worse than IPv4 here.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Path {
+    Unrouted,
+    Native,
+    Teredo,
+    SixToFour,
+    Tunnel { mtu: u32 },
+}
+
+// 6in4 over PPPoE, the largest MTU a tunnel leaves on common links.
+const TUNNEL_MTU: u32 = 1472;
+const TUNNEL_INTERFACES: &[&str] = &["sit", "he-", "6in4", "gif", "tun"];
+
+impl Path {
+    pub fn classify(ip: Ipv6Addr, interface: &str, mtu: Option<u32>) -> Path {
+        let segments = ip.segments();
+        match (segments[0], segments[1], mtu) {
+            (0x2001, 0x0000, _) => Path::Teredo,
+            (0x2002, _, _) => Path::SixToFour,
+            (_, _, Some(mtu)) if mtu <= TUNNEL_MTU => Path::Tunnel { mtu },
+            (_, _, Some(mtu))
+                if TUNNEL_INTERFACES
+                    .iter()
+                    .any(|prefix| interface.starts_with(prefix)) =>
+            {
+                Path::Tunnel { mtu }
+            }
+            _ => Path::Native,
+        }
+    }
+
+    // Whether connections should rather go over IPv4 when both work.
+    pub fn degraded(self) -> bool {
+        !matches!(self, Path::Unrouted | Path::Native)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Path::Unrouted => "none",
+            Path::Native => "native",
+            Path::Teredo => "teredo",
+            Path::SixToFour => "6to4",
+            Path::Tunnel { .. } => "tunnel",
+        }
+    }
+}
+
+impl std::fmt::Display for Path {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Path::Unrouted => write!(f, "no IPv6 route"),
+            Path::Native => write!(f, "native"),
+            Path::Teredo => write!(f, "Teredo (2001::/32)"),
+            Path::SixToFour => write!(f, "6to4 (2002::/16)"),
+            Path::Tunnel { mtu } => write!(f, "probably tunneled, interface MTU {}", mtu),
+        }
+    }
+}
+
+// The source address of the IPv6 default route, found by connecting a UDP
+// socket, which sends nothing.
+fn route_source() -> Option<Ipv6Addr> {
+    let socket = std::net::UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).ok()?;
+    socket
+        .connect((Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1), 9))
+        .ok()?;
+    match socket.local_addr().ok()?.ip() {
+        IpAddr::V6(ip) => Some(ip),
+        IpAddr::V4(_) => None,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn interface_mtu(interface: &str) -> Option<u32> {
+    std::fs::read_to_string(format!("/sys/class/net/{}/mtu", interface))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn interface_mtu(_interface: &str) -> Option<u32> {
+    None
+}
+
+fn detect_path() -> Path {
+    let Some(source) = route_source().filter(is_global_unicast) else {
+        return Path::Unrouted;
+    };
+    let interface = ipv6_interfaces(None)
+        .into_iter()
+        .find(|(_, ip)| *ip == source)
+        .map(|(name, _)| name)
+        .unwrap_or_default();
+    Path::classify(source, &interface, interface_mtu(&interface))
+}
+
+// Worked out once per run: the default route rarely changes under a
+// running command, and the address family preference asks on every
+// connection.
+pub fn path() -> Path {
+    static PATH: LazyLock<Path> = LazyLock::new(detect_path);
+    *PATH
+}
+
+fn solicit(interfaces: &[String], listen: Duration) -> io::Result<Vec<Advertisement>> {
+    let socket = Socket::new(Domain::IPV6, Type::RAW, Some(Protocol::ICMPV6))?;
+    socket.set_multicast_hops_v6(255)?;
+    socket.set_unicast_hops_v6(255)?;
+
+    let solicitation = [ICMPV6_ROUTER_SOLICITATION, 0, 0, 0, 0, 0, 0, 0];
+    for name in interfaces {
+        let index = interface_index(name);
+        if index == 0 {
+            continue;
+        }
+        socket.set_multicast_if_v6(index)?;
+        let target = SocketAddrV6::new(ALL_ROUTERS, 0, 0, index);
+        match socket.send_to(&solicitation, &SockAddr::from(target)) {
+            Ok(_) => say!("Sent router solicitation on {}", name),
+            Err(e) => eprintln!("Failed to send router solicitation on {}: {}", name, e),
+        }
+    }
+
+    let deadline = Instant::now() + listen;
+    let mut adverts = Vec::new();
+    let mut buf = [MaybeUninit::<u8>::uninit(); 1500];
+
+    while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+        if remaining.is_zero() {
+            break;
+        }
+        socket.set_read_timeout(Some(remaining))?;
+
+        let (n, from) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                ) =>
+            {
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        let Some(from) = from.as_socket_ipv6() else {
+            continue;
+        };
+        if !from.ip().is_unicast_link_local() {
+            continue;
+        }
+
+        let packet: Vec<u8> = buf[..n]
+            .iter()
+            .map(|b| unsafe { b.assume_init() })
+            .collect();
+        let interface = interface_name(from.scope_id());
+        if !interfaces.contains(&interface) {
+            continue;
+        }
+        if let Some(ra) = parse_advertisement(&packet, *from.ip(), interface)
+            && !adverts
+                .iter()
+                .any(|a: &Advertisement| a.router == ra.router && a.interface == ra.interface)
+        {
+            adverts.push(ra);
+        }
+    }
+
+    Ok(adverts)
+}
+
+fn print_advertisement(ra: &Advertisement) {
+    say!("Router {} on {}", ra.router, ra.interface);
+    say!(
+        "  flags M={} O={}, router lifetime {} s, hop limit {}",
+        ra.managed as u8,
+        ra.other as u8,
+        ra.lifetime,
+        ra.hop_limit
+    );
+    for prefix in &ra.prefixes {
+        let mut flags = Vec::new();
+        if prefix.on_link {
+            flags.push("on-link");
+        }
+        if prefix.autonomous {
+            flags.push("autonomous");
+        }
+        say!(
+            "  prefix {}/{} ({}) valid {} s, preferred {} s",
+            prefix.network,
+            prefix.len,
+            flags.join(", "),
+            prefix.valid,
+            prefix.preferred
+        );
+    }
+    for (addr, lifetime) in &ra.rdnss {
+        say!("  RDNSS {} (lifetime {} s)", addr, lifetime);
+    }
+    if let Some(mtu) = ra.mtu {
+        say!("  MTU {}", mtu);
+    }
+}
+
+fn verdict(adverts: &[Advertisement], addresses: &[(String, Ipv6Addr)]) -> (bool, String) {
+    let globals: Vec<_> = addresses
+        .iter()
+        .filter(|(_, ip)| is_global_unicast(ip))
+        .collect();
+
+    if adverts.is_empty() {
+        return match globals.is_empty() {
+            true => (false, "no router advertisements received; IPv6 does not appear to be routed on this link".to_string()),
+            false => (true, "global address present, but no router answered the solicitation (static configuration?)".to_string()),
+        };
+    }
+
+    if adverts.iter().all(|ra| ra.lifetime == 0) {
+        return (
+            false,
+            "routers advertise a lifetime of 0; none of them is a default router".to_string(),
+        );
+    }
+
+    let slaac: Vec<&Prefix> = adverts
+        .iter()
+        .flat_map(|ra| &ra.prefixes)
+        .filter(|p| p.autonomous && p.valid > 0 && p.len == 64)
+        .collect();
+    let in_prefix = globals
+        .iter()
+        .any(|(_, ip)| slaac.iter().any(|p| p.contains(*ip)));
+
+    match (slaac.is_empty(), in_prefix, globals.is_empty()) {
+        (false, true, _) => (true, "host has a global address from an advertised prefix".to_string()),
+        (false, false, _) => (false, "SLAAC prefix advertised but no address from it was configured (check accept_ra and autoconf settings)".to_string()),
+        (true, _, false) => (true, "global address present (assigned via DHCPv6 or statically)".to_string()),
+        (true, _, true) if adverts.iter().any(|ra| ra.managed) => (false, "addresses are managed by DHCPv6 (M=1) but none was acquired".to_string()),
+        (true, _, true) => (false, "routers advertise no autonomous prefix, so SLAAC cannot assign an address".to_string()),
+    }
+}
+
+pub const DIAG_COMMAND: Command = Command {
+    name: "ipv6-diag",
+    usage: "netcore ipv6-diag",
+    about: "Solicit router advertisements and show IPv6 addresses",
+    groups: &[DIAG_OPTS],
+};
+
+pub async fn diag_command(tokens: Vec<String>) {
+    let args = cli::parse_command(&DIAG_COMMAND, tokens);
+    let only = args.value("--interface").map(str::to_string);
+    let listen = cli::or_exit(args.parsed_with("--timeout", |v| units::duration(v, SECS)))
+        .unwrap_or(Duration::from_secs(LISTEN_SECS));
+
+    let addresses = ipv6_interfaces(only.as_deref());
+    let mut interfaces: Vec<String> = addresses.iter().map(|(name, _)| name.clone()).collect();
+    interfaces.sort();
+    interfaces.dedup();
+    if let Some(only) = &only
+        && interfaces.is_empty()
+    {
+        interfaces.push(only.clone());
+    }
+
+    say!(
+        "Listening for router advertisements for {} s",
+        listen.as_secs()
+    );
+    let solicited = interfaces.clone();
+    let adverts = match tokio::task::spawn_blocking(move || solicit(&solicited, listen)).await {
+        Ok(Ok(adverts)) => adverts,
+        Ok(Err(e)) => {
+            eprintln!(
+                "Cannot listen for router advertisements: {} (raw sockets need root or CAP_NET_RAW)",
+                e
+            );
+            std::process::exit(exit::code(&e));
+        }
+        Err(e) => {
+            eprintln!("Router solicitation failed: {}", e);
+            std::process::exit(exit::FAILURE);
+        }
+    };
+
+    say!();
+    for ra in &adverts {
+        print_advertisement(ra);
+    }
+
+    say!("Addresses:");
+    for (name, ip) in &addresses {
+        let scope = if ip.is_unicast_link_local() {
+            "link-local"
+        } else if ip.is_unique_local() {
+            "unique local"
+        } else if is_global_unicast(ip) {
+            "global"
+        } else {
+            "other"
+        };
+        say!("  {} {} ({})", name, ip, scope);
+    }
+    if addresses.is_empty() {
+        say!("  none");
+    }
+
+    let path = path();
+    say!();
+    say!("Path: {}", path);
+    output::emit(Value::object([
+        ("subject", Value::from("path")),
+        ("path", Value::from(path.name())),
+        ("degraded", Value::from(path.degraded())),
+    ]));
+    if path.degraded() {
+        say!("  connections prefer IPv4 unless --prefer v6 is given");
+    }
+
+    let (ok, message) = verdict(&adverts, &addresses);
+    say!();
+    let status = if ok { "PASS" } else { "FAIL" };
+    say!("{}  {}", status, message);
+    output::verdict(status, "ipv6", &message);
+    if !ok {
+        std::process::exit(exit::FAILURE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_tunneled_ipv6() {
+        let path = |ip: &str, interface, mtu| Path::classify(ip.parse().unwrap(), interface, mtu);
+        assert_eq!(
+            path("2001:0:4136:e378::1", "teredo", Some(1280)),
+            Path::Teredo
+        );
+        assert_eq!(
+            path("2002:cb00:7101::1", "eth0", Some(1500)),
+            Path::SixToFour
+        );
+        assert_eq!(
+            path("2001:470:1f0b::2", "he-ipv6", Some(1480)),
+            Path::Tunnel { mtu: 1480 }
+        );
+        assert_eq!(path("2a02:8071::5", "ppp0", Some(1492)), Path::Native);
+        assert_eq!(path("2a02:8071::5", "eth0", None), Path::Native);
+        assert!(Path::Teredo.degraded());
+        assert!(!Path::Native.degraded() && !Path::Unrouted.degraded());
+    }
+}
@@ -0,0 +1,269 @@
+// Running as a Kubernetes pod. The downward API hands a pod its own name,
+// namespace and addresses only if the manifest maps them to variables, by
+// convention POD_NAME, POD_NAMESPACE, POD_IP, NODE_NAME and HOST_IP; `info`
+// reports whichever are set. `kube-check` connects to the cluster's
+// services from inside, as the pod's other containers would: every service
+// in the namespace that kubelet announced with <NAME>_SERVICE_HOST and
+// <NAME>_SERVICE_PORT, plus any --service, and each --node-port on the
+// node's address.
+
+use std::net::IpAddr;
+use tokio::time::timeout;
+
+use crate::cli::{self, Command, Opt};
+use crate::exit;
+use crate::json::Value;
+use crate::outbound::{self, OutboundConfig};
+use crate::output;
+use crate::say;
+use crate::timeouts;
+
+const NAMESPACE_FILE: &str = "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--service",
+        value: Some("<host:port>"),
+        help: "Service to connect to, such as web.default.svc:80 (repeatable)",
+    },
+    Opt {
+        name: "--node-port",
+        value: Some("<port>"),
+        help: "NodePort to connect to on the node (repeatable)",
+    },
+    Opt {
+        name: "--node",
+        value: Some("<ip>"),
+        help: "Node address for --node-port, instead of HOST_IP",
+    },
+];
+
+#[derive(Default, Debug, PartialEq)]
+pub struct Pod {
+    pub name: Option<String>,
+    pub namespace: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub node: Option<String>,
+    pub node_ip: Option<IpAddr>,
+}
+
+impl Pod {
+    // The pod as the variables `get` returns describe it, if they describe
+    // one at all.
+    fn from_vars(get: impl Fn(&str) -> Option<String>) -> Option<Pod> {
+        let get = |name: &str| get(name).filter(|value| !value.is_empty());
+        let ip = |name: &str| get(name).and_then(|value| value.parse().ok());
+        let pod = Pod {
+            name: get("POD_NAME"),
+            namespace: get("POD_NAMESPACE"),
+            ip: ip("POD_IP"),
+            node: get("NODE_NAME"),
+            node_ip: ip("HOST_IP").or_else(|| ip("NODE_IP")),
+        };
+        (pod != Pod::default()).then_some(pod)
+    }
+
+    // Only variables the manifest set say this is a pod; the service
+    // account's namespace fills in one it left out.
+    pub fn detect() -> Option<Pod> {
+        let mut pod = Pod::from_vars(|name| std::env::var(name).ok())?;
+        if pod.namespace.is_none() {
+            pod.namespace = std::fs::read_to_string(NAMESPACE_FILE)
+                .ok()
+                .map(|namespace| namespace.trim().to_string())
+                .filter(|namespace| !namespace.is_empty());
+        }
+        Some(pod)
+    }
+
+    // One line for people: the pod, its address and its node.
+    pub fn describe(&self) -> String {
+        let mut text = match (&self.namespace, &self.name) {
+            (Some(namespace), Some(name)) => format!("{}/{}", namespace, name),
+            (None, Some(name)) => name.clone(),
+            (Some(namespace), None) => format!("in namespace {}", namespace),
+            (None, None) => "unnamed".to_string(),
+        };
+        if let Some(ip) = self.ip {
+            text += &format!(", IP {}", ip);
+        }
+        match (&self.node, self.node_ip) {
+            (Some(node), Some(ip)) => text += &format!(", on node {} ({})", node, ip),
+            (Some(node), None) => text += &format!(", on node {}", node),
+            (None, Some(ip)) => text += &format!(", on node {}", ip),
+            (None, None) => {}
+        }
+        text
+    }
+
+    // (subject, value) for each field that is known.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let text = |value: &Option<String>| value.clone();
+        let ip = |value: &Option<IpAddr>| value.map(|ip| ip.to_string());
+        [
+            ("pod_name", text(&self.name)),
+            ("pod_namespace", text(&self.namespace)),
+            ("pod_ip", ip(&self.ip)),
+            ("node_name", text(&self.node)),
+            ("node_ip", ip(&self.node_ip)),
+        ]
+        .into_iter()
+        .filter_map(|(subject, value)| Some((subject, value?)))
+        .collect()
+    }
+}
+
+// The services kubelet announced to the pod, as (name, host:port). Kubelet
+// upper-cases names and turns dashes into underscores, so MY_DB is my-db.
+fn announced_services(vars: &[(String, String)]) -> Vec<(String, String)> {
+    let mut services: Vec<(String, String)> = vars
+        .iter()
+        .filter_map(|(key, host)| {
+            let prefix = key.strip_suffix("_SERVICE_HOST")?;
+            let port_key = format!("{}_SERVICE_PORT", prefix);
+            let (_, port) = vars.iter().find(|(key, _)| *key == port_key)?;
+            let host = match host.parse::<IpAddr>() {
+                Ok(IpAddr::V6(ip)) => format!("[{}]", ip),
+                _ => host.clone(),
+            };
+            let name = prefix.to_ascii_lowercase().replace('_', "-");
+            Some((name, format!("{}:{}", host, port)))
+        })
+        .collect();
+    services.sort();
+    services
+}
+
+pub const COMMAND: Command = Command {
+    name: "kube-check",
+    usage: "netcore kube-check [--service <host:port>]... [--node-port <port>]...",
+    about: "Check that cluster services and NodePorts are reachable from inside a pod",
+    groups: &[OPTS, outbound::OPTS, timeouts::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+    let pod = Pod::detect();
+
+    let vars: Vec<(String, String)> = std::env::vars().collect();
+    let mut targets = announced_services(&vars);
+    targets.extend(
+        args.values("--service")
+            .map(|service| (service.to_string(), service.to_string())),
+    );
+    let node_ports = args
+        .values("--node-port")
+        .map(|port| {
+            port.parse::<u16>()
+                .map_err(|_| format!("invalid --node-port '{}'", port))
+        })
+        .collect::<Result<Vec<u16>, String>>();
+    let node_ports = cli::or_exit(node_ports);
+    if !node_ports.is_empty() {
+        let node = match args.value("--node") {
+            Some(node) => Some(cli::or_exit(
+                node.parse::<IpAddr>()
+                    .map_err(|_| format!("invalid --node '{}'", node)),
+            )),
+            None => pod.as_ref().and_then(|pod| pod.node_ip),
+        };
+        let Some(node) = node else {
+            eprintln!("--node-port needs --node, or HOST_IP set from status.hostIP");
+            std::process::exit(exit::USAGE);
+        };
+        for port in node_ports {
+            let target = std::net::SocketAddr::new(node, port).to_string();
+            targets.push((format!("nodeport/{}", port), target));
+        }
+    }
+
+    match &pod {
+        Some(pod) => say!("Pod: {}", pod.describe()),
+        None => say!("No downward API variables set, so the pod's own details are unknown"),
+    }
+    if targets.is_empty() {
+        eprintln!("No services announced to this pod; pass --service or --node-port to check some");
+        std::process::exit(exit::USAGE);
+    }
+
+    let mut tasks = tokio::task::JoinSet::new();
+    for (i, (name, target)) in targets.into_iter().enumerate() {
+        let outbound = outbound.clone();
+        tasks.spawn(async move {
+            let result = match timeout(timeouts::get().connect, outbound.connect(&target)).await {
+                Ok(Ok(_)) => Ok(()),
+                Ok(Err(e)) => Err(e.to_string()),
+                Err(_) => Err("timed out".to_string()),
+            };
+            (i, name, target, result)
+        });
+    }
+    let mut results = tasks.join_all().await;
+    results.sort_by_key(|(i, ..)| *i);
+
+    let mut passed = 0;
+    for (_, name, target, result) in &results {
+        let (status, detail) = match result {
+            Ok(()) => {
+                passed += 1;
+                ("PASS", format!("{} is reachable", target))
+            }
+            Err(e) => ("FAIL", format!("{} is not reachable: {}", target, e)),
+        };
+        say!("{}  {}: {}", status, name, detail);
+        output::emit(Value::object([
+            ("status", Value::from(status.to_ascii_lowercase())),
+            ("subject", Value::from(name.as_str())),
+            ("address", Value::from(target.as_str())),
+            ("detail", Value::from(detail)),
+        ]));
+    }
+    let code = exit::from_counts(passed, results.len());
+    if code != exit::SUCCESS {
+        std::process::exit(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_downward_api_and_announced_services() {
+        assert_eq!(Pod::from_vars(|_| None), None);
+        let pod = Pod::from_vars(|name| match name {
+            "POD_NAME" => Some("web-7d9f".to_string()),
+            "POD_IP" => Some("10.244.1.7".to_string()),
+            "NODE_NAME" => Some(String::new()),
+            "HOST_IP" => Some("192.168.49.2".to_string()),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(
+            pod.fields(),
+            vec![
+                ("pod_name", "web-7d9f".to_string()),
+                ("pod_ip", "10.244.1.7".to_string()),
+                ("node_ip", "192.168.49.2".to_string()),
+            ]
+        );
+
+        let vars = [
+            ("KUBERNETES_SERVICE_HOST", "10.96.0.1"),
+            ("KUBERNETES_SERVICE_PORT", "443"),
+            ("MY_DB_SERVICE_HOST", "fd00:10:96::5"),
+            ("MY_DB_SERVICE_PORT", "5432"),
+            ("ORPHAN_SERVICE_HOST", "10.96.0.9"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.to_string()));
+        assert_eq!(
+            announced_services(&vars),
+            vec![
+                ("kubernetes".to_string(), "10.96.0.1:443".to_string()),
+                ("my-db".to_string(), "[fd00:10:96::5]:5432".to_string()),
+            ]
+        );
+    }
+}
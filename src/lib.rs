@@ -0,0 +1,303 @@
+pub mod acl;
+pub mod acme;
+pub mod adaptive;
+pub mod aead;
+pub mod alert;
+pub mod audit;
+pub mod auth;
+pub mod bandwidth;
+pub mod beacon;
+pub mod blake3;
+pub mod certs;
+pub mod cli;
+pub mod completions;
+pub mod config;
+pub mod container;
+pub mod context;
+pub mod control;
+pub mod dhcp;
+pub mod dns;
+pub mod dotenv;
+pub mod e2e;
+pub mod ed25519;
+pub mod events;
+pub mod exit;
+pub mod fingerprint;
+pub mod firewall;
+pub mod flows;
+pub mod fuzz;
+pub mod gateway;
+pub mod geoip;
+pub mod guard;
+pub mod gzip;
+pub mod hex;
+pub mod history;
+pub mod honeypot;
+pub mod hostcache;
+pub mod identity;
+pub mod ipfix;
+pub mod ipv6;
+pub mod json;
+pub mod kube;
+pub mod lz4;
+pub mod mail;
+pub mod measure;
+pub mod mtls;
+pub mod multicast;
+pub mod mux;
+pub mod nat64;
+pub mod noise;
+pub mod ntp;
+pub mod osguess;
+pub mod otel;
+pub mod outbound;
+pub mod output;
+pub mod pair;
+pub mod peertunnel;
+pub mod pool;
+pub mod preflight;
+pub mod progress;
+pub mod proxy;
+pub mod reachable;
+pub mod relay;
+pub mod repl;
+pub mod revproxy;
+pub mod scan;
+pub mod scheduler;
+pub mod schema;
+pub mod secret;
+pub mod selfbench;
+pub mod selftest;
+pub mod server;
+pub mod services;
+pub mod session;
+pub mod sha2;
+pub mod share;
+#[cfg(all(feature = "sniff", target_os = "linux"))]
+pub mod sniff;
+pub mod ssh;
+pub mod sshd;
+pub mod stats;
+#[cfg(feature = "syn-scan")]
+pub mod synscan;
+pub mod syslog;
+pub mod targets;
+pub mod tcpinfo;
+pub mod telnet;
+pub mod timeouts;
+pub mod tls;
+pub mod top;
+pub mod trace;
+pub mod transport;
+pub mod tunnel;
+pub mod udpscan;
+pub mod units;
+pub mod upstream;
+pub mod voip;
+pub mod vpn;
+pub mod web;
+pub mod webhook;
+pub mod x25519;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::sync::Mutex;
+use tokio::task::JoinSet;
+use tokio::time::{Duration, timeout};
+
+use events::Event;
+use outbound::Preference;
+
+#[derive(Default)]
+pub struct HostInfo {
+    pub local_ipv4: Option<Ipv4Addr>,
+    pub public_ipv4: Option<Ipv4Addr>,
+    pub local_ipv6: Option<Ipv6Addr>,
+    pub public_ipv6: Option<Ipv6Addr>,
+}
+
+impl HostInfo {
+    pub fn preferred_public_ip(&self, prefer: Preference) -> Option<IpAddr> {
+        let v4 = self.public_ipv4.map(IpAddr::V4);
+        let v6 = self.public_ipv6.map(IpAddr::V6);
+
+        if prefer.prefers_v4() {
+            v4.or(v6)
+        } else {
+            v6.or(v4)
+        }
+    }
+
+    pub fn update(&mut self, event: HostInfoEvent) {
+        match event {
+            HostInfoEvent::LocalIpv4(ip) => self.local_ipv4 = ip,
+            HostInfoEvent::PublicIpv4(ip) => self.public_ipv4 = ip,
+            HostInfoEvent::LocalIpv6(ip) => self.local_ipv6 = ip,
+            HostInfoEvent::PublicIpv6(ip) => self.public_ipv6 = ip,
+        }
+    }
+}
+
+// One finished lookup; None if it failed or timed out.
+#[derive(Clone, Copy)]
+pub enum HostInfoEvent {
+    LocalIpv4(Option<Ipv4Addr>),
+    PublicIpv4(Option<Ipv4Addr>),
+    LocalIpv6(Option<Ipv6Addr>),
+    PublicIpv6(Option<Ipv6Addr>),
+}
+
+// The address lookups in flight, handed out in the order they finish so
+// local addresses don't wait on the public ones. Dropping this abandons
+// whatever is still running.
+pub struct Discovery {
+    lookups: JoinSet<HostInfoEvent>,
+    wait: Duration,
+    // Public addresses from the cache, used once the local lookups confirm
+    // we're still on the same network.
+    cached: Option<HostInfo>,
+    found: HostInfo,
+    ready: Vec<HostInfoEvent>,
+    looked_up_public: bool,
+}
+
+impl Discovery {
+    pub fn start(cached: Option<HostInfo>) -> Discovery {
+        let wait = timeouts::get().discovery;
+        let mut lookups = JoinSet::new();
+        lookups.spawn(async move {
+            HostInfoEvent::LocalIpv4(timeout(wait, get_local_ipv4()).await.ok().flatten())
+        });
+        lookups.spawn(async move {
+            HostInfoEvent::LocalIpv6(timeout(wait, get_local_ipv6()).await.ok().flatten())
+        });
+        let mut discovery = Discovery {
+            lookups,
+            wait,
+            cached,
+            found: HostInfo::default(),
+            ready: Vec::new(),
+            looked_up_public: false,
+        };
+        if discovery.cached.is_none() {
+            discovery.look_up_public();
+        }
+        discovery
+    }
+
+    // Whether the public addresses came from the lookup services rather
+    // than the cache.
+    pub fn looked_up_public(&self) -> bool {
+        self.looked_up_public
+    }
+
+    fn look_up_public(&mut self) {
+        let wait = self.wait;
+        self.lookups.spawn(async move {
+            // Behind a proxy the lookup services may be out of reach, but
+            // whatever the proxy lets out still tells the address.
+            let lookup = async {
+                match proxy::public_ipv4().await {
+                    Some(ip) => Some(ip),
+                    None => public_ip::addr_v4().await,
+                }
+            };
+            HostInfoEvent::PublicIpv4(timeout(wait, lookup).await.ok().flatten())
+        });
+        self.lookups.spawn(async move {
+            HostInfoEvent::PublicIpv6(timeout(wait, public_ip::addr_v6()).await.ok().flatten())
+        });
+        self.looked_up_public = true;
+    }
+
+    // Called once the local lookups are done.
+    fn use_cache(&mut self) {
+        let Some(cached) = self.cached.take() else {
+            return;
+        };
+        let same_network = cached.local_ipv4 == self.found.local_ipv4
+            && cached.local_ipv6 == self.found.local_ipv6;
+        if same_network {
+            self.ready
+                .push(HostInfoEvent::PublicIpv6(cached.public_ipv6));
+            self.ready
+                .push(HostInfoEvent::PublicIpv4(cached.public_ipv4));
+        } else {
+            self.look_up_public();
+        }
+    }
+
+    // Cancel safe: a result is only taken in the poll that returns it.
+    pub async fn next(&mut self) -> Option<HostInfoEvent> {
+        loop {
+            if let Some(event) = self.ready.pop() {
+                note_public_ip(event);
+                return Some(event);
+            }
+            let result = self.lookups.join_next().await?;
+            if let Ok(event) = result {
+                self.found.update(event);
+            }
+            if self.lookups.is_empty() {
+                self.use_cache();
+            }
+            if let Ok(event) = result {
+                note_public_ip(event);
+                return Some(event);
+            }
+        }
+    }
+}
+
+// The public address last seen for each family, to tell when one changes.
+static PUBLIC_IPS: Mutex<[Option<IpAddr>; 2]> = Mutex::new([None, None]);
+
+fn note_public_ip(event: HostInfoEvent) {
+    let ip = match event {
+        HostInfoEvent::PublicIpv4(Some(ip)) => IpAddr::V4(ip),
+        HostInfoEvent::PublicIpv6(Some(ip)) => IpAddr::V6(ip),
+        _ => return,
+    };
+    let mut known = PUBLIC_IPS.lock().unwrap();
+    let last = &mut known[ip.is_ipv6() as usize];
+    if *last != Some(ip) {
+        let old = last.replace(ip);
+        events::emit(Event::PublicIpChanged { old, new: ip });
+    }
+}
+
+pub async fn get_host_info() -> HostInfo {
+    let mut discovery = Discovery::start(None);
+    let mut info = HostInfo::default();
+    while let Some(event) = discovery.next().await {
+        info.update(event);
+    }
+    info
+}
+
+pub async fn get_local_ipv4() -> Option<Ipv4Addr> {
+    use std::net::IpAddr;
+
+    tokio::task::spawn_blocking(|| {
+        local_ip_address::local_ip().ok().and_then(|ip| match ip {
+            IpAddr::V4(ipv4) => Some(ipv4),
+            _ => None,
+        })
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+pub async fn get_local_ipv6() -> Option<Ipv6Addr> {
+    use std::net::IpAddr;
+
+    tokio::task::spawn_blocking(|| {
+        local_ip_address::local_ipv6().ok().and_then(|ip| match ip {
+            IpAddr::V6(ipv6) => Some(ipv6),
+            _ => None,
+        })
+    })
+    .await
+    .ok()
+    .flatten()
+}
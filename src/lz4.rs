@@ -0,0 +1,166 @@
+// The LZ4 block format: a run of sequences, each some literal bytes followed
+// by a copy from earlier output, with the last sequence holding literals
+// only. No frame header or checksum; the tunnel frames carry the length.
+
+const MIN_MATCH: usize = 4;
+// The format requires the last 5 bytes to be literals and the last match to
+// start at least 12 bytes before the end.
+const LAST_LITERALS: usize = 5;
+const MF_LIMIT: usize = 12;
+const MAX_OFFSET: usize = 65_535;
+const HASH_LOG: u32 = 12;
+
+fn read_u32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(data[at..at + 4].try_into().unwrap())
+}
+
+fn hash(sequence: u32) -> usize {
+    (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize
+}
+
+// Lengths that don't fit in a token nibble continue in 255s and a final
+// byte below 255.
+fn write_length(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 255 {
+        out.push(255);
+        len -= 255;
+    }
+    out.push(len as u8);
+}
+
+fn read_length(input: &[u8], at: &mut usize, mut len: usize) -> Option<usize> {
+    if len != 15 {
+        return Some(len);
+    }
+    loop {
+        let byte = *input.get(*at)?;
+        *at += 1;
+        len = len.checked_add(byte as usize)?;
+        if byte != 255 {
+            return Some(len);
+        }
+    }
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], copy: Option<(usize, usize)>) {
+    let match_len = copy.map_or(0, |(_, len)| len - MIN_MATCH);
+    out.push(((literals.len().min(15) as u8) << 4) | match_len.min(15) as u8);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = copy {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            write_length(out, match_len - 15);
+        }
+    }
+}
+
+// Greedy matching against the last position each 4-byte hash was seen at,
+// which is what the reference implementation's fast mode does.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() + input.len() / 255 + 16);
+    let mut table = vec![0u32; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut at = 0;
+
+    if input.len() > MF_LIMIT {
+        let limit = input.len() - MF_LIMIT;
+        let match_end = input.len() - LAST_LITERALS;
+        while at < limit {
+            let sequence = read_u32(input, at);
+            let slot = &mut table[hash(sequence)];
+            // Slots hold position + 1 so that 0 means empty.
+            let candidate = (*slot as usize).checked_sub(1);
+            *slot = at as u32 + 1;
+
+            let Some(from) = candidate
+                .filter(|&from| at - from <= MAX_OFFSET && read_u32(input, from) == sequence)
+            else {
+                at += 1;
+                continue;
+            };
+
+            let mut len = MIN_MATCH;
+            while at + len < match_end && input[from + len] == input[at + len] {
+                len += 1;
+            }
+            write_sequence(&mut out, &input[anchor..at], Some((at - from, len)));
+            at += len;
+            anchor = at;
+        }
+    }
+
+    write_sequence(&mut out, &input[anchor..], None);
+    out
+}
+
+// None for malformed input, or if it would decompress to more than `max`
+// bytes.
+pub fn decompress(input: &[u8], max: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut at = 0;
+
+    loop {
+        let token = *input.get(at)?;
+        at += 1;
+
+        let literals = read_length(input, &mut at, (token >> 4) as usize)?;
+        let literals = input.get(at..at.checked_add(literals)?)?;
+        at += literals.len();
+        if out.len() + literals.len() > max {
+            return None;
+        }
+        out.extend_from_slice(literals);
+        if at == input.len() {
+            return Some(out);
+        }
+
+        let offset = u16::from_le_bytes([*input.get(at)?, *input.get(at + 1)?]) as usize;
+        at += 2;
+        if offset == 0 || offset > out.len() {
+            return None;
+        }
+        let len = read_length(input, &mut at, (token & 15) as usize)?.checked_add(MIN_MATCH)?;
+        if out.len() + len > max {
+            return None;
+        }
+        // Copy byte by byte: the match may overlap what it's writing.
+        let from = out.len() - offset;
+        for i in 0..len {
+            out.push(out[from + i]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    #[test]
+    fn round_trips_random_and_repetitive_data() {
+        let mut rng = StdRng::seed_from_u64(162);
+        for _ in 0..200 {
+            let len = rng.gen_range(0..20_000);
+            // Few distinct bytes for some inputs so there's plenty to match.
+            let alphabet = rng.gen_range(1..=256u16);
+            let input: Vec<u8> = (0..len)
+                .map(|_| (rng.gen_range(0..alphabet)) as u8)
+                .collect();
+            let packed = compress(&input);
+            assert_eq!(decompress(&packed, input.len()), Some(input));
+        }
+    }
+
+    #[test]
+    fn shrinks_text_and_refuses_to_exceed_max() {
+        let input = b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n".repeat(100);
+        let packed = compress(&input);
+        assert!(packed.len() * 10 < input.len());
+        assert_eq!(decompress(&packed, input.len() - 1), None);
+        assert_eq!(decompress(&[0x0f, 0x01, 0x00], 1024), None);
+    }
+}
@@ -0,0 +1,256 @@
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::cli::{self, Command, Opt};
+use crate::exit;
+use crate::outbound::{self, OutboundConfig};
+use crate::output;
+use crate::say;
+use crate::timeouts;
+use crate::tls;
+
+const SMTP_PORT: u16 = 25;
+const PORTS: &[u16] = &[25, 587, 465, 993, 995];
+
+const PROBE_OPTS: &[Opt] = &[
+    Opt {
+        name: "--port",
+        value: Some("<port>"),
+        help: "Probe only this port (repeatable, default: 25, 587, 465, 993, 995)",
+    },
+    Opt {
+        name: "--expect-issuer",
+        value: Some("<text>"),
+        help: "Flag certificates whose issuer does not contain this text",
+    },
+];
+
+#[derive(Clone, Copy)]
+enum Mode {
+    StartTls,
+    Implicit,
+}
+
+fn service(port: u16) -> (&'static str, Mode) {
+    match port {
+        25 | 587 => ("smtp", Mode::StartTls),
+        465 => ("smtps", Mode::Implicit),
+        993 => ("imaps", Mode::Implicit),
+        995 => ("pop3s", Mode::Implicit),
+        143 => ("imap", Mode::StartTls),
+        110 => ("pop3", Mode::StartTls),
+        _ => ("tls", Mode::Implicit),
+    }
+}
+
+enum Outcome {
+    Unreachable(String),
+    NoStartTls(String),
+    Failed(String),
+    Secured {
+        banner: Option<String>,
+        certificate: tls::Certificate,
+    },
+}
+
+async fn read_reply(reader: &mut BufReader<&mut TcpStream>) -> Result<Vec<String>, String> {
+    let mut lines = Vec::new();
+    loop {
+        let mut line = String::new();
+        let n = timeout(timeouts::get().read * 5, reader.read_line(&mut line))
+            .await
+            .map_err(|_| "timed out waiting for server".to_string())?
+            .map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Err("connection closed".to_string());
+        }
+        let line = line.trim_end().to_string();
+        // SMTP continues multi-line replies with "250-"; IMAP and POP reply on one line.
+        let more = line.as_bytes().get(3) == Some(&b'-');
+        lines.push(line);
+        if !more {
+            return Ok(lines);
+        }
+    }
+}
+
+async fn command(
+    reader: &mut BufReader<&mut TcpStream>,
+    line: &str,
+) -> Result<Vec<String>, String> {
+    reader
+        .get_mut()
+        .write_all(format!("{}\r\n", line).as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    read_reply(reader).await
+}
+
+async fn starttls(stream: &mut TcpStream, port: u16) -> Result<Result<String, String>, String> {
+    let mut reader = BufReader::new(stream);
+    let banner = read_reply(&mut reader).await?.join(" | ");
+
+    let offered = match port {
+        110 => command(&mut reader, "CAPA").await?,
+        143 => command(&mut reader, "a1 CAPABILITY").await?,
+        _ => command(&mut reader, "EHLO netcore.invalid").await?,
+    }
+    .iter()
+    .any(|line| line.to_uppercase().contains("STARTTLS") || line.to_uppercase() == "STLS");
+    if !offered {
+        return Ok(Err(banner));
+    }
+
+    let reply = match port {
+        110 => command(&mut reader, "STLS").await?,
+        143 => command(&mut reader, "a2 STARTTLS").await?,
+        _ => command(&mut reader, "STARTTLS").await?,
+    };
+    let accepted = reply.last().is_some_and(|line| {
+        line.starts_with("220") || line.starts_with("+OK") || line.starts_with("a2 OK")
+    });
+    if !accepted {
+        return Err(format!("STARTTLS refused: {}", reply.join(" | ")));
+    }
+    Ok(Ok(banner))
+}
+
+async fn probe(outbound: &OutboundConfig, host: &str, port: u16) -> Outcome {
+    let target = format!("{}:{}", host, port);
+    let sni = host.trim_start_matches('[').trim_end_matches(']');
+    let (_, mode) = service(port);
+
+    let mut stream = match timeout(timeouts::get().connect * 2, outbound.connect(&target)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => return Outcome::Unreachable(e.to_string()),
+        Err(_) => return Outcome::Unreachable("connect timed out".to_string()),
+    };
+
+    let banner = match mode {
+        Mode::Implicit => None,
+        Mode::StartTls => match starttls(&mut stream, port).await {
+            Ok(Ok(banner)) => Some(banner),
+            Ok(Err(banner)) => return Outcome::NoStartTls(banner),
+            Err(e) => return Outcome::Failed(e),
+        },
+    };
+
+    match tls::handshake_certificate(&mut stream, sni).await {
+        Ok(certificate) => Outcome::Secured {
+            banner,
+            certificate,
+        },
+        Err(e) => Outcome::Failed(format!("TLS handshake failed: {}", e)),
+    }
+}
+
+pub const PROBE_COMMAND: Command = Command {
+    name: "mail-probe",
+    usage: "netcore mail-probe <host>",
+    about: "Check a mail server's ports: banners, STARTTLS and certificates",
+    groups: &[PROBE_OPTS, outbound::OPTS, timeouts::OPTS],
+};
+
+pub async fn probe_command(tokens: Vec<String>) {
+    let args = cli::parse_command(&PROBE_COMMAND, tokens);
+    let Some(host) = args.positional().first() else {
+        eprintln!("mail-probe requires a mail server host");
+        std::process::exit(exit::USAGE);
+    };
+    let expect = args.value("--expect-issuer");
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+
+    let mut ports: Vec<u16> = cli::or_exit(
+        args.values("--port")
+            .map(|port| port.parse().map_err(|_| format!("invalid port '{}'", port)))
+            .collect::<Result<_, _>>(),
+    );
+    if ports.is_empty() {
+        ports = PORTS.to_vec();
+    }
+
+    let mut reachable = Vec::new();
+    let mut unreachable = Vec::new();
+    let mut intercepted = false;
+
+    for port in ports {
+        let (name, _) = service(port);
+        match probe(&outbound, host, port).await {
+            Outcome::Unreachable(e) => {
+                say!("FAIL  {:<5} {:<6} {}", port, name, e);
+                output::verdict("FAIL", &port.to_string(), &e);
+                unreachable.push(port);
+            }
+            Outcome::NoStartTls(banner) => {
+                say!(
+                    "WARN  {:<5} {:<6} STARTTLS not offered (banner: {})",
+                    port,
+                    name,
+                    banner
+                );
+                let detail = format!("STARTTLS not offered (banner: {})", banner);
+                output::verdict("WARN", &port.to_string(), &detail);
+                reachable.push(port);
+                intercepted = true;
+            }
+            Outcome::Failed(e) => {
+                say!("FAIL  {:<5} {:<6} {}", port, name, e);
+                output::verdict("FAIL", &port.to_string(), &e);
+                reachable.push(port);
+            }
+            Outcome::Secured {
+                banner,
+                certificate,
+            } => {
+                let warnings = certificate.warnings(expect);
+                let status = if warnings.is_empty() { "PASS" } else { "WARN" };
+                say!(
+                    "{}  {:<5} {:<6} TLS ok, issuer {}",
+                    status,
+                    port,
+                    name,
+                    certificate.issuer
+                );
+                if let Some(banner) = banner {
+                    say!("      banner: {}", banner);
+                }
+                for warning in &warnings {
+                    say!("      {}", warning);
+                }
+                let mut detail = format!("TLS ok, issuer {}", certificate.issuer);
+                for warning in &warnings {
+                    detail.push_str(&format!("; {}", warning));
+                }
+                output::verdict(status, &port.to_string(), &detail);
+                reachable.push(port);
+                intercepted |= !warnings.is_empty();
+            }
+        }
+    }
+
+    say!();
+    let mut summary = Vec::new();
+    if unreachable.contains(&SMTP_PORT) && !reachable.is_empty() {
+        summary.push((
+            "WARN",
+            "port 25 is unreachable while other mail ports work; outbound SMTP is likely blocked by the ISP".to_string(),
+        ));
+    } else if reachable.is_empty() {
+        summary.push(("FAIL", format!("no mail port is reachable on {}", host)));
+    }
+    if intercepted {
+        summary.push((
+            "WARN",
+            "STARTTLS stripping or unexpected certificates suggest TLS interception".to_string(),
+        ));
+    }
+    for (status, detail) in summary {
+        say!("{}  {}", status, detail);
+        output::verdict(status, host, &detail);
+    }
+    if !unreachable.is_empty() || intercepted {
+        std::process::exit(exit::FAILURE);
+    }
+}
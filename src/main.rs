@@ -1,206 +1,469 @@
-use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
-use tokio::net::TcpListener;
-use tokio::time::{Duration, timeout};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use netcore::alert::Alerts;
+use netcore::auth::Auth;
+use netcore::bandwidth::Bandwidth;
+use netcore::beacon::Beacon;
+use netcore::cli::{Command, Opt};
+use netcore::config::Config;
+use netcore::container::{Container, Network};
+use netcore::history::History;
+use netcore::hostcache::Cache;
+use netcore::json::Value;
+use netcore::kube::Pod;
+use netcore::mux::MuxConfig;
+use netcore::outbound::{OutboundConfig, Preference};
+use netcore::relay::RelayClient;
+use netcore::revproxy::ReverseProxy;
+use netcore::say;
+use netcore::server::ServerBuilder;
+#[cfg(all(feature = "sniff", target_os = "linux"))]
+use netcore::sniff;
+use netcore::ssh::SshTunnel;
+use netcore::sshd::SshServer;
+use netcore::web::WebUi;
+use netcore::{
+    Discovery, HostInfo, HostInfoEvent, acl, acme, audit, auth, bandwidth, beacon, certs, cli,
+    completions, container, control, dhcp, dns, dotenv, exit, fingerprint, firewall, fuzz, geoip,
+    guard, history, honeypot, hostcache, identity, ipfix, ipv6, kube, mail, measure, mtls,
+    multicast, mux, nat64, ntp, otel, outbound, output, pair, peertunnel, pool, preflight,
+    reachable, relay, repl, revproxy, scan, scheduler, selfbench, selftest, server, share, ssh,
+    sshd, syslog, tcpinfo, telnet, timeouts, tls, top, trace, voip, vpn, web,
+};
+
+const SERVE_OPTS: &[Opt] = &[
+    Opt {
+        name: "--config",
+        value: Some("<path>"),
+        help: "Configuration file: handler sections, timeouts, scheduled jobs and alerts; NETCORE_<SECTION>_<KEY> variables override its keys, and options override both",
+    },
+    Opt {
+        name: "--help-json",
+        value: None,
+        help: "Print every command and its options as JSON, for tools wrapping the CLI",
+    },
+];
+
+// Serve's options, which `config check` takes too.
+const SERVE_GROUPS: &[&[Opt]] = &[
+    SERVE_OPTS,
+    server::ECHO_OPTS,
+    telnet::OPTS,
+    mux::OPTS,
+    pool::OPTS,
+    revproxy::OPTS,
+    mtls::OPTS,
+    acme::OPTS,
+    certs::OPTS,
+    certs::WARN_OPTS,
+    outbound::OPTS,
+    timeouts::OPTS,
+    history::OPTS,
+    otel::OPTS,
+    ipfix::OPTS,
+    syslog::OPTS,
+    audit::OPTS,
+    control::OPTS,
+    control::SERVE_OPTS,
+    web::OPTS,
+    guard::OPTS,
+    auth::OPTS,
+    ssh::OPTS,
+    sshd::OPTS,
+    relay::OPTS,
+    beacon::OPTS,
+    bandwidth::OPTS,
+    fingerprint::OPTS,
+    tcpinfo::OPTS,
+    geoip::OPTS,
+    acl::OPTS,
+];
+
+const SERVE_COMMAND: Command = Command {
+    name: "serve",
+    usage: "netcore",
+    about: "Run the echo server (the default command)",
+    groups: SERVE_GROUPS,
+};
+
+const CONFIG_COMMAND: Command = Command {
+    name: "config",
+    usage: "netcore config check [serve options]",
+    about: "Check serve's options and config file, the files they name and the ports it would use",
+    groups: SERVE_GROUPS,
+};
+
+const INFO_OPTS: &[Opt] = &[
+    Opt {
+        name: "--format",
+        value: Some("<text|env>"),
+        help: "Print the addresses as text or as NETCORE_*=value lines for shells and .env files (default: text)",
+    },
+    Opt {
+        name: "--write",
+        value: Some("<path>"),
+        help: "Also write them to this .env file, replacing its NETCORE_* lines",
+    },
+];
+
+const INFO_COMMAND: Command = Command {
+    name: "info",
+    usage: "netcore info",
+    about: "Show local and public addresses",
+    groups: &[
+        INFO_OPTS,
+        outbound::OPTS,
+        timeouts::OPTS,
+        hostcache::OPTS,
+        history::OPTS,
+        geoip::OPTS,
+    ],
+};
+
+// Every command, the default first, in the order help and completions list
+// them.
+const COMMANDS: &[&Command] = &[
+    &SERVE_COMMAND,
+    &INFO_COMMAND,
+    &CONFIG_COMMAND,
+    &measure::PING_COMMAND,
+    &measure::CHECK_COMMAND,
+    &measure::BENCH_COMMAND,
+    &selfbench::COMMAND,
+    &selftest::COMMAND,
+    &fuzz::COMMAND,
+    &history::COMMAND,
+    &top::COMMAND,
+    &control::COMMAND,
+    &repl::COMMAND,
+    &relay::COMMAND,
+    &identity::COMMAND,
+    &peertunnel::COMMAND,
+    &ipv6::DIAG_COMMAND,
+    &nat64::COMMAND,
+    &firewall::COMMAND,
+    &reachable::COMMAND,
+    &kube::COMMAND,
+    &vpn::COMMAND,
+    &dhcp::PROBE_COMMAND,
+    &dns::BENCH_COMMAND,
+    &tls::PROBE_COMMAND,
+    &certs::COMMAND,
+    &trace::HTTP_COMMAND,
+    &mail::PROBE_COMMAND,
+    &voip::COMMAND,
+    &ntp::COMMAND,
+    &multicast::COMMAND,
+    &beacon::COMMAND,
+    &pair::COMMAND,
+    &share::COMMAND,
+    &honeypot::COMMAND,
+    #[cfg(all(feature = "sniff", target_os = "linux"))]
+    &sniff::COMMAND,
+    &scan::REMOTE_COMMAND,
+    &scan::LAN_COMMAND,
+    &completions::COMMAND,
+];
+
+// What an operator may run over --ssh-server: read-only diagnostics.
+const SSH_COMMANDS: &[&Command] = &[
+    &INFO_COMMAND,
+    &measure::CHECK_COMMAND,
+    &scan::REMOTE_COMMAND,
+    &scan::LAN_COMMAND,
+];
+
+// Prints each address as soon as it's known, then the preferred public IP
+// once every lookup is done, unless `show` is off.
+async fn discover_host_info(prefer: Preference, cache: Option<&Cache>, show: bool) -> HostInfo {
+    let mut discovery = Discovery::start(cache.and_then(Cache::load));
+    let mut info = HostInfo::default();
+    while let Some(event) = discovery.next().await {
+        if show {
+            print_host_info_event(&event);
+        }
+        info.update(event);
+    }
 
+    let found_public = info.public_ipv4.is_some() || info.public_ipv6.is_some();
+    if let Some(cache) = cache
+        && discovery.looked_up_public()
+        && found_public
+        && let Err(e) = cache.store(&info)
+    {
+        eprintln!("Failed to update host info cache: {}", e);
+    }
 
-struct HostInfo {
-    local_ipv4: Option<Ipv4Addr>,
-    public_ipv4: Option<Ipv4Addr>,
-    local_ipv6: Option<Ipv6Addr>,
-    public_ipv6: Option<Ipv6Addr>,
+    if show && let Some(container) = container::detect() {
+        print_container(&container);
+    }
+    if show && let Some(pod) = Pod::detect() {
+        say!("Pod: {}", pod.describe());
+    }
+    if show && let Some(ip) = info.preferred_public_ip(prefer) {
+        say!(
+            "Preferred public IP: {} ({}, prefer {})",
+            ip,
+            outbound::family(ip),
+            prefer
+        );
+    }
+    info
 }
 
-const TIMEOUT_SECS: u64 = 2;
-
-async fn get_host_info() -> HostInfo {
-    let (local_v4, public_v4, local_v6, public_v6) = tokio::join!(
-        timeout(Duration::from_secs(TIMEOUT_SECS), get_local_ipv4()),
-        timeout(Duration::from_secs(TIMEOUT_SECS), public_ip::addr_v4()),
-        timeout(Duration::from_secs(TIMEOUT_SECS), get_local_ipv6()),
-        timeout(Duration::from_secs(TIMEOUT_SECS), public_ip::addr_v6())
-    );
-
-    HostInfo {
-        local_ipv4: local_v4.ok().flatten(),
-        public_ipv4: public_v4.ok().flatten(),
-        local_ipv6: local_v6.ok().flatten(),
-        public_ipv6: public_v6.ok().flatten(),
+fn print_container(container: &Container) {
+    match (container.network, container.gateway) {
+        (Network::Bridge, Some(gateway)) => say!(
+            "Container: {}, bridge network via {} (local addresses are the container's, not the host's)",
+            container.runtime,
+            gateway
+        ),
+        (Network::Bridge, None) => say!(
+            "Container: {}, bridge network (local addresses are the container's, not the host's)",
+            container.runtime
+        ),
+        (Network::Host, _) => say!("Container: {}, host network", container.runtime),
+        (Network::Isolated, _) => say!("Container: {}, no network", container.runtime),
     }
 }
 
-async fn get_local_ipv4() -> Option<Ipv4Addr> {
-    use std::net::IpAddr;
-
-    tokio::task::spawn_blocking(|| {
-        local_ip_address::local_ip().ok().and_then(|ip| match ip {
-            IpAddr::V4(ipv4) => Some(ipv4),
-            _ => None,
-        })
-    })
-    .await
-    .ok()
-    .flatten()
+fn print_host_info_event(event: &HostInfoEvent) {
+    match *event {
+        HostInfoEvent::LocalIpv4(Some(ip)) => say!("Local IPv4: {}", ip),
+        HostInfoEvent::LocalIpv4(None) => eprintln!("Failed to get local IPv4"),
+        HostInfoEvent::PublicIpv4(Some(ip)) => match geoip::lookup(IpAddr::V4(ip)) {
+            Some(geo) => say!("Public IPv4: {} ({})", ip, geo),
+            None => say!("Public IPv4: {}", ip),
+        },
+        HostInfoEvent::PublicIpv4(None) => eprintln!("Failed to get public IPv4"),
+        HostInfoEvent::LocalIpv6(Some(ip)) => say!("Local IPv6: {}", ip),
+        HostInfoEvent::LocalIpv6(None) => eprintln!("Failed to get local IPv6"),
+        HostInfoEvent::PublicIpv6(Some(ip)) => match geoip::lookup(IpAddr::V6(ip)) {
+            Some(geo) => say!("Public IPv6: {} ({})", ip, geo),
+            None => say!("Public IPv6: {}", ip),
+        },
+        HostInfoEvent::PublicIpv6(None) => eprintln!("Failed to get public IPv6"),
+    }
 }
 
-async fn get_local_ipv6() -> Option<Ipv6Addr> {
-    use std::net::IpAddr;
+async fn info_command(tokens: Vec<String>) {
+    let args = cli::parse_command(&INFO_COMMAND, tokens);
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+    let cache = cli::or_exit(Cache::from_args(&args));
+    cli::or_exit(geoip::init(&args));
+    let history = History::from_args(&args);
+    let env = match args.value("--format").unwrap_or("text") {
+        "text" => false,
+        "env" => true,
+        other => cli::or_exit(Err(format!(
+            "unknown --format '{}', expected text or env",
+            other
+        ))),
+    };
+
+    let info = discover_host_info(outbound.prefer, cache.as_ref(), !env).await;
+
+    let samples = measure::host_info_samples(&info);
+    measure::emit(&samples);
+    if let Some(history) = history {
+        measure::record(&history, &samples).await;
+    }
 
-    tokio::task::spawn_blocking(|| {
-        local_ip_address::local_ipv6().ok().and_then(|ip| match ip {
-            IpAddr::V6(ipv6) => Some(ipv6),
-            _ => None,
+    // Every address, empty when missing so a stale one is cleared.
+    let mut vars: Vec<(String, String)> = samples
+        .iter()
+        .map(|sample| {
+            let value = match sample.ok {
+                true => sample.value.clone(),
+                false => String::new(),
+            };
+            (format!("NETCORE_{}", sample.subject.to_uppercase()), value)
         })
-    })
-    .await
-    .ok()
-    .flatten()
-}
-
-async fn find_available_port_parallel(start: u16, end: u16) -> Option<u16> {
-    let tasks: Vec<_> = (start..=end)
-        .map(|port| tokio::spawn(async move { (port, is_port_available(port).await) }))
         .collect();
-
-    for task in tasks {
-        if let Ok((port, available)) = task.await {
-            if available {
-                return Some(port);
-            }
+    let preferred = info.preferred_public_ip(outbound.prefer);
+    vars.push((
+        "NETCORE_PUBLIC_IP".to_string(),
+        preferred.map(|ip| ip.to_string()).unwrap_or_default(),
+    ));
+    if env {
+        print!("{}", dotenv::render(&vars));
+    }
+    if let Some(path) = args.value("--write") {
+        if let Err(e) = dotenv::write(Path::new(path), &vars) {
+            eprintln!("Failed to write {}", e);
+            std::process::exit(exit::FAILURE);
+        }
+        if !env {
+            say!("Wrote {}", path);
         }
     }
-
-    None
 }
 
-async fn is_port_available(port: u16) -> bool {
-    let (ipv4_ok, ipv6_ok) = tokio::join!(check_port_ipv4(port), check_port_ipv6(port));
-
-    ipv4_ok && ipv6_ok
-}
-
-async fn check_port_ipv4(port: u16) -> bool {
-    TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))
-        .await
-        .is_ok()
-}
-
-async fn check_port_ipv6(port: u16) -> bool {
-    TcpListener::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0))
-        .await
-        .is_ok()
-}
-async fn handle_client(mut socket: tokio::net::TcpStream, addr: std::net::SocketAddr) {
-    println!("New connection from: {}", addr);
-
-    let mut buffer = [0; 1024];
-
-    loop {
-        match socket.read(&mut buffer).await {
-            Ok(0) => {
-                println!("Connection closed by: {}", addr);
-                break;
-            }
-            Ok(n) => {
-                println!("Received {} bytes from {}", n, addr);
-
-                // Echo back
-                if let Err(e) = socket.write_all(&buffer[..n]).await {
-                    eprintln!("Failed to write to {}: {}", addr, e);
-                    break;
-                }
-            }
-            Err(e) => {
-                eprintln!("Error reading from {}: {}", addr, e);
-                break;
-            }
-        }
+async fn serve(tokens: Vec<String>) {
+    let args = cli::parse_command(&SERVE_COMMAND, tokens);
+    if args.flag("--help-json") {
+        println!("{}", completions::help_json(COMMANDS));
+        return;
     }
-}
 
-async fn run_server_ipv4(listener: TcpListener) {
-    println!(
-        "IPv4 server listening on {}",
-        listener.local_addr().unwrap()
-    );
-
-    loop {
-        match listener.accept().await {
-            Ok((socket, addr)) => {
-                tokio::spawn(async move {
-                    handle_client(socket, addr).await;
-                });
-            }
-            Err(e) => {
-                eprintln!("IPv4 accept error: {}", e);
-            }
-        }
+    if let Some(extra) = args.positional().first() {
+        eprintln!("unexpected argument '{}'", extra);
+        std::process::exit(exit::USAGE);
     }
-}
 
-async fn run_server_ipv6(listener: TcpListener) {
-    println!(
-        "IPv6 server listening on {}",
-        listener.local_addr().unwrap()
-    );
-
-    loop {
-        match listener.accept().await {
-            Ok((socket, addr)) => {
-                tokio::spawn(async move {
-                    handle_client(socket, addr).await;
-                });
-            }
-            Err(e) => {
-                eprintln!("IPv6 accept error: {}", e);
-            }
-        }
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    let prefer = outbound.prefer;
+    let config = cli::or_exit(Config::from_args(&args, preflight::UNNAMED));
+    if let Some(config) = &config {
+        cli::or_exit(config.check_kinds(preflight::SECTIONS));
+    }
+    cli::or_exit(timeouts::init(&args, config.as_ref()));
+    let history = History::from_args(&args).map(Arc::new);
+    cli::or_exit(otel::init(&args));
+    cli::or_exit(ipfix::init(&args).await);
+    cli::or_exit(syslog::init(&args).await);
+    cli::or_exit(audit::init(&args));
+    fingerprint::init(&args);
+    tcpinfo::init(&args);
+    cli::or_exit(server::init_echo(&args, config.as_ref()));
+    cli::or_exit(geoip::init(&args));
+    cli::or_exit(acl::init(&args).await);
+    cli::or_exit(acme::init(&args));
+    if let Some((files, warn)) = cli::or_exit(certs::from_args(&args, config.as_ref())) {
+        tokio::spawn(certs::watch(files, warn));
     }
-}
-#[tokio::main]
-async fn main() {
-    let info = get_host_info().await;
 
-    match info.local_ipv4 {
-        Some(ip) => println!("Local IPv4: {}", ip),
-        None => eprintln!("Failed to get local IPv4"),
+    if !args.flag("--no-control") {
+        tokio::spawn(control::serve(
+            control::path_from_args(&args),
+            outbound.clone(),
+        ));
     }
 
-    match info.public_ipv4 {
-        Some(ip) => println!("Public IPv4: {}", ip),
-        None => eprintln!("Failed to get public IPv4"),
+    if let Some(config) = &config {
+        let jobs = cli::or_exit(scheduler::jobs(config));
+        let alerts = cli::or_exit(Alerts::from_config(config));
+        scheduler::spawn(jobs, outbound.clone(), history.clone(), Arc::new(alerts));
     }
 
-    match info.local_ipv6 {
-        Some(ip) => println!("Local IPv6: {}", ip),
-        None => eprintln!("Failed to get local IPv6"),
+    let auth = cli::or_exit(Auth::from_args(&args));
+    let web = cli::or_exit(WebUi::from_args(&args, auth));
+    let web_port = web.as_ref().map(|web| web.addr.port());
+    if let Some(web) = web {
+        tokio::spawn(web::serve(web, outbound.clone(), history.clone()));
     }
 
-    match info.public_ipv6 {
-        Some(ip) => println!("Public IPv6: {}", ip),
-        None => eprintln!("Failed to get public IPv6"),
+    if let Some(server) = cli::or_exit(SshServer::from_args(&args)) {
+        tokio::spawn(sshd::serve(server, SSH_COMMANDS));
     }
 
-    match find_available_port_parallel(6881, 6900).await {
-        Some(port) => {
-            println!("Found available port: {}", port);
+    let relay_outbound = outbound.clone();
+    let mux = cli::or_exit(MuxConfig::from_args(
+        &args,
+        config.as_ref(),
+        outbound.clone(),
+    ))
+    .map(Arc::new);
+    let reverse_proxy =
+        cli::or_exit(ReverseProxy::from_args(&args, config.as_ref(), outbound)).map(Arc::new);
+    if mux.is_some() && reverse_proxy.is_some() {
+        eprintln!("--http-route can't be combined with --mux routes");
+        std::process::exit(exit::USAGE);
+    }
+    let ssh_tunnel = cli::or_exit(SshTunnel::from_args(&args));
+    let relay_client = cli::or_exit(RelayClient::from_args(&args));
+    let beacon = cli::or_exit(Beacon::from_args(&args));
+    let limits = cli::or_exit(Bandwidth::from_args(&args)).listener();
 
-            let ipv4_listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))
-                .await
-                .unwrap();
+    discover_host_info(prefer, None, true).await;
 
-            let ipv6_listener =
-                TcpListener::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0))
-                    .await
-                    .unwrap();
+    let mut builder = ServerBuilder::new().port_range(6881..=6900).limits(limits);
+    if let Some(config) = mux {
+        builder = builder.handler(config);
+    }
+    if let Some(proxy) = reverse_proxy {
+        builder = builder.handler(proxy);
+    }
+    match builder.build().await {
+        Ok(server) => {
+            let port = server.port();
+            say!("Found available port: {}", port);
+            say!("Servers started on port {}", port);
+            output::emit(Value::object([("port", Value::from(port as u64))]));
+
+            if let Some(tunnel) = ssh_tunnel {
+                tokio::spawn(ssh::run(tunnel, port));
+            }
+            if let Some(client) = relay_client {
+                tokio::spawn(relay::run(client, relay_outbound, port));
+            }
+            if let Some(beacon) = beacon {
+                let mut services = vec![("echo", port)];
+                services.extend(web_port.map(|port| ("web", port)));
+                tokio::spawn(beacon::announce(beacon, services));
+            }
 
-            println!("Servers started on port {}", port);
+            server.run().await;
+        }
+        Err(e) => eprintln!("Failed to start server: {}", e),
+    }
+}
 
-            tokio::join!(
-                run_server_ipv4(ipv4_listener),
-                run_server_ipv6(ipv6_listener)
-            );
+#[tokio::main]
+async fn main() {
+    let mut tokens: Vec<String> = std::env::args().skip(1).collect();
+    let command = match tokens.first() {
+        Some(first) if !first.starts_with("--") => Some(tokens.remove(0)),
+        _ => None,
+    };
+
+    match command.as_deref() {
+        None | Some("serve") => serve(tokens).await,
+        Some("info") => info_command(tokens).await,
+        Some("config") => preflight::command(tokens, &CONFIG_COMMAND).await,
+        Some("ping") => measure::ping_command(tokens).await,
+        Some("check") => measure::check_command(tokens).await,
+        Some("bench") => measure::bench_command(tokens).await,
+        Some("selfbench") => selfbench::command(tokens).await,
+        Some("selftest") => selftest::command(tokens).await,
+        Some("fuzz") => fuzz::command(tokens),
+        Some("history") => history::command(tokens),
+        Some("top") => top::command(tokens).await,
+        Some("ctl") => control::command(tokens).await,
+        Some("repl") => repl::command(tokens).await,
+        Some("relay") => relay::command(tokens).await,
+        Some("keygen") => identity::command(tokens),
+        Some("peer-tunnel") => peertunnel::command(tokens).await,
+        Some("ipv6-diag") => ipv6::diag_command(tokens).await,
+        Some("nat64") => nat64::command(tokens).await,
+        Some("firewall") => firewall::command(tokens).await,
+        Some("reachable") => reachable::command(tokens).await,
+        Some("kube-check") => kube::command(tokens).await,
+        Some("vpn-check") => vpn::command(tokens).await,
+        Some("dhcp-probe") => dhcp::probe_command(tokens).await,
+        Some("dns-bench") => dns::bench_command(tokens).await,
+        Some("tls-probe") => tls::probe_command(tokens).await,
+        Some("certs") => certs::command(tokens).await,
+        Some("trace-http") => trace::http_command(tokens).await,
+        Some("mail-probe") => mail::probe_command(tokens).await,
+        Some("voip") => voip::command(tokens).await,
+        Some("ntp") => ntp::command(tokens).await,
+        Some("multicast") => multicast::command(tokens).await,
+        Some("beacon") => beacon::command(tokens).await,
+        Some("pair") => pair::command(tokens).await,
+        Some("share-text") => share::command(tokens).await,
+        Some("honeypot") => honeypot::command(tokens).await,
+        #[cfg(all(feature = "sniff", target_os = "linux"))]
+        Some("sniff") => sniff::command(tokens).await,
+        Some("scan-remote") => scan::remote_command(tokens).await,
+        Some("scan-lan") => scan::lan_command(tokens).await,
+        Some("completions") => completions::command(tokens, COMMANDS),
+        Some(other) => {
+            eprintln!("unknown command '{}'", other);
+            std::process::exit(exit::USAGE);
         }
-        None => eprintln!("No available port found in range 6882-6900"),
     }
 }
@@ -0,0 +1,899 @@
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpStream, lookup_host};
+use tokio::time::{Duration, Instant, sleep, timeout, timeout_at};
+
+use crate::HostInfo;
+use crate::cli::{self, Command, Opt};
+use crate::container;
+use crate::exit;
+use crate::gateway;
+use crate::geoip;
+use crate::history::{self, History};
+use crate::json::Value;
+use crate::kube::Pod;
+use crate::ntp;
+use crate::outbound::{self, OutboundConfig};
+use crate::output;
+use crate::progress::{self, Progress, Tracker, Unit};
+use crate::proxy;
+use crate::say;
+use crate::session::Header;
+use crate::timeouts;
+use crate::top::format_bytes;
+use crate::units::{self, MILLIS, SECS};
+
+const CHECK_DNS_NAME: &str = "example.com:80";
+const CHECK_TCP_IPV4: &str = "1.1.1.1:443";
+const CHECK_TCP_IPV6: &str = "[2606:4700:4700::1111]:443";
+const CHECK_NTP_SERVER: &str = "pool.ntp.org:123";
+const BENCH_CHUNK: usize = 16 * 1024;
+const BENCH_SECS: u64 = 10;
+const RESUME_DELAY: Duration = Duration::from_millis(500);
+
+const PING_OPTS: &[Opt] = &[
+    Opt {
+        name: "--count",
+        value: Some("<n>"),
+        help: "Number of connection attempts (default: 4)",
+    },
+    Opt {
+        name: "--interval",
+        value: Some("<duration>"),
+        help: "Delay between attempts (default: 1s)",
+    },
+];
+
+const BENCH_OPTS: &[Opt] = &[
+    Opt {
+        name: "--duration",
+        value: Some("<duration>"),
+        help: "How long to send data (default: 10s)",
+    },
+    Opt {
+        name: "--resume",
+        value: None,
+        help: "Reconnect and carry on if the connection drops",
+    },
+];
+
+const COMPARE_OPTS: &[Opt] = &[Opt {
+    name: "--interfaces",
+    value: Some("<a,b|all>"),
+    help: "Run on each interface at once and compare them (all: those with a default route)",
+}];
+
+pub struct Sample {
+    pub kind: &'static str,
+    pub subject: String,
+    pub value: String,
+    pub ok: bool,
+}
+
+impl Sample {
+    pub fn new(kind: &'static str, subject: &str, value: String, ok: bool) -> Sample {
+        Sample {
+            kind,
+            subject: subject.to_string(),
+            value,
+            ok,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("kind", Value::from(self.kind)),
+            ("subject", Value::from(self.subject.as_str())),
+            ("value", Value::from(self.value.as_str())),
+            ("ok", Value::from(self.ok)),
+        ])
+    }
+}
+
+// Samples are the results of the measuring commands in porcelain mode too.
+pub fn emit(samples: &[Sample]) {
+    for sample in samples {
+        output::emit(sample.to_json());
+    }
+}
+
+// Appending may rotate the file, so it runs on a blocking thread rather
+// than holding up the task that measured.
+pub async fn record(history: &History, samples: &[Sample]) {
+    let history = history.clone();
+    let rows: Vec<_> = samples
+        .iter()
+        .map(|s| (s.kind, s.subject.clone(), s.value.clone()))
+        .collect();
+    let written = tokio::task::spawn_blocking(move || {
+        rows.iter()
+            .try_for_each(|(kind, subject, value)| history.append(kind, subject, value))
+    })
+    .await;
+    if let Err(e) = written.unwrap_or_else(|e| Err(e.into())) {
+        eprintln!("Failed to record history: {}", e);
+    }
+}
+
+pub fn host_info_samples(info: &HostInfo) -> Vec<Sample> {
+    let fields = [
+        ("local_ipv4", info.local_ipv4.map(IpAddr::V4)),
+        ("public_ipv4", info.public_ipv4.map(IpAddr::V4)),
+        ("local_ipv6", info.local_ipv6.map(IpAddr::V6)),
+        ("public_ipv6", info.public_ipv6.map(IpAddr::V6)),
+    ];
+
+    let mut samples: Vec<Sample> = fields
+        .iter()
+        .map(|(subject, ip)| match ip {
+            Some(ip) => Sample::new("info", subject, ip.to_string(), true),
+            None => Sample::new("info", subject, history::NONE.to_string(), false),
+        })
+        .collect();
+
+    for (subject, ip) in [
+        ("public_ipv4_geo", fields[1].1),
+        ("public_ipv6_geo", fields[3].1),
+    ] {
+        if let Some(geo) = ip.and_then(geoip::lookup) {
+            samples.push(Sample::new("info", subject, geo.to_string(), true));
+        }
+    }
+
+    if let Some(pod) = Pod::detect() {
+        for (subject, value) in pod.fields() {
+            samples.push(Sample::new("info", subject, value, true));
+        }
+    }
+
+    // Inside a container the local addresses above are its own, not the
+    // host's.
+    if let Some(container) = container::detect() {
+        samples.push(Sample::new(
+            "info",
+            "container",
+            container.runtime.to_string(),
+            true,
+        ));
+        samples.push(Sample::new(
+            "info",
+            "container_network",
+            container.network.as_str().to_string(),
+            true,
+        ));
+        if let Some(gateway) = container.gateway {
+            samples.push(Sample::new(
+                "info",
+                "container_gateway",
+                gateway.to_string(),
+                true,
+            ));
+        }
+    }
+    samples
+}
+
+pub async fn tcp_rtt(outbound: &OutboundConfig, addr: SocketAddr) -> Result<Duration, String> {
+    let start = Instant::now();
+
+    match timeout(timeouts::get().connect, outbound.connect_addr(addr)).await {
+        Ok(Ok(_)) => Ok(start.elapsed()),
+        Ok(Err(e)) => Err(e.to_string()),
+        Err(_) => Err("timed out".to_string()),
+    }
+}
+
+pub fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+pub async fn ping<F>(
+    outbound: &OutboundConfig,
+    addr: SocketAddr,
+    count: u32,
+    interval: Duration,
+    mut on_result: F,
+) -> Vec<Result<Duration, String>>
+where
+    F: FnMut(u32, &Result<Duration, String>),
+{
+    let mut results = Vec::new();
+
+    for seq in 1..=count {
+        if seq > 1 {
+            sleep(interval).await;
+        }
+
+        let result = tcp_rtt(outbound, addr).await;
+        on_result(seq, &result);
+        results.push(result);
+    }
+
+    results
+}
+
+pub fn ping_samples(target: &str, results: &[Result<Duration, String>]) -> Vec<Sample> {
+    results
+        .iter()
+        .map(|result| match result {
+            Ok(rtt) => Sample::new("ping", target, format!("{:.3}", millis(*rtt)), true),
+            Err(_) => Sample::new("ping", target, history::NONE.to_string(), false),
+        })
+        .collect()
+}
+
+// The checks that go out through `outbound`, and so the ones that tell
+// interfaces apart.
+pub async fn tcp_samples(outbound: &OutboundConfig) -> Vec<Sample> {
+    let mut samples = Vec::new();
+    for (subject, target) in [("tcp_ipv4", CHECK_TCP_IPV4), ("tcp_ipv6", CHECK_TCP_IPV6)] {
+        let Ok(addr) = target.parse() else {
+            continue;
+        };
+        samples.push(match tcp_rtt(outbound, addr).await {
+            Ok(rtt) => Sample::new(
+                "check",
+                subject,
+                format!("connected in {:.2} ms", millis(rtt)),
+                true,
+            ),
+            Err(e) => Sample::new("check", subject, e, false),
+        });
+    }
+    samples
+}
+
+pub async fn check(outbound: &OutboundConfig) -> Vec<Sample> {
+    let info = crate::get_host_info().await;
+    let local = info
+        .local_ipv4
+        .map(IpAddr::V4)
+        .or(info.local_ipv6.map(IpAddr::V6));
+    let public = info
+        .public_ipv4
+        .map(IpAddr::V4)
+        .or(info.public_ipv6.map(IpAddr::V6));
+
+    let mut samples = vec![
+        match local {
+            Some(ip) => Sample::new("check", "local_address", ip.to_string(), true),
+            None => Sample::new("check", "local_address", "no address".to_string(), false),
+        },
+        match public {
+            Some(ip) => Sample::new("check", "public_address", ip.to_string(), true),
+            None => Sample::new(
+                "check",
+                "public_address",
+                "discovery failed".to_string(),
+                false,
+            ),
+        },
+    ];
+
+    // Only shown when the system asks for one.
+    let proxy = proxy::settings().await;
+    match (&proxy.proxy, &proxy.problem) {
+        (_, Some(problem)) => samples.push(Sample::new(
+            "check",
+            "proxy",
+            format!("not used: {}", problem),
+            false,
+        )),
+        (Some(via), None) => samples.push(Sample::new(
+            "check",
+            "proxy",
+            format!("{} from {}", via, proxy.source),
+            true,
+        )),
+        (None, None) => {}
+    }
+
+    let wait = timeouts::get().discovery;
+    let gateways = tokio::task::spawn_blocking(move || {
+        [
+            ("gateway_ipv4", gateway::default_ipv4()),
+            ("gateway_ipv6", gateway::default_ipv6()),
+        ]
+        .map(|(subject, found)| (subject, found.map(|found| gateway::probe(found, wait))))
+    })
+    .await
+    .unwrap_or_default();
+    for (subject, report) in gateways {
+        samples.push(match report {
+            Some(report) => Sample::new("check", subject, report.describe(), report.ok()),
+            None => Sample::new("check", subject, "no default route".to_string(), false),
+        });
+    }
+
+    let start = Instant::now();
+    let dns = timeout(timeouts::get().dns, lookup_host(CHECK_DNS_NAME))
+        .await
+        .map(|result| result.map(Iterator::count));
+    samples.push(match dns {
+        Ok(Ok(0)) => Sample::new("check", "dns", "no addresses".to_string(), false),
+        Ok(Ok(_)) => Sample::new(
+            "check",
+            "dns",
+            format!("resolved in {:.2} ms", millis(start.elapsed())),
+            true,
+        ),
+        Ok(Err(e)) => Sample::new("check", "dns", e.to_string(), false),
+        Err(_) => Sample::new("check", "dns", "timed out".to_string(), false),
+    });
+
+    samples.extend(tcp_samples(outbound).await);
+
+    samples.push(match outbound.resolve(CHECK_NTP_SERVER).await {
+        Ok(addrs) => match ntp::query(addrs[0]).await {
+            Ok(reply) if reply.offset.abs() > ntp::MAX_SKEW_SECS => Sample::new(
+                "check",
+                "clock",
+                format!("skewed by {:+.1} s", reply.offset),
+                false,
+            ),
+            Ok(reply) => Sample::new(
+                "check",
+                "clock",
+                format!("offset {:+.1} ms", reply.offset * 1000.0),
+                true,
+            ),
+            Err(e) => Sample::new("check", "clock", e, false),
+        },
+        Err(e) => Sample::new("check", "clock", e.to_string(), false),
+    });
+
+    samples
+}
+
+pub struct BenchResult {
+    pub sent: u64,
+    pub received: u64,
+    pub elapsed: Duration,
+    pub resumed: u32,
+}
+
+impl BenchResult {
+    pub fn mbps(&self) -> f64 {
+        self.received as f64 * 8.0 / self.elapsed.as_secs_f64().max(f64::EPSILON) / 1_000_000.0
+    }
+
+    pub fn sample(&self, target: &str) -> Sample {
+        Sample::new(
+            "bench",
+            target,
+            format!("{:.2}", self.mbps()),
+            self.received > 0,
+        )
+    }
+}
+
+// With `resume`, a dropped connection is reopened until the duration is up
+// and the totals carry on across connections. Every connection then starts
+// with the same session header, which the echo server returns first.
+pub async fn bench(
+    outbound: &OutboundConfig,
+    target: &str,
+    duration: Duration,
+    resume: bool,
+    progress: Option<Arc<dyn Progress>>,
+) -> Result<BenchResult, String> {
+    let tracker = progress.map(|progress| Tracker::timed(progress, "bench", Unit::Bytes, duration));
+    let start = Instant::now();
+    let deadline = start + duration;
+    let mut header = resume.then(Header::start);
+    let mut result = BenchResult {
+        sent: 0,
+        received: 0,
+        elapsed: Duration::ZERO,
+        resumed: 0,
+    };
+
+    loop {
+        let dropped = match bench_connect(outbound, target, header.as_ref()).await {
+            Ok(stream) => {
+                let (sent, received) = bench_connection(stream, deadline, tracker.as_ref()).await;
+                result.sent += sent;
+                result.received += received;
+                Instant::now() < deadline
+            }
+            // Only a reconnect is worth retrying; failing to reach the
+            // target at all is an error.
+            Err(e) if result.resumed == 0 => return Err(e),
+            Err(e) => {
+                eprintln!("Reconnecting to {} failed: {}", target, e);
+                true
+            }
+        };
+        let Some(previous) = &header else {
+            break;
+        };
+        if !dropped || Instant::now() + RESUME_DELAY >= deadline {
+            break;
+        }
+        sleep(RESUME_DELAY).await;
+        let next = previous.resumed();
+        say!(
+            "Connection dropped, resuming session {} (attempt {})",
+            next.token,
+            next.attempt
+        );
+        header = Some(next);
+        result.resumed += 1;
+    }
+
+    result.elapsed = start.elapsed();
+    if let Some(tracker) = &tracker {
+        tracker.finish();
+    }
+    Ok(result)
+}
+
+async fn bench_connect(
+    outbound: &OutboundConfig,
+    target: &str,
+    header: Option<&Header>,
+) -> Result<TcpStream, String> {
+    let mut stream = timeout(timeouts::get().connect, outbound.connect(target))
+        .await
+        .map_err(|_| "connect timed out".to_string())?
+        .map_err(|e| e.to_string())?;
+
+    if let Some(header) = header {
+        let sent = header.to_bytes();
+        let mut echoed = vec![0; sent.len()];
+        let exchange = async {
+            stream.write_all(&sent).await?;
+            stream.read_exact(&mut echoed).await
+        };
+        timeout(timeouts::get().handshake, exchange)
+            .await
+            .map_err(|_| "session handshake timed out".to_string())?
+            .map_err(|e| e.to_string())?;
+        if echoed != sent {
+            return Err("peer did not echo the session header".to_string());
+        }
+    }
+    Ok(stream)
+}
+
+// Bytes sent and echoed back on one connection, until the deadline or the
+// connection drops.
+async fn bench_connection(
+    stream: TcpStream,
+    deadline: Instant,
+    tracker: Option<&Tracker>,
+) -> (u64, u64) {
+    let (mut reader, mut writer) = stream.into_split();
+
+    let sender = tokio::spawn(async move {
+        let chunk = vec![0x5a; BENCH_CHUNK];
+        let mut sent = 0;
+        while Instant::now() < deadline {
+            if writer.write_all(&chunk).await.is_err() {
+                break;
+            }
+            sent += chunk.len() as u64;
+        }
+        let _ = writer.shutdown().await;
+        sent
+    });
+
+    let mut buffer = vec![0; BENCH_CHUNK];
+    let mut received = 0;
+    let drain_deadline = deadline + timeouts::get().read;
+    while let Ok(Ok(n)) = timeout_at(drain_deadline, reader.read(&mut buffer)).await {
+        if n == 0 {
+            break;
+        }
+        received += n as u64;
+        if let Some(tracker) = tracker {
+            tracker.add(n as u64);
+        }
+    }
+
+    (sender.await.unwrap_or(0), received)
+}
+
+// One outbound configuration per interface named by --interfaces, or None
+// to run once as usual.
+fn compared_interfaces(
+    args: &cli::Args,
+    outbound: &OutboundConfig,
+) -> Result<Option<Vec<OutboundConfig>>, String> {
+    let Some(list) = args.value("--interfaces") else {
+        return Ok(None);
+    };
+    if outbound.interface.is_some() {
+        return Err("--interfaces and --interface can't be combined".to_string());
+    }
+    let interfaces = match list {
+        "all" => gateway::default_interfaces(),
+        list => list
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(str::to_string)
+            .collect(),
+    };
+    if interfaces.is_empty() {
+        return Err(match list {
+            "all" => "no interface has a default route".to_string(),
+            _ => "--interfaces needs at least one interface name".to_string(),
+        });
+    }
+    Ok(Some(
+        interfaces
+            .into_iter()
+            .map(|interface| OutboundConfig {
+                interface: Some(interface),
+                ..outbound.clone()
+            })
+            .collect(),
+    ))
+}
+
+// Runs `run` on every interface at once, giving the results in the order
+// the interfaces were named.
+async fn on_each<T, F, Fut>(configs: Vec<OutboundConfig>, run: F) -> Vec<(String, T)>
+where
+    T: Send + 'static,
+    F: Fn(OutboundConfig) -> Fut,
+    Fut: std::future::Future<Output = T> + Send + 'static,
+{
+    let mut tasks = tokio::task::JoinSet::new();
+    for (i, config) in configs.into_iter().enumerate() {
+        let interface = config.interface.clone().unwrap_or_default();
+        let task = run(config);
+        tasks.spawn(async move { (i, interface, task.await) });
+    }
+    let mut results: Vec<(usize, String, T)> = tasks.join_all().await;
+    results.sort_by_key(|(i, _, _)| *i);
+    results
+        .into_iter()
+        .map(|(_, interface, result)| (interface, result))
+        .collect()
+}
+
+// Interfaces across, one row per measurement.
+fn print_comparison(interfaces: &[String], rows: &[(String, Vec<String>)]) {
+    let label_width = rows.iter().map(|(label, _)| label.len()).max().unwrap_or(0);
+    let widths: Vec<usize> = interfaces
+        .iter()
+        .enumerate()
+        .map(|(i, interface)| {
+            rows.iter()
+                .map(|(_, cells)| cells[i].len())
+                .chain([interface.len()])
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    let line = |label: &str, cells: &[String]| {
+        let mut line = format!("{:<width$}", label, width = label_width);
+        for (cell, width) in cells.iter().zip(&widths) {
+            line.push_str(&format!("  {:<width$}", cell, width = width));
+        }
+        say!("{}", line.trim_end());
+    };
+    line("", interfaces);
+    for (label, cells) in rows {
+        line(label, cells);
+    }
+}
+
+// Samples tagged with their interface, in porcelain output and history.
+async fn emit_compared(history: Option<&History>, interface: &str, samples: &[Sample]) {
+    for sample in samples {
+        let Value::Object(mut fields) = sample.to_json() else {
+            continue;
+        };
+        fields.push(("interface".to_string(), Value::from(interface)));
+        output::emit(Value::Object(fields));
+    }
+    if let Some(history) = history {
+        let tagged: Vec<Sample> = samples
+            .iter()
+            .map(|sample| Sample {
+                subject: format!("{}@{}", sample.subject, interface),
+                value: sample.value.clone(),
+                ..*sample
+            })
+            .collect();
+        record(history, &tagged).await;
+    }
+}
+
+pub const PING_COMMAND: Command = Command {
+    name: "ping",
+    usage: "netcore ping <host:port>",
+    about: "Measure TCP connect round trips",
+    groups: &[PING_OPTS, outbound::OPTS, timeouts::OPTS, history::OPTS],
+};
+
+pub async fn ping_command(tokens: Vec<String>) {
+    let args = cli::parse_command(&PING_COMMAND, tokens);
+
+    let Some(target) = args.positional().first() else {
+        eprintln!("ping requires a target host:port");
+        std::process::exit(exit::USAGE);
+    };
+    let count: u32 = cli::or_exit(args.parsed("--count")).unwrap_or(4);
+    let interval = cli::or_exit(args.parsed_with("--interval", |v| units::duration(v, MILLIS)))
+        .unwrap_or(Duration::from_millis(1000));
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+    let history = History::from_args(&args);
+
+    let addr = match outbound.resolve(target).await {
+        Ok(addrs) => addrs[0],
+        Err(e) => {
+            eprintln!("Failed to resolve {}: {}", target, e);
+            std::process::exit(exit::code(&e));
+        }
+    };
+
+    say!(
+        "PING {} ({}) over {}",
+        target,
+        addr,
+        outbound::family(addr.ip())
+    );
+
+    let results = ping(
+        &outbound,
+        addr,
+        count,
+        interval,
+        |seq, result| match result {
+            Ok(rtt) => say!(
+                "Connected to {} seq={} time={:.2} ms",
+                addr,
+                seq,
+                millis(*rtt)
+            ),
+            Err(e) => say!("No connection to {} seq={}: {}", addr, seq, e),
+        },
+    )
+    .await;
+
+    let samples = ping_samples(target, &results);
+    emit(&samples);
+    if let Some(history) = &history {
+        record(history, &samples).await;
+    }
+
+    let rtts: Vec<f64> = results.iter().flatten().map(|rtt| millis(*rtt)).collect();
+    let lost = results.len() - rtts.len();
+
+    say!("--- {} ping statistics ---", target);
+    say!(
+        "{} attempts, {} connected, {:.0}% loss",
+        results.len(),
+        rtts.len(),
+        lost as f64 * 100.0 / results.len().max(1) as f64
+    );
+
+    if !rtts.is_empty() {
+        let min = rtts.iter().copied().fold(f64::INFINITY, f64::min);
+        let max = rtts.iter().copied().fold(0.0, f64::max);
+        let avg = rtts.iter().sum::<f64>() / rtts.len() as f64;
+        say!("rtt min/avg/max = {:.2}/{:.2}/{:.2} ms", min, avg, max);
+    }
+    let code = exit::from_counts(rtts.len(), results.len());
+    if code != exit::SUCCESS {
+        std::process::exit(code);
+    }
+}
+
+pub const CHECK_COMMAND: Command = Command {
+    name: "check",
+    usage: "netcore check",
+    about: "Check addresses, DNS, the gateway and the clock",
+    groups: &[COMPARE_OPTS, outbound::OPTS, timeouts::OPTS, history::OPTS],
+};
+
+pub async fn check_command(tokens: Vec<String>) {
+    let args = cli::parse_command(&CHECK_COMMAND, tokens);
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+    let history = History::from_args(&args);
+    if let Some(configs) = cli::or_exit(compared_interfaces(&args, &outbound)) {
+        return compare_check(configs, history.as_ref()).await;
+    }
+
+    let samples = check(&outbound).await;
+    for sample in &samples {
+        let status = if sample.ok { "PASS" } else { "FAIL" };
+        say!("{}  {:<16} {}", status, sample.subject, sample.value);
+    }
+
+    emit(&samples);
+    if let Some(history) = &history {
+        record(history, &samples).await;
+    }
+
+    let code = check_exit_code(&samples);
+    if code != exit::SUCCESS {
+        std::process::exit(code);
+    }
+}
+
+// Only the connection checks: addresses, DNS, the gateways and the clock
+// are the same whichever interface asks.
+async fn compare_check(configs: Vec<OutboundConfig>, history: Option<&History>) {
+    let results = on_each(configs, |config| async move { tcp_samples(&config).await }).await;
+    let interfaces: Vec<String> = results.iter().map(|(name, _)| name.clone()).collect();
+    let rows: Vec<(String, Vec<String>)> = results[0]
+        .1
+        .iter()
+        .enumerate()
+        .map(|(i, sample)| {
+            let cells = results
+                .iter()
+                .map(|(_, samples)| {
+                    let sample = &samples[i];
+                    let mark = if sample.ok { "" } else { "FAIL " };
+                    format!("{}{}", mark, sample.value)
+                })
+                .collect();
+            (sample.subject.clone(), cells)
+        })
+        .collect();
+    print_comparison(&interfaces, &rows);
+
+    for (interface, samples) in &results {
+        emit_compared(history, interface, samples).await;
+    }
+    let samples = results.iter().flat_map(|(_, samples)| samples);
+    let passed = samples.clone().filter(|sample| sample.ok).count();
+    let code = exit::from_counts(passed, samples.count());
+    if code != exit::SUCCESS {
+        std::process::exit(code);
+    }
+}
+
+// Partial when some checks fail, no connectivity when every check that needs
+// the network does.
+fn check_exit_code(samples: &[Sample]) -> i32 {
+    let passed = samples.iter().filter(|s| s.ok).count();
+    let online = samples.iter().any(|s| s.ok && s.subject != "local_address");
+    match passed {
+        _ if passed == samples.len() => exit::SUCCESS,
+        _ if !online => exit::NO_CONNECTIVITY,
+        _ => exit::PARTIAL,
+    }
+}
+
+pub const BENCH_COMMAND: Command = Command {
+    name: "bench",
+    usage: "netcore bench <host:port>",
+    about: "Measure throughput against a netcore echo server",
+    groups: &[
+        BENCH_OPTS,
+        COMPARE_OPTS,
+        outbound::OPTS,
+        timeouts::OPTS,
+        history::OPTS,
+        progress::OPTS,
+    ],
+};
+
+pub async fn bench_command(tokens: Vec<String>) {
+    let args = cli::parse_command(&BENCH_COMMAND, tokens);
+
+    let Some(target) = args.positional().first() else {
+        eprintln!("bench requires the host:port of a netcore echo server");
+        std::process::exit(exit::USAGE);
+    };
+    let duration = cli::or_exit(args.parsed_with("--duration", |v| units::duration(v, SECS)))
+        .unwrap_or(Duration::from_secs(BENCH_SECS));
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+    let history = History::from_args(&args);
+    let resume = args.flag("--resume");
+    if let Some(configs) = cli::or_exit(compared_interfaces(&args, &outbound)) {
+        return compare_bench(configs, target, duration, resume, history.as_ref()).await;
+    }
+
+    say!("Benchmarking {} for {} s", target, duration.as_secs_f64());
+    let result = match bench(
+        &outbound,
+        target,
+        duration,
+        resume,
+        progress::from_args(&args),
+    )
+    .await
+    {
+        Ok(result) => result,
+        Err(e) => {
+            eprintln!("Benchmark against {} failed: {}", target, e);
+            std::process::exit(exit::code(&e));
+        }
+    };
+
+    say!(
+        "Sent {} bytes, received {} bytes in {:.2} s: {:.2} Mbit/s",
+        result.sent,
+        result.received,
+        result.elapsed.as_secs_f64(),
+        result.mbps()
+    );
+    if result.resumed > 0 {
+        say!("Resumed after {} dropped connections", result.resumed);
+    }
+
+    let sample = result.sample(target);
+    emit(std::slice::from_ref(&sample));
+    if let Some(history) = &history {
+        record(history, &[sample]).await;
+    }
+}
+
+// Every interface at once, so they share whatever is upstream of them;
+// without a progress bar, as there would be one per interface.
+async fn compare_bench(
+    configs: Vec<OutboundConfig>,
+    target: &str,
+    duration: Duration,
+    resume: bool,
+    history: Option<&History>,
+) {
+    say!(
+        "Benchmarking {} on {} interfaces for {} s",
+        target,
+        configs.len(),
+        duration.as_secs_f64()
+    );
+    let results = on_each(configs, |config| {
+        let target = target.to_string();
+        async move { bench(&config, &target, duration, resume, None).await }
+    })
+    .await;
+
+    let interfaces: Vec<String> = results.iter().map(|(name, _)| name.clone()).collect();
+    // A failure shows once, in the first row.
+    let cell = |show: fn(&BenchResult) -> String, first: bool| {
+        results
+            .iter()
+            .map(|(_, result)| match result {
+                Ok(result) => show(result),
+                Err(e) if first => format!("FAIL {}", e),
+                Err(_) => "-".to_string(),
+            })
+            .collect()
+    };
+    let rows = [
+        (
+            "Mbit/s".to_string(),
+            cell(|r| format!("{:.2}", r.mbps()), true),
+        ),
+        (
+            "received".to_string(),
+            cell(|r| format_bytes(r.received as f64), false),
+        ),
+        (
+            "resumed".to_string(),
+            cell(|r| r.resumed.to_string(), false),
+        ),
+    ];
+    print_comparison(&interfaces, &rows);
+
+    let mut passed = 0;
+    for (interface, result) in &results {
+        let sample = match result {
+            Ok(result) => result.sample(target),
+            Err(e) => {
+                eprintln!("Benchmark on {} failed: {}", interface, e);
+                Sample::new("bench", target, history::NONE.to_string(), false)
+            }
+        };
+        passed += sample.ok as usize;
+        emit_compared(history, interface, std::slice::from_ref(&sample)).await;
+    }
+    let code = exit::from_counts(passed, results.len());
+    if code != exit::SUCCESS {
+        std::process::exit(code);
+    }
+}
@@ -0,0 +1,276 @@
+// Client certificate authentication for the reverse proxy. netcore has no
+// TLS stack of its own, so the handshake is left to a TLS terminator in
+// front (nginx, HAProxy, Envoy, a cloud load balancer) that asks for a
+// client certificate and passes it on in the Client-Cert and
+// Client-Cert-Chain headers of RFC 9440. With --client-ca, requests are
+// only forwarded when that certificate chains up to one of the CAs given.
+//
+// The headers are only taken from the terminator's addresses, given with
+// --trusted-proxy; from anywhere else they could be made up. Each link of
+// the chain is still checked here, by name, validity and signature, so a
+// terminator that passes on whatever it was shown lets nothing through.
+// Signatures are checked with ed25519.rs, which is the only signature
+// netcore has, so the CAs and the certificates they issue must be Ed25519
+// (RFC 8410); RSA and ECDSA ones are refused rather than taken on trust.
+
+use hyper::HeaderMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::acl::Cidr;
+use crate::certs::{self, CertFile};
+use crate::cli::{Args, Opt};
+use crate::context::ClientIdentity;
+use crate::ed25519;
+use crate::tls::{self, Certificate};
+
+// Longest issuer chain followed before giving up.
+const MAX_CHAIN: usize = 8;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--client-ca",
+        value: Some("<path>"),
+        help: "Require client certificates issued by an Ed25519 CA in this PEM file, as passed on in Client-Cert by a TLS terminator",
+    },
+    Opt {
+        name: "--trusted-proxy",
+        value: Some("<ip[/prefix]>"),
+        help: "Address of the TLS terminator whose Client-Cert headers are taken (repeatable)",
+    },
+];
+
+pub struct ClientCa {
+    roots: Vec<Certificate>,
+    proxies: Vec<Cidr>,
+}
+
+impl ClientCa {
+    pub fn from_args(args: &Args) -> Result<Option<ClientCa>, String> {
+        args.value("--client-ca")
+            .map(|path| ClientCa::load(path).map_err(|e| format!("--client-ca {}", e)))
+            .transpose()
+    }
+
+    pub fn load(path: &str) -> Result<ClientCa, String> {
+        let file = CertFile::load(Path::new(path))?;
+        if file.chain.is_empty() {
+            return Err(format!("{}: no certificates found", path));
+        }
+        if let Some(root) = file.chain.iter().find(|root| root.ed25519_key.is_none()) {
+            return Err(format!(
+                "{}: {} doesn't have an Ed25519 key, the only kind netcore can check signatures with",
+                path, root.subject
+            ));
+        }
+        Ok(ClientCa {
+            roots: file.chain,
+            proxies: Vec::new(),
+        })
+    }
+
+    // Where the headers may come from.
+    pub fn trust(&mut self, proxies: Vec<Cidr>) -> Result<(), String> {
+        if proxies.is_empty() {
+            return Err(
+                "--client-ca needs --trusted-proxy, the address the TLS terminator connects from"
+                    .to_string(),
+            );
+        }
+        self.proxies = proxies;
+        Ok(())
+    }
+
+    // Who the client is, if `peer` is a trusted proxy and the certificate
+    // it passed on is acceptable at `now` (Unix seconds).
+    pub fn verify(
+        &self,
+        headers: &HeaderMap,
+        peer: IpAddr,
+        now: u64,
+    ) -> Result<ClientIdentity, String> {
+        if !self.proxies.iter().any(|proxy| proxy.contains(peer)) {
+            return Err(format!("{} is not a trusted proxy", peer));
+        }
+        let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+        let leaf = header("client-cert").ok_or("no client certificate")?;
+        let leaf = sf_certificate(leaf)?;
+        let chain = match header("client-cert-chain") {
+            Some(chain) => chain
+                .split(',')
+                .map(sf_certificate)
+                .collect::<Result<Vec<_>, _>>()?,
+            None => Vec::new(),
+        };
+
+        let mut current = &leaf;
+        for _ in 0..MAX_CHAIN {
+            let valid = current.not_before.is_none_or(|t| t <= now)
+                && current.not_after.is_some_and(|t| now <= t);
+            if !valid {
+                return Err(format!(
+                    "certificate for {} is not valid now",
+                    current.subject
+                ));
+            }
+            let signature: [u8; 64] = current
+                .ed25519_signature
+                .as_deref()
+                .and_then(|signature| signature.try_into().ok())
+                .ok_or_else(|| {
+                    format!(
+                        "certificate for {} isn't signed with Ed25519",
+                        current.subject
+                    )
+                })?;
+            let signed_by = |issuer: &Certificate| {
+                issuer.subject == current.issuer
+                    && issuer
+                        .ed25519_key
+                        .is_some_and(|key| ed25519::verify(&key, &current.signed, &signature))
+            };
+            if self.roots.iter().any(signed_by) {
+                return Ok(ClientIdentity {
+                    subject: leaf.subject.clone(),
+                    issuer: leaf.issuer.clone(),
+                });
+            }
+            current = chain
+                .iter()
+                .find(|c| c.subject != c.issuer && signed_by(c))
+                .ok_or_else(|| {
+                    format!(
+                        "certificate for {} is not signed by a trusted CA",
+                        current.subject
+                    )
+                })?;
+        }
+        Err("certificate chain too long".to_string())
+    }
+}
+
+// A DER certificate as an RFC 8941 byte sequence, `:base64:`.
+fn sf_certificate(value: &str) -> Result<Certificate, String> {
+    value
+        .trim()
+        .strip_prefix(':')
+        .and_then(|value| value.strip_suffix(':'))
+        .and_then(certs::decode_base64)
+        .and_then(|der| tls::parse_certificate(&der))
+        .ok_or_else(|| "malformed client certificate".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    // Made with openssl, all valid from 2026 to 2050: an Ed25519 CA,
+    // CN=netcore test CA; CN=client.test issued by it; the same client
+    // issued by another CA of the same name; and a self-signed ECDSA
+    // CN=netcore.test.
+    const CA: &str = "MIIBSjCB/aADAgECAhRliSXN1HC8+5nSQznp+nW+EmO3PjAFBgMrZXAwGjEYMBYGA1UEAwwPbmV0Y29yZSB0ZXN0IENBMCAXDTI2MDEwMTAwMDAwMFoYDzIwNTAwMTAxMDAwMDAwWjAaMRgwFgYDVQQDDA9uZXRjb3JlIHRlc3QgQ0EwKjAFBgMrZXADIQBb+5n7cTueL4MMGpbxkn2Cf/sN0IXMRzwdjhICMJNStqNTMFEwHQYDVR0OBBYEFLT2Kbe09YGt5lrds57BoN1CPNLZMB8GA1UdIwQYMBaAFLT2Kbe09YGt5lrds57BoN1CPNLZMA8GA1UdEwEB/wQFMAMBAf8wBQYDK2VwA0EA3kRKvo+RVNNYNUM3lVXyK4qir4WIOUPq+qqm5tR2wv827+myejOZBPJA84xH1WLb67d69WqkQrmBQHMjYOkEBA==";
+    const CLIENT: &str = "MIIBNTCB6KADAgECAhRqUErfOYSHRVxC0CKjTzDYXibjpjAFBgMrZXAwGjEYMBYGA1UEAwwPbmV0Y29yZSB0ZXN0IENBMCAXDTI2MDEwMTAwMDAwMFoYDzIwNTAwMTAxMDAwMDAwWjAWMRQwEgYDVQQDDAtjbGllbnQudGVzdDAqMAUGAytlcAMhAK8amMsvIFqR4PYoiFargIrPWTVqitcmmCx9DIPwTW7Ko0IwQDAdBgNVHQ4EFgQUo4ymZkD1b8I7oDY1pXtyRpUp5KEwHwYDVR0jBBgwFoAUtPYpt7T1ga3mWt2znsGg3UI80tkwBQYDK2VwA0EA50RO1Ege/RA75VvsN6YDioZh0/9N5A3jOou+wGEvlVEArH7+5rbgOx+WLb8vc48Ph+pXS3U8yZHcWCHg03gBBg==";
+    const FORGED: &str = "MIIBNTCB6KADAgECAhRdEbrQaDC5+F27o5jSbMijhvalOzAFBgMrZXAwGjEYMBYGA1UEAwwPbmV0Y29yZSB0ZXN0IENBMCAXDTI2MDEwMTAwMDAwMFoYDzIwNTAwMTAxMDAwMDAwWjAWMRQwEgYDVQQDDAtjbGllbnQudGVzdDAqMAUGAytlcAMhAK8amMsvIFqR4PYoiFargIrPWTVqitcmmCx9DIPwTW7Ko0IwQDAdBgNVHQ4EFgQUo4ymZkD1b8I7oDY1pXtyRpUp5KEwHwYDVR0jBBgwFoAUz5CixqTx2HPHij6EOojI8fkw2/gwBQYDK2VwA0EA38rTxXmeFzd2cHfYVPFPhALXKZKpsi0lsscKd0v4CBsJeUKUIBhlmhoy8+s9PNCusWi47ahF52+07PjtzNLFBA==";
+    const ECDSA: &str = "MIIBhjCCASugAwIBAgIUMHnVyBKjJYZ6uWf/Yu6axSRO/9YwCgYIKoZIzj0EAwIwFzEVMBMGA1UEAwwMbmV0Y29yZS50ZXN0MCAXDTI2MDEwMTAwMDAwMFoYDzIwNTAwMTAxMDAwMDAwWjAXMRUwEwYDVQQDDAxuZXRjb3JlLnRlc3QwWTATBgcqhkjOPQIBBggqhkjOPQMBBwNCAATt2x3zzPc7Ji2gMvaT6Moq7UMzGUERK0z9CGbcS2zSgJHZKq+Ho53nKtrDIWbE55jdyifcmdBKzKjTdYxAiZyMo1MwUTAdBgNVHQ4EFgQUnKCvaSVdOKFKDqCTYMw9dXRoTS0wHwYDVR0jBBgwFoAUnKCvaSVdOKFKDqCTYMw9dXRoTS0wDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNJADBGAiEArgGcQI+6gywGtTVOS1tLBIyk3usLRxxi58MTMp4Y0H0CIQC/IEpCHZ8ENURnoWJYyxQMGWCbN0fPLsYvcJjl1vZKCg==";
+    const NOW: u64 = 1_800_000_000;
+
+    fn headers(cert: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        let value = HeaderValue::from_str(&format!(":{}:", cert)).unwrap();
+        headers.insert("client-cert", value);
+        headers
+    }
+
+    fn client_ca() -> ClientCa {
+        let mut ca = ClientCa {
+            roots: vec![sf_certificate(&format!(":{}:", CA)).unwrap()],
+            proxies: Vec::new(),
+        };
+        assert!(ca.trust(Vec::new()).is_err());
+        ca.trust(vec!["10.0.0.0/8".parse().unwrap()]).unwrap();
+        ca
+    }
+
+    #[test]
+    fn accepts_certificates_signed_by_the_configured_ca() {
+        let ca = client_ca();
+        let proxy: IpAddr = "10.0.0.5".parse().unwrap();
+        assert!(ca.verify(&HeaderMap::new(), proxy, NOW).is_err());
+
+        let identity = ca.verify(&headers(CLIENT), proxy, NOW).unwrap();
+        assert_eq!(identity.subject, "CN=client.test");
+        assert_eq!(identity.issuer, "CN=netcore test CA");
+        // Before and after the validity period.
+        assert!(ca.verify(&headers(CLIENT), proxy, 1_700_000_000).is_err());
+        assert!(ca.verify(&headers(CLIENT), proxy, 2_600_000_000).is_err());
+        assert!(ca.verify(&headers("bm90IGEgY2VydA=="), proxy, NOW).is_err());
+    }
+
+    #[test]
+    fn takes_headers_only_from_trusted_proxies() {
+        let ca = client_ca();
+        for peer in ["192.0.2.1", "::1", "11.0.0.1"] {
+            assert_eq!(
+                ca.verify(&headers(CLIENT), peer.parse().unwrap(), NOW)
+                    .err()
+                    .unwrap(),
+                format!("{} is not a trusted proxy", peer)
+            );
+        }
+        // As an IPv4-mapped IPv6 address.
+        assert!(
+            ca.verify(&headers(CLIENT), "::ffff:10.1.2.3".parse().unwrap(), NOW)
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn refuses_certificates_the_ca_did_not_sign() {
+        let ca = client_ca();
+        let proxy: IpAddr = "10.0.0.5".parse().unwrap();
+        // Right names, wrong CA key.
+        assert_eq!(
+            ca.verify(&headers(FORGED), proxy, NOW).err().unwrap(),
+            "certificate for CN=client.test is not signed by a trusted CA"
+        );
+        // One flipped bit in the signed part.
+        let mut der = certs::decode_base64(CLIENT).unwrap();
+        let at = der.len() - 120;
+        der[at] ^= 1;
+        let altered = certs::encode_base64(&der);
+        assert!(ca.verify(&headers(&altered), proxy, NOW).is_err());
+        // Signed with something netcore can't check.
+        assert_eq!(
+            ca.verify(&headers(ECDSA), proxy, NOW).err().unwrap(),
+            "certificate for CN=netcore.test isn't signed with Ed25519"
+        );
+    }
+
+    #[test]
+    fn loads_only_ed25519_cas() {
+        let dir = std::env::temp_dir().join(format!("netcore-mtls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let pem = |name: &str, cert: &str| {
+            let path = dir.join(name);
+            let body: Vec<&str> = cert
+                .as_bytes()
+                .chunks(64)
+                .map(|line| std::str::from_utf8(line).unwrap())
+                .collect();
+            std::fs::write(
+                &path,
+                format!(
+                    "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+                    body.join("\n")
+                ),
+            )
+            .unwrap();
+            path.to_str().unwrap().to_string()
+        };
+        assert!(ClientCa::load(&pem("ca.pem", CA)).is_ok());
+        let error = ClientCa::load(&pem("ecdsa.pem", ECDSA)).err().unwrap();
+        assert!(error.ends_with("CN=netcore.test doesn't have an Ed25519 key, the only kind netcore can check signatures with"), "{}", error);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,263 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant, interval, timeout_at};
+
+use crate::cli::{self, Args, Command, Opt};
+use crate::exit;
+use crate::ipv6;
+use crate::json::Value;
+use crate::output;
+use crate::say;
+use crate::units::{self, MILLIS, SECS};
+
+const DEFAULT_TTL: u32 = 1;
+const SECS_PER_YEAR: u64 = 365 * 86_400;
+
+const OPTS: &[Opt] = &[
+    Opt {
+        name: "--group",
+        value: Some("<addr>"),
+        help: "Multicast group, e.g. 239.1.2.3 or ff15::1234",
+    },
+    Opt {
+        name: "--port",
+        value: Some("<port>"),
+        help: "UDP port",
+    },
+    Opt {
+        name: "--interface",
+        value: Some("<name|addr>"),
+        help: "Interface name (IPv6) or local address (IPv4) to use",
+    },
+    Opt {
+        name: "--ttl",
+        value: Some("<n>"),
+        help: "Multicast TTL / hop limit for send (default: 1)",
+    },
+    Opt {
+        name: "--count",
+        value: Some("<n>"),
+        help: "Datagrams to send, or stop after receiving this many",
+    },
+    Opt {
+        name: "--interval",
+        value: Some("<duration>"),
+        help: "Delay between datagrams (default: 1s)",
+    },
+    Opt {
+        name: "--duration",
+        value: Some("<duration>"),
+        help: "Stop receiving after this long",
+    },
+];
+
+struct Group {
+    addr: IpAddr,
+    port: u16,
+    interface: Option<String>,
+}
+
+impl Group {
+    fn from_args(args: &Args) -> Result<Group, String> {
+        let addr: IpAddr = args
+            .value("--group")
+            .ok_or("multicast requires --group")?
+            .parse()
+            .map_err(|_| "invalid --group address".to_string())?;
+        if !addr.is_multicast() {
+            return Err(format!("{} is not a multicast address", addr));
+        }
+        Ok(Group {
+            addr,
+            port: args.parsed("--port")?.ok_or("multicast requires --port")?,
+            interface: args.value("--interface").map(str::to_string),
+        })
+    }
+
+    fn ipv4_interface(&self) -> Result<Ipv4Addr, String> {
+        match &self.interface {
+            None => Ok(Ipv4Addr::UNSPECIFIED),
+            Some(value) => value.parse().or_else(|_| {
+                local_ip_address::list_afinet_netifas()
+                    .unwrap_or_default()
+                    .into_iter()
+                    .find_map(|(name, ip)| match ip {
+                        IpAddr::V4(ip) if name == *value => Some(ip),
+                        _ => None,
+                    })
+                    .ok_or_else(|| format!("no IPv4 address on interface '{}'", value))
+            }),
+        }
+    }
+
+    fn ipv6_interface(&self) -> Result<u32, String> {
+        match &self.interface {
+            None => Ok(0),
+            Some(name) => match ipv6::interface_index(name) {
+                0 => Err(format!("unknown interface '{}'", name)),
+                index => Ok(index),
+            },
+        }
+    }
+}
+
+fn socket(group: &Group) -> io::Result<Socket> {
+    let domain = if group.addr.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    if group.addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    Ok(socket)
+}
+
+fn into_tokio(socket: Socket) -> io::Result<UdpSocket> {
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+async fn send(group: Group, args: &Args) -> Result<(), exit::Error> {
+    let ttl: u32 = args.parsed("--ttl")?.unwrap_or(DEFAULT_TTL);
+    let count: Option<u64> = args.parsed("--count")?;
+    let period = args
+        .parsed_with("--interval", |v| units::duration(v, MILLIS))?
+        .unwrap_or(Duration::from_millis(1000));
+
+    let socket = socket(&group)?;
+    match group.addr {
+        IpAddr::V4(_) => {
+            socket.set_multicast_ttl_v4(ttl)?;
+            socket.set_multicast_if_v4(&group.ipv4_interface()?)?;
+        }
+        IpAddr::V6(_) => {
+            socket.set_multicast_hops_v6(ttl)?;
+            socket.set_multicast_if_v6(group.ipv6_interface()?)?;
+        }
+    }
+    let socket = into_tokio(socket)?;
+
+    let target = SocketAddr::new(group.addr, group.port);
+    say!("Sending to {} with TTL {}", target, ttl);
+
+    let mut ticker = interval(period);
+    let mut seq = 0u64;
+    while count.is_none_or(|count| seq < count) {
+        ticker.tick().await;
+        seq += 1;
+        let message = format!("netcore multicast seq={}", seq);
+        match socket.send_to(message.as_bytes(), target).await {
+            Ok(_) => say!("Sent seq={}", seq),
+            Err(e) => eprintln!("Failed to send seq={}: {}", seq, e),
+        }
+    }
+    Ok(())
+}
+
+async fn recv(group: Group, args: &Args) -> Result<(), exit::Error> {
+    let count: Option<u64> = args.parsed("--count")?;
+    let duration = args.parsed_with("--duration", |v| units::duration(v, SECS))?;
+
+    let socket = socket(&group)?;
+    socket
+        .bind(&SocketAddr::new(group.addr, group.port).into())
+        .or_else(|_| {
+            let any = match group.addr {
+                IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(_) => IpAddr::V6(std::net::Ipv6Addr::UNSPECIFIED),
+            };
+            socket.bind(&SocketAddr::new(any, group.port).into())
+        })
+        .map_err(|e| exit::Error::io(format!("cannot bind port {}", group.port), e))?;
+    match group.addr {
+        IpAddr::V4(addr) => socket
+            .join_multicast_v4(&addr, &group.ipv4_interface()?)
+            .map_err(|e| exit::Error::io(format!("cannot join {}", addr), e))?,
+        IpAddr::V6(addr) => socket
+            .join_multicast_v6(&addr, group.ipv6_interface()?)
+            .map_err(|e| exit::Error::io(format!("cannot join {}", addr), e))?,
+    }
+    let socket = into_tokio(socket)?;
+    say!(
+        "Joined {} on port {}, waiting for datagrams",
+        group.addr,
+        group.port
+    );
+
+    // Without --duration, wait until interrupted.
+    let deadline = Instant::now() + duration.unwrap_or(Duration::from_secs(SECS_PER_YEAR));
+    let mut sources: HashMap<SocketAddr, u64> = HashMap::new();
+    let mut received = 0u64;
+    let mut buf = [0; 2048];
+
+    loop {
+        tokio::select! {
+            result = timeout_at(deadline, socket.recv_from(&mut buf)) => match result {
+                Ok(Ok((n, from))) => {
+                    received += 1;
+                    *sources.entry(from).or_default() += 1;
+                    say!(
+                        "{} bytes from {}: {}",
+                        n,
+                        from,
+                        String::from_utf8_lossy(&buf[..n]).trim_end()
+                    );
+                    if count.is_some_and(|count| received >= count) {
+                        break;
+                    }
+                }
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_) => break,
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    say!();
+    say!(
+        "Received {} datagram(s) from {} source(s)",
+        received,
+        sources.len()
+    );
+    let mut sources: Vec<_> = sources.into_iter().collect();
+    sources.sort();
+    for (source, n) in sources {
+        say!("  {:<40} {}", source.to_string(), n);
+        output::emit(Value::object([
+            ("source", Value::from(source.to_string())),
+            ("datagrams", Value::from(n)),
+        ]));
+    }
+    if received == 0 {
+        std::process::exit(exit::FAILURE);
+    }
+    Ok(())
+}
+
+pub const COMMAND: Command = Command {
+    name: "multicast",
+    usage: "netcore multicast <send|recv>",
+    about: "Send or receive multicast datagrams",
+    groups: &[OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let group = cli::or_exit(Group::from_args(&args));
+
+    let result = match args.positional().first().map(String::as_str) {
+        Some("send") => send(group, &args).await,
+        Some("recv") => recv(group, &args).await,
+        _ => {
+            eprintln!("usage: netcore multicast <send|recv> --group <addr> --port <port>");
+            std::process::exit(exit::USAGE);
+        }
+    };
+    exit::or_exit(result);
+}
@@ -0,0 +1,768 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant, timeout_at};
+
+use crate::bandwidth::Limits;
+use crate::cli::{Args, Opt};
+use crate::config::Config;
+use crate::context::{ConnContext, TlsInfo};
+use crate::fingerprint;
+use crate::outbound::{self, OutboundConfig};
+use crate::pool::{self, Pool};
+use crate::say;
+use crate::stats::{self, Tracker};
+use crate::tcpinfo;
+use crate::transport::Transport;
+use crate::units::{self, MILLIS};
+
+pub const SNIFF_TIMEOUT_MS: u64 = 300;
+const MAX_SNIFF_BYTES: usize = 16 * 1024 + 5;
+
+const SSH_SIGNATURES: &[&[u8]] = &[b"SSH-"];
+const HTTP_SIGNATURES: &[&[u8]] = &[
+    b"GET ",
+    b"HEAD ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+    b"PRI * HTTP/2.0",
+];
+
+// Keys of the [mux] section.
+const SECTION_KEYS: &[&str] = &["ssh", "tls", "alpn", "http", "default", "sniff_timeout"];
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--mux-ssh",
+        value: Some("<target>"),
+        help: "Route SSH connections to 'echo' or host:port",
+    },
+    Opt {
+        name: "--mux-tls",
+        value: Some("<target>"),
+        help: "Route TLS connections to 'echo' or host:port",
+    },
+    Opt {
+        name: "--mux-alpn",
+        value: Some("<proto=target>"),
+        help: "Route TLS connections offering an ALPN protocol (repeatable)",
+    },
+    Opt {
+        name: "--mux-http",
+        value: Some("<target>"),
+        help: "Route HTTP connections to 'echo' or host:port",
+    },
+    Opt {
+        name: "--mux-default",
+        value: Some("<target>"),
+        help: "Route unrecognised connections (default: echo)",
+    },
+    Opt {
+        name: "--sniff-timeout",
+        value: Some("<duration>"),
+        help: "How long to wait for the first bytes (default: 300ms)",
+    },
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Protocol {
+    Ssh,
+    Tls,
+    Http,
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Protocol::Ssh => write!(f, "SSH"),
+            Protocol::Tls => write!(f, "TLS"),
+            Protocol::Http => write!(f, "HTTP"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Route {
+    Echo,
+    Backend(String),
+}
+
+impl Route {
+    fn parse(value: &str) -> Result<Route, String> {
+        match value {
+            "echo" => Ok(Route::Echo),
+            target => match target.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Ok(Route::Backend(target.to_string()))
+                }
+                _ => Err(format!("expected 'echo' or host:port, not '{}'", target)),
+            },
+        }
+    }
+}
+
+fn alpn_route(value: &str) -> Result<(String, Route), String> {
+    match value.split_once('=') {
+        Some((proto, target)) if !proto.is_empty() => {
+            Ok((proto.to_string(), Route::parse(target)?))
+        }
+        _ => Err(format!(
+            "invalid ALPN route '{}', expected proto=target",
+            value
+        )),
+    }
+}
+
+impl fmt::Display for Route {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Route::Echo => write!(f, "echo"),
+            Route::Backend(target) => write!(f, "{}", target),
+        }
+    }
+}
+
+pub struct MuxConfig {
+    pub ssh: Option<Route>,
+    pub tls: Option<Route>,
+    pub alpn: Vec<(String, Route)>,
+    pub http: Option<Route>,
+    pub fallback: Route,
+    pub sniff_timeout: Duration,
+    pub outbound: OutboundConfig,
+    // Ready connections for each backend, with --pool-idle.
+    pub pools: HashMap<String, Arc<Pool>>,
+}
+
+impl MuxConfig {
+    // Routes come from a [mux] section with the option names less --mux-
+    // (ssh, tls, alpn, http, default and sniff_timeout), and from the
+    // options, which win; ALPN routes from both are tried, the options'
+    // first.
+    pub fn from_args(
+        args: &Args,
+        config: Option<&Config>,
+        outbound: OutboundConfig,
+    ) -> Result<Option<MuxConfig>, String> {
+        let section = match config {
+            Some(config) => config.section("mux", SECTION_KEYS)?.map(|s| (config, s)),
+            None => None,
+        };
+        let route = |opt: &str, key: &str| -> Result<Option<Route>, String> {
+            if let Some(value) = args.value(opt) {
+                return Route::parse(value)
+                    .map(Some)
+                    .map_err(|e| format!("{}: {}", opt, e));
+            }
+            match section {
+                Some((config, section)) => section.parsed_with(config, key, Route::parse),
+                None => Ok(None),
+            }
+        };
+
+        let mut alpn = args
+            .values("--mux-alpn")
+            .map(alpn_route)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut sniff_timeout =
+            args.parsed_with("--sniff-timeout", |v| units::duration(v, MILLIS))?;
+        if let Some((config, section)) = section {
+            alpn.extend(section.parsed_all_with(config, "alpn", alpn_route)?);
+            if sniff_timeout.is_none() {
+                sniff_timeout =
+                    section.parsed_with(config, "sniff_timeout", |v| units::duration(v, MILLIS))?;
+            }
+        }
+
+        let mut config = MuxConfig {
+            ssh: route("--mux-ssh", "ssh")?,
+            tls: route("--mux-tls", "tls")?,
+            alpn,
+            http: route("--mux-http", "http")?,
+            fallback: route("--mux-default", "default")?.unwrap_or(Route::Echo),
+            sniff_timeout: sniff_timeout.unwrap_or(Duration::from_millis(SNIFF_TIMEOUT_MS)),
+            outbound,
+            pools: HashMap::new(),
+        };
+
+        let mut backends: Vec<&str> = [&config.ssh, &config.tls, &config.http]
+            .into_iter()
+            .flatten()
+            .chain(config.alpn.iter().map(|(_, route)| route))
+            .chain([&config.fallback])
+            .filter_map(|route| match route {
+                Route::Backend(target) => Some(target.as_str()),
+                Route::Echo => None,
+            })
+            .collect();
+        backends.sort_unstable();
+        backends.dedup();
+        let enabled = config.ssh.is_some()
+            || config.tls.is_some()
+            || !config.alpn.is_empty()
+            || config.http.is_some()
+            || config.fallback != Route::Echo;
+
+        if let Some(settings) = pool::Settings::from_args(args)? {
+            if backends.is_empty() {
+                return Err("--pool-idle needs a --mux route to a host:port backend".to_string());
+            }
+            let pools = backends
+                .iter()
+                .map(|target| {
+                    let pool = Pool::start(target, config.outbound.clone(), settings);
+                    (target.to_string(), pool)
+                })
+                .collect();
+            config.pools = pools;
+        }
+
+        Ok(enabled.then_some(config))
+    }
+
+    fn route_for(&self, protocol: Option<Protocol>, tls: Option<&TlsInfo>) -> &Route {
+        let route = match protocol {
+            Some(Protocol::Ssh) => self.ssh.as_ref(),
+            Some(Protocol::Tls) => {
+                let offered = tls.map_or(&[][..], |tls| &tls.alpn);
+                self.alpn
+                    .iter()
+                    .find(|(proto, _)| offered.iter().any(|offer| offer == proto))
+                    .map(|(_, route)| route)
+                    .or(self.tls.as_ref())
+            }
+            Some(Protocol::Http) => self.http.as_ref(),
+            None => None,
+        };
+
+        route.unwrap_or(&self.fallback)
+    }
+}
+
+enum Sniff {
+    Match(Protocol),
+    NeedMore,
+    Unknown,
+}
+
+fn sniff(buf: &[u8]) -> Sniff {
+    if buf.is_empty() {
+        return Sniff::NeedMore;
+    }
+
+    if buf[0] == 0x16 {
+        return match buf.get(1) {
+            None => Sniff::NeedMore,
+            Some(0x03) => Sniff::Match(Protocol::Tls),
+            Some(_) => Sniff::Unknown,
+        };
+    }
+
+    let mut need_more = false;
+
+    for (protocol, signatures) in [
+        (Protocol::Ssh, SSH_SIGNATURES),
+        (Protocol::Http, HTTP_SIGNATURES),
+    ] {
+        for signature in signatures {
+            if buf.starts_with(signature) {
+                return Sniff::Match(protocol);
+            }
+            if signature.starts_with(buf) {
+                need_more = true;
+            }
+        }
+    }
+
+    if need_more {
+        Sniff::NeedMore
+    } else {
+        Sniff::Unknown
+    }
+}
+
+fn tls_record_complete(buf: &[u8]) -> bool {
+    match buf.get(3..5) {
+        Some(len) => buf.len() >= 5 + u16::from_be_bytes([len[0], len[1]]) as usize,
+        None => false,
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Some(head)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        self.take(3)
+            .map(|b| (b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+
+    fn vec8(&mut self) -> Option<Reader<'a>> {
+        let len = self.u8()? as usize;
+        self.take(len).map(|buf| Reader { buf })
+    }
+
+    fn vec16(&mut self) -> Option<Reader<'a>> {
+        let len = self.u16()? as usize;
+        self.take(len).map(|buf| Reader { buf })
+    }
+}
+
+// The parts of a ClientHello that routing and fingerprints look at, read
+// from the first TLS record.
+struct ClientHello<'a> {
+    version: u16,
+    suites: Reader<'a>,
+    // In the order offered, each with its data.
+    extensions: Vec<(u16, Reader<'a>)>,
+}
+
+fn client_hello(record: &[u8]) -> Option<ClientHello<'_>> {
+    let mut record = Reader { buf: record };
+    if record.u8()? != 0x16 {
+        return None;
+    }
+    record.take(2)?;
+    let mut hello = record.vec16()?;
+
+    if hello.u8()? != 0x01 {
+        return None;
+    }
+    let len = hello.u24()?;
+    let mut hello = Reader {
+        buf: hello.take(len)?,
+    };
+
+    let version = hello.u16()?;
+    hello.take(32)?;
+    hello.vec8()?;
+    let suites = hello.vec16()?;
+    hello.vec8()?;
+
+    // Hellos from before TLS 1.2 may end without any.
+    let mut block = hello.vec16().unwrap_or(Reader { buf: &[] });
+    let mut extensions = Vec::new();
+    while !block.buf.is_empty() {
+        extensions.push((block.u16()?, block.vec16()?));
+    }
+
+    Some(ClientHello {
+        version,
+        suites,
+        extensions,
+    })
+}
+
+impl<'a> ClientHello<'a> {
+    fn extension(&self, kind: u16) -> Option<Reader<'a>> {
+        self.extensions
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map(|(_, data)| *data)
+    }
+}
+
+pub fn client_hello_sni(record: &[u8]) -> Option<String> {
+    let mut list = client_hello(record)?.extension(0x0000)?.vec16()?;
+    while !list.buf.is_empty() {
+        let name_type = list.u8()?;
+        let name = list.vec16()?;
+        if name_type == 0 {
+            return Some(String::from_utf8_lossy(name.buf).into_owned());
+        }
+    }
+
+    None
+}
+
+pub fn client_hello_alpn(record: &[u8]) -> Option<Vec<String>> {
+    let Some(mut data) = client_hello(record)?.extension(0x0010) else {
+        return Some(Vec::new());
+    };
+
+    let mut list = data.vec16()?;
+    let mut protocols = Vec::new();
+    while !list.buf.is_empty() {
+        let name = list.vec8()?;
+        protocols.push(String::from_utf8_lossy(name.buf).into_owned());
+    }
+    Some(protocols)
+}
+
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+fn join_u16(values: impl Iterator<Item = u16>) -> String {
+    values
+        .filter(|value| !is_grease(*value))
+        .map(|value| value.to_string())
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+// JA3 string: version,ciphers,extensions,groups,point formats.
+pub fn client_hello_ja3(record: &[u8]) -> Option<String> {
+    let mut hello = client_hello(record)?;
+    let ciphers = join_u16(std::iter::from_fn(|| hello.suites.u16()));
+
+    let mut groups = String::new();
+    let mut formats = String::new();
+    for (kind, mut data) in hello.extensions.iter().copied() {
+        match kind {
+            0x000a => {
+                let mut list = data.vec16()?;
+                groups = join_u16(std::iter::from_fn(|| list.u16()));
+            }
+            0x000b => {
+                let list = data.vec8()?;
+                formats = join_u16(list.buf.iter().map(|format| *format as u16));
+            }
+            _ => {}
+        }
+    }
+
+    Some(format!(
+        "{},{},{},{},{}",
+        hello.version,
+        ciphers,
+        join_u16(hello.extensions.iter().map(|(kind, _)| *kind)),
+        groups,
+        formats
+    ))
+}
+
+async fn read_prefix<S: Transport>(
+    socket: &mut S,
+    config: &MuxConfig,
+) -> (Vec<u8>, Option<Protocol>) {
+    let deadline = Instant::now() + config.sniff_timeout;
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 1024];
+
+    loop {
+        let protocol = match sniff(&buffer) {
+            Sniff::Match(Protocol::Tls)
+                if (!config.alpn.is_empty() || fingerprint::enabled())
+                    && !tls_record_complete(&buffer)
+                    && buffer.len() < MAX_SNIFF_BYTES =>
+            {
+                Some(Protocol::Tls)
+            }
+            Sniff::Match(protocol) => return (buffer, Some(protocol)),
+            Sniff::Unknown => return (buffer, None),
+            Sniff::NeedMore => None,
+        };
+
+        match timeout_at(deadline, socket.read(&mut chunk)).await {
+            Ok(Ok(n)) if n > 0 => buffer.extend_from_slice(&chunk[..n]),
+            _ => return (buffer, protocol),
+        }
+    }
+}
+
+pub async fn route_client(
+    mut socket: TcpStream,
+    mut ctx: ConnContext,
+    config: Arc<MuxConfig>,
+    limits: Limits,
+) {
+    let addr = ctx.peer;
+    let (prefix, protocol) = read_prefix(&mut socket, &config).await;
+    if let Some(protocol) = protocol {
+        ctx.label("netcore.mux.protocol", protocol);
+    }
+    if protocol == Some(Protocol::Tls) {
+        ctx.tls = TlsInfo::from_client_hello(&prefix);
+    }
+    let route = config.route_for(protocol, ctx.tls.as_ref());
+
+    match protocol {
+        Some(protocol) => say!("Sniffed {} from {}, routing to {}", protocol, addr, route),
+        None => say!("Unrecognised protocol from {}, routing to {}", addr, route),
+    }
+
+    match route {
+        Route::Echo => crate::server::echo_client(socket, ctx, limits, true, &prefix).await,
+        Route::Backend(target) => {
+            let pool = config.pools.get(target).map(Arc::as_ref);
+            forward(socket, ctx, &prefix, target, &config.outbound, pool, limits).await
+        }
+    }
+}
+
+pub async fn forward(
+    mut socket: TcpStream,
+    ctx: ConnContext,
+    prefix: &[u8],
+    target: &str,
+    outbound: &OutboundConfig,
+    pool: Option<&Pool>,
+    limits: Limits,
+) {
+    let addr = ctx.peer;
+    let mut tracker = stats::track("forward", addr);
+    tracker.attr("netcore.target", target);
+    ctx.record(&mut tracker);
+    fingerprint::record(&mut tracker, &socket, ctx.tls.as_ref());
+
+    let pooled = pool.and_then(Pool::take);
+    let reused = pooled.is_some();
+    if pool.is_some() {
+        tracker.attr("netcore.pooled", reused);
+    }
+    let upstream = match pooled {
+        Some(upstream) => upstream,
+        None => match outbound.connect(target).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                eprintln!("Failed to connect to {} for {}: {}", target, addr, e);
+                tracker.error(e);
+                return;
+            }
+        },
+    };
+
+    if let (Ok(local), Ok(peer)) = (upstream.local_addr(), upstream.peer_addr()) {
+        say!(
+            "Forwarding {} to {} ({}) from {} over {}{}",
+            addr,
+            target,
+            peer,
+            local,
+            outbound::family(peer.ip()),
+            if reused { ", pooled" } else { "" }
+        );
+    }
+
+    pipe(
+        &mut socket,
+        upstream,
+        addr,
+        prefix,
+        target,
+        &mut tracker,
+        &limits,
+    )
+    .await;
+    tcpinfo::record(&mut tracker, &socket);
+}
+
+// Replays the sniffed prefix upstream, then copies both ways until either
+// side closes.
+async fn pipe<C: Transport, U: Transport>(
+    socket: C,
+    mut upstream: U,
+    addr: SocketAddr,
+    prefix: &[u8],
+    target: &str,
+    tracker: &mut Tracker,
+    limits: &Limits,
+) {
+    if let Err(e) = upstream.write_all(prefix).await {
+        eprintln!("Failed to write to {}: {}", target, e);
+        tracker.error(e);
+        return;
+    }
+    tracker.received(prefix.len() as u64);
+
+    let mut socket = limits.wrap(tracker.wrap(socket));
+    let result = tokio::select! {
+        result = tokio::io::copy_bidirectional(&mut socket, &mut upstream) => result,
+        _ = tracker.killed() => {
+            say!("Connection from {} to {} closed by control request", addr, target);
+            return;
+        }
+    };
+
+    match result {
+        Ok((sent, received)) => {
+            let sent = sent + prefix.len() as u64;
+            say!(
+                "Connection from {} to {} closed ({} bytes sent, {} bytes received)",
+                addr,
+                target,
+                sent,
+                received
+            );
+        }
+        Err(e) => {
+            eprintln!("Error forwarding {} to {}: {}", addr, target, e);
+            tracker.error(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Args;
+    use crate::tls;
+    use crate::transport::MemoryTransport;
+
+    fn config(tokens: &[&str]) -> MuxConfig {
+        let args = Args::parse(tokens.iter().map(|t| t.to_string()), &[OPTS]).unwrap();
+        MuxConfig::from_args(&args, None, OutboundConfig::default())
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn sniffed(config: &MuxConfig, data: &[u8], max_read: usize) -> (Vec<u8>, Route) {
+        let (mut client, server) = MemoryTransport::pair(64 * 1024);
+        let mut server = server.with_max_read(max_read);
+        client.write_all(data).await.unwrap();
+        let (prefix, protocol) = read_prefix(&mut server, config).await;
+        let tls = TlsInfo::from_client_hello(&prefix);
+        let route = config.route_for(protocol, tls.as_ref()).clone();
+        (prefix, route)
+    }
+
+    #[tokio::test]
+    async fn http_split_across_reads_is_sniffed() {
+        let config = config(&["--mux-http", "127.0.0.1:8080"]);
+        let request = b"GET / HTTP/1.1\r\nHost: example\r\n\r\n";
+        let (prefix, route) = sniffed(&config, request, 1).await;
+        assert!(request.starts_with(&prefix));
+        assert_eq!(route, Route::Backend("127.0.0.1:8080".to_string()));
+    }
+
+    #[tokio::test]
+    async fn tls_routes_on_alpn_once_the_hello_is_complete() {
+        let config = config(&["--mux-tls", "echo", "--mux-alpn", "h2=127.0.0.1:8443"]);
+        let hello = tls::client_hello(&tls::PROFILES[0], "example.com");
+        let (prefix, route) = sniffed(&config, &hello, 100).await;
+        assert_eq!(prefix, hello);
+        assert_eq!(route, Route::Backend("127.0.0.1:8443".to_string()));
+    }
+
+    #[test]
+    fn tls_info_carries_sni_and_alpn() {
+        let hello = tls::client_hello(&tls::PROFILES[0], "example.com");
+        let info = TlsInfo::from_client_hello(&hello).unwrap();
+        assert_eq!(info.sni.as_deref(), Some("example.com"));
+        assert!(info.alpn.iter().any(|proto| proto == "h2"));
+        assert!(info.ja3.is_some());
+    }
+
+    #[tokio::test]
+    async fn unknown_protocol_takes_the_default_route() {
+        let config = config(&["--mux-default", "127.0.0.1:9000"]);
+        let (_, route) = sniffed(&config, b"\x00\x01binary", 1024).await;
+        assert_eq!(route, Route::Backend("127.0.0.1:9000".to_string()));
+    }
+
+    #[tokio::test]
+    async fn pipe_replays_prefix_and_copies_both_ways() {
+        let addr = "192.0.2.1:40000".parse().unwrap();
+        let (client, proxy_side) = MemoryTransport::pair(1024);
+        let (upstream_side, backend) = MemoryTransport::pair(1024);
+        let proxy = tokio::spawn(async move {
+            let mut tracker = stats::track("forward", addr);
+            let limits = Limits::default();
+            pipe(
+                proxy_side,
+                upstream_side,
+                addr,
+                b"GET ",
+                "backend",
+                &mut tracker,
+                &limits,
+            )
+            .await;
+        });
+
+        let (mut client_reader, mut client_writer) = tokio::io::split(client);
+        let (mut backend_reader, mut backend_writer) = tokio::io::split(backend);
+        client_writer
+            .write_all(b"/ HTTP/1.0\r\n\r\n")
+            .await
+            .unwrap();
+        let mut request = [0; 18];
+        backend_reader.read_exact(&mut request).await.unwrap();
+        assert_eq!(&request, b"GET / HTTP/1.0\r\n\r\n");
+
+        backend_writer
+            .write_all(b"HTTP/1.0 204 No Content\r\n\r\n")
+            .await
+            .unwrap();
+        backend_writer.shutdown().await.unwrap();
+        let mut reply = Vec::new();
+        client_reader.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"HTTP/1.0 204 No Content\r\n\r\n");
+
+        client_writer.shutdown().await.unwrap();
+        proxy.await.unwrap();
+    }
+
+    #[test]
+    fn config_section_routes_under_the_options() {
+        let parse = |text: &str| Config::parse(std::path::Path::new("netcore.conf"), text).unwrap();
+        let args =
+            |tokens: &[&str]| Args::parse(tokens.iter().map(|t| t.to_string()), &[OPTS]).unwrap();
+        let file = parse(
+            "[mux]\nssh = 127.0.0.1:22\nhttp = 127.0.0.1:80\nalpn = h2=127.0.0.1:8443\nsniff_timeout = 50\n",
+        );
+        let mux = MuxConfig::from_args(
+            &args(&[
+                "--mux-http",
+                "echo",
+                "--mux-alpn",
+                "acme-tls/1=127.0.0.1:9443",
+            ]),
+            Some(&file),
+            OutboundConfig::default(),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(mux.ssh, Some(Route::Backend("127.0.0.1:22".to_string())));
+        assert_eq!(mux.http, Some(Route::Echo));
+        let protocols: Vec<&str> = mux.alpn.iter().map(|(proto, _)| proto.as_str()).collect();
+        assert_eq!(protocols, ["acme-tls/1", "h2"]);
+        assert_eq!(mux.sniff_timeout, Duration::from_millis(50));
+
+        let error = |text: &str| {
+            MuxConfig::from_args(&args(&[]), Some(&parse(text)), OutboundConfig::default())
+                .err()
+                .unwrap()
+        };
+        assert_eq!(
+            error("[mux]\nssh = 127.0.0.1:22\ntimeout = 5\n"),
+            "netcore.conf:3: unknown key 'timeout' in [mux], expected ssh, tls, alpn, http, default, sniff_timeout"
+        );
+        assert_eq!(
+            error("[mux]\n\ntls = backend\n"),
+            "netcore.conf:3: invalid value 'backend' for 'tls': expected 'echo' or host:port, not 'backend'"
+        );
+        assert_eq!(
+            error("[mux]\n[mux]\n"),
+            "netcore.conf:2: [mux] was already given on line 1"
+        );
+    }
+}
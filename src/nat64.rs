@@ -0,0 +1,214 @@
+// IPv6-only networks that still reach IPv4, as mobile carriers run them.
+// DNS64 answers AAAA queries for IPv4-only names with addresses inside a
+// NAT64 prefix, and the NAT64 gateway translates connections to those
+// back to IPv4. That leaves IPv4 literals, which no DNS sees: a CLAT on
+// the host (464XLAT) gives them an IPv4 route anyway, and without one
+// outbound connections put them in the NAT64 prefix here.
+//
+// The prefix is found as RFC 7050 does, from the AAAA answer for
+// ipv4only.arpa, a name that only has the A records 192.0.0.170 and
+// 192.0.0.171.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use tokio::net::lookup_host;
+use tokio::sync::OnceCell;
+use tokio::time::timeout;
+
+use crate::cli::{self, Command};
+use crate::exit;
+use crate::json::Value;
+use crate::output;
+use crate::say;
+use crate::timeouts;
+
+const DISCOVERY_NAME: &str = "ipv4only.arpa";
+const WELL_KNOWN: [Ipv4Addr; 2] = [Ipv4Addr::new(192, 0, 0, 170), Ipv4Addr::new(192, 0, 0, 171)];
+
+// Where RFC 6052 puts the IPv4 address for each prefix length. Byte 8 is
+// always left zero.
+const LAYOUTS: [(u8, [usize; 4]); 6] = [
+    (96, [12, 13, 14, 15]),
+    (64, [9, 10, 11, 12]),
+    (56, [7, 9, 10, 11]),
+    (48, [6, 7, 9, 10]),
+    (40, [5, 6, 7, 9]),
+    (32, [4, 5, 6, 7]),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+// Only made from a synthesized address, so the length is always one
+// RFC 6052 allows.
+pub struct Prefix {
+    addr: Ipv6Addr,
+    len: u8,
+}
+
+impl Prefix {
+    // The prefix a synthesized AAAA for ipv4only.arpa was made with.
+    pub fn from_synthesized(ip: Ipv6Addr) -> Option<Prefix> {
+        let octets = ip.octets();
+        LAYOUTS.iter().find_map(|(len, positions)| {
+            let embedded = Ipv4Addr::from(positions.map(|i| octets[i]));
+            if !WELL_KNOWN.contains(&embedded) {
+                return None;
+            }
+            let mut prefix = [0; 16];
+            let end = *len as usize / 8;
+            prefix[..end].copy_from_slice(&octets[..end]);
+            Some(Prefix {
+                addr: prefix.into(),
+                len: *len,
+            })
+        })
+    }
+
+    pub fn synthesize(&self, ip: Ipv4Addr) -> Ipv6Addr {
+        let mut octets = self.addr.octets();
+        let (_, positions) = LAYOUTS.iter().find(|(len, _)| *len == self.len).unwrap();
+        for (position, byte) in positions.iter().zip(ip.octets()) {
+            octets[*position] = byte;
+        }
+        octets.into()
+    }
+}
+
+impl std::fmt::Display for Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.len)
+    }
+}
+
+// Asks the system resolver, since DNS64 lives in whatever resolver the
+// network hands out. None if nothing synthesizes AAAA records.
+pub async fn discover() -> Option<Prefix> {
+    let lookup = lookup_host((DISCOVERY_NAME, 0));
+    let addrs = timeout(timeouts::get().dns, lookup).await.ok()?.ok()?;
+    addrs.into_iter().find_map(|addr| match addr.ip() {
+        IpAddr::V6(ip) => Prefix::from_synthesized(ip),
+        IpAddr::V4(_) => None,
+    })
+}
+
+static PREFIX: OnceCell<Option<Prefix>> = OnceCell::const_new();
+
+// discover(), looked up once per run.
+pub async fn prefix() -> Option<Prefix> {
+    *PREFIX.get_or_init(discover).await
+}
+
+// The source address the system would use for IPv4, found by connecting
+// a UDP socket, which sends nothing. None if there's no IPv4 route.
+pub fn ipv4_route() -> Option<Ipv4Addr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9)).ok()?;
+    match socket.local_addr().ok()? {
+        SocketAddr::V4(addr) => Some(*addr.ip()),
+        SocketAddr::V6(_) => None,
+    }
+}
+
+// CLATs number their side of the translation from 192.0.0.0/29 (RFC 7335).
+pub fn is_clat(ip: Ipv4Addr) -> bool {
+    ip.octets()[..3] == [192, 0, 0] && ip.octets()[3] < 8
+}
+
+pub const COMMAND: Command = Command {
+    name: "nat64",
+    usage: "netcore nat64",
+    about: "Detect NAT64, DNS64 and 464XLAT and how IPv4 literals will behave",
+    groups: &[timeouts::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    cli::or_exit(timeouts::init(&args, None));
+
+    let prefix = discover().await;
+    let route = ipv4_route();
+    let clat = route.filter(|ip| is_clat(*ip));
+
+    match prefix {
+        Some(prefix) => say!(
+            "DNS64:  {} has AAAA records, NAT64 prefix {}",
+            DISCOVERY_NAME,
+            prefix
+        ),
+        None => say!("DNS64:  none, {} has no AAAA records", DISCOVERY_NAME),
+    }
+    match (route, clat) {
+        (_, Some(ip)) => say!("CLAT:   yes, IPv4 goes out from {}", ip),
+        (Some(ip), None) => say!("IPv4:   native, from {}", ip),
+        (None, None) => say!("IPv4:   no route"),
+    }
+
+    let (status, detail, code) = match (route, clat, prefix) {
+        (Some(_), None, _) => (
+            "PASS",
+            "IPv4 literals connect natively".to_string(),
+            exit::SUCCESS,
+        ),
+        (_, Some(_), _) => (
+            "PASS",
+            "IPv4 literals are translated by the CLAT (464XLAT)".to_string(),
+            exit::SUCCESS,
+        ),
+        (None, None, Some(prefix)) => (
+            "WARN",
+            format!(
+                "no IPv4 route: IPv4 literals only work through NAT64, as {}; \
+                 netcore's own connections do that",
+                prefix.synthesize(Ipv4Addr::new(192, 0, 2, 1))
+            ),
+            exit::SUCCESS,
+        ),
+        (None, None, None) => (
+            "FAIL",
+            "no IPv4 route and no NAT64: IPv4 literals and IPv4-only hosts are unreachable"
+                .to_string(),
+            exit::NO_CONNECTIVITY,
+        ),
+    };
+    say!("{}  {}", status, detail);
+    output::emit(Value::object([
+        ("status", Value::from(status.to_ascii_lowercase())),
+        ("subject", Value::from("nat64")),
+        (
+            "prefix",
+            Value::from(prefix.map(|prefix| prefix.to_string())),
+        ),
+        ("ipv4_route", Value::from(route.map(|ip| ip.to_string()))),
+        ("clat", Value::from(clat.is_some())),
+        ("detail", Value::from(detail)),
+    ]));
+    if code != exit::SUCCESS {
+        std::process::exit(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_prefix_and_synthesizes_with_it() {
+        let well_known = Prefix::from_synthesized("64:ff9b::c000:aa".parse().unwrap()).unwrap();
+        assert_eq!(well_known.to_string(), "64:ff9b::/96");
+        assert_eq!(
+            well_known.synthesize(Ipv4Addr::new(198, 51, 100, 7)),
+            "64:ff9b::c633:6407".parse::<Ipv6Addr>().unwrap()
+        );
+
+        // A /48 skips byte 8, so the address straddles it.
+        let synthesized: Ipv6Addr = "2001:db8:122:c000:0:aa00::".parse().unwrap();
+        let operator = Prefix::from_synthesized(synthesized).unwrap();
+        assert_eq!(operator.to_string(), "2001:db8:122::/48");
+        assert_eq!(operator.synthesize(WELL_KNOWN[0]), synthesized);
+
+        assert_eq!(
+            Prefix::from_synthesized("2001:db8::1".parse().unwrap()),
+            None
+        );
+        assert!(is_clat(Ipv4Addr::new(192, 0, 0, 4)));
+        assert!(!is_clat(Ipv4Addr::new(192, 0, 0, 170)));
+    }
+}
@@ -0,0 +1,346 @@
+// Noise_IK_25519_ChaChaPoly_SHA256 (noiseprotocol.org, revision 34) for
+// the peer tunnel. The initiator knows the responder's static key
+// beforehand and sends its own, encrypted, in the first message, so the
+// handshake takes one round trip and the responder learns who is calling:
+//
+//   <- s
+//   ...
+//   -> e, es, s, ss
+//   <- e, ee, se
+//
+// Only the handshake is here; transport messages are sealed with the keys
+// it ends with, a counter as nonce, by whoever carries them.
+
+use crate::aead::{self, KEY_LEN, NONCE_LEN, TAG_LEN};
+use crate::sha2::{hmac_sha256, sha256};
+use crate::x25519;
+
+const PROTOCOL: &[u8; 32] = b"Noise_IK_25519_ChaChaPoly_SHA256";
+
+pub struct StaticKey {
+    pub secret: [u8; 32],
+    pub public: [u8; 32],
+}
+
+impl StaticKey {
+    pub fn new(secret: [u8; 32]) -> StaticKey {
+        StaticKey {
+            secret,
+            public: x25519::public_key(&secret),
+        }
+    }
+}
+
+// What a handshake ends with, for this side.
+pub struct Keys {
+    pub send: [u8; KEY_LEN],
+    pub receive: [u8; KEY_LEN],
+}
+
+// 32 zero bits, then the counter little-endian.
+pub fn nonce(counter: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[4..].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+// A low-order point gives all zeros, which would make the key public.
+fn dh(secret: &[u8; 32], public: &[u8; 32]) -> Option<[u8; 32]> {
+    let shared = x25519::x25519(secret, public);
+    (shared != [0; 32]).then_some(shared)
+}
+
+fn hkdf(ck: &[u8; 32], ikm: &[u8]) -> ([u8; 32], [u8; 32]) {
+    let temp = hmac_sha256(ck, ikm);
+    let first = hmac_sha256(&temp, &[1]);
+    let second = hmac_sha256(&temp, &[&first[..], &[2]].concat());
+    (first, second)
+}
+
+struct Symmetric {
+    ck: [u8; 32],
+    h: [u8; 32],
+    k: Option<[u8; KEY_LEN]>,
+    n: u64,
+}
+
+impl Symmetric {
+    fn new(prologue: &[u8]) -> Symmetric {
+        let mut state = Symmetric {
+            ck: *PROTOCOL,
+            h: *PROTOCOL,
+            k: None,
+            n: 0,
+        };
+        state.mix_hash(prologue);
+        state
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.h = sha256(&[&self.h[..], data].concat());
+    }
+
+    fn mix_key(&mut self, ikm: &[u8; 32]) {
+        let (ck, k) = hkdf(&self.ck, ikm);
+        self.ck = ck;
+        self.k = Some(k);
+        self.n = 0;
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let ciphertext = match &self.k {
+            Some(k) => {
+                self.n += 1;
+                aead::seal(k, &nonce(self.n - 1), &self.h, plaintext)
+            }
+            None => plaintext.to_vec(),
+        };
+        self.mix_hash(&ciphertext);
+        ciphertext
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Option<Vec<u8>> {
+        let plaintext = match &self.k {
+            Some(k) => {
+                self.n += 1;
+                aead::open(k, &nonce(self.n - 1), &self.h, ciphertext)?
+            }
+            None => ciphertext.to_vec(),
+        };
+        self.mix_hash(ciphertext);
+        Some(plaintext)
+    }
+
+    fn split(&self) -> ([u8; KEY_LEN], [u8; KEY_LEN]) {
+        hkdf(&self.ck, &[])
+    }
+}
+
+pub struct Initiator {
+    state: Symmetric,
+    e: [u8; 32],
+    s: [u8; 32],
+}
+
+// The first message, to the responder whose static key is `remote`.
+pub fn initiate(
+    local: &StaticKey,
+    remote: &[u8; 32],
+    prologue: &[u8],
+    payload: &[u8],
+) -> Option<(Initiator, Vec<u8>)> {
+    initiate_with(rand::random(), local, remote, prologue, payload)
+}
+
+fn initiate_with(
+    e: [u8; 32],
+    local: &StaticKey,
+    remote: &[u8; 32],
+    prologue: &[u8],
+    payload: &[u8],
+) -> Option<(Initiator, Vec<u8>)> {
+    let mut state = Symmetric::new(prologue);
+    state.mix_hash(remote);
+
+    let e_public = x25519::public_key(&e);
+    let mut message = e_public.to_vec();
+    state.mix_hash(&e_public);
+    state.mix_key(&dh(&e, remote)?);
+    message.extend(state.encrypt_and_hash(&local.public));
+    state.mix_key(&dh(&local.secret, remote)?);
+    message.extend(state.encrypt_and_hash(payload));
+
+    let initiator = Initiator {
+        state,
+        e,
+        s: local.secret,
+    };
+    Some((initiator, message))
+}
+
+impl Initiator {
+    // The keys, if `message` is the responder's answer to this handshake.
+    pub fn finish(mut self, message: &[u8]) -> Option<(Keys, Vec<u8>)> {
+        let re: [u8; 32] = message.get(..32)?.try_into().ok()?;
+        self.state.mix_hash(&re);
+        self.state.mix_key(&dh(&self.e, &re)?);
+        self.state.mix_key(&dh(&self.s, &re)?);
+        let payload = self.state.decrypt_and_hash(&message[32..])?;
+        let (send, receive) = self.state.split();
+        Some((Keys { send, receive }, payload))
+    }
+}
+
+// A first message that decrypted, from the peer whose key is `remote`.
+pub struct Handshake {
+    pub remote: [u8; 32],
+    pub payload: Vec<u8>,
+    state: Symmetric,
+    re: [u8; 32],
+}
+
+pub fn receive(local: &StaticKey, prologue: &[u8], message: &[u8]) -> Option<Handshake> {
+    let mut state = Symmetric::new(prologue);
+    state.mix_hash(&local.public);
+
+    let re: [u8; 32] = message.get(..32)?.try_into().ok()?;
+    state.mix_hash(&re);
+    state.mix_key(&dh(&local.secret, &re)?);
+    let remote: [u8; 32] = state
+        .decrypt_and_hash(message.get(32..64 + TAG_LEN)?)?
+        .try_into()
+        .ok()?;
+    state.mix_key(&dh(&local.secret, &remote)?);
+    let payload = state.decrypt_and_hash(&message[64 + TAG_LEN..])?;
+
+    Some(Handshake {
+        remote,
+        payload,
+        state,
+        re,
+    })
+}
+
+impl Handshake {
+    // The answer to send back, and the keys it sets up.
+    pub fn reply(self, payload: &[u8]) -> Option<(Vec<u8>, Keys)> {
+        self.reply_with(rand::random(), payload)
+    }
+
+    fn reply_with(mut self, e: [u8; 32], payload: &[u8]) -> Option<(Vec<u8>, Keys)> {
+        let e_public = x25519::public_key(&e);
+        let mut message = e_public.to_vec();
+        self.state.mix_hash(&e_public);
+        self.state.mix_key(&dh(&e, &self.re)?);
+        self.state.mix_key(&dh(&e, &self.remote)?);
+        message.extend(self.state.encrypt_and_hash(payload));
+        let (receive, send) = self.state.split();
+        Some((message, Keys { send, receive }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex;
+
+    // Noise's HKDF is RFC 5869's with the chaining key as salt and no info;
+    // an empty salt is HashLen zeros.
+    #[test]
+    fn hkdf_matches_rfc_5869() {
+        let (first, second) = hkdf(&[0; 32], &[0x0b; 22]);
+        assert_eq!(
+            hex::encode(&[&first[..], &second[..10]].concat()),
+            "8da4e775a563c18f715f802a063c5a31b8a11f5c5ee1879ec3454e5f3c738d2d\
+             9d201395faa4b61a96c8"
+        );
+    }
+
+    fn bytes<const N: usize>(text: &str) -> [u8; N] {
+        hex::decode(text).unwrap().try_into().unwrap()
+    }
+
+    // The keys, prologue and payloads of cacophony's IK vectors. The
+    // expected messages were worked out apart from this code, with OpenSSL's
+    // X25519 and ChaCha20-Poly1305, so a mistake shared by both sides of a
+    // handshake here still shows.
+    #[test]
+    fn matches_an_independent_ik_handshake() {
+        let initiator_static =
+            bytes("e61ef9919cde45dd5f82166404bd08e38bceb5dfdfded0a34c8df7ed542214d1");
+        let initiator_ephemeral =
+            bytes("893e28b9dc6ca8d611ab664754b8ceb7bac5283349a3fb2c0b4d3c6a3bd6d7c8");
+        let responder_static =
+            bytes("4a3acbfdb163dec651dfa3194dece676d437029c62a408b4c5ea9114246e4893");
+        let responder_ephemeral =
+            bytes("bbdb4cdbd309f1a1f2e1456967fe288cadd6f712d65dc7b7793d5e63da6b375b");
+        let prologue = b"John Galt";
+
+        let alice = StaticKey::new(initiator_static);
+        let bob = StaticKey::new(responder_static);
+        assert_eq!(
+            hex::encode(&bob.public),
+            "31e0303fd6418d2f8c0e78b91f22e8caed0fbe48656dcf4767e4834f701b8f62"
+        );
+
+        let (initiator, first) = initiate_with(
+            initiator_ephemeral,
+            &alice,
+            &bob.public,
+            prologue,
+            b"Ludwig von Mises",
+        )
+        .unwrap();
+        assert_eq!(
+            hex::encode(&first),
+            "adfb8d5a2ce82f9d7cda6197260d587d2600ce9a9541995edaf206ca1ffbb63f\
+             c55fc228b08652f7ff59c8c173fd832cc16ea6972fb5ae5efee080ba20c2e286\
+             8841d753204cb072c0538b8e3744eeaa1da1da920588652e1e3bfe70ce747ecc\
+             6fdf692ddd6e8e351b6d660e3b5eeff0"
+        );
+
+        let handshake = receive(&bob, prologue, &first).unwrap();
+        assert_eq!(handshake.payload, b"Ludwig von Mises");
+        let (second, bob_keys) = handshake
+            .reply_with(responder_ephemeral, b"Murray Rothbard")
+            .unwrap();
+        assert_eq!(
+            hex::encode(&second),
+            "95ebc60d2b1fa672c1f46a8aa265ef51bfe38e7ccb39ec5be34069f144808843\
+             680bfddf7495533f033d8aafc9c6741db44fa23f1a36449a5029d5615dd10f"
+        );
+
+        let (alice_keys, payload) = initiator.finish(&second).unwrap();
+        assert_eq!(payload, b"Murray Rothbard");
+        assert_eq!(
+            hex::encode(&aead::seal(
+                &alice_keys.send,
+                &nonce(0),
+                b"",
+                b"F. A. Hayek"
+            )),
+            "bd79d5f24b60c3b7b86d75d02d68aacf8446f34e32c5800f332afd"
+        );
+        assert_eq!(
+            hex::encode(&aead::seal(&bob_keys.send, &nonce(0), b"", b"Carl Menger")),
+            "77c4d37554f82fe3e1eadac86168813d45adf84cda720b92a74802"
+        );
+    }
+
+    #[test]
+    fn handshakes_agree_and_refuse_anything_altered() {
+        let alice = StaticKey::new([1; 32]);
+        let bob = StaticKey::new([2; 32]);
+        let (initiator, first) = initiate(&alice, &bob.public, b"test", b"hello").unwrap();
+        let handshake = receive(&bob, b"test", &first).unwrap();
+        assert_eq!(handshake.remote, alice.public);
+        assert_eq!(handshake.payload, b"hello");
+        let (second, bob_keys) = handshake.reply(b"").unwrap();
+        let (alice_keys, payload) = initiator.finish(&second).unwrap();
+        assert!(payload.is_empty());
+        assert_eq!(alice_keys.send, bob_keys.receive);
+        assert_eq!(alice_keys.receive, bob_keys.send);
+        assert_ne!(alice_keys.send, alice_keys.receive);
+
+        // Meant for someone else, under another prologue, or altered.
+        let carol = StaticKey::new([3; 32]);
+        assert!(receive(&carol, b"test", &first).is_none());
+        assert!(receive(&bob, b"other", &first).is_none());
+        for byte in [0, 40, first.len() - 1] {
+            let mut altered = first.clone();
+            altered[byte] ^= 1;
+            assert!(receive(&bob, b"test", &altered).is_none());
+        }
+        assert!(receive(&bob, b"test", &first[..60]).is_none());
+
+        let (initiator, first) = initiate(&alice, &bob.public, b"test", b"").unwrap();
+        let (mut second, _) = receive(&bob, b"test", &first).unwrap().reply(b"").unwrap();
+        second[40] ^= 1;
+        assert!(initiator.finish(&second).is_none());
+
+        // A low-order ephemeral key.
+        let mut zero = first.clone();
+        zero[..32].fill(0);
+        assert!(receive(&bob, b"test", &zero).is_none());
+    }
+}
@@ -0,0 +1,213 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, sleep, timeout};
+
+use crate::cli::{self, Command, Opt};
+use crate::exit;
+use crate::json::Value;
+use crate::outbound::{self, OutboundConfig};
+use crate::output;
+use crate::say;
+use crate::timeouts;
+
+const NTP_PORT: u16 = 123;
+const NTP_UNIX_OFFSET: f64 = 2_208_988_800.0;
+pub const CLIENT_VERSION_4: u8 = 0x23;
+pub const MAX_SKEW_SECS: f64 = 5.0;
+
+const NTP_OPTS: &[Opt] = &[Opt {
+    name: "--count",
+    value: Some("<n>"),
+    help: "Number of queries (default: 4)",
+}];
+
+pub struct Reply {
+    pub offset: f64,
+    pub delay: f64,
+    pub stratum: u8,
+    pub reference: String,
+}
+
+fn now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+        + NTP_UNIX_OFFSET
+}
+
+fn encode(time: f64) -> [u8; 8] {
+    let secs = time.trunc() as u64 as u32;
+    let frac = (time.fract() * 4_294_967_296.0) as u64 as u32;
+    let mut bytes = [0; 8];
+    bytes[..4].copy_from_slice(&secs.to_be_bytes());
+    bytes[4..].copy_from_slice(&frac.to_be_bytes());
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> f64 {
+    let secs = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64;
+    let frac = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as f64;
+    secs + frac / 4_294_967_296.0
+}
+
+pub fn target(server: &str) -> String {
+    match server.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, NTP_PORT).to_string(),
+        Err(_) if !server.contains(':') => format!("{}:{}", server, NTP_PORT),
+        Err(_) => server.to_string(),
+    }
+}
+
+pub async fn query(addr: SocketAddr) -> Result<Reply, String> {
+    let unspecified = match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((unspecified, 0))
+        .await
+        .map_err(|e| e.to_string())?;
+    socket.connect(addr).await.map_err(|e| e.to_string())?;
+
+    let mut request = [0; 48];
+    request[0] = CLIENT_VERSION_4;
+    let sent = now();
+    let transmit = encode(sent);
+    request[40..48].copy_from_slice(&transmit);
+    socket.send(&request).await.map_err(|e| e.to_string())?;
+
+    let mut buf = [0; 128];
+    let reply = timeout(timeouts::get().read, async {
+        loop {
+            let n = socket.recv(&mut buf).await?;
+            // The origin timestamp must echo our transmit time, which
+            // rejects stale and spoofed replies.
+            if n >= 48 && buf[24..32] == transmit {
+                return Ok::<_, std::io::Error>(());
+            }
+        }
+    })
+    .await;
+    let received = now();
+    match reply {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => return Err(e.to_string()),
+        Err(_) => return Err("timed out".to_string()),
+    }
+
+    parse_reply(&buf, addr.is_ipv4(), sent, received)
+}
+
+// A server's reply to a request sent at `sent` and answered at `received`.
+pub fn parse_reply(reply: &[u8], ipv4: bool, sent: f64, received: f64) -> Result<Reply, String> {
+    let reply = reply.get(..48).ok_or("short reply")?;
+    let stratum = reply[1];
+    if reply[0] & 0x07 != 4 {
+        return Err("reply is not in server mode".to_string());
+    }
+    let reference = if stratum <= 1 {
+        String::from_utf8_lossy(&reply[12..16])
+            .trim_end_matches('\0')
+            .to_string()
+    } else if ipv4 {
+        Ipv4Addr::new(reply[12], reply[13], reply[14], reply[15]).to_string()
+    } else {
+        format!(
+            "{:02x}{:02x}{:02x}{:02x}",
+            reply[12], reply[13], reply[14], reply[15]
+        )
+    };
+    if stratum == 0 {
+        return Err(format!("kiss-o'-death {}", reference));
+    }
+
+    let server_received = decode(&reply[32..40]);
+    let server_sent = decode(&reply[40..48]);
+    Ok(Reply {
+        offset: ((server_received - sent) + (server_sent - received)) / 2.0,
+        delay: (received - sent) - (server_sent - server_received),
+        stratum,
+        reference,
+    })
+}
+
+pub const COMMAND: Command = Command {
+    name: "ntp",
+    usage: "netcore ntp <server>",
+    about: "Query an NTP server for the clock offset and round trip",
+    groups: &[NTP_OPTS, outbound::OPTS, timeouts::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let Some(server) = args.positional().first() else {
+        eprintln!("ntp requires a server, e.g. pool.ntp.org");
+        std::process::exit(exit::USAGE);
+    };
+    let count: u32 = cli::or_exit(args.parsed("--count")).unwrap_or(4).max(1);
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+
+    let addr = match outbound.resolve(&target(server)).await {
+        Ok(addrs) => addrs[0],
+        Err(e) => {
+            eprintln!("Failed to resolve {}: {}", server, e);
+            std::process::exit(exit::code(&e));
+        }
+    };
+    say!("NTP {} ({})", server, addr);
+
+    let mut best: Option<Reply> = None;
+    for seq in 1..=count {
+        if seq > 1 {
+            sleep(Duration::from_secs(1)).await;
+        }
+        match query(addr).await {
+            Ok(reply) => {
+                say!(
+                    "seq={} offset={:+.3} ms delay={:.3} ms stratum={} ref={}",
+                    seq,
+                    reply.offset * 1000.0,
+                    reply.delay * 1000.0,
+                    reply.stratum,
+                    reply.reference
+                );
+                output::emit(Value::object([
+                    ("seq", Value::from(seq as u64)),
+                    ("offset_ms", Value::from(reply.offset * 1000.0)),
+                    ("delay_ms", Value::from(reply.delay * 1000.0)),
+                    ("stratum", Value::from(reply.stratum as u64)),
+                    ("reference", Value::from(reply.reference.as_str())),
+                ]));
+                if best.as_ref().is_none_or(|best| reply.delay < best.delay) {
+                    best = Some(reply);
+                }
+            }
+            Err(e) => say!("seq={} failed: {}", seq, e),
+        }
+    }
+
+    let Some(best) = best else {
+        say!("No reply from {}", server);
+        output::verdict("FAIL", server, "no reply");
+        std::process::exit(exit::FAILURE);
+    };
+    say!();
+    say!(
+        "Clock offset {:+.3} ms (from the lowest-delay reply, {:.3} ms)",
+        best.offset * 1000.0,
+        best.delay * 1000.0
+    );
+    if best.offset.abs() > MAX_SKEW_SECS {
+        let detail = format!(
+            "local clock is off by {:.1} s; TLS certificate validation may fail",
+            best.offset
+        );
+        say!("WARN  {}", detail);
+        output::verdict("WARN", server, &detail);
+        std::process::exit(exit::FAILURE);
+    }
+    let detail = format!("offset {:+.3} ms", best.offset * 1000.0);
+    output::verdict("PASS", server, &detail);
+}
@@ -0,0 +1,299 @@
+use crate::scan::{HostReport, State};
+
+const MAX_CONFIDENCE: u32 = 95;
+// How much each kind of evidence counts towards a guess.
+const STACK_WEIGHT: u32 = 60;
+const TTL_WEIGHT: u32 = 35;
+const BANNER_WEIGHT: u32 = 25;
+const PORT_WEIGHT: u32 = 10;
+
+// Words that software puts in its banners and the OS they give away.
+const BANNER_HINTS: &[(&str, &str)] = &[
+    ("Ubuntu", "Linux"),
+    ("Debian", "Linux"),
+    ("CentOS", "Linux"),
+    ("Red Hat", "Linux"),
+    ("Fedora", "Linux"),
+    ("Raspbian", "Linux"),
+    ("Linux", "Linux"),
+    ("FreeBSD", "macOS/BSD"),
+    ("OpenBSD", "macOS/BSD"),
+    ("Darwin", "macOS/BSD"),
+    ("Mac OS", "macOS/BSD"),
+    ("Windows", "Windows"),
+    ("Microsoft", "Windows"),
+    ("Win32", "Windows"),
+    ("Win64", "Windows"),
+    ("RouterOS", "network device or Solaris"),
+    ("Cisco", "network device or Solaris"),
+];
+
+// Services that hardly run anywhere else.
+const PORT_HINTS: &[(u16, &str)] = &[
+    (135, "Windows"),
+    (139, "Windows"),
+    (445, "Windows"),
+    (3389, "Windows"),
+    (5985, "Windows"),
+    (548, "macOS/BSD"),
+];
+
+pub struct Guess {
+    pub os: &'static str,
+    pub confidence: u32,
+    pub evidence: Vec<String>,
+}
+
+struct Scores(Vec<(&'static str, u32, Vec<String>)>);
+
+impl Scores {
+    fn add(&mut self, os: &'static str, weight: u32, evidence: String) {
+        match self.0.iter_mut().find(|(name, _, _)| *name == os) {
+            Some((_, score, list)) => {
+                *score += weight;
+                list.push(evidence);
+            }
+            None => self.0.push((os, weight, vec![evidence])),
+        }
+    }
+}
+
+// A guess, not a fingerprint match: the stack signature from a SYN scan's
+// SYN-ACK, OS names in service banners and OS-specific open ports each add
+// weight to a candidate. Confidence is the winner's weight, scaled down by
+// its share of all the weight handed out when the evidence disagrees.
+pub fn guess(host: &HostReport) -> Option<Guess> {
+    let mut scores = Scores(Vec::new());
+
+    if let Some(syn_ack) = &host.syn_ack {
+        let os = syn_ack.os_guess();
+        let weight = match os {
+            "Linux" | "macOS/BSD" => STACK_WEIGHT,
+            _ => TTL_WEIGHT,
+        };
+        scores.add(
+            os,
+            weight,
+            format!(
+                "ttl {}/{}, tcp options {}",
+                syn_ack.ttl,
+                syn_ack.initial_ttl(),
+                syn_ack.options.join(",")
+            ),
+        );
+    }
+
+    for port in host.ports.iter().filter(|p| p.state == State::Open) {
+        let label = format!("{}/{}", port.port, port.proto.as_str());
+        if let Some(banner) = port.service.as_ref().and_then(|s| s.banner.as_deref()) {
+            let hint = BANNER_HINTS.iter().find(|(word, _)| banner.contains(word));
+            if let Some((word, os)) = hint {
+                scores.add(os, BANNER_WEIGHT, format!("'{}' in {} banner", word, label));
+            }
+        }
+        if let Some((_, os)) = PORT_HINTS.iter().find(|(number, _)| *number == port.port) {
+            scores.add(os, PORT_WEIGHT, format!("{} open", label));
+        }
+    }
+
+    // A bare TTL of 64 backs whichever Unix the other evidence points to.
+    if let Some(at) = scores.0.iter().position(|(os, _, _)| *os == "Unix-like")
+        && scores
+            .0
+            .iter()
+            .any(|(os, _, _)| matches!(*os, "Linux" | "macOS/BSD"))
+    {
+        let (_, weight, evidence) = scores.0.remove(at);
+        let unix = scores
+            .0
+            .iter_mut()
+            .filter(|(os, _, _)| matches!(*os, "Linux" | "macOS/BSD"))
+            .max_by_key(|(_, score, _)| *score)?;
+        unix.1 += weight;
+        unix.2.extend(evidence);
+    }
+
+    let total: u32 = scores.0.iter().map(|(_, score, _)| score).sum();
+    let (os, best, evidence) = scores.0.into_iter().max_by_key(|(_, score, _)| *score)?;
+    let confidence = if total > 100 {
+        best * 100 / total
+    } else {
+        best
+    };
+    Some(Guess {
+        os,
+        confidence: confidence.min(MAX_CONFIDENCE),
+        evidence,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fingerprint::Syn;
+    use crate::scan::{PortResult, Proto};
+    use crate::services::Service;
+
+    const LINUX: &str = "mss,sok,ts,nop,ws";
+    const BSD: &str = "mss,nop,ws,nop,nop,ts,sok,eol";
+    const WINDOWS: &str = "mss,nop,ws,nop,nop,sok";
+
+    fn syn(ttl: u8, options: &'static str) -> Syn {
+        Syn {
+            ttl,
+            window: 65535,
+            mss: Some(1460),
+            options: options.split(',').collect(),
+        }
+    }
+
+    fn port(port: u16, state: State, banner: Option<&str>) -> PortResult {
+        PortResult {
+            port,
+            proto: Proto::Tcp,
+            state,
+            rtt: None,
+            service: banner.map(|banner| Service {
+                name: "test".to_string(),
+                version: None,
+                banner: Some(banner.to_string()),
+            }),
+        }
+    }
+
+    fn host(syn_ack: Option<Syn>, ports: Vec<PortResult>) -> HostReport {
+        HostReport {
+            addr: [192, 0, 2, 1].into(),
+            name: None,
+            ptr: None,
+            ports,
+            syn_ack,
+            os: None,
+        }
+    }
+
+    #[test]
+    fn reads_the_stack_from_ttl_and_options() {
+        let cases = [
+            (1, LINUX, 32, "embedded or legacy"),
+            (32, LINUX, 32, "embedded or legacy"),
+            (33, LINUX, 64, "Linux"),
+            (64, LINUX, 64, "Linux"),
+            (50, BSD, 64, "macOS/BSD"),
+            (50, WINDOWS, 64, "Unix-like"),
+            (65, LINUX, 128, "Windows"),
+            (116, WINDOWS, 128, "Windows"),
+            (128, BSD, 128, "Windows"),
+            (129, WINDOWS, 255, "network device or Solaris"),
+            (255, LINUX, 255, "network device or Solaris"),
+        ];
+        for (ttl, options, initial, os) in cases {
+            let syn = syn(ttl, options);
+            assert_eq!(syn.initial_ttl(), initial, "ttl {}", ttl);
+            assert_eq!(syn.os_guess(), os, "ttl {} {}", ttl, options);
+        }
+    }
+
+    #[test]
+    fn weighs_the_evidence() {
+        let ubuntu = Some("SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13");
+        let iis = Some("HTTP/1.1 200 OK Microsoft-IIS/10.0");
+        let cases = [
+            // A full stack signature counts more than a bare TTL.
+            (host(Some(syn(52, LINUX)), vec![]), "Linux", 60, 1),
+            (host(Some(syn(116, WINDOWS)), vec![]), "Windows", 35, 1),
+            (host(Some(syn(60, "mss")), vec![]), "Unix-like", 35, 1),
+            (
+                host(Some(syn(20, "mss")), vec![]),
+                "embedded or legacy",
+                35,
+                1,
+            ),
+            // Banners and ports add up behind the stack.
+            (
+                host(
+                    Some(syn(116, WINDOWS)),
+                    vec![port(445, State::Open, None), port(80, State::Open, iis)],
+                ),
+                "Windows",
+                70,
+                3,
+            ),
+            // A TTL of 64 joins the Unix the banners name.
+            (
+                host(Some(syn(60, "mss")), vec![port(22, State::Open, ubuntu)]),
+                "Linux",
+                60,
+                2,
+            ),
+            // Only open ports count.
+            (
+                host(
+                    Some(syn(52, LINUX)),
+                    vec![
+                        port(3389, State::Closed, None),
+                        port(22, State::Filtered, iis),
+                    ],
+                ),
+                "Linux",
+                60,
+                1,
+            ),
+            (
+                host(None, vec![port(548, State::Open, None)]),
+                "macOS/BSD",
+                10,
+                1,
+            ),
+            // Disagreeing evidence scales the winner by its share.
+            (
+                host(
+                    Some(syn(52, LINUX)),
+                    vec![
+                        port(80, State::Open, iis),
+                        port(8080, State::Open, Some("Server: Win64")),
+                        port(445, State::Open, None),
+                        port(3389, State::Open, None),
+                    ],
+                ),
+                "Windows",
+                70 * 100 / 130,
+                4,
+            ),
+            // Agreeing evidence is capped short of certainty.
+            (
+                host(
+                    Some(syn(52, LINUX)),
+                    vec![
+                        port(22, State::Open, ubuntu),
+                        port(2222, State::Open, ubuntu),
+                        port(80, State::Open, Some("Apache/2.4.58 (Debian)")),
+                    ],
+                ),
+                "Linux",
+                MAX_CONFIDENCE,
+                4,
+            ),
+        ];
+        for (i, (host, os, confidence, evidence)) in cases.into_iter().enumerate() {
+            let guess = guess(&host).unwrap();
+            assert_eq!(
+                (guess.os, guess.confidence, guess.evidence.len()),
+                (os, confidence, evidence),
+                "case {}: {:?}",
+                i,
+                guess.evidence
+            );
+        }
+    }
+
+    #[test]
+    fn needs_some_evidence() {
+        assert!(guess(&host(None, vec![])).is_none());
+        let ports = vec![
+            port(22, State::Open, Some("SSH-2.0-Go")),
+            port(445, State::Closed, None),
+        ];
+        assert!(guess(&host(None, ports)).is_none());
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::time::{Duration, MissedTickBehavior, interval};
+
+use crate::cli::{Args, Opt};
+use crate::hex;
+use crate::json::Value;
+use crate::measure::Sample;
+use crate::say;
+use crate::secret;
+use crate::units::{self, SECS};
+use crate::webhook;
+
+const EXPORT_INTERVAL_SECS: u64 = 10;
+const MAX_PENDING_SPANS: usize = 4096;
+const SPAN_KIND_SERVER: u64 = 2;
+const STATUS_OK: u64 = 1;
+const STATUS_ERROR: u64 = 2;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--otlp-endpoint",
+        value: Some("<url>"),
+        help: "Export traces and metrics over OTLP/HTTP (e.g. http://localhost:4318)",
+    },
+    Opt {
+        name: "--otlp-header",
+        value: Some("<name=value>"),
+        help: "Extra header for OTLP requests; the value may be file:, env: or exec: (repeatable)",
+    },
+    Opt {
+        name: "--otlp-interval",
+        value: Some("<duration>"),
+        help: "How often to export (default: 10s)",
+    },
+];
+
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    name: &'static str,
+    start: u64,
+    end: u64,
+    attributes: Vec<(&'static str, Value)>,
+    error: bool,
+}
+
+struct Gauge {
+    name: String,
+    unit: &'static str,
+    value: f64,
+    time: u64,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+pub struct Exporter {
+    endpoint: String,
+    headers: Vec<(String, String)>,
+    started: u64,
+    spans: Mutex<Vec<SpanData>>,
+    gauges: Mutex<HashMap<String, Gauge>>,
+    counters: Mutex<HashMap<&'static str, u64>>,
+}
+
+static EXPORTER: OnceLock<Exporter> = OnceLock::new();
+
+fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Bool(b) => Value::object([("boolValue", Value::from(*b))]),
+        Value::Number(n) if n.fract() == 0.0 => {
+            Value::object([("intValue", Value::from(format!("{}", *n as i64)))])
+        }
+        Value::Number(n) => Value::object([("doubleValue", Value::from(*n))]),
+        Value::String(s) => Value::object([("stringValue", Value::from(s.as_str()))]),
+        other => Value::object([("stringValue", Value::from(other.to_string()))]),
+    };
+
+    Value::object([("key", Value::from(key)), ("value", value)])
+}
+
+fn attributes(attrs: &[(&'static str, Value)]) -> Value {
+    Value::Array(attrs.iter().map(|(k, v)| attribute(k, v)).collect())
+}
+
+fn resource() -> Value {
+    Value::object([(
+        "attributes",
+        Value::Array(vec![
+            attribute("service.name", &Value::from("netcore")),
+            attribute("service.version", &Value::from(env!("CARGO_PKG_VERSION"))),
+        ]),
+    )])
+}
+
+fn scope() -> Value {
+    Value::object([("name", Value::from("netcore"))])
+}
+
+pub struct Span {
+    data: Option<SpanData>,
+}
+
+impl Span {
+    pub fn attr(&mut self, key: &'static str, value: impl Into<Value>) {
+        if let Some(data) = &mut self.data {
+            data.attributes.push((key, value.into()));
+        }
+    }
+
+    pub fn error(&mut self, message: impl Into<Value>) {
+        if let Some(data) = &mut self.data {
+            data.error = true;
+            data.attributes.push(("error.message", message.into()));
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let (Some(mut data), Some(exporter)) = (self.data.take(), EXPORTER.get()) {
+            data.end = unix_nanos();
+            let mut spans = exporter.spans.lock().unwrap();
+            if spans.len() < MAX_PENDING_SPANS {
+                spans.push(data);
+            }
+        }
+    }
+}
+
+pub fn count(name: &'static str) {
+    if let Some(exporter) = EXPORTER.get() {
+        *exporter.counters.lock().unwrap().entry(name).or_default() += 1;
+    }
+}
+
+pub fn span(name: &'static str, peer: SocketAddr) -> Span {
+    if EXPORTER.get().is_none() {
+        return Span { data: None };
+    }
+    count("netcore.connections");
+
+    Span {
+        data: Some(SpanData {
+            trace_id: rand::random(),
+            span_id: rand::random(),
+            name,
+            start: unix_nanos(),
+            end: 0,
+            attributes: vec![
+                ("net.peer.ip", Value::from(peer.ip().to_string())),
+                ("net.peer.port", Value::from(peer.port() as u64)),
+            ],
+            error: false,
+        }),
+    }
+}
+
+pub fn record_samples(job: &str, samples: &[Sample]) {
+    let Some(exporter) = EXPORTER.get() else {
+        return;
+    };
+    let time = unix_nanos();
+    let mut gauges = exporter.gauges.lock().unwrap();
+
+    for sample in samples {
+        let (name, unit, value) = match sample.kind {
+            "ping" => ("netcore.ping.rtt", "ms", sample.value.parse().ok()),
+            "bench" => (
+                "netcore.bench.throughput",
+                "Mbit/s",
+                sample.value.parse().ok(),
+            ),
+            "check" => (
+                "netcore.check.ok",
+                "1",
+                Some(if sample.ok { 1.0 } else { 0.0 }),
+            ),
+            "cert" => ("netcore.cert.expiry", "d", sample.value.parse().ok()),
+            "info" => (
+                "netcore.info.available",
+                "1",
+                Some(if sample.ok { 1.0 } else { 0.0 }),
+            ),
+            _ => continue,
+        };
+        let Some(value) = value else {
+            continue;
+        };
+
+        gauges.insert(
+            format!("{}/{}", name, sample.subject),
+            Gauge {
+                name: name.to_string(),
+                unit,
+                value,
+                time,
+                attributes: vec![
+                    ("job", Value::from(job)),
+                    ("subject", Value::from(sample.subject.as_str())),
+                ],
+            },
+        );
+    }
+}
+
+impl Exporter {
+    fn traces_payload(&self) -> Option<Value> {
+        let spans: Vec<SpanData> = std::mem::take(&mut *self.spans.lock().unwrap());
+        if spans.is_empty() {
+            return None;
+        }
+
+        let spans = spans
+            .iter()
+            .map(|span| {
+                Value::object([
+                    ("traceId", Value::from(hex::encode(&span.trace_id))),
+                    ("spanId", Value::from(hex::encode(&span.span_id))),
+                    ("name", Value::from(span.name)),
+                    ("kind", Value::from(SPAN_KIND_SERVER)),
+                    ("startTimeUnixNano", Value::from(span.start.to_string())),
+                    ("endTimeUnixNano", Value::from(span.end.to_string())),
+                    ("attributes", attributes(&span.attributes)),
+                    (
+                        "status",
+                        Value::object([(
+                            "code",
+                            Value::from(if span.error { STATUS_ERROR } else { STATUS_OK }),
+                        )]),
+                    ),
+                ])
+            })
+            .collect();
+
+        Some(Value::object([(
+            "resourceSpans",
+            Value::Array(vec![Value::object([
+                ("resource", resource()),
+                (
+                    "scopeSpans",
+                    Value::Array(vec![Value::object([
+                        ("scope", scope()),
+                        ("spans", Value::Array(spans)),
+                    ])]),
+                ),
+            ])]),
+        )]))
+    }
+
+    fn metrics_payload(&self) -> Value {
+        let now = unix_nanos().to_string();
+        let mut metrics = Vec::new();
+
+        for gauge in self.gauges.lock().unwrap().values() {
+            metrics.push(Value::object([
+                ("name", Value::from(gauge.name.as_str())),
+                ("unit", Value::from(gauge.unit)),
+                (
+                    "gauge",
+                    Value::object([(
+                        "dataPoints",
+                        Value::Array(vec![Value::object([
+                            ("asDouble", Value::from(gauge.value)),
+                            ("timeUnixNano", Value::from(gauge.time.to_string())),
+                            ("attributes", attributes(&gauge.attributes)),
+                        ])]),
+                    )]),
+                ),
+            ]));
+        }
+
+        for (name, count) in self.counters.lock().unwrap().iter() {
+            metrics.push(Value::object([
+                ("name", Value::from(*name)),
+                ("unit", Value::from("1")),
+                (
+                    "sum",
+                    Value::object([
+                        ("aggregationTemporality", Value::from(2u64)),
+                        ("isMonotonic", Value::from(true)),
+                        (
+                            "dataPoints",
+                            Value::Array(vec![Value::object([
+                                ("asInt", Value::from(count.to_string())),
+                                ("startTimeUnixNano", Value::from(self.started.to_string())),
+                                ("timeUnixNano", Value::from(now.as_str())),
+                            ])]),
+                        ),
+                    ]),
+                ),
+            ]));
+        }
+
+        Value::object([(
+            "resourceMetrics",
+            Value::Array(vec![Value::object([
+                ("resource", resource()),
+                (
+                    "scopeMetrics",
+                    Value::Array(vec![Value::object([
+                        ("scope", scope()),
+                        ("metrics", Value::Array(metrics)),
+                    ])]),
+                ),
+            ])]),
+        )])
+    }
+
+    async fn export(&self) {
+        if let Some(traces) = self.traces_payload() {
+            let url = format!("{}/v1/traces", self.endpoint);
+            if let Err(e) = webhook::post_with_headers(&url, &self.headers, &traces).await {
+                eprintln!("OTLP trace export to {} failed: {}", url, e);
+            }
+        }
+
+        let url = format!("{}/v1/metrics", self.endpoint);
+        if let Err(e) =
+            webhook::post_with_headers(&url, &self.headers, &self.metrics_payload()).await
+        {
+            eprintln!("OTLP metric export to {} failed: {}", url, e);
+        }
+    }
+}
+
+pub fn init(args: &Args) -> Result<(), String> {
+    let Some(endpoint) = args.value("--otlp-endpoint") else {
+        return Ok(());
+    };
+
+    let headers = args
+        .values("--otlp-header")
+        .map(|header| -> Result<(String, String), String> {
+            let (name, value) = header
+                .split_once('=')
+                .ok_or_else(|| format!("invalid OTLP header '{}', expected name=value", header))?;
+            let value = secret::resolve(value.trim())
+                .map_err(|e| format!("OTLP header {}: {}", name.trim(), e))?;
+            Ok((name.trim().to_string(), value))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let period = args
+        .parsed_with("--otlp-interval", |v| units::duration(v, SECS))?
+        .unwrap_or(Duration::from_secs(EXPORT_INTERVAL_SECS));
+
+    let exporter = Exporter {
+        endpoint: endpoint.trim_end_matches('/').to_string(),
+        headers,
+        started: unix_nanos(),
+        spans: Mutex::default(),
+        gauges: Mutex::default(),
+        counters: Mutex::default(),
+    };
+    if EXPORTER.set(exporter).is_err() {
+        return Ok(());
+    }
+
+    say!("Exporting OTLP telemetry to {}", endpoint);
+    tokio::spawn(async move {
+        let mut ticker = interval(period.max(Duration::from_secs(1)));
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            if let Some(exporter) = EXPORTER.get() {
+                exporter.export().await;
+            }
+        }
+    });
+
+    Ok(())
+}
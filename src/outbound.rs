@@ -0,0 +1,259 @@
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::str::FromStr;
+use tokio::net::{TcpSocket, TcpStream, lookup_host};
+use tokio::task::JoinSet;
+use tokio::time::{Duration, sleep};
+
+use crate::cli::{Args, Opt};
+use crate::ipv6;
+use crate::nat64;
+use crate::upstream::Upstream;
+
+const CONNECTION_ATTEMPT_DELAY_MS: u64 = 250;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--prefer",
+        value: Some("<v4|v6|auto>"),
+        help: "Address family to try first (default: auto)",
+    },
+    Opt {
+        name: "--source-addr",
+        value: Some("<ip>"),
+        help: "Local address for outbound connections",
+    },
+    Opt {
+        name: "--source-port",
+        value: Some("<port>"),
+        help: "Local port for outbound connections",
+    },
+    Opt {
+        name: "--interface",
+        value: Some("<name>"),
+        help: "Bind outbound connections to a network interface",
+    },
+    Opt {
+        name: "--proxy",
+        value: Some("<url>"),
+        help: "Connect through a socks5:// or http:// (CONNECT) proxy; a SOCKS5 password may be file:, env: or exec:",
+    },
+];
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Preference {
+    V4,
+    V6,
+    #[default]
+    Auto,
+}
+
+impl Preference {
+    // Auto goes with IPv6 unless it is tunneled through something slower.
+    pub fn prefers_v4(self) -> bool {
+        match self {
+            Preference::V4 => true,
+            Preference::V6 => false,
+            Preference::Auto => ipv6::path().degraded(),
+        }
+    }
+
+    pub fn order(self, addrs: impl IntoIterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+        let (v4, v6): (Vec<_>, Vec<_>) = addrs.into_iter().partition(SocketAddr::is_ipv4);
+        let (primary, secondary) = if self.prefers_v4() {
+            (v4, v6)
+        } else {
+            (v6, v4)
+        };
+
+        let mut ordered = Vec::with_capacity(primary.len() + secondary.len());
+        let mut primary = primary.into_iter();
+        let mut secondary = secondary.into_iter();
+        loop {
+            match (primary.next(), secondary.next()) {
+                (None, None) => break,
+                (a, b) => ordered.extend(a.into_iter().chain(b)),
+            }
+        }
+        ordered
+    }
+}
+
+impl FromStr for Preference {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Preference, String> {
+        match value {
+            "v4" | "ipv4" => Ok(Preference::V4),
+            "v6" | "ipv6" => Ok(Preference::V6),
+            "auto" => Ok(Preference::Auto),
+            _ => Err(format!("invalid address family preference '{}'", value)),
+        }
+    }
+}
+
+impl fmt::Display for Preference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Preference::V4 => write!(f, "v4"),
+            Preference::V6 => write!(f, "v6"),
+            Preference::Auto => write!(f, "auto"),
+        }
+    }
+}
+
+pub fn family(ip: IpAddr) -> &'static str {
+    if ip.is_ipv4() { "IPv4" } else { "IPv6" }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct OutboundConfig {
+    pub prefer: Preference,
+    pub source_addr: Option<IpAddr>,
+    pub source_port: Option<u16>,
+    pub interface: Option<String>,
+    pub proxy: Option<Upstream>,
+}
+
+impl OutboundConfig {
+    pub fn from_args(args: &Args) -> Result<OutboundConfig, String> {
+        Ok(OutboundConfig {
+            prefer: args.parsed("--prefer")?.unwrap_or_default(),
+            source_addr: args.parsed("--source-addr")?,
+            source_port: args.parsed("--source-port")?,
+            interface: args.value("--interface").map(str::to_string),
+            proxy: args.parsed("--proxy")?,
+        })
+    }
+
+    pub async fn resolve(&self, target: &str) -> io::Result<Vec<SocketAddr>> {
+        let found: Vec<SocketAddr> = lookup_host(target).await?.collect();
+        let mut addrs =
+            self.prefer
+                .order(found.iter().copied().filter(|addr| match self.source_addr {
+                    Some(source) => source.is_ipv4() == addr.is_ipv4(),
+                    None => true,
+                }));
+
+        // Without an IPv4 route, IPv4 literals and names DNS64 didn't
+        // cover can still be reached through NAT64.
+        if found.iter().all(SocketAddr::is_ipv4)
+            && self.source_addr.is_none_or(|source| source.is_ipv6())
+            && nat64::ipv4_route().is_none()
+            && let Some(prefix) = nat64::prefix().await
+        {
+            addrs.extend(found.iter().filter_map(|addr| match addr.ip() {
+                IpAddr::V4(ip) => Some(SocketAddr::new(prefix.synthesize(ip).into(), addr.port())),
+                IpAddr::V6(_) => None,
+            }));
+        }
+
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no usable address for {}", target),
+            ));
+        }
+
+        Ok(addrs)
+    }
+
+    pub async fn connect(&self, target: &str) -> io::Result<TcpStream> {
+        if let Some(upstream) = &self.proxy {
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("'{}' is not host:port", target),
+                )
+            };
+            let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+            let port = port.parse().map_err(|_| invalid())?;
+            return self.through(upstream, host, port).await;
+        }
+        let addrs = self.resolve(target).await?;
+        self.happy_eyeballs(addrs).await
+    }
+
+    // With --proxy the proxy is dialed like any other target, then asked
+    // for the connection.
+    async fn through(&self, upstream: &Upstream, host: &str, port: u16) -> io::Result<TcpStream> {
+        let addrs = self.resolve(upstream.addr()).await?;
+        let mut stream = self.happy_eyeballs(addrs).await?;
+        upstream.handshake(&mut stream, host, port).await?;
+        Ok(stream)
+    }
+
+    async fn happy_eyeballs(&self, addrs: Vec<SocketAddr>) -> io::Result<TcpStream> {
+        let mut pending = addrs.into_iter();
+        let mut attempts = JoinSet::new();
+        let mut last_err = None;
+
+        loop {
+            if let Some(addr) = pending.next() {
+                let config = self.clone();
+                attempts.spawn(async move { config.dial(addr).await });
+            }
+
+            if attempts.is_empty() {
+                break;
+            }
+
+            tokio::select! {
+                Some(result) = attempts.join_next() => match result {
+                    Ok(Ok(stream)) => return Ok(stream),
+                    Ok(Err(e)) => last_err = Some(e),
+                    Err(e) => last_err = Some(io::Error::other(e)),
+                },
+                _ = sleep(Duration::from_millis(CONNECTION_ATTEMPT_DELAY_MS)), if pending.len() > 0 => {}
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| io::Error::from(io::ErrorKind::NotConnected)))
+    }
+
+    pub async fn connect_addr(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        match &self.proxy {
+            Some(upstream) => {
+                self.through(upstream, &addr.ip().to_string(), addr.port())
+                    .await
+            }
+            None => self.dial(addr).await,
+        }
+    }
+
+    async fn dial(&self, addr: SocketAddr) -> io::Result<TcpStream> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+
+        if let Some(interface) = &self.interface {
+            bind_device(&socket, interface)?;
+        }
+
+        if self.source_addr.is_some() || self.source_port.is_some() {
+            let ip = self.source_addr.unwrap_or(match addr {
+                SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            });
+            socket.set_reuseaddr(true)?;
+            socket.bind(SocketAddr::new(ip, self.source_port.unwrap_or(0)))?;
+        }
+
+        socket.connect(addr).await
+    }
+}
+
+#[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+fn bind_device(socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "fuchsia", target_os = "linux")))]
+fn bind_device(_socket: &TcpSocket, interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("cannot bind to interface {} on this platform", interface),
+    ))
+}
@@ -0,0 +1,120 @@
+// How commands talk to whoever runs them. By default they print text for
+// people. --quiet drops that text, leaving errors on stderr and the exit
+// code, for cron jobs. --porcelain drops it too but prints each result as
+// one JSON object per line instead, tagged with the command, in a shape
+// that only ever gains fields, for scripts. Each line says which
+// SCHEMA_VERSION it follows, and --schema prints its JSON Schema.
+//
+// Human text goes through say!, which is println! that stays silent outside
+// human mode; results go through emit or verdict, which only print in
+// porcelain mode.
+
+use std::io::Write;
+use std::sync::OnceLock;
+
+use crate::cli::{Args, Opt};
+use crate::json::Value;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--quiet",
+        value: None,
+        help: "Print nothing but errors; the exit code tells the outcome",
+    },
+    Opt {
+        name: "--porcelain",
+        value: None,
+        help: "Print results as one JSON object per line, for scripts",
+    },
+    Opt {
+        name: "--schema",
+        value: None,
+        help: "Print the JSON Schema of the --porcelain lines and exit",
+    },
+];
+
+// Bumped whenever a field is removed, renamed or changes type; adding one
+// keeps it.
+pub const SCHEMA_VERSION: u64 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Human,
+    Quiet,
+    Porcelain,
+}
+
+static MODE: OnceLock<(Mode, &'static str)> = OnceLock::new();
+
+pub fn init(command: &'static str, args: &Args) -> Result<(), String> {
+    let mode = match (args.flag("--quiet"), args.flag("--porcelain")) {
+        (true, true) => return Err("--quiet and --porcelain can't be combined".to_string()),
+        (true, false) => Mode::Quiet,
+        (false, true) => Mode::Porcelain,
+        (false, false) => Mode::Human,
+    };
+    let _ = MODE.set((mode, command));
+    Ok(())
+}
+
+// Programs that embed netcore never parse a command line, and get nothing
+// printed: an echo server would otherwise write a line to their stdout
+// for every read. What happens is in events::subscribe() instead.
+pub fn mode() -> Mode {
+    MODE.get().map_or(Mode::Quiet, |(mode, _)| *mode)
+}
+
+pub fn human() -> bool {
+    mode() == Mode::Human
+}
+
+#[macro_export]
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if $crate::output::human() {
+            println!($($arg)*);
+        }
+    };
+}
+
+// Prints one result in porcelain mode. `fields` is an object; the command
+// name goes in front of its fields.
+pub fn emit(fields: Value) {
+    if mode() != Mode::Porcelain {
+        return;
+    }
+    let command = MODE.get().map_or("netcore", |(_, command)| *command);
+    let mut line = vec![
+        ("command".to_string(), Value::from(command)),
+        ("schema_version".to_string(), Value::from(SCHEMA_VERSION)),
+    ];
+    if let Value::Object(fields) = fields {
+        line.extend(fields);
+    }
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "{}", Value::Object(line));
+    let _ = stdout.flush();
+}
+
+// The PASS, WARN and FAIL lines diagnostic commands print, as results.
+pub fn verdict(status: &str, subject: &str, detail: &str) {
+    emit(Value::object([
+        ("status", Value::from(status.to_ascii_lowercase())),
+        ("subject", Value::from(subject)),
+        ("detail", Value::from(detail)),
+    ]));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quiet_and_porcelain_exclude_each_other() {
+        let args = Args::parse(["--quiet".to_string(), "--porcelain".to_string()], &[OPTS]);
+        assert!(init("check", &args.unwrap()).is_err());
+        // Nothing was set, so this is still the library's silence.
+        assert_eq!(mode(), Mode::Quiet);
+        assert!(!human());
+    }
+}
@@ -0,0 +1,373 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::time::{Duration, sleep, timeout};
+
+use crate::acl;
+use crate::cli::{self, Args, Command, Opt};
+use crate::e2e;
+use crate::exit;
+use crate::outbound::{self, OutboundConfig};
+use crate::relay::{self, KEEPALIVE_SECS, KEEPALIVE_TIMEOUT_SECS};
+use crate::say;
+use crate::timeouts;
+use crate::tunnel::{self, Features, Incoming, Keepalive, Mux};
+
+const CODE_ALPHABET: &[u8] = b"23456789ABCDEFGHJKMNPQRSTUVWXYZ";
+const CODE_LEN: usize = 6;
+const CODE_EXPIRY_SECS: u64 = 600;
+const WRONG_CODE_DELAY_SECS: u64 = 1;
+const PAIRED_IDLE_SECS: u64 = 3600;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--relay",
+        value: Some("<host:port>"),
+        help: "Relay that brokers the pairing",
+    },
+    Opt {
+        name: "--compress",
+        value: None,
+        help: "Compress data sent to the paired peer if it supports that",
+    },
+    Opt {
+        name: "--verify",
+        value: None,
+        help: "Check a BLAKE3 digest of each stream received from the paired peer",
+    },
+    Opt {
+        name: "--no-encrypt",
+        value: None,
+        help: "Leave the traffic to the paired peer unencrypted; both sides must agree",
+    },
+];
+
+const PAIR_OPTS: &[Opt] = &[
+    Opt {
+        name: "--expose",
+        value: Some("<host:port>"),
+        help: "Forward the paired peer's connections to this target",
+    },
+    Opt {
+        name: "--listen",
+        value: Some("<addr>"),
+        help: "Accept local connections and tunnel them to the paired peer",
+    },
+];
+
+pub struct Peer {
+    pub observed: String,
+    pub addresses: String,
+}
+
+struct Offer {
+    joined: oneshot::Sender<(BufReader<TcpStream>, SocketAddr, String)>,
+}
+
+#[derive(Default)]
+pub struct Rendezvous {
+    offers: Mutex<HashMap<String, Offer>>,
+}
+
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_uppercase())
+        .collect()
+}
+
+fn new_code() -> String {
+    (0..CODE_LEN)
+        .map(|_| CODE_ALPHABET[rand::random::<usize>() % CODE_ALPHABET.len()] as char)
+        .collect()
+}
+
+fn display_code(code: &str) -> String {
+    let (a, b) = code.split_at(code.len() / 2);
+    format!("{}-{}", a, b)
+}
+
+async fn send_paired(reader: &mut BufReader<TcpStream>, peer: SocketAddr, addresses: &str) -> bool {
+    let line = format!("PAIRED {} {}\n", peer, addresses);
+    reader.get_mut().write_all(line.as_bytes()).await.is_ok()
+}
+
+impl Rendezvous {
+    pub async fn offer(
+        &self,
+        mut reader: BufReader<TcpStream>,
+        addr: SocketAddr,
+        code: &str,
+        addresses: &str,
+    ) {
+        let code = normalize(code);
+        let (joined, waiting) = oneshot::channel();
+        let accepted = {
+            let mut offers = self.offers.lock().unwrap();
+            let free = code.len() == CODE_LEN && !offers.contains_key(&code);
+            if free {
+                offers.insert(code.clone(), Offer { joined });
+            }
+            free
+        };
+        if !accepted {
+            let _ = reader.get_mut().write_all(b"ERR code unavailable\n").await;
+            return;
+        }
+        say!("Pairing code {} offered by {}", display_code(&code), addr);
+
+        let result = match reader.get_mut().write_all(b"WAIT\n").await {
+            // The offering side sends nothing while waiting, so a readable
+            // socket means it went away.
+            Ok(()) => tokio::select! {
+                result = timeout(Duration::from_secs(CODE_EXPIRY_SECS), waiting) => result.ok(),
+                _ = reader.fill_buf() => None,
+            },
+            Err(_) => None,
+        };
+        self.offers.lock().unwrap().remove(&code);
+
+        let Some(Ok((mut other, other_addr, other_addresses))) = result else {
+            say!("Pairing code {} withdrawn", display_code(&code));
+            return;
+        };
+        if !send_paired(&mut reader, other_addr, &other_addresses).await
+            || !send_paired(&mut other, addr, addresses).await
+        {
+            return;
+        }
+
+        say!("Paired {} with {}", addr, other_addr);
+        let result = timeout(
+            Duration::from_secs(PAIRED_IDLE_SECS),
+            tokio::io::copy_bidirectional(&mut reader, &mut other),
+        )
+        .await;
+        match result {
+            Ok(Ok((a, b))) => say!(
+                "Pairing {} <-> {} closed ({} bytes)",
+                addr,
+                other_addr,
+                a + b
+            ),
+            Ok(Err(e)) => say!("Pairing {} <-> {} ended: {}", addr, other_addr, e),
+            Err(_) => say!("Pairing {} <-> {} reached its time limit", addr, other_addr),
+        }
+    }
+
+    pub async fn join(
+        &self,
+        mut reader: BufReader<TcpStream>,
+        addr: SocketAddr,
+        code: &str,
+        addresses: &str,
+    ) {
+        let offer = self.offers.lock().unwrap().remove(&normalize(code));
+        match offer {
+            Some(offer) => {
+                let _ = offer.joined.send((reader, addr, addresses.to_string()));
+            }
+            None => {
+                say!("Unknown pairing code from {}", addr);
+                acl::offence(addr, "auth");
+                // Slow down anyone guessing codes.
+                sleep(Duration::from_secs(WRONG_CODE_DELAY_SECS)).await;
+                let _ = reader.get_mut().write_all(b"ERR unknown code\n").await;
+            }
+        }
+    }
+}
+
+fn local_addresses() -> String {
+    let addresses: Vec<String> = local_ip_address::list_afinet_netifas()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|(_, ip)| !ip.is_loopback() && !ip.is_unspecified())
+        .map(|(_, ip)| ip.to_string())
+        .collect();
+    if addresses.is_empty() {
+        "-".to_string()
+    } else {
+        addresses.join(",")
+    }
+}
+
+pub async fn connect(
+    outbound: &OutboundConfig,
+    relay: &str,
+    code: Option<&str>,
+    join_command: &str,
+    features: Features,
+    encrypt: bool,
+) -> Result<(Mux, Incoming, Peer), String> {
+    let socket = outbound.connect(relay).await.map_err(|e| e.to_string())?;
+    let mut control = BufReader::new(socket);
+
+    let (command, code) = match code {
+        Some(code) => ("PAIR-JOIN", normalize(code)),
+        None => ("PAIR-OFFER", new_code()),
+    };
+    let line = format!("{} {} {}\n", command, code, local_addresses());
+    control
+        .get_mut()
+        .write_all(line.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+
+    let mut reply = relay::read_line(&mut control)
+        .await
+        .ok_or("no reply from relay")?;
+    if reply == "WAIT" {
+        say!("Pairing code: {}", display_code(&code));
+        say!(
+            "Run '{} {}' on the other machine",
+            join_command,
+            display_code(&code)
+        );
+        reply = relay::read_line_within(&mut control, Duration::from_secs(CODE_EXPIRY_SECS))
+            .await
+            .ok_or("pairing code expired")?;
+    }
+
+    let peer = match reply.split_whitespace().collect::<Vec<_>>().as_slice() {
+        ["PAIRED", observed, addresses] => Peer {
+            observed: observed.to_string(),
+            addresses: addresses.to_string(),
+        },
+        _ => return Err(format!("relay refused pairing: {}", reply)),
+    };
+
+    let keepalive = Keepalive {
+        interval: Duration::from_secs(KEEPALIVE_SECS),
+        timeout: Duration::from_secs(KEEPALIVE_TIMEOUT_SECS),
+    };
+    let offerer = command == "PAIR-OFFER";
+    let (mux, incoming) = if encrypt {
+        let (io, fingerprint) = e2e::session(control, offerer, &code).await?;
+        say!(
+            "Encrypted; session fingerprint {} (should match the other side's)",
+            fingerprint
+        );
+        tunnel::session(io, offerer, keepalive, features)
+    } else {
+        tunnel::session(control, offerer, keepalive, features)
+    };
+    Ok((mux, incoming, peer))
+}
+
+async fn expose(mut incoming: Incoming, target: String, outbound: OutboundConfig) {
+    while let Some(mut stream) = incoming.accept().await {
+        let target = target.clone();
+        let outbound = outbound.clone();
+        tokio::spawn(async move {
+            match outbound.connect(&target).await {
+                Ok(mut upstream) => {
+                    let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+                }
+                Err(e) => eprintln!("Paired connection to {} failed: {}", target, e),
+            }
+        });
+    }
+}
+
+async fn listen(mux: &Mux, listener: TcpListener) {
+    loop {
+        let Ok((mut socket, addr)) = listener.accept().await else {
+            continue;
+        };
+        let Ok(mut stream) = mux.open().await else {
+            return;
+        };
+        say!("Tunnelling {} to the paired peer", addr);
+        tokio::spawn(async move {
+            let _ = tokio::io::copy_bidirectional(&mut socket, &mut stream).await;
+        });
+    }
+}
+
+pub fn features_from_args(args: &Args) -> Features {
+    Features {
+        compress: args.flag("--compress"),
+        verify: args.flag("--verify"),
+    }
+}
+
+pub fn encrypt_from_args(args: &Args) -> bool {
+    !args.flag("--no-encrypt")
+}
+
+pub fn relay_from_args(args: &Args) -> Result<&str, String> {
+    args.value("--relay")
+        .ok_or_else(|| "pairing requires --relay <host:port>".to_string())
+}
+
+pub const COMMAND: Command = Command {
+    name: "pair",
+    usage: "netcore pair [code]",
+    about: "Pair with another machine through a relay and tunnel between them",
+    groups: &[OPTS, PAIR_OPTS, outbound::OPTS, timeouts::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let relay = cli::or_exit(relay_from_args(&args));
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+    let code = args.positional().first().map(String::as_str);
+
+    let listener = match args.value("--listen") {
+        Some(addr) => match TcpListener::bind(addr).await {
+            Ok(listener) => Some(listener),
+            Err(e) => {
+                eprintln!("Failed to listen on {}: {}", addr, e);
+                std::process::exit(exit::code(&e));
+            }
+        },
+        None => None,
+    };
+    let target = args.value("--expose").map(str::to_string);
+    if listener.is_none() && target.is_none() {
+        eprintln!("pair requires --expose <host:port>, --listen <addr>, or both");
+        std::process::exit(exit::USAGE);
+    }
+
+    let features = features_from_args(&args);
+    let encrypt = encrypt_from_args(&args);
+    let (mux, mut incoming, peer) =
+        match connect(&outbound, relay, code, "netcore pair", features, encrypt).await {
+            Ok(paired) => paired,
+            Err(e) => {
+                eprintln!("Pairing failed: {}", e);
+                std::process::exit(exit::code(&e));
+            }
+        };
+    say!(
+        "Paired with {} (addresses {})",
+        peer.observed,
+        peer.addresses
+    );
+
+    let serve_incoming = async {
+        match target {
+            Some(target) => expose(incoming, target, outbound).await,
+            None => while incoming.accept().await.is_some() {},
+        }
+    };
+    match listener {
+        Some(listener) => tokio::select! {
+            _ = listen(&mux, listener) => {}
+            _ = serve_incoming => {}
+        },
+        None => serve_incoming.await,
+    }
+    say!("Paired session ended");
+    if features.compress {
+        say!("Compression: {}", mux.compression());
+    }
+    if features.verify {
+        say!("Integrity: {}", mux.integrity());
+    }
+}
@@ -0,0 +1,1078 @@
+// An encrypted UDP tunnel between netcore instances, so forwarded test
+// traffic between two LANs isn't readable on the way. Each side has an
+// identity key (`netcore keygen`) and lists the others' public keys:
+//
+//   [tunnel]
+//   listen = 0.0.0.0:51820
+//   key = /etc/netcore/identity_ed25519
+//
+//   [peer office]
+//   key = ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAI... netcore
+//   endpoint = office.example.net:51820
+//   forward = 127.0.0.1:15432 db.office.lan:5432
+//   allow = printer.lan:631
+//
+// A `forward` listens locally and carries each connection to the peer,
+// which connects to the target if its own section for this side `allow`s
+// it. Only a side with an `endpoint` for the other starts the handshake;
+// the other learns where it is from the handshake, and follows it when its
+// address changes.
+//
+// Sessions are keyed with Noise IK (see noise.rs), in the WireGuard mold:
+// an initiation carries a timestamp that must be later than the last one
+// from that peer, and each data packet a counter, which is the nonce and
+// is checked against a window of recent ones. Inside, a small
+// retransmitting link (cumulative acks, fast retransmit after three
+// duplicate acks, a backed-off timeout) turns the packets into a byte
+// stream for the same multiplexer the relay uses. There is no congestion
+// control past the fixed window, and no rekeying: keys last as long as the
+// session, which the multiplexer's keepalive ends when the peer goes quiet.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, DuplexStream};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::{Notify, mpsc, watch};
+use tokio::time::{Duration, Instant, sleep, sleep_until, timeout};
+
+use crate::acl;
+use crate::aead;
+use crate::cli::{self, Command, Opt};
+use crate::config::Config;
+use crate::ed25519;
+use crate::exit;
+use crate::identity;
+use crate::noise::{self, Initiator, Keys, StaticKey};
+use crate::outbound::{self, OutboundConfig};
+use crate::relay::{self, KEEPALIVE_SECS, KEEPALIVE_TIMEOUT_SECS};
+use crate::say;
+use crate::timeouts;
+use crate::tunnel::{self, Features, Incoming, Keepalive, Mux};
+
+const PROLOGUE: &[u8] = b"netcore peer tunnel";
+const INITIATION: u8 = 1;
+const RESPONSE: u8 = 2;
+const DATA: u8 = 3;
+// Seconds and nanoseconds, big-endian so they compare as bytes.
+const TIMESTAMP_LEN: usize = 12;
+// Data per packet, so one fits a 1280-byte IPv6 MTU with the headers.
+const SEGMENT: usize = 1200;
+const MAX_DATAGRAM: usize = 2048;
+// Segments sent and not yet acknowledged, and received ahead of a gap.
+const WINDOW: usize = 256;
+const DUPLICATE_ACKS: u32 = 3;
+const MIN_RTO: Duration = Duration::from_millis(200);
+const MAX_RTO: Duration = Duration::from_secs(5);
+const INBOUND_QUEUE: usize = 1024;
+const BUFFER: usize = 256 * 1024;
+const MIN_RETRY_SECS: u64 = 1;
+const MAX_RETRY_SECS: u64 = 60;
+
+const TUNNEL_KEYS: &[&str] = &["listen", "key"];
+const PEER_KEYS: &[&str] = &["key", "endpoint", "forward", "allow"];
+
+const OPTS: &[Opt] = &[Opt {
+    name: "--config",
+    value: Some("<path>"),
+    help: "Configuration file with a [tunnel] section and [peer NAME] sections",
+}];
+
+struct PeerConfig {
+    name: String,
+    // The peer's identity as an X25519 key.
+    public: [u8; 32],
+    endpoint: Option<String>,
+    forwards: Vec<(SocketAddr, String)>,
+    allow: Vec<String>,
+}
+
+struct Settings {
+    listen: SocketAddr,
+    key: PathBuf,
+    peers: Vec<PeerConfig>,
+}
+
+fn host_port(value: &str) -> Result<String, String> {
+    match value.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+            Ok(value.to_string())
+        }
+        _ => Err("expected host:port".to_string()),
+    }
+}
+
+fn forward(value: &str) -> Result<(SocketAddr, String), String> {
+    let (listen, target) = value
+        .split_once(char::is_whitespace)
+        .ok_or("expected '<listen addr> <host:port>'")?;
+    let listen = listen
+        .parse()
+        .map_err(|_| format!("'{}' is not an address", listen))?;
+    Ok((listen, host_port(target.trim())?))
+}
+
+fn settings(config: &Config) -> Result<Settings, String> {
+    let tunnel = config
+        .section("tunnel", TUNNEL_KEYS)?
+        .ok_or_else(|| format!("{}: missing [tunnel] section", config.path.display()))?;
+    tunnel.require(config, "listen")?;
+    let listen = tunnel
+        .parsed(config, "listen")?
+        .unwrap_or(([0; 4], 0).into());
+    let key = tunnel
+        .get("key")
+        .map(PathBuf::from)
+        .unwrap_or_else(identity::default_path);
+
+    let mut peers: Vec<PeerConfig> = Vec::new();
+    for section in config.sections("peer") {
+        if let Some(entry) = section
+            .entries
+            .iter()
+            .find(|entry| !PEER_KEYS.contains(&entry.key.as_str()))
+        {
+            return Err(config.error(
+                entry.line,
+                &format!(
+                    "unknown key '{}' in [peer {}], expected {}",
+                    entry.key,
+                    section.name,
+                    PEER_KEYS.join(", ")
+                ),
+            ));
+        }
+        section.require(config, "key")?;
+        let public = section
+            .parsed_with(config, "key", |v| {
+                identity::parse_public(v)
+                    .and_then(|key| ed25519::to_x25519(&key))
+                    .ok_or_else(|| "not an ssh-ed25519 public key".to_string())
+            })?
+            .unwrap_or_default();
+        if let Some(other) = peers.iter().find(|peer| peer.public == public) {
+            return Err(config.error(
+                section.line,
+                &format!(
+                    "[peer {}] has the same key as [peer {}]",
+                    section.name, other.name
+                ),
+            ));
+        }
+        peers.push(PeerConfig {
+            name: section.name.clone(),
+            public,
+            endpoint: section.parsed_with(config, "endpoint", host_port)?,
+            forwards: section.parsed_all_with(config, "forward", forward)?,
+            allow: section.parsed_all_with(config, "allow", host_port)?,
+        });
+    }
+    if peers.is_empty() {
+        return Err(format!(
+            "{}: peer-tunnel needs at least one [peer NAME] section",
+            config.path.display()
+        ));
+    }
+
+    Ok(Settings { listen, key, peers })
+}
+
+// Which packet counters have been seen: the highest so far, and the 64
+// below it as bits. Anything older is refused.
+#[derive(Default)]
+struct Replay {
+    next: u64,
+    seen: u64,
+}
+
+impl Replay {
+    fn check(&mut self, counter: u64) -> bool {
+        if counter >= self.next {
+            let shift = counter - self.next + 1;
+            self.seen = if shift >= 64 { 0 } else { self.seen << shift };
+            self.seen |= 1;
+            self.next = counter + 1;
+            return true;
+        }
+        let back = self.next - 1 - counter;
+        if back >= 64 || self.seen & (1 << back) != 0 {
+            return false;
+        }
+        self.seen |= 1 << back;
+        true
+    }
+}
+
+struct Sent {
+    data: Vec<u8>,
+    at: Instant,
+}
+
+// Both directions of the retransmitting link, counted in segments. An ack
+// is the next segment expected, so everything before it has arrived.
+struct Link {
+    next: u64,
+    unacked: BTreeMap<u64, Sent>,
+    acked: u64,
+    duplicates: u32,
+    rto: Duration,
+    expected: u64,
+    early: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Link {
+    fn new() -> Link {
+        Link {
+            next: 0,
+            unacked: BTreeMap::new(),
+            acked: 0,
+            duplicates: 0,
+            rto: MIN_RTO,
+            expected: 0,
+            early: BTreeMap::new(),
+        }
+    }
+
+    fn has_room(&self) -> bool {
+        self.unacked.len() < WINDOW
+    }
+
+    fn push(&mut self, data: Vec<u8>, now: Instant) -> u64 {
+        let seq = self.next;
+        self.next += 1;
+        self.unacked.insert(seq, Sent { data, at: now });
+        seq
+    }
+
+    // Takes an ack from the peer; the segment to send again, if this is the
+    // third time a bare ack has asked for it.
+    fn ack(&mut self, ack: u64, bare: bool) -> Option<(u64, Vec<u8>)> {
+        if ack > self.acked && ack <= self.next {
+            self.acked = ack;
+            self.unacked = self.unacked.split_off(&ack);
+            self.duplicates = 0;
+            self.rto = MIN_RTO;
+        } else if ack == self.acked && bare && !self.unacked.is_empty() {
+            self.duplicates += 1;
+            if self.duplicates == DUPLICATE_ACKS {
+                let sent = self.unacked.get(&ack)?;
+                return Some((ack, sent.data.clone()));
+            }
+        }
+        None
+    }
+
+    // When the oldest segment out will have waited too long.
+    fn deadline(&self) -> Option<Instant> {
+        let (_, sent) = self.unacked.first_key_value()?;
+        Some(sent.at + self.rto)
+    }
+
+    // Everything unacknowledged, to send again, once the deadline passes;
+    // each time the wait doubles.
+    fn expired(&mut self, now: Instant) -> Vec<(u64, Vec<u8>)> {
+        if self.deadline().is_none_or(|deadline| deadline > now) {
+            return Vec::new();
+        }
+        self.rto = (self.rto * 2).min(MAX_RTO);
+        self.unacked
+            .iter_mut()
+            .map(|(seq, sent)| {
+                sent.at = now;
+                (*seq, sent.data.clone())
+            })
+            .collect()
+    }
+
+    // Takes a segment from the peer; what can now be passed on, in order.
+    fn receive(&mut self, seq: u64, data: Vec<u8>) -> Vec<Vec<u8>> {
+        if seq < self.expected || seq >= self.expected + WINDOW as u64 {
+            return Vec::new();
+        }
+        self.early.insert(seq, data);
+        let mut ready = Vec::new();
+        while let Some(data) = self.early.remove(&self.expected) {
+            ready.push(data);
+            self.expected += 1;
+        }
+        ready
+    }
+}
+
+struct Session {
+    index: u32,
+    // The peer's index for the session, which packets to it carry.
+    remote: u32,
+    peer: usize,
+    keys: Keys,
+    counter: AtomicU64,
+    replay: Mutex<Replay>,
+    addr: Mutex<SocketAddr>,
+    inbound: mpsc::Sender<Vec<u8>>,
+    stop: Notify,
+    mux: Mux,
+}
+
+struct Peer {
+    config: PeerConfig,
+    session: watch::Sender<Option<Arc<Session>>>,
+    timestamp: Mutex<[u8; TIMESTAMP_LEN]>,
+}
+
+pub struct Tunnel {
+    socket: UdpSocket,
+    key: StaticKey,
+    identity: [u8; 32],
+    peers: Vec<Peer>,
+    sessions: Mutex<HashMap<u32, Arc<Session>>>,
+    pending: Mutex<HashMap<u32, (usize, Initiator)>>,
+    outbound: OutboundConfig,
+    keepalive: Keepalive,
+}
+
+fn timestamp() -> [u8; TIMESTAMP_LEN] {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let mut timestamp = [0; TIMESTAMP_LEN];
+    timestamp[..8].copy_from_slice(&now.as_secs().to_be_bytes());
+    timestamp[8..].copy_from_slice(&now.subsec_nanos().to_be_bytes());
+    timestamp
+}
+
+fn index_at(packet: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(packet.get(at..at + 4)?.try_into().ok()?))
+}
+
+// Binds the socket and the forwards, and starts handshaking with the peers
+// it has endpoints for.
+pub async fn start(config: &Config, outbound: OutboundConfig) -> Result<Arc<Tunnel>, String> {
+    let settings = settings(config)?;
+    let keypair = identity::read(&settings.key)?;
+    let socket = UdpSocket::bind(settings.listen)
+        .await
+        .map_err(|e| format!("failed to listen on {}: {}", settings.listen, e))?;
+    let mut listeners = Vec::new();
+    for (peer, config) in settings.peers.iter().enumerate() {
+        for (listen, target) in &config.forwards {
+            let listener = TcpListener::bind(listen)
+                .await
+                .map_err(|e| format!("failed to listen on {}: {}", listen, e))?;
+            listeners.push((peer, listener, target.clone()));
+        }
+    }
+
+    let tunnel = Arc::new(Tunnel {
+        socket,
+        key: StaticKey::new(keypair.x25519_secret()),
+        identity: keypair.public,
+        peers: settings
+            .peers
+            .into_iter()
+            .map(|config| Peer {
+                config,
+                session: watch::Sender::new(None),
+                timestamp: Mutex::new([0; TIMESTAMP_LEN]),
+            })
+            .collect(),
+        sessions: Mutex::new(HashMap::new()),
+        pending: Mutex::new(HashMap::new()),
+        outbound,
+        keepalive: Keepalive {
+            interval: Duration::from_secs(KEEPALIVE_SECS),
+            timeout: Duration::from_secs(KEEPALIVE_TIMEOUT_SECS),
+        },
+    });
+
+    tokio::spawn(receive(tunnel.clone()));
+    for (peer, config) in tunnel.peers.iter().enumerate() {
+        if config.config.endpoint.is_some() {
+            tokio::spawn(connect(tunnel.clone(), peer));
+        }
+    }
+    for (peer, listener, target) in listeners {
+        tokio::spawn(accept(tunnel.clone(), peer, listener, target));
+    }
+    Ok(tunnel)
+}
+
+impl Tunnel {
+    pub fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    pub fn fingerprint(&self) -> String {
+        identity::fingerprint(&self.identity)
+    }
+
+    fn new_index(&self) -> u32 {
+        let sessions = self.sessions.lock().unwrap();
+        let pending = self.pending.lock().unwrap();
+        loop {
+            let index = rand::random();
+            if !sessions.contains_key(&index) && !pending.contains_key(&index) {
+                return index;
+            }
+        }
+    }
+
+    // An endpoint's address as this socket can send to it.
+    async fn resolve(&self, endpoint: &str) -> Result<SocketAddr, String> {
+        let local = self.local_addr().map_err(|e| e.to_string())?;
+        let addrs = self
+            .outbound
+            .resolve(endpoint)
+            .await
+            .map_err(|e| format!("failed to resolve {}: {}", endpoint, e))?;
+        addrs
+            .into_iter()
+            .find_map(|addr| match (local.ip(), addr.ip()) {
+                (IpAddr::V4(_), IpAddr::V4(_)) | (IpAddr::V6(_), IpAddr::V6(_)) => Some(addr),
+                (IpAddr::V6(_), IpAddr::V4(ip)) => {
+                    Some(SocketAddr::new(ip.to_ipv6_mapped().into(), addr.port()))
+                }
+                (IpAddr::V4(_), IpAddr::V6(_)) => None,
+            })
+            .ok_or_else(|| {
+                format!(
+                    "no {} address for {}",
+                    outbound::family(local.ip()),
+                    endpoint
+                )
+            })
+    }
+
+    // Sends the first handshake message; the index the answer will carry.
+    async fn initiate(&self, peer: usize) -> Result<u32, String> {
+        let config = &self.peers[peer].config;
+        let addr = self
+            .resolve(config.endpoint.as_deref().unwrap_or_default())
+            .await?;
+        let (initiator, message) =
+            noise::initiate(&self.key, &config.public, PROLOGUE, &timestamp())
+                .ok_or("the peer's key is unusable")?;
+        let index = self.new_index();
+        let mut packet = vec![INITIATION];
+        packet.extend(index.to_le_bytes());
+        packet.extend(message);
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(index, (peer, initiator));
+        self.socket
+            .send_to(&packet, addr)
+            .await
+            .map_err(|e| format!("failed to send to {}: {}", addr, e))?;
+        Ok(index)
+    }
+
+    async fn initiation(self: &Arc<Self>, packet: &[u8], from: SocketAddr) -> Option<()> {
+        let sender = index_at(packet, 1)?;
+        let handshake = noise::receive(&self.key, PROLOGUE, &packet[5..])?;
+        let peer = self
+            .peers
+            .iter()
+            .position(|peer| peer.config.public == handshake.remote)?;
+        let config = &self.peers[peer].config;
+        let timestamp: [u8; TIMESTAMP_LEN] = handshake.payload.as_slice().try_into().ok()?;
+        {
+            let mut last = self.peers[peer].timestamp.lock().unwrap();
+            if timestamp <= *last {
+                return None;
+            }
+            *last = timestamp;
+        }
+
+        // When both sides start a handshake at once, the one from the lower
+        // key goes ahead and the other is dropped.
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if let Some(&ours) = pending
+                .iter()
+                .find(|(_, (p, _))| *p == peer)
+                .map(|(i, _)| i)
+            {
+                if self.key.public < config.public {
+                    return None;
+                }
+                pending.remove(&ours);
+            }
+        }
+
+        let index = self.new_index();
+        let (message, keys) = handshake.reply(&[])?;
+        let mut reply = vec![RESPONSE];
+        reply.extend(index.to_le_bytes());
+        reply.extend(sender.to_le_bytes());
+        reply.extend(message);
+        self.socket.send_to(&reply, from).await.ok()?;
+        self.establish(peer, index, sender, keys, from, false);
+        Some(())
+    }
+
+    fn response(self: &Arc<Self>, packet: &[u8], from: SocketAddr) -> Option<()> {
+        let sender = index_at(packet, 1)?;
+        let receiver = index_at(packet, 5)?;
+        let (peer, initiator) = self.pending.lock().unwrap().remove(&receiver)?;
+        let (keys, _) = initiator.finish(&packet[9..])?;
+        self.establish(peer, receiver, sender, keys, from, true);
+        Some(())
+    }
+
+    fn data(&self, packet: &[u8], from: SocketAddr) -> Option<()> {
+        let receiver = index_at(packet, 1)?;
+        let counter = u64::from_le_bytes(packet.get(5..13)?.try_into().ok()?);
+        let session = self.sessions.lock().unwrap().get(&receiver)?.clone();
+        let plaintext = aead::open(
+            &session.keys.receive,
+            &noise::nonce(counter),
+            &[],
+            &packet[13..],
+        )?;
+        if !session.replay.lock().unwrap().check(counter) {
+            return None;
+        }
+        *session.addr.lock().unwrap() = from;
+        // Like any other loss when the link is behind.
+        let _ = session.inbound.try_send(plaintext);
+        Some(())
+    }
+
+    fn establish(
+        self: &Arc<Self>,
+        peer: usize,
+        index: u32,
+        remote: u32,
+        keys: Keys,
+        addr: SocketAddr,
+        initiator: bool,
+    ) {
+        let (ours, theirs) = tokio::io::duplex(BUFFER);
+        let (mux, incoming) =
+            tunnel::session(theirs, initiator, self.keepalive, Features::default());
+        let (inbound, packets) = mpsc::channel(INBOUND_QUEUE);
+        let session = Arc::new(Session {
+            index,
+            remote,
+            peer,
+            keys,
+            counter: AtomicU64::new(0),
+            replay: Mutex::new(Replay::default()),
+            addr: Mutex::new(addr),
+            inbound,
+            stop: Notify::new(),
+            mux,
+        });
+        self.sessions.lock().unwrap().insert(index, session.clone());
+        say!(
+            "Tunnel to '{}' up at {}",
+            self.peers[peer].config.name,
+            addr
+        );
+        if let Some(old) = self.peers[peer].session.send_replace(Some(session.clone())) {
+            old.stop.notify_one();
+        }
+        tokio::spawn(carry(self.clone(), session, ours, packets));
+        tokio::spawn(serve(self.clone(), peer, incoming));
+    }
+
+    async fn send(&self, session: &Session, ack: u64, seq: u64, data: &[u8]) {
+        let counter = session.counter.fetch_add(1, Ordering::Relaxed);
+        let mut plaintext = Vec::with_capacity(16 + data.len());
+        plaintext.extend(ack.to_le_bytes());
+        plaintext.extend(seq.to_le_bytes());
+        plaintext.extend_from_slice(data);
+        let mut packet = vec![DATA];
+        packet.extend(session.remote.to_le_bytes());
+        packet.extend(counter.to_le_bytes());
+        packet.extend(aead::seal(
+            &session.keys.send,
+            &noise::nonce(counter),
+            &[],
+            &plaintext,
+        ));
+        let addr = *session.addr.lock().unwrap();
+        let _ = self.socket.send_to(&packet, addr).await;
+    }
+}
+
+async fn receive(tunnel: Arc<Tunnel>) {
+    let mut buf = vec![0; MAX_DATAGRAM];
+    loop {
+        let (len, from) = match tunnel.socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("Tunnel receive error: {}", e);
+                sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        if acl::check(from).is_some() {
+            continue;
+        }
+        let packet = &buf[..len];
+        match packet[0] {
+            INITIATION => tunnel.initiation(packet, from).await,
+            RESPONSE => tunnel.response(packet, from),
+            DATA => tunnel.data(packet, from),
+            _ => None,
+        };
+    }
+}
+
+// Keeps a session up with a peer this side has the endpoint of.
+async fn connect(tunnel: Arc<Tunnel>, peer: usize) {
+    let mut session = tunnel.peers[peer].session.subscribe();
+    let mut retry = MIN_RETRY_SECS;
+    loop {
+        if session.wait_for(Option::is_none).await.is_err() {
+            return;
+        }
+        match tunnel.initiate(peer).await {
+            Ok(index) => {
+                let up = timeout(timeouts::get().handshake, session.wait_for(Option::is_some))
+                    .await
+                    .is_ok_and(|up| up.is_ok());
+                tunnel.pending.lock().unwrap().remove(&index);
+                if up {
+                    retry = MIN_RETRY_SECS;
+                    continue;
+                }
+            }
+            Err(e) => eprintln!("Tunnel to '{}': {}", tunnel.peers[peer].config.name, e),
+        }
+        sleep(Duration::from_secs(retry)).await;
+        retry = (retry * 2).min(MAX_RETRY_SECS);
+    }
+}
+
+// Moves the session's byte stream over its packets until either side
+// closes, then forgets the session.
+async fn carry(
+    tunnel: Arc<Tunnel>,
+    session: Arc<Session>,
+    io: DuplexStream,
+    mut packets: mpsc::Receiver<Vec<u8>>,
+) {
+    let (mut reader, mut writer) = tokio::io::split(io);
+    let mut link = Link::new();
+    let mut buf = vec![0; SEGMENT];
+    'carry: loop {
+        let deadline = link.deadline();
+        tokio::select! {
+            _ = session.stop.notified() => break,
+            packet = packets.recv() => {
+                let Some(packet) = packet else { break };
+                if packet.len() < 16 {
+                    continue;
+                }
+                let ack = u64::from_le_bytes(packet[..8].try_into().unwrap());
+                let seq = u64::from_le_bytes(packet[8..16].try_into().unwrap());
+                let data = &packet[16..];
+                if let Some((seq, data)) = link.ack(ack, data.is_empty()) {
+                    tunnel.send(&session, link.expected, seq, &data).await;
+                }
+                if !data.is_empty() {
+                    for ready in link.receive(seq, data.to_vec()) {
+                        if writer.write_all(&ready).await.is_err() {
+                            break 'carry;
+                        }
+                    }
+                    tunnel.send(&session, link.expected, 0, &[]).await;
+                }
+            }
+            read = reader.read(&mut buf), if link.has_room() => match read {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    let seq = link.push(buf[..n].to_vec(), Instant::now());
+                    tunnel.send(&session, link.expected, seq, &buf[..n]).await;
+                }
+            },
+            _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                for (seq, data) in link.expired(Instant::now()) {
+                    tunnel.send(&session, link.expected, seq, &data).await;
+                }
+            }
+        }
+    }
+
+    tunnel.sessions.lock().unwrap().remove(&session.index);
+    let peer = &tunnel.peers[session.peer];
+    let ended = peer.session.send_if_modified(|current| {
+        let ours = current
+            .as_ref()
+            .is_some_and(|current| current.index == session.index);
+        if ours {
+            *current = None;
+        }
+        ours
+    });
+    if ended {
+        say!("Tunnel to '{}' down", peer.config.name);
+    }
+}
+
+// Streams the peer opens: a line naming the target, then the data.
+async fn serve(tunnel: Arc<Tunnel>, peer: usize, mut incoming: Incoming) {
+    while let Some(stream) = incoming.accept().await {
+        tokio::spawn(open(tunnel.clone(), peer, stream));
+    }
+}
+
+async fn open(tunnel: Arc<Tunnel>, peer: usize, stream: DuplexStream) {
+    let config = &tunnel.peers[peer].config;
+    let mut stream = BufReader::new(stream);
+    let Some(target) = relay::read_line_within(&mut stream, timeouts::get().read).await else {
+        return;
+    };
+    if !config.allow.contains(&target) {
+        say!("Refusing '{}' for '{}': not allowed", target, config.name);
+        return;
+    }
+    let mut upstream =
+        match timeout(timeouts::get().connect, tunnel.outbound.connect(&target)).await {
+            Ok(Ok(upstream)) => upstream,
+            Ok(Err(e)) => {
+                eprintln!(
+                    "Failed to connect to {} for '{}': {}",
+                    target, config.name, e
+                );
+                return;
+            }
+            Err(_) => {
+                eprintln!("Timed out connecting to {} for '{}'", target, config.name);
+                return;
+            }
+        };
+    let _ = tokio::io::copy_bidirectional(&mut stream, &mut upstream).await;
+}
+
+async fn accept(tunnel: Arc<Tunnel>, peer: usize, listener: TcpListener, target: String) {
+    loop {
+        match listener.accept().await {
+            Ok((_, addr)) if acl::check(addr).is_some() => {}
+            Ok((socket, _)) => {
+                tokio::spawn(through(tunnel.clone(), peer, socket, target.clone()));
+            }
+            Err(e) => eprintln!("Tunnel forward accept error: {}", e),
+        }
+    }
+}
+
+async fn through(tunnel: Arc<Tunnel>, peer: usize, mut socket: TcpStream, target: String) {
+    let config = &tunnel.peers[peer].config;
+    let mut session = tunnel.peers[peer].session.subscribe();
+    let session = match timeout(timeouts::get().connect, session.wait_for(Option::is_some)).await {
+        Ok(Ok(session)) => session.clone().unwrap(),
+        _ => {
+            eprintln!("No tunnel to '{}' for {}", config.name, target);
+            return;
+        }
+    };
+    let Ok(mut stream) = session.mux.open().await else {
+        return;
+    };
+    if stream
+        .write_all(format!("{}\n", target).as_bytes())
+        .await
+        .is_err()
+    {
+        return;
+    }
+    let _ = tokio::io::copy_bidirectional(&mut socket, &mut stream).await;
+}
+
+pub const COMMAND: Command = Command {
+    name: "peer-tunnel",
+    usage: "netcore peer-tunnel --config <path>",
+    about: "Carry forwarded connections to other netcore instances over an encrypted UDP tunnel",
+    groups: &[OPTS, outbound::OPTS, acl::OPTS, timeouts::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let Some(config) = cli::or_exit(Config::from_args(&args, &["tunnel", "timeouts"])) else {
+        eprintln!(
+            "peer-tunnel requires --config with a [tunnel] and at least one [peer NAME] section"
+        );
+        std::process::exit(exit::USAGE);
+    };
+    cli::or_exit(config.check_kinds(&["tunnel", "peer", "timeouts"]));
+    cli::or_exit(timeouts::init(&args, Some(&config)));
+    cli::or_exit(acl::init(&args).await);
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+
+    let tunnel = cli::or_exit(start(&config, outbound).await);
+    say!(
+        "Peer tunnel listening on {} as {} for {} peer(s)",
+        cli::or_exit(tunnel.local_addr().map_err(|e| e.to_string())),
+        tunnel.fingerprint(),
+        tunnel.peers.len()
+    );
+    std::future::pending::<()>().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn refuses_replayed_and_stale_counters() {
+        let mut replay = Replay::default();
+        for counter in [0, 2, 1, 70, 10, 69] {
+            assert!(replay.check(counter), "{}", counter);
+        }
+        // Seen already, or too far behind to tell.
+        for counter in [0, 1, 2, 70, 69, 6] {
+            assert!(!replay.check(counter), "{}", counter);
+        }
+        assert!(replay.check(7));
+        assert!(replay.check(500));
+        assert!(!replay.check(436));
+        assert!(replay.check(437));
+    }
+
+    #[test]
+    fn link_orders_acks_and_retransmits() {
+        let start = Instant::now();
+        let mut sender = Link::new();
+        let mut receiver = Link::new();
+        for byte in 0..4 {
+            assert_eq!(sender.push(vec![byte], start), byte as u64);
+        }
+
+        // Segment 1 is lost: 2 and 3 wait, and each asks again for 1.
+        assert_eq!(receiver.receive(0, vec![0]), vec![vec![0]]);
+        assert_eq!(sender.ack(receiver.expected, true), None);
+        assert!(receiver.receive(2, vec![2]).is_empty());
+        assert!(receiver.receive(3, vec![3]).is_empty());
+        assert!(receiver.receive(0, vec![0]).is_empty());
+        assert_eq!(sender.ack(1, true), None);
+        assert_eq!(sender.ack(1, false), None);
+        assert_eq!(sender.ack(1, true), None);
+        assert_eq!(sender.ack(1, true), Some((1, vec![1])));
+        assert_eq!(
+            receiver.receive(1, vec![1]),
+            vec![vec![1], vec![2], vec![3]]
+        );
+        assert_eq!(sender.ack(receiver.expected, true), None);
+        assert!(sender.unacked.is_empty());
+        assert_eq!(sender.deadline(), None);
+        assert!(receiver.receive(4 + WINDOW as u64, vec![9]).is_empty());
+        assert!(receiver.early.is_empty());
+
+        // Nothing acked: all of it goes again, and the wait doubles up to
+        // the limit.
+        sender.push(vec![4], start);
+        sender.push(vec![5], start);
+        assert!(sender.expired(start).is_empty());
+        let mut now = start + MIN_RTO;
+        assert_eq!(sender.expired(now), vec![(4, vec![4]), (5, vec![5])]);
+        assert_eq!(sender.deadline(), Some(now + MIN_RTO * 2));
+        for _ in 0..10 {
+            now += MAX_RTO;
+            sender.expired(now);
+        }
+        assert_eq!(sender.rto, MAX_RTO);
+        sender.ack(6, false);
+        assert_eq!(sender.rto, MIN_RTO);
+        // An ack for segments never sent is ignored.
+        assert_eq!(sender.ack(60, false), None);
+        assert_eq!(sender.acked, 6);
+    }
+
+    fn parse(text: &str) -> Result<Settings, String> {
+        settings(&Config::parse(Path::new("tunnel.conf"), text).unwrap())
+    }
+
+    #[test]
+    fn checks_the_config() {
+        let key = identity::public_line(&ed25519::Keypair::from_seed(&[1; 32]).public, "a");
+        let other = identity::public_line(&ed25519::Keypair::from_seed(&[2; 32]).public, "b");
+        let good = format!(
+            "[tunnel]\nlisten = 127.0.0.1:51820\n\
+             [peer a]\nkey = {}\nendpoint = a.example:51820\n\
+             forward = 127.0.0.1:1000 db:5432\nforward = [::1]:1001 web:80\n\
+             [peer b]\nkey = {}\nallow = printer:631\n",
+            key, other
+        );
+        let settings = parse(&good).unwrap();
+        assert_eq!(settings.peers.len(), 2);
+        assert_eq!(settings.peers[0].forwards[1].1, "web:80");
+        assert_eq!(settings.peers[1].allow, ["printer:631"]);
+        assert_eq!(settings.peers[1].endpoint, None);
+
+        let errors = [
+            (
+                format!("[tunnel]\nlisten = :1\n[peer a]\nkey = {}\n", key),
+                "tunnel.conf:2: invalid value ':1' for 'listen'",
+            ),
+            (
+                format!("[peer a]\nkey = {}\n", key),
+                "tunnel.conf: missing [tunnel] section",
+            ),
+            (
+                "[tunnel]\nlisten = 127.0.0.1:1\n".to_string(),
+                "tunnel.conf: peer-tunnel needs at least one [peer NAME] section",
+            ),
+            (
+                format!(
+                    "[tunnel]\nlisten = 127.0.0.1:1\n[peer a]\nkey = {}\nport = 1\n",
+                    key
+                ),
+                "tunnel.conf:5: unknown key 'port' in [peer a], expected key, endpoint, forward, allow",
+            ),
+            (
+                "[tunnel]\nlisten = 127.0.0.1:1\n[peer a]\nkey = ssh-rsa AAAA\n".to_string(),
+                "tunnel.conf:4: invalid value 'ssh-rsa AAAA' for 'key': not an ssh-ed25519 public key",
+            ),
+            (
+                format!(
+                    "[tunnel]\nlisten = 127.0.0.1:1\n[peer a]\nkey = {}\nallow = printer\n",
+                    key
+                ),
+                "tunnel.conf:5: invalid value 'printer' for 'allow': expected host:port",
+            ),
+            (
+                format!(
+                    "[tunnel]\nlisten = 127.0.0.1:1\n[peer a]\nkey = {}\nforward = db:5432\n",
+                    key
+                ),
+                "tunnel.conf:5: invalid value 'db:5432' for 'forward': expected '<listen addr> <host:port>'",
+            ),
+            (
+                format!(
+                    "[tunnel]\nlisten = 127.0.0.1:1\n[peer a]\nkey = {}\n[peer b]\nkey = {}\n",
+                    key, key
+                ),
+                "tunnel.conf:5: [peer b] has the same key as [peer a]",
+            ),
+        ];
+        for (text, error) in errors {
+            assert_eq!(parse(&text).err().unwrap(), error);
+        }
+    }
+
+    // Passes datagrams between `outside` and `inside`, dropping every
+    // fifth one each way.
+    async fn lossy(outside: UdpSocket, inside: SocketAddr) {
+        let relay = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        relay.connect(inside).await.unwrap();
+        let mut from = None;
+        let mut count = 0u64;
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let mut back = vec![0; MAX_DATAGRAM];
+        loop {
+            count += 1;
+            let pass = !count.is_multiple_of(5);
+            tokio::select! {
+                Ok((len, addr)) = outside.recv_from(&mut buf) => {
+                    from = Some(addr);
+                    if pass {
+                        let _ = relay.send(&buf[..len]).await;
+                    }
+                }
+                Ok(len) = relay.recv(&mut back) => {
+                    if let (Some(addr), true) = (from, pass) {
+                        let _ = outside.send_to(&back[..len], addr).await;
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn carries_forwards_over_a_lossy_path() {
+        let dir = std::env::temp_dir().join(format!("netcore-peertunnel-{}", std::process::id()));
+        let (near_key, far_key) = (dir.join("near"), dir.join("far"));
+        identity::write(&near_key, &[1; 32], "near").unwrap();
+        identity::write(&far_key, &[2; 32], "far").unwrap();
+        let near_public = identity::public_line(&ed25519::Keypair::from_seed(&[1; 32]).public, "");
+        let far_public = identity::public_line(&ed25519::Keypair::from_seed(&[2; 32]).public, "");
+
+        let echo = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let echo_addr = echo.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = echo.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = socket.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+
+        // The far side only allows the echo server, and doesn't know where
+        // the near side is.
+        let far = Config::parse(
+            Path::new("far.conf"),
+            &format!(
+                "[tunnel]\nlisten = 127.0.0.1:0\nkey = {}\n[peer near]\nkey = {}\nallow = {}\n",
+                far_key.display(),
+                near_public,
+                echo_addr
+            ),
+        )
+        .unwrap();
+        let far = start(&far, OutboundConfig::default()).await.unwrap();
+        let path = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = path.local_addr().unwrap();
+        tokio::spawn(lossy(path, far.local_addr().unwrap()));
+
+        let forward = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let refused = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let near = Config::parse(
+            Path::new("near.conf"),
+            &format!(
+                "[tunnel]\nlisten = 127.0.0.1:0\nkey = {}\n[peer far]\nkey = {}\n\
+                 endpoint = {}\nforward = {} {}\nforward = {} 127.0.0.1:1\n",
+                near_key.display(),
+                far_public,
+                endpoint,
+                forward,
+                echo_addr,
+                refused
+            ),
+        )
+        .unwrap();
+        let _near = start(&near, OutboundConfig::default()).await.unwrap();
+
+        let sent: Vec<u8> = (0..300_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut socket = TcpStream::connect(forward).await.unwrap();
+        let (mut reader, mut writer) = socket.split();
+        let mut received = Vec::new();
+        let ((), read) = tokio::join!(
+            async {
+                writer.write_all(&sent).await.unwrap();
+                writer.shutdown().await.unwrap();
+            },
+            timeout(Duration::from_secs(20), reader.read_to_end(&mut received))
+        );
+        assert!(read.is_ok(), "timed out after {} bytes", received.len());
+        assert!(
+            received == sent,
+            "{} of {} bytes came back",
+            received.len(),
+            sent.len()
+        );
+
+        // A target the far side doesn't allow is closed without a word.
+        let mut socket = TcpStream::connect(refused).await.unwrap();
+        let mut buf = Vec::new();
+        let read = timeout(Duration::from_secs(5), socket.read_to_end(&mut buf)).await;
+        assert!(matches!(read, Ok(Ok(0))));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+// Upstream connections opened ahead of time for --mux backends, so short
+// clients don't wait for a connect to the backend as well. A pooled
+// connection is handed out once and never comes back: what was said on it
+// isn't known, so it can't be given to anyone else.
+//
+// Backends close connections that sit unused for long, so ready ones are
+// replaced after --pool-lifetime, and checked for a close from the far
+// end every --pool-check and again just before being handed out.
+
+use socket2::SockRef;
+use std::collections::VecDeque;
+use std::io;
+use std::mem::MaybeUninit;
+use std::sync::{Arc, Mutex};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::{Duration, Instant, sleep, timeout};
+
+use crate::cli::{Args, Opt};
+use crate::outbound::OutboundConfig;
+use crate::say;
+use crate::timeouts;
+use crate::units::{self, SECS};
+
+const DEFAULT_LIFETIME_SECS: u64 = 60;
+const DEFAULT_CHECK_SECS: u64 = 5;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--pool-idle",
+        value: Some("<n>"),
+        help: "Keep this many connections to each --mux backend ready (default: 0, off)",
+    },
+    Opt {
+        name: "--pool-lifetime",
+        value: Some("<duration>"),
+        help: "Replace ready connections older than this (default: 60s)",
+    },
+    Opt {
+        name: "--pool-check",
+        value: Some("<duration>"),
+        help: "How often to check that ready connections are still open (default: 5s)",
+    },
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Settings {
+    pub idle: usize,
+    pub lifetime: Duration,
+    pub check: Duration,
+}
+
+impl Settings {
+    // None unless --pool-idle asks for connections.
+    pub fn from_args(args: &Args) -> Result<Option<Settings>, String> {
+        let idle = args.parsed("--pool-idle")?.unwrap_or(0);
+        let lifetime = args
+            .parsed_with("--pool-lifetime", |v| units::duration(v, SECS))?
+            .unwrap_or(Duration::from_secs(DEFAULT_LIFETIME_SECS));
+        let check = args
+            .parsed_with("--pool-check", |v| units::duration(v, SECS))?
+            .unwrap_or(Duration::from_secs(DEFAULT_CHECK_SECS));
+        if check.is_zero() {
+            return Err("--pool-check must be positive".to_string());
+        }
+        Ok((idle > 0).then_some(Settings {
+            idle,
+            lifetime,
+            check,
+        }))
+    }
+}
+
+pub struct Pool {
+    target: String,
+    settings: Settings,
+    ready: Mutex<VecDeque<(TcpStream, Instant)>>,
+    taken: Notify,
+}
+
+impl Pool {
+    // Starts filling at once, so the first clients find connections ready.
+    pub fn start(target: &str, outbound: OutboundConfig, settings: Settings) -> Arc<Pool> {
+        let pool = Arc::new(Pool {
+            target: target.to_string(),
+            settings,
+            ready: Mutex::new(VecDeque::new()),
+            taken: Notify::new(),
+        });
+        tokio::spawn(fill(pool.clone(), outbound));
+        pool
+    }
+
+    // The oldest ready connection that is still usable. Any found closed
+    // or too old on the way are dropped.
+    pub fn take(&self) -> Option<TcpStream> {
+        let found = {
+            let mut ready = self.ready.lock().unwrap();
+            std::iter::from_fn(|| ready.pop_front())
+                .find(|(stream, opened)| self.usable(stream, *opened))
+                .map(|(stream, _)| stream)
+        };
+        self.taken.notify_one();
+        found
+    }
+
+    pub fn ready(&self) -> usize {
+        self.ready.lock().unwrap().len()
+    }
+
+    fn usable(&self, stream: &TcpStream, opened: Instant) -> bool {
+        opened.elapsed() < self.settings.lifetime && open(stream)
+    }
+}
+
+// Whether the backend has yet to close `stream`. Peeking leaves anything
+// it sent first, such as an SSH banner, for the client.
+fn open(stream: &TcpStream) -> bool {
+    let mut buf = [MaybeUninit::uninit(); 1];
+    match SockRef::from(stream).peek(&mut buf) {
+        Ok(0) => false,
+        Ok(_) => true,
+        Err(e) => e.kind() == io::ErrorKind::WouldBlock,
+    }
+}
+
+// Tops the pool up whenever a connection is taken, and checks on the
+// ready ones in between. A backend that can't be reached is only retried
+// every --pool-check, however many clients come by.
+async fn fill(pool: Arc<Pool>, outbound: OutboundConfig) {
+    let mut failing = false;
+    loop {
+        pool.ready
+            .lock()
+            .unwrap()
+            .retain(|(stream, opened)| pool.usable(stream, *opened));
+        while pool.ready() < pool.settings.idle {
+            let result = timeout(timeouts::get().connect, outbound.connect(&pool.target)).await;
+            match result {
+                Ok(Ok(stream)) => {
+                    if failing {
+                        say!("Pool for {} is connecting again", pool.target);
+                        failing = false;
+                    }
+                    pool.ready
+                        .lock()
+                        .unwrap()
+                        .push_back((stream, Instant::now()));
+                }
+                Ok(Err(_)) | Err(_) if failing => break,
+                Ok(Err(e)) => {
+                    eprintln!("Pool for {} cannot connect: {}", pool.target, e);
+                    failing = true;
+                    break;
+                }
+                Err(_) => {
+                    eprintln!("Pool for {} cannot connect: timed out", pool.target);
+                    failing = true;
+                    break;
+                }
+            }
+        }
+        if failing {
+            sleep(pool.settings.check).await;
+        } else {
+            tokio::select! {
+                _ = pool.taken.notified() => {}
+                _ = sleep(pool.settings.check) => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn hands_out_open_connections_and_refills() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        let settings = Settings {
+            idle: 2,
+            lifetime: Duration::from_secs(60),
+            check: Duration::from_secs(60),
+        };
+        let pool = Pool::start(&target, OutboundConfig::default(), settings);
+
+        let (mut first, _) = listener.accept().await.unwrap();
+        let (_second, _) = listener.accept().await.unwrap();
+        while pool.ready() < 2 {
+            tokio::task::yield_now().await;
+        }
+        // The backend hangs up on the oldest, so the next one is handed out.
+        first.shutdown().await.unwrap();
+        drop(first);
+        sleep(Duration::from_millis(50)).await;
+        assert!(pool.take().is_some());
+        assert_eq!(pool.ready(), 0);
+
+        // Both taken or dropped, so two more are opened.
+        let _third = listener.accept().await.unwrap();
+        let _fourth = listener.accept().await.unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+// `netcore config check`: everything serve would refuse to start over,
+// found before a (re)start instead of after it, and all of it at once rather
+// than the first problem only. It takes serve's options, reads the config
+// file and each section in it, loads the files they name (certificates,
+// client CAs, blocklists, GeoIP databases, SSH keys) and binds each port
+// serve would listen on. Ports a running netcore holds count as fine, as
+// they come free when it stops.
+
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+
+use crate::acl;
+use crate::alert::Alerts;
+use crate::auth::Auth;
+use crate::bandwidth::Bandwidth;
+use crate::beacon::Beacon;
+use crate::certs;
+use crate::cli::{self, Args, Command};
+use crate::config::Config;
+use crate::control;
+use crate::exit;
+use crate::geoip;
+use crate::json::Value;
+use crate::mux::MuxConfig;
+use crate::outbound::OutboundConfig;
+use crate::output;
+use crate::relay::RelayClient;
+use crate::revproxy::ReverseProxy;
+use crate::say;
+use crate::scheduler;
+use crate::server;
+use crate::ssh::SshTunnel;
+use crate::sshd::SshServer;
+use crate::timeouts::Timeouts;
+use crate::web::WebUi;
+
+// The sections serve reads from --config: one per handler, then the rest.
+pub const SECTIONS: &[&str] = &[
+    "echo", "mux", "http", "tls", "timeouts", "job", "alert", "smtp",
+];
+
+// Those without a name, which NETCORE_ variables can give with no file.
+pub const UNNAMED: &[&str] = &["echo", "mux", "http", "tls", "timeouts", "smtp"];
+
+struct Findings(Vec<(String, Result<String, String>)>);
+
+impl Findings {
+    fn add(&mut self, subject: &str, result: Result<String, String>) {
+        self.0.push((subject.to_string(), result));
+    }
+}
+
+fn bind(addr: SocketAddr, running: bool) -> Result<String, String> {
+    match TcpListener::bind(addr) {
+        Ok(_) => Ok(format!("{} is free", addr)),
+        Err(e) if e.kind() == ErrorKind::AddrInUse && running => Ok(format!(
+            "{} is in use, presumably by the running instance",
+            addr
+        )),
+        Err(e) => Err(format!("cannot listen on {}: {}", addr, e)),
+    }
+}
+
+// Serve takes the first free port of its range.
+fn serve_port(running: bool) -> Result<String, String> {
+    let ports = server::DEFAULT_PORTS;
+    let free = ports
+        .clone()
+        .find(|port| TcpListener::bind((Ipv4Addr::UNSPECIFIED, *port)).is_ok());
+    match free {
+        Some(port) => Ok(format!("would listen on port {}", port)),
+        None if running => Ok(format!(
+            "ports {}-{} are in use, presumably by the running instance",
+            ports.start(),
+            ports.end()
+        )),
+        None => Err(format!(
+            "ports {}-{} are all in use",
+            ports.start(),
+            ports.end()
+        )),
+    }
+}
+
+async fn check(args: &Args) -> Findings {
+    let mut findings = Findings(Vec::new());
+    let config = match Config::from_args(args, UNNAMED) {
+        Ok(Some(config)) => {
+            let mut detail = match args.value("--config") {
+                Some(path) => format!("{}: {} sections", path, config.sections.len()),
+                None => "no file".to_string(),
+            };
+            if !config.env.is_empty() {
+                detail += &format!(", overridden by {}", config.env.join(", "));
+            }
+            findings.add("config", Ok(detail));
+            Some(config)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            findings.add("config", Err(e));
+            None
+        }
+    };
+    let config = config.as_ref();
+
+    if let Some(config) = config {
+        for section in &config.sections {
+            if !SECTIONS.contains(&section.kind.as_str()) {
+                let subject = format!("[{}]", section.kind);
+                findings.add(&subject, Err(config.error(section.line, "unknown section")));
+            }
+        }
+    }
+    fn done<T>(_: T) -> String {
+        "ok".to_string()
+    }
+    fn used<T>(value: Option<T>) -> String {
+        match value {
+            Some(_) => "ok".to_string(),
+            None => "not used".to_string(),
+        }
+    }
+    findings.add(
+        "timeouts",
+        Timeouts::from_args(args, config)
+            .map(|timeouts| format!("connect {:?}, read {:?}", timeouts.connect, timeouts.read)),
+    );
+    findings.add("echo", server::init_echo(args, config).map(done));
+    let outbound = OutboundConfig::from_args(args);
+    findings.add(
+        "outbound",
+        outbound.as_ref().map(done).map_err(Clone::clone),
+    );
+    let outbound = outbound.unwrap_or_default();
+    findings.add(
+        "mux",
+        MuxConfig::from_args(args, config, outbound.clone()).map(used),
+    );
+    findings.add(
+        "http",
+        ReverseProxy::from_args(args, config, outbound).map(used),
+    );
+    findings.add(
+        "tls",
+        certs::from_args(args, config).map(|files| {
+            let count = files.map_or(0, |(files, _)| files.len());
+            format!("{} certificate files", count)
+        }),
+    );
+    if let Some(config) = config {
+        findings.add(
+            "jobs",
+            scheduler::jobs(config).map(|jobs| format!("{} jobs", jobs.len())),
+        );
+        findings.add("alerts", Alerts::from_config(config).map(done));
+    }
+    for (subject, result) in acl::preflight(args).await {
+        findings.add(&subject, result);
+    }
+    findings.add("geoip", geoip::init(args).map(done));
+    findings.add("bandwidth", Bandwidth::from_args(args).map(done));
+    findings.add("ssh tunnel", SshTunnel::from_args(args).map(used));
+    findings.add("relay", RelayClient::from_args(args).map(used));
+    findings.add("beacon", Beacon::from_args(args).map(used));
+
+    // A control socket that answers means serve is running now.
+    let running = !args.flag("--no-control")
+        && control::request(
+            &control::path_from_args(args),
+            &Value::object([("command", Value::from("status"))]),
+        )
+        .await
+        .is_ok();
+    if !args.flag("--no-control") {
+        let path = control::path_from_args(args);
+        let state = if running {
+            "in use by the running instance"
+        } else {
+            "free"
+        };
+        findings.add(
+            "control socket",
+            Ok(format!("{} is {}", path.display(), state)),
+        );
+    }
+    findings.add("serve port", serve_port(running));
+    let web = Auth::from_args(args).and_then(|auth| WebUi::from_args(args, auth));
+    match web {
+        Ok(Some(web)) => findings.add("web ui", bind(web.addr, running)),
+        Ok(None) => {}
+        Err(e) => findings.add("web ui", Err(e)),
+    }
+    match SshServer::from_args(args) {
+        Ok(Some(server)) => findings.add("ssh server", bind(server.addr, running)),
+        Ok(None) => {}
+        Err(e) => findings.add("ssh server", Err(e)),
+    }
+    findings
+}
+
+pub async fn command(tokens: Vec<String>, command: &Command) {
+    let args = cli::parse_command(command, tokens);
+    match args.positional().first().map(String::as_str) {
+        Some("check") if args.positional().len() == 1 => {}
+        _ => {
+            eprintln!("usage: {}", command.usage);
+            std::process::exit(exit::USAGE);
+        }
+    }
+
+    let Findings(findings) = check(&args).await;
+    let mut problems = 0;
+    for (subject, result) in &findings {
+        let (status, detail) = match result {
+            Ok(detail) => ("PASS", detail),
+            Err(e) => {
+                problems += 1;
+                ("FAIL", e)
+            }
+        };
+        say!("{}  {}: {}", status, subject, detail);
+        output::verdict(status, subject, detail);
+    }
+    if problems > 0 {
+        say!("{} problems found", problems);
+        std::process::exit(exit::USAGE);
+    }
+    say!("serve would start with this configuration");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli::Opt;
+
+    #[tokio::test]
+    async fn reports_every_problem_with_its_line() {
+        let path =
+            std::env::temp_dir().join(format!("netcore-preflight-{}.conf", std::process::id()));
+        std::fs::write(
+            &path,
+            "[echo]\nbuffer = 0\n\n[ecoh]\n\n[mux]\nhttp = 127.0.0.1:8080\n",
+        )
+        .unwrap();
+        let config = Opt {
+            name: "--config",
+            value: Some("<path>"),
+            help: "",
+        };
+        let tokens = ["--no-control", "--config", path.to_str().unwrap()];
+        let args =
+            Args::parse(tokens.map(String::from), &[&[config], control::SERVE_OPTS]).unwrap();
+        let Findings(findings) = check(&args).await;
+        std::fs::remove_file(&path).unwrap();
+
+        let problems: Vec<String> = findings
+            .iter()
+            .filter_map(|(_, result)| result.clone().err())
+            .map(|e| e.replace(path.to_str().unwrap(), "netcore.conf"))
+            .collect();
+        assert_eq!(
+            problems,
+            [
+                "netcore.conf:4: unknown section",
+                "netcore.conf:2: invalid value '0' for 'buffer': must be between 1 byte and 1M",
+            ]
+        );
+        let mux = findings
+            .iter()
+            .find(|(subject, _)| subject == "mux")
+            .unwrap();
+        assert_eq!(mux.1, Ok("ok".to_string()));
+    }
+}
@@ -0,0 +1,266 @@
+// Progress of long operations like scans and benchmarks. The operation
+// counts what it has done on a Tracker, which passes updates at most every
+// INTERVAL to a Progress: the CLI's is a bar on stderr, and embedders can
+// implement their own or take updates from a channel.
+
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::sync::mpsc;
+use tokio::time::{Duration, Instant};
+
+use crate::cli::{Args, Opt};
+use crate::output;
+use crate::top::format_bytes;
+
+const INTERVAL: Duration = Duration::from_millis(200);
+
+pub const OPTS: &[Opt] = &[Opt {
+    name: "--progress",
+    value: None,
+    help: "Show progress, rate and time left on stderr",
+}];
+
+// The bar is human text, so --quiet and --porcelain leave it out.
+pub fn from_args(args: &Args) -> Option<Arc<dyn Progress>> {
+    (args.flag("--progress") && output::human())
+        .then(|| Arc::new(Bar::default()) as Arc<dyn Progress>)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unit {
+    Items(&'static str),
+    Bytes,
+}
+
+#[derive(Clone, Debug)]
+pub struct Update {
+    pub operation: &'static str,
+    pub unit: Unit,
+    pub done: u64,
+    // What `done` is heading for, when that's known up front.
+    pub total: Option<u64>,
+    // For operations that run for a set time instead, like bench.
+    pub duration: Option<Duration>,
+    pub elapsed: Duration,
+    pub finished: bool,
+}
+
+impl Update {
+    // From 0 to 1, if there's anything to measure against.
+    pub fn fraction(&self) -> Option<f64> {
+        if self.finished {
+            return Some(1.0);
+        }
+        let fraction = match (self.total, self.duration) {
+            (Some(0), _) => 1.0,
+            (Some(total), _) => self.done as f64 / total as f64,
+            (None, Some(duration)) => self.elapsed.as_secs_f64() / duration.as_secs_f64(),
+            (None, None) => return None,
+        };
+        Some(fraction.min(1.0))
+    }
+
+    // Units done per second so far.
+    pub fn rate(&self) -> f64 {
+        self.done as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    pub fn eta(&self) -> Option<Duration> {
+        if self.finished {
+            return Some(Duration::ZERO);
+        }
+        if let Some(total) = self.total {
+            let rate = self.rate();
+            return (self.done > 0 && rate > 0.0)
+                .then(|| Duration::from_secs_f64(total.saturating_sub(self.done) as f64 / rate));
+        }
+        self.duration
+            .map(|duration| duration.saturating_sub(self.elapsed))
+    }
+}
+
+pub trait Progress: Send + Sync {
+    fn update(&self, update: &Update);
+}
+
+// For embedders who would rather read updates as a stream. Updates sent
+// after the receiver is dropped are discarded.
+impl Progress for mpsc::UnboundedSender<Update> {
+    fn update(&self, update: &Update) {
+        let _ = self.send(update.clone());
+    }
+}
+
+fn format_amount(unit: Unit, amount: f64) -> String {
+    match unit {
+        Unit::Items(name) => format!("{:.0} {}", amount, name),
+        Unit::Bytes => format_bytes(amount),
+    }
+}
+
+// Redraws one line on stderr, ending it when the operation finishes.
+#[derive(Default)]
+pub struct Bar {
+    drawn: Mutex<usize>,
+}
+
+impl Progress for Bar {
+    fn update(&self, update: &Update) {
+        const WIDTH: usize = 24;
+        let mut line = String::new();
+        if let Some(fraction) = update.fraction() {
+            let filled = (fraction * WIDTH as f64) as usize;
+            line.push_str(&format!(
+                "[{}{}] {:3.0}% ",
+                "#".repeat(filled),
+                " ".repeat(WIDTH - filled),
+                fraction * 100.0
+            ));
+        }
+        match (update.unit, update.total) {
+            (Unit::Items(name), Some(total)) => {
+                line.push_str(&format!("{} of {} {}", update.done, total, name))
+            }
+            (unit, Some(total)) => line.push_str(&format!(
+                "{} of {}",
+                format_amount(unit, update.done as f64),
+                format_amount(unit, total as f64)
+            )),
+            (unit, None) => line.push_str(&format_amount(unit, update.done as f64)),
+        }
+        line.push_str(&format!(
+            ", {}/s",
+            format_amount(update.unit, update.rate())
+        ));
+        if let Some(eta) = update.eta().filter(|_| !update.finished) {
+            line.push_str(&format!(", {} s left", eta.as_secs()));
+        }
+
+        let mut drawn = self.drawn.lock().unwrap();
+        let padding = drawn.saturating_sub(line.len());
+        *drawn = line.len();
+        let mut stderr = std::io::stderr().lock();
+        let _ = write!(stderr, "\r{}{}", line, " ".repeat(padding));
+        if update.finished {
+            let _ = writeln!(stderr);
+        }
+        let _ = stderr.flush();
+    }
+}
+
+pub struct Tracker {
+    progress: Arc<dyn Progress>,
+    operation: &'static str,
+    unit: Unit,
+    total: Option<u64>,
+    duration: Option<Duration>,
+    start: Instant,
+    done: AtomicU64,
+    reported: Mutex<Instant>,
+}
+
+impl Tracker {
+    pub fn new(
+        progress: Arc<dyn Progress>,
+        operation: &'static str,
+        unit: Unit,
+        total: Option<u64>,
+    ) -> Tracker {
+        let start = Instant::now();
+        Tracker {
+            progress,
+            operation,
+            unit,
+            total,
+            duration: None,
+            start,
+            done: AtomicU64::new(0),
+            reported: Mutex::new(start),
+        }
+    }
+
+    // For operations that end after `duration` rather than a set amount.
+    pub fn timed(
+        progress: Arc<dyn Progress>,
+        operation: &'static str,
+        unit: Unit,
+        duration: Duration,
+    ) -> Tracker {
+        Tracker {
+            duration: Some(duration),
+            ..Tracker::new(progress, operation, unit, None)
+        }
+    }
+
+    fn snapshot(&self, finished: bool) -> Update {
+        Update {
+            operation: self.operation,
+            unit: self.unit,
+            done: self.done.load(Ordering::Relaxed),
+            total: self.total,
+            duration: self.duration,
+            elapsed: self.start.elapsed(),
+            finished,
+        }
+    }
+
+    pub fn add(&self, n: u64) {
+        self.done.fetch_add(n, Ordering::Relaxed);
+        let now = Instant::now();
+        {
+            let mut reported = self.reported.lock().unwrap();
+            if now - *reported < INTERVAL {
+                return;
+            }
+            *reported = now;
+        }
+        self.progress.update(&self.snapshot(false));
+    }
+
+    pub fn finish(&self) {
+        self.progress.update(&self.snapshot(true));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates_are_spaced_out_and_estimate_what_is_left() {
+        let (sender, mut updates) = mpsc::unbounded_channel();
+        let tracker = Tracker::new(Arc::new(sender), "scan", Unit::Items("probes"), Some(100));
+        tracker.add(10);
+        assert!(updates.try_recv().is_err());
+
+        std::thread::sleep(INTERVAL);
+        tracker.add(15);
+        assert_eq!(updates.try_recv().unwrap().done, 25);
+        tracker.add(1);
+        assert!(updates.try_recv().is_err());
+
+        tracker.finish();
+        let update = updates.try_recv().unwrap();
+        assert!(update.finished);
+        assert_eq!(update.fraction(), Some(1.0));
+
+        let counted = Update {
+            done: 25,
+            elapsed: Duration::from_secs(1),
+            finished: false,
+            ..update
+        };
+        assert_eq!(counted.fraction(), Some(0.25));
+        assert_eq!(counted.eta(), Some(Duration::from_secs(3)));
+        let timed = Update {
+            total: None,
+            duration: Some(Duration::from_secs(10)),
+            elapsed: Duration::from_secs(4),
+            ..counted
+        };
+        assert_eq!(timed.fraction(), Some(0.4));
+        assert_eq!(timed.eta(), Some(Duration::from_secs(6)));
+    }
+}
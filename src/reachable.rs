@@ -0,0 +1,241 @@
+// Whether the internet can actually reach a port here: listens on it and
+// has a netcore relay connect back to the address it sees this machine
+// at, which is the public address after any NAT, so a port forward or
+// UPnP mapping is tested end to end. The relay's view is compared with
+// the public address lookup too; when they differ there is another NAT
+// in the way that nothing here can forward through.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+
+use crate::cli::{self, Command, Opt};
+use crate::exit;
+use crate::ipv6;
+use crate::json::Value;
+use crate::outbound::{self, OutboundConfig};
+use crate::output;
+use crate::relay;
+use crate::say;
+use crate::timeouts;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--port",
+        value: Some("<n>"),
+        help: "TCP port that should be reachable",
+    },
+    Opt {
+        name: "--relay",
+        value: Some("<host:port>"),
+        help: "netcore relay to connect back from",
+    },
+];
+
+// Accepts and drops connections to `port` on every address, so there is
+// something for a probe to reach. If the port is taken, something already
+// is. Both families are bound since [::] doesn't take IPv4 everywhere; a
+// machine without IPv6 only gets the IPv4 listener.
+pub async fn listen(port: u16) -> Result<(), exit::Error> {
+    for ip in [
+        IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        Ipv6Addr::UNSPECIFIED.into(),
+    ] {
+        match TcpListener::bind((ip, port)).await {
+            Ok(listener) => {
+                tokio::spawn(async move { while listener.accept().await.is_ok() {} });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse || ip.is_ipv6() => {}
+            Err(e) => {
+                return Err(exit::Error::io(
+                    format!("Failed to listen on port {}", port),
+                    e,
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Addresses a relay on the same network would see: reaching one of them
+// says nothing about the internet.
+fn is_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                // Carrier-grade NAT, 100.64.0.0/10.
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64)
+        }
+        IpAddr::V6(ip) => !ipv6::is_global_unicast(&ip),
+    }
+}
+
+pub enum Hairpin {
+    // The public address is this machine's own, so there is no NAT.
+    NoNat,
+    Works,
+    Broken(String),
+}
+
+// Connects to this machine's public address from inside the LAN. A router
+// that hairpins sends the connection back in through its port forward;
+// one that doesn't leaves peers on the LAN to use the local address.
+pub async fn hairpin(outbound: &OutboundConfig, public: SocketAddr, local: &[IpAddr]) -> Hairpin {
+    if local.contains(&public.ip()) {
+        return Hairpin::NoNat;
+    }
+    match timeout(timeouts::get().connect, outbound.connect_addr(public)).await {
+        Ok(Ok(_)) => Hairpin::Works,
+        Ok(Err(e)) => Hairpin::Broken(e.to_string()),
+        Err(_) => Hairpin::Broken("timed out".to_string()),
+    }
+}
+
+pub const COMMAND: Command = Command {
+    name: "reachable",
+    usage: "netcore reachable --port <n> --relay <host:port>",
+    about: "Check that a port is reachable from the internet",
+    groups: &[OPTS, outbound::OPTS, timeouts::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let (Some(port), Some(relay)) = (
+        cli::or_exit(args.parsed::<u16>("--port")),
+        args.value("--relay"),
+    ) else {
+        eprintln!("usage: {}", COMMAND.usage);
+        std::process::exit(exit::USAGE);
+    };
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+    exit::or_exit(listen(port).await);
+
+    let (probe, info) = tokio::join!(relay::probe(&outbound, relay, port), crate::get_host_info());
+    let probe = exit::or_exit(probe);
+    let target = SocketAddr::new(probe.observed, port);
+    let public = match probe.observed {
+        IpAddr::V4(_) => info.public_ipv4.map(IpAddr::V4),
+        IpAddr::V6(_) => info.public_ipv6.map(IpAddr::V6),
+    };
+
+    let (status, detail) = match &probe.result {
+        Ok(()) => ("PASS", format!("{} is reachable from {}", target, relay)),
+        Err(reason) => (
+            "FAIL",
+            format!("{} is not reachable from {}: {}", target, relay, reason),
+        ),
+    };
+    say!("{}  {}", status, detail);
+    output::emit(Value::object([
+        ("status", Value::from(status.to_ascii_lowercase())),
+        ("subject", Value::from("reachable")),
+        ("address", Value::from(target.to_string())),
+        ("public_ip", Value::from(public.map(|ip| ip.to_string()))),
+        ("detail", Value::from(detail)),
+    ]));
+
+    let mut warnings = Vec::new();
+    if is_local(probe.observed) {
+        warnings.push(format!(
+            "{} saw this machine at local address {}, so it isn't testing the internet path",
+            relay, probe.observed
+        ));
+    } else if let Some(public) = public.filter(|public| *public != probe.observed) {
+        warnings.push(format!(
+            "{} saw this machine at {} but the public address lookup says {}; \
+             there may be another NAT in the way",
+            relay, probe.observed, public
+        ));
+    }
+    for warning in &warnings {
+        say!("WARN  {}", warning);
+        output::verdict("WARN", "reachable", warning);
+    }
+
+    // Hairpinning can only be told apart from a broken forward once the
+    // forward is known to work from outside.
+    if probe.result.is_ok() && !is_local(probe.observed) {
+        let local: Vec<IpAddr> = [
+            info.local_ipv4.map(IpAddr::V4),
+            info.local_ipv6.map(IpAddr::V6),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let (status, supported, detail) = match hairpin(&outbound, target, &local).await {
+            Hairpin::NoNat => (
+                "PASS",
+                None,
+                format!(
+                    "{} is this machine's own address, no NAT in the way",
+                    probe.observed
+                ),
+            ),
+            Hairpin::Works => (
+                "PASS",
+                Some(true),
+                format!(
+                    "the router hairpins, so peers on this LAN can use {} too",
+                    target
+                ),
+            ),
+            Hairpin::Broken(reason) => {
+                let lan = local
+                    .iter()
+                    .find(|ip| ip.is_ipv4() == target.is_ipv4())
+                    .map_or("this machine's local address".to_string(), |ip| {
+                        SocketAddr::new(*ip, port).to_string()
+                    });
+                (
+                    "WARN",
+                    Some(false),
+                    format!(
+                        "the router doesn't hairpin ({}), so peers on this LAN should use {}",
+                        reason, lan
+                    ),
+                )
+            }
+        };
+        say!("{}  hairpin: {}", status, detail);
+        output::emit(Value::object([
+            ("status", Value::from(status.to_ascii_lowercase())),
+            ("subject", Value::from("hairpin")),
+            ("supported", Value::from(supported)),
+            ("detail", Value::from(detail)),
+        ]));
+    }
+
+    if probe.result.is_err() {
+        say!(
+            "Check the router's port forward or UPnP mapping for port {}, \
+             and run 'netcore firewall --port {}' for the local firewall",
+            port,
+            port
+        );
+        std::process::exit(exit::FAILURE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_local_addresses_from_public_ones() {
+        for local in [
+            "192.168.1.5",
+            "10.0.0.1",
+            "100.100.0.1",
+            "127.0.0.1",
+            "fd00::1",
+        ] {
+            assert!(is_local(local.parse().unwrap()), "{}", local);
+        }
+        for public in ["203.0.113.9", "100.128.0.1", "2001:db8::1"] {
+            assert!(!is_local(public.parse().unwrap()), "{}", public);
+        }
+    }
+}
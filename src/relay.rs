@@ -0,0 +1,994 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader,
+    DuplexStream, ReadBuf,
+};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{Duration, Instant, sleep, timeout};
+
+use crate::acl;
+use crate::bandwidth::{self, Bandwidth, Limits};
+use crate::cli::{self, Args, Command, Opt};
+use crate::config::{Config, Section};
+use crate::ed25519::{self, Keypair};
+use crate::exit;
+use crate::hex;
+use crate::identity;
+use crate::outbound::OutboundConfig;
+use crate::pair::Rendezvous;
+use crate::say;
+use crate::secret;
+use crate::timeouts;
+use crate::tunnel::{self, Features, Keepalive};
+use crate::units::{self, SECS};
+
+const DEFAULT_LISTEN: &str = "[::]:7000";
+const MIN_RETRY_SECS: u64 = 1;
+const MAX_RETRY_SECS: u64 = 60;
+pub const KEEPALIVE_SECS: u64 = 15;
+pub const KEEPALIVE_TIMEOUT_SECS: u64 = 45;
+const QUOTA_PERIOD_SECS: u64 = 86_400;
+const IDLE_TIMEOUT_SECS: u64 = 300;
+// Longest control line, newline included; registration comes before any
+// authentication, so anything longer is dropped rather than buffered.
+const MAX_LINE: usize = 4096;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--relay",
+        value: Some("<host:port>"),
+        help: "Expose the server through a netcore relay",
+    },
+    Opt {
+        name: "--relay-name",
+        value: Some("<name>"),
+        help: "Peer name to register with the relay",
+    },
+    Opt {
+        name: "--relay-token",
+        value: Some("<token>"),
+        help: "Token for the relay peer, or file:, env: or exec: to look it up",
+    },
+    Opt {
+        name: "--relay-key",
+        value: Some("<path>"),
+        help: "Register with this key from 'netcore keygen' rather than a token",
+    },
+    Opt {
+        name: "--relay-keepalive",
+        value: Some("<duration>"),
+        help: "Interval between relay keepalives (default: 15s)",
+    },
+    Opt {
+        name: "--relay-timeout",
+        value: Some("<duration>"),
+        help: "Reconnect after this long without hearing from the relay (default: 45s)",
+    },
+    Opt {
+        name: "--relay-compress",
+        value: None,
+        help: "Compress data sent through the relay when it supports that",
+    },
+    Opt {
+        name: "--relay-verify",
+        value: None,
+        help: "Check a BLAKE3 digest of each stream received from the relay",
+    },
+];
+
+const RELAY_OPTS: &[Opt] = &[
+    Opt {
+        name: "--listen",
+        value: Some("<addr>"),
+        help: "Address peers register on (default: [::]:7000)",
+    },
+    Opt {
+        name: "--config",
+        value: Some("<path>"),
+        help: "Configuration file with [peer NAME] sections",
+    },
+    Opt {
+        name: "--keepalive",
+        value: Some("<duration>"),
+        help: "Interval between keepalives to peers (default: 15s)",
+    },
+    Opt {
+        name: "--keepalive-timeout",
+        value: Some("<duration>"),
+        help: "Drop peers silent for this long (default: 45s)",
+    },
+    Opt {
+        name: "--pairing",
+        value: None,
+        help: "Broker 'netcore pair' sessions between unregistered peers",
+    },
+    Opt {
+        name: "--compress",
+        value: None,
+        help: "Compress data sent to peers that support it",
+    },
+    Opt {
+        name: "--verify",
+        value: None,
+        help: "Check a BLAKE3 digest of each stream received from peers",
+    },
+];
+
+fn keepalive(args: &Args, interval: &str, timeout: &str) -> Result<Keepalive, String> {
+    let keepalive = Keepalive {
+        interval: args
+            .parsed_with(interval, |v| units::duration(v, SECS))?
+            .unwrap_or(Duration::from_secs(KEEPALIVE_SECS)),
+        timeout: args
+            .parsed_with(timeout, |v| units::duration(v, SECS))?
+            .unwrap_or(Duration::from_secs(KEEPALIVE_TIMEOUT_SECS)),
+    };
+
+    if keepalive.interval.is_zero() || keepalive.timeout <= keepalive.interval {
+        return Err(format!("{} must be shorter than {}", interval, timeout));
+    }
+    Ok(keepalive)
+}
+
+struct PeerConfig {
+    name: String,
+    // It registers with one or the other.
+    token: Option<String>,
+    key: Option<[u8; 32]>,
+    port: u16,
+    quota: Option<u64>,
+    quota_period: Duration,
+    idle_timeout: Duration,
+}
+
+fn duration(
+    config: &Config,
+    section: &Section,
+    key: &str,
+    default: u64,
+) -> Result<Duration, String> {
+    let duration = section.parsed_with(config, key, |v| {
+        units::duration(v, SECS).and_then(units::nonzero)
+    })?;
+    Ok(duration.unwrap_or(Duration::from_secs(default)))
+}
+
+fn peers(config: &Config) -> Result<Vec<PeerConfig>, String> {
+    config
+        .sections("peer")
+        .map(|section| {
+            let quota = section.parsed_with(config, "quota", units::size)?;
+
+            section.require(config, "port")?;
+            let port = section.parsed(config, "port")?.unwrap_or_default();
+            let token = section.parsed_with(config, "token", secret::resolve)?;
+            let key = section.parsed_with(config, "key", |v| {
+                identity::parse_public(v).ok_or_else(|| "not an ssh-ed25519 public key".to_string())
+            })?;
+            if token.is_some() == key.is_some() {
+                return Err(config.error(
+                    section.line,
+                    &format!("[peer {}] needs either 'token' or 'key'", section.name),
+                ));
+            }
+
+            Ok(PeerConfig {
+                name: section.name.clone(),
+                token,
+                key,
+                port,
+                quota,
+                quota_period: duration(config, section, "quota_period", QUOTA_PERIOD_SECS)?,
+                idle_timeout: duration(config, section, "idle_timeout", IDLE_TIMEOUT_SECS)?,
+            })
+        })
+        .collect()
+}
+
+struct Peer {
+    config: PeerConfig,
+    used: AtomicU64,
+    period: AtomicU64,
+    registered: AtomicBool,
+}
+
+impl Peer {
+    fn current_period(&self, relay: &Relay) -> u64 {
+        relay.started.elapsed().as_secs() / self.config.quota_period.as_secs().max(1)
+    }
+
+    fn usage(&self, relay: &Relay) -> u64 {
+        let period = self.current_period(relay);
+        if self.period.swap(period, Ordering::Relaxed) != period {
+            self.used.store(0, Ordering::Relaxed);
+        }
+        self.used.load(Ordering::Relaxed)
+    }
+
+    fn over_quota(&self, relay: &Relay) -> bool {
+        self.config
+            .quota
+            .is_some_and(|quota| self.usage(relay) >= quota)
+    }
+}
+
+struct Relay {
+    started: Instant,
+    bind_ip: IpAddr,
+    keepalive: Keepalive,
+    features: Features,
+    peers: HashMap<String, Arc<Peer>>,
+    pairing: Option<Rendezvous>,
+    bandwidth: Bandwidth,
+}
+
+struct Metered<S> {
+    inner: S,
+    peer: Arc<Peer>,
+    relay: Arc<Relay>,
+    session: Arc<SessionMeter>,
+}
+
+#[derive(Default)]
+struct SessionMeter {
+    activity: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if self.peer.over_quota(&self.relay) {
+            return Poll::Ready(Err(io::Error::other("quota exceeded")));
+        }
+
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = (buf.filled().len() - before) as u64;
+        if n > 0 {
+            self.peer.usage(&self.relay);
+            self.peer.used.fetch_add(n, Ordering::Relaxed);
+            let now = self.relay.started.elapsed().as_millis() as u64;
+            self.session.activity.store(now, Ordering::Relaxed);
+            self.session.bytes.fetch_add(n, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+async fn idle(relay: &Relay, activity: &AtomicU64, limit: Duration) {
+    loop {
+        let now = relay.started.elapsed().as_millis() as u64;
+        let idle = Duration::from_millis(now.saturating_sub(activity.load(Ordering::Relaxed)));
+        if idle >= limit {
+            return;
+        }
+        sleep(limit - idle).await;
+    }
+}
+
+async fn splice(
+    relay: Arc<Relay>,
+    peer: Arc<Peer>,
+    public: TcpStream,
+    addr: SocketAddr,
+    stream: DuplexStream,
+    limits: Limits,
+) {
+    let session = Arc::new(SessionMeter::default());
+    let now = relay.started.elapsed().as_millis() as u64;
+    session.activity.store(now, Ordering::Relaxed);
+
+    let mut public = Metered {
+        inner: limits.wrap(public),
+        peer: peer.clone(),
+        relay: relay.clone(),
+        session: session.clone(),
+    };
+    let mut stream = Metered {
+        inner: stream,
+        peer: peer.clone(),
+        relay: relay.clone(),
+        session: session.clone(),
+    };
+
+    let result = tokio::select! {
+        result = tokio::io::copy_bidirectional(&mut public, &mut stream) => result.map(|_| ()),
+        _ = idle(&relay, &session.activity, peer.config.idle_timeout) => {
+            Err(io::Error::new(io::ErrorKind::TimedOut, "idle timeout"))
+        }
+    };
+
+    let used = peer.usage(&relay);
+    let quota = peer
+        .config
+        .quota
+        .map_or_else(|| "unlimited".to_string(), |q| q.to_string());
+    match result {
+        Ok(()) => say!(
+            "Relay session {} via '{}' closed ({} bytes, {} of {} used)",
+            addr,
+            peer.config.name,
+            session.bytes.load(Ordering::Relaxed),
+            used,
+            quota
+        ),
+        Err(e) => say!(
+            "Relay session {} via '{}' ended: {} ({} of {} used)",
+            addr,
+            peer.config.name,
+            e,
+            used,
+            quota
+        ),
+    }
+}
+
+pub async fn read_line(reader: &mut (impl AsyncBufRead + Unpin)) -> Option<String> {
+    read_line_within(reader, timeouts::get().handshake).await
+}
+
+// A line of at most MAX_LINE bytes; None for a longer one, as for EOF.
+pub async fn read_line_within(
+    reader: &mut (impl AsyncBufRead + Unpin),
+    wait: Duration,
+) -> Option<String> {
+    let mut line = String::new();
+    let mut limited = reader.take(MAX_LINE as u64);
+    match timeout(wait, limited.read_line(&mut line)).await {
+        Ok(Ok(n)) if n > 0 && line.ends_with('\n') => Some(line.trim_end().to_string()),
+        _ => None,
+    }
+}
+
+// The first line from whoever connects to the relay.
+pub enum Request<'a> {
+    Register { name: &'a str, token: &'a str },
+    RegisterKey { name: &'a str },
+    Probe { port: u16 },
+    PairOffer { code: &'a str, addresses: &'a str },
+    PairJoin { code: &'a str, addresses: &'a str },
+}
+
+impl<'a> Request<'a> {
+    pub fn parse(line: &'a str) -> Option<Request<'a>> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["REGISTER", name, token] => Some(Request::Register { name, token }),
+            ["REGISTER-KEY", name] => Some(Request::RegisterKey { name }),
+            ["PROBE", port] => Some(Request::Probe {
+                port: port.parse().ok()?,
+            }),
+            ["PAIR-OFFER", code, addresses] => Some(Request::PairOffer { code, addresses }),
+            ["PAIR-JOIN", code, addresses] => Some(Request::PairJoin { code, addresses }),
+            _ => None,
+        }
+    }
+}
+
+async fn handle_peer(relay: Arc<Relay>, socket: TcpStream, addr: SocketAddr) {
+    let mut reader = BufReader::new(socket);
+    let Some(line) = read_line(&mut reader).await else {
+        return;
+    };
+
+    match Request::parse(&line) {
+        Some(Request::Register { name, token }) => {
+            let peer = relay
+                .peers
+                .get(name)
+                .filter(|peer| {
+                    peer.config.token.as_ref().is_some_and(|expected| {
+                        crate::auth::constant_time_eq(expected.as_bytes(), token.as_bytes())
+                    })
+                })
+                .cloned();
+            register(relay, reader, addr, name, peer).await
+        }
+        Some(Request::RegisterKey { name }) => {
+            let peer = challenge(&relay, &mut reader, name).await;
+            register(relay, reader, addr, name, peer).await
+        }
+        Some(Request::Probe { port }) => probe_back(reader, addr, port).await,
+        Some(Request::PairOffer { code, addresses }) => match &relay.pairing {
+            Some(rendezvous) => rendezvous.offer(reader, addr, code, addresses).await,
+            None => pairing_disabled(reader).await,
+        },
+        Some(Request::PairJoin { code, addresses }) => match &relay.pairing {
+            Some(rendezvous) => rendezvous.join(reader, addr, code, addresses).await,
+            None => pairing_disabled(reader).await,
+        },
+        None => {
+            eprintln!("Invalid relay handshake from {}", addr);
+            acl::offence(addr, "protocol");
+        }
+    }
+}
+
+async fn pairing_disabled(mut reader: BufReader<TcpStream>) {
+    let _ = reader.get_mut().write_all(b"ERR pairing disabled\n").await;
+}
+
+// Connects back to the client on `port` so it can tell whether its
+// listeners are reachable from outside. The relay only ever probes the
+// address the request came from, and says which address that was.
+async fn probe_back(mut reader: BufReader<TcpStream>, addr: SocketAddr, port: u16) {
+    let ip = addr.ip().to_canonical();
+    let reply = match timeout(timeouts::get().connect, TcpStream::connect((ip, port))).await {
+        Ok(Ok(_)) => format!("REACHABLE {}\n", ip),
+        Ok(Err(e)) => format!("UNREACHABLE {} {}\n", ip, e),
+        Err(_) => format!("UNREACHABLE {} timed out\n", ip),
+    };
+    let _ = reader.get_mut().write_all(reply.as_bytes()).await;
+}
+
+pub struct Probe {
+    // This machine's address as the relay saw it, which it connected to.
+    pub observed: IpAddr,
+    // Why the connection failed, if it did.
+    pub result: Result<(), String>,
+}
+
+// Asks `relay` to connect back to this machine on `port`.
+pub async fn probe(
+    outbound: &OutboundConfig,
+    relay: &str,
+    port: u16,
+) -> Result<Probe, exit::Error> {
+    let socket = outbound.connect(relay).await?;
+    let mut control = BufReader::new(socket);
+    control
+        .get_mut()
+        .write_all(format!("PROBE {}\n", port).as_bytes())
+        .await?;
+    let wait = timeouts::get().handshake + timeouts::get().connect;
+    let reply = read_line_within(&mut control, wait)
+        .await
+        .ok_or("no reply from relay")?;
+    let unexpected = || format!("unexpected reply from relay: {}", reply);
+    let mut words = reply.splitn(3, ' ');
+    let (Some(verdict), Some(Ok(observed))) = (words.next(), words.next().map(str::parse)) else {
+        return Err(unexpected().into());
+    };
+    let result = match (verdict, words.next()) {
+        ("REACHABLE", None) => Ok(()),
+        ("UNREACHABLE", Some(reason)) => Err(reason.to_string()),
+        _ => return Err(unexpected().into()),
+    };
+    Ok(Probe { observed, result })
+}
+
+// What a peer signs to register by key: the relay's nonce, and the name
+// so the signature can't stand for another peer that shares the key.
+fn registration(name: &str, nonce: &[u8; 32]) -> Vec<u8> {
+    [b"netcore relay registration\0", &nonce[..], name.as_bytes()].concat()
+}
+
+// The peer `name`, if it signs a fresh nonce with the key configured for
+// it. Every name is challenged, so which ones exist isn't given away.
+async fn challenge(
+    relay: &Relay,
+    reader: &mut BufReader<TcpStream>,
+    name: &str,
+) -> Option<Arc<Peer>> {
+    let nonce: [u8; 32] = rand::random();
+    let challenge = format!("CHALLENGE {}\n", hex::encode(&nonce));
+    reader
+        .get_mut()
+        .write_all(challenge.as_bytes())
+        .await
+        .ok()?;
+    let reply = read_line(reader).await?;
+    let signature: [u8; 64] = hex::decode(reply.strip_prefix("SIGNATURE ")?)?
+        .try_into()
+        .ok()?;
+    let peer = relay.peers.get(name)?;
+    let key = peer.config.key.as_ref()?;
+    ed25519::verify(key, &registration(name, &nonce), &signature).then(|| peer.clone())
+}
+
+// Takes `peer` on, if it proved itself to be the one called `name`.
+async fn register(
+    relay: Arc<Relay>,
+    mut reader: BufReader<TcpStream>,
+    addr: SocketAddr,
+    name: &str,
+    peer: Option<Arc<Peer>>,
+) {
+    let peer = match peer {
+        Some(peer) => peer,
+        None => {
+            eprintln!("Rejected relay registration for '{}' from {}", name, addr);
+            acl::offence(addr, "auth");
+            let _ = reader.get_mut().write_all(b"ERR unauthorized\n").await;
+            return;
+        }
+    };
+
+    if peer.registered.swap(true, Ordering::Relaxed) {
+        let _ = reader
+            .get_mut()
+            .write_all(b"ERR already registered\n")
+            .await;
+        return;
+    }
+
+    let public = match TcpListener::bind(SocketAddr::new(relay.bind_ip, peer.config.port)).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!(
+                "Failed to listen on port {} for '{}': {}",
+                peer.config.port, name, e
+            );
+            let _ = reader.get_mut().write_all(b"ERR listen failed\n").await;
+            peer.registered.store(false, Ordering::Relaxed);
+            return;
+        }
+    };
+
+    say!(
+        "Peer '{}' registered from {}, public port {}",
+        name,
+        addr,
+        peer.config.port
+    );
+    let ok = format!("OK {}\n", peer.config.port);
+    if reader.get_mut().write_all(ok.as_bytes()).await.is_ok() {
+        serve_peer(&relay, &peer, &public, reader).await;
+    }
+
+    peer.registered.store(false, Ordering::Relaxed);
+    say!("Peer '{}' disconnected", name);
+}
+
+async fn serve_peer(
+    relay: &Arc<Relay>,
+    peer: &Arc<Peer>,
+    public: &TcpListener,
+    control: BufReader<TcpStream>,
+) {
+    let (mux, mut incoming) = tunnel::session(control, true, relay.keepalive, relay.features);
+    let limits = relay.bandwidth.listener();
+
+    loop {
+        tokio::select! {
+            accepted = public.accept() => {
+                let Ok((socket, addr)) = accepted else {
+                    continue;
+                };
+                if acl::check(addr).is_some() {
+                    continue;
+                }
+                if peer.over_quota(relay) {
+                    say!("Refusing {} for '{}': quota exceeded", addr, peer.config.name);
+                    continue;
+                }
+
+                let Ok(stream) = mux.open().await else {
+                    break;
+                };
+                tokio::spawn(splice(
+                    relay.clone(),
+                    peer.clone(),
+                    socket,
+                    addr,
+                    stream,
+                    limits.clone(),
+                ));
+            }
+            stream = incoming.accept() => match stream {
+                Some(_) => {}
+                None => break,
+            }
+        }
+    }
+
+    if relay.features.compress {
+        say!(
+            "Compression for '{}': {}",
+            peer.config.name,
+            mux.compression()
+        );
+    }
+    if relay.features.verify {
+        say!("Integrity for '{}': {}", peer.config.name, mux.integrity());
+    }
+}
+
+pub const COMMAND: Command = Command {
+    name: "relay",
+    usage: "netcore relay",
+    about: "Run a relay that exposes peers and brokers pairing",
+    groups: &[RELAY_OPTS, bandwidth::OPTS, acl::OPTS, timeouts::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let listen: SocketAddr = cli::or_exit(
+        args.value("--listen")
+            .unwrap_or(DEFAULT_LISTEN)
+            .parse()
+            .map_err(|_| "invalid --listen address".to_string()),
+    );
+    let pairing = args.flag("--pairing");
+    let config = cli::or_exit(Config::from_args(&args, &["timeouts"]));
+    cli::or_exit(timeouts::init(&args, config.as_ref()));
+    let peers = match &config {
+        Some(config) => cli::or_exit(peers(config)),
+        None if pairing => Vec::new(),
+        None => {
+            eprintln!(
+                "relay requires --config with at least one [peer NAME] section, or --pairing"
+            );
+            std::process::exit(exit::USAGE);
+        }
+    };
+
+    cli::or_exit(acl::init(&args).await);
+
+    let relay = Arc::new(Relay {
+        started: Instant::now(),
+        bind_ip: listen.ip(),
+        keepalive: cli::or_exit(keepalive(&args, "--keepalive", "--keepalive-timeout")),
+        features: Features {
+            compress: args.flag("--compress"),
+            verify: args.flag("--verify"),
+        },
+        peers: peers
+            .into_iter()
+            .map(|config| {
+                let peer = Peer {
+                    config,
+                    used: AtomicU64::new(0),
+                    period: AtomicU64::new(0),
+                    registered: AtomicBool::new(false),
+                };
+                (peer.config.name.clone(), Arc::new(peer))
+            })
+            .collect(),
+        pairing: pairing.then(Rendezvous::default),
+        bandwidth: cli::or_exit(Bandwidth::from_args(&args)),
+    });
+
+    let listener = match TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to listen on {}: {}", listen, e);
+            std::process::exit(exit::code(&e));
+        }
+    };
+    say!(
+        "Relay listening on {} for {} peer(s){}",
+        listen,
+        relay.peers.len(),
+        if pairing { ", pairing enabled" } else { "" }
+    );
+
+    loop {
+        match listener.accept().await {
+            Ok((_, addr)) if acl::check(addr).is_some() => {}
+            Ok((socket, addr)) => {
+                tokio::spawn(handle_peer(relay.clone(), socket, addr));
+            }
+            Err(e) => eprintln!("Relay accept error: {}", e),
+        }
+    }
+}
+
+// How a peer proves to the relay which one it is.
+pub enum Credential {
+    Token(String),
+    Key(Keypair),
+}
+
+pub struct RelayClient {
+    pub relay: String,
+    pub name: String,
+    pub credential: Credential,
+    pub keepalive: Keepalive,
+    pub features: Features,
+}
+
+impl RelayClient {
+    pub fn from_args(args: &Args) -> Result<Option<RelayClient>, String> {
+        let Some(relay) = args.value("--relay") else {
+            return Ok(None);
+        };
+
+        let credential = match (
+            secret::value(args, "--relay-token")?,
+            args.value("--relay-key"),
+        ) {
+            (Some(_), Some(_)) => {
+                return Err("--relay-token and --relay-key can't be used together".to_string());
+            }
+            (Some(token), None) => Credential::Token(token),
+            (None, Some(path)) => Credential::Key(identity::read(Path::new(path))?),
+            (None, None) => return Err("--relay requires --relay-token or --relay-key".to_string()),
+        };
+
+        Ok(Some(RelayClient {
+            relay: relay.to_string(),
+            name: args
+                .value("--relay-name")
+                .ok_or("--relay requires --relay-name")?
+                .to_string(),
+            credential,
+            keepalive: keepalive(args, "--relay-keepalive", "--relay-timeout")?,
+            features: Features {
+                compress: args.flag("--relay-compress"),
+                verify: args.flag("--relay-verify"),
+            },
+        }))
+    }
+
+    // The public port the relay took on for this peer.
+    async fn register(&self, control: &mut BufReader<TcpStream>) -> Result<String, String> {
+        let refused = |reply: &str| format!("relay refused registration: {}", reply);
+        let request = match &self.credential {
+            Credential::Token(token) => format!("REGISTER {} {}\n", self.name, token),
+            Credential::Key(_) => format!("REGISTER-KEY {}\n", self.name),
+        };
+        write_line(control, &request).await?;
+        if let Credential::Key(keypair) = &self.credential {
+            let reply = read_line(control).await.ok_or("no reply from relay")?;
+            let nonce: [u8; 32] = reply
+                .strip_prefix("CHALLENGE ")
+                .and_then(hex::decode)
+                .and_then(|nonce| nonce.try_into().ok())
+                .ok_or_else(|| refused(&reply))?;
+            let signature = keypair.sign(&registration(&self.name, &nonce));
+            write_line(control, &format!("SIGNATURE {}\n", hex::encode(&signature))).await?;
+        }
+
+        let reply = read_line(control).await.ok_or("no reply from relay")?;
+        match reply.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["OK", port] => Ok(port.to_string()),
+            _ => Err(refused(&reply)),
+        }
+    }
+
+    async fn session(&self, outbound: &OutboundConfig, local_port: u16) -> Result<String, String> {
+        let socket = outbound
+            .connect(&self.relay)
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut control = BufReader::new(socket);
+        let port = self.register(&mut control).await?;
+        say!(
+            "Registered with relay {} as '{}', public port {}",
+            self.relay,
+            self.name,
+            port
+        );
+
+        let (mux, mut incoming) = tunnel::session(control, false, self.keepalive, self.features);
+        while let Some(mut stream) = incoming.accept().await {
+            tokio::spawn(async move {
+                let local = SocketAddr::from(([127, 0, 0, 1], local_port));
+                match TcpStream::connect(local).await {
+                    Ok(mut local) => {
+                        let _ = tokio::io::copy_bidirectional(&mut stream, &mut local).await;
+                    }
+                    Err(e) => eprintln!(
+                        "Relay connection to local port {} failed: {}",
+                        local_port, e
+                    ),
+                }
+            });
+        }
+
+        if self.features.compress {
+            say!("Relay compression: {}", mux.compression());
+        }
+        if self.features.verify {
+            say!("Relay integrity: {}", mux.integrity());
+        }
+        Ok("connection to relay lost".to_string())
+    }
+}
+
+async fn write_line(control: &mut BufReader<TcpStream>, line: &str) -> Result<(), String> {
+    control
+        .get_mut()
+        .write_all(line.as_bytes())
+        .await
+        .map_err(|e| e.to_string())
+}
+
+pub async fn run(client: RelayClient, outbound: OutboundConfig, local_port: u16) {
+    let mut retry = MIN_RETRY_SECS;
+
+    loop {
+        match client.session(&outbound, local_port).await {
+            Ok(reason) => {
+                eprintln!("Relay {}: {}, re-registering", client.relay, reason);
+                retry = MIN_RETRY_SECS;
+            }
+            Err(e) => {
+                eprintln!("Relay {}: {}, retrying in {} s", client.relay, e, retry);
+                sleep(Duration::from_secs(retry)).await;
+                retry = (retry * 2).min(MAX_RETRY_SECS);
+                continue;
+            }
+        }
+        sleep(Duration::from_secs(MIN_RETRY_SECS)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(text: &str) -> Result<Vec<PeerConfig>, String> {
+        peers(&Config::parse(Path::new("relay.conf"), text)?)
+    }
+
+    async fn start(peers: Vec<PeerConfig>) -> SocketAddr {
+        let relay = Arc::new(Relay {
+            started: Instant::now(),
+            bind_ip: IpAddr::from([127, 0, 0, 1]),
+            keepalive: Keepalive {
+                interval: Duration::from_secs(KEEPALIVE_SECS),
+                timeout: Duration::from_secs(KEEPALIVE_TIMEOUT_SECS),
+            },
+            features: Features::default(),
+            peers: peers
+                .into_iter()
+                .map(|config| {
+                    let peer = Peer {
+                        config,
+                        used: AtomicU64::new(0),
+                        period: AtomicU64::new(0),
+                        registered: AtomicBool::new(false),
+                    };
+                    (peer.config.name.clone(), Arc::new(peer))
+                })
+                .collect(),
+            pairing: None,
+            bandwidth: Bandwidth::from_args(&Args::parse(Vec::new(), &[bandwidth::OPTS]).unwrap())
+                .unwrap(),
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((socket, addr)) = listener.accept().await {
+                tokio::spawn(handle_peer(relay.clone(), socket, addr));
+            }
+        });
+        addr
+    }
+
+    // The control connection is kept, as the relay drops a peer with it.
+    async fn register_as(
+        relay: SocketAddr,
+        name: &str,
+        credential: Credential,
+    ) -> Result<(String, BufReader<TcpStream>), String> {
+        let client = RelayClient {
+            relay: relay.to_string(),
+            name: name.to_string(),
+            credential,
+            keepalive: Keepalive {
+                interval: Duration::from_secs(KEEPALIVE_SECS),
+                timeout: Duration::from_secs(KEEPALIVE_TIMEOUT_SECS),
+            },
+            features: Features::default(),
+        };
+        let mut control = BufReader::new(TcpStream::connect(relay).await.unwrap());
+        let port = client.register(&mut control).await?;
+        Ok((port, control))
+    }
+
+    #[tokio::test]
+    async fn registers_peers_by_token_or_signed_challenge() {
+        let key = Keypair::from_seed(&[1; 32]);
+        let text = format!(
+            "[peer home]\nport = 0\ntoken = s3cret\n\n[peer lab]\nport = 0\nkey = {}\n",
+            identity::public_line(&key.public, "lab")
+        );
+        let addr = start(config(&text).unwrap()).await;
+        let token = |token: &str| Credential::Token(token.to_string());
+        let signed = |seed: u8| Credential::Key(Keypair::from_seed(&[seed; 32]));
+
+        let refused = [
+            ("home", token("wrong")),
+            ("home", signed(1)),
+            ("lab", token("s3cret")),
+            ("lab", signed(2)),
+            ("nobody", signed(1)),
+        ];
+        for (name, credential) in refused {
+            assert_eq!(
+                register_as(addr, name, credential).await.unwrap_err(),
+                "relay refused registration: ERR unauthorized"
+            );
+        }
+        let (port, _lab) = register_as(addr, "lab", signed(1)).await.unwrap();
+        assert_eq!(port, "0");
+        let (port, _home) = register_as(addr, "home", token("s3cret")).await.unwrap();
+        assert_eq!(port, "0");
+
+        // A signature is only good for the nonce and name it was made for.
+        let mut control = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        write_line(&mut control, "REGISTER-KEY lab\n")
+            .await
+            .unwrap();
+        let challenge = read_line(&mut control).await.unwrap();
+        let nonce: [u8; 32] = hex::decode(challenge.strip_prefix("CHALLENGE ").unwrap())
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let signature = key.sign(&registration("home", &nonce));
+        let reply = format!("SIGNATURE {}\n", hex::encode(&signature));
+        write_line(&mut control, &reply).await.unwrap();
+        assert_eq!(read_line(&mut control).await.unwrap(), "ERR unauthorized");
+    }
+
+    #[test]
+    fn peers_need_a_token_or_a_key() {
+        let key = identity::public_line(&Keypair::from_seed(&[1; 32]).public, "lab");
+        assert!(config("[peer a]\nport = 1\ntoken = x\n").is_ok());
+        assert!(config(&format!("[peer a]\nport = 1\nkey = {}\n", key)).is_ok());
+        assert_eq!(
+            config("[peer a]\nport = 1\n").err().unwrap(),
+            "relay.conf:1: [peer a] needs either 'token' or 'key'"
+        );
+        assert!(config(&format!("[peer a]\nport = 1\ntoken = x\nkey = {}\n", key)).is_err());
+        assert!(config("[peer a]\nport = 1\nkey = ssh-rsa AAAA\n").is_err());
+    }
+
+    #[tokio::test]
+    async fn drops_control_lines_over_the_limit() {
+        let wait = Duration::from_secs(1);
+        let mut reader = BufReader::new(&b"REGISTER home s3cret\r\nPROBE 80\n"[..]);
+        assert_eq!(
+            read_line_within(&mut reader, wait).await.as_deref(),
+            Some("REGISTER home s3cret")
+        );
+        assert_eq!(
+            read_line_within(&mut reader, wait).await.as_deref(),
+            Some("PROBE 80")
+        );
+
+        let fits = format!("PROBE {}\n", "1".repeat(MAX_LINE - 7));
+        let mut reader = BufReader::new(fits.as_bytes());
+        assert!(read_line_within(&mut reader, wait).await.is_some());
+
+        // No newline within the limit, however much more follows.
+        let endless = "A".repeat(MAX_LINE * 16);
+        let mut reader = BufReader::new(endless.as_bytes());
+        assert_eq!(read_line_within(&mut reader, wait).await, None);
+        // A last line cut off by EOF is not a line either.
+        let mut reader = BufReader::new(&b"REGISTER home"[..]);
+        assert_eq!(read_line_within(&mut reader, wait).await, None);
+    }
+}
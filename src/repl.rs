@@ -0,0 +1,568 @@
+// `netcore repl`: a prompt on a running server's control socket, for
+// checking and probing from the server's side without retyping `netcore
+// ctl` each time. On a terminal the line is edited in place: left and
+// right move, up and down bring back earlier lines (kept in repl_history
+// next to the history file), and Tab completes command names. Piped
+// input is read a line at a time with no prompt, for scripts.
+
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::path::PathBuf;
+
+use crate::cli::{self, Command};
+use crate::control;
+use crate::exit;
+use crate::history;
+use crate::json::Value;
+use crate::output;
+use crate::say;
+use crate::top::format_bytes;
+
+const PROMPT: &str = "netcore> ";
+const MAX_HISTORY: usize = 500;
+
+// Name, arguments and what it does, for help, completion and usage errors.
+const COMMANDS: &[(&str, &str, &str)] = &[
+    (
+        "status",
+        "",
+        "Show whether the server is up and its version",
+    ),
+    ("stats", "", "Show traffic per handler"),
+    ("connections", "", "List active connections"),
+    ("flows", "", "List the flows that moved the most data"),
+    ("talkers", "", "List the addresses that moved the most data"),
+    ("kill", "<id>", "Close an active connection"),
+    ("info", "", "Show the server's addresses"),
+    ("check", "", "Run the connectivity checks from the server"),
+    (
+        "ping",
+        "<host:port> [count]",
+        "TCP-ping a target from the server",
+    ),
+    (
+        "connect",
+        "<host:port>",
+        "Try one connection from the server",
+    ),
+    (
+        "scan",
+        "<host> [ports]",
+        "Scan a host's ports from the server",
+    ),
+    ("help", "", "Show this list"),
+    ("exit", "", "Leave the prompt (or Ctrl-D)"),
+];
+
+pub const COMMAND: Command = Command {
+    name: "repl",
+    usage: "netcore repl",
+    about: "Run control commands interactively against a running server",
+    groups: &[control::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let path = control::path_from_args(&args);
+    let status = Value::object([("command", Value::from("status"))]);
+    if let Err(e) = control::request(&path, &status).await {
+        eprintln!("Cannot attach to a running netcore: {}", e);
+        std::process::exit(exit::code(&e));
+    }
+
+    let interactive = io::stdin().is_terminal();
+    if interactive {
+        say!("Connected to {}; type 'help' for commands", path.display());
+    }
+    let mut editor = Editor::load();
+    let mut failed = false;
+    loop {
+        // Reading blocks, so it gets a thread of its own.
+        let (returned, line) = tokio::task::spawn_blocking(move || {
+            let line = editor.read_line(interactive);
+            (editor, line)
+        })
+        .await
+        .unwrap();
+        editor = returned;
+        let Some(line) = line else {
+            break;
+        };
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.first() {
+            None => continue,
+            Some(&"exit" | &"quit") => break,
+            Some(&"help") => {
+                for (name, args, about) in COMMANDS {
+                    say!("  {:<30} {}", format!("{} {}", name, args), about);
+                }
+                continue;
+            }
+            Some(_) => {}
+        }
+
+        let result = match request(&words) {
+            Ok(request) => control::request(&path, &request).await,
+            Err(e) => Err(e.into()),
+        };
+        match result {
+            Ok(response) => {
+                render(words[0], &response);
+                output::emit(Value::object([("response", response)]));
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                failed = true;
+            }
+        }
+    }
+    editor.save();
+
+    // Scripts learn whether every command went through.
+    if failed && !interactive {
+        std::process::exit(exit::FAILURE);
+    }
+}
+
+// The control request a line stands for.
+fn request(words: &[&str]) -> Result<Value, String> {
+    let command = |name: &str| ("command", Value::from(name));
+    let count = |count: &str| {
+        count
+            .parse::<u64>()
+            .map_err(|_| format!("invalid count '{}'", count))
+    };
+    let fields = match words {
+        [name @ ("status" | "stats" | "connections" | "flows" | "talkers" | "info" | "check")] => {
+            vec![command(name)]
+        }
+        ["kill", id] => {
+            let id = id
+                .parse::<u64>()
+                .map_err(|_| format!("invalid connection id '{}'", id))?;
+            vec![command("kill"), ("id", Value::from(id))]
+        }
+        ["ping", target] => vec![command("ping"), ("target", Value::from(*target))],
+        ["ping", target, n] => vec![
+            command("ping"),
+            ("target", Value::from(*target)),
+            ("count", Value::from(count(n)?)),
+        ],
+        ["connect", target] => vec![
+            command("ping"),
+            ("target", Value::from(*target)),
+            ("count", Value::from(1u64)),
+        ],
+        ["scan", target] => vec![command("scan"), ("target", Value::from(*target))],
+        ["scan", target, ports] => vec![
+            command("scan"),
+            ("target", Value::from(*target)),
+            ("ports", Value::from(*ports)),
+        ],
+        [name, ..] => {
+            return Err(match COMMANDS.iter().find(|(known, _, _)| known == name) {
+                Some((name, args, _)) => format!("usage: {} {}", name, args),
+                None => format!("unknown command '{}'; try 'help'", name),
+            });
+        }
+        [] => return Err("no command".to_string()),
+    };
+    Ok(Value::object(fields))
+}
+
+fn text<'a>(value: &'a Value, key: &str) -> &'a str {
+    value.get(key).and_then(Value::as_str).unwrap_or("-")
+}
+
+fn number(value: &Value, key: &str) -> u64 {
+    value.get(key).and_then(Value::as_u64).unwrap_or(0)
+}
+
+fn render(command: &str, response: &Value) {
+    match command {
+        "status" => say!("netcore {} is running", text(response, "version")),
+        "stats" => {
+            say!("Up {} s", number(response, "uptime_secs"));
+            say!(
+                "{:<10} {:>7} {:>8} {:>7} {:>10} {:>10}",
+                "HANDLER",
+                "ACTIVE",
+                "TOTAL",
+                "ERRORS",
+                "IN",
+                "OUT"
+            );
+            for handler in response
+                .get("handlers")
+                .map(Value::as_array)
+                .unwrap_or_default()
+            {
+                say!(
+                    "{:<10} {:>7} {:>8} {:>7} {:>10} {:>10}",
+                    text(handler, "name"),
+                    number(handler, "active"),
+                    number(handler, "connections"),
+                    number(handler, "errors"),
+                    format_bytes(number(handler, "bytes_in") as f64),
+                    format_bytes(number(handler, "bytes_out") as f64)
+                );
+            }
+        }
+        "connections" => {
+            let connections = response.as_array();
+            if connections.is_empty() {
+                say!("No active connections");
+            }
+            for conn in connections {
+                say!(
+                    "{:>6} {:<10} {:<40} {:>7}s",
+                    number(conn, "id"),
+                    text(conn, "handler"),
+                    text(conn, "peer"),
+                    number(conn, "age_ms") / 1000
+                );
+            }
+        }
+        "flows" => {
+            for flow in response.as_array() {
+                say!(
+                    "{:<4} {:<40} > {:<40} {:>10} {:>7}s{}",
+                    text(flow, "protocol"),
+                    text(flow, "src"),
+                    text(flow, "dst"),
+                    format_bytes(number(flow, "bytes") as f64),
+                    number(flow, "duration_ms") / 1000,
+                    if flow.get("active") == Some(&Value::Bool(true)) {
+                        "  active"
+                    } else {
+                        ""
+                    }
+                );
+            }
+        }
+        "talkers" => {
+            for talker in response.as_array() {
+                say!(
+                    "{:<40} {:>10} {:>6} flow(s)  {}",
+                    text(talker, "addr"),
+                    format_bytes(number(talker, "bytes") as f64),
+                    number(talker, "flows"),
+                    talker.get("geo").and_then(Value::as_str).unwrap_or("")
+                );
+            }
+        }
+        "kill" => say!("Closed connection {}", number(response, "killed")),
+        "scan" => {
+            let ports = response.as_array();
+            let mut shown = 0;
+            for port in ports.iter().filter(|port| text(port, "state") == "open") {
+                say!(
+                    "{:>5}/{:<4} open  {}",
+                    number(port, "port"),
+                    text(port, "protocol"),
+                    port.get("service").and_then(Value::as_str).unwrap_or("")
+                );
+                shown += 1;
+            }
+            say!("{} of {} port(s) open", shown, ports.len());
+        }
+        // info, check, ping and connect answer with samples.
+        _ => {
+            for sample in response.as_array() {
+                let status = match sample.get("ok") {
+                    Some(Value::Bool(true)) => "PASS",
+                    _ => "FAIL",
+                };
+                say!(
+                    "{}  {:<16} {}",
+                    status,
+                    text(sample, "subject"),
+                    text(sample, "value")
+                );
+            }
+        }
+    }
+}
+
+// The command names `word` could be the start of.
+fn completions(word: &str) -> Vec<&'static str> {
+    COMMANDS
+        .iter()
+        .map(|(name, _, _)| *name)
+        .filter(|name| name.starts_with(word))
+        .collect()
+}
+
+fn history_path() -> PathBuf {
+    history::default_path().with_file_name("repl_history")
+}
+
+struct Editor {
+    history: Vec<String>,
+}
+
+impl Editor {
+    fn load() -> Editor {
+        let history = fs::read_to_string(history_path())
+            .map(|text| text.lines().map(str::to_string).collect())
+            .unwrap_or_default();
+        Editor { history }
+    }
+
+    fn save(&self) {
+        let path = history_path();
+        let start = self.history.len().saturating_sub(MAX_HISTORY);
+        let mut text = self.history[start..].join("\n");
+        text.push('\n');
+        if let Some(dir) = path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        if let Err(e) = fs::write(&path, text) {
+            eprintln!("Failed to save {}: {}", path.display(), e);
+        }
+    }
+
+    fn remember(&mut self, line: &str) {
+        if !line.trim().is_empty() && self.history.last().is_none_or(|last| last != line) {
+            self.history.push(line.to_string());
+        }
+    }
+
+    // The next line, or None at the end of input.
+    fn read_line(&mut self, interactive: bool) -> Option<String> {
+        #[cfg(unix)]
+        if interactive && let Some(raw) = term::Raw::enable() {
+            let line = self.edit();
+            drop(raw);
+            if let Some(line) = &line {
+                self.remember(line);
+            }
+            return line;
+        }
+
+        if interactive {
+            print!("{}", PROMPT);
+            let _ = io::stdout().flush();
+        }
+        let mut line = String::new();
+        match io::stdin().lock().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => {
+                let line = line.trim_end_matches(['\r', '\n']).to_string();
+                self.remember(&line);
+                Some(line)
+            }
+        }
+    }
+
+    // Reads a line a key at a time, with the terminal in raw mode.
+    #[cfg(unix)]
+    fn edit(&self) -> Option<String> {
+        let mut stdin = io::stdin().lock();
+        let mut key = || {
+            let mut byte = [0];
+            stdin.read_exact(&mut byte).ok().map(|_| byte[0])
+        };
+        let mut line: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // Where up and down have got to; the history's length is the line
+        // being typed, kept in `draft` while away from it.
+        let mut recalled = self.history.len();
+        let mut draft = Vec::new();
+        redraw(&line, cursor);
+
+        loop {
+            match key()? {
+                b'\r' | b'\n' => {
+                    print!("\r\n");
+                    let _ = io::stdout().flush();
+                    return Some(line.into_iter().collect());
+                }
+                // Ctrl-C drops the line, Ctrl-D on an empty one ends input.
+                0x03 => {
+                    print!("^C\r\n");
+                    line.clear();
+                    cursor = 0;
+                }
+                0x04 if line.is_empty() => {
+                    print!("\r\n");
+                    let _ = io::stdout().flush();
+                    return None;
+                }
+                0x04 if cursor < line.len() => {
+                    line.remove(cursor);
+                }
+                0x7f | 0x08 if cursor > 0 => {
+                    cursor -= 1;
+                    line.remove(cursor);
+                }
+                0x01 => cursor = 0,
+                0x05 => cursor = line.len(),
+                0x15 => {
+                    line.drain(..cursor);
+                    cursor = 0;
+                }
+                0x0b => line.truncate(cursor),
+                b'\t' => {
+                    let typed: String = line.iter().collect();
+                    if typed.contains(' ') {
+                        continue;
+                    }
+                    let found = completions(&typed);
+                    match found.as_slice() {
+                        [] => {}
+                        [only] => {
+                            line = format!("{} ", only).chars().collect();
+                            cursor = line.len();
+                        }
+                        several => {
+                            let common = several.iter().fold(several[0], |common, name| {
+                                let same = common
+                                    .chars()
+                                    .zip(name.chars())
+                                    .take_while(|(a, b)| a == b)
+                                    .count();
+                                &common[..same]
+                            });
+                            if common.len() > typed.len() {
+                                line = common.chars().collect();
+                                cursor = line.len();
+                            } else {
+                                print!("\r\n{}\r\n", several.join("  "));
+                            }
+                        }
+                    }
+                }
+                0x1b => {
+                    let introducer = key()?;
+                    if introducer != b'[' && introducer != b'O' {
+                        continue;
+                    }
+                    let mut code = key()?;
+                    // Delete and the like: ESC [ 3 ~.
+                    let numbered = code.is_ascii_digit();
+                    while code.is_ascii_digit() || code == b';' {
+                        code = key()?;
+                    }
+                    match code {
+                        b'A' | b'B' => {
+                            let next = if code == b'A' {
+                                recalled.checked_sub(1)
+                            } else {
+                                Some(recalled + 1).filter(|n| *n <= self.history.len())
+                            };
+                            let Some(next) = next else {
+                                continue;
+                            };
+                            if recalled == self.history.len() {
+                                draft = line.clone();
+                            }
+                            recalled = next;
+                            line = match self.history.get(recalled) {
+                                Some(earlier) => earlier.chars().collect(),
+                                None => draft.clone(),
+                            };
+                            cursor = line.len();
+                        }
+                        b'C' => cursor = (cursor + 1).min(line.len()),
+                        b'D' => cursor = cursor.saturating_sub(1),
+                        b'H' => cursor = 0,
+                        b'F' => cursor = line.len(),
+                        b'~' if numbered && cursor < line.len() => {
+                            line.remove(cursor);
+                        }
+                        _ => {}
+                    }
+                }
+                byte if byte >= 0x20 => {
+                    // The rest of a UTF-8 character follows its first byte.
+                    let mut bytes = vec![byte];
+                    let len = match byte {
+                        0xf0.. => 4,
+                        0xe0.. => 3,
+                        0xc0.. => 2,
+                        _ => 1,
+                    };
+                    while bytes.len() < len {
+                        bytes.push(key()?);
+                    }
+                    if let Ok(text) = std::str::from_utf8(&bytes) {
+                        for c in text.chars() {
+                            line.insert(cursor, c);
+                            cursor += 1;
+                        }
+                    }
+                }
+                _ => {}
+            }
+            redraw(&line, cursor);
+        }
+    }
+}
+
+#[cfg(unix)]
+fn redraw(line: &[char], cursor: usize) {
+    let text: String = line.iter().collect();
+    let mut out = format!("\r{}{}\x1b[K", PROMPT, text);
+    if cursor < line.len() {
+        out.push_str(&format!("\x1b[{}D", line.len() - cursor));
+    }
+    let mut stdout = io::stdout().lock();
+    let _ = stdout.write_all(out.as_bytes());
+    let _ = stdout.flush();
+}
+
+#[cfg(unix)]
+mod term {
+    // The terminal, raw for as long as this lives: no echo, no line
+    // buffering, and Ctrl-C and Ctrl-D as keys.
+    pub struct Raw(libc::termios);
+
+    impl Raw {
+        pub fn enable() -> Option<Raw> {
+            let mut original = unsafe { std::mem::zeroed::<libc::termios>() };
+            if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut original) } != 0 {
+                return None;
+            }
+            let mut raw = original;
+            raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG | libc::IEXTEN);
+            raw.c_iflag &= !(libc::IXON | libc::ICRNL);
+            raw.c_cc[libc::VMIN] = 1;
+            raw.c_cc[libc::VTIME] = 0;
+            if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) } != 0 {
+                return None;
+            }
+            Some(Raw(original))
+        }
+    }
+
+    impl Drop for Raw {
+        fn drop(&mut self) {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.0) };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_become_control_requests() {
+        let sent = request(&["connect", "example.com:443"]).unwrap();
+        assert_eq!(sent.get("command").and_then(Value::as_str), Some("ping"));
+        assert_eq!(sent.get("count").and_then(Value::as_u64), Some(1));
+
+        let sent = request(&["scan", "10.0.0.1", "22,80"]).unwrap();
+        assert_eq!(sent.get("ports").and_then(Value::as_str), Some("22,80"));
+        assert_eq!(
+            request(&["kill"]).unwrap_err(),
+            "usage: kill <id>".to_string()
+        );
+        assert!(request(&["kill", "x"]).is_err());
+        assert!(request(&["reboot"]).is_err());
+
+        assert_eq!(completions("c"), ["connections", "check", "connect"]);
+        assert_eq!(completions("sc"), ["scan"]);
+    }
+}
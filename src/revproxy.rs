@@ -0,0 +1,522 @@
+// A reverse proxy handler for HTTP: each request goes to the backend whose
+// --http-route matches its Host header and path, with X-Forwarded-For,
+// X-Forwarded-Proto and X-Forwarded-Host set for it. WebSocket and other
+// upgrades are passed through once the backend agrees to them. With
+// --client-ca, only clients with an accepted certificate get through. An
+// [http] section in the config file can hold `route` lines, `client_ca`
+// and `trusted_proxy` lines as well.
+
+use hyper::header::{CONNECTION, CONTENT_TYPE, HOST, HeaderMap, HeaderName, HeaderValue, UPGRADE};
+use hyper::server::conn::Http;
+use hyper::service::{Service, service_fn};
+use hyper::{Body, Client, Request, Response, StatusCode, Uri};
+use std::convert::Infallible;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use tokio::net::TcpStream;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::acl::Cidr;
+use crate::acme;
+use crate::bandwidth::Limits;
+use crate::cli::{Args, Opt};
+use crate::config::Config;
+use crate::context::{ClientIdentity, ConnContext};
+use crate::guard::Guard;
+use crate::history;
+use crate::mtls::ClientCa;
+use crate::outbound::OutboundConfig;
+use crate::say;
+use crate::stats;
+use crate::timeouts;
+
+// Keys of the [http] section.
+const SECTION_KEYS: &[&str] = &["route", "client_ca", "trusted_proxy"];
+
+pub const OPTS: &[Opt] = &[Opt {
+    name: "--http-route",
+    value: Some("<[host][/path]=target>"),
+    help: "Reverse-proxy HTTP requests for a host and path prefix to host:port (repeatable)",
+}];
+
+// Meant for one connection only, so never passed on (RFC 9110 7.6.1).
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// `example.com/api=127.0.0.1:8080`. No host matches any host, a leading
+// `*.` matches any subdomain, and no path means `/`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpRoute {
+    host: Option<String>,
+    path: String,
+    target: String,
+}
+
+impl FromStr for HttpRoute {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<HttpRoute, String> {
+        let invalid = || {
+            format!(
+                "invalid HTTP route '{}', expected [host][/path]=host:port",
+                value
+            )
+        };
+        let (rule, target) = value.rsplit_once('=').ok_or_else(invalid)?;
+        if target
+            .rsplit_once(':')
+            .is_none_or(|(_, port)| port.parse::<u16>().is_err())
+        {
+            return Err(invalid());
+        }
+        let (host, path) = match rule.find('/') {
+            Some(slash) => rule.split_at(slash),
+            None => (rule, "/"),
+        };
+        Ok(HttpRoute {
+            host: (!host.is_empty()).then(|| host.to_ascii_lowercase()),
+            path: path.to_string(),
+            target: target.to_string(),
+        })
+    }
+}
+
+impl HttpRoute {
+    fn matches(&self, host: &str, path: &str) -> bool {
+        let host_matches = match self.host.as_deref() {
+            None => true,
+            Some(wildcard) if wildcard.starts_with("*.") => host.ends_with(&wildcard[1..]),
+            Some(exact) => host == exact,
+        };
+        // `/api` covers `/api` and `/api/users`, not `/apis`.
+        let path_matches = match path.strip_prefix(self.path.as_str()) {
+            Some(rest) => self.path.ends_with('/') || rest.is_empty() || rest.starts_with('/'),
+            None => false,
+        };
+        host_matches && path_matches
+    }
+
+    // Exact hosts beat wildcards, which beat any host; then longer paths.
+    fn rank(&self) -> (u8, usize) {
+        let host = match self.host.as_deref() {
+            None => 0,
+            Some(host) if host.starts_with("*.") => 1,
+            Some(_) => 2,
+        };
+        (host, self.path.len())
+    }
+}
+
+pub fn route<'a>(routes: &'a [HttpRoute], host: &str, path: &str) -> Option<&'a HttpRoute> {
+    routes
+        .iter()
+        .filter(|route| route.matches(host, path))
+        .max_by_key(|route| route.rank())
+}
+
+// Dials backends the way every other outbound connection is made.
+#[derive(Clone)]
+pub struct Dialer {
+    outbound: Arc<OutboundConfig>,
+}
+
+impl Service<Uri> for Dialer {
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let outbound = self.outbound.clone();
+        Box::pin(async move {
+            let target = uri
+                .authority()
+                .map(|authority| authority.to_string())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no backend"))?;
+            timeout(timeouts::get().connect, outbound.connect(&target))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))?
+        })
+    }
+}
+
+pub struct ReverseProxy {
+    routes: Vec<HttpRoute>,
+    client: Client<Dialer>,
+    client_ca: Option<ClientCa>,
+    // --header-timeout and --min-rate, as on the web dashboard.
+    guard: Guard,
+}
+
+impl ReverseProxy {
+    // Routes from the options come before those from the config file, so
+    // they're tried first; --client-ca replaces the file's client_ca, and
+    // trusted proxies from both are taken.
+    pub fn from_args(
+        args: &Args,
+        config: Option<&Config>,
+        outbound: OutboundConfig,
+    ) -> Result<Option<ReverseProxy>, String> {
+        let mut routes = args
+            .values("--http-route")
+            .map(str::parse)
+            .collect::<Result<Vec<HttpRoute>, _>>()?;
+        let mut client_ca = ClientCa::from_args(args)?;
+        let mut proxies = args
+            .values("--trusted-proxy")
+            .map(str::parse)
+            .collect::<Result<Vec<Cidr>, _>>()?;
+        if let Some(config) = config
+            && let Some(section) = config.section("http", SECTION_KEYS)?
+        {
+            routes.extend(section.parsed_all_with(config, "route", str::parse)?);
+            if client_ca.is_none() {
+                client_ca = section.parsed_with(config, "client_ca", ClientCa::load)?;
+            }
+            proxies.extend(section.parsed_all_with(config, "trusted_proxy", str::parse::<Cidr>)?);
+        }
+        if routes.is_empty() {
+            if client_ca.is_some() {
+                return Err("--client-ca needs --http-route, or route lines in [http]".to_string());
+            }
+            return Ok(None);
+        }
+        match &mut client_ca {
+            Some(client_ca) => client_ca.trust(proxies)?,
+            None if !proxies.is_empty() => {
+                return Err("--trusted-proxy is only used with --client-ca".to_string());
+            }
+            None => {}
+        }
+        let client = Client::builder().build(Dialer {
+            outbound: Arc::new(outbound),
+        });
+        Ok(Some(ReverseProxy {
+            routes,
+            client,
+            client_ca,
+            guard: Guard::from_args(args)?,
+        }))
+    }
+
+    async fn forward(
+        &self,
+        mut request: Request<Body>,
+        client: SocketAddr,
+        upgraded: &Mutex<Option<JoinHandle<()>>>,
+        identified: &Mutex<Option<ClientIdentity>>,
+    ) -> Response<Body> {
+        if let Some(response) = acme::respond(request.uri().path()) {
+            return response;
+        }
+        let identity = match &self.client_ca {
+            Some(ca) => match ca.verify(request.headers(), client.ip(), history::now()) {
+                Ok(identity) => Some(identity),
+                Err(e) => {
+                    say!("Rejected {} from {}: {}", request.uri().path(), client, e);
+                    return respond(StatusCode::FORBIDDEN, "client certificate required\n");
+                }
+            },
+            None => None,
+        };
+        // The session is put down to whoever sent the first request.
+        if let Some(identity) = &identity {
+            identified
+                .lock()
+                .unwrap()
+                .get_or_insert_with(|| identity.clone());
+        }
+        let host = request
+            .headers()
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .or(request.uri().host())
+            .unwrap_or_default()
+            .to_string();
+        let path = request.uri().path().to_string();
+        let Some(route) = route(&self.routes, &host_name(&host), &path) else {
+            say!("No HTTP route for {}{} from {}", host, path, client);
+            return respond(StatusCode::NOT_FOUND, "no route\n");
+        };
+
+        let upgrade = wants_upgrade(request.headers());
+        let client_upgrade = upgrade.is_some().then(|| hyper::upgrade::on(&mut request));
+        let (mut parts, body) = request.into_parts();
+        let path_and_query = parts.uri.path_and_query().map_or("/", |path| path.as_str());
+        parts.uri = match format!("http://{}{}", route.target, path_and_query).parse() {
+            Ok(uri) => uri,
+            Err(_) => return respond(StatusCode::BAD_REQUEST, "bad request\n"),
+        };
+        let method = parts.method.clone();
+        rewrite(&mut parts.headers, client.ip(), &host, upgrade);
+
+        let mut response = match self.client.request(Request::from_parts(parts, body)).await {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("Backend {} failed for {}: {}", route.target, client, e);
+                return respond(StatusCode::BAD_GATEWAY, "bad gateway\n");
+            }
+        };
+        say!(
+            "{} {}{} from {}{} to {}: {}",
+            method,
+            host,
+            path,
+            client,
+            identity.map_or(String::new(), |identity| format!(" ({})", identity.subject)),
+            route.target,
+            response.status()
+        );
+
+        match client_upgrade {
+            Some(client_upgrade) if response.status() == StatusCode::SWITCHING_PROTOCOLS => {
+                let backend_upgrade = hyper::upgrade::on(&mut response);
+                let target = route.target.clone();
+                let copy = tokio::spawn(async move {
+                    match tokio::try_join!(client_upgrade, backend_upgrade) {
+                        Ok((mut client, mut backend)) => {
+                            let _ = tokio::io::copy_bidirectional(&mut client, &mut backend).await;
+                        }
+                        Err(e) => eprintln!("Upgrade to {} failed: {}", target, e),
+                    }
+                });
+                *upgraded.lock().unwrap() = Some(copy);
+            }
+            _ => strip_hop_by_hop(response.headers_mut()),
+        }
+        response
+    }
+}
+
+// The Host header without its port.
+fn host_name(host: &str) -> String {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() && !name.ends_with(':') => name,
+        _ => host,
+    };
+    name.trim_matches(['[', ']']).to_ascii_lowercase()
+}
+
+// The protocol asked for, if the request wants to switch to one.
+fn wants_upgrade(headers: &HeaderMap) -> Option<HeaderValue> {
+    let connection = headers.get(CONNECTION)?.to_str().ok()?;
+    connection
+        .split(',')
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"))
+        .then(|| headers.get(UPGRADE).cloned())
+        .flatten()
+}
+
+fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_str(name.trim()).ok())
+        .collect();
+    for name in named {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+// Prepares a request's headers for the backend.
+pub fn rewrite(headers: &mut HeaderMap, client: IpAddr, host: &str, upgrade: Option<HeaderValue>) {
+    strip_hop_by_hop(headers);
+    let forwarded_for = match headers
+        .get("x-forwarded-for")
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(earlier) => format!("{}, {}", earlier, client),
+        None => client.to_string(),
+    };
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+        headers.insert("x-forwarded-for", value);
+    }
+    // This side only speaks plain HTTP.
+    headers.insert("x-forwarded-proto", HeaderValue::from_static("http"));
+    if let Ok(value) = HeaderValue::from_str(host) {
+        headers.insert("x-forwarded-host", value);
+    }
+    if let Some(upgrade) = upgrade {
+        headers.insert(CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(UPGRADE, upgrade);
+    }
+}
+
+fn respond(status: StatusCode, body: &'static str) -> Response<Body> {
+    let mut response = Response::new(Body::from(body));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+    response
+}
+
+pub async fn serve_client(
+    proxy: Arc<ReverseProxy>,
+    socket: TcpStream,
+    mut ctx: ConnContext,
+    limits: Limits,
+) {
+    let addr = ctx.peer;
+    let mut tracker = stats::track("http-proxy", addr);
+    ctx.record(&mut tracker);
+
+    // At most one upgrade per connection: after it, there's no more HTTP.
+    let upgraded = Arc::new(Mutex::new(None));
+    let identified = Arc::new(Mutex::new(None));
+    let guard = proxy.guard;
+    let service = {
+        let upgraded = upgraded.clone();
+        let identified = identified.clone();
+        service_fn(move |request| {
+            let proxy = proxy.clone();
+            let upgraded = upgraded.clone();
+            let identified = identified.clone();
+            async move {
+                let response = proxy.forward(request, addr, &upgraded, &identified).await;
+                Ok::<_, Infallible>(response)
+            }
+        })
+    };
+    let mut http = Http::new();
+    http.http1_only(true)
+        .http1_header_read_timeout(guard.header_timeout);
+    let socket = guard.wrap(limits.wrap(tracker.wrap(socket)), addr);
+    let connection = http.serve_connection(socket, service).with_upgrades();
+
+    let killed = tokio::select! {
+        result = connection => {
+            if let Err(e) = result {
+                tracker.error(e);
+            }
+            false
+        }
+        _ = tracker.killed() => true,
+    };
+    let identity = identified.lock().unwrap().take();
+    if let Some(identity) = identity {
+        ctx.identify(identity, &mut tracker);
+    }
+    if killed {
+        say!("Connection from {} closed by control request", addr);
+        return;
+    }
+    let copy = upgraded.lock().unwrap().take();
+    if let Some(copy) = copy {
+        tokio::select! {
+            _ = copy => {}
+            _ = tracker.killed() => say!("Connection from {} closed by control request", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_by_host_and_path() {
+        let routes: Vec<HttpRoute> = [
+            "/=127.0.0.1:8000",
+            "/api=127.0.0.1:8001",
+            "*.example.com=127.0.0.1:8002",
+            "www.example.com/static/=127.0.0.1:8003",
+        ]
+        .iter()
+        .map(|rule| rule.parse().unwrap())
+        .collect();
+        let target = |host: &str, path: &str| {
+            route(&routes, &host_name(host), path).map(|route| route.target.as_str())
+        };
+        assert_eq!(target("other.org", "/"), Some("127.0.0.1:8000"));
+        assert_eq!(target("other.org", "/api/users"), Some("127.0.0.1:8001"));
+        assert_eq!(target("other.org", "/apis"), Some("127.0.0.1:8000"));
+        assert_eq!(
+            target("WWW.example.com:8080", "/api"),
+            Some("127.0.0.1:8002")
+        );
+        assert_eq!(
+            target("www.example.com", "/static/app.js"),
+            Some("127.0.0.1:8003")
+        );
+        assert_eq!(
+            target("example.com", "/static/app.js"),
+            Some("127.0.0.1:8000")
+        );
+        assert!("example.com".parse::<HttpRoute>().is_err());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONNECTION, HeaderValue::from_static("keep-alive, x-secret"));
+        headers.insert("x-secret", HeaderValue::from_static("1"));
+        headers.insert("x-forwarded-for", HeaderValue::from_static("198.51.100.7"));
+        rewrite(
+            &mut headers,
+            "192.0.2.1".parse().unwrap(),
+            "www.example.com",
+            Some(HeaderValue::from_static("websocket")),
+        );
+        assert!(headers.get("x-secret").is_none());
+        assert_eq!(headers["x-forwarded-for"], "198.51.100.7, 192.0.2.1");
+        assert_eq!(headers["x-forwarded-proto"], "http");
+        assert_eq!(headers["x-forwarded-host"], "www.example.com");
+        assert_eq!(headers[UPGRADE], "websocket");
+    }
+
+    #[tokio::test]
+    async fn closes_connections_that_are_slow_to_send_headers() {
+        use crate::guard;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let tokens = ["--http-route", "/=127.0.0.1:9", "--header-timeout", "300ms"];
+        let args = Args::parse(tokens.map(String::from), &[OPTS, guard::OPTS]).unwrap();
+        let proxy = ReverseProxy::from_args(&args, None, OutboundConfig::default())
+            .unwrap()
+            .unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, peer) = listener.accept().await.unwrap();
+        tokio::spawn(serve_client(
+            Arc::new(proxy),
+            socket,
+            ConnContext::new(peer),
+            Limits::default(),
+        ));
+
+        client.write_all(b"GET / HTTP/1.1\r\nHo").await.unwrap();
+        let started = std::time::Instant::now();
+        let mut rest = Vec::new();
+        let read = timeout(
+            std::time::Duration::from_secs(5),
+            client.read_to_end(&mut rest),
+        )
+        .await;
+        assert!(read.is_ok(), "still open after 5s");
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,1450 @@
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::{Duration, Instant, sleep_until, timeout};
+
+use crate::adaptive::{self, Limiter, Outcome, Permit};
+use crate::cli::{self, Args, Command, Opt};
+use crate::dns;
+use crate::exit;
+use crate::fingerprint::Syn;
+use crate::geoip;
+use crate::history;
+use crate::json::{self, Value};
+use crate::osguess::{self, Guess};
+use crate::outbound::{self, OutboundConfig};
+use crate::output::{self, Mode};
+use crate::progress::{self, Progress, Tracker, Unit};
+use crate::say;
+use crate::services::{self, Service};
+#[cfg(feature = "syn-scan")]
+use crate::synscan;
+use crate::targets;
+use crate::timeouts;
+use crate::udpscan;
+use crate::units::{self, MILLIS};
+
+const DEFAULT_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_CONCURRENCY: usize = 256;
+const MIN_CONCURRENCY: usize = 8;
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+const AUTO_CHECKPOINT_PROBES: usize = 10_000;
+const PTR_CONCURRENCY: usize = 32;
+const MAX_REMOTE_HOSTS: usize = 65536;
+const MAX_LAN_HOSTS: usize = 4096;
+const COMMON_PORTS: &[u16] = &[
+    21, 22, 23, 25, 53, 80, 110, 135, 139, 143, 443, 445, 548, 631, 993, 995, 1883, 3306, 3389,
+    5000, 5900, 8080, 8443, 9100,
+];
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--ports",
+        value: Some("<list>"),
+        help: "Ports to probe, e.g. 22,80,8000-8100 (default: common services)",
+    },
+    Opt {
+        name: "--timeout",
+        value: Some("<duration>"),
+        help: "Connect timeout per port (default: 1s)",
+    },
+    Opt {
+        name: "--concurrency",
+        value: Some("<n>"),
+        help: "Most connection attempts in flight; fewer while timeouts pile up (default: 256)",
+    },
+    Opt {
+        name: "--output",
+        value: Some("<format>"),
+        help: "Report format: text, json, csv or xml (nmap-style) (default: text)",
+    },
+    Opt {
+        name: "--rate",
+        value: Some("<per-sec>"),
+        help: "Most connection attempts started per second (default: unlimited)",
+    },
+    Opt {
+        name: "--host-concurrency",
+        value: Some("<n>"),
+        help: "Most attempts in flight against any one host (default: unlimited)",
+    },
+    Opt {
+        name: "--sequential",
+        value: None,
+        help: "Probe hosts and ports in order instead of shuffled",
+    },
+    Opt {
+        name: "--udp",
+        value: None,
+        help: "Also probe common UDP services (DNS, NTP, SNMP, NetBIOS, ...)",
+    },
+    Opt {
+        name: "--udp-ports",
+        value: Some("<list>"),
+        help: "UDP ports to probe, in the same format as --ports (implies --udp)",
+    },
+    Opt {
+        name: "--syn",
+        value: None,
+        help: "Half-open SYN scan over a raw socket for IPv4 targets; needs root or CAP_NET_RAW, falls back to connect scanning otherwise",
+    },
+    Opt {
+        name: "--os-guess",
+        value: None,
+        help: "Guess each host's OS from banners, open ports and, with --syn, its TCP stack",
+    },
+    Opt {
+        name: "--no-resolve",
+        value: None,
+        help: "Skip reverse DNS lookups of scanned hosts",
+    },
+    Opt {
+        name: "--checkpoint",
+        value: Some("<path>"),
+        help: "Save progress to this file every few seconds (default: scan-state.json next to the history file, for scans of 10000+ probes)",
+    },
+    Opt {
+        name: "--resume",
+        value: Some("<path>"),
+        help: "Continue the interrupted scan saved in this checkpoint file",
+    },
+    Opt {
+        name: "--diff",
+        value: Some("<report.json>"),
+        help: "Only print ports opened or closed since this JSON report; exit 1 on changes",
+    },
+    Opt {
+        name: "--save",
+        value: Some("<path>"),
+        help: "Also write the JSON report to this file",
+    },
+];
+
+const LAN_OPTS: &[Opt] = &[Opt {
+    name: "--subnet",
+    value: Some("<target>"),
+    help: "Network or range to sweep, repeatable (default: the local IPv4 address's /24)",
+}];
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum State {
+    Open,
+    Closed,
+    Filtered,
+    OpenFiltered,
+}
+
+impl State {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::Open => "open",
+            State::Closed => "closed",
+            State::Filtered => "filtered",
+            State::OpenFiltered => "open|filtered",
+        }
+    }
+
+    fn parse(value: &str) -> Option<State> {
+        [
+            State::Open,
+            State::Closed,
+            State::Filtered,
+            State::OpenFiltered,
+        ]
+        .into_iter()
+        .find(|state| state.as_str() == value)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Proto {
+    Tcp,
+    Udp,
+}
+
+impl Proto {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Proto::Tcp => "tcp",
+            Proto::Udp => "udp",
+        }
+    }
+
+    fn parse(value: &str) -> Option<Proto> {
+        [Proto::Tcp, Proto::Udp]
+            .into_iter()
+            .find(|proto| proto.as_str() == value)
+    }
+}
+
+pub struct PortResult {
+    pub port: u16,
+    pub proto: Proto,
+    pub state: State,
+    pub rtt: Option<Duration>,
+    pub service: Option<Service>,
+}
+
+pub struct HostReport {
+    pub addr: IpAddr,
+    pub name: Option<String>,
+    pub ptr: Option<String>,
+    pub ports: Vec<PortResult>,
+    // The first SYN-ACK of a SYN scan, for OS guessing.
+    pub syn_ack: Option<Syn>,
+    pub os: Option<Guess>,
+}
+
+impl PortResult {
+    pub fn to_json(&self) -> Value {
+        Value::object([
+            ("port", Value::from(self.port as u64)),
+            ("protocol", Value::from(self.proto.as_str())),
+            ("state", Value::from(self.state.as_str())),
+            (
+                "rtt_ms",
+                Value::from(
+                    self.rtt
+                        .map(|rtt| (rtt.as_secs_f64() * 10_000.0).round() / 10.0),
+                ),
+            ),
+            (
+                "service",
+                Value::from(self.service.as_ref().map(|s| s.name.clone())),
+            ),
+            (
+                "version",
+                Value::from(self.service.as_ref().and_then(|s| s.version.clone())),
+            ),
+        ])
+    }
+
+    fn from_json(value: &Value) -> Option<PortResult> {
+        let text = |key| value.get(key).and_then(Value::as_str);
+        Some(PortResult {
+            port: u16::try_from(value.get("port")?.as_u64()?).ok()?,
+            proto: Proto::parse(text("protocol")?)?,
+            state: State::parse(text("state")?)?,
+            rtt: value
+                .get("rtt_ms")
+                .and_then(Value::as_f64)
+                .map(|ms| Duration::from_secs_f64(ms / 1000.0)),
+            service: text("service").map(|name| Service {
+                name: name.to_string(),
+                version: text("version").map(str::to_string),
+                banner: None,
+            }),
+        })
+    }
+}
+
+impl HostReport {
+    // A refused connection proves the host is there as much as an open port.
+    fn up(&self) -> bool {
+        self.ports
+            .iter()
+            .any(|p| matches!(p.state, State::Open | State::Closed))
+    }
+
+    fn geo(&self) -> Option<String> {
+        geoip::lookup(self.addr).map(|geo| geo.to_string())
+    }
+}
+
+pub struct Report {
+    pub kind: &'static str,
+    started: u64,
+    elapsed: Duration,
+    pub hosts: Vec<HostReport>,
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Text,
+    Json,
+    Csv,
+    Xml,
+}
+
+impl Format {
+    fn from_args(args: &Args) -> Result<Format, String> {
+        match args.value("--output").unwrap_or("text") {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "xml" => Ok(Format::Xml),
+            other => Err(format!(
+                "unknown --output '{}', expected text, json, csv or xml",
+                other
+            )),
+        }
+    }
+}
+
+pub fn parse_ports(value: &str) -> Result<Vec<u16>, String> {
+    let invalid = || format!("invalid port list '{}'", value);
+    let mut ports = Vec::new();
+    for part in value.split(',').map(str::trim) {
+        match part.split_once('-') {
+            Some((from, to)) => {
+                let from: u16 = from.parse().map_err(|_| invalid())?;
+                let to: u16 = to.parse().map_err(|_| invalid())?;
+                if from == 0 || from > to {
+                    return Err(invalid());
+                }
+                ports.extend(from..=to);
+            }
+            None => ports.push(part.parse().ok().filter(|p| *p > 0).ok_or_else(invalid)?),
+        }
+    }
+    ports.sort_unstable();
+    ports.dedup();
+    Ok(ports)
+}
+
+type ProbeKey = (IpAddr, &'static str, u16);
+
+// Finished probes of a long scan, saved every few seconds so an interrupted
+// scan can pick up where it stopped. The file also keeps the command line,
+// which --resume reuses so the same targets and ports are probed.
+struct Checkpoint {
+    path: PathBuf,
+    kind: &'static str,
+    args: Vec<String>,
+    done: Mutex<Vec<Value>>,
+    resumed: Mutex<HashMap<ProbeKey, PortResult>>,
+    saved: Mutex<Instant>,
+}
+
+impl Checkpoint {
+    fn new(
+        args: &Args,
+        kind: &'static str,
+        tokens: Vec<String>,
+        previous: Option<Value>,
+        probes: usize,
+    ) -> Option<Checkpoint> {
+        let path = match args.value("--resume").or(args.value("--checkpoint")) {
+            Some(path) => PathBuf::from(path),
+            None if probes >= AUTO_CHECKPOINT_PROBES => {
+                history::default_path().with_file_name("scan-state.json")
+            }
+            None => return None,
+        };
+        if output::human() {
+            eprintln!(
+                "Saving progress to {}; continue an interrupted scan with --resume {}",
+                path.display(),
+                path.display()
+            );
+        }
+
+        let done = previous
+            .as_ref()
+            .and_then(|state| state.get("done"))
+            .map(Value::as_array)
+            .unwrap_or_default()
+            .to_vec();
+        let resumed = done
+            .iter()
+            .filter_map(|entry| {
+                let addr = entry.get("addr")?.as_str()?.parse().ok()?;
+                let result = PortResult::from_json(entry.get("result")?)?;
+                Some(((addr, result.proto.as_str(), result.port), result))
+            })
+            .collect();
+        Some(Checkpoint {
+            path,
+            kind,
+            args: tokens,
+            done: Mutex::new(done),
+            resumed: Mutex::new(resumed),
+            saved: Mutex::new(Instant::now()),
+        })
+    }
+
+    fn take_resumed(&self, addr: IpAddr, proto: Proto, port: u16) -> Option<PortResult> {
+        self.resumed
+            .lock()
+            .unwrap()
+            .remove(&(addr, proto.as_str(), port))
+    }
+
+    fn record(&self, addr: IpAddr, result: &PortResult) {
+        self.done.lock().unwrap().push(Value::object([
+            ("addr", Value::from(addr.to_string())),
+            ("result", result.to_json()),
+        ]));
+        let mut saved = self.saved.lock().unwrap();
+        if saved.elapsed() >= CHECKPOINT_INTERVAL {
+            self.save();
+            *saved = Instant::now();
+        }
+    }
+
+    fn save(&self) {
+        let state = Value::object([
+            ("kind", Value::from(self.kind)),
+            (
+                "args",
+                Value::Array(self.args.iter().map(|a| Value::from(a.as_str())).collect()),
+            ),
+            ("done", Value::Array(self.done.lock().unwrap().clone())),
+        ]);
+        // Write then rename, so an interruption mid-write keeps the last
+        // complete checkpoint.
+        let temp = self.path.with_extension("tmp");
+        let result = self
+            .path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&temp, format!("{}\n", state)))
+            .and_then(|_| std::fs::rename(&temp, &self.path));
+        if let Err(e) = result {
+            eprintln!("Failed to save checkpoint {}: {}", self.path.display(), e);
+        }
+    }
+
+    fn finish(&self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn without_resume(tokens: impl IntoIterator<Item = String>) -> Vec<String> {
+    let mut kept = Vec::new();
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        if token == "--resume" {
+            tokens.next();
+        } else if !token.starts_with("--resume=") {
+            kept.push(token);
+        }
+    }
+    kept
+}
+
+// Parses the command line, or for --resume the one saved in the checkpoint
+// followed by any options given now, which win over the saved ones. --resume
+// is added back so the resumed scan keeps saving to the same file.
+fn parse_resumable(
+    kind: &str,
+    command: &Command,
+    tokens: Vec<String>,
+) -> (Args, Vec<String>, Option<Value>) {
+    let args = cli::parse_command(command, tokens.clone());
+    let Some(path) = args.value("--resume").map(str::to_string) else {
+        return (args, tokens, None);
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) => {
+            eprintln!("Failed to read checkpoint {}: {}", path, e);
+            std::process::exit(exit::code(&e));
+        }
+    };
+    let state =
+        cli::or_exit(json::parse(&text).map_err(|e| format!("invalid checkpoint {}: {}", path, e)));
+    if state.get("kind").and_then(Value::as_str) != Some(kind) {
+        eprintln!("{} is not a scan-{} checkpoint", path, kind);
+        std::process::exit(exit::USAGE);
+    }
+    let saved = state
+        .get("args")
+        .map(Value::as_array)
+        .unwrap_or_default()
+        .iter()
+        .filter_map(Value::as_str)
+        .map(str::to_string);
+    let mut saved = without_resume(saved);
+    saved.extend(without_resume(tokens));
+    saved.extend(["--resume".to_string(), path]);
+    let args = cli::parse_command(command, saved.clone());
+    (args, saved, Some(state))
+}
+
+struct Scanner {
+    outbound: OutboundConfig,
+    ports: Vec<u16>,
+    udp_ports: Vec<u16>,
+    wait: Duration,
+    limiter: Arc<Limiter>,
+    host_concurrency: Option<usize>,
+    pace: Option<Duration>,
+    next_start: Mutex<Instant>,
+    sequential: bool,
+    syn: bool,
+    resolve: bool,
+    os_guess: bool,
+    checkpoint: Option<Checkpoint>,
+    progress: Option<Arc<dyn Progress>>,
+}
+
+impl Scanner {
+    fn from_args(args: &Args) -> Result<Scanner, String> {
+        let ports = match args.value("--ports") {
+            Some(ports) => parse_ports(ports)?,
+            None => COMMON_PORTS.to_vec(),
+        };
+        let udp_ports = match args.value("--udp-ports") {
+            Some(ports) => parse_ports(ports)?,
+            None if args.flag("--udp") => udpscan::COMMON_PORTS.to_vec(),
+            None => Vec::new(),
+        };
+        let outbound = OutboundConfig::from_args(args)?;
+        // Through a proxy only its own connections can be seen.
+        if outbound.proxy.is_some()
+            && (args.flag("--syn") || args.flag("--os-guess") || !udp_ports.is_empty())
+        {
+            return Err(
+                "--proxy only carries TCP connect scans, not --syn, --os-guess or UDP".to_string(),
+            );
+        }
+        let mut concurrency = args
+            .parsed("--concurrency")?
+            .unwrap_or(DEFAULT_CONCURRENCY)
+            .max(1);
+        if let Some(budget) = adaptive::fd_budget()
+            && concurrency > budget
+        {
+            eprintln!(
+                "Open file limit allows {} connections in flight, lowering --concurrency",
+                budget
+            );
+            concurrency = budget;
+        }
+
+        let rate: Option<f64> = args.parsed("--rate")?;
+        if rate.is_some_and(|rate| rate <= 0.0) {
+            return Err("--rate must be positive".to_string());
+        }
+
+        Ok(Scanner {
+            outbound,
+            ports,
+            udp_ports,
+            wait: args
+                .parsed_with("--timeout", |v| units::duration(v, MILLIS))?
+                .unwrap_or(Duration::from_millis(DEFAULT_TIMEOUT_MS)),
+            limiter: Limiter::new(MIN_CONCURRENCY, concurrency),
+            host_concurrency: args
+                .parsed::<usize>("--host-concurrency")?
+                .map(|n| n.max(1)),
+            pace: rate.map(|rate| Duration::from_secs_f64(1.0 / rate)),
+            next_start: Mutex::new(Instant::now()),
+            sequential: args.flag("--sequential"),
+            syn: args.flag("--syn"),
+            resolve: !args.flag("--no-resolve"),
+            os_guess: args.flag("--os-guess"),
+            checkpoint: None,
+            progress: progress::from_args(args),
+        })
+    }
+
+    // Hands out evenly spaced start times so bursts never exceed --rate.
+    async fn wait_turn(&self) {
+        let Some(pace) = self.pace else {
+            return;
+        };
+        let start = {
+            let mut next = self.next_start.lock().unwrap();
+            let start = (*next).max(Instant::now());
+            *next = start + pace;
+            start
+        };
+        sleep_until(start).await;
+    }
+
+    async fn probe(
+        &self,
+        proto: Proto,
+        addr: SocketAddr,
+        host: Option<Arc<Semaphore>>,
+        permit: Permit,
+    ) -> PortResult {
+        let _host_permit = match &host {
+            Some(host) => Some(host.acquire().await),
+            None => None,
+        };
+        self.wait_turn().await;
+        if proto == Proto::Udp {
+            let answer = udpscan::probe(&self.outbound, addr, self.wait).await;
+            // Silence is normal for UDP, so only answers move the limit.
+            if matches!(answer.state, State::Open | State::Closed) {
+                permit.finish(Outcome::Answered);
+            }
+            return PortResult {
+                port: addr.port(),
+                proto,
+                state: answer.state,
+                rtt: answer.rtt,
+                service: answer.service,
+            };
+        }
+        let start = Instant::now();
+        let (state, outcome) = match timeout(self.wait, self.outbound.connect_addr(addr)).await {
+            Ok(Ok(_)) => (State::Open, Outcome::Answered),
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                (State::Closed, Outcome::Answered)
+            }
+            Ok(Err(e)) => (State::Filtered, Outcome::from_error(&e)),
+            Err(_) => (State::Filtered, Outcome::TimedOut),
+        };
+        permit.finish(outcome);
+        PortResult {
+            port: addr.port(),
+            proto,
+            state,
+            rtt: (state != State::Filtered).then(|| start.elapsed()),
+            service: None,
+        }
+    }
+
+    async fn scan(self: Arc<Self>, targets: Vec<(IpAddr, Option<String>)>) -> Vec<HostReport> {
+        let host_permits: Vec<_> = targets
+            .iter()
+            .map(|_| self.host_concurrency.map(|n| Arc::new(Semaphore::new(n))))
+            .collect();
+        let mut order: Vec<(usize, Proto, u16)> = (0..targets.len())
+            .flat_map(|index| {
+                let tcp = self
+                    .ports
+                    .iter()
+                    .map(move |&port| (index, Proto::Tcp, port));
+                let udp = self
+                    .udp_ports
+                    .iter()
+                    .map(move |&port| (index, Proto::Udp, port));
+                tcp.chain(udp)
+            })
+            .collect();
+        // Shuffled probes spread load across hosts and don't look like a sweep.
+        if !self.sequential {
+            order.shuffle(&mut rand::thread_rng());
+        }
+
+        let mut hosts: Vec<HostReport> = targets
+            .into_iter()
+            .map(|(addr, name)| HostReport {
+                addr,
+                name,
+                ptr: None,
+                syn_ack: None,
+                os: None,
+                ports: Vec::new(),
+            })
+            .collect();
+        if let Some(checkpoint) = &self.checkpoint {
+            let total = order.len();
+            order.retain(|&(index, proto, port)| {
+                let host = &mut hosts[index];
+                match checkpoint.take_resumed(host.addr, proto, port) {
+                    Some(result) => {
+                        host.ports.push(result);
+                        false
+                    }
+                    None => true,
+                }
+            });
+            if order.len() < total && output::human() {
+                eprintln!(
+                    "Resuming, {} of {} probes already done",
+                    total - order.len(),
+                    total
+                );
+            }
+        }
+        let total = order.len();
+        let tracker = self.progress.clone().map(|progress| {
+            Tracker::new(progress, "scan", Unit::Items("probes"), Some(total as u64))
+        });
+        if self.syn {
+            order = self.syn_scan(&mut hosts, order).await;
+            if let Some(tracker) = &tracker {
+                tracker.add((total - order.len()) as u64);
+            }
+        }
+
+        // Probes are only spawned once the limiter has room for them, so a
+        // wide scan never holds more tasks or sockets than the limit allows.
+        let mut probes = JoinSet::new();
+        let mut order = order.into_iter();
+        let mut queued = order.next();
+        loop {
+            if queued.is_none() && probes.is_empty() {
+                break;
+            }
+            let result = tokio::select! {
+                permit = self.limiter.acquire(), if queued.is_some() => {
+                    let Some((index, proto, port)) = queued.take() else {
+                        continue;
+                    };
+                    queued = order.next();
+                    let scanner = self.clone();
+                    let addr = SocketAddr::new(hosts[index].addr, port);
+                    let host = host_permits[index].clone();
+                    probes.spawn(async move {
+                        (index, scanner.probe(proto, addr, host, permit).await)
+                    });
+                    continue;
+                }
+                Some(result) = probes.join_next() => result,
+                _ = tokio::signal::ctrl_c(), if self.checkpoint.is_some() => {
+                    if let Some(checkpoint) = &self.checkpoint {
+                        checkpoint.save();
+                    }
+                    eprintln!("Interrupted, progress saved");
+                    std::process::exit(exit::INTERRUPTED);
+                }
+            };
+            if let Ok((index, port)) = result {
+                if let Some(checkpoint) = &self.checkpoint {
+                    checkpoint.record(hosts[index].addr, &port);
+                }
+                hosts[index].ports.push(port);
+            }
+            if let Some(tracker) = &tracker {
+                tracker.add(1);
+            }
+        }
+        if let Some(tracker) = &tracker {
+            tracker.finish();
+        }
+        for host in &mut hosts {
+            host.ports.sort_by_key(|p| (p.proto.as_str(), p.port));
+        }
+        hosts
+    }
+
+    // Fills in results for the IPv4 TCP probes and hands back whatever still
+    // needs probing: UDP, IPv6 targets, or everything if raw sockets fail.
+    #[cfg(feature = "syn-scan")]
+    async fn syn_scan(
+        &self,
+        hosts: &mut [HostReport],
+        order: Vec<(usize, Proto, u16)>,
+    ) -> Vec<(usize, Proto, u16)> {
+        let raw = match synscan::open(&self.outbound) {
+            Ok(raw) => raw,
+            Err(e) => {
+                eprintln!(
+                    "SYN scan unavailable ({}), falling back to connect scanning",
+                    e
+                );
+                return order;
+            }
+        };
+        let (v4, rest): (Vec<_>, Vec<_>) = order
+            .into_iter()
+            .partition(|&(index, proto, _)| proto == Proto::Tcp && hosts[index].addr.is_ipv4());
+        let probes: Vec<(std::net::Ipv4Addr, u16)> = v4
+            .iter()
+            .filter_map(|&(index, _, port)| match hosts[index].addr {
+                IpAddr::V4(addr) => Some((addr, port)),
+                IpAddr::V6(_) => None,
+            })
+            .collect();
+        let mut answers = match raw.scan(&probes, self.wait, self.pace).await {
+            Ok(answers) => answers,
+            Err(e) => {
+                eprintln!("SYN scan failed ({}), falling back to connect scanning", e);
+                return v4.into_iter().chain(rest).collect();
+            }
+        };
+        for (&(index, proto, port), probe) in v4.iter().zip(&probes) {
+            let (state, rtt, syn_ack) = match answers.remove(probe) {
+                Some(answer) => (answer.state, Some(answer.rtt), answer.syn_ack),
+                None => (State::Filtered, None, None),
+            };
+            let host = &mut hosts[index];
+            if host.syn_ack.is_none() {
+                host.syn_ack = syn_ack;
+            }
+            let result = PortResult {
+                port,
+                proto,
+                state,
+                rtt,
+                service: None,
+            };
+            if let Some(checkpoint) = &self.checkpoint {
+                checkpoint.record(host.addr, &result);
+            }
+            host.ports.push(result);
+        }
+        rest
+    }
+
+    // The service pass only visits open TCP ports, under the same
+    // concurrency and rate limits as the scan itself.
+    async fn identify(self: Arc<Self>, hosts: &mut [HostReport]) {
+        let mut probes = JoinSet::new();
+        for (index, host) in hosts.iter().enumerate() {
+            for (slot, port) in host.ports.iter().enumerate() {
+                if port.proto != Proto::Tcp || port.state != State::Open {
+                    continue;
+                }
+                let scanner = self.clone();
+                let addr = SocketAddr::new(host.addr, port.port);
+                let permit = self.limiter.acquire().await;
+                probes.spawn(async move {
+                    let _permit = permit;
+                    scanner.wait_turn().await;
+                    let service = services::detect(&scanner.outbound, addr, scanner.wait).await;
+                    (index, slot, service)
+                });
+            }
+        }
+        while let Some(result) = probes.join_next().await {
+            if let Ok((index, slot, Some(service))) = result {
+                hosts[index].ports[slot].service = Some(service);
+            }
+        }
+    }
+
+    #[cfg(not(feature = "syn-scan"))]
+    async fn syn_scan(
+        &self,
+        _: &mut [HostReport],
+        order: Vec<(usize, Proto, u16)>,
+    ) -> Vec<(usize, Proto, u16)> {
+        eprintln!("This build has no SYN scan support, falling back to connect scanning");
+        order
+    }
+}
+
+impl Report {
+    // Remote targets were each named on purpose, so any that stay silent make
+    // the results partial; a LAN sweep expects most addresses to be empty.
+    fn exit_code(&self) -> i32 {
+        let up = self.hosts.iter().filter(|host| host.up()).count();
+        match self.kind {
+            "lan" if up > 0 => exit::SUCCESS,
+            _ => exit::from_counts(up, self.hosts.len()),
+        }
+    }
+
+    fn to_json(&self) -> Value {
+        let hosts = self.hosts.iter().map(|host| {
+            let ports = host.ports.iter().map(PortResult::to_json);
+            Value::object([
+                ("addr", Value::from(host.addr.to_string())),
+                ("name", Value::from(host.name.clone())),
+                ("ptr", Value::from(host.ptr.clone())),
+                ("geo", Value::from(host.geo())),
+                ("status", Value::from(if host.up() { "up" } else { "down" })),
+                (
+                    "os_guess",
+                    match &host.os {
+                        Some(guess) => Value::object([
+                            ("os", Value::from(guess.os)),
+                            ("confidence", Value::from(guess.confidence as u64)),
+                            (
+                                "evidence",
+                                Value::Array(
+                                    guess
+                                        .evidence
+                                        .iter()
+                                        .map(|e| Value::from(e.as_str()))
+                                        .collect(),
+                                ),
+                            ),
+                        ]),
+                        None => Value::Null,
+                    },
+                ),
+                ("ports", Value::Array(ports.collect())),
+            ])
+        });
+
+        Value::object([
+            ("schema_version", Value::from(output::SCHEMA_VERSION)),
+            ("scanner", Value::from("netcore")),
+            ("version", Value::from(env!("CARGO_PKG_VERSION"))),
+            ("kind", Value::from(self.kind)),
+            ("started", Value::from(self.started)),
+            ("elapsed_ms", Value::from(self.elapsed.as_millis() as u64)),
+            ("hosts", Value::Array(hosts.collect())),
+        ])
+    }
+
+    fn to_csv(&self) -> String {
+        let mut out =
+            String::from("host,name,ptr,os_guess,port,protocol,state,rtt_ms,service,version\n");
+        for host in &self.hosts {
+            for p in &host.ports {
+                out.push_str(&format!(
+                    "{},{},{},{},{},{},{},{},{},{}\n",
+                    host.addr,
+                    csv_field(host.name.as_deref().unwrap_or_default()),
+                    csv_field(host.ptr.as_deref().unwrap_or_default()),
+                    host.os
+                        .as_ref()
+                        .map(|g| csv_field(&format!("{} ({}%)", g.os, g.confidence)))
+                        .unwrap_or_default(),
+                    p.port,
+                    p.proto.as_str(),
+                    p.state.as_str(),
+                    p.rtt
+                        .map(|rtt| format!("{:.1}", rtt.as_secs_f64() * 1000.0))
+                        .unwrap_or_default(),
+                    csv_field(p.service.as_ref().map_or("", |s| &s.name)),
+                    csv_field(
+                        p.service
+                            .as_ref()
+                            .and_then(|s| s.version.as_deref())
+                            .unwrap_or_default()
+                    )
+                ));
+            }
+        }
+        out
+    }
+
+    // The subset of nmap's XML that common importers read: hosts, status,
+    // addresses, hostnames and per-port state, with filtered ports folded
+    // into <extraports>.
+    fn to_xml(&self) -> String {
+        let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        out.push_str(&format!(
+            "<nmaprun scanner=\"netcore\" version=\"{}\" start=\"{}\" xmloutputversion=\"1.05\">\n",
+            env!("CARGO_PKG_VERSION"),
+            self.started
+        ));
+        for host in &self.hosts {
+            let state = if host.up() { "up" } else { "down" };
+            out.push_str(&format!("<host><status state=\"{}\"/>\n", state));
+            out.push_str(&format!(
+                "<address addr=\"{}\" addrtype=\"{}\"/>\n",
+                host.addr,
+                if host.addr.is_ipv4() { "ipv4" } else { "ipv6" }
+            ));
+            let names = [(&host.name, "user"), (&host.ptr, "PTR")];
+            let names: String = names
+                .iter()
+                .filter_map(|(name, kind)| {
+                    let name = xml_escape(name.as_deref()?);
+                    Some(format!("<hostname name=\"{}\" type=\"{}\"/>", name, kind))
+                })
+                .collect();
+            if !names.is_empty() {
+                out.push_str(&format!("<hostnames>{}</hostnames>\n", names));
+            }
+            out.push_str("<ports>\n");
+            for state in [State::Filtered, State::OpenFiltered] {
+                let count = host.ports.iter().filter(|p| p.state == state).count();
+                if count > 0 {
+                    out.push_str(&format!(
+                        "<extraports state=\"{}\" count=\"{}\"/>\n",
+                        state.as_str(),
+                        count
+                    ));
+                }
+            }
+            for p in host
+                .ports
+                .iter()
+                .filter(|p| matches!(p.state, State::Open | State::Closed))
+            {
+                let reason = match (p.proto, p.state) {
+                    (Proto::Tcp, State::Open) => "syn-ack",
+                    (Proto::Tcp, _) => "conn-refused",
+                    (Proto::Udp, State::Open) => "udp-response",
+                    (Proto::Udp, _) => "port-unreach",
+                };
+                let service = match &p.service {
+                    Some(Service {
+                        name,
+                        version: Some(version),
+                        ..
+                    }) => format!(
+                        "<service name=\"{}\" version=\"{}\"/>",
+                        xml_escape(name),
+                        xml_escape(version)
+                    ),
+                    Some(service) => format!("<service name=\"{}\"/>", xml_escape(&service.name)),
+                    None => String::new(),
+                };
+                out.push_str(&format!(
+                    "<port protocol=\"{}\" portid=\"{}\"><state state=\"{}\" reason=\"{}\"/>{}</port>\n",
+                    p.proto.as_str(),
+                    p.port,
+                    p.state.as_str(),
+                    reason,
+                    service
+                ));
+            }
+            out.push_str("</ports>");
+            if let Some(guess) = &host.os {
+                out.push_str(&format!(
+                    "<os><osmatch name=\"{}\" accuracy=\"{}\"/></os>",
+                    xml_escape(guess.os),
+                    guess.confidence
+                ));
+            }
+            out.push_str("</host>\n");
+        }
+        out.push_str(&format!(
+            "<runstats><finished time=\"{}\" elapsed=\"{:.2}\"/><hosts up=\"{}\" down=\"{}\" total=\"{}\"/></runstats>\n</nmaprun>\n",
+            self.started + self.elapsed.as_secs(),
+            self.elapsed.as_secs_f64(),
+            self.hosts.iter().filter(|h| h.up()).count(),
+            self.hosts.iter().filter(|h| !h.up()).count(),
+            self.hosts.len()
+        ));
+        out
+    }
+
+    fn print_text(&self) {
+        for host in self
+            .hosts
+            .iter()
+            .filter(|h| h.up() || self.kind == "remote")
+        {
+            let mut label = match (&host.name, &host.ptr) {
+                (Some(name), Some(ptr)) if name != ptr => {
+                    format!("{} ({}, {})", name, host.addr, ptr)
+                }
+                (Some(name), _) | (None, Some(name)) => format!("{} ({})", name, host.addr),
+                (None, None) => host.addr.to_string(),
+            };
+            if let Some(geo) = host.geo() {
+                label = format!("{} [{}]", label, geo);
+            }
+            let open: Vec<_> = host
+                .ports
+                .iter()
+                .filter(|p| p.state == State::Open)
+                .collect();
+            let closed = host
+                .ports
+                .iter()
+                .filter(|p| p.state == State::Closed)
+                .count();
+            let silent = host
+                .ports
+                .iter()
+                .filter(|p| p.state == State::OpenFiltered)
+                .count();
+            let filtered = host.ports.len() - open.len() - closed - silent;
+            let mut summary = format!(
+                "{}: {}, {} open, {} closed, {} filtered",
+                label,
+                if host.up() { "up" } else { "down" },
+                open.len(),
+                closed,
+                filtered
+            );
+            if silent > 0 {
+                summary.push_str(&format!(", {} open|filtered", silent));
+            }
+            say!("{}", summary);
+            for p in open {
+                let mut line = format!(
+                    "  {:>5}/{} open  {:.1} ms",
+                    p.port,
+                    p.proto.as_str(),
+                    p.rtt.unwrap_or_default().as_secs_f64() * 1000.0
+                );
+                if let Some(service) = &p.service {
+                    line.push_str(&format!("  {}", service.name));
+                }
+                if let Some(version) = p.service.as_ref().and_then(|s| s.version.as_ref()) {
+                    line.push_str(&format!(" ({})", version));
+                }
+                say!("{}", line);
+            }
+            if let Some(guess) = &host.os {
+                say!(
+                    "  OS guess: {}, {}% confidence ({})",
+                    guess.os,
+                    guess.confidence,
+                    guess.evidence.join("; ")
+                );
+            }
+        }
+        say!(
+            "Scanned {} host(s) in {:.1} s, {} up",
+            self.hosts.len(),
+            self.elapsed.as_secs_f64(),
+            self.hosts.iter().filter(|h| h.up()).count()
+        );
+    }
+
+    // Porcelain mode prints each host as a line of JSON, whatever --output
+    // says.
+    fn print(&self, format: Format) {
+        match output::mode() {
+            Mode::Human => {}
+            Mode::Quiet => return,
+            Mode::Porcelain => {
+                for host in self
+                    .to_json()
+                    .get("hosts")
+                    .map(Value::as_array)
+                    .unwrap_or_default()
+                {
+                    output::emit(host.clone());
+                }
+                return;
+            }
+        }
+        match format {
+            Format::Text => self.print_text(),
+            Format::Json => println!("{}", self.to_json()),
+            Format::Csv => print!("{}", self.to_csv()),
+            Format::Xml => print!("{}", self.to_xml()),
+        }
+    }
+}
+
+type PortKey = (String, u16, String);
+
+fn port_states(report: &Value) -> HashMap<PortKey, String> {
+    let mut states = HashMap::new();
+    for host in report.get("hosts").map(Value::as_array).unwrap_or_default() {
+        let Some(addr) = host.get("addr").and_then(Value::as_str) else {
+            continue;
+        };
+        for port in host.get("ports").map(Value::as_array).unwrap_or_default() {
+            let number = port.get("port").and_then(Value::as_u64);
+            let state = port.get("state").and_then(Value::as_str);
+            let proto = port.get("protocol").and_then(Value::as_str);
+            if let (Some(number), Some(state)) = (number, state) {
+                let proto = proto.unwrap_or("tcp").to_string();
+                states.insert((addr.to_string(), number as u16, proto), state.to_string());
+            }
+        }
+    }
+    states
+}
+
+fn load_report(path: &str) -> Result<Value, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read report {}: {}", path, e))?;
+    json::parse(&text).map_err(|e| format!("invalid report {}: {}", path, e))
+}
+
+// Prints ports that opened or stopped being open and returns how many did.
+// Ports probed in only one of the two scans are not compared.
+fn print_diff(previous: &Value, current: &Value) -> usize {
+    let before = port_states(previous);
+    let after = port_states(current);
+
+    let mut changes: Vec<_> = after
+        .iter()
+        .filter_map(|(key, state)| {
+            let was = before.get(key)?;
+            (*was == "open" && state != "open" || *was != "open" && state == "open")
+                .then_some((key, state, was))
+        })
+        .collect();
+    changes.sort();
+
+    for ((addr, port, proto), state, was) in &changes {
+        let sign = if *state == "open" { '+' } else { '-' };
+        say!(
+            "{} {} {}/{} {} (was {})",
+            sign,
+            addr,
+            port,
+            proto,
+            state,
+            was
+        );
+        output::emit(Value::object([
+            ("addr", Value::from(addr.as_str())),
+            ("port", Value::from(*port as u64)),
+            ("protocol", Value::from(proto.as_str())),
+            ("state", Value::from(state.as_str())),
+            ("was", Value::from(was.as_str())),
+        ]));
+    }
+    changes.len()
+}
+
+fn finish(report: Report, args: &Args, format: Format) {
+    // Read the previous report first: --diff and --save may name the same file.
+    let previous = args
+        .value("--diff")
+        .map(|path| cli::or_exit(load_report(path)));
+
+    let json = report.to_json();
+    if let Some(path) = args.value("--save")
+        && let Err(e) = std::fs::write(path, format!("{}\n", json))
+    {
+        eprintln!("Failed to write {}: {}", path, e);
+        std::process::exit(exit::code(&e));
+    }
+
+    let changed = match previous {
+        Some(previous) => print_diff(&previous, &json) > 0,
+        None => {
+            report.print(format);
+            false
+        }
+    };
+    let code = match changed {
+        true => exit::FAILURE,
+        false => report.exit_code(),
+    };
+    if code != exit::SUCCESS {
+        std::process::exit(code);
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn xml_escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Only hosts that answered are looked up, so a sweep of an empty /24 doesn't
+// wait on hundreds of PTR misses.
+async fn resolve_names(hosts: &mut [HostReport]) {
+    let permits = Arc::new(Semaphore::new(PTR_CONCURRENCY));
+    let mut lookups = JoinSet::new();
+    for (index, host) in hosts.iter().enumerate().filter(|(_, h)| h.up()) {
+        let permits = permits.clone();
+        let addr = host.addr;
+        lookups.spawn(async move {
+            let _permit = permits.acquire().await;
+            (index, dns::reverse(addr, timeouts::get().dns).await)
+        });
+    }
+    while let Some(result) = lookups.join_next().await {
+        if let Ok((index, ptr)) = result {
+            hosts[index].ptr = ptr;
+        }
+    }
+}
+
+async fn run(
+    kind: &'static str,
+    scanner: Scanner,
+    targets: Vec<(IpAddr, Option<String>)>,
+) -> Report {
+    let started = history::now();
+    let start = Instant::now();
+    if output::human() {
+        eprintln!(
+            "Scanning {} port(s) on {} host(s)",
+            scanner.ports.len() + scanner.udp_ports.len(),
+            targets.len()
+        );
+    }
+    let scanner = Arc::new(scanner);
+    let mut hosts = scanner.clone().scan(targets).await;
+    if let Some(checkpoint) = &scanner.checkpoint {
+        checkpoint.finish();
+    }
+    if services::enabled() {
+        scanner.clone().identify(&mut hosts).await;
+    }
+    if scanner.os_guess {
+        for host in &mut hosts {
+            host.os = osguess::guess(host);
+        }
+    }
+    if scanner.resolve {
+        resolve_names(&mut hosts).await;
+    }
+    Report {
+        kind,
+        started,
+        elapsed: start.elapsed(),
+        hosts,
+    }
+}
+
+// Connect-scans loopback ports 1..=count with default settings and returns
+// how many probes finished and how long they took.
+pub async fn loopback_throughput(count: u16) -> Result<(usize, Duration), String> {
+    let mut scanner = Scanner::from_args(&Args::parse(Vec::new(), &[OPTS])?)?;
+    scanner.ports = (1..=count).collect();
+    let start = Instant::now();
+    let hosts = Arc::new(scanner)
+        .scan(vec![(IpAddr::V4(Ipv4Addr::LOCALHOST), None)])
+        .await;
+    Ok((hosts[0].ports.len(), start.elapsed()))
+}
+
+// Connect-scans one host with the server's outbound settings, for the
+// control socket.
+pub async fn host_ports(
+    outbound: &OutboundConfig,
+    addr: IpAddr,
+    ports: Option<&str>,
+) -> Result<Vec<PortResult>, String> {
+    let mut scanner = Scanner::from_args(&Args::parse(Vec::new(), &[OPTS])?)?;
+    scanner.outbound = outbound.clone();
+    if let Some(ports) = ports {
+        scanner.ports = parse_ports(ports)?;
+    }
+    let mut hosts = Arc::new(scanner).scan(vec![(addr, None)]).await;
+    Ok(hosts.remove(0).ports)
+}
+
+pub const REMOTE_COMMAND: Command = Command {
+    name: "scan-remote",
+    usage: "netcore scan-remote <target>...",
+    about: "Scan ports on remote hosts",
+    groups: &[
+        OPTS,
+        targets::OPTS,
+        services::OPTS,
+        outbound::OPTS,
+        timeouts::OPTS,
+        geoip::OPTS,
+        progress::OPTS,
+    ],
+};
+
+pub async fn remote_command(tokens: Vec<String>) {
+    let (args, tokens, previous) = parse_resumable("remote", &REMOTE_COMMAND, tokens);
+    let format = cli::or_exit(Format::from_args(&args));
+    let mut scanner = cli::or_exit(Scanner::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+    cli::or_exit(geoip::init(&args));
+    cli::or_exit(services::init(&args));
+    if args.positional().is_empty() && args.value("--targets-file").is_none() {
+        eprintln!("usage: netcore scan-remote <target>... [--ports <list>] [--output <format>]");
+        std::process::exit(exit::USAGE);
+    }
+
+    let specs = args.positional().to_vec();
+    let hosts = targets::expand(&args, specs, &scanner.outbound, MAX_REMOTE_HOSTS).await;
+    let hosts = exit::or_exit(hosts);
+    let probes = hosts.len() * (scanner.ports.len() + scanner.udp_ports.len());
+    scanner.checkpoint = Checkpoint::new(&args, "remote", tokens, previous, probes);
+    let report = run("remote", scanner, hosts).await;
+    finish(report, &args, format);
+}
+
+pub const LAN_COMMAND: Command = Command {
+    name: "scan-lan",
+    usage: "netcore scan-lan",
+    about: "Find hosts on the local network and scan their ports",
+    groups: &[
+        LAN_OPTS,
+        OPTS,
+        targets::OPTS,
+        services::OPTS,
+        outbound::OPTS,
+        timeouts::OPTS,
+        progress::OPTS,
+    ],
+};
+
+pub async fn lan_command(tokens: Vec<String>) {
+    let (args, tokens, previous) = parse_resumable("lan", &LAN_COMMAND, tokens);
+    let format = cli::or_exit(Format::from_args(&args));
+    let mut scanner = cli::or_exit(Scanner::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+    cli::or_exit(services::init(&args));
+
+    let mut specs: Vec<String> = args.values("--subnet").map(str::to_string).collect();
+    if specs.is_empty() && args.value("--targets-file").is_none() {
+        match crate::get_local_ipv4().await {
+            Some(ip) => specs.push(format!("{}/24", ip)),
+            None => {
+                eprintln!("no local IPv4 address, pass --subnet");
+                std::process::exit(exit::NO_CONNECTIVITY);
+            }
+        }
+    }
+    let hosts = targets::expand(&args, specs, &scanner.outbound, MAX_LAN_HOSTS).await;
+    let hosts = exit::or_exit(hosts);
+    let probes = hosts.len() * (scanner.ports.len() + scanner.udp_ports.len());
+    scanner.checkpoint = Checkpoint::new(&args, "lan", tokens, previous, probes);
+    let report = run("lan", scanner, hosts).await;
+    finish(report, &args, format);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use std::collections::BTreeSet;
+
+    const CASES: u64 = 500;
+
+    #[test]
+    fn parse_ports_returns_exactly_the_listed_ports() {
+        for case in 0..CASES {
+            let mut rng = StdRng::seed_from_u64(case);
+            let mut expected = BTreeSet::new();
+            let mut parts = Vec::new();
+            for _ in 0..rng.gen_range(1..8) {
+                let from: u16 = rng.gen_range(1..=u16::MAX);
+                if rng.gen_bool(0.5) {
+                    let to = from.saturating_add(rng.gen_range(0..300));
+                    expected.extend(from..=to);
+                    parts.push(format!("{}-{}", from, to));
+                } else {
+                    expected.insert(from);
+                    parts.push(from.to_string());
+                }
+            }
+            let separator = if rng.gen_bool(0.5) { "," } else { " , " };
+            let list = parts.join(separator);
+
+            let ports = parse_ports(&list).unwrap();
+            assert!(
+                ports.windows(2).all(|w| w[0] < w[1]),
+                "case {}: {}",
+                case,
+                list
+            );
+            assert_eq!(
+                ports,
+                expected.into_iter().collect::<Vec<_>>(),
+                "case {}: {}",
+                case,
+                list
+            );
+        }
+    }
+
+    #[test]
+    fn parse_ports_rejects_zero_and_reversed_ranges() {
+        for case in 0..CASES {
+            let mut rng = StdRng::seed_from_u64(case);
+            let from: u16 = rng.gen_range(2..=u16::MAX);
+            let to = rng.gen_range(1..from);
+            assert!(
+                parse_ports(&format!("{}-{}", from, to)).is_err(),
+                "case {}",
+                case
+            );
+            assert!(
+                parse_ports(&format!("0-{}", from)).is_err(),
+                "case {}",
+                case
+            );
+            assert!(
+                parse_ports(&format!("{},0", from)).is_err(),
+                "case {}",
+                case
+            );
+        }
+        assert!(parse_ports("65536").is_err());
+        assert!(parse_ports("1-65536").is_err());
+        assert!(parse_ports("").is_err());
+    }
+}
@@ -0,0 +1,334 @@
+use std::sync::Arc;
+use tokio::time::{Duration, MissedTickBehavior, interval, sleep};
+
+use crate::alert::Alerts;
+use crate::certs;
+use crate::config::{Config, Section};
+use crate::history::{self, History};
+use crate::json::Value;
+use crate::measure::{self, Sample};
+use crate::otel;
+use crate::outbound::OutboundConfig;
+use crate::say;
+use crate::units::{self, SECS};
+use crate::webhook;
+
+const SECS_PER_DAY: u64 = 86_400;
+
+pub enum Task {
+    Info,
+    Check,
+    Ping { target: String, count: u32 },
+    Bench { target: String, duration: Duration },
+    Certs { files: Vec<String>, warn: Duration },
+}
+
+pub enum Schedule {
+    Every(Duration),
+    Daily(u64),
+}
+
+pub struct Job {
+    pub name: String,
+    pub task: Task,
+    pub schedule: Schedule,
+    pub webhooks: Vec<String>,
+}
+
+fn parse_time_of_day(value: &str) -> Option<u64> {
+    let (hour, minute) = value.trim().split_once(':')?;
+    let hour: u64 = hour.parse().ok()?;
+    let minute: u64 = minute.parse().ok()?;
+    (hour < 24 && minute < 60).then_some(hour * 3600 + minute * 60)
+}
+
+fn task(config: &Config, section: &Section) -> Result<Task, String> {
+    let run = section.require(config, "run")?;
+
+    match run {
+        "info" => Ok(Task::Info),
+        "check" => Ok(Task::Check),
+        "ping" => Ok(Task::Ping {
+            target: section.require(config, "target")?.to_string(),
+            count: section.parsed(config, "count")?.unwrap_or(4),
+        }),
+        "bench" => {
+            let duration = section
+                .parsed_with(config, "duration", |v| units::duration(v, SECS))?
+                .unwrap_or(Duration::from_secs(10));
+            Ok(Task::Bench {
+                target: section.require(config, "target")?.to_string(),
+                duration,
+            })
+        }
+        "certs" => {
+            let files: Vec<String> = section
+                .entries
+                .iter()
+                .filter(|e| e.key == "file")
+                .map(|e| e.value.clone())
+                .collect();
+            if files.is_empty() {
+                return Err(config.error(section.line, "a certs job needs at least one 'file'"));
+            }
+            let warn = section
+                .parsed_with(config, "warn", |v| units::duration(v, SECS))?
+                .unwrap_or(certs::DEFAULT_WARN);
+            Ok(Task::Certs { files, warn })
+        }
+        other => Err(config.error(
+            section.entry("run").map_or(section.line, |e| e.line),
+            &format!("unknown job task '{}'", other),
+        )),
+    }
+}
+
+fn schedule(config: &Config, section: &Section) -> Result<Schedule, String> {
+    match (section.entry("every"), section.entry("at")) {
+        (Some(every), None) => units::duration(&every.value, SECS)
+            .and_then(units::nonzero)
+            .map(Schedule::Every)
+            .map_err(|e| {
+                config.error(
+                    every.line,
+                    &format!("invalid interval '{}': {}", every.value, e),
+                )
+            }),
+        (None, Some(at)) => parse_time_of_day(&at.value)
+            .map(Schedule::Daily)
+            .ok_or_else(|| config.error(at.line, &format!("invalid time of day '{}'", at.value))),
+        _ => Err(config.error(
+            section.line,
+            &format!(
+                "[job {}] needs exactly one of 'every' or 'at'",
+                section.name
+            ),
+        )),
+    }
+}
+
+pub fn jobs(config: &Config) -> Result<Vec<Job>, String> {
+    config
+        .sections("job")
+        .map(|section| {
+            Ok(Job {
+                name: section.name.clone(),
+                task: task(config, section)?,
+                schedule: schedule(config, section)?,
+                webhooks: section
+                    .entries
+                    .iter()
+                    .filter(|e| e.key == "webhook")
+                    .map(|e| e.value.clone())
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+impl Task {
+    async fn run(&self, outbound: &OutboundConfig) -> Vec<Sample> {
+        match self {
+            Task::Info => measure::host_info_samples(&crate::get_host_info().await),
+            Task::Check => measure::check(outbound).await,
+            Task::Ping { target, count } => match outbound.resolve(target).await {
+                Ok(addrs) => {
+                    let results = measure::ping(
+                        outbound,
+                        addrs[0],
+                        *count,
+                        Duration::from_secs(1),
+                        |_, _| {},
+                    )
+                    .await;
+                    measure::ping_samples(target, &results)
+                }
+                Err(e) => {
+                    eprintln!("Failed to resolve {}: {}", target, e);
+                    measure::ping_samples(target, &[Err(e.to_string())])
+                }
+            },
+            Task::Bench { target, duration } => {
+                match measure::bench(outbound, target, *duration, false, None).await {
+                    Ok(result) => vec![result.sample(target)],
+                    Err(e) => {
+                        eprintln!("Benchmark against {} failed: {}", target, e);
+                        Vec::new()
+                    }
+                }
+            }
+            Task::Certs { files, warn } => certs::samples(files, *warn),
+        }
+    }
+}
+
+fn secs_until(time_of_day: u64, now: u64) -> u64 {
+    let now = now % SECS_PER_DAY;
+    if time_of_day > now {
+        time_of_day - now
+    } else {
+        SECS_PER_DAY - now + time_of_day
+    }
+}
+
+async fn run_once(
+    job: &Job,
+    outbound: &OutboundConfig,
+    history: Option<&History>,
+    alerts: &Alerts,
+) {
+    let samples = job.task.run(outbound).await;
+    let failed = samples.iter().filter(|s| !s.ok).count();
+    say!(
+        "Job '{}' finished: {} result(s), {} failed",
+        job.name,
+        samples.len(),
+        failed
+    );
+
+    if let Some(history) = history {
+        measure::record(history, &samples).await;
+    }
+
+    otel::record_samples(&job.name, &samples);
+    alerts.evaluate(&job.name, &samples).await;
+
+    if job.webhooks.is_empty() {
+        return;
+    }
+
+    let payload = Value::object([
+        ("job", Value::from(job.name.as_str())),
+        ("time", Value::from(history::now())),
+        (
+            "results",
+            Value::Array(samples.iter().map(Sample::to_json).collect()),
+        ),
+    ]);
+
+    for url in &job.webhooks {
+        if let Err(e) = webhook::post(url, &payload).await {
+            eprintln!("Webhook {} for job '{}' failed: {}", url, job.name, e);
+        }
+    }
+}
+
+async fn run(
+    job: Job,
+    outbound: OutboundConfig,
+    history: Option<Arc<History>>,
+    alerts: Arc<Alerts>,
+) {
+    match job.schedule {
+        Schedule::Every(period) => {
+            say!("Scheduled job '{}' every {} s", job.name, period.as_secs());
+            let mut ticker = interval(period);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                run_once(&job, &outbound, history.as_deref(), &alerts).await;
+            }
+        }
+        Schedule::Daily(time_of_day) => {
+            say!(
+                "Scheduled job '{}' daily at {:02}:{:02} UTC",
+                job.name,
+                time_of_day / 3600,
+                time_of_day % 3600 / 60
+            );
+            loop {
+                let wait = secs_until(time_of_day, history::now());
+                sleep(Duration::from_secs(wait)).await;
+                run_once(&job, &outbound, history.as_deref(), &alerts).await;
+            }
+        }
+    }
+}
+
+pub fn spawn(
+    jobs: Vec<Job>,
+    outbound: OutboundConfig,
+    history: Option<Arc<History>>,
+    alerts: Arc<Alerts>,
+) {
+    for job in jobs {
+        tokio::spawn(run(job, outbound.clone(), history.clone(), alerts.clone()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn reads_times_of_day() {
+        assert_eq!(parse_time_of_day("00:00"), Some(0));
+        assert_eq!(parse_time_of_day(" 7:05 "), Some(7 * 3600 + 5 * 60));
+        assert_eq!(parse_time_of_day("23:59"), Some(86_340));
+        for text in ["24:00", "12:60", "12", "noon", "-1:00", ""] {
+            assert_eq!(parse_time_of_day(text), None, "{}", text);
+        }
+    }
+
+    #[test]
+    fn waits_for_the_next_daily_run() {
+        let ten = 10 * 3600;
+        // Any day: only the time of day counts.
+        for day in [0, 19_000] {
+            let now = day * SECS_PER_DAY + ten;
+            assert_eq!(secs_until(12 * 3600, now), 2 * 3600);
+            assert_eq!(secs_until(9 * 3600, now), 23 * 3600);
+            // A run due right now is tomorrow's; this one just happened.
+            assert_eq!(secs_until(ten, now), SECS_PER_DAY);
+            assert_eq!(secs_until(ten + 1, now), 1);
+        }
+        assert_eq!(secs_until(0, SECS_PER_DAY - 1), 1);
+    }
+
+    #[test]
+    fn reads_jobs_and_their_schedules() {
+        let parse = |text: &str| jobs(&Config::parse(Path::new("netcore.conf"), text).unwrap());
+        let jobs = parse(
+            "[job nightly]\nrun = ping\ntarget = example.com\nat = 02:30\n\
+             webhook = http://127.0.0.1:9/a\nwebhook = http://127.0.0.1:9/b\n\
+             [job often]\nrun = check\nevery = 5m\n",
+        )
+        .unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].name, "nightly");
+        assert!(
+            matches!(&jobs[0].task, Task::Ping { target, count: 4 } if target == "example.com")
+        );
+        assert!(matches!(jobs[0].schedule, Schedule::Daily(9000)));
+        assert_eq!(jobs[0].webhooks.len(), 2);
+        assert!(matches!(jobs[1].task, Task::Check));
+        assert!(matches!(jobs[1].schedule, Schedule::Every(d) if d == Duration::from_secs(300)));
+
+        let error = |text| parse(text).err().unwrap();
+        assert_eq!(
+            error("[job x]\nrun = check\nevery = 1m\nat = 01:00\n"),
+            "netcore.conf:1: [job x] needs exactly one of 'every' or 'at'"
+        );
+        assert_eq!(
+            error("[job x]\nrun = check\n"),
+            "netcore.conf:1: [job x] needs exactly one of 'every' or 'at'"
+        );
+        assert_eq!(
+            error("[job x]\nrun = check\nat = 25:00\n"),
+            "netcore.conf:3: invalid time of day '25:00'"
+        );
+        assert!(
+            error("[job x]\nrun = check\nevery = 0\n")
+                .starts_with("netcore.conf:3: invalid interval '0'")
+        );
+        assert_eq!(
+            error("[job x]\nrun = reboot\nevery = 1m\n"),
+            "netcore.conf:2: unknown job task 'reboot'"
+        );
+        assert_eq!(
+            error("[job x]\nrun = certs\nevery = 1d\n"),
+            "netcore.conf:1: a certs job needs at least one 'file'"
+        );
+    }
+}
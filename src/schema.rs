@@ -0,0 +1,192 @@
+// JSON Schemas of the machine output, for `<command> --schema`. Every
+// porcelain line and --output json report carries schema_version; within
+// a version fields are only ever added, so tools should ignore ones they
+// don't know. Removing or renaming a field, or changing its type, bumps
+// SCHEMA_VERSION, and these schemas with it.
+
+use crate::json::Value;
+use crate::output::SCHEMA_VERSION;
+
+const DRAFT: &str = "https://json-schema.org/draft/2020-12/schema";
+
+// Commands whose lines are measurement samples.
+const SAMPLE_COMMANDS: &[&str] = &["info", "ping", "check", "bench", "selfbench"];
+const SCAN_COMMANDS: &[&str] = &["scan-remote", "scan-lan"];
+
+fn typed(kind: &str) -> Value {
+    Value::object([("type", Value::from(kind))])
+}
+
+fn nullable(kind: &str) -> Value {
+    Value::object([(
+        "type",
+        Value::Array(vec![Value::from(kind), Value::from("null")]),
+    )])
+}
+
+fn object(required: &[&str], properties: Vec<(&str, Value)>) -> Value {
+    Value::object([
+        ("type", Value::from("object")),
+        (
+            "required",
+            Value::Array(required.iter().map(|name| Value::from(*name)).collect()),
+        ),
+        ("properties", Value::object(properties)),
+        // Later releases may add fields without a new version.
+        ("additionalProperties", Value::from(true)),
+    ])
+}
+
+fn array(items: Value) -> Value {
+    Value::object([("type", Value::from("array")), ("items", items)])
+}
+
+fn envelope(command: &str) -> Vec<(&'static str, Value)> {
+    vec![
+        ("command", Value::object([("const", Value::from(command))])),
+        (
+            "schema_version",
+            Value::object([("const", Value::from(SCHEMA_VERSION))]),
+        ),
+    ]
+}
+
+fn port() -> Value {
+    object(
+        &["port", "protocol", "state"],
+        vec![
+            ("port", typed("integer")),
+            ("protocol", typed("string")),
+            ("state", typed("string")),
+            ("rtt_ms", nullable("number")),
+            ("service", nullable("string")),
+            ("version", nullable("string")),
+        ],
+    )
+}
+
+fn host() -> Vec<(&'static str, Value)> {
+    vec![
+        ("addr", typed("string")),
+        ("name", nullable("string")),
+        ("ptr", nullable("string")),
+        ("geo", nullable("string")),
+        ("status", typed("string")),
+        (
+            "os_guess",
+            Value::object([(
+                "oneOf",
+                Value::Array(vec![
+                    typed("null"),
+                    object(
+                        &["os", "confidence", "evidence"],
+                        vec![
+                            ("os", typed("string")),
+                            ("confidence", typed("integer")),
+                            ("evidence", array(typed("string"))),
+                        ],
+                    ),
+                ]),
+            )]),
+        ),
+        ("ports", array(port())),
+    ]
+}
+
+// The schema of the lines `command` prints with --porcelain.
+pub fn for_command(command: &str) -> Value {
+    let mut properties = envelope(command);
+    let (title, required, definitions) = if SAMPLE_COMMANDS.contains(&command) {
+        properties.extend([
+            ("kind", typed("string")),
+            ("subject", typed("string")),
+            ("value", typed("string")),
+            ("ok", typed("boolean")),
+            // Only with --interfaces.
+            ("interface", typed("string")),
+        ]);
+        (
+            "one measurement",
+            &["kind", "subject", "value", "ok"][..],
+            None,
+        )
+    } else if SCAN_COMMANDS.contains(&command) {
+        properties.extend(host());
+        // What --output json prints in one go.
+        let mut report = vec![(
+            "schema_version",
+            Value::object([("const", Value::from(SCHEMA_VERSION))]),
+        )];
+        report.extend([
+            (
+                "scanner",
+                Value::object([("const", Value::from("netcore"))]),
+            ),
+            ("version", typed("string")),
+            ("kind", typed("string")),
+            ("started", typed("integer")),
+            ("elapsed_ms", typed("integer")),
+            ("hosts", array(object(&["addr", "status", "ports"], host()))),
+        ]);
+        let report = object(&["schema_version", "kind", "hosts"], report);
+        (
+            "one scanned host",
+            &["addr", "status", "ports"][..],
+            Some(Value::object([("report", report)])),
+        )
+    } else {
+        // Diagnostic commands report PASS, WARN and FAIL verdicts; others
+        // print fields of their own.
+        properties.extend([
+            ("status", typed("string")),
+            ("subject", typed("string")),
+            ("detail", typed("string")),
+        ]);
+        ("one result", &[][..], None)
+    };
+
+    let mut required = required.to_vec();
+    required.extend(["command", "schema_version"]);
+    let Value::Object(mut schema) = object(&required, properties) else {
+        unreachable!()
+    };
+    let mut head = vec![
+        ("$schema".to_string(), Value::from(DRAFT)),
+        (
+            "title".to_string(),
+            Value::from(format!("netcore {}: {}", command, title)),
+        ),
+    ];
+    head.append(&mut schema);
+    if let Some(definitions) = definitions {
+        head.push(("$defs".to_string(), definitions));
+    }
+    Value::Object(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::measure::Sample;
+
+    #[test]
+    fn sample_schema_covers_what_is_emitted() {
+        let schema = for_command("check");
+        let properties = schema.get("properties").unwrap();
+        let Value::Object(fields) =
+            Sample::new("dns", "example.com", "12 ms".into(), true).to_json()
+        else {
+            panic!("samples are objects");
+        };
+        for (name, _) in fields {
+            assert!(properties.get(&name).is_some(), "{} missing", name);
+        }
+        let required = schema.get("required").unwrap().as_array();
+        assert!(
+            required
+                .iter()
+                .any(|v| v.as_str() == Some("schema_version"))
+        );
+        assert!(for_command("scan-lan").get("$defs").is_some());
+    }
+}
@@ -0,0 +1,113 @@
+// Credentials given by reference, so the command line and the config file
+// say where a token is rather than what it is:
+//
+//   file:<path>      the file's contents, less a trailing newline
+//   env:<NAME>       the environment variable's value
+//   exec:<command>   what the command prints, run by the shell
+//
+// Anything else is taken as the secret itself. References are looked up
+// once, when the option or key is read at startup.
+
+use std::env;
+use std::fs;
+use std::process::Command;
+
+use crate::cli::Args;
+
+pub fn resolve(value: &str) -> Result<String, String> {
+    let secret = if let Some(path) = value.strip_prefix("file:") {
+        fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?
+    } else if let Some(name) = value.strip_prefix("env:") {
+        env::var(name).map_err(|_| format!("${} is not set", name))?
+    } else if let Some(command) = value.strip_prefix("exec:") {
+        run(command)?
+    } else {
+        return Ok(value.to_string());
+    };
+    let secret = secret
+        .strip_suffix('\n')
+        .map(|s| s.strip_suffix('\r').unwrap_or(s))
+        .unwrap_or(&secret);
+    if secret.is_empty() {
+        return Err("it's empty".to_string());
+    }
+    Ok(secret.to_string())
+}
+
+fn run(command: &str) -> Result<String, String> {
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+    let output = cmd
+        .arg(command)
+        .output()
+        .map_err(|e| format!("cannot run '{}': {}", command, e))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!(
+            "'{}' exited with {}: {}",
+            command,
+            output.status,
+            stderr.trim()
+        ));
+    }
+    String::from_utf8(output.stdout).map_err(|_| format!("'{}' printed invalid UTF-8", command))
+}
+
+// The value of option `name`, looked up.
+pub fn value(args: &Args, name: &str) -> Result<Option<String>, String> {
+    args.value(name)
+        .map(|value| resolve(value).map_err(|e| format!("{} {}: {}", name, value, e)))
+        .transpose()
+}
+
+// Every value of option `name`, each looked up.
+pub fn values(args: &Args, name: &str) -> Result<Vec<String>, String> {
+    args.values(name)
+        .map(|value| resolve(value).map_err(|e| format!("{} {}: {}", name, value, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn looks_up_files_variables_and_commands() {
+        let path = env::temp_dir().join(format!("netcore-secret-{}", std::process::id()));
+        fs::write(&path, "s3cret\n").unwrap();
+        assert_eq!(
+            resolve(&format!("file:{}", path.display())).unwrap(),
+            "s3cret"
+        );
+        fs::remove_file(&path).unwrap();
+        assert!(
+            resolve(&format!("file:{}", path.display()))
+                .unwrap_err()
+                .starts_with("cannot read")
+        );
+
+        assert_eq!(resolve("env:PATH").unwrap(), env::var("PATH").unwrap());
+        assert_eq!(
+            resolve("env:NETCORE_SECRET_UNSET").unwrap_err(),
+            "$NETCORE_SECRET_UNSET is not set"
+        );
+        assert_eq!(resolve("plain:text").unwrap(), "plain:text");
+
+        if cfg!(unix) {
+            assert_eq!(resolve("exec:echo from-vault").unwrap(), "from-vault");
+            assert_eq!(resolve("exec:printf ''").unwrap_err(), "it's empty");
+            assert!(
+                resolve("exec:echo nope >&2; exit 3")
+                    .unwrap_err()
+                    .ends_with(": nope")
+            );
+        }
+    }
+}
@@ -0,0 +1,230 @@
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::time::{Duration, Instant};
+
+use crate::bandwidth::Limits;
+use crate::cli::{self, Command, Opt};
+use crate::context::ConnContext;
+use crate::exit;
+use crate::history::{self, History};
+use crate::measure::{self, Sample};
+use crate::mux;
+use crate::outbound::OutboundConfig;
+use crate::output;
+use crate::say;
+use crate::scan;
+use crate::units::{self, SECS};
+
+const HANDLERS: &[&str] = &["echo", "forward", "scan"];
+const DEFAULT_SECS: u64 = 3;
+const ROUND_TRIP_SIZE: usize = 64;
+const SCAN_PORTS: u16 = 5000;
+
+const OPTS: &[Opt] = &[Opt {
+    name: "--duration",
+    value: Some("<duration>"),
+    help: "How long each throughput and round trip run lasts (default: 3s)",
+}];
+
+struct Figure {
+    handler: &'static str,
+    metric: &'static str,
+    value: f64,
+    unit: &'static str,
+}
+
+impl Figure {
+    fn sample(&self) -> Sample {
+        Sample::new(
+            "selfbench",
+            &format!("{} {}", self.handler, self.metric),
+            format!("{:.2}", self.value),
+            self.value > 0.0,
+        )
+    }
+}
+
+pub async fn listen() -> io::Result<TcpListener> {
+    TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))).await
+}
+
+pub async fn echo_server() -> io::Result<SocketAddr> {
+    let listener = listen().await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((socket, peer)) = listener.accept().await {
+            let ctx = ConnContext::accepted(&socket, peer);
+            tokio::spawn(crate::server::echo_client(
+                socket,
+                ctx,
+                Limits::default(),
+                false,
+                &[],
+            ));
+        }
+    });
+    Ok(addr)
+}
+
+// The same forwarding path `--mux` routes backends through, pointed at a
+// local echo server.
+async fn forward_server(backend: SocketAddr) -> io::Result<SocketAddr> {
+    let listener = listen().await?;
+    let addr = listener.local_addr()?;
+    let outbound = Arc::new(OutboundConfig::default());
+    let backend = backend.to_string();
+    tokio::spawn(async move {
+        while let Ok((socket, peer)) = listener.accept().await {
+            let outbound = outbound.clone();
+            let backend = backend.clone();
+            let ctx = ConnContext::accepted(&socket, peer);
+            tokio::spawn(async move {
+                mux::forward(
+                    socket,
+                    ctx,
+                    &[],
+                    &backend,
+                    &outbound,
+                    None,
+                    Limits::default(),
+                )
+                .await
+            });
+        }
+    });
+    Ok(addr)
+}
+
+// Small messages one at a time, so this measures per-read overhead rather
+// than bandwidth.
+async fn round_trip(addr: SocketAddr, duration: Duration) -> io::Result<Duration> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    let message = [0x5a; ROUND_TRIP_SIZE];
+    let mut reply = [0; ROUND_TRIP_SIZE];
+    let start = Instant::now();
+    let mut count = 0;
+    while start.elapsed() < duration {
+        stream.write_all(&message).await?;
+        stream.read_exact(&mut reply).await?;
+        count += 1;
+    }
+    Ok(start.elapsed() / count.max(1))
+}
+
+async fn measure_path(
+    handler: &'static str,
+    addr: SocketAddr,
+    duration: Duration,
+) -> Result<Vec<Figure>, String> {
+    let outbound = OutboundConfig::default();
+    let bench = measure::bench(&outbound, &addr.to_string(), duration, false, None).await?;
+    let rtt = round_trip(addr, duration)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(vec![
+        Figure {
+            handler,
+            metric: "throughput",
+            value: bench.mbps(),
+            unit: "Mbit/s",
+        },
+        Figure {
+            handler,
+            metric: "round trip",
+            value: rtt.as_secs_f64() * 1_000_000.0,
+            unit: "us",
+        },
+    ])
+}
+
+async fn run(handler: &'static str, duration: Duration) -> Result<Vec<Figure>, String> {
+    match handler {
+        "echo" => {
+            let echo = echo_server().await.map_err(|e| e.to_string())?;
+            measure_path(handler, echo, duration).await
+        }
+        "forward" => {
+            let echo = echo_server().await.map_err(|e| e.to_string())?;
+            let proxy = forward_server(echo).await.map_err(|e| e.to_string())?;
+            measure_path(handler, proxy, duration).await
+        }
+        _ => {
+            let (probes, elapsed) = scan::loopback_throughput(SCAN_PORTS).await?;
+            Ok(vec![Figure {
+                handler,
+                metric: "connect probes",
+                value: probes as f64 / elapsed.as_secs_f64().max(f64::EPSILON),
+                unit: "/s",
+            }])
+        }
+    }
+}
+
+// Runs each handler against loopback so numbers from different builds on
+// the same machine can be compared; --record keeps them in the history.
+pub const COMMAND: Command = Command {
+    name: "selfbench",
+    usage: "netcore selfbench [echo|forward|scan]...",
+    about: "Benchmark the built-in handlers over loopback",
+    groups: &[OPTS, history::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let duration = cli::or_exit(args.parsed_with("--duration", |v| units::duration(v, SECS)))
+        .unwrap_or(Duration::from_secs(DEFAULT_SECS));
+    let history = History::from_args(&args);
+
+    let mut handlers = Vec::new();
+    for name in args.positional() {
+        match HANDLERS.iter().find(|handler| *handler == name) {
+            Some(handler) => handlers.push(*handler),
+            None => {
+                eprintln!(
+                    "unknown handler '{}', expected one of {}",
+                    name,
+                    HANDLERS.join(", ")
+                );
+                std::process::exit(exit::USAGE);
+            }
+        }
+    }
+    if handlers.is_empty() {
+        handlers = HANDLERS.to_vec();
+    }
+
+    let mut figures = Vec::new();
+    for handler in handlers {
+        if output::human() {
+            eprintln!("Benchmarking {} handler", handler);
+        }
+        match run(handler, duration).await {
+            Ok(results) => figures.extend(results),
+            Err(e) => {
+                eprintln!("Benchmark of {} failed: {}", handler, e);
+                std::process::exit(exit::code(&e));
+            }
+        }
+    }
+
+    say!("{:<10} {:<16} {:>12}", "HANDLER", "METRIC", "RESULT");
+    for figure in &figures {
+        say!(
+            "{:<10} {:<16} {:>12.2} {}",
+            figure.handler,
+            figure.metric,
+            figure.value,
+            figure.unit
+        );
+    }
+
+    let samples: Vec<Sample> = figures.iter().map(Figure::sample).collect();
+    measure::emit(&samples);
+    if let Some(history) = &history {
+        measure::record(history, &samples).await;
+    }
+}
@@ -0,0 +1,222 @@
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant, sleep, timeout};
+
+use crate::auth::Auth;
+use crate::bandwidth::Limits;
+use crate::cli::{self, Args, Command};
+use crate::context::ConnContext;
+use crate::exit;
+use crate::guard::Guard;
+use crate::mux::{self, MuxConfig};
+use crate::outbound::OutboundConfig;
+use crate::output;
+use crate::say;
+use crate::selfbench;
+use crate::tls;
+use crate::web::{self, WebUi};
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const ECHO_BYTES: usize = 256 * 1024;
+const BACKEND_REPLY: &[u8] = b"HTTP/1.0 200 OK\r\nContent-Length: 17\r\n\r\nnetcore selftest\n";
+
+enum Verdict {
+    Pass(String),
+    Fail(String),
+    Skip(&'static str),
+}
+
+fn failed(e: impl ToString) -> Verdict {
+    Verdict::Fail(e.to_string())
+}
+
+// Writes and reads at the same time, as a real client would, so a large
+// payload can't deadlock on full socket buffers.
+async fn exchange(addr: SocketAddr, payload: &[u8], expect: usize) -> io::Result<Vec<u8>> {
+    let stream = TcpStream::connect(addr).await?;
+    let (mut reader, mut writer) = stream.into_split();
+    let payload = payload.to_vec();
+    let writer = tokio::spawn(async move { writer.write_all(&payload).await });
+    let mut reply = vec![0; expect];
+    reader.read_exact(&mut reply).await?;
+    writer.await.map_err(io::Error::other)??;
+    Ok(reply)
+}
+
+async fn echo_integrity() -> Verdict {
+    let echo = match selfbench::echo_server().await {
+        Ok(addr) => addr,
+        Err(e) => return failed(e),
+    };
+    let payload: Vec<u8> = (0..ECHO_BYTES).map(|_| rand::random()).collect();
+    match exchange(echo, &payload, payload.len()).await {
+        Ok(reply) if reply == payload => Verdict::Pass(format!("{} bytes", payload.len())),
+        Ok(_) => failed("reply differs from what was sent"),
+        Err(e) => failed(e),
+    }
+}
+
+// A backend that answers any request with a fixed HTTP response.
+async fn http_backend() -> io::Result<SocketAddr> {
+    let listener = selfbench::listen().await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut socket, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = [0; 1024];
+                if socket.read(&mut buf).await.is_ok() {
+                    let _ = socket.write_all(BACKEND_REPLY).await;
+                }
+            });
+        }
+    });
+    Ok(addr)
+}
+
+async fn mux_server(tokens: Vec<String>) -> Result<SocketAddr, String> {
+    let args = Args::parse(tokens, &[mux::OPTS])?;
+    let config = MuxConfig::from_args(&args, None, OutboundConfig::default())?
+        .ok_or_else(|| "no mux routes".to_string())?;
+    let config = Arc::new(config);
+    let listener = selfbench::listen().await.map_err(|e| e.to_string())?;
+    let addr = listener.local_addr().map_err(|e| e.to_string())?;
+    tokio::spawn(async move {
+        while let Ok((socket, peer)) = listener.accept().await {
+            let config = config.clone();
+            let ctx = ConnContext::accepted(&socket, peer);
+            tokio::spawn(mux::route_client(socket, ctx, config, Limits::default()));
+        }
+    });
+    Ok(addr)
+}
+
+async fn mux_http() -> Verdict {
+    let backend = match http_backend().await {
+        Ok(addr) => addr,
+        Err(e) => return failed(e),
+    };
+    let proxy = match mux_server(vec!["--mux-http".to_string(), backend.to_string()]).await {
+        Ok(addr) => addr,
+        Err(e) => return failed(e),
+    };
+    let request = b"GET / HTTP/1.0\r\nHost: selftest\r\n\r\n";
+    match exchange(proxy, request, BACKEND_REPLY.len()).await {
+        Ok(reply) if reply == BACKEND_REPLY => {
+            Verdict::Pass(format!("sniffed and forwarded to {}", backend))
+        }
+        Ok(_) => failed("unexpected reply from backend"),
+        Err(e) => failed(e),
+    }
+}
+
+// The ClientHello has to be sniffed, then replayed intact to the route;
+// routing it to echo means the client should get its own hello back.
+async fn mux_tls() -> Verdict {
+    let proxy = match mux_server(vec!["--mux-tls".to_string(), "echo".to_string()]).await {
+        Ok(addr) => addr,
+        Err(e) => return failed(e),
+    };
+    let hello = tls::client_hello(&tls::PROFILES[0], "selftest.invalid");
+    match exchange(proxy, &hello, hello.len()).await {
+        Ok(reply) if reply == hello => Verdict::Pass(format!("{} byte ClientHello", hello.len())),
+        Ok(_) => failed("ClientHello was altered"),
+        Err(e) => failed(e),
+    }
+}
+
+async fn web_dashboard() -> Verdict {
+    // Grab a free port, then hand it to the dashboard, which binds its own.
+    let addr = match selfbench::listen()
+        .await
+        .and_then(|listener| listener.local_addr())
+    {
+        Ok(addr) => addr,
+        Err(e) => return failed(e),
+    };
+    let guard = match Args::parse(Vec::new(), &[]).and_then(|args| Guard::from_args(&args)) {
+        Ok(guard) => guard,
+        Err(e) => return failed(e),
+    };
+    let ui = WebUi {
+        addr,
+        auth: Auth::default(),
+        guard,
+    };
+    tokio::spawn(web::serve(ui, OutboundConfig::default(), None));
+
+    let deadline = Instant::now() + CHECK_TIMEOUT;
+    let mut stream = loop {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => break stream,
+            Err(_) if Instant::now() < deadline => sleep(Duration::from_millis(20)).await,
+            Err(e) => return failed(e),
+        }
+    };
+    let mut reply = Vec::new();
+    let result = async {
+        let request = format!("GET / HTTP/1.0\r\nHost: {}\r\n\r\n", addr);
+        stream.write_all(request.as_bytes()).await?;
+        stream.read_to_end(&mut reply).await
+    };
+    if let Err(e) = result.await {
+        return failed(e);
+    }
+    let reply = String::from_utf8_lossy(&reply);
+    match reply.lines().next() {
+        Some(status) if status.contains(" 200 ") => Verdict::Pass(format!("GET / on {}", addr)),
+        Some(status) => failed(status),
+        None => failed("empty reply"),
+    }
+}
+
+async fn within(check: impl Future<Output = Verdict>) -> Verdict {
+    timeout(CHECK_TIMEOUT, check)
+        .await
+        .unwrap_or_else(|_| failed("timed out"))
+}
+
+// Starts each handler on an ephemeral loopback port and talks to it as a
+// client would; exits non-zero if any check fails.
+pub const COMMAND: Command = Command {
+    name: "selftest",
+    usage: "netcore selftest",
+    about: "Run the built-in handlers against themselves",
+    groups: &[],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    cli::parse_command(&COMMAND, tokens);
+
+    let results = vec![
+        ("echo integrity", within(echo_integrity()).await),
+        ("mux http", within(mux_http()).await),
+        ("mux tls", within(mux_tls()).await),
+        ("web dashboard", within(web_dashboard()).await),
+        ("tls handshake", Verdict::Skip("netcore has no TLS server")),
+        (
+            "websocket",
+            Verdict::Skip("netcore has no WebSocket handler"),
+        ),
+        ("udp echo", Verdict::Skip("netcore has no UDP echo handler")),
+    ];
+
+    let mut failures = 0;
+    for (name, verdict) in &results {
+        let (status, detail) = match verdict {
+            Verdict::Pass(detail) => ("PASS", detail.as_str()),
+            Verdict::Fail(detail) => {
+                failures += 1;
+                ("FAIL", detail.as_str())
+            }
+            Verdict::Skip(reason) => ("SKIP", *reason),
+        };
+        say!("{}  {:<16} {}", status, name, detail);
+        output::verdict(status, name, detail);
+    }
+    if failures > 0 {
+        std::process::exit(exit::FAILURE);
+    }
+}
@@ -0,0 +1,580 @@
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+
+use crate::acl;
+use crate::adaptive::{Limiter, Outcome, Permit};
+use crate::bandwidth::Limits;
+use crate::cli::{Args, Opt};
+use crate::config::Config;
+use crate::context::ConnContext;
+use crate::events::{self, Event};
+use crate::fingerprint;
+use crate::mux::{self, MuxConfig};
+use crate::outbound;
+use crate::revproxy::{self, ReverseProxy};
+use crate::say;
+use crate::session;
+use crate::stats;
+use crate::tcpinfo;
+use crate::telnet::{self, LineMode};
+use crate::transport::Transport;
+use crate::units;
+
+pub const DEFAULT_PORTS: RangeInclusive<u16> = 6881..=6900;
+const PORT_CHECK_CONCURRENCY: usize = 8;
+const DEFAULT_ECHO_BUFFER: usize = 1024;
+const MAX_ECHO_BUFFER: u64 = 1024 * 1024;
+
+// Keys of the [echo] section.
+const ECHO_KEYS: &[&str] = &["buffer", "telnet"];
+
+pub const ECHO_OPTS: &[Opt] = &[Opt {
+    name: "--echo-buffer",
+    value: Some("<size>"),
+    help: "Bytes the echo handler reads and writes back at a time (default: 1K)",
+}];
+
+static ECHO_BUFFER: AtomicUsize = AtomicUsize::new(DEFAULT_ECHO_BUFFER);
+
+fn echo_buffer(value: &str) -> Result<usize, String> {
+    match units::size(value)? {
+        size @ 1..=MAX_ECHO_BUFFER => Ok(size as usize),
+        _ => Err("must be between 1 byte and 1M".to_string()),
+    }
+}
+
+// The echo handler's settings: an [echo] section's `buffer` and `telnet`,
+// under --echo-buffer and --telnet.
+pub fn init_echo(args: &Args, config: Option<&Config>) -> Result<(), String> {
+    let section = match config {
+        Some(config) => config.section("echo", ECHO_KEYS)?.map(|s| (config, s)),
+        None => None,
+    };
+    let mut buffer = args
+        .parsed_with("--echo-buffer", echo_buffer)?
+        .unwrap_or(DEFAULT_ECHO_BUFFER);
+    let mut telnet = false;
+    if let Some((config, section)) = section {
+        if args.value("--echo-buffer").is_none()
+            && let Some(configured) = section.parsed_with(config, "buffer", echo_buffer)?
+        {
+            buffer = configured;
+        }
+        telnet = section.parsed(config, "telnet")?.unwrap_or(false);
+    }
+    ECHO_BUFFER.store(buffer, Ordering::Relaxed);
+    telnet::init(args, telnet);
+    Ok(())
+}
+
+pub type HandlerFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+// What a server does with each connection it accepts.
+pub trait Handler: Send + Sync + 'static {
+    fn handle(&self, socket: TcpStream, ctx: ConnContext, limits: Limits) -> HandlerFuture;
+}
+
+// Sends every byte straight back.
+pub struct Echo;
+
+impl Handler for Echo {
+    fn handle(&self, socket: TcpStream, ctx: ConnContext, limits: Limits) -> HandlerFuture {
+        Box::pin(handle_client(socket, ctx, limits))
+    }
+}
+
+// Sniffs each connection and hands it to the matching --mux route.
+impl Handler for Arc<MuxConfig> {
+    fn handle(&self, socket: TcpStream, ctx: ConnContext, limits: Limits) -> HandlerFuture {
+        Box::pin(mux::route_client(socket, ctx, self.clone(), limits))
+    }
+}
+
+// Proxies each HTTP request to the backend its --http-route picks.
+impl Handler for Arc<ReverseProxy> {
+    fn handle(&self, socket: TcpStream, ctx: ConnContext, limits: Limits) -> HandlerFuture {
+        Box::pin(revproxy::serve_client(self.clone(), socket, ctx, limits))
+    }
+}
+
+// The netcore server for programs that embed it: binds the lowest free
+// port in a range and runs a handler on every connection. It prints
+// nothing unless a netcore command line chose an output mode; listeners
+// and connections are reported through events::subscribe().
+pub struct ServerBuilder {
+    ports: RangeInclusive<u16>,
+    handler: Arc<dyn Handler>,
+    dual_stack: bool,
+    limits: Limits,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> ServerBuilder {
+        ServerBuilder::new()
+    }
+}
+
+impl ServerBuilder {
+    pub fn new() -> ServerBuilder {
+        ServerBuilder {
+            ports: DEFAULT_PORTS,
+            handler: Arc::new(Echo),
+            dual_stack: true,
+            limits: Limits::default(),
+        }
+    }
+
+    pub fn port_range(mut self, ports: RangeInclusive<u16>) -> ServerBuilder {
+        self.ports = ports;
+        self
+    }
+
+    pub fn handler(mut self, handler: impl Handler) -> ServerBuilder {
+        self.handler = Arc::new(handler);
+        self
+    }
+
+    // Listen on IPv6 as well as IPv4, on the same port; without it the port
+    // only has to be free on IPv4.
+    pub fn dual_stack(mut self, dual_stack: bool) -> ServerBuilder {
+        self.dual_stack = dual_stack;
+        self
+    }
+
+    pub fn limits(mut self, limits: Limits) -> ServerBuilder {
+        self.limits = limits;
+        self
+    }
+
+    pub async fn build(self) -> io::Result<Server> {
+        let (start, end) = (*self.ports.start(), *self.ports.end());
+        let port = find_available_port_parallel(start, end, self.dual_stack)
+            .await
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("no available port in range {}-{}", start, end),
+                )
+            })?;
+
+        let mut listeners =
+            vec![TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).await?];
+        if self.dual_stack {
+            listeners.push(
+                TcpListener::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0)).await?,
+            );
+        }
+        for listener in &listeners {
+            fingerprint::prepare(listener);
+        }
+
+        Ok(Server {
+            port,
+            listeners,
+            handler: self.handler,
+            limits: self.limits,
+        })
+    }
+}
+
+pub struct Server {
+    port: u16,
+    listeners: Vec<TcpListener>,
+    handler: Arc<dyn Handler>,
+    limits: Limits,
+}
+
+impl Server {
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect()
+    }
+
+    // Accepts connections until this future is dropped, which closes the
+    // listeners; connections already accepted carry on.
+    pub async fn run(self) {
+        let mut listeners = JoinSet::new();
+        for listener in self.listeners {
+            listeners.spawn(accept(listener, self.handler.clone(), self.limits.clone()));
+        }
+        while listeners.join_next().await.is_some() {}
+    }
+}
+
+async fn accept(listener: TcpListener, handler: Arc<dyn Handler>, limits: Limits) {
+    let local = listener.local_addr().unwrap();
+    let family = outbound::family(local.ip());
+    say!("{} server listening on {}", family, local);
+    events::emit(Event::ListenerStarted { addr: local });
+
+    loop {
+        match listener.accept().await {
+            Ok((_, addr)) if acl::check(addr).is_some() => {}
+            Ok((socket, addr)) => {
+                let ctx = ConnContext::accepted(&socket, addr);
+                tokio::spawn(handler.handle(socket, ctx, limits.clone()));
+            }
+            Err(e) => {
+                say!("{} accept error: {}", family, e);
+                events::emit(Event::AcceptFailed {
+                    addr: local,
+                    error: e.to_string(),
+                });
+            }
+        }
+    }
+}
+
+// Checks run in order under the limiter, and the lowest free port wins
+// even if a later check finishes first.
+async fn find_available_port_parallel(start: u16, end: u16, dual_stack: bool) -> Option<u16> {
+    let limiter = Limiter::new(1, PORT_CHECK_CONCURRENCY);
+    let mut checks = JoinSet::new();
+    for port in start..=end {
+        let permit = limiter.acquire().await;
+        checks.spawn(async move { (port, is_port_available(port, dual_stack, permit).await) });
+    }
+
+    let mut lowest = None;
+    while let Some(result) = checks.join_next().await {
+        if let Ok((port, true)) = result {
+            lowest = Some(lowest.map_or(port, |lowest: u16| lowest.min(port)));
+        }
+    }
+    lowest
+}
+
+async fn is_port_available(port: u16, dual_stack: bool, permit: Permit) -> bool {
+    let (ipv4, ipv6) = tokio::join!(check_port_ipv4(port), async {
+        match dual_stack {
+            true => check_port_ipv6(port).await,
+            false => Ok(()),
+        }
+    });
+
+    let exhausted = [&ipv4, &ipv6]
+        .into_iter()
+        .filter_map(|result| result.as_ref().err())
+        .any(|e| matches!(Outcome::from_error(e), Outcome::Exhausted));
+    permit.finish(if exhausted {
+        Outcome::Exhausted
+    } else {
+        Outcome::Answered
+    });
+    ipv4.is_ok() && ipv6.is_ok()
+}
+
+async fn check_port_ipv4(port: u16) -> io::Result<()> {
+    TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port))
+        .await
+        .map(drop)
+}
+
+async fn check_port_ipv6(port: u16) -> io::Result<()> {
+    TcpListener::bind(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, port, 0, 0))
+        .await
+        .map(drop)
+}
+
+pub async fn handle_client(socket: TcpStream, ctx: ConnContext, limits: Limits) {
+    echo_client(socket, ctx, limits, true, &[]).await
+}
+
+// `selfbench` turns off the per-read lines, which would otherwise flood the
+// terminal and measure how fast it scrolls. `prefix` is what the mux already
+// read while sniffing, echoed as if it had just arrived.
+pub async fn echo_client(
+    mut socket: TcpStream,
+    mut ctx: ConnContext,
+    limits: Limits,
+    log_reads: bool,
+    prefix: &[u8],
+) {
+    let addr = ctx.peer;
+    say!("New connection from: {}", addr);
+    let header = match prefix {
+        [] => session::peek(&socket).await,
+        prefix => session::Header::parse(prefix),
+    };
+    if let Some(header) = header {
+        if header.attempt > 0 {
+            say!(
+                "Resuming session {} from {} (attempt {})",
+                header.token,
+                addr,
+                header.attempt
+            );
+        }
+        ctx.label("netcore.session", header.token);
+        ctx.label("netcore.session.attempt", header.attempt);
+    }
+
+    let mut tracker = stats::track("echo", addr);
+    ctx.record(&mut tracker);
+    fingerprint::record(&mut tracker, &socket, ctx.tls.as_ref());
+    echo(
+        limits.wrap(&mut socket),
+        &mut tracker,
+        addr,
+        log_reads,
+        prefix,
+    )
+    .await;
+    tcpinfo::record(&mut tracker, &socket);
+}
+
+async fn echo<S: Transport>(
+    mut socket: S,
+    tracker: &mut stats::Tracker,
+    addr: SocketAddr,
+    log_reads: bool,
+    prefix: &[u8],
+) {
+    let mut buffer = vec![0; ECHO_BUFFER.load(Ordering::Relaxed)];
+    let mut telnet = telnet::enabled().then(LineMode::default);
+    if !prefix.is_empty()
+        && !answer(&mut socket, tracker, &mut telnet, prefix, addr, log_reads).await
+    {
+        return;
+    }
+
+    loop {
+        let result = tokio::select! {
+            result = socket.read(&mut buffer) => result,
+            _ = tracker.killed() => {
+                say!("Connection from {} closed by control request", addr);
+                break;
+            }
+        };
+
+        match result {
+            Ok(0) => {
+                say!("Connection closed by: {}", addr);
+                break;
+            }
+            Ok(n) => {
+                if !answer(
+                    &mut socket,
+                    tracker,
+                    &mut telnet,
+                    &buffer[..n],
+                    addr,
+                    log_reads,
+                )
+                .await
+                {
+                    break;
+                }
+            }
+            // The error goes out with the connection_closed event.
+            Err(e) => {
+                say!("Error reading from {}: {}", addr, e);
+                tracker.error(e);
+                break;
+            }
+        }
+    }
+}
+
+// Echoes back what was read; false once the connection can't be written.
+async fn answer<S: Transport>(
+    socket: &mut S,
+    tracker: &mut stats::Tracker,
+    telnet: &mut Option<LineMode>,
+    data: &[u8],
+    addr: SocketAddr,
+    log_reads: bool,
+) -> bool {
+    if log_reads {
+        say!("Received {} bytes from {}", data.len(), addr);
+    }
+    tracker.received(data.len() as u64);
+
+    let reply = match telnet {
+        Some(telnet) => {
+            let (data, mut reply) = telnet.input(data);
+            reply.extend(telnet.output(&data));
+            reply
+        }
+        None => data.to_vec(),
+    };
+    if let Err(e) = socket.write_all(&reply).await {
+        say!("Failed to write to {}: {}", addr, e);
+        tracker.error(e);
+        return false;
+    }
+    tracker.sent(reply.len() as u64);
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MemoryTransport;
+
+    // Ports the finder returns must sit inside the range and be free on both
+    // address families, whichever ports around them are already taken.
+    #[tokio::test]
+    async fn port_finder_stays_in_range_and_skips_taken_ports() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        for case in 0..50 {
+            let mut rng = StdRng::seed_from_u64(case);
+            let start = rng.gen_range(20000..60000);
+            let end = start + rng.gen_range(0..10);
+            let mut taken = Vec::new();
+            for port in start..=end {
+                if rng.gen_bool(0.5)
+                    && let Ok(listener) =
+                        TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port)).await
+                {
+                    taken.push(listener);
+                }
+            }
+            let held: Vec<u16> = taken
+                .iter()
+                .map(|listener| listener.local_addr().unwrap().port())
+                .collect();
+
+            if let Some(port) = find_available_port_parallel(start, end, true).await {
+                assert!((start..=end).contains(&port), "case {}: {}", case, port);
+                assert!(!held.contains(&port), "case {}: {} is taken", case, port);
+                assert!(is_port_available(port, true, Limiter::new(1, 1).acquire().await).await);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn built_server_echoes_on_the_port_it_reports() {
+        let server = ServerBuilder::new()
+            .port_range(47000..=47100)
+            .handler(Echo)
+            .dual_stack(false)
+            .build()
+            .await
+            .unwrap();
+        let port = server.port();
+        assert!((47000..=47100).contains(&port));
+        assert_eq!(server.local_addrs().len(), 1);
+        let running = tokio::spawn(server.run());
+
+        let mut client = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+        client.write_all(b"ping").await.unwrap();
+        let mut reply = [0; 4];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"ping");
+
+        running.abort();
+    }
+
+    #[tokio::test]
+    async fn lifecycle_events_reach_subscribers() {
+        let mut events = events::subscribe();
+        let server = ServerBuilder::new()
+            .port_range(47200..=47300)
+            .dual_stack(false)
+            .build()
+            .await
+            .unwrap();
+        let listening = server.local_addrs()[0];
+        let running = tokio::spawn(server.run());
+
+        let client = loop {
+            match events.recv().await.unwrap() {
+                Event::ListenerStarted { addr } if addr == listening => {
+                    break TcpStream::connect((Ipv4Addr::LOCALHOST, addr.port()))
+                        .await
+                        .unwrap();
+                }
+                _ => {}
+            }
+        };
+        let peer = client.local_addr().unwrap();
+        drop(client);
+
+        let mut opened = None;
+        loop {
+            match events.recv().await.unwrap() {
+                Event::ConnectionOpened { id, peer: p, .. } if p == peer => opened = Some(id),
+                Event::ConnectionClosed { id, peer: p, .. } if p == peer => {
+                    assert_eq!(Some(id), opened);
+                    break;
+                }
+                _ => {}
+            }
+        }
+        running.abort();
+    }
+
+    #[tokio::test]
+    async fn echo_returns_split_writes_intact() {
+        let addr = "192.0.2.1:40000".parse().unwrap();
+        let (client, server) = MemoryTransport::pair(64);
+        let server = server.with_max_read(7);
+        let handler = tokio::spawn(async move {
+            let mut tracker = stats::track("echo", addr);
+            echo(server, &mut tracker, addr, false, &[]).await;
+        });
+
+        let message: Vec<u8> = (0..=255).cycle().take(4096).collect();
+        let (mut reader, mut writer) = tokio::io::split(client);
+        let mut reply = vec![0; message.len()];
+        let (written, read) =
+            tokio::join!(writer.write_all(&message), reader.read_exact(&mut reply));
+        written.unwrap();
+        read.unwrap();
+        assert_eq!(reply, message);
+
+        drop((reader, writer));
+        handler.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn echo_answers_the_sniffed_prefix_first() {
+        let addr = "192.0.2.1:40001".parse().unwrap();
+        let mut events = events::subscribe();
+        let (mut client, server) = MemoryTransport::pair(64);
+        let handler = tokio::spawn(async move {
+            let mut tracker = stats::track("echo", addr);
+            echo(server, &mut tracker, addr, false, b"GET ").await;
+        });
+
+        client.write_all(b"/ HTTP/1.0\r\n").await.unwrap();
+        let mut reply = [0; 16];
+        client.read_exact(&mut reply).await.unwrap();
+        assert_eq!(&reply, b"GET / HTTP/1.0\r\n");
+        drop(client);
+        handler.await.unwrap();
+
+        // The prefix is counted like any other read.
+        loop {
+            if let Event::ConnectionClosed {
+                peer,
+                bytes_in,
+                bytes_out,
+                ..
+            } = events.recv().await.unwrap()
+                && peer == addr
+            {
+                assert_eq!((bytes_in, bytes_out), (16, 16));
+                break;
+            }
+        }
+    }
+}
@@ -0,0 +1,471 @@
+use std::net::SocketAddr;
+use std::str::Chars;
+use std::sync::OnceLock;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{Duration, Instant, timeout, timeout_at};
+
+use crate::cli::{Args, Opt};
+use crate::outbound::OutboundConfig;
+use crate::scan::parse_ports;
+
+const BUILTIN: &str = include_str!("services.txt");
+const MAX_REPLY: usize = 4096;
+// Once a reply has started, stop reading after this long without more data.
+const REPLY_GAP: Duration = Duration::from_millis(200);
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--services",
+        value: None,
+        help: "Identify services and versions on open TCP ports",
+    },
+    Opt {
+        name: "--signatures",
+        value: Some("<path>"),
+        help: "Service signature file tried before the built-in one (repeatable, implies --services)",
+    },
+];
+
+static PROBES: OnceLock<Vec<Probe>> = OnceLock::new();
+
+struct Probe {
+    name: String,
+    payload: Vec<u8>,
+    ports: Option<Vec<u16>>,
+    matches: Vec<Match>,
+}
+
+struct Match {
+    service: String,
+    pattern: Vec<Token>,
+    product: Option<String>,
+}
+
+enum Token {
+    Char(char),
+    Any,
+    Star,
+    Version,
+}
+
+pub struct Service {
+    pub name: String,
+    pub version: Option<String>,
+    // The first line of the reply, kept for OS hints.
+    pub banner: Option<String>,
+}
+
+pub fn init(args: &Args) -> Result<(), String> {
+    if !args.flag("--services") && args.value("--signatures").is_none() {
+        return Ok(());
+    }
+    let mut probes: Vec<Probe> = Vec::new();
+    for path in args.values("--signatures") {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| format!("failed to read signatures {}: {}", path, e))?;
+        let parsed = parse(&text).map_err(|e| format!("invalid signatures {}: {}", path, e))?;
+        for probe in parsed {
+            if !probes.iter().any(|p| p.name == probe.name) {
+                probes.push(probe);
+            }
+        }
+    }
+    for probe in parse(BUILTIN).expect("built-in signatures are valid") {
+        match probes.iter_mut().find(|p| p.name == probe.name) {
+            Some(custom) => custom.matches.extend(probe.matches),
+            None => probes.push(probe),
+        }
+    }
+    let _ = PROBES.set(probes);
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    PROBES.get().is_some()
+}
+
+fn parse(text: &str) -> Result<Vec<Probe>, String> {
+    let mut probes: Vec<Probe> = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let err = |message: String| format!("line {}: {}", number + 1, message);
+        let (keyword, rest) = line.split_once(' ').unwrap_or((line, ""));
+        let rest = rest.trim();
+        if keyword == "probe" {
+            let (name, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+            let (payload, _) = quoted(rest).map_err(err)?;
+            probes.push(Probe {
+                name: name.to_string(),
+                payload: unescape(&payload).map_err(err)?,
+                ports: None,
+                matches: Vec::new(),
+            });
+            continue;
+        }
+        let Some(probe) = probes.last_mut() else {
+            return Err(err(format!("'{}' before any probe", keyword)));
+        };
+        match keyword {
+            "ports" => probe.ports = Some(parse_ports(rest).map_err(err)?),
+            "match" => {
+                let (service, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+                let (pattern, product) = quoted(rest).map_err(err)?;
+                probe.matches.push(Match {
+                    service: service.to_string(),
+                    pattern: compile(&pattern).map_err(err)?,
+                    product: Some(product.trim().to_string()).filter(|p| !p.is_empty()),
+                });
+            }
+            other => return Err(err(format!("unknown keyword '{}'", other))),
+        }
+    }
+    Ok(probes)
+}
+
+// Splits a leading "..." off `text`, returning its still-escaped contents.
+fn quoted(text: &str) -> Result<(String, &str), String> {
+    let body = text
+        .strip_prefix('"')
+        .ok_or_else(|| "expected a quoted string".to_string())?;
+    let mut escaped = false;
+    for (at, c) in body.char_indices() {
+        match c {
+            '"' if !escaped => return Ok((body[..at].to_string(), &body[at + 1..])),
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+fn escape(chars: &mut Chars) -> Result<char, String> {
+    match chars.next() {
+        Some('r') => Ok('\r'),
+        Some('n') => Ok('\n'),
+        Some('t') => Ok('\t'),
+        Some('0') => Ok('\0'),
+        Some('x') => {
+            let hex: String = chars.by_ref().take(2).collect();
+            u8::from_str_radix(&hex, 16)
+                .map(char::from)
+                .map_err(|_| format!("invalid escape \\x{}", hex))
+        }
+        Some(c @ ('\\' | '"' | '*' | '?' | '{')) => Ok(c),
+        Some(c) => Err(format!("invalid escape \\{}", c)),
+        None => Err("trailing backslash".to_string()),
+    }
+}
+
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let mut chars = text.chars();
+    let mut bytes = Vec::new();
+    while let Some(c) = chars.next() {
+        let c = if c == '\\' { escape(&mut chars)? } else { c };
+        let mut buf = [0; 4];
+        // \xNN means that byte, not its UTF-8 encoding.
+        match u8::try_from(c) {
+            Ok(byte) => bytes.push(byte),
+            Err(_) => bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes()),
+        }
+    }
+    Ok(bytes)
+}
+
+fn compile(text: &str) -> Result<Vec<Token>, String> {
+    let mut chars = text.chars();
+    let mut tokens = Vec::new();
+    while let Some(c) = chars.next() {
+        tokens.push(match c {
+            '\\' => Token::Char(escape(&mut chars)?),
+            '*' => Token::Star,
+            '?' => Token::Any,
+            '{' => {
+                let name: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                if name != "version" {
+                    return Err(format!("unknown capture {{{}}}", name));
+                }
+                Token::Version
+            }
+            c => Token::Char(c),
+        });
+    }
+    Ok(tokens)
+}
+
+fn is_version_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '+' | '~' | ':')
+}
+
+// Replies are compared byte for byte, each byte read as the char with the
+// same value, so binary banners can be matched with \xNN.
+fn matches(pattern: &[Token], text: &[char], version: &mut Option<String>) -> bool {
+    match pattern.split_first() {
+        None => true,
+        Some((Token::Char(c), rest)) => {
+            text.first() == Some(c) && matches(rest, &text[1..], version)
+        }
+        Some((Token::Any, rest)) => !text.is_empty() && matches(rest, &text[1..], version),
+        Some((Token::Star, rest)) => (0..=text.len()).any(|at| matches(rest, &text[at..], version)),
+        Some((Token::Version, rest)) => {
+            let len = text.iter().take_while(|&&c| is_version_char(c)).count();
+            let found = (1..=len)
+                .rev()
+                .find(|&len| matches(rest, &text[len..], version));
+            if let Some(len) = found {
+                *version = Some(text[..len].iter().collect());
+            }
+            found.is_some()
+        }
+    }
+}
+
+impl Probe {
+    fn identify(&self, reply: &[u8]) -> Option<Service> {
+        let text: Vec<char> = reply.iter().map(|&b| char::from(b)).collect();
+        self.matches.iter().find_map(|m| {
+            let mut version = None;
+            if !matches(&m.pattern, &text, &mut version) {
+                return None;
+            }
+            let version = match (&m.product, version) {
+                (Some(product), Some(version)) => Some(format!("{} {}", product, version)),
+                (product, version) => product.clone().or(version),
+            };
+            let banner: String = text
+                .iter()
+                .take_while(|&&c| c != '\r' && c != '\n')
+                .filter(|c| !c.is_control())
+                .collect();
+            Some(Service {
+                name: m.service.clone(),
+                version,
+                banner: Some(banner).filter(|b| !b.is_empty()),
+            })
+        })
+    }
+}
+
+async fn exchange(
+    outbound: &OutboundConfig,
+    addr: SocketAddr,
+    payload: &[u8],
+    wait: Duration,
+) -> Option<Vec<u8>> {
+    let deadline = Instant::now() + wait;
+    let mut stream = timeout_at(deadline, outbound.connect_addr(addr))
+        .await
+        .ok()?
+        .ok()?;
+    if !payload.is_empty() {
+        stream.write_all(payload).await.ok()?;
+    }
+    let mut reply = Vec::new();
+    let mut buf = [0; 1024];
+    while reply.len() < MAX_REPLY {
+        let read = if reply.is_empty() {
+            timeout_at(deadline, stream.read(&mut buf)).await
+        } else {
+            timeout(REPLY_GAP, stream.read(&mut buf)).await
+        };
+        match read {
+            Ok(Ok(n)) if n > 0 => reply.extend_from_slice(&buf[..n]),
+            _ => break,
+        }
+    }
+    (!reply.is_empty()).then_some(reply)
+}
+
+// Runs the probes that apply to this port, each on its own connection,
+// until one of them recognises the reply.
+pub async fn detect(
+    outbound: &OutboundConfig,
+    addr: SocketAddr,
+    wait: Duration,
+) -> Option<Service> {
+    let probes = PROBES.get()?.iter().filter(|probe| {
+        probe
+            .ports
+            .as_ref()
+            .is_none_or(|ports| ports.contains(&addr.port()))
+    });
+    for probe in probes {
+        if let Some(reply) = exchange(outbound, addr, &probe.payload, wait).await
+            && let Some(service) = probe.identify(&reply)
+        {
+            return Some(service);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identify(probes: &[Probe], probe: &str, reply: &[u8]) -> Option<(String, Option<String>)> {
+        let probe = probes.iter().find(|p| p.name == probe).unwrap();
+        probe.identify(reply).map(|s| (s.name, s.version))
+    }
+
+    #[test]
+    fn recognises_builtin_banners() {
+        let probes = parse(BUILTIN).unwrap();
+        let cases: &[(&str, &[u8], &str, Option<&str>)] = &[
+            (
+                "NULL",
+                b"SSH-2.0-OpenSSH_9.6p1 Ubuntu-3ubuntu13\r\n",
+                "ssh",
+                Some("OpenSSH 9.6p1"),
+            ),
+            (
+                "NULL",
+                b"SSH-2.0-dropbear_2022.83\r\n",
+                "ssh",
+                Some("Dropbear 2022.83"),
+            ),
+            ("NULL", b"SSH-2.0-Cisco-1.25\r\n", "ssh", None),
+            (
+                "NULL",
+                b"220 (vsFTPd 3.0.5)\r\n",
+                "ftp",
+                Some("vsftpd 3.0.5"),
+            ),
+            (
+                "NULL",
+                b"220 ProFTPD 1.3.8 Server ready.\r\n",
+                "ftp",
+                Some("ProFTPD 1.3.8"),
+            ),
+            (
+                "NULL",
+                b"220 mail.example.com ESMTP Postfix (Debian)\r\n",
+                "smtp",
+                Some("Postfix"),
+            ),
+            (
+                "NULL",
+                b"220 mx ESMTP Exim 4.96 Mon, 1 Jan\r\n",
+                "smtp",
+                Some("Exim 4.96"),
+            ),
+            ("NULL", b"+OK Dovecot ready.\r\n", "pop3", Some("Dovecot")),
+            (
+                "NULL",
+                b"* OK [CAPABILITY IMAP4rev1] Dovecot ready.\r\n",
+                "imap",
+                Some("Dovecot"),
+            ),
+            ("NULL", b"RFB 003.008\n", "vnc", Some("003.008")),
+            (
+                "NULL",
+                b"J\0\0\0\n8.0.36\0\x08\0\0\0",
+                "mysql",
+                Some("MySQL 8.0.36"),
+            ),
+            ("NULL", b"\xff\xfd\x18\xff\xfd\x20", "telnet", None),
+            (
+                "GetRequest",
+                b"HTTP/1.1 200 OK\r\nServer: nginx/1.24.0\r\n\r\n",
+                "http",
+                Some("nginx 1.24.0"),
+            ),
+            (
+                "GetRequest",
+                b"HTTP/1.0 200 OK\r\nServer: Apache/2.4.58 (Unix)\r\n\r\n",
+                "http",
+                Some("Apache httpd 2.4.58"),
+            ),
+            (
+                "GetRequest",
+                b"HTTP/1.1 404 Not Found\r\n\r\n",
+                "http",
+                None,
+            ),
+            ("RedisPing", b"+PONG\r\n", "redis", None),
+            (
+                "RedisPing",
+                b"-NOAUTH Authentication required.\r\n",
+                "redis",
+                None,
+            ),
+            (
+                "MemcachedVersion",
+                b"VERSION 1.6.21\r\n",
+                "memcached",
+                Some("1.6.21"),
+            ),
+        ];
+        for &(probe, reply, service, version) in cases {
+            assert_eq!(
+                identify(&probes, probe, reply),
+                Some((service.to_string(), version.map(str::to_string))),
+                "{}",
+                String::from_utf8_lossy(reply)
+            );
+        }
+
+        for (probe, reply) in [
+            ("NULL", &b"hello\r\n"[..]),
+            ("NULL", b" SSH-2.0-OpenSSH_9.6\r\n"),
+            ("GetRequest", b"SSH-2.0-OpenSSH_9.6\r\n"),
+            ("RedisPing", b"-ERR unknown command\r\n"),
+        ] {
+            assert_eq!(identify(&probes, probe, reply), None, "{:?}", reply);
+        }
+    }
+
+    #[test]
+    fn matches_patterns() {
+        let cases: &[(&str, &str, Option<Option<&str>>)] = &[
+            ("abc", "abcdef", Some(None)),
+            ("abc", "ab", None),
+            ("a?c", "abc", Some(None)),
+            ("a?c", "ac", None),
+            ("*def", "abcdef", Some(None)),
+            ("\\*x", "*x", Some(None)),
+            ("\\*x", "ax", None),
+            ("v{version} ", "v1.2.3-rc1 build", Some(Some("1.2.3-rc1"))),
+            // The version backs off until the rest of the pattern fits.
+            ("v{version}.x", "v1.2.x", Some(Some("1.2"))),
+            ("v{version}", "v!", None),
+            ("\\x00\\xff", "\0\u{ff}", Some(None)),
+        ];
+        for &(pattern, text, expected) in cases {
+            let tokens = compile(pattern).unwrap();
+            let text: Vec<char> = text.chars().collect();
+            let mut version = None;
+            let found = matches(&tokens, &text, &mut version).then_some(version);
+            assert_eq!(
+                found,
+                expected.map(|v| v.map(str::to_string)),
+                "{}",
+                pattern
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_malformed_signatures() {
+        let cases = [
+            ("match ssh \"SSH-\"", "line 1: 'match' before any probe"),
+            ("probe X \"\"\nport 22", "line 2: unknown keyword 'port'"),
+            ("probe X \"\\q\"", "line 1: invalid escape \\q"),
+            ("probe X \"abc", "line 1: unterminated string"),
+            ("probe X abc", "line 1: expected a quoted string"),
+            (
+                "probe X \"\"\nmatch x \"{name}\"",
+                "line 2: unknown capture {name}",
+            ),
+        ];
+        for (text, error) in cases {
+            assert_eq!(parse(text).err().as_deref(), Some(error), "{}", text);
+        }
+
+        let probes = parse("probe X \"A\\x00\\xe9\\r\\n\"\nports 1,2\n").unwrap();
+        assert_eq!(probes[0].payload, b"A\0\xe9\r\n");
+        assert_eq!(probes[0].ports, Some(vec![1, 2]));
+    }
+}
@@ -0,0 +1,90 @@
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::timeouts;
+
+const MAGIC: &str = "NETCORE-SESSION";
+// Room for the magic, a token and an attempt number.
+const MAX_HEADER: usize = 64;
+
+// Ties the connections of one resumable session together. The client sends
+// it as the first line of every connection, with the attempt number going up
+// on each reconnect; an echo server sends it back like any other data, so
+// the client can also tell the peer is still the echo server it started
+// with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header {
+    pub token: String,
+    pub attempt: u32,
+}
+
+impl Header {
+    pub fn start() -> Header {
+        Header {
+            token: format!("{:016x}", rand::random::<u64>()),
+            attempt: 0,
+        }
+    }
+
+    pub fn resumed(&self) -> Header {
+        Header {
+            token: self.token.clone(),
+            attempt: self.attempt + 1,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        format!("{} {} {}\r\n", MAGIC, self.token, self.attempt).into_bytes()
+    }
+
+    pub fn parse(data: &[u8]) -> Option<Header> {
+        let end = data.iter().position(|&b| b == b'\n')?;
+        let line = std::str::from_utf8(&data[..end]).ok()?;
+        let mut fields = line.trim_end_matches('\r').split(' ');
+        if fields.next()? != MAGIC {
+            return None;
+        }
+        let token = fields.next()?;
+        if token.is_empty() || !token.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        let attempt = fields.next()?.parse().ok()?;
+        if fields.next().is_some() {
+            return None;
+        }
+        Some(Header {
+            token: token.to_string(),
+            attempt,
+        })
+    }
+}
+
+// The header at the start of `socket`, if there is one, without consuming
+// it: the handler still echoes it back. Anything that doesn't arrive in the
+// first segment is treated as no header.
+pub async fn peek(socket: &TcpStream) -> Option<Header> {
+    let mut buffer = [0; MAX_HEADER];
+    let n = timeout(timeouts::get().read, socket.peek(&mut buffer))
+        .await
+        .ok()?
+        .ok()?;
+    Header::parse(&buffer[..n])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trips_and_rejects_other_data() {
+        let header = Header::start().resumed().resumed();
+        let bytes = header.to_bytes();
+        assert!(bytes.len() <= MAX_HEADER);
+        assert_eq!(Header::parse(&bytes), Some(header));
+
+        assert_eq!(Header::parse(b"GET / HTTP/1.1\r\n"), None);
+        assert_eq!(Header::parse(b"NETCORE-SESSION 00ff"), None);
+        assert_eq!(Header::parse(b"NETCORE-SESSION zz 1\r\n"), None);
+        assert_eq!(Header::parse(b"NETCORE-SESSION 00ff 1 extra\r\n"), None);
+    }
+}
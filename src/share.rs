@@ -0,0 +1,189 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::cli::{self, Opt};
+use crate::exit;
+use crate::json::Value;
+use crate::outbound::{self, OutboundConfig};
+use crate::output::{self, Mode};
+use crate::pair;
+use crate::say;
+use crate::timeouts;
+use crate::tunnel::Features;
+
+const MAX_TEXT_BYTES: usize = 64 * 1024;
+const CLIPBOARD_TOOLS: &[&[&str]] = &[
+    &["pbcopy"],
+    &["wl-copy"],
+    &["xclip", "-selection", "clipboard"],
+    &["xsel", "--clipboard", "--input"],
+];
+
+const SHARE_OPTS: &[Opt] = &[Opt {
+    name: "--copy",
+    value: None,
+    help: "Also put received text on the clipboard",
+}];
+
+fn copy_to_clipboard(text: &str) -> Result<&'static str, String> {
+    for tool in CLIPBOARD_TOOLS {
+        let Ok(mut child) = Command::new(tool[0])
+            .args(&tool[1..])
+            .stdin(Stdio::piped())
+            .spawn()
+        else {
+            continue;
+        };
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin
+                .write_all(text.as_bytes())
+                .map_err(|e| e.to_string())?;
+        }
+        return match child.wait() {
+            Ok(status) if status.success() => Ok(tool[0]),
+            Ok(status) => Err(format!("{} exited with {}", tool[0], status)),
+            Err(e) => Err(e.to_string()),
+        };
+    }
+    Err("no clipboard tool found (pbcopy, wl-copy, xclip or xsel)".to_string())
+}
+
+async fn read_stdin() -> Result<String, String> {
+    let mut text = String::new();
+    tokio::io::stdin()
+        .take(MAX_TEXT_BYTES as u64 + 1)
+        .read_to_string(&mut text)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(text)
+}
+
+async fn send(
+    outbound: &OutboundConfig,
+    relay: &str,
+    code: &str,
+    text: String,
+    features: Features,
+    encrypt: bool,
+) -> Result<(), String> {
+    if text.len() > MAX_TEXT_BYTES {
+        return Err(format!("text is larger than {} bytes", MAX_TEXT_BYTES));
+    }
+    let (mux, _incoming, peer) = pair::connect(
+        outbound,
+        relay,
+        Some(code),
+        "netcore share-text",
+        features,
+        encrypt,
+    )
+    .await?;
+    say!("Paired with {}", peer.observed);
+
+    let mut stream = mux.open().await.map_err(|e| e.to_string())?;
+    stream
+        .write_all(text.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    stream.shutdown().await.map_err(|e| e.to_string())?;
+
+    // Wait for the receiver to close its side so the text is not cut off.
+    let mut ack = Vec::new();
+    let _ = stream.read_to_end(&mut ack).await;
+    say!("Sent {} bytes", text.len());
+    if features.compress {
+        say!("Compression: {}", mux.compression());
+    }
+    Ok(())
+}
+
+async fn receive(
+    outbound: &OutboundConfig,
+    relay: &str,
+    copy: bool,
+    features: Features,
+    encrypt: bool,
+) -> Result<(), String> {
+    let (mux, mut incoming, peer) = pair::connect(
+        outbound,
+        relay,
+        None,
+        "netcore share-text",
+        features,
+        encrypt,
+    )
+    .await?;
+    let mut stream = incoming.accept().await.ok_or("paired peer disconnected")?;
+
+    let mut text = Vec::new();
+    (&mut stream)
+        .take(MAX_TEXT_BYTES as u64 + 1)
+        .read_to_end(&mut text)
+        .await
+        .map_err(|e| e.to_string())?;
+    if text.len() > MAX_TEXT_BYTES {
+        return Err(format!("peer sent more than {} bytes", MAX_TEXT_BYTES));
+    }
+    let _ = stream.shutdown().await;
+    if mux.integrity().failed > 0 {
+        return Err("received text does not match the digest the sender computed".to_string());
+    }
+
+    let text = String::from_utf8_lossy(&text);
+    // The text is the result, so --quiet still prints it.
+    match output::mode() {
+        Mode::Human => {
+            eprintln!("Received {} bytes from {}", text.len(), peer.observed);
+            println!("{}", text.trim_end_matches('\n'));
+        }
+        Mode::Quiet => println!("{}", text.trim_end_matches('\n')),
+        Mode::Porcelain => output::emit(Value::object([
+            ("from", Value::from(peer.observed.to_string())),
+            ("text", Value::from(text.as_ref())),
+        ])),
+    }
+    if copy {
+        match copy_to_clipboard(&text) {
+            Ok(tool) if output::human() => eprintln!("Copied to the clipboard with {}", tool),
+            Ok(_) => {}
+            Err(e) => eprintln!("Could not copy to the clipboard: {}", e),
+        }
+    }
+    Ok(())
+}
+
+pub const COMMAND: cli::Command = cli::Command {
+    name: "share-text",
+    usage: "netcore share-text [code [text|-]]",
+    about: "Send text to a paired machine, or wait to receive it",
+    groups: &[pair::OPTS, SHARE_OPTS, outbound::OPTS, timeouts::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let relay = cli::or_exit(pair::relay_from_args(&args));
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+
+    let features = pair::features_from_args(&args);
+    let encrypt = pair::encrypt_from_args(&args);
+    let result = match args.positional() {
+        [] => receive(&outbound, relay, args.flag("--copy"), features, encrypt).await,
+        [code, rest @ ..] => {
+            let text = match rest {
+                [] => read_stdin().await,
+                [dash] if dash == "-" => read_stdin().await,
+                words => Ok(words.join(" ")),
+            };
+            match text {
+                Ok(text) => send(&outbound, relay, code, text, features, encrypt).await,
+                Err(e) => Err(e),
+            }
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("share-text failed: {}", e);
+        std::process::exit(exit::code(&e));
+    }
+}
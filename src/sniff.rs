@@ -0,0 +1,813 @@
+// `netcore sniff`: a packet capture for boxes without tcpdump. An AF_PACKET
+// socket sees every frame on one interface, or all of them, from the
+// network header up; each one is decoded into a line like tcpdump's and
+// printed as it arrives. --filter takes tcpdump's filter syntax, evaluated
+// here on the decoded packet rather than compiled for the kernel, which is
+// plenty for field debugging at the rates a shell can print anyway.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::acl::Cidr;
+use crate::cli::{self, Command, Opt};
+use crate::exit;
+use crate::flows::{self, Key, Table};
+use crate::ipv6;
+use crate::json::Value;
+use crate::output;
+use crate::say;
+use crate::top::format_bytes;
+
+const ETH_P_ALL: u16 = 0x0003;
+const ETH_P_IP: u16 = 0x0800;
+const ETH_P_ARP: u16 = 0x0806;
+const ETH_P_IPV6: u16 = 0x86dd;
+const ARPHRD_LOOPBACK: u16 = 772;
+// Big enough for any frame short of jumbo frames with offloads.
+const SNAPLEN: usize = 65536;
+const FLOW_CAPACITY: usize = 65536;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--interface",
+        value: Some("<name>"),
+        help: "Interface to capture on (default: all of them)",
+    },
+    Opt {
+        name: "--filter",
+        value: Some("<expr>"),
+        help: "tcpdump-style filter, such as \"tcp port 6881 and not host 10.0.0.1\"",
+    },
+    Opt {
+        name: "--count",
+        value: Some("<n>"),
+        help: "Stop after this many matching packets",
+    },
+    Opt {
+        name: "--flows",
+        value: None,
+        help: "Instead of each packet, print the top flows and talkers when the capture stops",
+    },
+];
+
+pub const COMMAND: Command = Command {
+    name: "sniff",
+    usage: "netcore sniff [--interface <name>] [--filter <expr>] [--count <n>] [--flows]",
+    about: "Capture packets and print a decoded summary of each (needs CAP_NET_RAW)",
+    groups: &[OPTS],
+};
+
+// What a filter can ask about a packet, and what its summary shows.
+#[derive(Default, Debug)]
+pub struct Packet {
+    // "IP", "IP6", "ARP" or the EtherType of anything else.
+    pub network: String,
+    // "tcp", "udp", "icmp", "icmp6", or empty when there's no transport
+    // header to speak of.
+    pub protocol: &'static str,
+    pub src: Option<IpAddr>,
+    pub dst: Option<IpAddr>,
+    pub sport: Option<u16>,
+    pub dport: Option<u16>,
+    // The protocol's own fields, after the addresses.
+    pub info: String,
+}
+
+impl Packet {
+    pub fn summary(&self) -> String {
+        let endpoint = |ip: Option<IpAddr>, port: Option<u16>| match (ip, port) {
+            (Some(ip), Some(port)) => SocketAddr::new(ip, port).to_string(),
+            (Some(ip), None) => ip.to_string(),
+            (None, _) => "?".to_string(),
+        };
+        match self.network.as_str() {
+            "IP" | "IP6" => format!(
+                "{} {} > {} {}",
+                self.network,
+                endpoint(self.src, self.sport),
+                endpoint(self.dst, self.dport),
+                self.info
+            ),
+            _ => format!("{} {}", self.network, self.info),
+        }
+    }
+}
+
+// `data` starts at the network header, as SOCK_DGRAM packet sockets hand
+// frames over; `ethertype` is the protocol the link layer said it carries.
+pub fn decode(ethertype: u16, data: &[u8]) -> Packet {
+    match ethertype {
+        ETH_P_IP => decode_ipv4(data),
+        ETH_P_IPV6 => decode_ipv6(data),
+        ETH_P_ARP => decode_arp(data),
+        other => Packet {
+            network: format!("ethertype 0x{:04x}", other),
+            info: format!("length {}", data.len()),
+            ..Packet::default()
+        },
+    }
+}
+
+fn truncated(network: &str) -> Packet {
+    Packet {
+        network: network.to_string(),
+        info: "truncated".to_string(),
+        ..Packet::default()
+    }
+}
+
+fn be16(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+fn be32(data: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn decode_ipv4(data: &[u8]) -> Packet {
+    if data.len() < 20 || data[0] >> 4 != 4 {
+        return truncated("IP");
+    }
+    let header = (data[0] & 0x0f) as usize * 4;
+    if header < 20 || data.len() < header {
+        return truncated("IP");
+    }
+    // Less than the header says when the capture cut the packet short.
+    let total = (be16(data, 2) as usize).clamp(header, data.len());
+    let src = Ipv4Addr::new(data[12], data[13], data[14], data[15]);
+    let dst = Ipv4Addr::new(data[16], data[17], data[18], data[19]);
+    let fragment = be16(data, 6) & 0x1fff;
+    let mut packet = Packet {
+        network: "IP".to_string(),
+        src: Some(src.into()),
+        dst: Some(dst.into()),
+        ..Packet::default()
+    };
+    let payload = &data[header..total];
+    if fragment != 0 {
+        // Only the first fragment carries the transport header.
+        packet.info = format!("fragment offset {} length {}", fragment * 8, payload.len());
+        return packet;
+    }
+    transport(&mut packet, data[9], payload, data[8]);
+    packet
+}
+
+fn decode_ipv6(data: &[u8]) -> Packet {
+    if data.len() < 40 || data[0] >> 4 != 6 {
+        return truncated("IP6");
+    }
+    let address = |at: usize| {
+        let mut octets = [0; 16];
+        octets.copy_from_slice(&data[at..at + 16]);
+        IpAddr::V6(Ipv6Addr::from(octets))
+    };
+    let mut packet = Packet {
+        network: "IP6".to_string(),
+        src: Some(address(8)),
+        dst: Some(address(24)),
+        ..Packet::default()
+    };
+    let end = (40 + be16(data, 4) as usize).min(data.len());
+    let (mut next, mut at) = (data[6], 40);
+    // Hop-by-hop, routing and destination options share one layout;
+    // fragments have a fixed size.
+    while matches!(next, 0 | 43 | 44 | 60) && at + 8 <= end {
+        let length = match next {
+            44 => {
+                if be16(data, at + 2) & 0xfff8 != 0 {
+                    packet.info = "fragment".to_string();
+                    return packet;
+                }
+                8
+            }
+            _ => (data[at + 1] as usize + 1) * 8,
+        };
+        next = data[at];
+        at += length;
+    }
+    if at > end {
+        packet.info = "truncated".to_string();
+        return packet;
+    }
+    transport(&mut packet, next, &data[at..end], data[7]);
+    packet
+}
+
+fn transport(packet: &mut Packet, protocol: u8, data: &[u8], ttl: u8) {
+    match protocol {
+        6 if data.len() >= 20 => {
+            packet.protocol = "tcp";
+            packet.sport = Some(be16(data, 0));
+            packet.dport = Some(be16(data, 2));
+            let offset = (data[12] >> 4) as usize * 4;
+            let flags = data[13];
+            let names = [
+                (0x02, 'S'),
+                (0x01, 'F'),
+                (0x04, 'R'),
+                (0x08, 'P'),
+                (0x20, 'U'),
+                (0x40, 'E'),
+                (0x80, 'W'),
+                (0x10, '.'),
+            ];
+            let flags: String = names
+                .iter()
+                .filter(|(bit, _)| flags & bit != 0)
+                .map(|(_, name)| *name)
+                .collect();
+            let mut info = format!("tcp [{}] seq {}", flags, be32(data, 4));
+            if data[13] & 0x10 != 0 {
+                info += &format!(" ack {}", be32(data, 8));
+            }
+            info += &format!(
+                " win {} len {}",
+                be16(data, 14),
+                data.len().saturating_sub(offset)
+            );
+            packet.info = info;
+        }
+        17 if data.len() >= 8 => {
+            packet.protocol = "udp";
+            packet.sport = Some(be16(data, 0));
+            packet.dport = Some(be16(data, 2));
+            packet.info = format!("udp len {}", data.len() - 8);
+        }
+        1 if data.len() >= 4 => {
+            packet.protocol = "icmp";
+            let kind = match (data[0], data[1]) {
+                (0, _) => "echo reply".to_string(),
+                (3, code) => format!("unreachable code {}", code),
+                (8, _) => "echo request".to_string(),
+                (11, _) => "time exceeded".to_string(),
+                (kind, code) => format!("type {} code {}", kind, code),
+            };
+            packet.info = format!("icmp {} ttl {}", kind, ttl);
+            if matches!(data[0], 0 | 8) && data.len() >= 8 {
+                packet.info += &format!(" id {} seq {}", be16(data, 4), be16(data, 6));
+            }
+        }
+        58 if data.len() >= 4 => {
+            packet.protocol = "icmp6";
+            let kind = match (data[0], data[1]) {
+                (1, code) => format!("unreachable code {}", code),
+                (2, _) => "packet too big".to_string(),
+                (3, _) => "time exceeded".to_string(),
+                (128, _) => "echo request".to_string(),
+                (129, _) => "echo reply".to_string(),
+                (133, _) => "router solicitation".to_string(),
+                (134, _) => "router advertisement".to_string(),
+                (135, _) if data.len() >= 24 => {
+                    let mut target = [0; 16];
+                    target.copy_from_slice(&data[8..24]);
+                    format!("neighbor solicitation who-has {}", Ipv6Addr::from(target))
+                }
+                (136, _) => "neighbor advertisement".to_string(),
+                (kind, code) => format!("type {} code {}", kind, code),
+            };
+            packet.info = format!("icmp6 {} hlim {}", kind, ttl);
+        }
+        6 | 17 | 1 | 58 => packet.info = "truncated".to_string(),
+        other => packet.info = format!("protocol {} len {}", other, data.len()),
+    }
+}
+
+fn decode_arp(data: &[u8]) -> Packet {
+    // Only Ethernet and IPv4, which is all ARP carries in practice.
+    if data.len() < 28 || be16(data, 0) != 1 || be16(data, 2) != ETH_P_IP {
+        return truncated("ARP");
+    }
+    let mac = |at: usize| {
+        data[at..at + 6]
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<_>>()
+            .join(":")
+    };
+    let sender = Ipv4Addr::new(data[14], data[15], data[16], data[17]);
+    let target = Ipv4Addr::new(data[24], data[25], data[26], data[27]);
+    let info = match be16(data, 6) {
+        1 => format!("who-has {} tell {}", target, sender),
+        2 => format!("reply {} is-at {}", sender, mac(8)),
+        op => format!("op {} {} > {}", op, sender, target),
+    };
+    Packet {
+        network: "ARP".to_string(),
+        src: Some(sender.into()),
+        dst: Some(target.into()),
+        info,
+        ..Packet::default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    Src,
+    Dst,
+    Either,
+}
+
+impl Side {
+    fn check<T: Copy>(self, src: Option<T>, dst: Option<T>, test: impl Fn(T) -> bool) -> bool {
+        let src = src.is_some_and(&test);
+        let dst = dst.is_some_and(&test);
+        match self {
+            Side::Src => src,
+            Side::Dst => dst,
+            Side::Either => src || dst,
+        }
+    }
+}
+
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Network(&'static str),
+    Protocol(&'static str),
+    Host(Side, Vec<IpAddr>),
+    Net(Side, Cidr),
+    Ports(Side, u16, u16),
+}
+
+impl Filter {
+    pub fn matches(&self, packet: &Packet) -> bool {
+        match self {
+            Filter::And(a, b) => a.matches(packet) && b.matches(packet),
+            Filter::Or(a, b) => a.matches(packet) || b.matches(packet),
+            Filter::Not(a) => !a.matches(packet),
+            Filter::Network(network) => packet.network == *network,
+            Filter::Protocol(protocol) => packet.protocol == *protocol,
+            Filter::Host(side, ips) => side.check(packet.src, packet.dst, |ip| ips.contains(&ip)),
+            Filter::Net(side, net) => side.check(packet.src, packet.dst, |ip| net.contains(ip)),
+            Filter::Ports(side, low, high) => side.check(packet.sport, packet.dport, |port| {
+                (*low..=*high).contains(&port)
+            }),
+        }
+    }
+
+    // tcpdump's syntax: primitives such as `host`, `net`, `port`,
+    // `portrange`, `tcp`, `udp`, `icmp`, `icmp6`, `arp`, `ip` and `ip6`,
+    // qualified by `src` or `dst`, and combined with and, or, not and
+    // parentheses. `tcp port 80` is tcp and port 80.
+    pub fn parse(expression: &str) -> Result<Filter, String> {
+        let spaced = expression.replace('(', " ( ").replace(')', " ) ");
+        let tokens: Vec<&str> = spaced.split_whitespace().collect();
+        let mut parser = Parser { tokens, at: 0 };
+        let filter = parser.or()?;
+        match parser.peek() {
+            None => Ok(filter),
+            Some(token) => Err(format!("unexpected '{}' in filter", token)),
+        }
+    }
+}
+
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    at: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.at).copied()
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        let token = self.peek().ok_or("filter ends too early")?;
+        self.at += 1;
+        Ok(token)
+    }
+
+    fn or(&mut self) -> Result<Filter, String> {
+        let mut filter = self.and()?;
+        while matches!(self.peek(), Some("or" | "||")) {
+            self.at += 1;
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter, String> {
+        let mut filter = self.not()?;
+        while matches!(self.peek(), Some("and" | "&&")) {
+            self.at += 1;
+            filter = Filter::And(Box::new(filter), Box::new(self.not()?));
+        }
+        Ok(filter)
+    }
+
+    fn not(&mut self) -> Result<Filter, String> {
+        match self.next()? {
+            "not" | "!" => Ok(Filter::Not(Box::new(self.not()?))),
+            "(" => {
+                let filter = self.or()?;
+                match self.next()? {
+                    ")" => Ok(filter),
+                    token => Err(format!("expected ')' in filter, got '{}'", token)),
+                }
+            }
+            token => self.primitive(token),
+        }
+    }
+
+    fn primitive(&mut self, token: &'a str) -> Result<Filter, String> {
+        let protocol = match token {
+            "tcp" => Some("tcp"),
+            "udp" => Some("udp"),
+            "icmp" => Some("icmp"),
+            "icmp6" => Some("icmp6"),
+            _ => None,
+        };
+        if let Some(protocol) = protocol {
+            let filter = Filter::Protocol(protocol);
+            // A qualified port: tcp port 80, udp dst port 53.
+            return match self.peek() {
+                Some("port" | "portrange" | "src" | "dst") => {
+                    Ok(Filter::And(Box::new(filter), Box::new(self.qualified()?)))
+                }
+                _ => Ok(filter),
+            };
+        }
+        match token {
+            "ip" => Ok(Filter::Network("IP")),
+            "ip6" => Ok(Filter::Network("IP6")),
+            "arp" => Ok(Filter::Network("ARP")),
+            _ => {
+                self.at -= 1;
+                self.qualified()
+            }
+        }
+    }
+
+    fn qualified(&mut self) -> Result<Filter, String> {
+        let side = match self.peek() {
+            Some("src") => Side::Src,
+            Some("dst") => Side::Dst,
+            _ => Side::Either,
+        };
+        if side != Side::Either {
+            self.at += 1;
+        }
+        let kind = self.next()?;
+        let value = self.next()?;
+        match kind {
+            "host" => Ok(Filter::Host(side, resolve(value)?)),
+            "net" => Ok(Filter::Net(side, value.parse()?)),
+            "port" => {
+                let port = port(value)?;
+                Ok(Filter::Ports(side, port, port))
+            }
+            "portrange" => {
+                let (low, high) = value
+                    .split_once('-')
+                    .ok_or_else(|| format!("invalid portrange '{}', expected low-high", value))?;
+                Ok(Filter::Ports(side, port(low)?, port(high)?))
+            }
+            other => Err(format!("unknown filter primitive '{}'", other)),
+        }
+    }
+}
+
+fn port(value: &str) -> Result<u16, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid port '{}' in filter", value))
+}
+
+// Names are looked up once, up front, as tcpdump does.
+fn resolve(host: &str) -> Result<Vec<IpAddr>, String> {
+    if let Ok(ip) = host.parse() {
+        return Ok(vec![ip]);
+    }
+    let addrs = (host, 0)
+        .to_socket_addrs()
+        .map_err(|e| format!("{}: {}", host, e))?;
+    Ok(addrs.map(|addr| addr.ip()).collect())
+}
+
+struct Capture {
+    fd: OwnedFd,
+}
+
+struct Frame {
+    ethertype: u16,
+    interface: i32,
+    outgoing: bool,
+    loopback: bool,
+    length: usize,
+}
+
+impl Capture {
+    fn open(interface: Option<&str>) -> io::Result<Capture> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_PACKET,
+                libc::SOCK_DGRAM,
+                ETH_P_ALL.to_be() as libc::c_int,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let capture = Capture {
+            fd: unsafe { OwnedFd::from_raw_fd(fd) },
+        };
+
+        // Wakes up now and then to notice Ctrl-C.
+        let timeout = libc::timeval {
+            tv_sec: 0,
+            tv_usec: 200_000,
+        };
+        unsafe {
+            libc::setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const _ as *const libc::c_void,
+                std::mem::size_of_val(&timeout) as libc::socklen_t,
+            )
+        };
+
+        if let Some(interface) = interface {
+            let index = ipv6::interface_index(interface);
+            if index == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no interface named {}", interface),
+                ));
+            }
+            let mut address: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+            address.sll_family = libc::AF_PACKET as u16;
+            address.sll_protocol = ETH_P_ALL.to_be();
+            address.sll_ifindex = index as i32;
+            let result = unsafe {
+                libc::bind(
+                    fd,
+                    &address as *const _ as *const libc::sockaddr,
+                    std::mem::size_of_val(&address) as libc::socklen_t,
+                )
+            };
+            if result != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        Ok(capture)
+    }
+
+    // None when the wait for a packet timed out.
+    fn receive(&self, buffer: &mut [u8]) -> io::Result<Option<Frame>> {
+        let mut address: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        let mut length = std::mem::size_of_val(&address) as libc::socklen_t;
+        let received = unsafe {
+            libc::recvfrom(
+                self.fd.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                libc::MSG_TRUNC,
+                &mut address as *mut _ as *mut libc::sockaddr,
+                &mut length,
+            )
+        };
+        if received < 0 {
+            let e = io::Error::last_os_error();
+            return match e.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => Ok(None),
+                _ => Err(e),
+            };
+        }
+        Ok(Some(Frame {
+            ethertype: u16::from_be(address.sll_protocol),
+            interface: address.sll_ifindex,
+            outgoing: address.sll_pkttype == libc::PACKET_OUTGOING,
+            loopback: address.sll_hatype == ARPHRD_LOOPBACK,
+            length: received as usize,
+        }))
+    }
+}
+
+fn interface_name(index: i32, names: &mut HashMap<i32, String>) -> String {
+    names
+        .entry(index)
+        .or_insert_with(|| {
+            let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+            let found = unsafe { libc::if_indextoname(index as u32, name.as_mut_ptr()) };
+            if found.is_null() {
+                return index.to_string();
+            }
+            unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) }
+                .to_string_lossy()
+                .into_owned()
+        })
+        .clone()
+}
+
+fn print_flows(flows: &Table) {
+    say!("Top flows");
+    for (key, flow) in flows.top(flows::DEFAULT_LIMIT) {
+        let dst = key.dst.map(|dst| dst.to_string()).unwrap_or_default();
+        let packets = flow.packets.unwrap_or(0);
+        let duration = (flow.last - flow.first).as_secs_f64();
+        say!(
+            "  {:<5} {:<40} > {:<40} {:>10} {:>7} pkts {:>7.1}s",
+            key.protocol,
+            key.src,
+            dst,
+            format_bytes(flow.bytes as f64),
+            packets,
+            duration
+        );
+        output::emit(Value::object([
+            ("record", Value::from("flow")),
+            ("protocol", Value::from(key.protocol)),
+            ("src", Value::from(key.src.to_string())),
+            ("dst", Value::from(dst)),
+            ("bytes", Value::from(flow.bytes)),
+            ("packets", Value::from(packets)),
+            ("duration_ms", Value::from((duration * 1000.0) as u64)),
+        ]));
+    }
+    say!("Top talkers");
+    for talker in flows.talkers(flows::DEFAULT_LIMIT) {
+        say!(
+            "  {:<40} {:>10} {:>7} pkts {:>5} flow(s)",
+            talker.addr,
+            format_bytes(talker.bytes as f64),
+            talker.packets.unwrap_or(0),
+            talker.flows
+        );
+        let Value::Object(mut fields) = talker.to_json() else {
+            unreachable!()
+        };
+        fields.insert(0, ("record".to_string(), Value::from("talker")));
+        output::emit(Value::Object(fields));
+    }
+}
+
+fn clock(time: SystemTime) -> (f64, String) {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since.as_secs() % 86_400;
+    let text = format!(
+        "{:02}:{:02}:{:02}.{:06}",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        since.subsec_micros()
+    );
+    (since.as_secs_f64(), text)
+}
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    if let Some(extra) = args.positional().first() {
+        eprintln!("unexpected argument '{}'", extra);
+        std::process::exit(exit::USAGE);
+    }
+    let filter = args
+        .value("--filter")
+        .map(|expression| cli::or_exit(Filter::parse(expression)));
+    let count = cli::or_exit(args.parsed::<u64>("--count"));
+    let interface = args.value("--interface").map(str::to_string);
+    let capture = exit::or_exit(
+        Capture::open(interface.as_deref())
+            .map_err(|e| exit::Error::io("Failed to open a packet socket", e)),
+    );
+    say!(
+        "Capturing on {}{}",
+        interface.as_deref().unwrap_or("all interfaces"),
+        args.value("--filter")
+            .map(|expression| format!(" for '{}'", expression))
+            .unwrap_or_default()
+    );
+
+    let filtered = filter.is_some();
+    let account = args.flag("--flows");
+    let stop = Arc::new(AtomicBool::new(false));
+    tokio::spawn({
+        let stop = stop.clone();
+        async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                stop.store(true, Ordering::Relaxed);
+            }
+        }
+    });
+
+    let result = tokio::task::spawn_blocking(move || {
+        let mut buffer = vec![0; SNAPLEN];
+        let mut names = HashMap::new();
+        let (mut seen, mut matched) = (0u64, 0u64);
+        let mut flows = Table::new(FLOW_CAPACITY);
+        while !stop.load(Ordering::Relaxed) && count.is_none_or(|count| matched < count) {
+            let Some(frame) = capture.receive(&mut buffer)? else {
+                continue;
+            };
+            // Loopback hands each packet over twice, going out and coming
+            // back in.
+            if frame.loopback && frame.outgoing {
+                continue;
+            }
+            seen += 1;
+            let data = &buffer[..frame.length.min(buffer.len())];
+            let packet = decode(frame.ethertype, data);
+            if !filter.as_ref().is_none_or(|filter| filter.matches(&packet)) {
+                continue;
+            }
+            matched += 1;
+
+            if account {
+                if let (Some(src), Some(dst)) = (packet.src, packet.dst) {
+                    let key = Key {
+                        protocol: match packet.protocol {
+                            "" => "ip",
+                            protocol => protocol,
+                        },
+                        src: SocketAddr::new(src, packet.sport.unwrap_or(0)),
+                        dst: Some(SocketAddr::new(dst, packet.dport.unwrap_or(0))),
+                    };
+                    let now = Instant::now();
+                    flows.add(key, frame.length as u64, Some(1), now, now);
+                }
+                continue;
+            }
+            let (time, clock) = clock(SystemTime::now());
+            let interface = interface_name(frame.interface, &mut names);
+            let direction = if frame.outgoing { "Out" } else { "In" };
+            let summary = packet.summary();
+            say!("{} {} {:<3} {}", clock, interface, direction, summary);
+            output::emit(Value::object([
+                ("time", Value::from(time)),
+                ("interface", Value::from(interface)),
+                ("direction", Value::from(direction.to_ascii_lowercase())),
+                ("length", Value::from(frame.length as u64)),
+                ("network", Value::from(packet.network.as_str())),
+                ("protocol", Value::from(packet.protocol)),
+                ("src", Value::from(packet.src.map(|ip| ip.to_string()))),
+                ("dst", Value::from(packet.dst.map(|ip| ip.to_string()))),
+                ("sport", Value::from(packet.sport.map(u64::from))),
+                ("dport", Value::from(packet.dport.map(u64::from))),
+                ("summary", Value::from(summary)),
+            ]));
+        }
+        Ok::<_, io::Error>((seen, matched, flows))
+    })
+    .await
+    .expect("capture thread panicked");
+
+    let (seen, matched, flows) = exit::or_exit(result);
+    if account {
+        print_flows(&flows);
+    }
+    match filtered {
+        true => say!("{} packets matched, of {} captured", matched, seen),
+        false => say!("{} packets captured", seen),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_and_filters_like_tcpdump() {
+        // A SYN from 10.0.0.2:51000 to 10.0.0.1:6881.
+        let mut syn = vec![
+            0x45, 0, 0, 40, 0, 1, 0x40, 0, 64, 6, 0, 0, 10, 0, 0, 2, 10, 0, 0, 1,
+        ];
+        syn.extend_from_slice(&[
+            0xc7, 0x38, 0x1a, 0xe1, 0, 0, 0, 7, 0, 0, 0, 0, 0x50, 0x02, 0xfa, 0xf0, 0, 0, 0, 0,
+        ]);
+        let packet = decode(ETH_P_IP, &syn);
+        assert_eq!(
+            packet.summary(),
+            "IP 10.0.0.2:51000 > 10.0.0.1:6881 tcp [S] seq 7 win 64240 len 0"
+        );
+
+        let matches = |expression: &str| Filter::parse(expression).unwrap().matches(&packet);
+        assert!(matches("port 6881"));
+        assert!(matches("tcp dst port 6881 and src host 10.0.0.2"));
+        assert!(matches("not udp and (net 10.0.0.0/8 or ip6)"));
+        assert!(matches("src portrange 50000-52000"));
+        assert!(!matches("udp port 6881"));
+        assert!(!matches("src port 6881 or arp"));
+        assert!(Filter::parse("port").is_err());
+        assert!(Filter::parse("(tcp").is_err());
+        assert!(Filter::parse("tcp port 80 extra").is_err());
+
+        let arp = [
+            0, 1, 8, 0, 6, 4, 0, 1, 2, 0, 0, 0, 0, 1, 192, 168, 1, 5, 0, 0, 0, 0, 0, 0, 192, 168,
+            1, 1,
+        ];
+        let packet = decode(ETH_P_ARP, &arp);
+        assert_eq!(packet.summary(), "ARP who-has 192.168.1.1 tell 192.168.1.5");
+        assert!(
+            Filter::parse("arp and host 192.168.1.1")
+                .unwrap()
+                .matches(&packet)
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use std::process::Stdio;
+use tokio::process::Command;
+use tokio::time::{Duration, sleep};
+
+use crate::cli::{Args, Opt};
+use crate::say;
+
+const RETRY_SECS: u64 = 10;
+const SERVER_ALIVE_SECS: u64 = 15;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--ssh-tunnel",
+        value: Some("<[user@]host[:port]>"),
+        help: "Expose the server through an SSH remote forward",
+    },
+    Opt {
+        name: "--ssh-remote-port",
+        value: Some("<port>"),
+        help: "Port to listen on at the SSH server (default: local port)",
+    },
+    Opt {
+        name: "--ssh-identity",
+        value: Some("<path>"),
+        help: "Private key for the SSH tunnel",
+    },
+];
+
+pub struct SshTunnel {
+    pub destination: String,
+    pub port: Option<u16>,
+    pub remote_port: Option<u16>,
+    pub identity: Option<String>,
+}
+
+impl SshTunnel {
+    pub fn from_args(args: &Args) -> Result<Option<SshTunnel>, String> {
+        let Some(value) = args.value("--ssh-tunnel") else {
+            return Ok(None);
+        };
+
+        let (destination, port) = split_port(value)?;
+        if destination.is_empty() || destination.starts_with('-') {
+            return Err(format!("invalid SSH destination '{}'", value));
+        }
+
+        Ok(Some(SshTunnel {
+            destination: destination.replace(['[', ']'], ""),
+            port,
+            remote_port: args.parsed("--ssh-remote-port")?,
+            identity: args.value("--ssh-identity").map(str::to_string),
+        }))
+    }
+
+    fn command(&self, local_port: u16) -> Command {
+        let mut command = Command::new("ssh");
+        command
+            .args(["-N", "-T"])
+            .args(["-o", "BatchMode=yes"])
+            .args(["-o", "ExitOnForwardFailure=yes"])
+            .arg("-o")
+            .arg(format!("ServerAliveInterval={}", SERVER_ALIVE_SECS))
+            .args(["-o", "ServerAliveCountMax=3"])
+            .arg("-R")
+            .arg(format!(
+                "{}:127.0.0.1:{}",
+                self.remote_port.unwrap_or(local_port),
+                local_port
+            ));
+
+        if let Some(port) = self.port {
+            command.arg("-p").arg(port.to_string());
+        }
+        if let Some(identity) = &self.identity {
+            command.arg("-i").arg(identity);
+        }
+
+        command
+            .arg(&self.destination)
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        command
+    }
+}
+
+fn split_port(value: &str) -> Result<(&str, Option<u16>), String> {
+    let (host_part, port) = match value.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') || host.ends_with(']') => (host, Some(port)),
+        _ => (value, None),
+    };
+
+    let port = port
+        .map(|port| {
+            port.parse()
+                .map_err(|_| format!("invalid SSH port in '{}'", value))
+        })
+        .transpose()?;
+    Ok((host_part, port))
+}
+
+pub async fn run(tunnel: SshTunnel, local_port: u16) {
+    let remote_port = tunnel.remote_port.unwrap_or(local_port);
+
+    loop {
+        say!(
+            "Opening SSH tunnel: {} port {} -> local port {}",
+            tunnel.destination,
+            remote_port,
+            local_port
+        );
+
+        match tunnel.command(local_port).status().await {
+            Ok(status) => eprintln!("SSH tunnel to {} exited: {}", tunnel.destination, status),
+            Err(e) => eprintln!("Failed to start ssh for {}: {}", tunnel.destination, e),
+        }
+
+        sleep(Duration::from_secs(RETRY_SECS)).await;
+    }
+}
@@ -0,0 +1,1509 @@
+// A small SSH server for remote diagnostics (--ssh-server): on a box that
+// only has netcore, an operator can `ssh -p 2222 box check` or open a
+// shell there and run info, check and the scans, and nothing else. Only
+// what OpenSSH clients need is spoken: curve25519-sha256 key exchange, an
+// ssh-ed25519 host key, chacha20-poly1305@openssh.com, and ssh-ed25519
+// public key logins against an authorized_keys file. There is no
+// re-keying; a session long enough to want one is dropped.
+//
+// Commands run as netcore itself in a child process, their words split on
+// whitespace. Only the options listed in SHELL are accepted, none of which
+// names a file, so nothing on the box can be read or written through them.
+
+use std::collections::VecDeque;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::process::Command as Process;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, timeout};
+
+use crate::acl;
+use crate::aead::{self, SSH_KEY_LEN};
+use crate::cli::{self, Args, Command, Opt};
+use crate::ed25519::{self, Keypair};
+use crate::exit;
+use crate::history;
+use crate::identity;
+use crate::output;
+use crate::proxy;
+use crate::say;
+use crate::sha2::sha256;
+use crate::stats::{self, Counted};
+use crate::x25519;
+
+const KEX: &[&str] = &["curve25519-sha256", "curve25519-sha256@libssh.org"];
+const HOST_KEY: &str = identity::ALGORITHM;
+const CIPHER: &str = "chacha20-poly1305@openssh.com";
+
+// Like sshd's LoginGraceTime, if shorter.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(60);
+const MAX_AUTH_ATTEMPTS: usize = 6;
+const MAX_PACKET: usize = 256 * 1024;
+// What each side may send on the channel before hearing back.
+const WINDOW: u32 = 1 << 20;
+const MAX_CHUNK: u32 = 32 * 1024;
+const PROMPT: &str = "netcore> ";
+
+const DISCONNECT: u8 = 1;
+const IGNORE: u8 = 2;
+const UNIMPLEMENTED: u8 = 3;
+const DEBUG: u8 = 4;
+const SERVICE_REQUEST: u8 = 5;
+const SERVICE_ACCEPT: u8 = 6;
+const KEXINIT: u8 = 20;
+const NEWKEYS: u8 = 21;
+const KEX_ECDH_INIT: u8 = 30;
+const KEX_ECDH_REPLY: u8 = 31;
+const USERAUTH_REQUEST: u8 = 50;
+const USERAUTH_FAILURE: u8 = 51;
+const USERAUTH_SUCCESS: u8 = 52;
+const USERAUTH_PK_OK: u8 = 60;
+const GLOBAL_REQUEST: u8 = 80;
+const REQUEST_FAILURE: u8 = 82;
+const CHANNEL_OPEN: u8 = 90;
+const CHANNEL_OPEN_CONFIRMATION: u8 = 91;
+const CHANNEL_OPEN_FAILURE: u8 = 92;
+const CHANNEL_WINDOW_ADJUST: u8 = 93;
+const CHANNEL_DATA: u8 = 94;
+const CHANNEL_EXTENDED_DATA: u8 = 95;
+const CHANNEL_EOF: u8 = 96;
+const CHANNEL_CLOSE: u8 = 97;
+const CHANNEL_REQUEST: u8 = 98;
+const CHANNEL_SUCCESS: u8 = 99;
+const CHANNEL_FAILURE: u8 = 100;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--ssh-server",
+        value: Some("<addr>"),
+        help: "Serve a restricted SSH shell for diagnostics on this address (e.g. 0.0.0.0:2222)",
+    },
+    Opt {
+        name: "--ssh-host-key",
+        value: Some("<path>"),
+        help: "Ed25519 host key in OpenSSH format, generated if missing (default: next to the history file)",
+    },
+    Opt {
+        name: "--ssh-authorized-keys",
+        value: Some("<path>"),
+        help: "ssh-ed25519 keys allowed to log in; lines with options are skipped (default: ~/.ssh/authorized_keys)",
+    },
+];
+
+pub struct SshServer {
+    pub addr: SocketAddr,
+    host_key: Keypair,
+    authorized: Vec<[u8; 32]>,
+}
+
+impl SshServer {
+    pub fn from_args(args: &Args) -> Result<Option<SshServer>, String> {
+        let Some(addr) = args.parsed::<SocketAddr>("--ssh-server")? else {
+            return Ok(None);
+        };
+
+        let keys_path = match args.value("--ssh-authorized-keys") {
+            Some(path) => PathBuf::from(path),
+            None => std::env::var_os("HOME")
+                .map(|home| PathBuf::from(home).join(".ssh").join("authorized_keys"))
+                .ok_or("--ssh-server needs --ssh-authorized-keys when $HOME isn't set")?,
+        };
+        let text = fs::read_to_string(&keys_path)
+            .map_err(|e| format!("{}: {}", keys_path.display(), e))?;
+        let authorized = authorized_keys(&text);
+        if authorized.is_empty() {
+            return Err(format!(
+                "{}: no ssh-ed25519 keys without options",
+                keys_path.display()
+            ));
+        }
+
+        let host_key_path = match args.value("--ssh-host-key") {
+            Some(path) => PathBuf::from(path),
+            None => history::default_path().with_file_name("ssh_host_ed25519_key"),
+        };
+        let host_key = if host_key_path.exists() {
+            identity::read(&host_key_path)?
+        } else {
+            let seed: [u8; 32] = rand::random();
+            identity::write(&host_key_path, &seed, "netcore host key")
+                .map_err(|e| format!("{}: {}", host_key_path.display(), e))?;
+            say!("Generated SSH host key {}", host_key_path.display());
+            Keypair::from_seed(&seed)
+        };
+
+        Ok(Some(SshServer {
+            addr,
+            host_key,
+            authorized,
+        }))
+    }
+}
+
+// The ssh-ed25519 keys in an authorized_keys file. Lines with options
+// such as from= are skipped rather than trusted without them.
+fn authorized_keys(text: &str) -> Vec<[u8; 32]> {
+    text.lines().filter_map(identity::parse_public).collect()
+}
+
+// SSH wire encoding (RFC 4251 5).
+#[derive(Default)]
+pub struct Writer(pub Vec<u8>);
+
+impl Writer {
+    pub fn message(kind: u8) -> Writer {
+        Writer(vec![kind])
+    }
+
+    pub fn byte(mut self, byte: u8) -> Writer {
+        self.0.push(byte);
+        self
+    }
+
+    pub fn bool(self, value: bool) -> Writer {
+        self.byte(value as u8)
+    }
+
+    pub fn u32(mut self, value: u32) -> Writer {
+        self.0.extend_from_slice(&value.to_be_bytes());
+        self
+    }
+
+    pub fn string(mut self, value: impl AsRef<[u8]>) -> Writer {
+        let value = value.as_ref();
+        self.0
+            .extend_from_slice(&(value.len() as u32).to_be_bytes());
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    // An unsigned big-endian number.
+    pub fn mpint(self, value: &[u8]) -> Writer {
+        let start = value.iter().position(|b| *b != 0).unwrap_or(value.len());
+        let mut digits = value[start..].to_vec();
+        if digits.first().is_some_and(|b| b & 0x80 != 0) {
+            digits.insert(0, 0);
+        }
+        self.string(digits)
+    }
+}
+
+pub struct Reader<'a>(pub &'a [u8]);
+
+impl<'a> Reader<'a> {
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.0.len() < n {
+            return Err("malformed packet".to_string());
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    pub fn byte(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, String> {
+        Ok(self.byte()? != 0)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn string(&mut self) -> Result<&'a [u8], String> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn text(&mut self) -> Result<String, String> {
+        Ok(String::from_utf8_lossy(self.string()?).into_owned())
+    }
+}
+
+type Stream = Counted<TcpStream>;
+
+struct Receiver {
+    half: ReadHalf<Stream>,
+    seq: u32,
+    key: Option<[u8; SSH_KEY_LEN]>,
+}
+
+impl Receiver {
+    // The next packet's payload, skipping the ones that carry nothing.
+    async fn read(&mut self) -> Result<Vec<u8>, String> {
+        loop {
+            let payload = self.read_any().await?;
+            match payload.first() {
+                Some(&IGNORE | &DEBUG | &UNIMPLEMENTED) => continue,
+                Some(&DISCONNECT) => return Err("client disconnected".to_string()),
+                Some(_) => return Ok(payload),
+                None => return Err("empty packet".to_string()),
+            }
+        }
+    }
+
+    async fn read_any(&mut self) -> Result<Vec<u8>, String> {
+        let mut length = [0; 4];
+        read_exact(&mut self.half, &mut length).await?;
+        let packet = match &self.key {
+            None => {
+                let mut packet = vec![0; checked_length(u32::from_be_bytes(length))?];
+                read_exact(&mut self.half, &mut packet).await?;
+                packet
+            }
+            Some(key) => {
+                let len = checked_length(aead::ssh_length(key, self.seq, &length))?;
+                let mut sealed = vec![0; 4 + len + aead::TAG_LEN];
+                sealed[..4].copy_from_slice(&length);
+                read_exact(&mut self.half, &mut sealed[4..]).await?;
+                aead::ssh_open(key, self.seq, &sealed).ok_or("packet failed authentication")?
+            }
+        };
+        self.seq = self.seq.wrapping_add(1);
+        unpad(&packet)
+    }
+}
+
+// The payload of a packet after its length: a padding length, the
+// payload, then the padding.
+pub fn unpad(packet: &[u8]) -> Result<Vec<u8>, String> {
+    let padding = *packet.first().ok_or("malformed packet")? as usize;
+    packet
+        .get(1..packet.len().saturating_sub(padding))
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| "malformed packet".to_string())
+}
+
+pub fn checked_length(len: u32) -> Result<usize, String> {
+    let len = len as usize;
+    if !(5..=MAX_PACKET).contains(&len) {
+        return Err(format!("bad packet length {}", len));
+    }
+    Ok(len)
+}
+
+async fn read_exact(half: &mut ReadHalf<Stream>, buf: &mut [u8]) -> Result<(), String> {
+    half.read_exact(buf)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+struct Sender {
+    half: WriteHalf<Stream>,
+    seq: u32,
+    key: Option<[u8; SSH_KEY_LEN]>,
+}
+
+impl Sender {
+    async fn send(&mut self, payload: Writer) -> Result<(), String> {
+        let payload = payload.0;
+        // The length isn't counted towards the block size once it is
+        // encrypted apart from the rest.
+        let counted = payload.len() + if self.key.is_some() { 1 } else { 5 };
+        let mut padding = 8 - counted % 8;
+        if padding < 4 {
+            padding += 8;
+        }
+        let mut packet = ((1 + payload.len() + padding) as u32)
+            .to_be_bytes()
+            .to_vec();
+        packet.push(padding as u8);
+        packet.extend_from_slice(&payload);
+        packet.extend((0..padding).map(|_| rand::random::<u8>()));
+        if let Some(key) = &self.key {
+            packet = aead::ssh_seal(key, self.seq, &packet);
+        }
+        self.seq = self.seq.wrapping_add(1);
+        self.half
+            .write_all(&packet)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn disconnect(&mut self, reason: u32, description: &str) {
+        let message = Writer::message(DISCONNECT)
+            .u32(reason)
+            .string(description)
+            .string("");
+        let _ = self.send(message).await;
+    }
+}
+
+async fn exchange_versions(
+    rx: &mut ReadHalf<Stream>,
+    tx: &mut WriteHalf<Stream>,
+    ours: &str,
+) -> Result<String, String> {
+    tx.write_all(format!("{}\r\n", ours).as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    // Servers may send other lines first, but clients don't.
+    let mut line = Vec::new();
+    loop {
+        let byte = rx.read_u8().await.map_err(|e| e.to_string())?;
+        if byte == b'\n' {
+            break;
+        }
+        if line.len() == 255 {
+            return Err("version line too long".to_string());
+        }
+        line.push(byte);
+    }
+    let line = String::from_utf8_lossy(&line)
+        .trim_end_matches('\r')
+        .to_string();
+    if !line.starts_with("SSH-2.0-") && !line.starts_with("SSH-1.99-") {
+        return Err(format!("not an SSH 2 client: {:?}", line));
+    }
+    Ok(line)
+}
+
+fn name_list(list: &[u8]) -> Vec<&str> {
+    std::str::from_utf8(list)
+        .unwrap_or_default()
+        .split(',')
+        .collect()
+}
+
+// Whether the client guessed wrong with a first key exchange packet sent
+// ahead, which is then to be ignored.
+pub fn negotiate(kexinit: &[u8]) -> Result<bool, String> {
+    let mut kexinit = Reader(kexinit);
+    kexinit.byte()?;
+    kexinit.take(16)?;
+    let lists: Vec<Vec<&str>> = (0..10)
+        .map(|_| kexinit.string().map(name_list))
+        .collect::<Result<_, _>>()?;
+    let follows = kexinit.bool()?;
+
+    let wanted = [
+        ("key exchange", &lists[0], KEX),
+        ("host key", &lists[1], &[HOST_KEY][..]),
+        ("cipher", &lists[2], &[CIPHER][..]),
+        ("cipher", &lists[3], &[CIPHER][..]),
+        ("compression", &lists[6], &["none"][..]),
+        ("compression", &lists[7], &["none"][..]),
+    ];
+    for (what, offered, ours) in wanted {
+        if !offered.iter().any(|name| ours.contains(name)) {
+            return Err(format!(
+                "no common {} algorithm (client offers {})",
+                what,
+                offered.join(",")
+            ));
+        }
+    }
+    let guessed = lists[0].first().is_some_and(|kex| KEX.contains(kex))
+        && lists[1].first() == Some(&HOST_KEY);
+    Ok(follows && !guessed)
+}
+
+fn derive(shared: &[u8; 32], hash: &[u8; 32], letter: u8, session: &[u8; 32]) -> [u8; SSH_KEY_LEN] {
+    let k = Writer::default().mpint(shared).0;
+    let first = sha256(&[&k[..], hash, &[letter], session].concat());
+    let second = sha256(&[&k[..], hash, &first].concat());
+    let mut key = [0; SSH_KEY_LEN];
+    key[..32].copy_from_slice(&first);
+    key[32..].copy_from_slice(&second);
+    key
+}
+
+// The algorithms on offer, which are the only ones spoken.
+fn kexinit() -> Writer {
+    let mut cookie = Writer::message(KEXINIT);
+    cookie.0.extend_from_slice(&rand::random::<[u8; 16]>());
+    cookie
+        .string(KEX.join(","))
+        .string(HOST_KEY)
+        .string(CIPHER)
+        .string(CIPHER)
+        // Unused with an AEAD cipher, but the lists can't be empty.
+        .string("hmac-sha2-256")
+        .string("hmac-sha2-256")
+        .string("none")
+        .string("none")
+        .string("")
+        .string("")
+        .bool(false)
+        .u32(0)
+}
+
+// RFC 5656 4 with RFC 8731's curve25519-sha256, `secret` being this
+// side's ephemeral key. Returns the session identifier.
+async fn key_exchange(
+    rx: &mut Receiver,
+    tx: &mut Sender,
+    host_key: &Keypair,
+    client_version: &str,
+    server_version: &str,
+    secret: &[u8; 32],
+) -> Result<[u8; 32], String> {
+    let ours = kexinit();
+    let server_kexinit = ours.0.clone();
+    tx.send(ours).await?;
+
+    let client_kexinit = rx.read().await?;
+    if client_kexinit[0] != KEXINIT {
+        return Err("expected KEXINIT".to_string());
+    }
+    if negotiate(&client_kexinit)? {
+        rx.read().await?;
+    }
+
+    let init = rx.read().await?;
+    let mut init = Reader(&init);
+    if init.byte()? != KEX_ECDH_INIT {
+        return Err("expected KEX_ECDH_INIT".to_string());
+    }
+    let client_public: [u8; 32] = init
+        .string()?
+        .try_into()
+        .map_err(|_| "bad client key exchange value")?;
+    let server_public = x25519::public_key(secret);
+    let shared = x25519::x25519(secret, &client_public);
+    if shared == [0; 32] {
+        return Err("bad client key exchange value".to_string());
+    }
+
+    let host_blob = identity::blob(&host_key.public);
+    let exchange = Writer::default()
+        .string(client_version)
+        .string(server_version)
+        .string(&client_kexinit)
+        .string(&server_kexinit)
+        .string(&host_blob)
+        .string(client_public)
+        .string(server_public)
+        .mpint(&shared);
+    let hash = sha256(&exchange.0);
+    let signature = Writer::default()
+        .string(HOST_KEY)
+        .string(host_key.sign(&hash));
+    let reply = Writer::message(KEX_ECDH_REPLY)
+        .string(&host_blob)
+        .string(server_public)
+        .string(&signature.0);
+    tx.send(reply).await?;
+
+    tx.send(Writer::message(NEWKEYS)).await?;
+    tx.key = Some(derive(&shared, &hash, b'D', &hash));
+    if rx.read().await?[0] != NEWKEYS {
+        return Err("expected NEWKEYS".to_string());
+    }
+    rx.key = Some(derive(&shared, &hash, b'C', &hash));
+    Ok(hash)
+}
+
+// Returns the user name and the key they logged in with.
+async fn authenticate(
+    rx: &mut Receiver,
+    tx: &mut Sender,
+    server: &SshServer,
+    session: &[u8; 32],
+) -> Result<(String, [u8; 32]), String> {
+    let request = rx.read().await?;
+    let mut request = Reader(&request);
+    if request.byte()? != SERVICE_REQUEST || request.string()? != b"ssh-userauth" {
+        return Err("expected a user authentication request".to_string());
+    }
+    tx.send(Writer::message(SERVICE_ACCEPT).string("ssh-userauth"))
+        .await?;
+
+    let mut attempts = 0;
+    while attempts < MAX_AUTH_ATTEMPTS {
+        let request = rx.read().await?;
+        let mut request = Reader(&request);
+        if request.byte()? != USERAUTH_REQUEST {
+            return Err("expected a user authentication request".to_string());
+        }
+        let user = request.text()?;
+        let service = request.string()?;
+        let method = request.string()?;
+        if service == b"ssh-connection" && method == b"publickey" {
+            attempts += 1;
+            let signed = request.bool()?;
+            let algorithm = request.string()?;
+            let blob = request.string()?;
+            let key = identity::from_blob(blob)
+                .filter(|key| algorithm == HOST_KEY.as_bytes() && server.authorized.contains(key));
+            match key {
+                Some(_) if !signed => {
+                    let ok = Writer::message(USERAUTH_PK_OK)
+                        .string(algorithm)
+                        .string(blob);
+                    tx.send(ok).await?;
+                    continue;
+                }
+                Some(key) => {
+                    let mut signature = Reader(request.string()?);
+                    let signature = signature
+                        .string()
+                        .ok()
+                        .filter(|algorithm| *algorithm == HOST_KEY.as_bytes())
+                        .and_then(|_| signature.string().ok())
+                        .and_then(|s| <[u8; 64]>::try_from(s).ok());
+                    let data = Writer::default()
+                        .string(session)
+                        .byte(USERAUTH_REQUEST)
+                        .string(&user)
+                        .string("ssh-connection")
+                        .string("publickey")
+                        .bool(true)
+                        .string(algorithm)
+                        .string(blob);
+                    if signature.is_some_and(|s| ed25519::verify(&key, &data.0, &s)) {
+                        tx.send(Writer::message(USERAUTH_SUCCESS)).await?;
+                        return Ok((user, key));
+                    }
+                }
+                None => {}
+            }
+        }
+        let failure = Writer::message(USERAUTH_FAILURE)
+            .string("publickey")
+            .bool(false);
+        tx.send(failure).await?;
+    }
+    tx.disconnect(14, "too many authentication failures").await;
+    Err("too many authentication failures".to_string())
+}
+
+pub async fn serve(server: SshServer, commands: &'static [&'static Command]) {
+    let listener = match TcpListener::bind(server.addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to bind SSH server on {}: {}", server.addr, e);
+            return;
+        }
+    };
+    say!(
+        "SSH server listening on {} (host key {})",
+        server.addr,
+        identity::fingerprint(&server.host_key.public)
+    );
+
+    let server = Arc::new(server);
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok((_, addr)) if acl::check(addr).is_some() => continue,
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("SSH server accept error: {}", e);
+                continue;
+            }
+        };
+        let server = server.clone();
+        tokio::spawn(async move {
+            if let Err(e) = session(server, socket, addr, commands).await {
+                eprintln!("SSH session from {} ended: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn session(
+    server: Arc<SshServer>,
+    socket: TcpStream,
+    addr: SocketAddr,
+    commands: &'static [&'static Command],
+) -> Result<(), String> {
+    let mut tracker = stats::track("ssh", addr);
+    let (rx, tx) = tokio::io::split(tracker.wrap(socket));
+    let mut rx = Receiver {
+        half: rx,
+        seq: 0,
+        key: None,
+    };
+    let mut tx = Sender {
+        half: tx,
+        seq: 0,
+        key: None,
+    };
+
+    let login = async {
+        let ours = format!("SSH-2.0-netcore_{}", env!("CARGO_PKG_VERSION"));
+        let theirs = exchange_versions(&mut rx.half, &mut tx.half, &ours).await?;
+        let session = key_exchange(
+            &mut rx,
+            &mut tx,
+            &server.host_key,
+            &theirs,
+            &ours,
+            &rand::random(),
+        )
+        .await?;
+        authenticate(&mut rx, &mut tx, &server, &session).await
+    };
+    let (user, key) = match timeout(LOGIN_TIMEOUT, login).await {
+        Ok(Ok(login)) => login,
+        Ok(Err(e)) => {
+            acl::offence(addr, "ssh");
+            tracker.error(&e);
+            return Err(e);
+        }
+        Err(_) => {
+            tx.disconnect(2, "login timed out").await;
+            return Err("login timed out".to_string());
+        }
+    };
+    say!(
+        "SSH login from {} as {} with key {}",
+        addr,
+        user,
+        identity::fingerprint(&key)
+    );
+    tracker.attr("netcore.ssh.user", user.as_str());
+    tracker.attr("netcore.ssh.key", identity::fingerprint(&key));
+
+    // Packets are read on their own task, so a half-read one is never
+    // dropped while waiting on a command's output instead.
+    let (packets_tx, mut packets) = mpsc::channel(16);
+    let reader = tokio::spawn(async move {
+        loop {
+            let packet = rx
+                .read()
+                .await
+                .map(|packet| (rx.seq.wrapping_sub(1), packet));
+            let failed = packet.is_err();
+            if packets_tx.send(packet).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    let (outputs_tx, mut outputs) = mpsc::channel(16);
+    let mut shell = Shell {
+        tx,
+        channel: None,
+        commands,
+        user,
+        addr,
+        outputs: outputs_tx,
+        running: None,
+    };
+    let result = loop {
+        tokio::select! {
+            packet = packets.recv() => match packet {
+                Some(Ok((seq, packet))) => match shell.packet(seq, &packet).await {
+                    Ok(true) => {}
+                    Ok(false) => break Ok(()),
+                    Err(e) => break Err(e),
+                },
+                Some(Err(e)) if e == "client disconnected" => break Ok(()),
+                Some(Err(e)) => break Err(e),
+                None => break Ok(()),
+            },
+            Some(output) = outputs.recv() => {
+                if let Err(e) = shell.output(output).await {
+                    break Err(e);
+                }
+            }
+            _ = tracker.killed() => {
+                shell.tx.disconnect(11, "closed by control request").await;
+                say!("SSH session from {} closed by control request", addr);
+                break Ok(());
+            }
+        }
+    };
+    reader.abort();
+    if let Err(e) = &result {
+        tracker.error(e);
+    }
+    result
+}
+
+enum Output {
+    Data { stderr: bool, data: Vec<u8> },
+    Exited(i32),
+}
+
+struct Channel {
+    remote: u32,
+    window: u32,
+    max_packet: u32,
+    received: u32,
+    pty: bool,
+    // A shell rather than a single command.
+    interactive: bool,
+    line: Vec<u8>,
+    escape: bool,
+    pending: VecDeque<(bool, Vec<u8>)>,
+    exited: Option<i32>,
+}
+
+// Dropping it stops the command.
+struct Running(JoinHandle<()>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+struct Shell {
+    tx: Sender,
+    channel: Option<Channel>,
+    commands: &'static [&'static Command],
+    user: String,
+    addr: SocketAddr,
+    outputs: mpsc::Sender<Output>,
+    running: Option<Running>,
+}
+
+impl Shell {
+    // False once the session is over.
+    async fn packet(&mut self, seq: u32, packet: &[u8]) -> Result<bool, String> {
+        let mut reader = Reader(packet);
+        let kind = reader.byte()?;
+        match kind {
+            KEXINIT => {
+                self.tx.disconnect(3, "re-keying isn't supported").await;
+                return Err("client asked to re-key".to_string());
+            }
+            GLOBAL_REQUEST => {
+                reader.string()?;
+                if reader.bool()? {
+                    self.tx.send(Writer::message(REQUEST_FAILURE)).await?;
+                }
+            }
+            CHANNEL_OPEN => self.open(&mut reader).await?,
+            CHANNEL_REQUEST => self.request(&mut reader).await?,
+            CHANNEL_WINDOW_ADJUST => {
+                reader.u32()?;
+                let more = reader.u32()?;
+                if let Some(channel) = &mut self.channel {
+                    channel.window = channel.window.saturating_add(more);
+                }
+                self.flush().await?;
+            }
+            CHANNEL_DATA => {
+                reader.u32()?;
+                let data = reader.string()?;
+                self.input(data).await?;
+            }
+            CHANNEL_EXTENDED_DATA | CHANNEL_EOF => {}
+            CHANNEL_CLOSE => {
+                if let Some(channel) = self.channel.take() {
+                    self.running = None;
+                    let close = Writer::message(CHANNEL_CLOSE).u32(channel.remote);
+                    let _ = self.tx.send(close).await;
+                }
+                return Ok(false);
+            }
+            _ => {
+                let unimplemented = Writer::message(UNIMPLEMENTED).u32(seq);
+                self.tx.send(unimplemented).await?;
+            }
+        }
+        Ok(true)
+    }
+
+    async fn open(&mut self, reader: &mut Reader<'_>) -> Result<(), String> {
+        let kind = reader.string()?;
+        let remote = reader.u32()?;
+        let window = reader.u32()?;
+        let max_packet = reader.u32()?;
+        if kind != b"session" || self.channel.is_some() {
+            let failure = Writer::message(CHANNEL_OPEN_FAILURE)
+                .u32(remote)
+                .u32(1)
+                .string("only one session channel is supported")
+                .string("");
+            return self.tx.send(failure).await;
+        }
+        self.channel = Some(Channel {
+            remote,
+            window,
+            max_packet: max_packet.clamp(1024, MAX_CHUNK),
+            received: 0,
+            pty: false,
+            interactive: false,
+            line: Vec::new(),
+            escape: false,
+            pending: VecDeque::new(),
+            exited: None,
+        });
+        let confirmation = Writer::message(CHANNEL_OPEN_CONFIRMATION)
+            .u32(remote)
+            .u32(0)
+            .u32(WINDOW)
+            .u32(MAX_CHUNK);
+        self.tx.send(confirmation).await
+    }
+
+    async fn request(&mut self, reader: &mut Reader<'_>) -> Result<(), String> {
+        reader.u32()?;
+        let kind = reader.text()?;
+        let want_reply = reader.bool()?;
+        let Some(channel) = &mut self.channel else {
+            return Err("channel request without a channel".to_string());
+        };
+        let remote = channel.remote;
+        let started = channel.interactive || self.running.is_some() || channel.exited.is_some();
+        let accepted = match kind.as_str() {
+            "pty-req" => {
+                channel.pty = true;
+                true
+            }
+            "shell" if !started => {
+                channel.interactive = true;
+                true
+            }
+            "exec" if !started => true,
+            _ => false,
+        };
+        if want_reply {
+            let reply = if accepted {
+                CHANNEL_SUCCESS
+            } else {
+                CHANNEL_FAILURE
+            };
+            self.tx.send(Writer::message(reply).u32(remote)).await?;
+        }
+        if !accepted {
+            return Ok(());
+        }
+        match kind.as_str() {
+            "shell" => {
+                let greeting = format!(
+                    "netcore {} diagnostics shell; type 'help' for commands\n{}",
+                    env!("CARGO_PKG_VERSION"),
+                    PROMPT
+                );
+                self.write(false, greeting.as_bytes()).await
+            }
+            "exec" => {
+                let line = reader.text()?;
+                self.run(&line).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn input(&mut self, data: &[u8]) -> Result<(), String> {
+        let Some(channel) = &mut self.channel else {
+            return Ok(());
+        };
+        channel.received += data.len() as u32;
+        if channel.received > WINDOW / 2 {
+            let adjust = Writer::message(CHANNEL_WINDOW_ADJUST)
+                .u32(channel.remote)
+                .u32(channel.received);
+            channel.received = 0;
+            self.tx.send(adjust).await?;
+        }
+        if !self.channel.as_ref().is_some_and(|c| c.interactive) {
+            return Ok(());
+        }
+
+        for &byte in data {
+            let channel = self.channel.as_mut().unwrap();
+            if self.running.is_some() {
+                // Ctrl-C stops the command; anything else waits for it.
+                if byte == 0x03 {
+                    self.running = None;
+                    self.write(false, format!("^C\n{}", PROMPT).as_bytes())
+                        .await?;
+                }
+                continue;
+            }
+            if channel.escape {
+                // Arrow keys and the like: ESC [ ... final byte.
+                channel.escape = !(byte.is_ascii_alphabetic() || byte == b'~');
+                continue;
+            }
+            match byte {
+                0x1b => channel.escape = true,
+                b'\r' | b'\n' if byte == b'\r' || !channel.pty => {
+                    let line = String::from_utf8_lossy(&channel.line).into_owned();
+                    channel.line.clear();
+                    if channel.pty {
+                        self.write(false, b"\n").await?;
+                    }
+                    if !self.execute(line.trim()).await? {
+                        return self.finish(0).await;
+                    }
+                }
+                0x7f | 0x08 => {
+                    let erased = channel.line.pop().is_some();
+                    if erased && channel.pty {
+                        self.write(false, b"\x08 \x08").await?;
+                    }
+                }
+                0x03 => {
+                    channel.line.clear();
+                    self.write(false, format!("^C\n{}", PROMPT).as_bytes())
+                        .await?;
+                }
+                0x04 if channel.line.is_empty() => return self.finish(0).await,
+                byte if byte >= 0x20 => {
+                    channel.line.push(byte);
+                    if channel.pty {
+                        self.write(false, &[byte]).await?;
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    // A line typed at the shell; false on exit.
+    async fn execute(&mut self, line: &str) -> Result<bool, String> {
+        match line {
+            "" => self.write(false, PROMPT.as_bytes()).await?,
+            "exit" | "quit" | "logout" => return Ok(false),
+            "help" => {
+                let mut help = String::from("Commands (add --help for their options):\n");
+                for command in self.commands {
+                    help.push_str(&format!("  {:<14} {}\n", command.name, command.about));
+                }
+                help.push_str(&format!("  {:<14} End the session\n{}", "exit", PROMPT));
+                self.write(false, help.as_bytes()).await?;
+            }
+            line => self.run(line).await?,
+        }
+        Ok(true)
+    }
+
+    async fn run(&mut self, line: &str) -> Result<(), String> {
+        let words = match restrict(line, self.commands) {
+            Ok(words) => words,
+            Err(e) => {
+                self.write(true, format!("{}\n", e).as_bytes()).await?;
+                return self.output(Output::Exited(exit::USAGE)).await;
+            }
+        };
+        say!("SSH {}@{} ran: {}", self.user, self.addr, words.join(" "));
+        let exe = std::env::current_exe().map_err(|e| e.to_string())?;
+        let mut child = Process::new(exe)
+            .args(&words)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(|e| e.to_string())?;
+        let outputs = self.outputs.clone();
+        let task = tokio::spawn(async move {
+            let stdout = pipe(child.stdout.take(), false, outputs.clone());
+            let stderr = pipe(child.stderr.take(), true, outputs.clone());
+            tokio::join!(stdout, stderr);
+            let code = match child.wait().await {
+                Ok(status) => status.code().unwrap_or(128),
+                Err(_) => 1,
+            };
+            let _ = outputs.send(Output::Exited(code)).await;
+        });
+        self.running = Some(Running(task));
+        Ok(())
+    }
+
+    async fn output(&mut self, output: Output) -> Result<(), String> {
+        match output {
+            Output::Data { stderr, data } => self.write(stderr, &data).await,
+            Output::Exited(code) => {
+                self.running = None;
+                let Some(channel) = &mut self.channel else {
+                    return Ok(());
+                };
+                if channel.interactive {
+                    self.write(false, PROMPT.as_bytes()).await
+                } else {
+                    self.finish(code).await
+                }
+            }
+        }
+    }
+
+    // Queues output for the client, as the channel window allows. On a
+    // terminal, stderr is mixed in and line ends become CRLF.
+    async fn write(&mut self, stderr: bool, data: &[u8]) -> Result<(), String> {
+        let Some(channel) = &mut self.channel else {
+            return Ok(());
+        };
+        let data = if channel.pty {
+            let mut crlf = Vec::with_capacity(data.len());
+            for &byte in data {
+                if byte == b'\n' {
+                    crlf.push(b'\r');
+                }
+                crlf.push(byte);
+            }
+            crlf
+        } else {
+            data.to_vec()
+        };
+        channel.pending.push_back((stderr && !channel.pty, data));
+        self.flush().await
+    }
+
+    async fn flush(&mut self) -> Result<(), String> {
+        let Some(channel) = &mut self.channel else {
+            return Ok(());
+        };
+        while channel.window > 0 {
+            let Some((stderr, data)) = channel.pending.front_mut() else {
+                break;
+            };
+            let n = data
+                .len()
+                .min(channel.window.min(channel.max_packet) as usize);
+            let chunk: Vec<u8> = data.drain(..n).collect();
+            let message = if *stderr {
+                Writer::message(CHANNEL_EXTENDED_DATA)
+                    .u32(channel.remote)
+                    .u32(1)
+            } else {
+                Writer::message(CHANNEL_DATA).u32(channel.remote)
+            };
+            if data.is_empty() {
+                channel.pending.pop_front();
+            }
+            channel.window -= n as u32;
+            self.tx.send(message.string(chunk)).await?;
+        }
+        if channel.pending.is_empty()
+            && let Some(code) = channel.exited
+        {
+            let remote = channel.remote;
+            let status = Writer::message(CHANNEL_REQUEST)
+                .u32(remote)
+                .string("exit-status")
+                .bool(false)
+                .u32(code as u32);
+            self.tx.send(status).await?;
+            self.tx
+                .send(Writer::message(CHANNEL_EOF).u32(remote))
+                .await?;
+            self.tx
+                .send(Writer::message(CHANNEL_CLOSE).u32(remote))
+                .await?;
+            channel.exited = None;
+        }
+        Ok(())
+    }
+
+    // Ends the channel once the output still queued is out.
+    async fn finish(&mut self, code: i32) -> Result<(), String> {
+        if let Some(channel) = &mut self.channel {
+            channel.exited = Some(code);
+        }
+        self.flush().await
+    }
+}
+
+async fn pipe(pipe: Option<impl AsyncRead + Unpin>, stderr: bool, outputs: mpsc::Sender<Output>) {
+    let Some(mut pipe) = pipe else {
+        return;
+    };
+    let mut buf = vec![0; 8192];
+    while let Ok(n) = pipe.read(&mut buf).await {
+        if n == 0 {
+            break;
+        }
+        let data = buf[..n].to_vec();
+        if outputs.send(Output::Data { stderr, data }).await.is_err() {
+            break;
+        }
+    }
+}
+
+// Options every command takes at the shell: output, address selection and
+// timeouts.
+const SHELL_COMMON: &[&str] = &[
+    "--help",
+    "--quiet",
+    "--porcelain",
+    "--schema",
+    "--no-proxy",
+    "--prefer",
+    "--source-addr",
+    "--source-port",
+    "--interface",
+    "--timeout-all",
+    "--discovery-timeout",
+    "--connect-timeout",
+    "--read-timeout",
+    "--write-timeout",
+    "--dns-timeout",
+    "--handshake-timeout",
+];
+
+const SHELL_SCAN: &[&str] = &[
+    "--ports",
+    "--timeout",
+    "--concurrency",
+    "--output",
+    "--rate",
+    "--host-concurrency",
+    "--sequential",
+    "--udp",
+    "--udp-ports",
+    "--os-guess",
+    "--no-resolve",
+    "--exclude",
+    "--services",
+    "--progress",
+];
+
+// What the shell runs and the options each may be given there, on top of
+// SHELL_COMMON. Anything else is refused, so an option added to a command
+// later stays unavailable over SSH until it's listed here. Left out on
+// purpose: whatever names a file (--diff, --save, --targets-file and the
+// like), writes history, or needs the server's raw sockets (--syn).
+const SHELL: &[(&str, &[&str])] = &[
+    ("info", &["--format", "--no-cache"]),
+    ("check", &["--interfaces"]),
+    ("scan-remote", SHELL_SCAN),
+    ("scan-lan", SHELL_SCAN),
+];
+
+// The words of a command line, if it is one of `commands` and every option
+// it gives is allowed at the shell.
+fn restrict(line: &str, commands: &[&Command]) -> Result<Vec<String>, String> {
+    let words: Vec<String> = line.split_whitespace().map(str::to_string).collect();
+    let Some(name) = words.first() else {
+        return Err("no command".to_string());
+    };
+    let unavailable = || format!("{}: not available over SSH; try 'help'", name);
+    let command = commands
+        .iter()
+        .find(|command| command.name == name)
+        .ok_or_else(unavailable)?;
+    let (_, allowed) = SHELL
+        .iter()
+        .find(|(shell, _)| shell == name)
+        .ok_or_else(unavailable)?;
+    let mut groups = vec![cli::HELP, output::OPTS, proxy::OPTS];
+    groups.extend_from_slice(command.groups);
+    let args = Args::parse(words[1..].to_vec(), &groups)?;
+    if let Some(opt) = command.options().find(|opt| {
+        let given = args.flag(opt.name) || args.value(opt.name).is_some();
+        given && !SHELL_COMMON.contains(&opt.name) && !allowed.contains(&opt.name)
+    }) {
+        return Err(format!("{} can't be used over SSH", opt.name));
+    }
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex;
+    use crate::scan;
+
+    // Listed for the shell but missing from SHELL.
+    const UNLISTED: Command = Command {
+        name: "scan-test",
+        usage: "netcore scan-test <target>",
+        about: "A scan",
+        groups: &[&[Opt {
+            name: "--ports",
+            value: Some("<list>"),
+            help: "",
+        }]],
+    };
+
+    #[test]
+    fn restricts_commands_and_keys() {
+        let commands: &[&Command] = &[&scan::REMOTE_COMMAND, &UNLISTED];
+        assert_eq!(
+            restrict("scan-remote  10.0.0.1 --ports 22 --quiet", commands).unwrap(),
+            ["scan-remote", "10.0.0.1", "--ports", "22", "--quiet"]
+        );
+        // --diff reads a report from any path it's given.
+        assert_eq!(
+            restrict("scan-remote 10.0.0.1 --diff /etc/shadow", commands).unwrap_err(),
+            "--diff can't be used over SSH"
+        );
+        assert!(restrict("scan-remote 10.0.0.1 --save /etc/passwd", commands).is_err());
+        assert!(restrict("scan-remote --targets-file=/etc/hosts", commands).is_err());
+        assert!(restrict("scan-remote 10.0.0.1 --syn", commands).is_err());
+        assert!(restrict("scan-test 10.0.0.1 --ports 22", commands).is_err());
+        assert!(restrict("share /etc", commands).is_err());
+        assert!(restrict("scan-remote --bogus", commands).is_err());
+
+        let public = Keypair::from_seed(&[7; 32]).public;
+        let line = identity::public_line(&public, "admin@laptop");
+        let text = format!(
+            "# comment\n{}\nfrom=\"10.0.0.1\" {}\nssh-rsa AAAA other\n",
+            line, line
+        );
+        assert_eq!(authorized_keys(&text), vec![public]);
+    }
+
+    fn ends(socket: TcpStream) -> (Receiver, Sender) {
+        let peer = socket.peer_addr().unwrap();
+        let (rx, tx) = tokio::io::split(stats::track("ssh", peer).wrap(socket));
+        let rx = Receiver {
+            half: rx,
+            seq: 0,
+            key: None,
+        };
+        let tx = Sender {
+            half: tx,
+            seq: 0,
+            key: None,
+        };
+        (rx, tx)
+    }
+
+    // The server's ends of a connection, and the client's socket.
+    async fn connected() -> ((Receiver, Sender), TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (ends(server), client)
+    }
+
+    fn bytes(text: &str) -> [u8; 32] {
+        hex::decode(text).unwrap().try_into().unwrap()
+    }
+
+    // The ephemeral keys are RFC 7748 6.1's, so both sides must arrive at
+    // its shared secret.
+    #[tokio::test]
+    async fn exchanges_keys_and_switches_to_encryption() {
+        let alice = bytes("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob = bytes("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        let ((mut rx, mut tx), client) = connected().await;
+        let (mut client_rx, mut client_tx) = ends(client);
+        let host_key = Keypair::from_seed(&[3; 32]);
+        let host_public = host_key.public;
+        let server = tokio::spawn(async move {
+            let session = key_exchange(
+                &mut rx,
+                &mut tx,
+                &host_key,
+                "SSH-2.0-test",
+                "SSH-2.0-us",
+                &alice,
+            )
+            .await?;
+            let request = rx.read().await?;
+            tx.send(Writer::message(SERVICE_ACCEPT).string("ssh-userauth"))
+                .await?;
+            Ok::<_, String>((session, request))
+        });
+
+        let server_kexinit = client_rx.read().await.unwrap();
+        assert_eq!(server_kexinit[0], KEXINIT);
+        let client_kexinit = kexinit().0;
+        client_tx
+            .send(Writer(client_kexinit.clone()))
+            .await
+            .unwrap();
+        let client_public = x25519::public_key(&bob);
+        let init = Writer::message(KEX_ECDH_INIT).string(client_public);
+        client_tx.send(init).await.unwrap();
+
+        let reply = client_rx.read().await.unwrap();
+        let mut reply = Reader(&reply);
+        assert_eq!(reply.byte().unwrap(), KEX_ECDH_REPLY);
+        let host_blob = reply.string().unwrap();
+        assert_eq!(identity::from_blob(host_blob), Some(host_public));
+        let server_public: [u8; 32] = reply.string().unwrap().try_into().unwrap();
+        assert_eq!(
+            server_public,
+            bytes("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a")
+        );
+        let shared = x25519::x25519(&bob, &server_public);
+        assert_eq!(
+            shared,
+            bytes("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742")
+        );
+        let hash = sha256(
+            &Writer::default()
+                .string("SSH-2.0-test")
+                .string("SSH-2.0-us")
+                .string(&client_kexinit)
+                .string(&server_kexinit)
+                .string(host_blob)
+                .string(client_public)
+                .string(server_public)
+                .mpint(&shared)
+                .0,
+        );
+        let mut signature = Reader(reply.string().unwrap());
+        assert_eq!(signature.string().unwrap(), HOST_KEY.as_bytes());
+        let signature: [u8; 64] = signature.string().unwrap().try_into().unwrap();
+        assert!(ed25519::verify(&host_public, &hash, &signature));
+
+        assert_eq!(client_rx.read().await.unwrap(), [NEWKEYS]);
+        client_tx.send(Writer::message(NEWKEYS)).await.unwrap();
+        client_tx.key = Some(derive(&shared, &hash, b'C', &hash));
+        client_rx.key = Some(derive(&shared, &hash, b'D', &hash));
+        let request = Writer::message(SERVICE_REQUEST).string("ssh-userauth");
+        client_tx.send(request).await.unwrap();
+        assert_eq!(
+            client_rx.read().await.unwrap(),
+            Writer::message(SERVICE_ACCEPT).string("ssh-userauth").0
+        );
+        let (session, request) = server.await.unwrap().unwrap();
+        assert_eq!(session, hash);
+        assert_eq!(request[0], SERVICE_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_low_order_client_key() {
+        let ((mut rx, mut tx), client) = connected().await;
+        let (mut client_rx, mut client_tx) = ends(client);
+        let server = tokio::spawn(async move {
+            let host_key = Keypair::from_seed(&[3; 32]);
+            key_exchange(&mut rx, &mut tx, &host_key, "a", "b", &[1; 32]).await
+        });
+        client_rx.read().await.unwrap();
+        client_tx.send(kexinit()).await.unwrap();
+        let init = Writer::message(KEX_ECDH_INIT).string([0; 32]);
+        client_tx.send(init).await.unwrap();
+        assert_eq!(
+            server.await.unwrap().unwrap_err(),
+            "bad client key exchange value"
+        );
+    }
+
+    fn query(key: &Keypair) -> Writer {
+        Writer::message(USERAUTH_REQUEST)
+            .string("admin")
+            .string("ssh-connection")
+            .string("publickey")
+            .bool(false)
+            .string(HOST_KEY)
+            .string(identity::blob(&key.public))
+    }
+
+    // A login with `key`, signed over `session`.
+    fn login(key: &Keypair, session: &[u8; 32], tamper: bool) -> Writer {
+        let blob = identity::blob(&key.public);
+        let request = |writer: Writer| {
+            writer
+                .string("admin")
+                .string("ssh-connection")
+                .string("publickey")
+                .bool(true)
+                .string(HOST_KEY)
+                .string(&blob)
+        };
+        let data = request(Writer::default().string(session).byte(USERAUTH_REQUEST));
+        let mut signature = key.sign(&data.0);
+        if tamper {
+            signature[0] ^= 1;
+        }
+        let signature = Writer::default().string(HOST_KEY).string(signature);
+        request(Writer::message(USERAUTH_REQUEST)).string(signature.0)
+    }
+
+    #[tokio::test]
+    async fn logs_in_only_authorized_keys_with_good_signatures() {
+        let ((mut rx, mut tx), client) = connected().await;
+        let (mut client_rx, mut client_tx) = ends(client);
+        let user = Keypair::from_seed(&[5; 32]);
+        let stranger = Keypair::from_seed(&[6; 32]);
+        let session = [9; 32];
+        let server = SshServer {
+            addr: ([127, 0, 0, 1], 0).into(),
+            host_key: Keypair::from_seed(&[3; 32]),
+            authorized: vec![user.public],
+        };
+        let server =
+            tokio::spawn(async move { authenticate(&mut rx, &mut tx, &server, &session).await });
+
+        let request = Writer::message(SERVICE_REQUEST).string("ssh-userauth");
+        client_tx.send(request).await.unwrap();
+        assert_eq!(client_rx.read().await.unwrap()[0], SERVICE_ACCEPT);
+
+        let refused = [
+            query(&stranger),
+            login(&stranger, &session, false),
+            login(&user, &[8; 32], false),
+            login(&user, &session, true),
+        ];
+        for attempt in refused {
+            client_tx.send(attempt).await.unwrap();
+            assert_eq!(client_rx.read().await.unwrap()[0], USERAUTH_FAILURE);
+        }
+        client_tx.send(query(&user)).await.unwrap();
+        assert_eq!(client_rx.read().await.unwrap()[0], USERAUTH_PK_OK);
+        client_tx.send(login(&user, &session, false)).await.unwrap();
+        assert_eq!(client_rx.read().await.unwrap(), [USERAUTH_SUCCESS]);
+        assert_eq!(
+            server.await.unwrap().unwrap(),
+            ("admin".to_string(), user.public)
+        );
+    }
+
+    // What the server reads from a client that sends `bytes` and closes.
+    async fn receive(key: Option<[u8; SSH_KEY_LEN]>, bytes: &[u8]) -> Result<Vec<u8>, String> {
+        let ((mut rx, _tx), mut client) = connected().await;
+        rx.key = key;
+        client.write_all(bytes).await.unwrap();
+        drop(client);
+        rx.read().await
+    }
+
+    fn packet(length: u32, padding: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = length.to_be_bytes().to_vec();
+        packet.push(padding);
+        packet.extend_from_slice(payload);
+        packet.extend(std::iter::repeat_n(0, padding as usize));
+        packet
+    }
+
+    #[tokio::test]
+    async fn refuses_bad_lengths_and_forged_packets() {
+        let request = [SERVICE_REQUEST, 0, 0, 0, 0];
+        let good = packet(10, 4, &request);
+        assert_eq!(receive(None, &good).await.unwrap(), request);
+        let too_long = MAX_PACKET as u32 + 1;
+        assert_eq!(
+            receive(None, &too_long.to_be_bytes()).await.unwrap_err(),
+            format!("bad packet length {}", too_long)
+        );
+        assert_eq!(
+            receive(None, &packet(4, 3, &[])).await.unwrap_err(),
+            "bad packet length 4"
+        );
+        // Cut off before the length it gave.
+        assert!(receive(None, &good[..8]).await.is_err());
+        // More padding than packet.
+        assert_eq!(
+            receive(None, &packet(10, 12, &request)[..14])
+                .await
+                .unwrap_err(),
+            "malformed packet"
+        );
+
+        let key = [4; SSH_KEY_LEN];
+        let sealed = aead::ssh_seal(&key, 0, &good);
+        assert_eq!(receive(Some(key), &sealed).await.unwrap(), request);
+        let oversized = aead::ssh_seal(&key, 0, &packet(too_long, 4, &request));
+        assert_eq!(
+            receive(Some(key), &oversized).await.unwrap_err(),
+            format!("bad packet length {}", too_long)
+        );
+        for byte in [5, sealed.len() - 1] {
+            let mut forged = sealed.clone();
+            forged[byte] ^= 1;
+            assert_eq!(
+                receive(Some(key), &forged).await.unwrap_err(),
+                "packet failed authentication"
+            );
+        }
+        // Replayed under the next sequence number, or sealed with another key.
+        let replayed = aead::ssh_seal(&key, 1, &good);
+        assert!(receive(Some(key), &replayed).await.is_err());
+        let other = aead::ssh_seal(&[5; SSH_KEY_LEN], 0, &good);
+        assert!(receive(Some(key), &other).await.is_err());
+    }
+}
@@ -0,0 +1,428 @@
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::Notify;
+
+use crate::events::{self, Event};
+use crate::flows;
+use crate::geoip;
+use crate::history;
+use crate::json::Value;
+use crate::otel;
+
+const RECENT_SESSIONS: usize = 50;
+
+#[derive(Default)]
+struct Counters {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+}
+
+struct Active {
+    handler: &'static str,
+    peer: SocketAddr,
+    local: Option<SocketAddr>,
+    started: Instant,
+    counters: Arc<Counters>,
+    kill: Arc<Notify>,
+}
+
+#[derive(Default)]
+struct HandlerStats {
+    connections: u64,
+    errors: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+struct Session {
+    id: u64,
+    handler: &'static str,
+    peer: SocketAddr,
+    ended: u64,
+    duration_ms: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    error: Option<String>,
+    attrs: Vec<(&'static str, Value)>,
+}
+
+#[derive(Default)]
+struct Inner {
+    active: HashMap<u64, Active>,
+    handlers: HashMap<&'static str, HandlerStats>,
+    recent: VecDeque<Session>,
+    blocked: HashMap<&'static str, u64>,
+    verified: u64,
+    verify_failed: u64,
+}
+
+struct Registry {
+    started: Instant,
+    next_id: AtomicU64,
+    inner: Mutex<Inner>,
+}
+
+static REGISTRY: LazyLock<Registry> = LazyLock::new(|| Registry {
+    started: Instant::now(),
+    next_id: AtomicU64::new(1),
+    inner: Mutex::default(),
+});
+
+pub struct Tracker {
+    id: u64,
+    peer: SocketAddr,
+    counters: Arc<Counters>,
+    kill: Arc<Notify>,
+    error: Option<String>,
+    attrs: Vec<(&'static str, Value)>,
+    span: otel::Span,
+}
+
+pub fn track(handler: &'static str, peer: SocketAddr) -> Tracker {
+    let id = REGISTRY.next_id.fetch_add(1, Ordering::Relaxed);
+    let counters = Arc::new(Counters::default());
+    let kill = Arc::new(Notify::new());
+
+    let mut inner = REGISTRY.inner.lock().unwrap();
+    inner.handlers.entry(handler).or_default().connections += 1;
+    inner.active.insert(
+        id,
+        Active {
+            handler,
+            peer,
+            local: None,
+            started: Instant::now(),
+            counters: counters.clone(),
+            kill: kill.clone(),
+        },
+    );
+    drop(inner);
+    events::emit(Event::ConnectionOpened { id, handler, peer });
+
+    let mut tracker = Tracker {
+        id,
+        peer,
+        counters,
+        kill,
+        error: None,
+        attrs: Vec::new(),
+        span: otel::span(handler, peer),
+    };
+    if let Some(geo) = geoip::lookup(peer.ip()) {
+        if let Some(country) = geo.country {
+            tracker.attr("netcore.geo.country", country);
+        }
+        if let Some(asn) = geo.asn {
+            tracker.attr("netcore.geo.asn", asn);
+        }
+        if let Some(org) = geo.org {
+            tracker.attr("netcore.geo.org", org);
+        }
+    }
+    tracker
+}
+
+impl Tracker {
+    pub async fn killed(&self) {
+        self.kill.notified().await
+    }
+
+    pub fn received(&self, n: u64) {
+        self.counters.bytes_in.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn sent(&self, n: u64) {
+        self.counters.bytes_out.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn error(&mut self, message: impl ToString) {
+        let message = message.to_string();
+        self.span.error(message.as_str());
+        self.error = Some(message);
+    }
+
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    // The address the connection came in on, which completes its flow.
+    pub fn local(&mut self, local: SocketAddr) {
+        let mut inner = REGISTRY.inner.lock().unwrap();
+        if let Some(active) = inner.active.get_mut(&self.id) {
+            active.local = Some(local);
+        }
+    }
+
+    pub fn attr(&mut self, key: &'static str, value: impl Into<Value>) {
+        let value = value.into();
+        self.span.attr(key, value.clone());
+        self.attrs.push((key, value));
+    }
+
+    pub fn wrap<S>(&self, inner: S) -> Counted<S> {
+        Counted {
+            inner,
+            counters: self.counters.clone(),
+        }
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        let bytes_in = self.counters.bytes_in.load(Ordering::Relaxed);
+        let bytes_out = self.counters.bytes_out.load(Ordering::Relaxed);
+        self.span.attr("netcore.bytes_in", bytes_in);
+        self.span.attr("netcore.bytes_out", bytes_out);
+
+        let mut inner = REGISTRY.inner.lock().unwrap();
+        let Some(active) = inner.active.remove(&self.id) else {
+            return;
+        };
+
+        let stats = inner.handlers.entry(active.handler).or_default();
+        stats.bytes_in += bytes_in;
+        stats.bytes_out += bytes_out;
+        if self.error.is_some() {
+            stats.errors += 1;
+        }
+
+        if inner.recent.len() == RECENT_SESSIONS {
+            inner.recent.pop_front();
+        }
+        let duration_ms = active.started.elapsed().as_millis() as u64;
+        let flow = flows::Key {
+            protocol: "tcp",
+            src: active.peer,
+            dst: active.local,
+        };
+        inner.recent.push_back(Session {
+            id: self.id,
+            handler: active.handler,
+            peer: active.peer,
+            ended: history::now(),
+            duration_ms,
+            bytes_in,
+            bytes_out,
+            error: self.error.clone(),
+            attrs: std::mem::take(&mut self.attrs),
+        });
+        drop(inner);
+        flows::record(flow, bytes_in + bytes_out, active.started);
+
+        events::emit(Event::ConnectionClosed {
+            id: self.id,
+            handler: active.handler,
+            peer: active.peer,
+            duration_ms,
+            bytes_in,
+            bytes_out,
+            error: self.error.take(),
+        });
+    }
+}
+
+pub fn blocked(reason: &'static str) {
+    let mut inner = REGISTRY.inner.lock().unwrap();
+    *inner.blocked.entry(reason).or_default() += 1;
+}
+
+// A tunnel stream checked against the digest its sender computed.
+pub fn integrity(ok: bool) {
+    let mut inner = REGISTRY.inner.lock().unwrap();
+    if ok {
+        inner.verified += 1;
+    } else {
+        inner.verify_failed += 1;
+    }
+}
+
+pub fn kill(id: u64) -> bool {
+    let inner = REGISTRY.inner.lock().unwrap();
+    match inner.active.get(&id) {
+        Some(active) => {
+            active.kill.notify_one();
+            true
+        }
+        None => false,
+    }
+}
+
+// Each live connection's flow, bytes so far and start.
+pub fn live_flows() -> Vec<(flows::Key, u64, Instant)> {
+    let inner = REGISTRY.inner.lock().unwrap();
+    inner
+        .active
+        .values()
+        .map(|active| {
+            let key = flows::Key {
+                protocol: "tcp",
+                src: active.peer,
+                dst: active.local,
+            };
+            let bytes = active.counters.bytes_in.load(Ordering::Relaxed)
+                + active.counters.bytes_out.load(Ordering::Relaxed);
+            (key, bytes, active.started)
+        })
+        .collect()
+}
+
+pub struct Counted<S> {
+    inner: S,
+    counters: Arc<Counters>,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let n = (buf.filled().len() - before) as u64;
+        self.counters.bytes_in.fetch_add(n, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            self.counters
+                .bytes_out
+                .fetch_add(n as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+pub fn snapshot() -> Value {
+    let inner = REGISTRY.inner.lock().unwrap();
+
+    let mut handlers: Vec<_> = inner
+        .handlers
+        .iter()
+        .map(|(name, stats)| {
+            let live = inner.active.values().filter(|a| a.handler == *name);
+            let (active, live_in, live_out) = live.fold((0u64, 0, 0), |(n, i, o), a| {
+                (
+                    n + 1,
+                    i + a.counters.bytes_in.load(Ordering::Relaxed),
+                    o + a.counters.bytes_out.load(Ordering::Relaxed),
+                )
+            });
+
+            Value::object([
+                ("name", Value::from(*name)),
+                ("active", Value::from(active)),
+                ("connections", Value::from(stats.connections)),
+                ("errors", Value::from(stats.errors)),
+                ("bytes_in", Value::from(stats.bytes_in + live_in)),
+                ("bytes_out", Value::from(stats.bytes_out + live_out)),
+            ])
+        })
+        .collect();
+    handlers.sort_by(|a, b| {
+        let name = |v: &Value| {
+            v.get("name")
+                .and_then(Value::as_str)
+                .unwrap_or_default()
+                .to_string()
+        };
+        name(a).cmp(&name(b))
+    });
+
+    let mut connections: Vec<_> = inner.active.iter().collect();
+    connections.sort_by_key(|(id, _)| **id);
+    let connections = connections
+        .into_iter()
+        .map(|(id, active)| {
+            Value::object([
+                ("id", Value::from(*id)),
+                ("handler", Value::from(active.handler)),
+                ("peer", Value::from(active.peer.to_string())),
+                (
+                    "geo",
+                    Value::from(geoip::lookup(active.peer.ip()).map(|geo| geo.to_string())),
+                ),
+                (
+                    "age_ms",
+                    Value::from(active.started.elapsed().as_millis() as u64),
+                ),
+                (
+                    "bytes_in",
+                    Value::from(active.counters.bytes_in.load(Ordering::Relaxed)),
+                ),
+                (
+                    "bytes_out",
+                    Value::from(active.counters.bytes_out.load(Ordering::Relaxed)),
+                ),
+            ])
+        })
+        .collect();
+
+    let recent = inner
+        .recent
+        .iter()
+        .rev()
+        .map(|session| {
+            Value::object([
+                ("id", Value::from(session.id)),
+                ("handler", Value::from(session.handler)),
+                ("peer", Value::from(session.peer.to_string())),
+                ("ended", Value::from(session.ended)),
+                ("duration_ms", Value::from(session.duration_ms)),
+                ("bytes_in", Value::from(session.bytes_in)),
+                ("bytes_out", Value::from(session.bytes_out)),
+                ("error", Value::from(session.error.clone())),
+                ("attrs", Value::object(session.attrs.clone())),
+            ])
+        })
+        .collect();
+
+    Value::object([
+        (
+            "uptime_secs",
+            Value::from(REGISTRY.started.elapsed().as_secs()),
+        ),
+        ("handlers", Value::Array(handlers)),
+        ("connections", Value::Array(connections)),
+        ("recent", Value::Array(recent)),
+        (
+            "blocked",
+            Value::object(
+                inner
+                    .blocked
+                    .iter()
+                    .map(|(reason, count)| (*reason, Value::from(*count))),
+            ),
+        ),
+        (
+            "integrity",
+            Value::object([
+                ("verified", Value::from(inner.verified)),
+                ("failed", Value::from(inner.verify_failed)),
+            ]),
+        ),
+    ])
+}
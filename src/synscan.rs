@@ -0,0 +1,229 @@
+use rand::Rng;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Mutex;
+use tokio::io::unix::AsyncFd;
+use tokio::time::{Duration, Instant, sleep_until, timeout};
+
+use crate::fingerprint::{self, Syn};
+use crate::gateway::checksum;
+use crate::outbound::OutboundConfig;
+use crate::scan::State;
+
+const SYN: u8 = 0x02;
+const RST: u8 = 0x04;
+const ACK: u8 = 0x10;
+// How often the receiver wakes up to check whether the scan is over.
+const POLL: Duration = Duration::from_millis(50);
+
+pub struct SynScanner {
+    socket: AsyncFd<Socket>,
+    source: Option<Ipv4Addr>,
+    port: u16,
+}
+
+pub struct Answer {
+    pub state: State,
+    pub rtt: Duration,
+    pub syn_ack: Option<Syn>,
+}
+
+#[derive(Default)]
+struct Progress {
+    sent: HashMap<(Ipv4Addr, u16), Instant>,
+    answers: HashMap<(Ipv4Addr, u16), Answer>,
+    finished: Option<Instant>,
+}
+
+// Fails with PermissionDenied without root or CAP_NET_RAW, which is how
+// callers find out they have to fall back to connect scanning.
+pub fn open(outbound: &OutboundConfig) -> io::Result<SynScanner> {
+    let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::TCP))?;
+    socket.set_nonblocking(true)?;
+    if let Some(interface) = &outbound.interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    let source = match outbound.source_addr {
+        Some(IpAddr::V4(source)) => {
+            socket.bind(&SockAddr::from(SocketAddr::new(source.into(), 0)))?;
+            Some(source)
+        }
+        _ => None,
+    };
+    Ok(SynScanner {
+        socket: AsyncFd::new(socket)?,
+        source,
+        port: outbound
+            .source_port
+            .unwrap_or_else(|| rand::thread_rng().gen_range(40_000..60_000)),
+    })
+}
+
+impl SynScanner {
+    // Sends one SYN per probe, paced by `pace`, and reads replies until `wait`
+    // after the last one went out. Probes missing from the result got no answer.
+    pub async fn scan(
+        &self,
+        probes: &[(Ipv4Addr, u16)],
+        wait: Duration,
+        pace: Option<Duration>,
+    ) -> io::Result<HashMap<(Ipv4Addr, u16), Answer>> {
+        let progress = Mutex::new(Progress::default());
+        let (sent, received) = tokio::join!(
+            self.send_all(probes, pace, &progress),
+            self.receive(wait, &progress)
+        );
+        sent?;
+        received?;
+        Ok(progress.into_inner().unwrap().answers)
+    }
+
+    async fn send_all(
+        &self,
+        probes: &[(Ipv4Addr, u16)],
+        pace: Option<Duration>,
+        progress: &Mutex<Progress>,
+    ) -> io::Result<()> {
+        let mut sources = HashMap::new();
+        let mut next = Instant::now();
+        let result = async {
+            for &(target, port) in probes {
+                if let Some(pace) = pace {
+                    sleep_until(next).await;
+                    next = next.max(Instant::now()) + pace;
+                }
+                let source = match self.source {
+                    Some(source) => source,
+                    None => match sources.get(&target) {
+                        Some(&source) => source,
+                        None => {
+                            let source = route_source(target)?;
+                            sources.insert(target, source);
+                            source
+                        }
+                    },
+                };
+                let packet = syn_packet(source, target, self.port, port, rand::random());
+                let to = SockAddr::from(SocketAddr::new(target.into(), 0));
+                loop {
+                    let mut guard = self.socket.writable().await?;
+                    if let Ok(result) =
+                        guard.try_io(|socket| socket.get_ref().send_to(&packet, &to))
+                    {
+                        result?;
+                        break;
+                    }
+                }
+                progress
+                    .lock()
+                    .unwrap()
+                    .sent
+                    .insert((target, port), Instant::now());
+            }
+            Ok(())
+        }
+        .await;
+        progress.lock().unwrap().finished = Some(Instant::now());
+        result
+    }
+
+    async fn receive(&self, wait: Duration, progress: &Mutex<Progress>) -> io::Result<()> {
+        let mut buf = [0u8; 1500];
+        loop {
+            {
+                let progress = progress.lock().unwrap();
+                if let Some(at) = progress.finished
+                    && (at.elapsed() >= wait || progress.answers.len() == progress.sent.len())
+                {
+                    return Ok(());
+                }
+            }
+            let Ok(guard) = timeout(POLL, self.socket.readable()).await else {
+                continue;
+            };
+            let mut guard = guard?;
+            let Ok(result) = guard.try_io(|socket| socket.get_ref().read(&mut buf)) else {
+                continue;
+            };
+            let packet = &buf[..result?];
+            let Some((from, port, flags)) = parse_reply(packet, self.port) else {
+                continue;
+            };
+            let state = if flags & (SYN | ACK) == SYN | ACK {
+                State::Open
+            } else if flags & RST != 0 {
+                State::Closed
+            } else {
+                continue;
+            };
+            let mut progress = progress.lock().unwrap();
+            if let Some(&sent) = progress.sent.get(&(from, port)) {
+                progress
+                    .answers
+                    .entry((from, port))
+                    .or_insert_with(|| Answer {
+                        state,
+                        rtt: sent.elapsed(),
+                        syn_ack: (state == State::Open)
+                            .then(|| fingerprint::parse_syn(packet))
+                            .flatten(),
+                    });
+            }
+        }
+    }
+}
+
+// The address the kernel will put in the IP header, needed up front for the
+// TCP checksum.
+fn route_source(target: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    let udp = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    udp.connect((target, 9))?;
+    match udp.local_addr()?.ip() {
+        IpAddr::V4(source) => Ok(source),
+        IpAddr::V6(_) => Err(io::Error::other("no IPv4 route")),
+    }
+}
+
+fn syn_packet(source: Ipv4Addr, target: Ipv4Addr, from: u16, to: u16, seq: u32) -> [u8; 40] {
+    let mut packet = [0u8; 40];
+    packet[0..2].copy_from_slice(&from.to_be_bytes());
+    packet[2..4].copy_from_slice(&to.to_be_bytes());
+    packet[4..8].copy_from_slice(&seq.to_be_bytes());
+    packet[12] = 10 << 4; // header length in words
+    packet[13] = SYN;
+    packet[14..16].copy_from_slice(&64240u16.to_be_bytes());
+    // The options of a Linux client: MSS, SACK permitted, timestamps and
+    // window scaling. Offering all of them makes the SYN-ACK show which ones
+    // the target supports and how it orders them.
+    packet[20..24].copy_from_slice(&[2, 4, 0x05, 0xb4]);
+    packet[24..26].copy_from_slice(&[4, 2]);
+    packet[26..28].copy_from_slice(&[8, 10]);
+    packet[28..32].copy_from_slice(&seq.wrapping_mul(7).to_be_bytes());
+    packet[36..40].copy_from_slice(&[1, 3, 3, 7]);
+
+    let mut pseudo = Vec::with_capacity(12 + packet.len());
+    pseudo.extend_from_slice(&source.octets());
+    pseudo.extend_from_slice(&target.octets());
+    pseudo.extend_from_slice(&[0, libc::IPPROTO_TCP as u8]);
+    pseudo.extend_from_slice(&(packet.len() as u16).to_be_bytes());
+    pseudo.extend_from_slice(&packet);
+    packet[16..18].copy_from_slice(&checksum(&pseudo).to_be_bytes());
+    packet
+}
+
+// Raw TCP sockets see every inbound segment, IP header included; keep only
+// the ones addressed to our probe port.
+pub fn parse_reply(packet: &[u8], port: u16) -> Option<(Ipv4Addr, u16, u8)> {
+    let ihl = (*packet.first()? & 0x0f) as usize * 4;
+    if packet.len() < ihl + 20 || packet[9] != libc::IPPROTO_TCP as u8 {
+        return None;
+    }
+    let tcp = &packet[ihl..];
+    if u16::from_be_bytes([tcp[2], tcp[3]]) != port {
+        return None;
+    }
+    let from = Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]);
+    Some((from, u16::from_be_bytes([tcp[0], tcp[1]]), tcp[13]))
+}
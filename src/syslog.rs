@@ -0,0 +1,492 @@
+// Shipping events to a central syslog server (--syslog): connection,
+// listener, public IP and integrity events from the bus, and alerts as
+// they fire and resolve, each as one RFC 5424 message. The event's fields
+// go in structured data so a collector can index them without parsing the
+// text. UDP sends a datagram per message; TCP frames them by octet count
+// (RFC 6587) and reconnects when the server goes away. There is no TLS
+// transport, as netcore has no TLS stack of its own.
+
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::OnceLock;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpStream, UdpSocket};
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::mpsc;
+use tokio::time::timeout;
+
+use crate::beacon;
+use crate::cli::{Args, Opt};
+use crate::events::{self, Event};
+use crate::history;
+use crate::json::Value;
+use crate::say;
+use crate::timeouts;
+
+const DEFAULT_UDP_PORT: u16 = 514;
+const DEFAULT_TCP_PORT: u16 = 601;
+// Messages waiting for a slow or unreachable server before new ones are
+// dropped.
+const QUEUE: usize = 1024;
+// The documentation enterprise number from RFC 5612; netcore has none of
+// its own.
+const SD_ID: &str = "netcore@32473";
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--syslog",
+        value: Some("<udp|tcp://host:port>"),
+        help: "Send connection events and alerts to a syslog server (RFC 5424)",
+    },
+    Opt {
+        name: "--syslog-facility",
+        value: Some("<name>"),
+        help: "Facility of the messages, such as daemon or local0 (default: daemon)",
+    },
+];
+
+const FACILITIES: &[(&str, u8)] = &[
+    ("kern", 0),
+    ("user", 1),
+    ("mail", 2),
+    ("daemon", 3),
+    ("auth", 4),
+    ("syslog", 5),
+    ("lpr", 6),
+    ("news", 7),
+    ("uucp", 8),
+    ("cron", 9),
+    ("authpriv", 10),
+    ("ftp", 11),
+    ("local0", 16),
+    ("local1", 17),
+    ("local2", 18),
+    ("local3", 19),
+    ("local4", 20),
+    ("local5", 21),
+    ("local6", 22),
+    ("local7", 23),
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+}
+
+#[derive(Debug, PartialEq)]
+enum Transport {
+    Udp,
+    Tcp,
+}
+
+struct Sink {
+    facility: u8,
+    hostname: String,
+    queue: mpsc::Sender<String>,
+}
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+fn parse_target(target: &str) -> Result<(Transport, String), String> {
+    let (transport, rest) = match target.split_once("://") {
+        Some(("udp", rest)) => (Transport::Udp, rest),
+        Some(("tcp", rest)) => (Transport::Tcp, rest),
+        Some(("tls", _)) => {
+            return Err(
+                "--syslog tls:// isn't supported, as netcore has no TLS stack; use tcp:// through a local relay such as stunnel".to_string(),
+            );
+        }
+        Some((other, _)) => return Err(format!("unknown --syslog transport '{}'", other)),
+        None => (Transport::Udp, target),
+    };
+    if rest.is_empty() {
+        return Err(format!("--syslog '{}' has no host", target));
+    }
+    let port = match transport {
+        Transport::Udp => DEFAULT_UDP_PORT,
+        Transport::Tcp => DEFAULT_TCP_PORT,
+    };
+    // A bare host, or an IPv6 address with no port, gets the default.
+    let has_port = match rest.rsplit_once(':') {
+        Some((host, port)) => {
+            port.parse::<u16>().is_ok() && (host.ends_with(']') || !host.contains(':'))
+        }
+        None => false,
+    };
+    let address = if has_port {
+        rest.to_string()
+    } else if rest.contains(':') && !rest.starts_with('[') {
+        format!("[{}]:{}", rest, port)
+    } else {
+        format!("{}:{}", rest, port)
+    };
+    Ok((transport, address))
+}
+
+// RFC 3339 in UTC, as RFC 5424 wants.
+fn timestamp(millis: u64) -> String {
+    let secs = millis / 1000;
+    let rem = secs % 86_400;
+    format!(
+        "{}T{:02}:{:02}:{:02}.{:03}Z",
+        history::format_date(secs),
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+        millis % 1000
+    )
+}
+
+// Header fields are printable ASCII without spaces, or `-` when empty.
+fn header_field(text: &str, max: usize) -> String {
+    let field: String = text
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+    if field.is_empty() {
+        "-".to_string()
+    } else {
+        field
+    }
+}
+
+fn param_value(value: &Value) -> String {
+    let text = match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    text.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace(']', "\\]")
+}
+
+// One message, `fields` as its structured data. Null fields are left out.
+pub fn format(
+    facility: u8,
+    severity: Severity,
+    millis: u64,
+    hostname: &str,
+    msgid: &str,
+    fields: &[(String, Value)],
+    text: &str,
+) -> String {
+    let params: String = fields
+        .iter()
+        .filter(|(_, value)| !matches!(value, Value::Null))
+        .map(|(name, value)| format!(" {}=\"{}\"", header_field(name, 32), param_value(value)))
+        .collect();
+    let data = if params.is_empty() {
+        "-".to_string()
+    } else {
+        format!("[{}{}]", SD_ID, params)
+    };
+    format!(
+        "<{}>1 {} {} netcore {} {} {} {}",
+        facility as u16 * 8 + severity as u16,
+        timestamp(millis),
+        header_field(hostname, 255),
+        std::process::id(),
+        header_field(msgid, 32),
+        data,
+        text
+    )
+}
+
+fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+fn send(severity: Severity, msgid: &str, fields: &[(String, Value)], text: &str) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    let message = format(
+        sink.facility,
+        severity,
+        unix_millis(),
+        &sink.hostname,
+        msgid,
+        fields,
+        text,
+    );
+    // A full queue means the server is gone or slow; losing messages beats
+    // holding up connections.
+    let _ = sink.queue.try_send(message);
+}
+
+fn severity(event: &Event) -> Severity {
+    match event {
+        Event::IntegrityFailed { .. } => Severity::Error,
+        Event::ConnectionClosed { error: Some(_), .. } | Event::AcceptFailed { .. } => {
+            Severity::Warning
+        }
+        Event::PublicIpChanged { .. } => Severity::Notice,
+        _ => Severity::Info,
+    }
+}
+
+fn describe(event: &Event) -> String {
+    match event {
+        Event::ListenerStarted { addr } => format!("Listening on {}", addr),
+        Event::AcceptFailed { addr, error } => {
+            format!("Failed to accept a connection on {}: {}", addr, error)
+        }
+        Event::ConnectionOpened { id, handler, peer } => {
+            format!("Connection {} from {} opened ({})", id, peer, handler)
+        }
+        Event::ConnectionClosed {
+            id,
+            peer,
+            duration_ms,
+            bytes_in,
+            bytes_out,
+            error,
+            ..
+        } => {
+            let mut text = format!(
+                "Connection {} from {} closed after {} ms, {} bytes in, {} bytes out",
+                id, peer, duration_ms, bytes_in, bytes_out
+            );
+            if let Some(error) = error {
+                text += &format!(": {}", error);
+            }
+            text
+        }
+        Event::PublicIpChanged {
+            old: Some(old),
+            new,
+        } => {
+            format!("Public IP changed from {} to {}", old, new)
+        }
+        Event::PublicIpChanged { old: None, new } => format!("Public IP is {}", new),
+        Event::IntegrityFailed { stream, .. } => {
+            format!("Tunnel stream {} failed its integrity check", stream)
+        }
+    }
+}
+
+fn fields(value: Value) -> Vec<(String, Value)> {
+    match value {
+        Value::Object(fields) => fields
+            .into_iter()
+            .filter(|(name, _)| name != "event")
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+pub fn alert(name: &str, message: &str) {
+    let fields = [("alert".to_string(), Value::from(name))];
+    send(
+        Severity::Warning,
+        "alert",
+        &fields,
+        &format!("Alert '{}': {}", name, message),
+    );
+}
+
+pub fn resolved(name: &str, subject: &str) {
+    let fields = [
+        ("alert".to_string(), Value::from(name)),
+        ("subject".to_string(), Value::from(subject)),
+    ];
+    send(
+        Severity::Notice,
+        "alert_resolved",
+        &fields,
+        &format!("Alert '{}' resolved for {}", name, subject),
+    );
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Tcp(Option<TcpStream>),
+}
+
+impl Connection {
+    async fn open(transport: Transport, address: &str) -> Result<Connection, String> {
+        match transport {
+            Transport::Tcp => Ok(Connection::Tcp(None)),
+            Transport::Udp => {
+                let server = tokio::net::lookup_host(address)
+                    .await
+                    .map_err(|e| format!("{}: {}", address, e))?
+                    .next()
+                    .ok_or_else(|| format!("{}: no addresses", address))?;
+                let bind: SocketAddr = match server {
+                    SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+                    SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+                };
+                let socket = UdpSocket::bind(bind)
+                    .await
+                    .map_err(|e| format!("Failed to open a socket for syslog: {}", e))?;
+                socket
+                    .connect(server)
+                    .await
+                    .map_err(|e| format!("{}: {}", address, e))?;
+                Ok(Connection::Udp(socket))
+            }
+        }
+    }
+
+    async fn send(&mut self, address: &str, message: &str) -> Result<(), String> {
+        match self {
+            Connection::Udp(socket) => socket
+                .send(message.as_bytes())
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string()),
+            Connection::Tcp(stream) => {
+                if stream.is_none() {
+                    let connected = timeout(timeouts::get().connect, TcpStream::connect(address))
+                        .await
+                        .map_err(|_| "timed out connecting".to_string())?
+                        .map_err(|e| e.to_string())?;
+                    *stream = Some(connected);
+                }
+                let framed = format!("{} {}", message.len(), message);
+                let written = stream.as_mut().unwrap().write_all(framed.as_bytes()).await;
+                if let Err(e) = written {
+                    // Reconnect for the next message.
+                    *stream = None;
+                    return Err(e.to_string());
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+pub async fn init(args: &Args) -> Result<(), String> {
+    let Some(target) = args.value("--syslog") else {
+        return Ok(());
+    };
+    let (transport, address) = parse_target(target)?;
+    let name = args.value("--syslog-facility").unwrap_or("daemon");
+    let facility = FACILITIES
+        .iter()
+        .find(|(known, _)| *known == name)
+        .map(|(_, code)| *code)
+        .ok_or_else(|| format!("unknown --syslog-facility '{}'", name))?;
+    let tcp = transport == Transport::Tcp;
+    let mut connection = Connection::open(transport, &address).await?;
+    let (queue, mut messages) = mpsc::channel::<String>(QUEUE);
+    let sink = Sink {
+        facility,
+        hostname: beacon::hostname(),
+        queue,
+    };
+    if SINK.set(sink).is_err() {
+        return Ok(());
+    }
+
+    say!(
+        "Sending events to syslog at {} over {}",
+        address,
+        if tcp { "TCP" } else { "UDP" }
+    );
+    tokio::spawn(async move {
+        // Say once when the server can't be reached, not per message.
+        let mut failing = false;
+        while let Some(message) = messages.recv().await {
+            match connection.send(&address, &message).await {
+                Ok(()) => failing = false,
+                Err(e) if !failing => {
+                    eprintln!("Failed to send to syslog at {}: {}", address, e);
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+    });
+
+    let mut events = events::subscribe();
+    tokio::spawn(async move {
+        loop {
+            match events.recv().await {
+                Ok(event) => send(
+                    severity(&event),
+                    event.name(),
+                    &fields(event.to_json()),
+                    &describe(&event),
+                ),
+                Err(RecvError::Lagged(missed)) => send(
+                    Severity::Warning,
+                    "lagged",
+                    &[("missed".to_string(), Value::from(missed))],
+                    &format!("{} events were not sent to syslog", missed),
+                ),
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_rfc5424_with_escaped_structured_data() {
+        let fields = [
+            ("peer".to_string(), Value::from("[::1]:4000")),
+            ("error".to_string(), Value::from("said \"no\\\"")),
+            ("bytes_in".to_string(), Value::from(12u64)),
+            ("old".to_string(), Value::Null),
+        ];
+        let message = format(
+            3,
+            Severity::Warning,
+            1_700_000_000_123,
+            "web 1",
+            "connection_closed",
+            &fields,
+            "Connection closed",
+        );
+        let expected = format!(
+            "<28>1 2023-11-14T22:13:20.123Z web1 netcore {} connection_closed \
+             [netcore@32473 peer=\"[::1\\]:4000\" error=\"said \\\"no\\\\\\\"\" bytes_in=\"12\"] \
+             Connection closed",
+            std::process::id()
+        );
+        assert_eq!(message, expected);
+        assert!(
+            format(16, Severity::Info, 0, "", "alert", &[], "x")
+                .starts_with("<134>1 1970-01-01T00:00:00.000Z - netcore ")
+        );
+
+        assert_eq!(
+            parse_target("tcp://logs:6514").unwrap(),
+            (Transport::Tcp, "logs:6514".to_string())
+        );
+        assert_eq!(
+            parse_target("fd00::1").unwrap(),
+            (Transport::Udp, "[fd00::1]:514".to_string())
+        );
+        assert!(parse_target("tls://logs").is_err());
+    }
+
+    #[test]
+    fn reports_failed_accepts_as_warnings() {
+        let event = Event::AcceptFailed {
+            addr: "[::]:6881".parse().unwrap(),
+            error: "Too many open files".to_string(),
+        };
+        assert_eq!(severity(&event), Severity::Warning);
+        assert_eq!(
+            describe(&event),
+            "Failed to accept a connection on [::]:6881: Too many open files"
+        );
+        assert_eq!(
+            event.to_json().to_string(),
+            r#"{"event":"accept_failed","addr":"[::]:6881","error":"Too many open files"}"#
+        );
+    }
+}
@@ -0,0 +1,276 @@
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::acl::Cidr;
+use crate::cli::{Args, Opt};
+use crate::exit;
+use crate::outbound::OutboundConfig;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--targets-file",
+        value: Some("<path>"),
+        help: "Read targets from a file, one per line, # starts a comment (repeatable)",
+    },
+    Opt {
+        name: "--exclude",
+        value: Some("<target>"),
+        help: "Skip an address, network, range or host name (repeatable)",
+    },
+    Opt {
+        name: "--exclude-file",
+        value: Some("<path>"),
+        help: "Read exclusions from a file, in the same format as --targets-file (repeatable)",
+    },
+];
+
+// Addresses are kept as integers of their family's width so networks and
+// ranges of either family expand and compare the same way.
+#[derive(Clone, Copy)]
+struct Range {
+    v4: bool,
+    first: u128,
+    last: u128,
+}
+
+impl Range {
+    fn single(ip: IpAddr) -> Range {
+        let (v4, value) = to_int(ip);
+        Range {
+            v4,
+            first: value,
+            last: value,
+        }
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (v4, value) = to_int(ip.to_canonical());
+        v4 == self.v4 && (self.first..=self.last).contains(&value)
+    }
+
+    fn addrs(&self) -> impl Iterator<Item = IpAddr> + '_ {
+        (self.first..=self.last).map(|value| from_int(self.v4, value))
+    }
+}
+
+enum Spec {
+    Range(Range),
+    Name(String),
+}
+
+fn to_int(ip: IpAddr) -> (bool, u128) {
+    match ip {
+        IpAddr::V4(ip) => (true, u32::from(ip) as u128),
+        IpAddr::V6(ip) => (false, u128::from(ip)),
+    }
+}
+
+fn from_int(v4: bool, value: u128) -> IpAddr {
+    if v4 {
+        IpAddr::V4(Ipv4Addr::from(value as u32))
+    } else {
+        IpAddr::V6(Ipv6Addr::from(value))
+    }
+}
+
+// Accepts 10.0.0.7, 10.0.0.0/24, 10.0.0.1-10.0.0.50, 10.0.0.1-50 (last
+// octet only) and host names. Scanned IPv4 networks skip their network and
+// broadcast addresses; excluded ones don't.
+fn parse(value: &str, hosts_only: bool) -> Result<Spec, String> {
+    if value.contains('/') {
+        let cidr: Cidr = value.parse()?;
+        let (mut first, mut last) = cidr.bounds();
+        let v4 = !value.contains(':');
+        if hosts_only && v4 && last - first > 1 {
+            first += 1;
+            last -= 1;
+        }
+        return Ok(Spec::Range(Range { v4, first, last }));
+    }
+    if let Some((from, to)) = value.split_once('-')
+        && let Ok(from) = from.parse::<IpAddr>()
+    {
+        let invalid = || format!("invalid range '{}'", value);
+        let to = match (from, to.parse::<IpAddr>(), to.parse::<u8>()) {
+            (_, Ok(to), _) if to.is_ipv4() == from.is_ipv4() => to,
+            (IpAddr::V4(from), _, Ok(last)) => {
+                let [a, b, c, _] = from.octets();
+                IpAddr::V4(Ipv4Addr::new(a, b, c, last))
+            }
+            _ => return Err(invalid()),
+        };
+        let (v4, first) = to_int(from);
+        let (_, last) = to_int(to);
+        if first > last {
+            return Err(invalid());
+        }
+        return Ok(Spec::Range(Range { v4, first, last }));
+    }
+    match value.parse::<IpAddr>() {
+        Ok(ip) => Ok(Spec::Range(Range::single(ip))),
+        Err(_) if !value.is_empty() && !value.contains(char::is_whitespace) => {
+            Ok(Spec::Name(value.to_string()))
+        }
+        Err(_) => Err(format!("invalid target '{}'", value)),
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<String>, String> {
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read targets file {}: {}", path, e))?;
+    Ok(text
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default())
+        .flat_map(|line| line.split([',', ' ', '\t']))
+        .filter(|target| !target.is_empty())
+        .map(str::to_string)
+        .collect())
+}
+
+async fn resolve(outbound: &OutboundConfig, name: &str) -> Result<Vec<IpAddr>, exit::Error> {
+    let addrs = outbound
+        .resolve(&format!("{}:0", name))
+        .await
+        .map_err(|e| exit::Error::io(format!("failed to resolve {}", name), e))?;
+    Ok(addrs.into_iter().map(|addr| addr.ip()).collect())
+}
+
+// Expands the given targets plus any --targets-file entries into a
+// deduplicated host list, in the order given, minus --exclude matches.
+// Host names scan their preferred address but exclude all of them.
+pub async fn expand(
+    args: &Args,
+    mut specs: Vec<String>,
+    outbound: &OutboundConfig,
+    limit: usize,
+) -> Result<Vec<(IpAddr, Option<String>)>, exit::Error> {
+    for path in args.values("--targets-file") {
+        specs.extend(read_file(path)?);
+    }
+    let mut excludes: Vec<String> = args.values("--exclude").map(str::to_string).collect();
+    for path in args.values("--exclude-file") {
+        excludes.extend(read_file(path)?);
+    }
+
+    let mut excluded = Vec::new();
+    for value in &excludes {
+        match parse(value, false)? {
+            Spec::Range(range) => excluded.push(range),
+            Spec::Name(name) => excluded.extend(
+                resolve(outbound, &name)
+                    .await?
+                    .into_iter()
+                    .map(Range::single),
+            ),
+        }
+    }
+
+    let too_many = || format!("targets expand to more than {} hosts", limit);
+    let mut seen = HashSet::new();
+    let mut targets = Vec::new();
+    for value in &specs {
+        let (range, name) = match parse(value, true)? {
+            Spec::Range(range) => (range, None),
+            Spec::Name(name) => {
+                let ip = resolve(outbound, &name).await?[0];
+                (Range::single(ip), Some(name))
+            }
+        };
+        if range.last - range.first >= limit as u128 {
+            return Err(too_many().into());
+        }
+        for ip in range.addrs() {
+            if excluded.iter().any(|range| range.contains(ip)) || !seen.insert(ip) {
+                continue;
+            }
+            if targets.len() == limit {
+                return Err(too_many().into());
+            }
+            targets.push((ip, name.clone()));
+        }
+    }
+    Ok(targets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const CASES: u64 = 500;
+
+    fn range(spec: Spec) -> Range {
+        match spec {
+            Spec::Range(range) => range,
+            Spec::Name(name) => panic!("parsed as host name {}", name),
+        }
+    }
+
+    #[test]
+    fn ipv4_networks_expand_to_their_hosts() {
+        for case in 0..CASES {
+            let mut rng = StdRng::seed_from_u64(case);
+            let prefix = rng.gen_range(20..=32);
+            let spec = format!("{}/{}", Ipv4Addr::from(rng.r#gen::<u32>()), prefix);
+            let cidr: Cidr = spec.parse().unwrap();
+
+            let hosts: Vec<IpAddr> = range(parse(&spec, true).unwrap()).addrs().collect();
+            let size = 1u64 << (32 - prefix);
+            let expected = if size > 2 { size - 2 } else { size };
+            assert_eq!(hosts.len() as u64, expected, "{}", spec);
+            assert!(hosts.iter().all(|ip| cidr.contains(*ip)), "{}", spec);
+            assert!(hosts.windows(2).all(|w| w[0] < w[1]), "{}", spec);
+            let (network, broadcast) = cidr.bounds();
+            if size > 2 {
+                assert!(!hosts.contains(&from_int(true, network)), "{}", spec);
+                assert!(!hosts.contains(&from_int(true, broadcast)), "{}", spec);
+            }
+
+            // Exclusions cover the whole network, network and broadcast too.
+            let excluded = range(parse(&spec, false).unwrap());
+            assert_eq!(excluded.last - excluded.first + 1, size as u128, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn ipv6_networks_keep_every_address() {
+        for case in 0..CASES {
+            let mut rng = StdRng::seed_from_u64(case);
+            let prefix = rng.gen_range(116..=128);
+            let spec = format!("{}/{}", Ipv6Addr::from(rng.r#gen::<u128>()), prefix);
+            let cidr: Cidr = spec.parse().unwrap();
+            let hosts: Vec<IpAddr> = range(parse(&spec, true).unwrap()).addrs().collect();
+            assert_eq!(hosts.len() as u128, 1u128 << (128 - prefix), "{}", spec);
+            assert!(hosts.iter().all(|ip| cidr.contains(*ip)), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn ranges_cover_their_ends_and_nothing_else() {
+        for case in 0..CASES {
+            let mut rng = StdRng::seed_from_u64(case);
+            let first: u32 = rng.r#gen();
+            let last = first.saturating_add(rng.gen_range(0..500));
+            let (from, to) = (Ipv4Addr::from(first), Ipv4Addr::from(last));
+            let spec = format!("{}-{}", from, to);
+            let parsed = range(parse(&spec, true).unwrap());
+            assert_eq!(parsed.addrs().next(), Some(IpAddr::V4(from)), "{}", spec);
+            assert_eq!(parsed.addrs().last(), Some(IpAddr::V4(to)), "{}", spec);
+            assert!(parsed.contains(IpAddr::V4(from)) && parsed.contains(IpAddr::V4(to)));
+            if let Some(before) = first.checked_sub(1) {
+                assert!(!parsed.contains(IpAddr::V4(before.into())), "{}", spec);
+            }
+            if let Some(after) = last.checked_add(1) {
+                assert!(!parsed.contains(IpAddr::V4(after.into())), "{}", spec);
+            }
+            if first < last {
+                assert!(
+                    parse(&format!("{}-{}", to, from), true).is_err(),
+                    "{}",
+                    spec
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+// TCP-level health of connections netcore handles (--tcp-stats): the
+// kernel's smoothed RTT, retransmissions and congestion window, read with
+// TCP_INFO as a connection ends and added to its session. Byte counts say
+// how much moved; these say whether the path was struggling to move it.
+
+#[cfg(target_os = "linux")]
+use std::os::fd::AsRawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use tokio::net::TcpStream;
+
+use crate::cli::{Args, Opt};
+use crate::say;
+use crate::stats::Tracker;
+
+pub const OPTS: &[Opt] = &[Opt {
+    name: "--tcp-stats",
+    value: None,
+    help: "Record RTT, retransmissions and congestion window of each connection as it closes",
+}];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// TCP_INFO is read in Linux's layout, so elsewhere the flag does nothing.
+pub fn init(args: &Args) {
+    let enabled = cfg!(target_os = "linux") && args.flag("--tcp-stats");
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub struct Health {
+    pub rtt_us: u32,
+    pub rttvar_us: u32,
+    // Segments retransmitted over the connection's life.
+    pub retransmits: u32,
+    // Segments in flight still thought lost.
+    pub lost: u32,
+    // In segments of snd_mss bytes.
+    pub cwnd: u32,
+    pub mss: u32,
+}
+
+impl Health {
+    pub fn summary(&self) -> String {
+        format!(
+            "rtt={:.2}ms rttvar={:.2}ms retrans={} lost={} cwnd={} mss={}",
+            self.rtt_us as f64 / 1000.0,
+            self.rttvar_us as f64 / 1000.0,
+            self.retransmits,
+            self.lost,
+            self.cwnd,
+            self.mss
+        )
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn read(socket: &impl AsRawFd) -> std::io::Result<Health> {
+    let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of_val(&info) as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            libc::TCP_INFO,
+            &mut info as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(Health {
+        rtt_us: info.tcpi_rtt,
+        rttvar_us: info.tcpi_rttvar,
+        retransmits: info.tcpi_total_retrans,
+        lost: info.tcpi_lost,
+        cwnd: info.tcpi_snd_cwnd,
+        mss: info.tcpi_snd_mss,
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn read<S>(_socket: &S) -> std::io::Result<Health> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+// Call while the socket is still open, once the handler is done with it.
+pub fn record(tracker: &mut Tracker, socket: &TcpStream) {
+    if !enabled() {
+        return;
+    }
+    let health = match read(socket) {
+        Ok(health) => health,
+        Err(e) => {
+            eprintln!("Failed to read TCP stats of {}: {}", tracker.peer(), e);
+            return;
+        }
+    };
+    say!("TCP stats of {}: {}", tracker.peer(), health.summary());
+    tracker.attr("netcore.tcp.rtt_us", health.rtt_us as u64);
+    tracker.attr("netcore.tcp.rttvar_us", health.rttvar_us as u64);
+    tracker.attr("netcore.tcp.retransmits", health.retransmits as u64);
+    tracker.attr("netcore.tcp.lost", health.lost as u64);
+    tracker.attr("netcore.tcp.cwnd", health.cwnd as u64);
+    tracker.attr("netcore.tcp.mss", health.mss as u64);
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn reads_health_of_a_live_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        client.write_all(b"ping").unwrap();
+        let mut buffer = [0; 4];
+        server.read_exact(&mut buffer).unwrap();
+
+        let health = read(&client).unwrap();
+        assert!(health.cwnd > 0);
+        assert!(health.mss > 0);
+        assert_eq!(health.retransmits, 0);
+        assert!(health.summary().starts_with("rtt="));
+    }
+}
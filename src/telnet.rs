@@ -0,0 +1,146 @@
+// Telnet line mode for the echo handler (--telnet). `telnet` opens by
+// negotiating options with IAC sequences and ends lines with CR NUL or
+// CR LF; echoed back raw, the negotiation comes out as garbage and the
+// client keeps answering itself. With it on, negotiation is stripped and
+// every option refused, lines come back as CR LF, and a literal 0xff is
+// escaped again on the way out.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cli::{Args, Opt};
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+pub const OPTS: &[Opt] = &[Opt {
+    name: "--telnet",
+    value: None,
+    help: "Strip telnet option negotiation and echo lines with CRLF, for telnet and nc -C clients",
+}];
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+// `configured` is the [echo] section's `telnet`, which --telnet can only
+// add to.
+pub fn init(args: &Args, configured: bool) {
+    ENABLED.store(args.flag("--telnet") || configured, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+#[derive(Default, Clone, Copy, PartialEq)]
+enum State {
+    #[default]
+    Data,
+    Cr,
+    Iac,
+    Option(u8),
+    Sub,
+    SubIac,
+}
+
+// Carries sequences split across reads over to the next one.
+#[derive(Default)]
+pub struct LineMode {
+    state: State,
+    // Whether the last byte written was a CR, for a LF in the next write.
+    after_cr: bool,
+}
+
+impl LineMode {
+    // The data in what the client sent, and the replies its negotiation
+    // needs.
+    pub fn input(&mut self, bytes: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let mut data = Vec::with_capacity(bytes.len());
+        let mut replies = Vec::new();
+        for &byte in bytes {
+            self.state = match (self.state, byte) {
+                (State::Cr, 0 | b'\n') => {
+                    data.push(b'\n');
+                    State::Data
+                }
+                (State::Data | State::Cr, IAC) => State::Iac,
+                (State::Data | State::Cr, b'\r') => {
+                    data.push(b'\r');
+                    State::Cr
+                }
+                (State::Data | State::Cr, byte) => {
+                    data.push(byte);
+                    State::Data
+                }
+                (State::Iac, IAC) => {
+                    data.push(IAC);
+                    State::Data
+                }
+                (State::Iac, WILL | WONT | DO | DONT) => State::Option(byte),
+                (State::Iac, SB) => State::Sub,
+                // Go ahead, are you there and the rest carry nothing to echo.
+                (State::Iac, _) => State::Data,
+                (State::Option(verb), option) => {
+                    // Refusals only: a client doesn't answer those, so
+                    // negotiation ends here.
+                    match verb {
+                        WILL => replies.extend_from_slice(&[IAC, DONT, option]),
+                        DO => replies.extend_from_slice(&[IAC, WONT, option]),
+                        _ => {}
+                    }
+                    State::Data
+                }
+                (State::Sub, IAC) => State::SubIac,
+                (State::Sub, _) => State::Sub,
+                (State::SubIac, SE) => State::Data,
+                (State::SubIac, _) => State::Sub,
+            };
+        }
+        (data, replies)
+    }
+
+    // `data` as the client expects it: bare LFs as CR LF and 0xff doubled.
+    pub fn output(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + data.len() / 16);
+        for &byte in data {
+            match byte {
+                b'\n' if !self.after_cr => out.extend_from_slice(b"\r\n"),
+                IAC => out.extend_from_slice(&[IAC, IAC]),
+                byte => out.push(byte),
+            }
+            self.after_cr = byte == b'\r';
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strips_negotiation_and_normalises_line_ends() {
+        let mut telnet = LineMode::default();
+        // What Linux telnet sends first: DO SUPPRESS-GO-AHEAD, WILL
+        // TERMINAL-TYPE and a terminal speed subnegotiation.
+        let (data, replies) =
+            telnet.input(b"\xff\xfd\x03\xff\xfb\x18\xff\xfa\x20\x00\x33\x38\xff\xf0hi\r\n");
+        assert_eq!(data, b"hi\r\n");
+        assert_eq!(replies, b"\xff\xfc\x03\xff\xfe\x18");
+
+        // A sequence split between reads, CR NUL and an escaped 0xff.
+        let (data, replies) = telnet.input(b"a\xff");
+        assert_eq!((data, replies), (b"a".to_vec(), Vec::new()));
+        let (data, replies) = telnet.input(b"\xfb\x01b\r\0c\xff\xff\r");
+        assert_eq!(data, b"b\r\nc\xff\r");
+        assert_eq!(replies, b"\xff\xfe\x01");
+        let (data, _) = telnet.input(b"\n");
+        assert_eq!(data, b"\n");
+
+        assert_eq!(telnet.output(b"one\ntwo\r"), b"one\r\ntwo\r");
+        assert_eq!(telnet.output(b"\n\xff"), b"\n\xff\xff");
+    }
+}
@@ -0,0 +1,150 @@
+use std::sync::OnceLock;
+use tokio::time::Duration;
+
+use crate::cli::{Args, Opt};
+use crate::config::Config;
+use crate::units::{self, MILLIS};
+
+const DEFAULT_MS: u64 = 2000;
+
+pub const OPTS: &[Opt] = &[
+    Opt {
+        name: "--timeout-all",
+        value: Some("<duration>"),
+        help: "Default for every timeout below (default: 2s)",
+    },
+    Opt {
+        name: "--discovery-timeout",
+        value: Some("<duration>"),
+        help: "Local and public address and gateway discovery",
+    },
+    Opt {
+        name: "--connect-timeout",
+        value: Some("<duration>"),
+        help: "Outbound TCP connects",
+    },
+    Opt {
+        name: "--read-timeout",
+        value: Some("<duration>"),
+        help: "Waiting for a reply once connected",
+    },
+    Opt {
+        name: "--write-timeout",
+        value: Some("<duration>"),
+        help: "Delivering webhooks and alert email",
+    },
+    Opt {
+        name: "--dns-timeout",
+        value: Some("<duration>"),
+        help: "Name and reverse lookups",
+    },
+    Opt {
+        name: "--handshake-timeout",
+        value: Some("<duration>"),
+        help: "TLS and relay handshakes",
+    },
+];
+
+// The config file keys, in the same order as the fields below.
+const KEYS: &[&str] = &["discovery", "connect", "read", "write", "dns", "handshake"];
+
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    pub discovery: Duration,
+    pub connect: Duration,
+    pub read: Duration,
+    pub write: Duration,
+    pub dns: Duration,
+    pub handshake: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts::all(Duration::from_millis(DEFAULT_MS))
+    }
+}
+
+impl Timeouts {
+    fn all(timeout: Duration) -> Timeouts {
+        Timeouts {
+            discovery: timeout,
+            connect: timeout,
+            read: timeout,
+            write: timeout,
+            dns: timeout,
+            handshake: timeout,
+        }
+    }
+
+    fn field(&mut self, key: &str) -> &mut Duration {
+        match key {
+            "discovery" => &mut self.discovery,
+            "connect" => &mut self.connect,
+            "read" => &mut self.read,
+            "write" => &mut self.write,
+            "dns" => &mut self.dns,
+            _ => &mut self.handshake,
+        }
+    }
+
+    // A [timeouts] section in the config file sets the baseline, with bare
+    // numbers in milliseconds and `all` as its default; options on the command line
+    // override it, and a specific option beats --timeout-all.
+    pub fn from_args(args: &Args, config: Option<&Config>) -> Result<Timeouts, String> {
+        let section = config.and_then(|config| Some((config, config.sections("timeouts").last()?)));
+
+        let mut all = Duration::from_millis(DEFAULT_MS);
+        if let Some((config, section)) = section {
+            if let Some(entry) = section
+                .entries
+                .iter()
+                .find(|entry| entry.key != "all" && !KEYS.contains(&entry.key.as_str()))
+            {
+                return Err(config.error(
+                    entry.line,
+                    &format!(
+                        "unknown timeout '{}', expected all or {}",
+                        entry.key,
+                        KEYS.join(", ")
+                    ),
+                ));
+            }
+            if let Some(timeout) = section.parsed_with(config, "all", parse)? {
+                all = timeout;
+            }
+        }
+        let cli_all = args.parsed_with("--timeout-all", parse)?;
+
+        let mut timeouts = Timeouts::all(cli_all.unwrap_or(all));
+        for key in KEYS {
+            let from_config = match section {
+                Some((config, section)) if cli_all.is_none() => {
+                    section.parsed_with(config, key, parse)?
+                }
+                _ => None,
+            };
+            let from_args = args.parsed_with(&format!("--{}-timeout", key), parse)?;
+            if let Some(timeout) = from_args.or(from_config) {
+                *timeouts.field(key) = timeout;
+            }
+        }
+        Ok(timeouts)
+    }
+}
+
+fn parse(value: &str) -> Result<Duration, String> {
+    units::duration(value, MILLIS).and_then(units::nonzero)
+}
+
+static TIMEOUTS: OnceLock<Timeouts> = OnceLock::new();
+
+pub fn init(args: &Args, config: Option<&Config>) -> Result<(), String> {
+    let _ = TIMEOUTS.set(Timeouts::from_args(args, config)?);
+    Ok(())
+}
+
+// The process-wide timeouts, or the defaults in commands that don't take
+// the options.
+pub fn get() -> &'static Timeouts {
+    TIMEOUTS.get_or_init(Timeouts::default)
+}
@@ -0,0 +1,782 @@
+use std::fmt;
+use std::io;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant, timeout, timeout_at};
+
+use crate::cli::{self, Command, Opt};
+use crate::exit;
+use crate::history;
+use crate::measure::millis;
+use crate::outbound::{self, OutboundConfig};
+use crate::output;
+use crate::say;
+use crate::timeouts;
+
+const GREASE: u16 = 0x0a0a;
+const X25519: u16 = 0x001d;
+const TLS13: u16 = 0x0304;
+const TLS12: u16 = 0x0303;
+const PADDED_HELLO_LEN: usize = 512;
+const HELLO_RETRY_RANDOM: [u8; 8] = [0xc2, 0xa2, 0x11, 0x16, 0x7a, 0xbb, 0x8c, 0x5e];
+
+const INTERCEPTING_ISSUERS: &[&str] = &[
+    "avast",
+    "bitdefender",
+    "blue coat",
+    "cisco umbrella",
+    "eset",
+    "fortinet",
+    "fortigate",
+    "kaspersky",
+    "netspark",
+    "palo alto",
+    "sophos",
+    "zscaler",
+];
+
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_STATUS_REQUEST: u16 = 5;
+const EXT_SUPPORTED_GROUPS: u16 = 10;
+const EXT_EC_POINT_FORMATS: u16 = 11;
+const EXT_SIGNATURE_ALGORITHMS: u16 = 13;
+const EXT_ALPN: u16 = 16;
+const EXT_SCT: u16 = 18;
+const EXT_PADDING: u16 = 21;
+const EXT_EXTENDED_MASTER_SECRET: u16 = 23;
+const EXT_COMPRESS_CERTIFICATE: u16 = 27;
+const EXT_RECORD_SIZE_LIMIT: u16 = 28;
+const EXT_SESSION_TICKET: u16 = 35;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+const EXT_PSK_MODES: u16 = 45;
+const EXT_KEY_SHARE: u16 = 51;
+const EXT_RENEGOTIATION_INFO: u16 = 0xff01;
+
+pub const OPTS: &[Opt] = &[Opt {
+    name: "--profile",
+    value: Some("<name>"),
+    help: "ClientHello profile: chrome, firefox, safari or netcore (repeatable, default: all)",
+}];
+
+const PROBE_OPTS: &[Opt] = &[Opt {
+    name: "--sni",
+    value: Some("<name>"),
+    help: "Server name to send (default: the target host)",
+}];
+
+pub struct Profile {
+    pub name: &'static str,
+    grease: bool,
+    ciphers: &'static [u16],
+    groups: &'static [u16],
+    signatures: &'static [u16],
+    alpn: &'static [&'static str],
+    extensions: &'static [u16],
+}
+
+pub const PROFILES: &[Profile] = &[
+    Profile {
+        name: "chrome",
+        grease: true,
+        ciphers: &[
+            0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013, 0xc014,
+            0x009c, 0x009d, 0x002f, 0x0035,
+        ],
+        groups: &[X25519, 0x0017, 0x0018],
+        signatures: &[
+            0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601,
+        ],
+        alpn: &["h2", "http/1.1"],
+        extensions: &[
+            GREASE,
+            EXT_SERVER_NAME,
+            EXT_EXTENDED_MASTER_SECRET,
+            EXT_RENEGOTIATION_INFO,
+            EXT_SUPPORTED_GROUPS,
+            EXT_EC_POINT_FORMATS,
+            EXT_SESSION_TICKET,
+            EXT_ALPN,
+            EXT_STATUS_REQUEST,
+            EXT_SIGNATURE_ALGORITHMS,
+            EXT_SCT,
+            EXT_KEY_SHARE,
+            EXT_PSK_MODES,
+            EXT_SUPPORTED_VERSIONS,
+            EXT_COMPRESS_CERTIFICATE,
+            GREASE,
+            EXT_PADDING,
+        ],
+    },
+    Profile {
+        name: "firefox",
+        grease: false,
+        ciphers: &[
+            0x1301, 0x1303, 0x1302, 0xc02b, 0xc02f, 0xcca9, 0xcca8, 0xc02c, 0xc030, 0xc00a, 0xc009,
+            0xc013, 0xc014, 0x009c, 0x009d, 0x002f, 0x0035,
+        ],
+        groups: &[X25519, 0x0017, 0x0018, 0x0019, 0x0100, 0x0101],
+        signatures: &[
+            0x0403, 0x0503, 0x0603, 0x0804, 0x0805, 0x0806, 0x0401, 0x0501, 0x0601, 0x0203, 0x0201,
+        ],
+        alpn: &["h2", "http/1.1"],
+        extensions: &[
+            EXT_SERVER_NAME,
+            EXT_EXTENDED_MASTER_SECRET,
+            EXT_RENEGOTIATION_INFO,
+            EXT_SUPPORTED_GROUPS,
+            EXT_EC_POINT_FORMATS,
+            EXT_SESSION_TICKET,
+            EXT_ALPN,
+            EXT_STATUS_REQUEST,
+            EXT_KEY_SHARE,
+            EXT_SUPPORTED_VERSIONS,
+            EXT_SIGNATURE_ALGORITHMS,
+            EXT_PSK_MODES,
+            EXT_RECORD_SIZE_LIMIT,
+            EXT_PADDING,
+        ],
+    },
+    Profile {
+        name: "safari",
+        grease: true,
+        ciphers: &[
+            0x1301, 0x1302, 0x1303, 0xc02c, 0xc02b, 0xcca9, 0xc030, 0xc02f, 0xcca8, 0xc00a, 0xc009,
+            0xc014, 0xc013, 0x009d, 0x009c, 0x0035, 0x002f, 0xc008, 0xc012, 0x000a,
+        ],
+        groups: &[X25519, 0x0017, 0x0018, 0x0019],
+        // Safari really does list rsa_pss_rsae_sha384 twice.
+        signatures: &[
+            0x0403, 0x0804, 0x0401, 0x0503, 0x0203, 0x0805, 0x0805, 0x0501, 0x0806, 0x0601, 0x0201,
+        ],
+        alpn: &["h2", "http/1.1"],
+        extensions: &[
+            GREASE,
+            EXT_SERVER_NAME,
+            EXT_EXTENDED_MASTER_SECRET,
+            EXT_RENEGOTIATION_INFO,
+            EXT_SUPPORTED_GROUPS,
+            EXT_EC_POINT_FORMATS,
+            EXT_ALPN,
+            EXT_STATUS_REQUEST,
+            EXT_SIGNATURE_ALGORITHMS,
+            EXT_SCT,
+            EXT_KEY_SHARE,
+            EXT_PSK_MODES,
+            EXT_SUPPORTED_VERSIONS,
+            EXT_COMPRESS_CERTIFICATE,
+            GREASE,
+            EXT_PADDING,
+        ],
+    },
+    Profile {
+        name: "netcore",
+        grease: false,
+        ciphers: &[0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030],
+        groups: &[X25519],
+        signatures: &[0x0403, 0x0804],
+        alpn: &[],
+        extensions: &[
+            EXT_SERVER_NAME,
+            EXT_SUPPORTED_GROUPS,
+            EXT_SIGNATURE_ALGORITHMS,
+            EXT_KEY_SHARE,
+            EXT_SUPPORTED_VERSIONS,
+        ],
+    },
+];
+
+// TLS 1.2 only, so the server sends its certificate chain in the clear.
+const CERTIFICATE_PROFILE: Profile = Profile {
+    name: "tls12",
+    grease: false,
+    ciphers: &[
+        0xc02b, 0xc02f, 0xc02c, 0xc030, 0xcca9, 0xcca8, 0xc013, 0xc014, 0x009c, 0x009d, 0x002f,
+        0x0035,
+    ],
+    groups: &[X25519, 0x0017, 0x0018],
+    signatures: &[
+        0x0403, 0x0804, 0x0401, 0x0503, 0x0805, 0x0501, 0x0806, 0x0601, 0x0201,
+    ],
+    alpn: &["http/1.1"],
+    extensions: &[
+        EXT_SERVER_NAME,
+        EXT_EXTENDED_MASTER_SECRET,
+        EXT_RENEGOTIATION_INFO,
+        EXT_SUPPORTED_GROUPS,
+        EXT_EC_POINT_FORMATS,
+        EXT_ALPN,
+        EXT_SIGNATURE_ALGORITHMS,
+    ],
+};
+
+pub fn profiles_from_args(args: &cli::Args) -> Result<Vec<&'static Profile>, String> {
+    let names: Vec<&str> = args.values("--profile").collect();
+    if names.is_empty() {
+        return Ok(PROFILES.iter().collect());
+    }
+    names
+        .into_iter()
+        .map(|name| {
+            PROFILES
+                .iter()
+                .find(|p| p.name == name)
+                .ok_or_else(|| format!("unknown TLS profile '{}'", name))
+        })
+        .collect()
+}
+
+fn push_u16(buf: &mut Vec<u8>, value: u16) {
+    buf.extend_from_slice(&value.to_be_bytes());
+}
+
+fn push_vec16(buf: &mut Vec<u8>, data: &[u8]) {
+    push_u16(buf, data.len() as u16);
+    buf.extend_from_slice(data);
+}
+
+fn list16(values: &[u16], grease: bool) -> Vec<u8> {
+    let mut list = Vec::new();
+    if grease {
+        push_u16(&mut list, GREASE);
+    }
+    for value in values {
+        push_u16(&mut list, *value);
+    }
+    list
+}
+
+fn extension_body(profile: &Profile, kind: u16, sni: &str) -> Vec<u8> {
+    let mut body = Vec::new();
+    match kind {
+        EXT_SERVER_NAME => {
+            let mut entry = vec![0];
+            push_vec16(&mut entry, sni.as_bytes());
+            push_vec16(&mut body, &entry);
+        }
+        EXT_STATUS_REQUEST => body.extend_from_slice(&[1, 0, 0, 0, 0]),
+        EXT_SUPPORTED_GROUPS => push_vec16(&mut body, &list16(profile.groups, profile.grease)),
+        EXT_EC_POINT_FORMATS => body.extend_from_slice(&[1, 0]),
+        EXT_SIGNATURE_ALGORITHMS => push_vec16(&mut body, &list16(profile.signatures, false)),
+        EXT_ALPN => {
+            let mut list = Vec::new();
+            for proto in profile.alpn {
+                list.push(proto.len() as u8);
+                list.extend_from_slice(proto.as_bytes());
+            }
+            push_vec16(&mut body, &list);
+        }
+        EXT_COMPRESS_CERTIFICATE => body.extend_from_slice(&[2, 0, 2]),
+        EXT_RECORD_SIZE_LIMIT => push_u16(&mut body, 0x4001),
+        EXT_RENEGOTIATION_INFO => body.push(0),
+        EXT_SUPPORTED_VERSIONS => {
+            let versions = list16(&[TLS13, TLS12], profile.grease);
+            body.push(versions.len() as u8);
+            body.extend_from_slice(&versions);
+        }
+        EXT_PSK_MODES => body.extend_from_slice(&[1, 1]),
+        EXT_KEY_SHARE => {
+            // The server only needs a well-formed share to answer with a
+            // ServerHello; the handshake is never completed.
+            let mut shares = Vec::new();
+            if profile.grease {
+                push_u16(&mut shares, GREASE);
+                push_vec16(&mut shares, &[0]);
+            }
+            push_u16(&mut shares, X25519);
+            push_vec16(&mut shares, &rand::random::<[u8; 32]>());
+            push_vec16(&mut body, &shares);
+        }
+        GREASE => body.push(0),
+        _ => {}
+    }
+    body
+}
+
+pub fn client_hello(profile: &Profile, sni: &str) -> Vec<u8> {
+    let mut hello = Vec::new();
+    push_u16(&mut hello, TLS12);
+    hello.extend_from_slice(&rand::random::<[u8; 32]>());
+    hello.push(32);
+    hello.extend_from_slice(&rand::random::<[u8; 32]>());
+    push_vec16(&mut hello, &list16(profile.ciphers, profile.grease));
+    hello.extend_from_slice(&[1, 0]);
+
+    let mut extensions = Vec::new();
+    for &kind in profile.extensions {
+        if kind == EXT_PADDING {
+            continue;
+        }
+        if kind == EXT_SERVER_NAME && sni.parse::<std::net::IpAddr>().is_ok() {
+            continue;
+        }
+        push_u16(&mut extensions, kind);
+        push_vec16(&mut extensions, &extension_body(profile, kind, sni));
+    }
+    if profile.extensions.contains(&EXT_PADDING) {
+        let unpadded = 4 + hello.len() + 2 + extensions.len();
+        if unpadded < PADDED_HELLO_LEN {
+            let pad = (PADDED_HELLO_LEN - unpadded).saturating_sub(4);
+            push_u16(&mut extensions, EXT_PADDING);
+            push_vec16(&mut extensions, &vec![0; pad]);
+        }
+    }
+    push_vec16(&mut hello, &extensions);
+
+    let mut handshake = vec![1];
+    handshake.extend_from_slice(&(hello.len() as u32).to_be_bytes()[1..]);
+    handshake.extend_from_slice(&hello);
+
+    let mut record = vec![0x16, 0x03, 0x01];
+    push_vec16(&mut record, &handshake);
+    record
+}
+
+pub enum Outcome {
+    ServerHello {
+        version: u16,
+        cipher: u16,
+        retry: bool,
+    },
+    Alert(u8),
+    Closed,
+    Failed(String),
+}
+
+impl Outcome {
+    pub fn negotiated(&self) -> bool {
+        matches!(self, Outcome::ServerHello { .. })
+    }
+}
+
+fn version_name(version: u16) -> String {
+    match version {
+        0x0304 => "TLS 1.3".to_string(),
+        0x0303 => "TLS 1.2".to_string(),
+        0x0302 => "TLS 1.1".to_string(),
+        0x0301 => "TLS 1.0".to_string(),
+        other => format!("0x{:04x}", other),
+    }
+}
+
+fn alert_name(code: u8) -> String {
+    match code {
+        0 => "close_notify".to_string(),
+        10 => "unexpected_message".to_string(),
+        40 => "handshake_failure".to_string(),
+        47 => "illegal_parameter".to_string(),
+        50 => "decode_error".to_string(),
+        70 => "protocol_version".to_string(),
+        71 => "insufficient_security".to_string(),
+        80 => "internal_error".to_string(),
+        86 => "inappropriate_fallback".to_string(),
+        112 => "unrecognized_name".to_string(),
+        120 => "no_application_protocol".to_string(),
+        other => format!("alert {}", other),
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Outcome::ServerHello {
+                version,
+                cipher,
+                retry,
+            } => {
+                write!(
+                    f,
+                    "ServerHello {} cipher 0x{:04x}",
+                    version_name(*version),
+                    cipher
+                )?;
+                if *retry {
+                    write!(f, " (hello retry request)")?;
+                }
+                Ok(())
+            }
+            Outcome::Alert(code) => write!(f, "alert {}", alert_name(*code)),
+            Outcome::Closed => write!(f, "connection closed without reply"),
+            Outcome::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+pub fn parse_server_hello(handshake: &[u8]) -> Option<Outcome> {
+    if handshake.first() != Some(&2) || handshake.len() < 4 + 2 + 32 + 1 {
+        return None;
+    }
+    let body = &handshake[4..];
+    let mut version = u16::from_be_bytes([body[0], body[1]]);
+    let retry = body[2 + 24..2 + 32] == HELLO_RETRY_RANDOM;
+    let session = body[34] as usize;
+    let rest = body.get(35 + session..)?;
+    let cipher = u16::from_be_bytes([*rest.first()?, *rest.get(1)?]);
+
+    let mut extensions = rest.get(5..).unwrap_or_default();
+    while extensions.len() >= 4 {
+        let kind = u16::from_be_bytes([extensions[0], extensions[1]]);
+        let len = u16::from_be_bytes([extensions[2], extensions[3]]) as usize;
+        let data = extensions.get(4..4 + len)?;
+        if kind == EXT_SUPPORTED_VERSIONS && len == 2 {
+            version = u16::from_be_bytes([data[0], data[1]]);
+        }
+        extensions = &extensions[4 + len..];
+    }
+
+    Some(Outcome::ServerHello {
+        version,
+        cipher,
+        retry,
+    })
+}
+
+async fn read_reply(stream: &mut TcpStream) -> io::Result<Outcome> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    loop {
+        if buf.len() >= 5 {
+            let len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+            if buf.len() >= 5 + len {
+                let payload = &buf[5..5 + len];
+                return Ok(match buf[0] {
+                    0x15 if len >= 2 => Outcome::Alert(payload[1]),
+                    0x16 => parse_server_hello(payload).unwrap_or_else(|| {
+                        Outcome::Failed("unexpected handshake message".to_string())
+                    }),
+                    kind => Outcome::Failed(format!("unexpected record type {}", kind)),
+                });
+            }
+        }
+
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(if buf.is_empty() {
+                Outcome::Closed
+            } else {
+                Outcome::Failed("truncated reply".to_string())
+            });
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+}
+
+pub async fn probe(
+    outbound: &OutboundConfig,
+    target: &str,
+    sni: &str,
+    profile: &Profile,
+) -> (Outcome, Duration) {
+    let start = Instant::now();
+    let deadline = start + timeouts::get().handshake;
+
+    let outcome = async {
+        let mut stream = match timeout_at(deadline, outbound.connect(target)).await {
+            Ok(Ok(stream)) => stream,
+            Ok(Err(e)) => return Outcome::Failed(format!("connect failed: {}", e)),
+            Err(_) => return Outcome::Failed("connect timed out".to_string()),
+        };
+        if let Err(e) = stream.write_all(&client_hello(profile, sni)).await {
+            return Outcome::Failed(e.to_string());
+        }
+        match timeout_at(deadline, read_reply(&mut stream)).await {
+            Ok(Ok(outcome)) => outcome,
+            Ok(Err(e)) => Outcome::Failed(e.to_string()),
+            Err(_) => Outcome::Failed("timed out waiting for reply".to_string()),
+        }
+    };
+
+    (outcome.await, start.elapsed())
+}
+
+pub const PROBE_COMMAND: Command = Command {
+    name: "tls-probe",
+    usage: "netcore tls-probe <host:port>",
+    about: "Try several TLS ClientHello profiles to see if the path filters on them",
+    groups: &[OPTS, PROBE_OPTS, outbound::OPTS, timeouts::OPTS],
+};
+
+pub async fn probe_command(tokens: Vec<String>) {
+    let args = cli::parse_command(&PROBE_COMMAND, tokens);
+    let Some(target) = args.positional().first() else {
+        eprintln!("tls-probe requires a target host:port");
+        std::process::exit(exit::USAGE);
+    };
+    let profiles = cli::or_exit(profiles_from_args(&args));
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+
+    let host = target
+        .rsplit_once(':')
+        .map_or(target.as_str(), |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let sni = args.value("--sni").unwrap_or(host);
+
+    say!("Probing {} with SNI '{}'", target, sni);
+    let mut negotiated = 0;
+    let mut dropped = 0;
+    for profile in &profiles {
+        let (outcome, elapsed) = probe(&outbound, target, sni, profile).await;
+        let status = if outcome.negotiated() { "PASS" } else { "FAIL" };
+        let detail = format!("{} ({:.2} ms)", outcome, millis(elapsed));
+        say!("{}  {:<8} {}", status, profile.name, detail);
+        output::verdict(status, profile.name, &detail);
+        match outcome {
+            Outcome::ServerHello { .. } => negotiated += 1,
+            Outcome::Alert(_) => {}
+            Outcome::Closed | Outcome::Failed(_) => dropped += 1,
+        }
+    }
+
+    say!();
+    if negotiated == profiles.len() {
+        say!("Every profile negotiated; the path does not filter on ClientHello fingerprint");
+        return;
+    }
+    if negotiated == 0 {
+        say!("No profile negotiated; this looks like a connectivity or server problem");
+    } else if dropped > 0 {
+        say!(
+            "Only {} of {} profiles negotiated and the rest were dropped; a middlebox is likely filtering on ClientHello fingerprint",
+            negotiated,
+            profiles.len()
+        );
+    } else {
+        say!(
+            "Only {} of {} profiles negotiated; the server refused the others' parameters with an alert",
+            negotiated,
+            profiles.len()
+        );
+    }
+    std::process::exit(exit::FAILURE);
+}
+
+pub struct Certificate {
+    pub subject: String,
+    pub issuer: String,
+    // The validity period, in Unix seconds.
+    pub not_before: Option<u64>,
+    pub not_after: Option<u64>,
+    // The Ed25519 key it certifies (RFC 8410), if that's what it holds.
+    pub ed25519_key: Option<[u8; 32]>,
+    // The signed part as it was encoded, and the issuer's signature over
+    // it if the issuer signed with Ed25519.
+    pub signed: Vec<u8>,
+    pub ed25519_signature: Option<Vec<u8>>,
+}
+
+// id-Ed25519, 1.3.101.112, inside its AlgorithmIdentifier.
+const ED25519_ALGORITHM: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x70];
+
+struct Der<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Der<'a> {
+    fn next(&mut self) -> Option<(u8, &'a [u8])> {
+        let tag = *self.buf.first()?;
+        let first = *self.buf.get(1)? as usize;
+        let (len, header) = if first < 0x80 {
+            (first, 2)
+        } else {
+            let octets = first & 0x7f;
+            if octets == 0 || octets > 4 {
+                return None;
+            }
+            let len = self
+                .buf
+                .get(2..2 + octets)?
+                .iter()
+                .fold(0, |len, b| len << 8 | *b as usize);
+            (len, 2 + octets)
+        };
+        let value = self.buf.get(header..header + len)?;
+        self.buf = &self.buf[header + len..];
+        Some((tag, value))
+    }
+}
+
+fn distinguished_name(name: &[u8]) -> String {
+    let mut parts = Vec::new();
+    let mut rdns = Der { buf: name };
+    while let Some((_, set)) = rdns.next() {
+        let mut attributes = Der { buf: set };
+        while let Some((_, attribute)) = attributes.next() {
+            let mut fields = Der { buf: attribute };
+            let (Some((_, oid)), Some((_, value))) = (fields.next(), fields.next()) else {
+                continue;
+            };
+            let label = match oid {
+                [0x55, 0x04, 0x03] => "CN",
+                [0x55, 0x04, 0x06] => "C",
+                [0x55, 0x04, 0x0a] => "O",
+                [0x55, 0x04, 0x0b] => "OU",
+                _ => continue,
+            };
+            parts.push(format!("{}={}", label, String::from_utf8_lossy(value)));
+        }
+    }
+    parts.join(", ")
+}
+
+// UTCTime (YYMMDDHHMMSSZ) or GeneralizedTime (YYYYMMDDHHMMSSZ), as Unix
+// seconds.
+fn asn1_time(tag: u8, value: &[u8]) -> Option<u64> {
+    let text = std::str::from_utf8(value).ok()?.strip_suffix('Z')?;
+    let (year, rest) = match tag {
+        0x17 => {
+            let year: i64 = text.get(..2)?.parse().ok()?;
+            (
+                if year < 50 { 2000 + year } else { 1900 + year },
+                &text[2..],
+            )
+        }
+        0x18 => (text.get(..4)?.parse().ok()?, &text[4..]),
+        _ => return None,
+    };
+    let fields: Vec<u32> = (0..5)
+        .map(|i| rest.get(i * 2..i * 2 + 2)?.parse().ok())
+        .collect::<Option<_>>()?;
+    let days = history::days_from_civil(year, fields[0], fields[1]);
+    let secs = days * 86_400 + (fields[2] * 3600 + fields[3] * 60 + fields[4]) as i64;
+    u64::try_from(secs).ok()
+}
+
+pub fn parse_certificate(der: &[u8]) -> Option<Certificate> {
+    let (_, certificate) = Der { buf: der }.next()?;
+    let mut parts = Der { buf: certificate };
+    let (_, tbs) = parts.next()?;
+    let signed = &certificate[..certificate.len() - parts.buf.len()];
+    let ed25519_signature = match (parts.next()?, parts.next()?) {
+        ((_, ED25519_ALGORITHM), (0x03, [0, signature @ ..])) => Some(signature.to_vec()),
+        _ => None,
+    };
+    let mut fields = Der { buf: tbs };
+
+    let mut field = fields.next()?;
+    if field.0 == 0xa0 {
+        field = fields.next()?;
+    }
+    let _serial = field;
+    fields.next()?;
+    let (_, issuer) = fields.next()?;
+    let (_, validity) = fields.next()?;
+    let (_, subject) = fields.next()?;
+    let (_, key_info) = fields.next()?;
+    let mut key_info = Der { buf: key_info };
+    let ed25519_key = match (key_info.next()?, key_info.next()?) {
+        ((_, ED25519_ALGORITHM), (0x03, [0, key @ ..])) => key.try_into().ok(),
+        _ => None,
+    };
+
+    let mut validity = Der { buf: validity };
+    let mut time = || {
+        validity
+            .next()
+            .and_then(|(tag, value)| asn1_time(tag, value))
+    };
+    Some(Certificate {
+        subject: distinguished_name(subject),
+        issuer: distinguished_name(issuer),
+        not_before: time(),
+        not_after: time(),
+        ed25519_key,
+        signed: signed.to_vec(),
+        ed25519_signature,
+    })
+}
+
+async fn read_certificate(stream: &mut TcpStream) -> Result<Certificate, String> {
+    let mut records = Vec::new();
+    let mut handshake = Vec::new();
+    let mut chunk = [0; 4096];
+
+    loop {
+        while records.len() >= 5 {
+            let len = u16::from_be_bytes([records[3], records[4]]) as usize;
+            if records.len() < 5 + len {
+                break;
+            }
+            let record: Vec<u8> = records.drain(..5 + len).collect();
+            match record[0] {
+                0x16 => handshake.extend_from_slice(&record[5..]),
+                0x15 if len >= 2 => return Err(format!("alert {}", alert_name(record[6]))),
+                kind => return Err(format!("unexpected record type {}", kind)),
+            }
+        }
+
+        while handshake.len() >= 4 {
+            let len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake.len() < 4 + len {
+                break;
+            }
+            let message: Vec<u8> = handshake.drain(..4 + len).collect();
+            match message[0] {
+                11 => {
+                    let first = message
+                        .get(7..10)
+                        .map(|len| u32::from_be_bytes([0, len[0], len[1], len[2]]) as usize);
+                    return first
+                        .and_then(|len| message.get(10..10 + len))
+                        .and_then(parse_certificate)
+                        .ok_or_else(|| "malformed certificate".to_string());
+                }
+                14 => return Err("server sent no certificate".to_string()),
+                _ => {}
+            }
+        }
+
+        let n = stream.read(&mut chunk).await.map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Err("connection closed before certificate".to_string());
+        }
+        records.extend_from_slice(&chunk[..n]);
+    }
+}
+
+pub async fn handshake_certificate(
+    stream: &mut TcpStream,
+    sni: &str,
+) -> Result<Certificate, String> {
+    stream
+        .write_all(&client_hello(&CERTIFICATE_PROFILE, sni))
+        .await
+        .map_err(|e| e.to_string())?;
+    timeout(timeouts::get().handshake, read_certificate(stream))
+        .await
+        .map_err(|_| "timed out waiting for certificate".to_string())?
+}
+
+pub async fn certificate(
+    outbound: &OutboundConfig,
+    target: &str,
+    sni: &str,
+) -> Result<Certificate, String> {
+    let mut stream = timeout(timeouts::get().connect, outbound.connect(target))
+        .await
+        .map_err(|_| "connect timed out".to_string())?
+        .map_err(|e| format!("connect failed: {}", e))?;
+    handshake_certificate(&mut stream, sni).await
+}
+
+impl Certificate {
+    pub fn warnings(&self, expect: Option<&str>) -> Vec<String> {
+        let issuer = self.issuer.to_lowercase();
+        let mut warnings = Vec::new();
+        if let Some(expect) = expect
+            && !issuer.contains(&expect.to_lowercase())
+        {
+            warnings.push(format!("issuer does not match expected '{}'", expect));
+        }
+        if let Some(product) = INTERCEPTING_ISSUERS.iter().find(|p| issuer.contains(*p)) {
+            warnings.push(format!(
+                "issuer looks like a TLS-inspecting product ({})",
+                product
+            ));
+        }
+        if self.issuer == self.subject {
+            warnings.push("certificate is self-signed".to_string());
+        }
+        warnings
+    }
+}
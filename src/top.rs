@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use tokio::time::{Duration, Instant, sleep};
+
+use crate::cli::{self, Command, Opt};
+use crate::control;
+use crate::exit;
+use crate::json::Value;
+use crate::units::{self, MILLIS};
+
+const TOP_OPTS: &[Opt] = &[Opt {
+    name: "--interval",
+    value: Some("<duration>"),
+    help: "Refresh interval (default: 1s)",
+}];
+
+const MAX_ROWS: usize = 10;
+
+struct Poll {
+    at: Instant,
+    bytes: HashMap<String, (u64, u64)>,
+}
+
+pub fn format_bytes(bytes: f64) -> String {
+    const UNITS: &[&str] = &["B", "KB", "MB", "GB", "TB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{:.0} {}", value, UNITS[unit])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn field<'a>(value: &'a Value, key: &str) -> &'a str {
+    value.get(key).and_then(Value::as_str).unwrap_or("-")
+}
+
+fn number(value: &Value, key: &str) -> u64 {
+    value.get(key).and_then(Value::as_u64).unwrap_or(0)
+}
+
+fn render(stats: &Value, talkers: &Value, previous: Option<&Poll>, now: Instant) -> String {
+    let list = |key| stats.get(key).map(Value::as_array).unwrap_or_default();
+    let elapsed = previous.map(|p| (now - p.at).as_secs_f64().max(f64::EPSILON));
+
+    let mut out = String::new();
+    out.push_str(&format!(
+        "netcore top - up {} s - {}\n\n",
+        number(stats, "uptime_secs"),
+        crate::history::format_time(crate::history::now())
+    ));
+
+    out.push_str(&format!(
+        "{:<10} {:>7} {:>8} {:>7} {:>6} {:>11} {:>11}\n",
+        "HANDLER", "ACTIVE", "TOTAL", "ERRORS", "ERR%", "IN/s", "OUT/s"
+    ));
+    for handler in list("handlers") {
+        let name = field(handler, "name");
+        let total = number(handler, "connections");
+        let errors = number(handler, "errors");
+        let (bytes_in, bytes_out) = (number(handler, "bytes_in"), number(handler, "bytes_out"));
+        let (rate_in, rate_out) = match (previous.and_then(|p| p.bytes.get(name)), elapsed) {
+            (Some((prev_in, prev_out)), Some(secs)) => (
+                format!(
+                    "{}/s",
+                    format_bytes(bytes_in.saturating_sub(*prev_in) as f64 / secs)
+                ),
+                format!(
+                    "{}/s",
+                    format_bytes(bytes_out.saturating_sub(*prev_out) as f64 / secs)
+                ),
+            ),
+            _ => ("-".to_string(), "-".to_string()),
+        };
+
+        out.push_str(&format!(
+            "{:<10} {:>7} {:>8} {:>7} {:>5.1}% {:>11} {:>11}\n",
+            name,
+            number(handler, "active"),
+            total,
+            errors,
+            errors as f64 * 100.0 / total.max(1) as f64,
+            rate_in,
+            rate_out
+        ));
+    }
+
+    let connections = list("connections");
+    out.push_str(&format!("\nActive connections ({})\n", connections.len()));
+    out.push_str(&format!(
+        "{:>6} {:<10} {:<40} {:>8} {:>10} {:>10}\n",
+        "ID", "HANDLER", "PEER", "AGE", "IN", "OUT"
+    ));
+    for conn in connections.iter().take(MAX_ROWS) {
+        out.push_str(&format!(
+            "{:>6} {:<10} {:<40} {:>7}s {:>10} {:>10}\n",
+            number(conn, "id"),
+            field(conn, "handler"),
+            field(conn, "peer"),
+            number(conn, "age_ms") / 1000,
+            format_bytes(number(conn, "bytes_in") as f64),
+            format_bytes(number(conn, "bytes_out") as f64)
+        ));
+    }
+    if connections.len() > MAX_ROWS {
+        out.push_str(&format!("  ... {} more\n", connections.len() - MAX_ROWS));
+    }
+
+    out.push_str("\nTop talkers\n");
+    out.push_str(&format!(
+        "{:<40} {:>10} {:>7}  {}\n",
+        "ADDRESS", "BYTES", "FLOWS", "GEO"
+    ));
+    for talker in talkers.as_array().iter().take(MAX_ROWS) {
+        out.push_str(&format!(
+            "{:<40} {:>10} {:>7}  {}\n",
+            field(talker, "addr"),
+            format_bytes(number(talker, "bytes") as f64),
+            number(talker, "flows"),
+            talker.get("geo").and_then(Value::as_str).unwrap_or("")
+        ));
+    }
+
+    out.push_str("\nRecent sessions\n");
+    out.push_str(&format!(
+        "{:<19} {:<10} {:<40} {:>9} {:>10} {:>10}  {}\n",
+        "ENDED", "HANDLER", "PEER", "DURATION", "IN", "OUT", "ERROR"
+    ));
+    for session in list("recent").iter().take(MAX_ROWS) {
+        out.push_str(&format!(
+            "{:<19} {:<10} {:<40} {:>7}ms {:>10} {:>10}  {}\n",
+            crate::history::format_time(number(session, "ended")),
+            field(session, "handler"),
+            field(session, "peer"),
+            number(session, "duration_ms"),
+            format_bytes(number(session, "bytes_in") as f64),
+            format_bytes(number(session, "bytes_out") as f64),
+            session.get("error").and_then(Value::as_str).unwrap_or("")
+        ));
+    }
+
+    out
+}
+
+fn draw(screen: &str) {
+    let mut stdout = io::stdout().lock();
+    let _ = write!(stdout, "\x1b[H\x1b[2J{}", screen);
+    let _ = stdout.flush();
+}
+
+pub const COMMAND: Command = Command {
+    name: "top",
+    usage: "netcore top",
+    about: "Watch a running server's traffic",
+    groups: &[TOP_OPTS, control::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let interval = cli::or_exit(args.parsed_with("--interval", |v| units::duration(v, MILLIS)))
+        .unwrap_or(Duration::from_millis(1000));
+    let path = control::path_from_args(&args);
+    let request = Value::object([("command", Value::from("stats"))]);
+    let talkers_request = Value::object([
+        ("command", Value::from("talkers")),
+        ("limit", Value::from(MAX_ROWS as u64)),
+    ]);
+
+    if let Err(e) = control::request(&path, &request).await {
+        eprintln!("Cannot attach to a running netcore: {}", e);
+        std::process::exit(exit::code(&e));
+    }
+
+    print!("\x1b[?1049h\x1b[?25l");
+    let mut previous: Option<Poll> = None;
+    let mut failure = None;
+
+    loop {
+        let now = Instant::now();
+        let polled = tokio::try_join!(
+            control::request(&path, &request),
+            control::request(&path, &talkers_request)
+        );
+        match polled {
+            Ok((stats, talkers)) => {
+                draw(&render(&stats, &talkers, previous.as_ref(), now));
+                let bytes = stats
+                    .get("handlers")
+                    .map(Value::as_array)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|h| {
+                        let totals = (number(h, "bytes_in"), number(h, "bytes_out"));
+                        (field(h, "name").to_string(), totals)
+                    })
+                    .collect();
+                previous = Some(Poll { at: now, bytes });
+            }
+            Err(e) => {
+                failure = Some(e);
+                break;
+            }
+        }
+
+        tokio::select! {
+            _ = sleep(interval) => {}
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    print!("\x1b[?25h\x1b[?1049l");
+    let _ = io::stdout().flush();
+
+    if let Some(e) = failure {
+        eprintln!("Lost connection to netcore: {}", e);
+        std::process::exit(exit::FAILURE);
+    }
+}
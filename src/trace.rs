@@ -0,0 +1,239 @@
+use hyper::header::LOCATION;
+use hyper::{Body, Method, Request, Uri};
+use tokio::time::{Instant, timeout};
+
+use crate::cli::{self, Command, Opt};
+use crate::exit;
+use crate::json::Value;
+use crate::measure::millis;
+use crate::outbound::{self, OutboundConfig};
+use crate::output;
+use crate::proxy;
+use crate::say;
+use crate::timeouts;
+use crate::tls;
+
+const MAX_REDIRECTS: u32 = 10;
+
+const PROXY_HEADERS: &[&str] = &[
+    "via",
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-host",
+    "x-forwarded-proto",
+    "x-cache",
+    "x-cache-lookup",
+    "x-squid-error",
+    "proxy-connection",
+];
+
+const TRACE_OPTS: &[Opt] = &[
+    Opt {
+        name: "--max-redirects",
+        value: Some("<n>"),
+        help: "Stop after this many redirects (default: 10)",
+    },
+    Opt {
+        name: "--expect-issuer",
+        value: Some("<text>"),
+        help: "Flag HTTPS certificates whose issuer does not contain this text",
+    },
+];
+
+fn resolve_location(base: &Uri, location: &str) -> Option<Uri> {
+    if location.contains("://") {
+        return location.parse().ok();
+    }
+    let scheme = base.scheme_str()?;
+    let authority = base.authority()?;
+    if let Some(rest) = location.strip_prefix("//") {
+        return format!("{}://{}", scheme, rest).parse().ok();
+    }
+    let path = if location.starts_with('/') {
+        location.to_string()
+    } else {
+        let dir = base.path().rsplit_once('/').map_or("", |(dir, _)| dir);
+        format!("{}/{}", dir, location)
+    };
+    format!("{}://{}{}", scheme, authority, path).parse().ok()
+}
+
+fn port(uri: &Uri) -> u16 {
+    uri.port_u16()
+        .unwrap_or(if uri.scheme_str() == Some("https") {
+            443
+        } else {
+            80
+        })
+}
+
+async fn inspect_https(uri: &Uri, outbound: &OutboundConfig, expect: Option<&str>) -> bool {
+    let Some(host) = uri.host() else {
+        return false;
+    };
+    let sni = host.trim_start_matches('[').trim_end_matches(']');
+    let target = format!("{}:{}", host, port(uri));
+
+    let certificate = match tls::certificate(outbound, &target, sni).await {
+        Ok(certificate) => certificate,
+        Err(e) => {
+            say!("  certificate: {}", e);
+            return false;
+        }
+    };
+    say!("  certificate subject: {}", certificate.subject);
+    say!("  certificate issuer:  {}", certificate.issuer);
+
+    let warnings = certificate.warnings(expect);
+    for warning in &warnings {
+        say!("  WARN {}", warning);
+    }
+    !warnings.is_empty()
+}
+
+pub const HTTP_COMMAND: Command = Command {
+    name: "trace-http",
+    usage: "netcore trace-http <url>",
+    about: "Time each phase of an HTTP request",
+    groups: &[TRACE_OPTS, outbound::OPTS, timeouts::OPTS],
+};
+
+pub async fn http_command(tokens: Vec<String>) {
+    let args = cli::parse_command(&HTTP_COMMAND, tokens);
+    let Some(url) = args.positional().first() else {
+        eprintln!("trace-http requires a URL");
+        std::process::exit(exit::USAGE);
+    };
+    let max_redirects: u32 = cli::or_exit(args.parsed("--max-redirects")).unwrap_or(MAX_REDIRECTS);
+    let expect = args.value("--expect-issuer");
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+
+    let mut uri: Uri = match url.parse() {
+        Ok(uri) => uri,
+        Err(e) => {
+            eprintln!("invalid URL '{}': {}", url, e);
+            std::process::exit(exit::USAGE);
+        }
+    };
+
+    let client = proxy::client();
+    let mut proxied = false;
+    let mut suspicious = false;
+
+    for hop in 0..=max_redirects {
+        say!("[{}] GET {}", hop, uri);
+        // Proxy headers from the proxy that was asked for say nothing new.
+        let configured = proxy::for_uri(&uri).await;
+        if let Some(via) = &configured {
+            say!(
+                "  through proxy {} ({})",
+                via,
+                proxy::settings().await.source
+            );
+        }
+
+        if uri.scheme_str() == Some("https") {
+            suspicious |= inspect_https(&uri, &outbound, expect).await;
+            say!("  cannot follow HTTPS responses, only http:// is available");
+            break;
+        }
+
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(uri.clone())
+            .header("user-agent", concat!("netcore/", env!("CARGO_PKG_VERSION")))
+            .body(Body::empty());
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                say!("  invalid request: {}", e);
+                break;
+            }
+        };
+
+        let start = Instant::now();
+        let response = match timeout(timeouts::get().read * 5, client.request(request)).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                say!("  failed: {}", e);
+                output::verdict("FAIL", &uri.to_string(), &e.to_string());
+                std::process::exit(exit::code(&e));
+            }
+            Err(_) => {
+                say!("  timed out");
+                output::verdict("FAIL", &uri.to_string(), "timed out");
+                std::process::exit(exit::FAILURE);
+            }
+        };
+        let elapsed = start.elapsed();
+
+        say!(
+            "  {} {:?} in {:.2} ms",
+            response.status(),
+            response.version(),
+            millis(elapsed)
+        );
+        let headers = response.headers().iter().map(|(name, value)| {
+            let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+            (name.to_string(), Value::from(value))
+        });
+        output::emit(Value::object([
+            ("hop", Value::from(hop as u64)),
+            ("url", Value::from(uri.to_string())),
+            ("status", Value::from(response.status().as_u16() as u64)),
+            ("ms", Value::from(millis(elapsed))),
+            ("headers", Value::Object(headers.collect())),
+        ]));
+        for (name, value) in response.headers() {
+            let marker = if PROXY_HEADERS.contains(&name.as_str()) {
+                proxied |= configured.is_none();
+                "  <- proxy"
+            } else {
+                ""
+            };
+            say!(
+                "  {}: {}{}",
+                name,
+                String::from_utf8_lossy(value.as_bytes()),
+                marker
+            );
+        }
+
+        if !response.status().is_redirection() {
+            break;
+        }
+        let Some(next) = response
+            .headers()
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| resolve_location(&uri, location))
+        else {
+            say!("  redirect without a usable Location header");
+            break;
+        };
+        if hop == max_redirects {
+            say!("  stopping after {} redirects", max_redirects);
+        }
+        uri = next;
+    }
+
+    say!();
+    let mut verdicts = Vec::new();
+    if proxied {
+        verdicts.push((
+            "WARN",
+            "responses carry proxy headers; a proxy is on the path",
+        ));
+    }
+    if suspicious {
+        verdicts.push(("WARN", "certificate checks suggest TLS interception"));
+    }
+    if !proxied && !suspicious {
+        verdicts.push(("PASS", "no proxy or interception indicators"));
+    }
+    for (status, detail) in verdicts {
+        say!("{}  {}", status, detail);
+        output::verdict(status, url, detail);
+    }
+}
@@ -0,0 +1,101 @@
+use tokio::io::{AsyncRead, AsyncWrite};
+
+// What handlers talk over once a connection is accepted: a TCP stream, the
+// same stream wrapped for bandwidth limits or byte counting, or an
+// in-memory pair in tests.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Transport for T {}
+
+// Only the tests drive handlers without sockets until there's a library
+// target for anything else to do it from.
+#[cfg(test)]
+pub use memory::MemoryTransport;
+
+#[cfg(test)]
+mod memory {
+    use std::io;
+    use std::pin::Pin;
+    use std::task::{Context, Poll, ready};
+    use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+
+    // One end of an in-memory connection. Reads can be capped so tests see
+    // messages arrive split up, the way a slow network delivers them.
+    pub struct MemoryTransport {
+        inner: DuplexStream,
+        max_read: usize,
+    }
+
+    impl MemoryTransport {
+        pub fn pair(capacity: usize) -> (MemoryTransport, MemoryTransport) {
+            let (a, b) = tokio::io::duplex(capacity);
+            let end = |inner| MemoryTransport {
+                inner,
+                max_read: usize::MAX,
+            };
+            (end(a), end(b))
+        }
+
+        pub fn with_max_read(mut self, max_read: usize) -> MemoryTransport {
+            self.max_read = max_read.max(1);
+            self
+        }
+    }
+
+    impl AsyncRead for MemoryTransport {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let mut chunk = vec![0; buf.remaining().min(self.max_read)];
+            let mut limited = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut limited))?;
+            buf.put_slice(limited.filled());
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for MemoryTransport {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            data: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.inner).poll_write(cx, data)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    mod tests {
+        use super::*;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        #[tokio::test]
+        async fn max_read_splits_messages() {
+            let (mut a, b) = MemoryTransport::pair(64);
+            let mut b = b.with_max_read(3);
+            a.write_all(b"hello world").await.unwrap();
+            drop(a);
+
+            let mut buf = [0; 64];
+            let mut reads = Vec::new();
+            loop {
+                let n = b.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                reads.push(buf[..n].to_vec());
+            }
+            assert!(reads.iter().all(|read| read.len() <= 3));
+            assert_eq!(reads.concat(), b"hello world");
+        }
+    }
+}
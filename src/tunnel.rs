@@ -0,0 +1,956 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::io::{
+    AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf,
+};
+use tokio::sync::{Notify, Semaphore, mpsc, watch};
+use tokio::time::{Duration, Instant, sleep, timeout};
+
+use crate::blake3::Hasher;
+use crate::events::{self, Event};
+use crate::hex;
+use crate::lz4;
+use crate::otel;
+use crate::stats;
+use crate::timeouts;
+
+const FRAME_OPEN: u8 = 0;
+const FRAME_DATA: u8 = 1;
+const FRAME_WINDOW: u8 = 2;
+const FRAME_FIN: u8 = 3;
+const FRAME_RESET: u8 = 4;
+const FRAME_PING: u8 = 5;
+const FRAME_PONG: u8 = 6;
+const FRAME_FEATURES: u8 = 7;
+const FRAME_DATA_LZ4: u8 = 8;
+const FRAME_DIGEST: u8 = 9;
+
+// What a peer supports or asks for, as a bit set in the length field of
+// FRAME_OPEN and of the FRAME_FEATURES reply to it. Peers that predate
+// negotiation open streams with 0 and never reply, so nothing they don't
+// understand is ever sent to them.
+const FEATURE_LZ4: u32 = 1 << 0;
+// A BLAKE3 digest of the stream in a FRAME_DIGEST just before FRAME_FIN.
+const FEATURE_DIGEST: u32 = 1 << 1;
+// Not sent: the opener hasn't had the reply yet.
+const PENDING: u32 = 1 << 31;
+// Compressing data that doesn't shrink only costs CPU: after a frame that
+// saves less than an eighth, this many go out as they are before the next
+// sample.
+const SKIP_FRAMES: u32 = 32;
+
+const HEADER_LEN: usize = 9;
+const MAX_FRAME: usize = 16 * 1024;
+const STREAM_WINDOW: usize = 256 * 1024;
+const WRITE_QUEUE: usize = 64;
+// Frames received on a stream and not yet written out to it. The window
+// bounds their bytes; a peer sending many tiny frames fills this first, and
+// then waits like one the tunnel can't keep up with.
+const INBOUND_FRAMES: usize = 1024;
+// Streams open at once, from both sides together.
+pub const MAX_STREAMS: usize = 256;
+
+struct Frame {
+    kind: u8,
+    stream: u32,
+    len: u32,
+    payload: Vec<u8>,
+}
+
+impl Frame {
+    fn control(kind: u8, stream: u32, len: u32) -> Frame {
+        Frame {
+            kind,
+            stream,
+            len,
+            payload: Vec::new(),
+        }
+    }
+
+    fn data(stream: u32, payload: Vec<u8>) -> Frame {
+        Frame {
+            kind: FRAME_DATA,
+            stream,
+            len: payload.len() as u32,
+            payload,
+        }
+    }
+
+    fn lz4(stream: u32, payload: Vec<u8>) -> Frame {
+        Frame {
+            kind: FRAME_DATA_LZ4,
+            ..Frame::data(stream, payload)
+        }
+    }
+
+    fn digest(stream: u32, digest: [u8; 32]) -> Frame {
+        Frame {
+            kind: FRAME_DIGEST,
+            ..Frame::data(stream, digest.to_vec())
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        buf.push(self.kind);
+        buf.extend_from_slice(&self.stream.to_be_bytes());
+        buf.extend_from_slice(&self.len.to_be_bytes());
+        buf.extend_from_slice(&self.payload);
+        buf
+    }
+}
+
+struct Entry {
+    credit: Arc<Semaphore>,
+    // What the peer may still send before a FRAME_WINDOW from this side.
+    window: usize,
+    inbound: Option<mpsc::Sender<Vec<u8>>>,
+    features: watch::Sender<u32>,
+    // Everything received so far, if this side checks digests.
+    digest: Option<Hasher>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Keepalive {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+// What this side of a session asks for.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Features {
+    // Compress what this side sends where the peer can decode it.
+    pub compress: bool,
+    // Have the peer end each stream with a digest of what it sent, and check
+    // it against what arrived.
+    pub verify: bool,
+}
+
+impl Features {
+    fn bits(&self) -> u32 {
+        if self.verify {
+            FEATURE_LZ4 | FEATURE_DIGEST
+        } else {
+            FEATURE_LZ4
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IntegrityStats {
+    pub verified: u64,
+    pub failed: u64,
+}
+
+impl fmt::Display for IntegrityStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} streams verified, {} failed",
+            self.verified, self.failed
+        )
+    }
+}
+
+#[derive(Default)]
+struct Counters {
+    raw: AtomicU64,
+    wire: AtomicU64,
+    skipped: AtomicU64,
+    busy_micros: AtomicU64,
+}
+
+// Data sent on streams where the peer accepts compression, before and after.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CompressionStats {
+    pub raw: u64,
+    pub wire: u64,
+    pub skipped_frames: u64,
+    pub busy: Duration,
+}
+
+impl CompressionStats {
+    pub fn ratio(&self) -> f64 {
+        self.raw as f64 / self.wire.max(1) as f64
+    }
+
+    // Raw data compressed per second of time spent compressing.
+    pub fn mbytes_per_sec(&self) -> f64 {
+        self.raw as f64 / self.busy.as_secs_f64().max(f64::EPSILON) / 1_000_000.0
+    }
+}
+
+impl fmt::Display for CompressionStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} bytes sent as {} (ratio {:.2}, {:.0} MB/s, {} incompressible frames skipped)",
+            self.raw,
+            self.wire,
+            self.ratio(),
+            self.mbytes_per_sec(),
+            self.skipped_frames
+        )
+    }
+}
+
+struct Shared {
+    streams: Mutex<HashMap<u32, Entry>>,
+    frames: mpsc::Sender<Frame>,
+    started: Instant,
+    last_seen: AtomicU64,
+    closed: AtomicBool,
+    dead: Notify,
+    // The reading side is done, so the writing side closes too.
+    ended: Notify,
+    features: Features,
+    compression: Counters,
+    verified: AtomicU64,
+    failed: AtomicU64,
+}
+
+impl Shared {
+    fn new(frames: mpsc::Sender<Frame>, features: Features) -> Arc<Shared> {
+        Arc::new(Shared {
+            streams: Mutex::new(HashMap::new()),
+            frames,
+            started: Instant::now(),
+            last_seen: AtomicU64::new(0),
+            closed: AtomicBool::new(false),
+            dead: Notify::new(),
+            ended: Notify::new(),
+            features,
+            compression: Counters::default(),
+            verified: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+        })
+    }
+
+    fn touch(&self) {
+        let now = self.started.elapsed().as_millis() as u64;
+        self.last_seen.store(now, Ordering::Relaxed);
+    }
+
+    fn silence(&self) -> Duration {
+        let now = self.started.elapsed().as_millis() as u64;
+        Duration::from_millis(now.saturating_sub(self.last_seen.load(Ordering::Relaxed)))
+    }
+}
+
+struct Guard {
+    shared: Arc<Shared>,
+    id: u32,
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        self.shared.streams.lock().unwrap().remove(&self.id);
+    }
+}
+
+pub struct Mux {
+    shared: Arc<Shared>,
+    next_id: AtomicU32,
+}
+
+pub struct Incoming {
+    streams: mpsc::UnboundedReceiver<DuplexStream>,
+}
+
+impl Incoming {
+    pub async fn accept(&mut self) -> Option<DuplexStream> {
+        self.streams.recv().await
+    }
+}
+
+// Whatever `features` asks for, this side decodes compressed data and sends
+// digests when the peer asks for those.
+pub fn session<S>(
+    io: S,
+    initiator: bool,
+    keepalive: Keepalive,
+    features: Features,
+) -> (Mux, Incoming)
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (frames, queue) = mpsc::channel(WRITE_QUEUE);
+    let shared = Shared::new(frames, features);
+    let (accepted, streams) = mpsc::unbounded_channel();
+    let (reader, writer) = tokio::io::split(io);
+
+    tokio::spawn(write_frames(writer, queue, shared.clone()));
+    tokio::spawn(read_frames(reader, shared.clone(), accepted));
+    tokio::spawn(keep_alive(Arc::downgrade(&shared), keepalive));
+
+    let mux = Mux {
+        shared,
+        next_id: AtomicU32::new(if initiator { 1 } else { 2 }),
+    };
+    (mux, Incoming { streams })
+}
+
+impl Mux {
+    pub async fn open(&self) -> io::Result<DuplexStream> {
+        if self.shared.streams.lock().unwrap().len() >= MAX_STREAMS {
+            return Err(io::Error::other(format!(
+                "already {} streams open on the tunnel",
+                MAX_STREAMS
+            )));
+        }
+        let id = self.next_id.fetch_add(2, Ordering::Relaxed);
+        self.shared
+            .frames
+            .send(Frame::control(FRAME_OPEN, id, self.shared.features.bits()))
+            .await
+            .map_err(|_| closed())?;
+        Ok(start_stream(&self.shared, id, PENDING))
+    }
+
+    pub fn integrity(&self) -> IntegrityStats {
+        IntegrityStats {
+            verified: self.shared.verified.load(Ordering::Relaxed),
+            failed: self.shared.failed.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn compression(&self) -> CompressionStats {
+        let counters = &self.shared.compression;
+        CompressionStats {
+            raw: counters.raw.load(Ordering::Relaxed),
+            wire: counters.wire.load(Ordering::Relaxed),
+            skipped_frames: counters.skipped.load(Ordering::Relaxed),
+            busy: Duration::from_micros(counters.busy_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "tunnel closed")
+}
+
+fn start_stream(shared: &Arc<Shared>, id: u32, features: u32) -> DuplexStream {
+    let (local, remote) = tokio::io::duplex(STREAM_WINDOW);
+    let credit = Arc::new(Semaphore::new(STREAM_WINDOW));
+    let (inbound, received) = mpsc::channel(INBOUND_FRAMES);
+    let (features, peer_features) = watch::channel(features);
+
+    shared.streams.lock().unwrap().insert(
+        id,
+        Entry {
+            credit: credit.clone(),
+            window: STREAM_WINDOW,
+            inbound: Some(inbound),
+            features,
+            digest: shared.features.verify.then(Hasher::new),
+        },
+    );
+
+    let guard = Arc::new(Guard {
+        shared: shared.clone(),
+        id,
+    });
+    let (read_half, write_half) = tokio::io::split(remote);
+    tokio::spawn(send_stream(
+        read_half,
+        credit,
+        peer_features,
+        shared.clone(),
+        id,
+        guard.clone(),
+    ));
+    tokio::spawn(receive_stream(
+        write_half,
+        received,
+        shared.clone(),
+        id,
+        guard,
+    ));
+
+    local
+}
+
+fn pack(shared: &Shared, id: u32, data: &[u8], skip: &mut u32) -> Frame {
+    let counters = &shared.compression;
+    counters.raw.fetch_add(data.len() as u64, Ordering::Relaxed);
+    let frame = if *skip > 0 {
+        *skip -= 1;
+        counters.skipped.fetch_add(1, Ordering::Relaxed);
+        Frame::data(id, data.to_vec())
+    } else {
+        let started = Instant::now();
+        let packed = lz4::compress(data);
+        let busy = started.elapsed().as_micros() as u64;
+        counters.busy_micros.fetch_add(busy, Ordering::Relaxed);
+        if packed.len() + data.len() / 8 > data.len() {
+            *skip = SKIP_FRAMES;
+            Frame::data(id, data.to_vec())
+        } else {
+            Frame::lz4(id, packed)
+        }
+    };
+    counters
+        .wire
+        .fetch_add(frame.payload.len() as u64, Ordering::Relaxed);
+    frame
+}
+
+async fn send_stream(
+    mut source: ReadHalf<DuplexStream>,
+    credit: Arc<Semaphore>,
+    mut peer_features: watch::Receiver<u32>,
+    shared: Arc<Shared>,
+    id: u32,
+    _guard: Arc<Guard>,
+) {
+    let mut buffer = vec![0; MAX_FRAME];
+    let mut skip = 0;
+    // Until the opener hears otherwise the peer might want a digest, so
+    // everything is hashed from the start.
+    let mut digest = Some(Hasher::new());
+
+    loop {
+        let n = match source.read(&mut buffer).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+
+        match credit.acquire_many(n as u32).await {
+            Ok(permit) => permit.forget(),
+            Err(_) => return,
+        }
+        let features = *peer_features.borrow();
+        if features & (PENDING | FEATURE_DIGEST) == 0 {
+            digest = None;
+        }
+        if let Some(hasher) = &mut digest {
+            hasher.update(&buffer[..n]);
+        }
+        // Credit and window updates count uncompressed bytes.
+        let frame = if shared.features.compress && features & FEATURE_LZ4 != 0 {
+            pack(&shared, id, &buffer[..n], &mut skip)
+        } else {
+            Frame::data(id, buffer[..n].to_vec())
+        };
+        if shared.frames.send(frame).await.is_err() {
+            return;
+        }
+    }
+
+    if let Some(hasher) = digest {
+        // A short stream can end before the reply to its FRAME_OPEN arrives.
+        let known = peer_features.wait_for(|features| features & PENDING == 0);
+        let _ = timeout(timeouts::get().handshake, known).await;
+        if *peer_features.borrow() & (PENDING | FEATURE_DIGEST) == FEATURE_DIGEST {
+            let frame = Frame::digest(id, hasher.finalize());
+            if shared.frames.send(frame).await.is_err() {
+                return;
+            }
+        }
+    }
+    let _ = shared.frames.send(Frame::control(FRAME_FIN, id, 0)).await;
+}
+
+fn check_digest(shared: &Shared, id: u32, hasher: Hasher, sent: &[u8; 32]) {
+    let received = hasher.finalize();
+    if received == *sent {
+        shared.verified.fetch_add(1, Ordering::Relaxed);
+        stats::integrity(true);
+        otel::count("netcore.integrity.verified");
+        return;
+    }
+
+    shared.failed.fetch_add(1, Ordering::Relaxed);
+    stats::integrity(false);
+    otel::count("netcore.integrity.failed");
+    let expected = hex::encode(sent);
+    let actual = hex::encode(&received);
+    eprintln!(
+        "Integrity check failed on tunnel stream {}: sent {}, received {}",
+        id, expected, actual
+    );
+    events::emit(Event::IntegrityFailed {
+        stream: id,
+        expected,
+        actual,
+    });
+}
+
+async fn receive_stream(
+    mut sink: WriteHalf<DuplexStream>,
+    mut received: mpsc::Receiver<Vec<u8>>,
+    shared: Arc<Shared>,
+    id: u32,
+    _guard: Arc<Guard>,
+) {
+    while let Some(data) = received.recv().await {
+        if sink.write_all(&data).await.is_err() {
+            let _ = shared.frames.send(Frame::control(FRAME_RESET, id, 0)).await;
+            return;
+        }
+        // Opened again before the peer hears of it, so it can never be
+        // ahead of what this side allows.
+        if let Some(entry) = shared.streams.lock().unwrap().get_mut(&id) {
+            entry.window += data.len();
+        }
+        let window = Frame::control(FRAME_WINDOW, id, data.len() as u32);
+        if shared.frames.send(window).await.is_err() {
+            return;
+        }
+    }
+
+    let _ = sink.shutdown().await;
+}
+
+async fn write_frames<W: AsyncWrite>(
+    mut writer: WriteHalf<W>,
+    mut queue: mpsc::Receiver<Frame>,
+    shared: Arc<Shared>,
+) {
+    loop {
+        let frame = tokio::select! {
+            frame = queue.recv() => frame,
+            _ = shared.ended.notified() => None,
+        };
+        let Some(frame) = frame else {
+            break;
+        };
+        if writer.write_all(&frame.encode()).await.is_err() {
+            break;
+        }
+    }
+    let _ = writer.shutdown().await;
+}
+
+// Ends the session over a peer that doesn't keep to the protocol.
+fn violation(what: &str) {
+    eprintln!("Tunnel peer {}, closing connection", what);
+}
+
+async fn read_frames<R: AsyncRead>(
+    mut reader: ReadHalf<R>,
+    shared: Arc<Shared>,
+    accepted: mpsc::UnboundedSender<DuplexStream>,
+) {
+    let mut header = [0; HEADER_LEN];
+
+    loop {
+        let read = tokio::select! {
+            read = reader.read_exact(&mut header) => read,
+            _ = shared.dead.notified() => break,
+        };
+        if read.is_err() {
+            break;
+        }
+        shared.touch();
+
+        let kind = header[0];
+        let id = u32::from_be_bytes([header[1], header[2], header[3], header[4]]);
+        let len = u32::from_be_bytes([header[5], header[6], header[7], header[8]]);
+
+        match kind {
+            FRAME_OPEN => {
+                let refused = {
+                    let streams = shared.streams.lock().unwrap();
+                    streams.contains_key(&id) || streams.len() >= MAX_STREAMS
+                };
+                if refused {
+                    let _ = shared.frames.send(Frame::control(FRAME_RESET, id, 0)).await;
+                    continue;
+                }
+                if len != 0 {
+                    let reply = Frame::control(FRAME_FEATURES, id, shared.features.bits());
+                    let _ = shared.frames.send(reply).await;
+                }
+                let stream = start_stream(&shared, id, len & !PENDING);
+                if accepted.send(stream).is_err() {
+                    let _ = shared.frames.send(Frame::control(FRAME_RESET, id, 0)).await;
+                }
+            }
+            FRAME_DATA | FRAME_DATA_LZ4 => {
+                if len as usize > MAX_FRAME {
+                    break;
+                }
+                let mut payload = vec![0; len as usize];
+                if reader.read_exact(&mut payload).await.is_err() {
+                    break;
+                }
+                if kind == FRAME_DATA_LZ4 {
+                    match lz4::decompress(&payload, MAX_FRAME) {
+                        Some(data) => payload = data,
+                        None => break,
+                    }
+                }
+                let inbound = {
+                    let mut streams = shared.streams.lock().unwrap();
+                    let Some(entry) = streams.get_mut(&id) else {
+                        continue;
+                    };
+                    // Windows count what was sent before compression.
+                    if payload.len() > entry.window {
+                        violation("sent past the stream's window");
+                        break;
+                    }
+                    entry.window -= payload.len();
+                    if let Some(hasher) = &mut entry.digest {
+                        hasher.update(&payload);
+                    }
+                    entry.inbound.clone()
+                };
+                if let Some(inbound) = inbound {
+                    let _ = inbound.send(payload).await;
+                }
+            }
+            FRAME_DIGEST => {
+                if len != 32 {
+                    break;
+                }
+                let mut sent = [0; 32];
+                if reader.read_exact(&mut sent).await.is_err() {
+                    break;
+                }
+                let hasher = shared
+                    .streams
+                    .lock()
+                    .unwrap()
+                    .get_mut(&id)
+                    .and_then(|entry| entry.digest.take());
+                if let Some(hasher) = hasher {
+                    check_digest(&shared, id, hasher, &sent);
+                }
+            }
+            FRAME_FEATURES => {
+                if let Some(entry) = shared.streams.lock().unwrap().get(&id) {
+                    entry.features.send_replace(len & !PENDING);
+                }
+            }
+            FRAME_WINDOW => {
+                if let Some(entry) = shared.streams.lock().unwrap().get(&id) {
+                    // Credit returns what was sent, so never more than the
+                    // window it started from.
+                    if entry.credit.available_permits() + len as usize > STREAM_WINDOW {
+                        violation("granted more than the stream's window");
+                        break;
+                    }
+                    entry.credit.add_permits(len as usize);
+                }
+            }
+            FRAME_FIN => {
+                if let Some(entry) = shared.streams.lock().unwrap().get_mut(&id) {
+                    entry.inbound = None;
+                }
+            }
+            FRAME_RESET => {
+                if let Some(entry) = shared.streams.lock().unwrap().get_mut(&id) {
+                    entry.inbound = None;
+                    entry.credit.close();
+                }
+            }
+            FRAME_PING => {
+                let _ = shared.frames.send(Frame::control(FRAME_PONG, 0, len)).await;
+            }
+            FRAME_PONG => {}
+            _ => break,
+        }
+    }
+
+    shared.closed.store(true, Ordering::Relaxed);
+    shared.ended.notify_one();
+    for entry in shared.streams.lock().unwrap().values_mut() {
+        entry.inbound = None;
+        entry.credit.close();
+    }
+}
+
+// Reads `data` as frames from a peer, as a session would, until it ends or
+// the peer breaks a rule. What this side would send is dropped. For the
+// fuzz target: read_frames runs in a task of its own in a session, where a
+// panic wouldn't reach the fuzzer.
+pub async fn read_frames_from(data: &[u8]) {
+    let (frames, mut queue) = mpsc::channel(WRITE_QUEUE);
+    let shared = Shared::new(frames, Features::default());
+    let (accepted, _) = mpsc::unbounded_channel();
+    let (reader, _) = tokio::io::split(tokio::io::join(data, tokio::io::sink()));
+    tokio::select! {
+        _ = read_frames(reader, shared, accepted) => {}
+        _ = async { while queue.recv().await.is_some() {} } => {}
+    }
+}
+
+async fn keep_alive(shared: Weak<Shared>, keepalive: Keepalive) {
+    let mut token = 0u32;
+
+    loop {
+        sleep(keepalive.interval).await;
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        if shared.closed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let silence = shared.silence();
+        if silence >= keepalive.timeout {
+            eprintln!(
+                "Tunnel peer silent for {} s, closing connection",
+                silence.as_secs()
+            );
+            shared.dead.notify_one();
+            return;
+        }
+
+        token = token.wrapping_add(1);
+        if shared
+            .frames
+            .send(Frame::control(FRAME_PING, 0, token))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    const KEEPALIVE: Keepalive = Keepalive {
+        interval: Duration::from_secs(60),
+        timeout: Duration::from_secs(120),
+    };
+
+    async fn transfer(data: &[u8], compress: (bool, bool)) -> CompressionStats {
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let features = |compress| Features {
+            compress,
+            verify: false,
+        };
+        let (mux, _) = session(a, true, KEEPALIVE, features(compress.0));
+        let (_other, mut incoming) = session(b, false, KEEPALIVE, features(compress.1));
+
+        let mut stream = mux.open().await.unwrap();
+        let mut accepted = incoming.accept().await.unwrap();
+        let reader = tokio::spawn(async move {
+            let mut received = Vec::new();
+            accepted.read_to_end(&mut received).await.unwrap();
+            received
+        });
+        stream.write_all(data).await.unwrap();
+        stream.shutdown().await.unwrap();
+        assert_eq!(reader.await.unwrap(), data);
+        mux.compression()
+    }
+
+    #[tokio::test]
+    async fn speaks_the_frame_format() {
+        // kind, stream and length big-endian, then the payload.
+        let window = Frame::control(FRAME_WINDOW, 0x0102_0304, 0x0001_0000);
+        assert_eq!(window.encode(), [2, 1, 2, 3, 4, 0, 1, 0, 0]);
+        assert_eq!(
+            Frame::data(7, b"hi".to_vec()).encode(),
+            b"\x01\0\0\0\x07\0\0\0\x02hi"
+        );
+        let digest = Frame::digest(9, [0xaa; 32]).encode();
+        assert_eq!(digest[..HEADER_LEN], [9, 0, 0, 0, 9, 0, 0, 0, 32]);
+
+        // The other end of a session, byte by byte.
+        let (a, peer) = tokio::io::duplex(64 * 1024);
+        let (mux, mut incoming) = session(a, true, KEEPALIVE, Features::default());
+        let (mut from_peer, mut peer) = tokio::io::split(peer);
+        let mut frame = async |len: usize| {
+            let mut frame = vec![0; len];
+            from_peer.read_exact(&mut frame).await.unwrap();
+            frame
+        };
+
+        // Opening offers the features this side decodes: always LZ4.
+        let mut stream = mux.open().await.unwrap();
+        assert_eq!(frame(HEADER_LEN).await, [0, 0, 0, 0, 1, 0, 0, 0, 1]);
+        // The reply says what the peer decodes, here nothing, and no
+        // digests. Without it the stream waits before its FIN in case the
+        // peer wants one.
+        peer.write_all(&[7, 0, 0, 0, 1, 0, 0, 0, 0]).await.unwrap();
+        stream.write_all(b"abc").await.unwrap();
+        assert_eq!(frame(12).await, b"\x01\0\0\0\x01\0\0\0\x03abc");
+        stream.shutdown().await.unwrap();
+        assert_eq!(frame(HEADER_LEN).await, [3, 0, 0, 0, 1, 0, 0, 0, 0]);
+
+        peer.write_all(&[5, 0, 0, 0, 0, 0, 0, 0, 42]).await.unwrap();
+        assert_eq!(frame(HEADER_LEN).await, [6, 0, 0, 0, 0, 0, 0, 0, 42]);
+
+        // Streams the peer opens take even ids.
+        peer.write_all(&[0, 0, 0, 0, 2, 0, 0, 0, 0]).await.unwrap();
+        peer.write_all(b"\x01\0\0\0\x02\0\0\0\x03xyz")
+            .await
+            .unwrap();
+        // An LZ4 block of three literals.
+        peer.write_all(b"\x08\0\0\0\x02\0\0\0\x04\x30uvw")
+            .await
+            .unwrap();
+        peer.write_all(&[3, 0, 0, 0, 2, 0, 0, 0, 0]).await.unwrap();
+        let mut accepted = incoming.accept().await.unwrap();
+        let mut received = Vec::new();
+        accepted.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"xyzuvw");
+
+        // An unknown kind ends the session.
+        peer.write_all(&[0x7f, 0, 0, 0, 0, 0, 0, 0, 0])
+            .await
+            .unwrap();
+        assert!(incoming.accept().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn compresses_what_shrinks_and_skips_what_does_not() {
+        let text = b"netcore tunnel frame, compressed if it helps\n".repeat(8000);
+        let stats = transfer(&text, (true, true)).await;
+        assert!(stats.wire * 4 < stats.raw, "{}", stats);
+
+        let mut rng = StdRng::seed_from_u64(162);
+        let noise: Vec<u8> = (0..256 * 1024).map(|_| rng.r#gen()).collect();
+        let stats = transfer(&noise, (true, true)).await;
+        assert!(stats.skipped_frames > 0, "{}", stats);
+        assert!(stats.wire >= stats.raw, "{}", stats);
+    }
+
+    #[tokio::test]
+    async fn digests_are_checked_in_both_directions() {
+        let features = Features {
+            compress: true,
+            verify: true,
+        };
+        let (a, b) = tokio::io::duplex(64 * 1024);
+        let (opener, _) = session(a, true, KEEPALIVE, features);
+        let (acceptor, mut incoming) = session(b, false, KEEPALIVE, features);
+
+        let mut stream = opener.open().await.unwrap();
+        let mut accepted = incoming.accept().await.unwrap();
+        // Short enough to end before the reply to FRAME_OPEN could arrive.
+        stream.write_all(b"ping").await.unwrap();
+        stream.shutdown().await.unwrap();
+        accepted.write_all(&[7; 100_000]).await.unwrap();
+        accepted.shutdown().await.unwrap();
+
+        let mut received = Vec::new();
+        accepted.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"ping");
+        received.clear();
+        stream.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), 100_000);
+
+        for mux in [&opener, &acceptor] {
+            let integrity = mux.integrity();
+            assert_eq!((integrity.verified, integrity.failed), (1, 0));
+        }
+    }
+
+    // A session on one end and raw frames on the other.
+    fn raw_peer() -> (Mux, Incoming, DuplexStream) {
+        let (a, b) = tokio::io::duplex(1024 * 1024);
+        let (mux, incoming) = session(a, false, KEEPALIVE, Features::default());
+        (mux, incoming, b)
+    }
+
+    async fn send(raw: &mut DuplexStream, frame: Frame) {
+        raw.write_all(&frame.encode()).await.unwrap();
+    }
+
+    // Every frame the session sends until it closes the connection.
+    async fn frames_until_closed(raw: &mut DuplexStream) -> Vec<(u8, u32, u32)> {
+        let mut frames = Vec::new();
+        let mut header = [0; HEADER_LEN];
+        loop {
+            match timeout(Duration::from_secs(5), raw.read_exact(&mut header)).await {
+                Ok(Ok(_)) => {}
+                Ok(Err(_)) => return frames,
+                Err(_) => panic!("session still open after {:?}", frames),
+            }
+            let id = u32::from_be_bytes(header[1..5].try_into().unwrap());
+            let len = u32::from_be_bytes(header[5..9].try_into().unwrap());
+            if matches!(header[0], FRAME_DATA | FRAME_DATA_LZ4 | FRAME_DIGEST) {
+                let mut payload = vec![0; len as usize];
+                raw.read_exact(&mut payload).await.unwrap();
+            }
+            frames.push((header[0], id, len));
+        }
+    }
+
+    #[tokio::test]
+    async fn data_past_the_window_closes_the_session() {
+        let (_mux, _incoming, mut raw) = raw_peer();
+        send(&mut raw, Frame::control(FRAME_OPEN, 1, 0)).await;
+        // Nothing reads the stream, so none of the window comes back.
+        for _ in 0..STREAM_WINDOW / MAX_FRAME {
+            send(&mut raw, Frame::data(1, vec![0; MAX_FRAME])).await;
+        }
+        send(&mut raw, Frame::data(1, vec![0])).await;
+        let frames = frames_until_closed(&mut raw).await;
+        assert!(frames.iter().all(|frame| frame.0 != FRAME_RESET));
+    }
+
+    #[tokio::test]
+    async fn window_credit_is_capped_at_the_initial_window() {
+        let (mux, _incoming, mut raw) = raw_peer();
+        let mut stream = mux.open().await.unwrap();
+        stream.write_all(&[1; 1000]).await.unwrap();
+        // Returning what was sent is fine; anything more is not.
+        let mut header = [0; HEADER_LEN];
+        raw.read_exact(&mut header).await.unwrap();
+        assert_eq!(header[0], FRAME_OPEN);
+        let mut received = 0;
+        while received < 1000 {
+            raw.read_exact(&mut header).await.unwrap();
+            let len = u32::from_be_bytes(header[5..9].try_into().unwrap());
+            raw.read_exact(&mut vec![0; len as usize]).await.unwrap();
+            received += len;
+        }
+        send(&mut raw, Frame::control(FRAME_WINDOW, 2, 1000)).await;
+        send(&mut raw, Frame::control(FRAME_PING, 0, 7)).await;
+        raw.read_exact(&mut header).await.unwrap();
+        assert_eq!((header[0], header[8]), (FRAME_PONG, 7));
+
+        send(&mut raw, Frame::control(FRAME_WINDOW, 2, 1)).await;
+        frames_until_closed(&mut raw).await;
+        let mut rest = Vec::new();
+        assert_eq!(stream.read_to_end(&mut rest).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn duplicate_and_excess_streams_are_reset() {
+        let (mux, _incoming, mut raw) = raw_peer();
+        for id in 0..MAX_STREAMS as u32 {
+            send(&mut raw, Frame::control(FRAME_OPEN, id * 2 + 1, 0)).await;
+        }
+        send(&mut raw, Frame::control(FRAME_OPEN, 1, 0)).await;
+        send(&mut raw, Frame::control(FRAME_OPEN, 9999, 0)).await;
+        send(&mut raw, Frame::control(FRAME_PING, 0, 1)).await;
+
+        let mut header = [0; HEADER_LEN];
+        let mut resets = Vec::new();
+        loop {
+            raw.read_exact(&mut header).await.unwrap();
+            let id = u32::from_be_bytes(header[1..5].try_into().unwrap());
+            match header[0] {
+                FRAME_RESET => resets.push(id),
+                FRAME_PONG => break,
+                kind => panic!("unexpected frame {}", kind),
+            }
+        }
+        assert_eq!(resets, [1, 9999]);
+        assert!(mux.open().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn compression_is_only_used_when_enabled() {
+        let text = b"abcdefgh".repeat(16 * 1024);
+        assert_eq!(transfer(&text, (false, true)).await.raw, 0);
+        // Each side decides for what it sends; the other decodes either way.
+        assert!(transfer(&text, (true, false)).await.wire < text.len() as u64 / 4);
+    }
+}
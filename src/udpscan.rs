@@ -0,0 +1,163 @@
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant, timeout};
+
+use crate::dns;
+use crate::ntp;
+use crate::outbound::OutboundConfig;
+use crate::scan::State;
+use crate::services::Service;
+
+pub const COMMON_PORTS: &[u16] = &[53, 67, 69, 123, 137, 161, 500, 1900, 5353];
+
+const DNS_ID: u16 = 0x6e63;
+const SYS_DESCR_OID: &[u8] = &[0x06, 0x08, 0x2b, 0x06, 0x01, 0x02, 0x01, 0x01, 0x01, 0x00];
+
+pub struct Answer {
+    pub state: State,
+    pub rtt: Option<Duration>,
+    pub service: Option<Service>,
+}
+
+// Something a service on this port answers; unknown ports get an empty
+// datagram, which only catches services that reply to anything.
+fn payload(port: u16) -> Vec<u8> {
+    match port {
+        53 | 5353 => dns::encode_query(DNS_ID, "example.com").unwrap_or_default(),
+        123 => {
+            let mut request = vec![0; 48];
+            request[0] = ntp::CLIENT_VERSION_4;
+            request
+        }
+        // SNMPv1 get-request for sysDescr.0 with community "public".
+        161 => [
+            &[0x30, 0x29, 0x02, 0x01, 0x00, 0x04, 0x06][..],
+            b"public",
+            &[0xa0, 0x1c, 0x02, 0x04, 0x6e, 0x63, 0x6f, 0x72],
+            &[0x02, 0x01, 0x00, 0x02, 0x01, 0x00, 0x30, 0x0e, 0x30, 0x0c],
+            SYS_DESCR_OID,
+            &[0x05, 0x00],
+        ]
+        .concat(),
+        // NetBIOS node status request for the wildcard name "*".
+        137 => {
+            let mut request = vec![0x6e, 0x63, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x20, b'C', b'K'];
+            request.extend_from_slice(&[b'A'; 30]);
+            request.extend_from_slice(&[0, 0, 0x21, 0, 1]);
+            request
+        }
+        _ => Vec::new(),
+    }
+}
+
+// Names the service from its reply; anything we can't make sense of still
+// proves the port is open.
+pub fn describe(port: u16, reply: &[u8]) -> Option<Service> {
+    let (name, version) = match port {
+        53 | 5353 if reply.len() >= 12 && reply[..2] == DNS_ID.to_be_bytes() => ("dns", None),
+        123 if reply.len() >= 48 && reply[0] & 0x07 == 4 => {
+            ("ntp", Some(format!("stratum {}", reply[1])))
+        }
+        161 if reply.first() == Some(&0x30) => ("snmp", sys_descr(reply)),
+        137 => ("netbios", netbios_name(reply)),
+        _ => return None,
+    };
+    Some(Service {
+        name: name.to_string(),
+        banner: version.clone(),
+        version,
+    })
+}
+
+fn sys_descr(reply: &[u8]) -> Option<String> {
+    let at = reply
+        .windows(SYS_DESCR_OID.len())
+        .position(|window| window == SYS_DESCR_OID)?;
+    let value = &reply[at + SYS_DESCR_OID.len()..];
+    if value.first() != Some(&0x04) {
+        return None;
+    }
+    let (len, start) = match *value.get(1)? {
+        0x81 => (*value.get(2)? as usize, 3),
+        len if len < 0x80 => (len as usize, 2),
+        _ => return None,
+    };
+    let text = String::from_utf8_lossy(value.get(start..start + len)?);
+    Some(
+        text.lines()
+            .next()
+            .unwrap_or_default()
+            .chars()
+            .take(60)
+            .collect(),
+    )
+}
+
+// The node status reply lists the host's registered names; the first unique
+// name with suffix 0x00 is the machine name.
+fn netbios_name(reply: &[u8]) -> Option<String> {
+    let count = *reply.get(56)? as usize;
+    reply
+        .get(57..57 + count * 18)?
+        .chunks(18)
+        .find(|entry| entry[15] == 0 && entry[16] & 0x80 == 0)
+        .map(|entry| String::from_utf8_lossy(&entry[..15]).trim_end().to_string())
+}
+
+fn bind(outbound: &OutboundConfig, addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    if let Some(interface) = &outbound.interface {
+        socket.bind_device(Some(interface.as_bytes()))?;
+    }
+    let source = match (outbound.source_addr, addr) {
+        (Some(source), _) if source.is_ipv4() == addr.is_ipv4() => source,
+        (_, SocketAddr::V4(_)) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (_, SocketAddr::V6(_)) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    socket.bind(&SockAddr::from(SocketAddr::new(source, 0)))?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+// A reply means open and an ICMP port unreachable means closed. Other ICMP
+// errors mean a firewall answered for the host; silence after a retry is
+// open|filtered, since UDP services often ignore probes they don't parse.
+pub async fn probe(outbound: &OutboundConfig, addr: SocketAddr, wait: Duration) -> Answer {
+    let answer = |state, rtt, service| Answer {
+        state,
+        rtt,
+        service,
+    };
+    let socket = match bind(outbound, addr) {
+        Ok(socket) => socket,
+        Err(_) => return answer(State::Filtered, None, None),
+    };
+    if socket.connect(addr).await.is_err() {
+        return answer(State::Filtered, None, None);
+    }
+
+    let request = payload(addr.port());
+    let mut buf = [0; 1500];
+    for _ in 0..2 {
+        let start = Instant::now();
+        let result = match socket.send(&request).await {
+            Ok(_) => timeout(wait / 2, socket.recv(&mut buf)).await,
+            Err(e) => Ok(Err(e)),
+        };
+        match result {
+            Ok(Ok(n)) => {
+                let service = describe(addr.port(), &buf[..n]);
+                return answer(State::Open, Some(start.elapsed()), service);
+            }
+            Ok(Err(e)) if e.kind() == ErrorKind::ConnectionRefused => {
+                return answer(State::Closed, Some(start.elapsed()), None);
+            }
+            Ok(Err(_)) => return answer(State::Filtered, None, None),
+            Err(_) => {}
+        }
+    }
+    answer(State::OpenFiltered, None, None)
+}
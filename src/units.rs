@@ -0,0 +1,190 @@
+// Durations, sizes and rates as options and config files take them, so
+// every one of them accepts the same forms: `1500ms`, `1.5s`, `2m30s`,
+// `1.5GiB`, `100Mbps`, `10M/s`. A bare number keeps the unit its option
+// always had, so existing command lines mean what they did.
+
+use tokio::time::Duration;
+
+pub const MILLIS: Duration = Duration::from_millis(1);
+pub const SECS: Duration = Duration::from_secs(1);
+
+const DURATION_UNITS: &[(&str, f64)] = &[
+    ("ms", 0.001),
+    ("s", 1.0),
+    ("m", 60.0),
+    ("h", 3600.0),
+    ("d", 86_400.0),
+];
+
+// A leading decimal number and whatever follows it.
+fn split_number(value: &str) -> Result<(f64, &str), String> {
+    let end = value
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(value.len());
+    let (number, rest) = value.split_at(end);
+    match number.parse::<f64>() {
+        Ok(number) => Ok((number, rest)),
+        Err(_) if number.is_empty() => Err(format!("'{}' does not start with a number", value)),
+        Err(_) => Err(format!("'{}' is not a number", number)),
+    }
+}
+
+// One or more number and unit pairs, like `1h30m`. A bare number is in
+// units of `bare`.
+pub fn duration(value: &str, bare: Duration) -> Result<Duration, String> {
+    let value = value.trim();
+    if value.is_empty() {
+        return Err("expected a duration like 500ms, 10s or 5m".to_string());
+    }
+    let mut secs = 0.0;
+    let mut rest = value;
+    while !rest.is_empty() {
+        let (number, after) = split_number(rest)?;
+        let unit_len = after
+            .find(|c: char| c.is_ascii_digit() || c == '.')
+            .unwrap_or(after.len());
+        let (unit, after) = after.split_at(unit_len);
+        let scale = match unit.trim() {
+            "" if rest == value => bare.as_secs_f64(),
+            "" => return Err(format!("'{}' needs a unit after every number", value)),
+            unit => DURATION_UNITS
+                .iter()
+                .find(|(name, _)| *name == unit)
+                .map(|(_, scale)| *scale)
+                .ok_or_else(|| {
+                    format!(
+                        "unknown unit '{}' in '{}'; use ms, s, m, h or d",
+                        unit, value
+                    )
+                })?,
+        };
+        secs += number * scale;
+        rest = after;
+    }
+    Duration::try_from_secs_f64(secs).map_err(|_| format!("'{}' is too long", value))
+}
+
+// For intervals and periods, where zero would mean spinning.
+pub fn nonzero(duration: Duration) -> Result<Duration, String> {
+    match duration.is_zero() {
+        true => Err("must be longer than zero".to_string()),
+        false => Ok(duration),
+    }
+}
+
+fn size_scale(unit: &str) -> Option<f64> {
+    // KB and KiB alike are 1024 bytes: sizes here are buffers and quotas,
+    // never disk capacities.
+    let shift = match unit.to_ascii_lowercase().as_str() {
+        "" | "b" => 0,
+        "k" | "kb" | "kib" => 10,
+        "m" | "mb" | "mib" => 20,
+        "g" | "gb" | "gib" => 30,
+        "t" | "tb" | "tib" => 40,
+        _ => return None,
+    };
+    Some((1u64 << shift) as f64)
+}
+
+fn whole(value: &str, amount: f64) -> Result<u64, String> {
+    if amount > u64::MAX as f64 {
+        return Err(format!("'{}' is too large", value));
+    }
+    Ok(amount.round() as u64)
+}
+
+// Bytes, with an optional binary unit: `4096`, `64K`, `1.5GiB`.
+pub fn size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, unit) = split_number(value)?;
+    let scale = size_scale(unit.trim()).ok_or_else(|| {
+        format!(
+            "unknown unit '{}' in '{}'; use B, K, M, G or T",
+            unit.trim(),
+            value
+        )
+    })?;
+    whole(value, number * scale)
+}
+
+// Bytes per second, given either in bits (`100Mbps`, decimal prefixes as
+// network speeds are quoted) or as a size per second (`10M/s`).
+pub fn rate(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    if let Some(bits) = value
+        .strip_suffix("bps")
+        .or_else(|| value.strip_suffix("bit/s"))
+    {
+        let (number, unit) = split_number(bits)?;
+        let scale = match unit.trim().to_ascii_lowercase().as_str() {
+            "" => 1.0,
+            "k" => 1e3,
+            "m" => 1e6,
+            "g" => 1e9,
+            _ => {
+                return Err(format!(
+                    "unknown unit '{}bps' in '{}'; use bps, Kbps, Mbps or Gbps",
+                    unit.trim(),
+                    value
+                ));
+            }
+        };
+        return whole(value, number * scale / 8.0);
+    }
+    size(value.strip_suffix("/s").unwrap_or(value))
+        .map_err(|e| format!("{} (or give bits per second, like 100Mbps)", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_documented_forms() {
+        assert_eq!(duration("10s", MILLIS), Ok(Duration::from_secs(10)));
+        assert_eq!(duration("5m", SECS), Ok(Duration::from_secs(300)));
+        assert_eq!(duration("1.5s", SECS), Ok(Duration::from_millis(1500)));
+        assert_eq!(duration("1h30m", SECS), Ok(Duration::from_secs(5400)));
+        assert_eq!(duration("250", MILLIS), Ok(Duration::from_millis(250)));
+        assert_eq!(duration("15", SECS), Ok(Duration::from_secs(15)));
+
+        assert_eq!(size("4096"), Ok(4096));
+        assert_eq!(size("64K"), Ok(65_536));
+        assert_eq!(size("1.5GiB"), Ok(3 << 29));
+        assert_eq!(rate("100Mbps"), Ok(12_500_000));
+        assert_eq!(rate("10M/s"), Ok(10 << 20));
+        assert_eq!(rate("1K/s"), Ok(1024));
+    }
+
+    #[test]
+    fn explains_what_is_wrong() {
+        assert!(
+            duration("10x", SECS)
+                .unwrap_err()
+                .contains("unknown unit 'x'")
+        );
+        assert!(duration("1m30", SECS).unwrap_err().contains("needs a unit"));
+        assert!(
+            duration("", SECS)
+                .unwrap_err()
+                .contains("expected a duration")
+        );
+        assert!(
+            duration("fast", SECS)
+                .unwrap_err()
+                .contains("does not start with a number")
+        );
+        assert!(
+            duration("1.2.3s", SECS)
+                .unwrap_err()
+                .contains("is not a number")
+        );
+        assert!(
+            size("3 parsecs")
+                .unwrap_err()
+                .contains("unknown unit 'parsecs'")
+        );
+        assert!(rate("5Xbps").unwrap_err().contains("Mbps"));
+        assert!(size("1e30T").is_err());
+    }
+}
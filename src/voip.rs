@@ -0,0 +1,472 @@
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::time::{Duration, Instant, sleep, timeout_at};
+
+use crate::cli::{self, Args, Command, Opt};
+use crate::exit;
+use crate::measure::millis;
+use crate::outbound::{self, OutboundConfig};
+use crate::output;
+use crate::say;
+use crate::timeouts;
+use crate::units::{self, MILLIS};
+
+const SIP_PORT: u16 = 5060;
+const RTP_PORT: u16 = 16384;
+const RTP_PAYLOAD: usize = 160;
+const RTP_HEADER: usize = 12;
+const SIP_RETRANSMITS: &[u64] = &[500, 1000, 2000];
+
+const OPTS: &[Opt] = &[
+    Opt {
+        name: "--sip-port",
+        value: Some("<port>"),
+        help: "SIP port (default: 5060)",
+    },
+    Opt {
+        name: "--rtp-port",
+        value: Some("<port>"),
+        help: "First of two RTP echo ports (default: 16384)",
+    },
+];
+
+const PROBE_OPTS: &[Opt] = &[
+    Opt {
+        name: "--count",
+        value: Some("<n>"),
+        help: "RTP packets to send (default: 50)",
+    },
+    Opt {
+        name: "--interval",
+        value: Some("<duration>"),
+        help: "Delay between RTP packets (default: 20ms)",
+    },
+];
+
+fn bind_udp(ip: IpAddr, port: u16) -> io::Result<UdpSocket> {
+    let domain = if ip.is_ipv4() {
+        Domain::IPV4
+    } else {
+        Domain::IPV6
+    };
+    let socket = Socket::new(domain, Type::DGRAM, Some(Protocol::UDP))?;
+    if ip.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.bind(&SocketAddr::new(ip, port).into())?;
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
+
+fn header<'a>(message: &'a str, name: &str) -> Option<&'a str> {
+    message.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then_some(value.trim())
+    })
+}
+
+fn sip_response(request: &str, from: SocketAddr) -> Option<String> {
+    let method = request.split_whitespace().next()?;
+    let via = header(request, "via")?;
+    let via = match via.split_once(";rport") {
+        Some((head, tail)) => {
+            let tail = tail.trim_start_matches(|c: char| c == '=' || c.is_ascii_digit());
+            format!(
+                "{};received={};rport={}{}",
+                head,
+                from.ip(),
+                from.port(),
+                tail
+            )
+        }
+        None => format!("{};received={}", via, from.ip()),
+    };
+    let status = if method == "OPTIONS" {
+        "200 OK"
+    } else {
+        "501 Not Implemented"
+    };
+    let to = header(request, "to").unwrap_or_default();
+    let to = if to.contains(";tag=") {
+        to.to_string()
+    } else {
+        format!("{};tag={:08x}", to, rand::random::<u32>())
+    };
+
+    Some(format!(
+        "SIP/2.0 {}\r\nVia: {}\r\nFrom: {}\r\nTo: {}\r\nCall-ID: {}\r\nCSeq: {}\r\nAllow: OPTIONS\r\nServer: netcore/{}\r\nContent-Length: 0\r\n\r\n",
+        status,
+        via,
+        header(request, "from").unwrap_or_default(),
+        to,
+        header(request, "call-id").unwrap_or_default(),
+        header(request, "cseq").unwrap_or_default(),
+        env!("CARGO_PKG_VERSION")
+    ))
+}
+
+async fn serve_sip(socket: UdpSocket) {
+    let mut buf = [0; 4096];
+    loop {
+        let (n, from) = match socket.recv_from(&mut buf).await {
+            Ok(received) => received,
+            Err(e) => {
+                eprintln!("SIP receive error: {}", e);
+                continue;
+            }
+        };
+        let request = String::from_utf8_lossy(&buf[..n]);
+        if request.starts_with("SIP/2.0") {
+            continue;
+        }
+        if let Some(response) = sip_response(&request, from) {
+            say!(
+                "SIP {} from {}",
+                request.split_whitespace().next().unwrap_or("?"),
+                from
+            );
+            let _ = socket.send_to(response.as_bytes(), from).await;
+        }
+    }
+}
+
+async fn serve_rtp(socket: UdpSocket) {
+    let mut buf = [0; 2048];
+    loop {
+        let Ok((n, from)) = socket.recv_from(&mut buf).await else {
+            continue;
+        };
+        if n < RTP_HEADER || buf[0] >> 6 != 2 {
+            continue;
+        }
+        // Echo the header so the sender can match the packet, and tell it
+        // which address its packets arrived from.
+        let mut reply = buf[..RTP_HEADER].to_vec();
+        reply.extend_from_slice(from.to_string().as_bytes());
+        let _ = socket.send_to(&reply, from).await;
+    }
+}
+
+fn ports(args: &Args) -> Result<(u16, u16), String> {
+    Ok((
+        args.parsed("--sip-port")?.unwrap_or(SIP_PORT),
+        args.parsed("--rtp-port")?.unwrap_or(RTP_PORT),
+    ))
+}
+
+async fn serve(args: Args) {
+    let (sip_port, rtp_port) = cli::or_exit(ports(&args));
+
+    let mut bound = 0;
+    for ip in [
+        IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    ] {
+        for (port, sip) in [(sip_port, true), (rtp_port, false), (rtp_port + 1, false)] {
+            match bind_udp(ip, port) {
+                Ok(socket) => {
+                    bound += 1;
+                    if sip {
+                        tokio::spawn(serve_sip(socket));
+                    } else {
+                        tokio::spawn(serve_rtp(socket));
+                    }
+                }
+                Err(e) => eprintln!("Failed to bind {}: {}", SocketAddr::new(ip, port), e),
+            }
+        }
+    }
+    if bound == 0 {
+        std::process::exit(exit::FAILURE);
+    }
+
+    say!(
+        "VoIP responder on SIP port {} and RTP ports {}-{}",
+        sip_port,
+        rtp_port,
+        rtp_port + 1
+    );
+    std::future::pending::<()>().await;
+}
+
+async fn local_socket(target: SocketAddr) -> io::Result<(UdpSocket, SocketAddr)> {
+    let unspecified = match target {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((unspecified, 0)).await?;
+    // A connected throwaway socket reveals which source address the kernel picks.
+    let route = UdpSocket::bind((unspecified, 0)).await?;
+    route.connect(target).await?;
+    let local = SocketAddr::new(route.local_addr()?.ip(), socket.local_addr()?.port());
+    Ok((socket, local))
+}
+
+fn rport(via: &str) -> Option<SocketAddr> {
+    let param = |name: &str| {
+        via.split(';')
+            .find_map(|p| p.trim().strip_prefix(name)?.strip_prefix('='))
+    };
+    let ip: IpAddr = param("received")?.parse().ok()?;
+    let port: u16 = param("rport")?.parse().ok()?;
+    Some(SocketAddr::new(ip, port))
+}
+
+async fn sip_options(target: SocketAddr, host: &str) -> Result<(), String> {
+    let (socket, local) = local_socket(target).await.map_err(|e| e.to_string())?;
+    let request = format!(
+        "OPTIONS sip:{} SIP/2.0\r\nVia: SIP/2.0/UDP {};branch=z9hG4bK{:08x};rport\r\nMax-Forwards: 70\r\nFrom: <sip:netcore@{}>;tag={:08x}\r\nTo: <sip:{}>\r\nCall-ID: {:016x}@netcore\r\nCSeq: 1 OPTIONS\r\nContact: <sip:netcore@{}>\r\nAccept: application/sdp\r\nUser-Agent: netcore/{}\r\nContent-Length: 0\r\n\r\n",
+        host,
+        local,
+        rand::random::<u32>(),
+        local.ip(),
+        rand::random::<u32>(),
+        host,
+        rand::random::<u64>(),
+        local,
+        env!("CARGO_PKG_VERSION")
+    );
+
+    let start = Instant::now();
+    let mut buf = [0; 4096];
+    for wait in SIP_RETRANSMITS {
+        socket
+            .send_to(request.as_bytes(), target)
+            .await
+            .map_err(|e| e.to_string())?;
+        let deadline = Instant::now() + Duration::from_millis(*wait);
+
+        while let Ok(Ok((n, from))) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+            let response = String::from_utf8_lossy(&buf[..n]);
+            let Some(status) = response
+                .lines()
+                .next()
+                .and_then(|l| l.strip_prefix("SIP/2.0 "))
+            else {
+                continue;
+            };
+            if from != target || status.starts_with('1') {
+                continue;
+            }
+
+            let detail = format!(
+                "{} in {:.2} ms ({})",
+                status,
+                millis(start.elapsed()),
+                header(&response, "server")
+                    .or(header(&response, "user-agent"))
+                    .unwrap_or("no server header")
+            );
+            say!("PASS  sip  {}", detail);
+            output::verdict("PASS", "sip", &detail);
+            match header(&response, "via").and_then(rport) {
+                Some(mapped) if mapped == local => {
+                    say!("      mapped address {} matches local, no NAT", mapped)
+                }
+                Some(mapped) => say!("      mapped address {} (local {}), NAT", mapped, local),
+                None => say!("      server did not report received/rport"),
+            }
+            return Ok(());
+        }
+    }
+    Err("no response to OPTIONS".to_string())
+}
+
+struct RtpResult {
+    rtts: Vec<f64>,
+    mapped: Option<SocketAddr>,
+}
+
+fn rtp_packet(seq: u16, timestamp: u32, ssrc: u32) -> Vec<u8> {
+    let mut packet = vec![0x80, 0x00];
+    packet.extend_from_slice(&seq.to_be_bytes());
+    packet.extend_from_slice(&timestamp.to_be_bytes());
+    packet.extend_from_slice(&ssrc.to_be_bytes());
+    packet.resize(RTP_HEADER + RTP_PAYLOAD, 0);
+    packet
+}
+
+async fn rtp_round_trips(
+    socket: Arc<UdpSocket>,
+    target: SocketAddr,
+    count: u16,
+    interval: Duration,
+) -> RtpResult {
+    let ssrc: u32 = rand::random();
+    let start = Instant::now();
+
+    let sender = {
+        let socket = socket.clone();
+        tokio::spawn(async move {
+            for seq in 0..count {
+                let micros = start.elapsed().as_micros() as u32;
+                let _ = socket.send_to(&rtp_packet(seq, micros, ssrc), target).await;
+                sleep(interval).await;
+            }
+        })
+    };
+
+    let deadline = start + interval * count as u32 + Duration::from_secs(1);
+    let mut seen = vec![false; count as usize];
+    let mut result = RtpResult {
+        rtts: Vec::new(),
+        mapped: None,
+    };
+    let mut buf = [0; 2048];
+    while let Ok(Ok((n, from))) = timeout_at(deadline, socket.recv_from(&mut buf)).await {
+        if from != target || n < RTP_HEADER || buf[8..12] != ssrc.to_be_bytes() {
+            continue;
+        }
+        let seq = u16::from_be_bytes([buf[2], buf[3]]) as usize;
+        if seen.get(seq) != Some(&false) {
+            continue;
+        }
+        seen[seq] = true;
+
+        let sent = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        let now = start.elapsed().as_micros() as u32;
+        result.rtts.push(now.wrapping_sub(sent) as f64 / 1000.0);
+        result.mapped = String::from_utf8_lossy(&buf[RTP_HEADER..n]).parse().ok();
+    }
+    let _ = sender.await;
+    result
+}
+
+async fn probe(args: Args, outbound: OutboundConfig) {
+    let Some(host) = args.positional().get(1) else {
+        eprintln!("voip probe requires a host running 'netcore voip serve' or a SIP server");
+        std::process::exit(exit::USAGE);
+    };
+    let (sip_port, rtp_port) = cli::or_exit(ports(&args));
+    let count: u16 = cli::or_exit(args.parsed("--count")).unwrap_or(50).max(1);
+    let interval = cli::or_exit(args.parsed_with("--interval", |v| units::duration(v, MILLIS)))
+        .unwrap_or(Duration::from_millis(20));
+
+    let ip = match outbound.resolve(&format!("{}:{}", host, sip_port)).await {
+        Ok(addrs) => addrs[0].ip(),
+        Err(e) => {
+            eprintln!("Failed to resolve {}: {}", host, e);
+            std::process::exit(exit::code(&e));
+        }
+    };
+
+    let mut failed = false;
+    say!("SIP OPTIONS to {}", SocketAddr::new(ip, sip_port));
+    if let Err(e) = sip_options(SocketAddr::new(ip, sip_port), host).await {
+        let detail = format!("{} (UDP {} blocked or no SIP service)", e, sip_port);
+        say!("FAIL  sip  {}", detail);
+        output::verdict("FAIL", "sip", &detail);
+        failed = true;
+    }
+
+    let target = SocketAddr::new(ip, rtp_port);
+    let (socket, local) = match local_socket(target).await {
+        Ok((socket, local)) => (Arc::new(socket), local),
+        Err(e) => {
+            eprintln!("Failed to open RTP socket: {}", e);
+            std::process::exit(exit::code(&e));
+        }
+    };
+
+    say!();
+    say!("RTP {} packets to {} from {}", count, target, local);
+    let main = rtp_round_trips(socket.clone(), target, count, interval).await;
+    let received = main.rtts.len();
+    if received == 0 {
+        let detail = format!(
+            "no packets returned (UDP {} blocked or no responder)",
+            rtp_port
+        );
+        say!("FAIL  rtp  {}", detail);
+        output::verdict("FAIL", "rtp", &detail);
+        std::process::exit(exit::FAILURE);
+    }
+
+    let loss = 100.0 * (count as usize - received) as f64 / count as f64;
+    let avg = main.rtts.iter().sum::<f64>() / received as f64;
+    let jitter = main
+        .rtts
+        .windows(2)
+        .map(|pair| (pair[1] - pair[0]).abs())
+        .sum::<f64>()
+        / (received.max(2) - 1) as f64;
+    let status = if loss > 1.0 || jitter > 30.0 {
+        failed = true;
+        "WARN"
+    } else {
+        "PASS"
+    };
+    let detail = format!(
+        "{}/{} returned, loss {:.1}%, rtt avg {:.2} ms, jitter {:.2} ms",
+        received, count, loss, avg, jitter
+    );
+    say!("{}  rtp  {}", status, detail);
+    output::verdict(status, "rtp", &detail);
+
+    let other = rtp_round_trips(socket, SocketAddr::new(ip, rtp_port + 1), 3, interval).await;
+    let symmetric = matches!((main.mapped, other.mapped), (Some(a), Some(b)) if a != b);
+    failed |= symmetric;
+    let (status, detail) = match (main.mapped, other.mapped) {
+        (Some(a), Some(b)) if a != b => (
+            "WARN",
+            format!(
+                "symmetric NAT: mapped {} for port {} but {} for port {}",
+                a,
+                rtp_port,
+                b,
+                rtp_port + 1
+            ),
+        ),
+        (Some(a), Some(_)) if a == local => ("PASS", format!("no NAT, peer sees {}", a)),
+        (Some(a), Some(_)) => (
+            "PASS",
+            format!(
+                "endpoint-independent mapping {} for both ports (local {})",
+                a, local
+            ),
+        ),
+        _ => (
+            "WARN",
+            format!(
+                "could not compare mappings; port {} did not answer",
+                rtp_port + 1
+            ),
+        ),
+    };
+    say!("{}  nat  {}", status, detail);
+    if symmetric {
+        say!("      media will need a relay (TURN) or an ALG to traverse this NAT");
+    }
+    output::verdict(status, "nat", &detail);
+
+    if failed {
+        std::process::exit(exit::FAILURE);
+    }
+}
+
+pub const COMMAND: Command = Command {
+    name: "voip",
+    usage: "netcore voip <serve|probe <host>>",
+    about: "Measure SIP and RTP quality, or serve as the far end",
+    groups: &[OPTS, PROBE_OPTS, outbound::OPTS, timeouts::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    let outbound = cli::or_exit(OutboundConfig::from_args(&args));
+    cli::or_exit(timeouts::init(&args, None));
+
+    match args.positional().first().map(String::as_str) {
+        Some("serve") => serve(args).await,
+        Some("probe") => probe(args, outbound).await,
+        _ => {
+            eprintln!("usage: netcore voip <serve|probe <host>>");
+            std::process::exit(exit::USAGE);
+        }
+    }
+}
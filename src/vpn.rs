@@ -0,0 +1,241 @@
+// Whether traffic actually goes through the VPN. A tunnel that is up can
+// still be bypassed: VPNs that only route IPv4 leave IPv6 going out the
+// usual way, and a resolver reached outside the tunnel shows every name
+// looked up to the local network. Both are found by asking the routing
+// table which interface each kind of traffic would leave from.
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+
+use crate::cli::{self, Command};
+use crate::dns;
+use crate::exit;
+use crate::geoip;
+use crate::json::Value;
+use crate::output;
+use crate::say;
+use crate::timeouts;
+
+// Name prefixes of tunnel interfaces, for systems without /sys/class/net
+// to ask, with what usually creates them.
+const TUNNEL_NAMES: &[(&str, &str)] = &[
+    ("wg", "WireGuard"),
+    ("nordlynx", "WireGuard"),
+    ("tailscale", "Tailscale"),
+    ("utun", "TUN"),
+    ("tun", "TUN, e.g. OpenVPN"),
+    ("tap", "TAP, e.g. OpenVPN"),
+    ("ipsec", "IPsec"),
+    ("vti", "IPsec"),
+    ("cscotun", "Cisco AnyConnect"),
+    ("gpd", "GlobalProtect"),
+    ("zt", "ZeroTier"),
+];
+
+// systemd-resolved's stub forwards to these; the stub itself is local.
+const RESOLVED_UPSTREAMS: &str = "/run/systemd/resolve/resolv.conf";
+
+// What kind of tunnel `name` is, if it is one. Linux says so in sysfs:
+// WireGuard sets DEVTYPE, TUN/TAP devices have tun_flags.
+pub fn tunnel_kind(name: &str, devtype: Option<&str>, tun: bool) -> Option<&'static str> {
+    match devtype {
+        Some("wireguard") => return Some("WireGuard"),
+        Some("vti" | "xfrm") => return Some("IPsec"),
+        _ => {}
+    }
+    let named = TUNNEL_NAMES
+        .iter()
+        .find(|(prefix, _)| name.starts_with(prefix))
+        .map(|(_, kind)| *kind);
+    match (named, tun) {
+        (Some(kind), _) => Some(kind),
+        (None, true) => Some("TUN/TAP"),
+        (None, false) => None,
+    }
+}
+
+fn sysfs_kind(name: &str) -> Option<&'static str> {
+    let dir = format!("/sys/class/net/{}", name);
+    let uevent = fs::read_to_string(format!("{}/uevent", dir)).unwrap_or_default();
+    let devtype = uevent
+        .lines()
+        .find_map(|line| line.strip_prefix("DEVTYPE="));
+    let tun = fs::metadata(format!("{}/tun_flags", dir)).is_ok();
+    tunnel_kind(name, devtype, tun)
+}
+
+// Every interface that is up with an address, with the tunnel ones marked.
+fn interfaces() -> Vec<(String, IpAddr, Option<&'static str>)> {
+    local_ip_address::list_afinet_netifas()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, ip)| {
+            let kind = sysfs_kind(&name);
+            (name, ip, kind)
+        })
+        .collect()
+}
+
+// The interface traffic to `dest` would leave from, found by connecting a
+// UDP socket, which sends nothing.
+fn route(dest: IpAddr, interfaces: &[(String, IpAddr, Option<&'static str>)]) -> Option<String> {
+    let bind = match dest {
+        IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind((bind, 0)).ok()?;
+    socket.connect(SocketAddr::new(dest, 53)).ok()?;
+    let source = socket.local_addr().ok()?.ip();
+    interfaces
+        .iter()
+        .find(|(_, ip, _)| *ip == source)
+        .map(|(name, _, _)| name.clone())
+}
+
+pub const COMMAND: Command = Command {
+    name: "vpn-check",
+    usage: "netcore vpn-check",
+    about: "Find VPN interfaces and check that IPv6 and DNS don't leak around them",
+    groups: &[timeouts::OPTS, geoip::OPTS],
+};
+
+pub async fn command(tokens: Vec<String>) {
+    let args = cli::parse_command(&COMMAND, tokens);
+    cli::or_exit(timeouts::init(&args, None));
+    cli::or_exit(geoip::init(&args));
+
+    let interfaces = interfaces();
+    let mut tunnels: Vec<(&str, &str)> = Vec::new();
+    for (name, _, kind) in &interfaces {
+        if let Some(kind) = kind
+            && !tunnels.iter().any(|(seen, _)| seen == name)
+        {
+            tunnels.push((name, kind));
+        }
+    }
+    output::emit(Value::object([
+        ("subject", Value::from("tunnels")),
+        (
+            "interfaces",
+            Value::Array(
+                tunnels
+                    .iter()
+                    .map(|(name, kind)| {
+                        Value::object([("name", Value::from(*name)), ("kind", Value::from(*kind))])
+                    })
+                    .collect(),
+            ),
+        ),
+    ]));
+    if tunnels.is_empty() {
+        say!("FAIL  no VPN interface is up");
+        output::verdict("FAIL", "vpn", "no VPN interface is up");
+        std::process::exit(exit::FAILURE);
+    }
+    let described: Vec<String> = tunnels
+        .iter()
+        .map(|(name, kind)| format!("{} ({})", name, kind))
+        .collect();
+    say!("VPN:  {}", described.join(", "));
+
+    let through_tunnel = |interface: &str| tunnels.iter().any(|(name, _)| *name == interface);
+    let mut leaks = 0;
+    let mut report = |subject: &str, dest: IpAddr, what: String| {
+        let (status, detail) = match route(dest, &interfaces) {
+            Some(via) if through_tunnel(&via) => ("PASS", format!("{} goes through {}", what, via)),
+            Some(via) => {
+                leaks += 1;
+                (
+                    "FAIL",
+                    format!("{} leaks: it goes out {}, outside the tunnel", what, via),
+                )
+            }
+            None => ("PASS", format!("{} has no route, so nothing leaks", what)),
+        };
+        say!("{}  {:<5} {}", status, subject, detail);
+        output::verdict(status, subject, &detail);
+    };
+
+    // Documentation addresses: only the route to them matters.
+    report(
+        "ipv4",
+        Ipv4Addr::new(192, 0, 2, 1).into(),
+        "IPv4".to_string(),
+    );
+    report(
+        "ipv6",
+        Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+        "IPv6".to_string(),
+    );
+
+    let mut servers = dns::nameservers("/etc/resolv.conf");
+    if servers.iter().all(IpAddr::is_loopback) {
+        servers = dns::nameservers(RESOLVED_UPSTREAMS);
+    }
+    servers.retain(|ip| !ip.is_loopback());
+    if servers.is_empty() {
+        let detail = "only a local resolver is configured; where it forwards isn't known";
+        say!("WARN  {:<5} {}", "dns", detail);
+        output::verdict("WARN", "dns", detail);
+    }
+    for server in servers {
+        report("dns", server, format!("name server {}", server));
+    }
+
+    let info = crate::get_host_info().await;
+    for ip in [
+        info.public_ipv4.map(IpAddr::V4),
+        info.public_ipv6.map(IpAddr::V6),
+    ]
+    .into_iter()
+    .flatten()
+    {
+        let geo = geoip::lookup(ip);
+        match &geo {
+            Some(geo) => say!("Exit: {} {}", ip, geo),
+            None => say!(
+                "Exit: {} (give --geoip with an ASN database for its network)",
+                ip
+            ),
+        }
+        output::emit(Value::object([
+            ("subject", Value::from("exit")),
+            ("ip", Value::from(ip.to_string())),
+            ("asn", Value::from(geo.as_ref().and_then(|geo| geo.asn))),
+            (
+                "org",
+                Value::from(geo.as_ref().and_then(|geo| geo.org.clone())),
+            ),
+            (
+                "country",
+                Value::from(geo.as_ref().and_then(|geo| geo.country.clone())),
+            ),
+        ]));
+    }
+
+    if leaks > 0 {
+        std::process::exit(exit::FAILURE);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognises_tunnels() {
+        assert_eq!(
+            tunnel_kind("wg0", Some("wireguard"), false),
+            Some("WireGuard")
+        );
+        assert_eq!(
+            tunnel_kind("home", Some("wireguard"), false),
+            Some("WireGuard")
+        );
+        assert_eq!(tunnel_kind("tun0", None, true), Some("TUN, e.g. OpenVPN"));
+        assert_eq!(tunnel_kind("corp", None, true), Some("TUN/TAP"));
+        assert_eq!(tunnel_kind("utun3", None, false), Some("TUN"));
+        assert_eq!(tunnel_kind("eth0", None, false), None);
+        assert_eq!(tunnel_kind("wlan0", None, false), None);
+    }
+}
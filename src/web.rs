@@ -0,0 +1,578 @@
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE, HOST, ORIGIN};
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::acl;
+use crate::acme;
+use crate::audit;
+use crate::auth::{Auth, Role};
+use crate::cli::{Args, Opt};
+use crate::control;
+use crate::events;
+use crate::guard::Guard;
+use crate::history::{self, History};
+use crate::json::Value;
+use crate::measure;
+use crate::outbound::OutboundConfig;
+use crate::say;
+use crate::stats;
+
+const DASHBOARD: &str = include_str!("dashboard.html");
+const HISTORY_DAYS: u64 = 7;
+const MAX_HISTORY_RECORDS: usize = 5000;
+
+pub const OPTS: &[Opt] = &[Opt {
+    name: "--web-ui",
+    value: Some("<addr>"),
+    help: "Serve the web dashboard on this address (e.g. 127.0.0.1:8080)",
+}];
+
+pub struct WebUi {
+    pub addr: SocketAddr,
+    pub auth: Auth,
+    pub guard: Guard,
+}
+
+impl WebUi {
+    pub fn from_args(args: &Args, auth: Auth) -> Result<Option<WebUi>, String> {
+        let Some(addr) = args.parsed::<SocketAddr>("--web-ui")? else {
+            return Ok(None);
+        };
+
+        if auth.is_open() && !addr.ip().is_loopback() {
+            return Err(format!(
+                "--web-ui on non-loopback address {} requires --admin-token or --read-token",
+                addr
+            ));
+        }
+
+        let guard = Guard::from_args(args)?;
+        Ok(Some(WebUi { addr, auth, guard }))
+    }
+}
+
+struct State {
+    addr: SocketAddr,
+    auth: Auth,
+    outbound: OutboundConfig,
+    history: Arc<History>,
+}
+
+pub async fn serve(web: WebUi, outbound: OutboundConfig, history: Option<Arc<History>>) {
+    let state = Arc::new(State {
+        addr: web.addr,
+        auth: web.auth,
+        outbound,
+        history: history.unwrap_or_else(|| Arc::new(History::new(history::default_path()))),
+    });
+
+    let listener = match TcpListener::bind(web.addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            eprintln!("Failed to bind web dashboard on {}: {}", web.addr, e);
+            return;
+        }
+    };
+    say!("Web dashboard listening on http://{}", web.addr);
+
+    let mut http = Http::new();
+    http.http1_only(true)
+        .http1_header_read_timeout(web.guard.header_timeout);
+
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok((_, addr)) if acl::check(addr).is_some() => continue,
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("Web dashboard accept error: {}", e);
+                continue;
+            }
+        };
+
+        let state = state.clone();
+        let service = service_fn(move |request| {
+            let state = state.clone();
+            async move { Ok::<_, Infallible>(handle(request, &state, addr).await) }
+        });
+        let connection = http.serve_connection(web.guard.wrap(socket, addr), service);
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                eprintln!("Web dashboard connection from {} closed: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn handle(request: Request<Body>, state: &State, addr: SocketAddr) -> Response<Body> {
+    // The CA can't present a token, so challenges come before auth.
+    if let Some(response) = acme::respond(request.uri().path()) {
+        return response;
+    }
+    if let Some(refusal) = foreign(&request, state.addr) {
+        return respond(StatusCode::FORBIDDEN, "text/plain", refusal);
+    }
+    let Some(role) = state.auth.role(offered_token(&request).as_deref()) else {
+        acl::offence(addr, "auth");
+        audit::record(
+            "auth_failed",
+            vec![
+                ("via", Value::from("web")),
+                ("from", addr.to_string().into()),
+            ],
+        );
+        return respond(StatusCode::UNAUTHORIZED, "text/plain", "unauthorized\n");
+    };
+
+    let query = request.uri().query().unwrap_or_default().to_string();
+    let command = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/") => {
+            return respond(StatusCode::OK, "text/html; charset=utf-8", DASHBOARD);
+        }
+        (&Method::GET, "/api/info") => "info",
+        (&Method::GET, "/api/stats") => "stats",
+        (&Method::GET, "/api/history") => "history",
+        (&Method::GET, "/api/events") => "events",
+        (&Method::POST, "/api/check") => "check",
+        (&Method::POST, "/api/kill") => "kill",
+        (
+            _,
+            "/" | "/api/info" | "/api/stats" | "/api/history" | "/api/events" | "/api/check"
+            | "/api/kill",
+        ) => {
+            return respond(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "text/plain",
+                "method not allowed\n",
+            );
+        }
+        _ => return respond(StatusCode::NOT_FOUND, "text/plain", "not found\n"),
+    };
+
+    let required = control::required_role(command);
+    if role < required {
+        return respond(
+            StatusCode::FORBIDDEN,
+            "text/plain",
+            &format!("{} requires the {} role\n", command, required),
+        );
+    }
+
+    let response = match command {
+        "info" => {
+            let info = crate::get_host_info().await;
+            let samples = measure::host_info_samples(&info);
+            json(Value::Array(
+                samples.iter().map(measure::Sample::to_json).collect(),
+            ))
+        }
+        "stats" => json(stats::snapshot()),
+        "history" => history_records(state, &query).await,
+        "events" => event_stream(),
+        "check" => {
+            let samples = measure::check(&state.outbound).await;
+            measure::record(&state.history, &samples).await;
+            json(Value::Array(
+                samples.iter().map(measure::Sample::to_json).collect(),
+            ))
+        }
+        _ => match query_param(&query, "id").and_then(|id| id.parse().ok()) {
+            Some(id) if stats::kill(id) => json(Value::object([("killed", Value::from(id))])),
+            Some(id) => respond(
+                StatusCode::NOT_FOUND,
+                "text/plain",
+                &format!("no active connection with id {}\n", id),
+            ),
+            None => respond(StatusCode::BAD_REQUEST, "text/plain", "missing id\n"),
+        },
+    };
+    if required == Role::Admin {
+        let status = response.status();
+        let error = (!status.is_success()).then(|| status.to_string());
+        let request = Value::from(without_token(&query));
+        let from = Some(addr.to_string());
+        audit::admin("web", from, command, request, error.as_deref());
+    }
+    response
+}
+
+// Server-sent events, so the dashboard can refresh when something changes
+// rather than on a timer. Subscribers that fall behind get a `lagged` event
+// and should reload everything.
+fn event_stream() -> Response<Body> {
+    let mut events = events::subscribe();
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move {
+        loop {
+            let (name, data) = match events.recv().await {
+                Ok(event) => (event.name(), event.to_json()),
+                Err(RecvError::Lagged(missed)) => {
+                    ("lagged", Value::object([("missed", Value::from(missed))]))
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let chunk = format!("event: {}\ndata: {}\n\n", name, data);
+            if sender.send_data(chunk.into()).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header("cache-control", "no-store")
+        .body(body)
+        .unwrap_or_default()
+}
+
+async fn history_records(state: &State, query: &str) -> Response<Body> {
+    let kind = query_param(query, "kind");
+    let days = query_param(query, "since")
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(HISTORY_DAYS);
+    let since = history::now().saturating_sub(days.saturating_mul(86_400));
+
+    let history = state.history.clone();
+    let read = tokio::task::spawn_blocking(move || {
+        let records = history.records()?;
+        let kept = history.kept_since(&records);
+        Ok::<_, std::io::Error>((records, kept))
+    });
+    let (records, kept) = match read.await.unwrap_or_else(|e| Err(e.into())) {
+        Ok(read) => read,
+        Err(e) => {
+            return respond(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "text/plain",
+                &format!("{}\n", e),
+            );
+        }
+    };
+
+    let records: Vec<_> = records
+        .into_iter()
+        .filter(|r| r.time >= since && kind.as_ref().is_none_or(|kind| r.kind == *kind))
+        .collect();
+    let skip = records.len().saturating_sub(MAX_HISTORY_RECORDS);
+
+    let mut response = json(Value::Array(
+        records
+            .into_iter()
+            .skip(skip)
+            .map(|r| {
+                Value::object([
+                    ("time", Value::from(r.time)),
+                    ("kind", Value::from(r.kind)),
+                    ("subject", Value::from(r.subject)),
+                    ("value", Value::from(r.value)),
+                ])
+            })
+            .collect(),
+    ));
+    // Older records were rotated away, so the window asked for is cut.
+    if let Some(kept) = kept.filter(|&kept| since < kept) {
+        response
+            .headers_mut()
+            .insert("x-history-kept-since", kept.into());
+    }
+    response
+}
+
+// Any page open in the operator's browser can send requests to the
+// dashboard, or rebind its own name to the dashboard's address and read the
+// replies. So the Host has to name the bound address itself, and an Origin,
+// which browsers send with anything cross-site, has to be that same host.
+fn foreign(request: &Request<Body>, bound: SocketAddr) -> Option<&'static str> {
+    let header = |name| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    let Some(host) = header(HOST).filter(|host| serves(host, bound)) else {
+        return Some("the Host header must be the dashboard's address\n");
+    };
+
+    let same = |origin: &str| {
+        let origin = origin
+            .strip_prefix("http://")
+            .or(origin.strip_prefix("https://"));
+        origin.is_some_and(|origin| origin.eq_ignore_ascii_case(host))
+    };
+    match request.headers().get(ORIGIN) {
+        Some(_) if !header(ORIGIN).is_some_and(same) => Some("cross-origin request refused\n"),
+        _ => None,
+    }
+}
+
+fn serves(host: &str, bound: SocketAddr) -> bool {
+    let (name, port) = match host.rsplit_once(':') {
+        Some((name, port)) if !host.ends_with(']') => (name, port.parse().ok()),
+        _ => (host, Some(80)),
+    };
+    if port != Some(bound.port()) {
+        return false;
+    }
+
+    let name = name.trim_start_matches('[').trim_end_matches(']');
+    if name.eq_ignore_ascii_case("localhost") {
+        return bound.ip().is_loopback();
+    }
+    name.parse::<IpAddr>()
+        .is_ok_and(|ip| ip == bound.ip() || bound.ip().is_unspecified())
+}
+
+fn offered_token(request: &Request<Body>) -> Option<String> {
+    let bearer = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+
+    bearer.or_else(|| {
+        request
+            .uri()
+            .query()
+            .and_then(|query| query_param(query, "token"))
+    })
+}
+
+// The query as the audit log keeps it: a token given as ?token= is dropped,
+// so the log doesn't hand out admin access to whoever can read it.
+fn without_token(query: &str) -> String {
+    query
+        .split('&')
+        .filter(|pair| pair.split('=').next() != Some("token"))
+        .collect::<Vec<_>>()
+        .join("&")
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| percent_decode(value))
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => out.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).unwrap_or_default();
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) => {
+                        out.push(byte);
+                        i += 2;
+                    }
+                    Err(_) => out.push(b'%'),
+                }
+            }
+            byte => out.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn json(value: Value) -> Response<Body> {
+    respond(StatusCode::OK, "application/json", &value.to_string())
+}
+
+fn respond(status: StatusCode, content_type: &str, body: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, content_type)
+        .header("cache-control", "no-store")
+        .body(Body::from(body.to_string()))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(tokens: &[&str]) -> State {
+        let args = Args::parse(tokens.iter().map(|t| t.to_string()), &[crate::auth::OPTS]);
+        let path = std::env::temp_dir().join(format!("netcore-web-{}", std::process::id()));
+        State {
+            addr: "127.0.0.1:8080".parse().unwrap(),
+            auth: Auth::from_args(&args.unwrap()).unwrap(),
+            outbound: OutboundConfig::default(),
+            history: Arc::new(History::new(path)),
+        }
+    }
+
+    async fn status(state: &State, method: Method, uri: &str, headers: &[(&str, &str)]) -> u16 {
+        let mut request = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        let from = "127.0.0.1:40000".parse().unwrap();
+        handle(request.body(Body::empty()).unwrap(), state, from)
+            .await
+            .status()
+            .as_u16()
+    }
+
+    #[test]
+    fn serves_only_its_own_address() {
+        let loopback: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        assert!(serves("127.0.0.1:8080", loopback));
+        assert!(serves("LOCALHOST:8080", loopback));
+        assert!(!serves("127.0.0.1:8081", loopback));
+        assert!(!serves("127.0.0.1", loopback));
+        assert!(!serves("rebound.example:8080", loopback));
+
+        let v6: SocketAddr = "[::1]:80".parse().unwrap();
+        assert!(serves("[::1]", v6));
+        assert!(serves("[::1]:80", v6));
+        assert!(serves("localhost", v6));
+
+        let wildcard: SocketAddr = "0.0.0.0:8080".parse().unwrap();
+        assert!(serves("192.0.2.7:8080", wildcard));
+        assert!(!serves("localhost:8080", wildcard));
+        assert!(!serves("dashboard.example:8080", wildcard));
+    }
+
+    #[tokio::test]
+    async fn refuses_foreign_hosts() {
+        let state = state(&[]);
+        let host = |host| [("host", host)];
+
+        assert_eq!(
+            status(&state, Method::GET, "/api/stats", &host("127.0.0.1:8080")).await,
+            200
+        );
+        assert_eq!(
+            status(&state, Method::GET, "/api/stats", &host("localhost:8080")).await,
+            200
+        );
+        assert_eq!(
+            status(
+                &state,
+                Method::GET,
+                "/api/stats",
+                &host("rebound.example:8080")
+            )
+            .await,
+            403
+        );
+        assert_eq!(status(&state, Method::GET, "/api/stats", &[]).await, 403);
+    }
+
+    #[tokio::test]
+    async fn refuses_cross_origin_requests() {
+        let state = state(&["--admin-token", "s3cret"]);
+        let headers = |origin| {
+            [
+                ("host", "127.0.0.1:8080"),
+                ("authorization", "Bearer s3cret"),
+                ("origin", origin),
+            ]
+        };
+        let kill = "/api/kill?id=18446744073709551615";
+
+        assert_eq!(
+            status(
+                &state,
+                Method::POST,
+                kill,
+                &headers("http://attacker.example")
+            )
+            .await,
+            403
+        );
+        assert_eq!(
+            status(&state, Method::POST, kill, &headers("null")).await,
+            403
+        );
+        assert_eq!(
+            status(
+                &state,
+                Method::POST,
+                kill,
+                &headers("http://127.0.0.1:8080")
+            )
+            .await,
+            404
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_tokens_out_of_the_audit_log() {
+        let dir = std::env::temp_dir().join(format!("netcore-web-audit-{}", std::process::id()));
+        let path = dir.join("audit.log");
+        let args = Args::parse(
+            ["--audit-log".to_string(), path.display().to_string()],
+            &[audit::OPTS],
+        );
+        audit::init(&args.unwrap()).unwrap();
+
+        let state = state(&["--admin-token", "s3cret"]);
+        let kill = "/api/kill?id=18446744073709551615&token=s3cret";
+        let host = [("host", "127.0.0.1:8080")];
+        assert_eq!(status(&state, Method::POST, kill, &host).await, 404);
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        assert!(
+            log.contains(r#""request":"id=18446744073709551615""#),
+            "{}",
+            log
+        );
+        assert!(!log.contains("s3cret"), "{}", log);
+        assert_eq!(without_token("token=a&id=1&token=b"), "id=1");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_history_for_any_window() {
+        let dir = std::env::temp_dir().join(format!("netcore-web-history-{}", std::process::id()));
+        let history = History::new(dir.join("history.tsv"));
+        history.append("ping", "example.com", "12.5").unwrap();
+        let state = State {
+            history: Arc::new(history),
+            ..state(&[])
+        };
+
+        for since in ["", "since=1", "since=18446744073709551615", "since=soon"] {
+            let response = history_records(&state, since).await;
+            assert_eq!(response.status(), StatusCode::OK, "{}", since);
+            assert!(!response.headers().contains_key("x-history-kept-since"));
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            let body = String::from_utf8_lossy(&body);
+            assert!(
+                body.contains(r#""subject":"example.com""#),
+                "{}: {}",
+                since,
+                body
+            );
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn needs_a_token_to_change_anything() {
+        let open = state(&[]);
+        let host = [("host", "127.0.0.1:8080")];
+        assert_eq!(status(&open, Method::GET, "/api/stats", &host).await, 200);
+        assert_eq!(
+            status(&open, Method::POST, "/api/kill?id=1", &host).await,
+            403
+        );
+        assert_eq!(status(&open, Method::POST, "/api/check", &host).await, 403);
+    }
+}
@@ -0,0 +1,48 @@
+use hyper::{Body, Method, Request};
+use tokio::time::timeout;
+
+use crate::json::Value;
+
+pub async fn post(url: &str, payload: &Value) -> Result<(), String> {
+    post_with_headers(url, &[], payload).await
+}
+
+pub async fn post_with_headers(
+    url: &str,
+    headers: &[(String, String)],
+    payload: &Value,
+) -> Result<(), String> {
+    if !url.starts_with("http://") {
+        return Err(format!(
+            "unsupported webhook URL '{}', only http:// is available",
+            url
+        ));
+    }
+
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(url)
+        .header("content-type", "application/json")
+        .header("user-agent", concat!("netcore/", env!("CARGO_PKG_VERSION")));
+    for (name, value) in headers {
+        request = request.header(name.as_str(), value.as_str());
+    }
+
+    let request = request
+        .body(Body::from(payload.to_string()))
+        .map_err(|e| e.to_string())?;
+
+    let response = timeout(
+        crate::timeouts::get().write,
+        crate::proxy::client().request(request),
+    )
+    .await
+    .map_err(|_| "timed out".to_string())?
+    .map_err(|e| e.to_string())?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("webhook returned {}", response.status()))
+    }
+}
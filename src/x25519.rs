@@ -0,0 +1,253 @@
+// X25519 Diffie-Hellman (RFC 7748): a Montgomery ladder over GF(2^255 - 19)
+// with field elements in five 51-bit limbs. The field is shared with
+// ed25519.
+
+use std::ops::{Add, Mul, Sub};
+
+const MASK: u64 = (1 << 51) - 1;
+
+#[derive(Clone, Copy)]
+pub struct Fe([u64; 5]);
+
+pub const ZERO: Fe = Fe([0; 5]);
+pub const ONE: Fe = Fe([1, 0, 0, 0, 0]);
+
+fn load8(bytes: &[u8]) -> u64 {
+    u64::from_le_bytes(bytes[..8].try_into().unwrap())
+}
+
+impl Fe {
+    // The top bit is ignored, as the RFC asks.
+    pub fn from_bytes(b: &[u8; 32]) -> Fe {
+        Fe([
+            load8(&b[0..]) & MASK,
+            (load8(&b[6..]) >> 3) & MASK,
+            (load8(&b[12..]) >> 6) & MASK,
+            (load8(&b[19..]) >> 1) & MASK,
+            (load8(&b[24..]) >> 12) & MASK,
+        ])
+    }
+
+    pub fn to_bytes(self) -> [u8; 32] {
+        let mut h = self.carry().0;
+        // Subtract p if h >= p: q is 1 exactly when h + 19 overflows 2^255.
+        let mut q = (h[0] + 19) >> 51;
+        for limb in &h[1..] {
+            q = (limb + q) >> 51;
+        }
+        h[0] += 19 * q;
+        for i in 0..4 {
+            h[i + 1] += h[i] >> 51;
+            h[i] &= MASK;
+        }
+        h[4] &= MASK;
+
+        let words = [
+            h[0] | (h[1] << 51),
+            (h[1] >> 13) | (h[2] << 38),
+            (h[2] >> 26) | (h[3] << 25),
+            (h[3] >> 39) | (h[4] << 12),
+        ];
+        let mut out = [0; 32];
+        for (i, word) in words.iter().enumerate() {
+            out[i * 8..i * 8 + 8].copy_from_slice(&word.to_le_bytes());
+        }
+        out
+    }
+
+    // Brings every limb back under 2^51 plus a little, folding the carry out
+    // of the top limb back in as 19 (since 2^255 = 19).
+    fn carry(self) -> Fe {
+        let mut h = self.0;
+        for i in 0..4 {
+            h[i + 1] += h[i] >> 51;
+            h[i] &= MASK;
+        }
+        h[0] += 19 * (h[4] >> 51);
+        h[4] &= MASK;
+        h[1] += h[0] >> 51;
+        h[0] &= MASK;
+        Fe(h)
+    }
+
+    pub fn square(self) -> Fe {
+        self.mul(self)
+    }
+
+    fn mul_small(self, n: u64) -> Fe {
+        Fe::reduce(self.0.map(|limb| u128::from(limb) * u128::from(n)))
+    }
+
+    fn reduce(mut r: [u128; 5]) -> Fe {
+        for i in 0..4 {
+            r[i + 1] += r[i] >> 51;
+            r[i] &= MASK as u128;
+        }
+        r[0] += 19 * (r[4] >> 51);
+        r[4] &= MASK as u128;
+        Fe(r.map(|limb| limb as u64)).carry()
+    }
+
+    // z^(p - 2), by Fermat. The exponent is public, so square-and-multiply
+    // over its bits leaks nothing.
+    pub fn invert(self) -> Fe {
+        // p - 2 = 2^255 - 21: every bit set except bits 2 and 4, and bit 255.
+        let mut result = ONE;
+        for bit in (0..255).rev() {
+            result = result.square();
+            if bit != 2 && bit != 4 {
+                result = result.mul(self);
+            }
+        }
+        result
+    }
+}
+
+impl Add for Fe {
+    type Output = Fe;
+
+    fn add(self, other: Fe) -> Fe {
+        Fe(std::array::from_fn(|i| self.0[i] + other.0[i])).carry()
+    }
+}
+
+impl Sub for Fe {
+    type Output = Fe;
+
+    // Adds 2p first so no limb goes negative.
+    fn sub(self, other: Fe) -> Fe {
+        const TWO_P: [u64; 5] = [
+            0xfffffffffffda,
+            0xffffffffffffe,
+            0xffffffffffffe,
+            0xffffffffffffe,
+            0xffffffffffffe,
+        ];
+        Fe(std::array::from_fn(|i| self.0[i] + TWO_P[i] - other.0[i])).carry()
+    }
+}
+
+impl Mul for Fe {
+    type Output = Fe;
+
+    fn mul(self, other: Fe) -> Fe {
+        let a = self.0.map(u128::from);
+        let b = other.0.map(u128::from);
+        let b19 = b.map(|limb| limb * 19);
+        let r = [
+            a[0] * b[0] + a[1] * b19[4] + a[2] * b19[3] + a[3] * b19[2] + a[4] * b19[1],
+            a[0] * b[1] + a[1] * b[0] + a[2] * b19[4] + a[3] * b19[3] + a[4] * b19[2],
+            a[0] * b[2] + a[1] * b[1] + a[2] * b[0] + a[3] * b19[4] + a[4] * b19[3],
+            a[0] * b[3] + a[1] * b[2] + a[2] * b[1] + a[3] * b[0] + a[4] * b19[4],
+            a[0] * b[4] + a[1] * b[3] + a[2] * b[2] + a[3] * b[1] + a[4] * b[0],
+        ];
+        Fe::reduce(r)
+    }
+}
+
+// Swaps a and b when `swap` is 1, without branching on it.
+pub fn cswap(swap: u64, a: &mut Fe, b: &mut Fe) {
+    let mask = 0u64.wrapping_sub(swap);
+    for i in 0..5 {
+        let t = mask & (a.0[i] ^ b.0[i]);
+        a.0[i] ^= t;
+        b.0[i] ^= t;
+    }
+}
+
+pub fn x25519(scalar: &[u8; 32], u: &[u8; 32]) -> [u8; 32] {
+    let mut k = *scalar;
+    k[0] &= 248;
+    k[31] &= 127;
+    k[31] |= 64;
+
+    let x1 = Fe::from_bytes(u);
+    let (mut x2, mut z2, mut x3, mut z3) = (ONE, ZERO, x1, ONE);
+    let mut swap = 0;
+    for t in (0..255).rev() {
+        let bit = u64::from((k[t / 8] >> (t % 8)) & 1);
+        swap ^= bit;
+        cswap(swap, &mut x2, &mut x3);
+        cswap(swap, &mut z2, &mut z3);
+        swap = bit;
+
+        let a = x2.add(z2);
+        let aa = a.square();
+        let b = x2.sub(z2);
+        let bb = b.square();
+        let e = aa.sub(bb);
+        let c = x3.add(z3);
+        let d = x3.sub(z3);
+        let da = d.mul(a);
+        let cb = c.mul(b);
+        x3 = da.add(cb).square();
+        z3 = x1.mul(da.sub(cb).square());
+        x2 = aa.mul(bb);
+        z2 = e.mul(aa.add(e.mul_small(121_665)));
+    }
+    cswap(swap, &mut x2, &mut x3);
+    cswap(swap, &mut z2, &mut z3);
+
+    x2.mul(z2.invert()).to_bytes()
+}
+
+pub fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+    let mut base = [0; 32];
+    base[0] = 9;
+    x25519(secret, &base)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hex;
+
+    fn bytes(text: &str) -> [u8; 32] {
+        hex::decode(text).unwrap().try_into().unwrap()
+    }
+
+    #[test]
+    fn matches_rfc_7748_vectors() {
+        let scalar = bytes("a546e36bf0527c9d3b16154b82465edd62144c0ac1fc5a18506a2244ba449ac4");
+        let u = bytes("e6db6867583030db3594c1a424b15f7c726624ec26b3353b10a903a6d0ab1c4c");
+        assert_eq!(
+            x25519(&scalar, &u),
+            bytes("c3da55379de9c6908e94ea4df28d084f32eccf03491c71f754b4075577a28552")
+        );
+
+        let alice = bytes("77076d0a7318a57d3c16c17251b26645df4c2f87ebc0992ab177fba51db92c2a");
+        let bob = bytes("5dab087e624a8a4b79e17f8b83800ee66f3bb1292618b6fd1c2f8b27ff88e0eb");
+        assert_eq!(
+            public_key(&alice),
+            bytes("8520f0098930a754748b7ddcb43ef75a0dbf3a0d26381af4eba4a98eaa9b4e6a")
+        );
+        assert_eq!(
+            public_key(&bob),
+            bytes("de9edb7d7b7dc1b4d35b61c2ece435373f8343c85b78674dadfc7e146f882b4f")
+        );
+        let shared = bytes("4a5d9d5ba4ce2de1728e3bf480350f25e07e21c947d19e3376f09b3c1e161742");
+        assert_eq!(x25519(&alice, &public_key(&bob)), shared);
+        assert_eq!(x25519(&bob, &public_key(&alice)), shared);
+    }
+
+    // RFC 7748 5.2: the result fed back in as the next scalar, the
+    // scalar as the next u.
+    #[test]
+    fn matches_rfc_7748_iterations() {
+        let mut k = bytes("0900000000000000000000000000000000000000000000000000000000000000");
+        let mut u = k;
+        for i in 1..=1000 {
+            (k, u) = (x25519(&k, &u), k);
+            if i == 1 {
+                assert_eq!(
+                    k,
+                    bytes("422c8e7a6227d7bca1350b3e2bb7279f7897b87bb6854b783c60e80311ae3079")
+                );
+            }
+        }
+        assert_eq!(
+            k,
+            bytes("684cf59ba83309552800ef566f2f4d3c1c3887c49360e3875f2eb94d99532c51")
+        );
+    }
+}